@@ -1,12 +1,9 @@
 use std::env;
 use std::fs;
 use std::path::Path;
-use std::process::Command;
 
 const GNOME_EXTENSION_SRC: &str = "src/gnome-extension";
 const GNOME_EXTENSION_FILES: &[&str] = &["metadata.json"];
-const GNOME_EXTENSION_SCHEMA_FILES: &[&str] =
-    &["schemas/org.gnome.shell.extensions.kanata-switcher.gschema.xml"];
 
 fn main() {
     for file in GNOME_EXTENSION_FILES {
@@ -22,9 +19,6 @@ fn main() {
         }
         println!("cargo:rerun-if-changed={}", path.display());
     }
-    for file in GNOME_EXTENSION_SCHEMA_FILES {
-        println!("cargo:rerun-if-changed={}/{}", GNOME_EXTENSION_SRC, file);
-    }
 
     let out_dir = env::var("OUT_DIR").unwrap();
     let out_path = Path::new(&out_dir);
@@ -75,32 +69,6 @@ fn main() {
         });
     }
 
-    let target_schema_dir = target_gnome_dir.join("schemas");
-    fs::create_dir_all(&target_schema_dir).expect("Failed to create gnome schema directory");
-    for file in GNOME_EXTENSION_SCHEMA_FILES {
-        let src = src_dir.join(file);
-        let dst = target_gnome_dir.join(file);
-        fs::copy(&src, &dst).unwrap_or_else(|e| {
-            panic!(
-                "Failed to copy {} to {}: {}",
-                src.display(),
-                dst.display(),
-                e
-            )
-        });
-    }
-
-    let compile_result = Command::new("glib-compile-schemas")
-        .arg(&target_schema_dir)
-        .output()
-        .unwrap_or_else(|e| panic!("Failed to run glib-compile-schemas: {}", e));
-    if !compile_result.status.success() {
-        panic!(
-            "glib-compile-schemas failed: {}",
-            String::from_utf8_lossy(&compile_result.stderr)
-        );
-    }
-
     println!(
         "cargo:warning=GNOME extension files copied to {}",
         target_gnome_dir.display()
@@ -0,0 +1,172 @@
+//! zbus proxy for the `com.github.kanata.Switcher` DBus interface of a running kanata-switcher
+//! daemon. `SwitcherProxy` is async, `SwitcherProxyBlocking` blocking; both talk to the default
+//! instance unless built with `instance_name`/`instance_path` for `--dbus-suffix` daemons.
+//!
+//! ```no_run
+//! # async fn example() -> zbus::Result<()> {
+//! use futures_util::StreamExt;
+//! use kanata_switcher_client::SwitcherProxy;
+//!
+//! let connection = zbus::Connection::session().await?;
+//! let switcher = SwitcherProxy::new(&connection).await?;
+//! println!("layer: {}", switcher.current_layer().await?);
+//! let mut changes = switcher.receive_current_layer_changed().await;
+//! while let Some(change) = changes.next().await {
+//!     println!("layer: {}", change.get().await?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+use zbus::Connection;
+use zbus::zvariant::{OwnedValue, Value};
+
+pub const DBUS_NAME: &str = "com.github.kanata.Switcher";
+pub const DBUS_PATH: &str = "/com/github/kanata/Switcher";
+pub const DBUS_INTERFACE: &str = "com.github.kanata.Switcher";
+
+/// Bus name of the daemon started with `--dbus-suffix SUFFIX` (`None`: the default instance)
+pub fn instance_name(suffix: Option<&str>) -> String {
+    match suffix {
+        Some(suffix) => format!("{}.{}", DBUS_NAME, suffix),
+        None => DBUS_NAME.to_string(),
+    }
+}
+
+/// Object path of the daemon started with `--dbus-suffix SUFFIX` (`None`: the default instance)
+pub fn instance_path(suffix: Option<&str>) -> String {
+    match suffix {
+        Some(suffix) => format!("{}/{}", DBUS_PATH, suffix),
+        None => DBUS_PATH.to_string(),
+    }
+}
+
+/// `SwitcherProxy` for the default instance or a `--dbus-suffix` one
+pub async fn connect(
+    connection: &Connection,
+    suffix: Option<&str>,
+) -> zbus::Result<SwitcherProxy<'static>> {
+    SwitcherProxy::builder(connection)
+        .destination(instance_name(suffix))?
+        .path(instance_path(suffix))?
+        .build()
+        .await
+}
+
+/// Rule indices are config rule numbers from 0; the on_native_terminal rule is -1,
+/// on_session_inactive -2
+#[zbus::proxy(
+    interface = "com.github.kanata.Switcher",
+    default_service = "com.github.kanata.Switcher",
+    default_path = "/com/github/kanata/Switcher"
+)]
+pub trait Switcher {
+    /// Apply the rules to a window as if it got focus (for focus sources the daemon doesn't know)
+    fn window_focus(&self, window_class: &str, window_title: &str) -> zbus::Result<()>;
+
+    /// (layer, held virtual keys, layer source, matched rules)
+    fn get_status(&self) -> zbus::Result<(String, Vec<String>, String, Vec<i32>)>;
+
+    fn get_paused(&self) -> zbus::Result<bool>;
+
+    /// Kanata layer name -> display name from the config's `layer_names`
+    fn get_layer_display_names(&self) -> zbus::Result<BTreeMap<String, String>>;
+
+    /// (degraded, problems as "component: problem")
+    fn get_health(&self) -> zbus::Result<(bool, Vec<String>)>;
+
+    /// (backend, capability -> supported)
+    fn get_backend_info(&self) -> zbus::Result<(String, BTreeMap<String, bool>)>;
+
+    /// The last `lines` lines the daemon printed, oldest first (0 = all kept)
+    fn get_recent_logs(&self, lines: u32) -> zbus::Result<Vec<String>>;
+
+    fn get_kanata_connected(&self) -> zbus::Result<bool>;
+
+    /// Rule index -> the rule's `name`, for rules that have one
+    fn get_rule_names(&self) -> zbus::Result<BTreeMap<i32, String>>;
+
+    /// (rule index, summary, hits) since the daemon (re)started
+    fn get_rule_stats(&self) -> zbus::Result<Vec<(i32, String, u64)>>;
+
+    /// (messages per second, sent, deferred, coalesced); 0 per second means unlimited
+    fn get_rate_limit_stats(&self) -> zbus::Result<(u32, u64, u64, u64)>;
+
+    /// Layer names reported by kanata (empty until connected)
+    fn get_layers(&self) -> zbus::Result<Vec<String>>;
+
+    /// Switch to a layer until the next focus change; false for unknown layers, while a layer
+    /// is pinned or when kanata is not connected
+    fn set_layer(&self, layer: &str) -> zbus::Result<bool>;
+
+    /// Hold kanata on a layer and stop focus-based switching until `unpin_layer`
+    fn pin_layer(&self, layer: &str) -> zbus::Result<bool>;
+
+    /// False when no layer was pinned
+    fn unpin_layer(&self) -> zbus::Result<bool>;
+
+    /// False for keys kanata doesn't know or when kanata is not connected
+    fn press_virtual_key(&self, name: &str) -> zbus::Result<bool>;
+
+    fn release_virtual_key(&self, name: &str) -> zbus::Result<bool>;
+
+    fn tap_virtual_key(&self, name: &str) -> zbus::Result<bool>;
+
+    /// Display settings (`show-focus-layer-only`, `show-top-bar-icon`)
+    fn get_settings(&self) -> zbus::Result<BTreeMap<String, OwnedValue>>;
+
+    fn set_setting(&self, key: &str, value: &Value<'_>) -> zbus::Result<()>;
+
+    fn restart(&self) -> zbus::Result<()>;
+
+    /// Re-read the config file's rules; fails (keeping the current rules) on config errors
+    fn reload(&self) -> zbus::Result<()>;
+
+    fn pause(&self) -> zbus::Result<()>;
+
+    fn unpause(&self) -> zbus::Result<()>;
+
+    /// The layer kanata is on, as far as the daemon knows
+    #[zbus(property)]
+    fn current_layer(&self) -> zbus::Result<String>;
+
+    /// Virtual keys the daemon holds
+    #[zbus(property)]
+    fn virtual_keys(&self) -> zbus::Result<Vec<String>>;
+
+    /// `receive_paused_changed` follows this property; the daemon's `PausedChanged` signal
+    /// carries the same value and has no method here, its name would clash
+    #[zbus(property)]
+    fn paused(&self) -> zbus::Result<bool>;
+
+    #[zbus(signal)]
+    fn status_changed(
+        &self,
+        layer: &str,
+        virtual_keys: Vec<&str>,
+        source: &str,
+        matched_rules: Vec<i32>,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn setting_changed(&self, key: &str, value: Value<'_>) -> zbus::Result<()>;
+
+    /// connected = false when the kanata connection is lost; `dropped_actions` counts the
+    /// virtual key/reload actions dropped while it was down
+    #[zbus(signal)]
+    fn kanata_connection_changed(&self, connected: bool, dropped_actions: u64) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn log_line(&self, line: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn focus_changed(
+        &self,
+        class: &str,
+        title: &str,
+        native_terminal: bool,
+        matched_rules: Vec<i32>,
+        window_id: &str,
+    ) -> zbus::Result<()>;
+}
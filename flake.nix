@@ -30,16 +30,12 @@
 
             src = ./src/gnome-extension;
 
-            nativeBuildInputs = [ pkgs.buildPackages.glib.dev ];
-
             installPhase = ''
               runHook preInstall
 
               extensionDir=$out/share/gnome-shell/extensions/kanata-switcher@7mind.io
-              mkdir -p $extensionDir/schemas
+              mkdir -p $extensionDir
               cp *.js metadata.json $extensionDir/
-              cp schemas/org.gnome.shell.extensions.kanata-switcher.gschema.xml $extensionDir/schemas/
-              ${pkgs.buildPackages.glib.dev}/bin/glib-compile-schemas $extensionDir/schemas
 
               runHook postInstall
             '';
@@ -65,7 +61,7 @@
             src = rustDaemonSrc;
             strictDeps = true;
             buildInputs = [ pkgs.dbus ];
-            nativeBuildInputs = [ pkgs.pkg-config pkgs.buildPackages.glib.dev ];
+            nativeBuildInputs = [ pkgs.pkg-config ];
           };
 
           rustDaemonCargoArtifacts = craneLib.buildDepsOnly rustDaemonCommonArgs;
@@ -80,11 +76,8 @@
 
             postInstall = ''
               mkdir -p $out/bin/gnome
-              mkdir -p $out/bin/gnome/schemas
               cp ${./src/gnome-extension}/*.js $out/bin/gnome/
               cp ${./src/gnome-extension}/metadata.json $out/bin/gnome/
-              cp ${./src/gnome-extension}/schemas/org.gnome.shell.extensions.kanata-switcher.gschema.xml $out/bin/gnome/schemas/
-              ${pkgs.buildPackages.glib.dev}/bin/glib-compile-schemas $out/bin/gnome/schemas
             '';
 
             meta = with pkgs.lib; {
@@ -158,10 +151,6 @@
                   }
                 ];
               }).config.system.build.toplevel;
-            gnome-schema = pkgs.runCommand "kanata-switcher-gnome-schema-check" {} ''
-              test -f ${kanata-switcher-gnome-extension}/share/gnome-shell/extensions/kanata-switcher@7mind.io/schemas/gschemas.compiled
-              touch $out
-            '';
             gnome-extension-files = pkgs.runCommand "kanata-switcher-gnome-extension-files-check" {} ''
               set -euo pipefail
 
@@ -356,7 +345,9 @@
           partOf = [ "graphical-session.target" ];
           wantedBy = [ "graphical-session.target" ];
           serviceConfig = {
-            Type = "simple";
+            Type = "notify";
+            TimeoutStartSec = "infinity";
+            WatchdogSec = 30;
             ExecStart = lib.concatStringsSep " " execArgs;
             Restart = "on-failure";
             RestartSec = 5;
@@ -392,7 +383,9 @@
             ];
           };
           Service = {
-            Type = "simple";
+            Type = "notify";
+            TimeoutStartSec = "infinity";
+            WatchdogSec = 30;
             ExecStart = lib.concatStringsSep " " execArgs;
             Restart = "on-failure";
             RestartSec = 5;
@@ -8,7 +8,6 @@ TMP_DIR="$(mktemp -d)"
 trap 'rm -rf "$TMP_DIR"' EXIT
 
 cp -R "$EXT_DIR"/. "$TMP_DIR"
-glib-compile-schemas "$TMP_DIR/schemas"
 
 gnome-extensions pack "$TMP_DIR" --force --out-dir=/tmp
 gnome-extensions install "/tmp/${EXT_UUID}.shell-extension.zip" --force
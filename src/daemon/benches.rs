@@ -0,0 +1,204 @@
+//! Timing harness for the focus matching hot path (`FocusHandler::handle`, `Pattern::is_match`).
+//!
+//! Ignored by default; run in release mode so numbers mean something:
+//!
+//! ```text
+//! cargo test --release --bin kanata-switcher bench_matching -- --ignored --nocapture
+//! ```
+//!
+//! With `KANATA_SWITCHER_BENCH_BASELINE=path` the results are compared with the file (and the test
+//! fails on a regression above `REGRESSION_THRESHOLD`); a missing file is created from this run.
+
+use super::*;
+use std::hint::black_box;
+use std::time::Instant;
+
+const RULE_COUNT: usize = 500;
+const TITLE_LEN: usize = 2000;
+/// Each benchmark runs for about this long after warmup
+const MEASURE_TIME: Duration = Duration::from_millis(500);
+const WARMUP_TIME: Duration = Duration::from_millis(100);
+/// Slowdown vs the baseline that fails the run (0.5 = 50% slower); run-to-run noise is
+/// around 20%, so only real slowdowns (like losing regex caching) trip it
+const REGRESSION_THRESHOLD: f64 = 0.5;
+const BASELINE_ENV: &str = "KANATA_SWITCHER_BENCH_BASELINE";
+
+/// Runs `f` repeatedly and returns the mean time per call in nanoseconds
+fn measure(mut f: impl FnMut()) -> f64 {
+    let warmup_start = Instant::now();
+    while warmup_start.elapsed() < WARMUP_TIME {
+        f();
+    }
+    let start = Instant::now();
+    let mut iterations: u64 = 0;
+    while start.elapsed() < MEASURE_TIME {
+        f();
+        iterations += 1;
+    }
+    start.elapsed().as_nanos() as f64 / iterations as f64
+}
+
+fn long_title(suffix: &str) -> String {
+    let mut title = "lorem ipsum dolor sit amet ".repeat(TITLE_LEN / 27);
+    title.push_str(suffix);
+    title
+}
+
+/// `RULE_COUNT` rules with anchored class regexes and unanchored title regexes; only the last
+/// rule matches `app-target`
+fn synthetic_rules(fallthrough_every: Option<usize>) -> Vec<Rule> {
+    (0..RULE_COUNT)
+        .map(|i| {
+            let class = if i == RULE_COUNT - 1 {
+                "^app-target$".to_string()
+            } else {
+                format!("^app-{}$", i)
+            };
+            Rule {
+                class: Some(class),
+                title: (i % 3 == 0).then(|| format!("document-{}\\.(md|txt)", i)),
+                layer: Some(format!("layer_{}", i % 16)),
+                fallthrough: fallthrough_every.is_some_and(|every| i % every == 0),
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+fn window(class: &str, title: &str) -> WindowInfo {
+    WindowInfo {
+        class: class.to_string(),
+        title: title.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Alternates two windows so every call does full matching and produces actions
+fn bench_handler(rules: Vec<Rule>, first: WindowInfo, second: WindowInfo) -> f64 {
+    let mut handler = FocusHandler::new(rules, None, true);
+    let mut flip = false;
+    measure(|| {
+        flip = !flip;
+        let win = if flip { &first } else { &second };
+        black_box(handler.handle(black_box(win), "base"));
+    })
+}
+
+fn parse_baseline(contents: &str) -> BTreeMap<String, f64> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (name, ns) = line.split_once(' ')?;
+            Some((name.to_string(), ns.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+#[test]
+#[ignore = "benchmark; run with --release -- --ignored --nocapture"]
+fn bench_matching() {
+    let title = long_title("document-42.md");
+    let other_title = long_title("notes");
+
+    let title_pattern = Pattern::compile("title", "document-\\d+\\.(md|txt)").unwrap();
+    let class_pattern = Pattern::compile("class", "^app-target$").unwrap();
+    let wildcard = Pattern::compile("title", "*").unwrap();
+
+    let results: Vec<(&str, f64)> = vec![
+        (
+            "match_pattern_regex_long_title",
+            measure(|| {
+                black_box(black_box(&title_pattern).is_match(black_box(&title)));
+            }),
+        ),
+        (
+            "match_pattern_anchored_class",
+            measure(|| {
+                black_box(black_box(&class_pattern).is_match(black_box("app-target")));
+            }),
+        ),
+        (
+            "match_pattern_wildcard",
+            measure(|| {
+                black_box(black_box(&wildcard).is_match(black_box(&title)));
+            }),
+        ),
+        (
+            "handle_500_rules_no_match",
+            bench_handler(
+                synthetic_rules(None),
+                window("unmatched-a", &title),
+                window("unmatched-b", &other_title),
+            ),
+        ),
+        (
+            "handle_500_rules_last_match",
+            bench_handler(
+                synthetic_rules(None),
+                window("app-target", &title),
+                window("unmatched", &other_title),
+            ),
+        ),
+        (
+            "handle_500_rules_fallthrough",
+            bench_handler(
+                synthetic_rules(Some(10)),
+                window("app-target", &title),
+                window("app-0", &other_title),
+            ),
+        ),
+    ];
+
+    let baseline_path = env::var(BASELINE_ENV).ok().map(PathBuf::from);
+    let baseline = baseline_path
+        .as_ref()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .map(|contents| parse_baseline(&contents));
+
+    let mut regressions = Vec::new();
+    for (name, ns) in &results {
+        let change = baseline
+            .as_ref()
+            .and_then(|baseline| baseline.get(*name))
+            .map(|old| (ns - old) / old);
+        match change {
+            Some(change) => {
+                println!("{:<32} {:>12.1} ns/iter  {:+.1}%", name, ns, change * 100.0);
+                if change > REGRESSION_THRESHOLD {
+                    regressions.push(format!("{} ({:+.1}%)", name, change * 100.0));
+                }
+            }
+            None => println!("{:<32} {:>12.1} ns/iter", name, ns),
+        }
+    }
+
+    if let (Some(path), None) = (&baseline_path, &baseline) {
+        let contents: String = results
+            .iter()
+            .map(|(name, ns)| format!("{} {:.1}\n", name, ns))
+            .collect();
+        fs::write(path, contents).expect("failed to write benchmark baseline");
+        println!("Saved baseline to {}", path.display());
+    }
+    assert!(
+        regressions.is_empty(),
+        "slower than baseline: {}",
+        regressions.join(", ")
+    );
+}
+
+#[test]
+fn test_parse_baseline() {
+    let baseline = parse_baseline("handle_500_rules_no_match 1234.5\nbroken\nmatch_pattern_wildcard 2.0\n");
+    assert_eq!(baseline.len(), 2);
+    assert_eq!(baseline.get("handle_500_rules_no_match"), Some(&1234.5));
+}
+
+#[test]
+fn test_synthetic_rules_match_only_last_rule() {
+    let rules = synthetic_rules(None);
+    assert_eq!(rules.len(), RULE_COUNT);
+    let mut handler = FocusHandler::new(rules, None, true);
+    handler.handle(&window("app-target", &long_title("x")), "base");
+    assert_eq!(handler.last_matched_rules(), vec![RULE_COUNT - 1]);
+}
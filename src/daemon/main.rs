@@ -1,11 +1,12 @@
 use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
 use futures_util::StreamExt;
-use ksni::menu::{CheckmarkItem, StandardItem};
+use ksni::menu::{CheckmarkItem, RadioGroup, RadioItem, StandardItem, SubMenu};
 use ksni::{Icon as SniIcon, MenuItem, Status as SniStatus, ToolTip, Tray, TrayService};
 use noto_sans_mono_bitmap::{get_raster, get_raster_width, FontWeight, RasterHeight, RasterizedChar};
-use regex::Regex;
+use regex::{Regex, RegexBuilder};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::env;
 use std::fs;
 use std::os::fd::AsFd;
@@ -15,19 +16,22 @@ use std::process::Command;
 use std::sync::atomic::{AtomicU64, Ordering};
 #[cfg(test)]
 use std::sync::atomic::AtomicUsize;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::io::unix::AsyncFd;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader as TokioBufReader};
 use tokio::net::TcpStream as TokioTcpStream;
-use tokio::net::tcp::OwnedWriteHalf;
-use tokio::sync::{Mutex as TokioMutex, oneshot, watch};
+use tokio::sync::{Mutex as TokioMutex, broadcast, oneshot, watch};
 use wayland_client::{
     Connection as WaylandConnection, Dispatch, Proxy, QueueHandle,
     backend::{ObjectId, WaylandError},
     globals::{GlobalListContents, registry_queue_init},
-    protocol::wl_registry,
+    protocol::{wl_registry, wl_seat::WlSeat},
+};
+use wayland_protocols::ext::idle_notify::v1::client::{
+    ext_idle_notification_v1::{self, ExtIdleNotificationV1},
+    ext_idle_notifier_v1::ExtIdleNotifierV1,
 };
 use wayland_protocols_wlr::foreign_toplevel::v1::client::{
     zwlr_foreign_toplevel_handle_v1::{self, ZwlrForeignToplevelHandleV1},
@@ -35,14 +39,91 @@ use wayland_protocols_wlr::foreign_toplevel::v1::client::{
 };
 use x11rb::connection::Connection as X11Connection;
 use x11rb::protocol::Event as X11Event;
+use x11rb::protocol::screensaver::ConnectionExt as ScreenSaverConnectionExt;
 use x11rb::protocol::xproto::{
-    AtomEnum, ChangeWindowAttributesAux, ConnectionExt as X11ConnectionExt, EventMask, Window,
+    AtomEnum, ChangeWindowAttributesAux, ConnectionExt as X11ConnectionExt, EventMask, GrabMode,
+    GrabStatus, Window,
 };
 use x11rb::rust_connection::RustConnection;
 use zbus::Connection;
 use zbus::object_server::SignalEmitter;
 use zbus::zvariant::{OwnedObjectPath, OwnedValue, Structure, Value};
 
+// === Log Buffer ===
+
+// Daemon log lines also land in `LOG_BUFFER`, so a daemon started via autostart (no terminal, no
+// journal) can still hand out its recent logs over DBus. CLI output and anything carrying window
+// titles uses the std macros and stays out of it
+macro_rules! log_line {
+    ($($arg:tt)+) => {
+        $crate::record_log_line(format!($($arg)+), false)
+    };
+}
+
+macro_rules! elog_line {
+    ($($arg:tt)+) => {
+        $crate::record_log_line(format!($($arg)+), true)
+    };
+}
+
+/// Lines kept for `GetRecentLogs`/`kswitchctl logs`
+const LOG_BUFFER_LINES: usize = 1000;
+/// Lines a slow `LogLine` forwarder may fall behind before it skips ahead
+const LOG_SUBSCRIBER_CAPACITY: usize = 256;
+
+static LOG_BUFFER: LazyLock<LogBuffer> = LazyLock::new(|| LogBuffer::new(LOG_BUFFER_LINES));
+
+/// Bounded history of printed lines, plus a live feed of new ones
+struct LogBuffer {
+    capacity: usize,
+    lines: Mutex<VecDeque<String>>,
+    sender: broadcast::Sender<String>,
+}
+
+impl LogBuffer {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+            sender: broadcast::channel(LOG_SUBSCRIBER_CAPACITY).0,
+        }
+    }
+
+    fn push(&self, line: String) {
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line.clone());
+        // Sent under the lock so subscribers see lines in buffer order
+        let _ = self.sender.send(line);
+    }
+
+    /// The last `limit` lines, oldest first; 0 = every kept line
+    fn recent(&self, limit: usize) -> Vec<String> {
+        let lines = self.lines.lock().unwrap();
+        let skip = match limit {
+            0 => 0,
+            limit => lines.len().saturating_sub(limit),
+        };
+        lines.iter().skip(skip).cloned().collect()
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.sender.subscribe()
+    }
+}
+
+/// Backs `log_line!`/`elog_line!`: prints the line and keeps it in `LOG_BUFFER`
+fn record_log_line(line: String, stderr: bool) {
+    if stderr {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+    LOG_BUFFER.push(line);
+}
+
 // Generated COSMIC protocols
 mod cosmic_workspace {
     #![allow(dead_code, non_camel_case_types, unused_unsafe, unused_variables)]
@@ -85,26 +166,47 @@ use cosmic_workspace::{
 };
 
 const GNOME_EXTENSION_UUID: &str = "kanata-switcher@7mind.io";
-const DCONF_FOCUS_ONLY_KEY: &str = "/org/gnome/shell/extensions/kanata-switcher/show-focus-layer-only";
+/// Where extension versions with a GSettings schema kept the display settings
+const DCONF_SETTINGS_DIR: &str = "/org/gnome/shell/extensions/kanata-switcher/";
 const DBUS_NAME: &str = "com.github.kanata.Switcher";
 const DBUS_PATH: &str = "/com/github/kanata/Switcher";
 const DBUS_INTERFACE: &str = "com.github.kanata.Switcher";
+/// GTK application id of the settings window (also its window class)
+const SETTINGS_APP_ID: &str = "com.github.kanata.Switcher.Settings";
 const GNOME_FOCUS_OBJECT_PATH: &str = "/com/github/kanata/Switcher/Gnome";
 const GNOME_FOCUS_INTERFACE: &str = "com.github.kanata.Switcher.Gnome";
 const GNOME_FOCUS_METHOD: &str = "GetFocus";
+/// Like `GetFocus`, plus the app's desktop-file ID (extensions newer than the `app` rule field)
+const GNOME_FOCUS_APP_METHOD: &str = "GetFocusApp";
+/// Like `GetFocusApp`, plus the active workspace name (extensions newer than `workspace` rules)
+const GNOME_FOCUS_WORKSPACE_METHOD: &str = "GetFocusWorkspace";
+/// Like `GetFocusWorkspace`, plus the window's ID (extensions newer than `WindowInfo::window_id`)
+const GNOME_FOCUS_ID_METHOD: &str = "GetFocusId";
+/// All normal windows as (class, title, app) triples, for `--snapshot`
+const GNOME_LIST_WINDOWS_METHOD: &str = "ListWindows";
 const KDE_QUERY_INTERFACE: &str = "com.github.kanata.Switcher.KdeQuery";
 const KDE_QUERY_METHOD: &str = "Focus";
+const KDE_QUERY_WINDOW_METHOD: &str = "Window";
+const KDE_QUERY_DONE_METHOD: &str = "Done";
 const LOGIND_BUS_NAME: &str = "org.freedesktop.login1";
 const LOGIND_MANAGER_PATH: &str = "/org/freedesktop/login1";
 const LOGIND_MANAGER_INTERFACE: &str = "org.freedesktop.login1.Manager";
 const LOGIND_SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
 const LOGIND_USER_INTERFACE: &str = "org.freedesktop.login1.User";
+const LOGIND_SEAT_INTERFACE: &str = "org.freedesktop.login1.Seat";
 const LOGIND_ERROR_NO_SESSION_FOR_PID: &str = "org.freedesktop.login1.NoSessionForPID";
 const LOGIND_EMPTY_OBJECT_PATH: &str = "/";
+/// Session bus screen lockers (interface and object path) that emit `ActiveChanged(b)`:
+/// GNOME Shell's and KDE's kscreenlocker
+const SCREEN_LOCKERS: [(&str, &str); 2] = [
+    ("org.gnome.ScreenSaver", "/org/gnome/ScreenSaver"),
+    ("org.freedesktop.ScreenSaver", "/org/freedesktop/ScreenSaver"),
+];
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum ControlCommand {
     Restart,
+    Reload,
     Pause,
     Unpause,
 }
@@ -113,6 +215,7 @@ impl ControlCommand {
     fn dbus_method(self) -> &'static str {
         match self {
             ControlCommand::Restart => "Restart",
+            ControlCommand::Reload => "Reload",
             ControlCommand::Pause => "Pause",
             ControlCommand::Unpause => "Unpause",
         }
@@ -121,6 +224,7 @@ impl ControlCommand {
     fn label(self) -> &'static str {
         match self {
             ControlCommand::Restart => "restart",
+            ControlCommand::Reload => "reload",
             ControlCommand::Pause => "pause",
             ControlCommand::Unpause => "unpause",
         }
@@ -135,6 +239,34 @@ enum TrayFocusOnly {
     False,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum KanataNameKind {
+    Layers,
+    VirtualKeys,
+}
+
+/// What to do when a rule names a layer kanata doesn't have
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum UnknownLayerPolicy {
+    /// Switch to the default layer instead (with a warning)
+    #[default]
+    Fallback,
+    /// Keep the current layer (with a warning)
+    Skip,
+    /// Keep the current layer and log an error; unknown layers in the config fail startup
+    Error,
+}
+
+impl UnknownLayerPolicy {
+    fn as_arg(self) -> &'static str {
+        match self {
+            UnknownLayerPolicy::Fallback => "fallback",
+            UnknownLayerPolicy::Skip => "skip",
+            UnknownLayerPolicy::Error => "error",
+        }
+    }
+}
+
 impl TrayFocusOnly {
     fn as_bool(self) -> bool {
         matches!(self, TrayFocusOnly::True)
@@ -158,9 +290,79 @@ struct Args {
     #[arg(short = 'H', long, default_value = "127.0.0.1")]
     host: String,
 
+    /// Use the named kanata endpoint from the config's "kanata" entry instead of -H/-p
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["host", "port", "kanata_tls_ca", "kanata_tls_pin", "kanata_tls_server_name"])]
+    kanata: Option<String>,
+
+    /// Connect to kanata over TLS, verifying the endpoint against this PEM CA bundle
+    #[arg(long, value_name = "PATH")]
+    kanata_tls_ca: Option<PathBuf>,
+
+    /// Connect to kanata over TLS, pinning the endpoint certificate's SHA-256 fingerprint (repeatable)
+    #[arg(long, value_name = "SHA256", value_parser = parse_cert_fingerprint)]
+    kanata_tls_pin: Vec<CertFingerprint>,
+
+    /// Name the TLS certificate must be valid for (default: --host)
+    #[arg(long, value_name = "NAME")]
+    kanata_tls_server_name: Option<String>,
+
+    /// Treat a kanata write that doesn't finish within MS milliseconds as a stalled connection and reconnect
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_KANATA_WRITE_TIMEOUT_MS)]
+    kanata_write_timeout: u64,
+
+    /// Cap ChangeLayer/ActOnFakeKey messages sent to kanata per second, deferring the excess
+    /// in order (0 = unlimited)
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_KANATA_RATE_LIMIT)]
+    kanata_rate_limit: u32,
+
+    /// Send kanata messages to kanata (tcp), or to an embedded stand-in that prints (log) or
+    /// drops (null) them, for running without kanata
+    #[arg(long, value_enum, value_name = "SINK", default_value_t = KanataSink::Tcp, conflicts_with_all = ["host", "port", "kanata", "kanata_tls_ca", "kanata_tls_pin", "kanata_tls_server_name"])]
+    kanata_sink: KanataSink,
+
+    /// Handle focus events as usual but only log the messages kanata would get (--kanata-sink
+    /// log), as a separate instance (--dbus-suffix dry_run unless given) next to the real daemon
+    #[arg(long, conflicts_with_all = ["kanata_sink", "host", "port", "kanata", "kanata_tls_ca", "kanata_tls_pin", "kanata_tls_server_name", "install_autostart", "install_systemd_service"])]
+    dry_run: bool,
+
+    /// Stop waiting for kanata at startup after SECONDS: exit with an error, or start without it
+    /// when --allow-offline is set
+    #[arg(long, value_name = "SECONDS")]
+    connect_timeout: Option<u64>,
+
+    /// Start even if kanata is unreachable: run degraded and keep reconnecting in the background
+    #[arg(long, conflicts_with = "once")]
+    allow_offline: bool,
+
+    /// Before connecting, wait until this systemd unit (user or system, e.g. kanata.service) is
+    /// active, so both starting at login doesn't cause a burst of failed connection attempts
+    #[arg(long, value_name = "UNIT")]
+    wait_for_unit: Option<String>,
+
+    /// Give up waiting for --wait-for-unit after SECONDS and connect anyway
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_WAIT_FOR_UNIT_TIMEOUT_SECS, requires = "wait_for_unit")]
+    wait_for_unit_timeout: u64,
+
+    /// Show a desktop notification when the kanata connection is lost and when it comes back
+    #[arg(long, conflicts_with = "once")]
+    notify_kanata: bool,
+
+    /// Show a desktop notification on layer changes; same as `{"notifications": {}}` in the
+    /// config
+    #[arg(long, conflicts_with = "once")]
+    notify_layer_changes: bool,
+
     #[arg(short = 'c', long)]
     config: Option<PathBuf>,
 
+    /// Kanata .kbd config used to validate layer and virtual key names in rules
+    #[arg(long, value_name = "PATH")]
+    kanata_config: Option<PathBuf>,
+
+    /// Unknown layer in a rule: switch to the default layer, keep the current one, or treat it as an error
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = UnknownLayerPolicy::Fallback)]
+    unknown_layer: UnknownLayerPolicy,
+
     /// Quiet mode: suppress focus and layer-switch messages
     #[arg(short = 'q', long)]
     quiet: bool,
@@ -169,6 +371,10 @@ struct Args {
     #[arg(long)]
     quiet_focus: bool,
 
+    /// Print protocol details, such as kanata messages this version doesn't understand
+    #[arg(long)]
+    debug: bool,
+
     /// Auto-install GNOME extension if missing (default behavior)
     #[arg(long)]
     install_gnome_extension: bool,
@@ -181,7 +387,12 @@ struct Args {
     #[arg(long)]
     no_indicator: bool,
 
-    /// Override SNI focus-only mode (true/false). When set, GSettings is not read.
+    /// Don't watch logind for VT switches and locks (on_native_terminal, on_session_inactive and
+    /// on_locked never apply); same as `{"vt_monitor": false}` in the config
+    #[arg(long)]
+    no_vt_monitor: bool,
+
+    /// Override SNI focus-only mode (true/false) for this run instead of the stored setting
     #[arg(long, value_enum, value_name = "true|false")]
     indicator_focus_only: Option<TrayFocusOnly>,
 
@@ -193,10 +404,86 @@ struct Args {
     #[arg(long, conflicts_with_all = ["install_autostart", "restart", "pause", "unpause"])]
     uninstall_autostart: bool,
 
+    /// Install and enable a systemd user unit (Type=notify, started with
+    /// graphical-session.target) running the daemon with the other options given, then exit
+    #[arg(long, conflicts_with_all = ["uninstall_systemd_service", "install_autostart", "uninstall_autostart", "restart", "pause", "unpause"])]
+    install_systemd_service: bool,
+
+    /// Stop, disable and remove the systemd user unit, then exit
+    #[arg(long, conflicts_with_all = ["install_systemd_service", "install_autostart", "uninstall_autostart", "restart", "pause", "unpause"])]
+    uninstall_systemd_service: bool,
+
+    /// Remember the last focus-derived layer and re-apply it right after (re)connecting to kanata
+    #[arg(long)]
+    restore_layer: bool,
+
+    /// Pause switching while an app shares the screen through the xdg-desktop-portal ScreenCast
+    /// interface, resuming when sharing stops
+    #[arg(long)]
+    pause_while_sharing: bool,
+
+    /// Layer to hold while --pause-while-sharing has paused switching (default: kanata's default layer)
+    #[arg(long, value_name = "LAYER", requires = "pause_while_sharing")]
+    sharing_layer: Option<String>,
+
+    /// logind session to follow for VT switches, locks and idle (see `loginctl list-sessions`),
+    /// instead of the one from XDG_SESSION_ID or the daemon's PID
+    #[arg(long, value_name = "ID", conflicts_with = "seat")]
+    session_id: Option<String>,
+
+    /// Follow this user's session on the given seat (e.g. seat0), preferring a graphical one
+    #[arg(long, value_name = "SEAT")]
+    seat: Option<String>,
+
+    /// Run as a separate instance: DBus name com.github.kanata.Switcher.SUFFIX, object path
+    /// /com/github/kanata/Switcher/SUFFIX, own state file and autostart entry
+    #[arg(long, value_name = "SUFFIX", value_parser = parse_dbus_suffix)]
+    dbus_suffix: Option<String>,
+
+    /// Restrict filesystem writes to the runtime, state and temp dirs (Landlock) and deny
+    /// system-administration syscalls (seccomp) before the daemon starts
+    #[arg(long, conflicts_with_all = ["uninstall_autostart", "uninstall_systemd_service", "uninstall", "install_desktop", "uninstall_desktop", "restart", "pause", "unpause", "check_config", "settings", "print_kanata_names"])]
+    harden: bool,
+
+    /// Rewrite the installed autostart entry when its Exec line differs from this invocation's options
+    #[arg(long)]
+    sync_autostart: bool,
+
+    /// Wait SECONDS before starting. Written to the autostart entry as X-GNOME-Autostart-Delay too;
+    /// skipped when gnome-session already applied that delay.
+    #[arg(long, value_name = "SECONDS")]
+    autostart_delay: Option<u64>,
+
+    /// Only autostart in these desktops (XDG_CURRENT_DESKTOP names, e.g. "GNOME;KDE")
+    #[arg(long, value_name = "DESKTOPS", requires = "install_autostart", conflicts_with = "autostart_not_show_in")]
+    autostart_only_show_in: Option<String>,
+
+    /// Do not autostart in these desktops (XDG_CURRENT_DESKTOP names, e.g. "GNOME")
+    #[arg(long, value_name = "DESKTOPS", requires = "install_autostart")]
+    autostart_not_show_in: Option<String>,
+
+    /// Remove everything kanata-switcher installed (autostart, systemd unit, GNOME extension,
+    /// settings, launcher entry, temporary KWin scripts) and exit. Config files are kept.
+    #[arg(long, conflicts_with_all = ["install_autostart", "uninstall_autostart", "install_systemd_service", "uninstall_systemd_service", "install_desktop", "uninstall_desktop", "restart", "pause", "unpause"])]
+    uninstall: bool,
+
+    /// Install an application launcher entry (settings window or monitor) and icon, then exit
+    #[arg(long, conflicts_with_all = ["uninstall_desktop", "install_autostart", "uninstall_autostart", "restart", "pause", "unpause"])]
+    install_desktop: bool,
+
+    /// Remove the application launcher entry and icon, then exit
+    #[arg(long, conflicts_with_all = ["install_desktop", "install_autostart", "uninstall_autostart", "restart", "pause", "unpause"])]
+    uninstall_desktop: bool,
+
     /// Send Restart request to an existing daemon and exit
     #[arg(long, conflicts_with_all = ["pause", "unpause"])]
     restart: bool,
 
+    /// Send Reload request to an existing daemon (re-read the config's rules without
+    /// restarting) and exit
+    #[arg(long, conflicts_with_all = ["restart", "pause", "unpause"])]
+    reload: bool,
+
     /// Send Pause request to an existing daemon and exit
     #[arg(long, conflicts_with_all = ["restart", "unpause"])]
     pause: bool,
@@ -204,26 +491,131 @@ struct Args {
     /// Send Unpause request to an existing daemon and exit
     #[arg(long, conflicts_with_all = ["restart", "pause"])]
     unpause: bool,
+
+    /// Print the running daemon's layer, held virtual keys, layer source, pause and kanata
+    /// connection state, then exit
+    #[arg(long, conflicts_with_all = ["install_autostart", "uninstall_autostart", "uninstall", "install_desktop", "uninstall_desktop", "restart", "reload", "pause", "unpause", "check_config", "settings", "print_kanata_names", "once", "snapshot"])]
+    status: bool,
+
+    /// Print --status as JSON (one object, e.g. for waybar custom modules)
+    #[arg(long, requires = "status")]
+    json: bool,
+
+    /// Print the running daemon's layer as one waybar JSON line now and on every change, until
+    /// interrupted
+    #[arg(long, conflicts_with_all = ["install_autostart", "uninstall_autostart", "uninstall", "install_desktop", "uninstall_desktop", "restart", "reload", "pause", "unpause", "check_config", "dump_config", "settings", "print_kanata_names", "once", "snapshot", "status"])]
+    follow_status: bool,
+
+    /// Check the running daemon and exit with 0 (healthy), 1 (degraded), 2 (kanata
+    /// disconnected) or 3 (no daemon running), for monitoring and systemd ExecCondition
+    #[arg(long, conflicts_with_all = ["install_autostart", "uninstall_autostart", "uninstall", "install_desktop", "uninstall_desktop", "restart", "reload", "pause", "unpause", "check_config", "dump_config", "settings", "print_kanata_names", "once", "snapshot", "status", "follow_status"])]
+    healthcheck: bool,
+
+    /// Validate the config (and rule names against --kanata-config, if given) and exit
+    #[arg(long, conflicts_with_all = ["install_autostart", "uninstall_autostart", "restart", "pause", "unpause", "print_kanata_names"])]
+    check_config: bool,
+
+    /// With --check-config: also connect to the running kanata (--host/--port, --kanata) and
+    /// check layer and virtual key names against it
+    #[arg(long, requires = "check_config", conflicts_with_all = ["kanata_sink", "dry_run"])]
+    check_kanata: bool,
+
+    /// Rewrite a config file of an older format version in the current format (keeping a
+    /// backup) and exit
+    #[arg(long, conflicts_with_all = ["install_autostart", "uninstall_autostart", "uninstall", "install_desktop", "uninstall_desktop", "restart", "reload", "pause", "unpause", "check_config", "dump_config", "settings", "print_kanata_names", "once", "snapshot", "status", "follow_status"])]
+    migrate_config: bool,
+
+    /// Run the rules against a window with this class and title as a freshly started daemon
+    /// would, print the matched rules, layer and virtual key actions, and exit
+    #[arg(long = "match", num_args = 2, value_names = ["CLASS", "TITLE"], conflicts_with_all = ["install_autostart", "uninstall_autostart", "uninstall", "install_desktop", "uninstall_desktop", "restart", "reload", "pause", "unpause", "check_config", "dump_config", "settings", "print_kanata_names", "once", "snapshot", "status", "follow_status"])]
+    match_window: Option<Vec<String>>,
+
+    /// Print the configuration as loaded (defaults filled in, --kanata applied) as JSON and exit
+    #[arg(long, conflicts_with_all = ["install_autostart", "uninstall_autostart", "uninstall", "install_desktop", "uninstall_desktop", "restart", "reload", "pause", "unpause", "check_config", "settings", "print_kanata_names", "once", "snapshot", "status"])]
+    dump_config: bool,
+
+    /// Open the settings window (requires the `settings-gui` build feature) and exit
+    #[arg(long, conflicts_with_all = ["install_autostart", "uninstall_autostart", "restart", "pause", "unpause", "check_config", "print_kanata_names"])]
+    settings: bool,
+
+    /// Apply the rules to the focused window once and exit, without listening for focus changes
+    #[arg(long, conflicts_with_all = ["install_autostart", "uninstall_autostart", "uninstall", "install_desktop", "uninstall_desktop", "restart", "pause", "unpause", "check_config", "settings", "print_kanata_names", "restore_layer", "sync_autostart"])]
+    once: bool,
+
+    /// Print a config fragment with one rule per app among the open windows and exit
+    #[arg(long, conflicts_with_all = ["install_autostart", "uninstall_autostart", "uninstall", "install_desktop", "uninstall_desktop", "restart", "pause", "unpause", "check_config", "settings", "print_kanata_names", "restore_layer", "sync_autostart", "once"])]
+    snapshot: bool,
+
+    /// Print layer or virtual key names from --kanata-config, one per line (for shell completion)
+    #[arg(long, value_enum, value_name = "KIND", requires = "kanata_config", conflicts_with_all = ["install_autostart", "uninstall_autostart", "restart", "pause", "unpause"])]
+    print_kanata_names: Option<KanataNameKind>,
+
+    /// Print how --install-autostart would start the daemon with the other options given (Exec
+    /// and systemd ExecStart lines, resolved config path, detected backend) and exit
+    #[arg(long, conflicts_with_all = ["install_autostart", "uninstall_autostart", "uninstall", "install_desktop", "uninstall_desktop", "restart", "reload", "pause", "unpause", "check_config", "dump_config", "settings", "print_kanata_names", "once", "snapshot", "status", "follow_status"])]
+    print_effective_invocation: bool,
 }
 
 const AUTOSTART_DESKTOP_FILENAME: &str = "kanata-switcher.desktop";
+const AUTOSTART_DESKTOP_STEM: &str = "kanata-switcher";
 const AUTOSTART_PASSTHROUGH_OPTIONS: &[&str] = &[
     "port",
     "host",
+    "kanata",
+    "kanata_tls_ca",
+    "kanata_tls_pin",
+    "kanata_tls_server_name",
+    "kanata_write_timeout",
+    "kanata_rate_limit",
+    "connect_timeout",
+    "allow_offline",
+    "wait_for_unit",
+    "wait_for_unit_timeout",
+    "notify_kanata",
+    "notify_layer_changes",
     "config",
+    "kanata_config",
+    "unknown_layer",
     "quiet",
     "quiet_focus",
+    "debug",
     "install_gnome_extension",
     "no_install_gnome_extension",
     "no_indicator",
+    "no_vt_monitor",
     "indicator_focus_only",
+    "autostart_delay",
+    "restore_layer",
+    "pause_while_sharing",
+    "sharing_layer",
+    "harden",
+    "session_id",
+    "seat",
+    "dbus_suffix",
 ];
 const AUTOSTART_ONESHOT_OPTIONS: &[&str] = &[
     "restart",
+    "reload",
     "pause",
     "unpause",
     "install_autostart",
     "uninstall_autostart",
+    "install_systemd_service",
+    "uninstall_systemd_service",
+    "install_desktop",
+    "uninstall_desktop",
+    "uninstall",
+    "check_config",
+    "check_kanata",
+    "dump_config",
+    "match_window",
+    "migrate_config",
+    "print_kanata_names",
+    "print_effective_invocation",
+    "settings",
+    "status",
+    "json",
+    "follow_status",
 ];
 
 fn resolve_install_gnome_extension(matches: &ArgMatches) -> bool {
@@ -253,6 +645,9 @@ fn resolve_control_command(args: &Args) -> Option<ControlCommand> {
     if args.restart {
         return Some(ControlCommand::Restart);
     }
+    if args.reload {
+        return Some(ControlCommand::Reload);
+    }
     if args.pause {
         return Some(ControlCommand::Pause);
     }
@@ -268,22 +663,33 @@ fn resolve_binary_path() -> Result<PathBuf, Box<dyn std::error::Error + Send + S
     Ok(canonical)
 }
 
-fn autostart_dir() -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+fn xdg_config_home() -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
     if let Ok(xdg_config_home) = env::var("XDG_CONFIG_HOME") {
         if xdg_config_home.is_empty() {
             return Err("XDG_CONFIG_HOME is empty".into());
         }
-        return Ok(PathBuf::from(xdg_config_home).join("autostart"));
+        return Ok(PathBuf::from(xdg_config_home));
     }
     let home = env::var("HOME")?;
     if home.is_empty() {
         return Err("HOME is empty".into());
     }
-    Ok(PathBuf::from(home).join(".config").join("autostart"))
+    Ok(PathBuf::from(home).join(".config"))
 }
 
-fn autostart_desktop_path() -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
-    Ok(autostart_dir()?.join(AUTOSTART_DESKTOP_FILENAME))
+fn autostart_dir() -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(xdg_config_home()?.join("autostart"))
+}
+
+/// `kanata-switcher.desktop`, or `kanata-switcher-SUFFIX.desktop` for a `--dbus-suffix` instance
+fn autostart_desktop_path(
+    instance: &DbusInstance,
+) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(autostart_dir()?.join(format!(
+        "{}{}.desktop",
+        AUTOSTART_DESKTOP_STEM,
+        instance.file_tag()
+    )))
 }
 
 fn escape_desktop_exec_arg(value: &str) -> String {
@@ -304,7 +710,45 @@ fn escape_desktop_exec_arg(value: &str) -> String {
     escaped
 }
 
-fn build_autostart_desktop_content(exec_path: &Path, exec_args: &[String]) -> String {
+/// Desktop entry keys controlling when the autostart entry runs.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct AutostartConditions {
+    delay_secs: Option<u64>,
+    only_show_in: Vec<String>,
+    not_show_in: Vec<String>,
+}
+
+impl AutostartConditions {
+    fn from_args(args: &Args) -> Self {
+        Self {
+            delay_secs: args.autostart_delay,
+            only_show_in: parse_desktop_list(args.autostart_only_show_in.as_deref()),
+            not_show_in: parse_desktop_list(args.autostart_not_show_in.as_deref()),
+        }
+    }
+}
+
+/// Split a user-supplied desktop list ("GNOME;KDE" or "GNOME,KDE").
+fn parse_desktop_list(value: Option<&str>) -> Vec<String> {
+    value
+        .unwrap_or_default()
+        .split([';', ','])
+        .map(str::trim)
+        .filter(|desktop| !desktop.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Delay to apply before startup. gnome-session sets DESKTOP_AUTOSTART_ID for the apps it launches,
+/// in which case it has already honoured X-GNOME-Autostart-Delay.
+fn startup_delay(delay_secs: Option<u64>, launched_by_gnome_session: bool) -> Option<Duration> {
+    match delay_secs {
+        Some(secs) if secs > 0 && !launched_by_gnome_session => Some(Duration::from_secs(secs)),
+        _ => None,
+    }
+}
+
+fn build_autostart_exec_line(exec_path: &Path, exec_args: &[String]) -> String {
     let exec_path_str = exec_path
         .to_str()
         .expect("autostart exec path contains invalid UTF-8");
@@ -313,14 +757,194 @@ fn build_autostart_desktop_content(exec_path: &Path, exec_args: &[String]) -> St
     for arg in exec_args {
         exec_parts.push(escape_desktop_exec_arg(arg));
     }
-    let exec_line = exec_parts.join(" ");
-    format!(
+    exec_parts.join(" ")
+}
+
+fn build_autostart_desktop_content(
+    exec_path: &Path,
+    exec_args: &[String],
+    conditions: &AutostartConditions,
+) -> String {
+    let exec_path_str = exec_path
+        .to_str()
+        .expect("autostart exec path contains invalid UTF-8");
+    let mut content = format!(
         "[Desktop Entry]\nType=Application\nName=Kanata Switcher\nExec={}\nTryExec={}\nX-GNOME-Autostart-enabled=true\n",
-        exec_line,
+        build_autostart_exec_line(exec_path, exec_args),
         escape_desktop_exec_arg(exec_path_str)
+    );
+    if let Some(delay) = conditions.delay_secs {
+        content.push_str(&format!("X-GNOME-Autostart-Delay={}\n", delay));
+    }
+    if !conditions.only_show_in.is_empty() {
+        content.push_str(&format!("OnlyShowIn={};\n", conditions.only_show_in.join(";")));
+    }
+    if !conditions.not_show_in.is_empty() {
+        content.push_str(&format!("NotShowIn={};\n", conditions.not_show_in.join(";")));
+    }
+    content
+}
+
+/// `ExecStart=` argument quoting: desktop-entry quoting already doubles `%`; systemd also
+/// expands `$`
+fn escape_systemd_exec_arg(value: &str) -> String {
+    escape_desktop_exec_arg(value).replace('$', "$$")
+}
+
+/// What an installed background instance starts with, recorded as comments in the autostart
+/// entry and printed by `--print-effective-invocation`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct InvocationMetadata {
+    /// Config file the instance reads (`--config`, else the first existing default)
+    config_path: PathBuf,
+    /// Desktop backend detected now; the daemon detects it again on every start
+    backend: Environment,
+}
+
+impl InvocationMetadata {
+    fn from_args(args: &Args) -> Self {
+        let config_path = resolve_config_path(args.config.as_deref());
+        Self {
+            config_path: std::path::absolute(&config_path).unwrap_or(config_path),
+            backend: detect_environment(),
+        }
+    }
+
+    /// `# Key: value` lines placed above `[Desktop Entry]`
+    fn comment_lines(&self) -> String {
+        let missing = if self.config_path.exists() {
+            ""
+        } else {
+            " (not found)"
+        };
+        format!(
+            "# Config: {}{}\n# Backend: {} (detected now; the daemon detects it again at startup)\n",
+            self.config_path.display(),
+            missing,
+            self.backend.as_str()
+        )
+    }
+}
+
+/// `--print-effective-invocation` output: the metadata comments, the autostart `Exec=` line and
+/// the equivalent `ExecStart=` line for a systemd user unit
+fn format_effective_invocation(
+    exec_path: &Path,
+    exec_args: &[String],
+    metadata: &InvocationMetadata,
+) -> String {
+    format!(
+        "{}Exec={}\nExecStart={}\n",
+        metadata.comment_lines(),
+        build_autostart_exec_line(exec_path, exec_args),
+        build_systemd_exec_start(exec_path, exec_args)
     )
 }
 
+/// Value of a systemd unit's `ExecStart=` for the daemon binary and `exec_args`
+fn build_systemd_exec_start(exec_path: &Path, exec_args: &[String]) -> String {
+    let exec_start: Vec<String> = std::iter::once(exec_path.to_string_lossy().to_string())
+        .chain(exec_args.iter().cloned())
+        .map(|arg| escape_systemd_exec_arg(&arg))
+        .collect();
+    exec_start.join(" ")
+}
+
+fn print_effective_invocation(
+    matches: &ArgMatches,
+    args: &Args,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let exec_path = resolve_binary_path()?;
+    let exec_args = autostart_passthrough_args(matches, args);
+    print!(
+        "{}",
+        format_effective_invocation(&exec_path, &exec_args, &InvocationMetadata::from_args(args))
+    );
+    Ok(())
+}
+
+/// Value of a `Key=value` line in the `[Desktop Entry]` group.
+fn desktop_entry_value<'a>(content: &'a str, key: &str) -> Option<&'a str> {
+    content
+        .lines()
+        .skip_while(|line| line.trim() != "[Desktop Entry]")
+        .skip(1)
+        .take_while(|line| !line.trim_start().starts_with('['))
+        .find_map(|line| {
+            let (line_key, value) = line.split_once('=')?;
+            (line_key.trim() == key).then(|| value.trim())
+        })
+}
+
+/// Compare an installed autostart entry with this invocation. Returns the rewritten entry
+/// (keeping its OnlyShowIn/NotShowIn) if the Exec line differs, None if it is in sync.
+fn autostart_sync_content(
+    installed: &str,
+    exec_path: &Path,
+    exec_args: &[String],
+    delay_secs: Option<u64>,
+) -> Option<String> {
+    let exec_line = build_autostart_exec_line(exec_path, exec_args);
+    if desktop_entry_value(installed, "Exec") == Some(exec_line.as_str()) {
+        return None;
+    }
+    let conditions = AutostartConditions {
+        delay_secs,
+        only_show_in: parse_desktop_list(desktop_entry_value(installed, "OnlyShowIn")),
+        not_show_in: parse_desktop_list(desktop_entry_value(installed, "NotShowIn")),
+    };
+    Some(build_autostart_desktop_content(
+        exec_path,
+        exec_args,
+        &conditions,
+    ))
+}
+
+/// Warn (or with --sync-autostart, fix) when the installed autostart entry would start the daemon
+/// with different options than the ones used now. Failures only warn: the daemon still starts.
+fn check_autostart_sync(matches: &ArgMatches, args: &Args) {
+    let Ok(desktop_path) = autostart_desktop_path(&DbusInstance::new(args.dbus_suffix.as_deref()))
+    else {
+        return;
+    };
+    let Ok(installed) = std::fs::read_to_string(&desktop_path) else {
+        return;
+    };
+    let exec_path = match resolve_binary_path() {
+        Ok(path) => path,
+        Err(e) => {
+            elog_line!("[Autostart] Cannot resolve binary path: {}", e);
+            return;
+        }
+    };
+    let exec_args = autostart_passthrough_args(matches, args);
+    let Some(content) =
+        autostart_sync_content(&installed, &exec_path, &exec_args, args.autostart_delay)
+    else {
+        return;
+    };
+    let content = format!("{}{}", InvocationMetadata::from_args(args).comment_lines(), content);
+    if !args.sync_autostart {
+        log_line!(
+            "[Autostart] {} starts the daemon with different options (Exec={}); rerun with --sync-autostart to update it",
+            desktop_path.display(),
+            desktop_entry_value(&installed, "Exec").unwrap_or_default()
+        );
+        return;
+    }
+    match std::fs::write(&desktop_path, content) {
+        Ok(()) => log_line!(
+            "[Autostart] Updated {} to match current options",
+            desktop_path.display()
+        ),
+        Err(e) => elog_line!(
+            "[Autostart] Failed to update {}: {}",
+            desktop_path.display(),
+            e
+        ),
+    }
+}
+
 fn autostart_passthrough_args(matches: &ArgMatches, args: &Args) -> Vec<String> {
     use clap::parser::ValueSource;
 
@@ -339,6 +963,60 @@ fn autostart_passthrough_args(matches: &ArgMatches, args: &Args) -> Vec<String>
                 exec_args.push("-H".to_string());
                 exec_args.push(args.host.clone());
             }
+            "kanata" => {
+                let endpoint = args
+                    .kanata
+                    .as_ref()
+                    .expect("kanata missing after command-line input");
+                exec_args.push("--kanata".to_string());
+                exec_args.push(endpoint.clone());
+            }
+            "kanata_tls_ca" => {
+                let ca = args
+                    .kanata_tls_ca
+                    .as_ref()
+                    .expect("kanata_tls_ca missing after command-line input");
+                exec_args.push("--kanata-tls-ca".to_string());
+                exec_args.push(ca.to_string_lossy().to_string());
+            }
+            "kanata_tls_pin" => {
+                for pin in &args.kanata_tls_pin {
+                    exec_args.push("--kanata-tls-pin".to_string());
+                    exec_args.push(format_cert_fingerprint(pin));
+                }
+            }
+            "kanata_tls_server_name" => {
+                let server_name = args
+                    .kanata_tls_server_name
+                    .as_ref()
+                    .expect("kanata_tls_server_name missing after command-line input");
+                exec_args.push("--kanata-tls-server-name".to_string());
+                exec_args.push(server_name.clone());
+            }
+            "kanata_write_timeout" => {
+                exec_args.push("--kanata-write-timeout".to_string());
+                exec_args.push(args.kanata_write_timeout.to_string());
+            }
+            "kanata_rate_limit" => {
+                exec_args.push("--kanata-rate-limit".to_string());
+                exec_args.push(args.kanata_rate_limit.to_string());
+            }
+            "connect_timeout" => {
+                let timeout = args
+                    .connect_timeout
+                    .expect("connect_timeout missing after command-line input");
+                exec_args.push("--connect-timeout".to_string());
+                exec_args.push(timeout.to_string());
+            }
+            "allow_offline" => {
+                exec_args.push("--allow-offline".to_string());
+            }
+            "notify_kanata" => {
+                exec_args.push("--notify-kanata".to_string());
+            }
+            "notify_layer_changes" => {
+                exec_args.push("--notify-layer-changes".to_string());
+            }
             "config" => {
                 let config = args
                     .config
@@ -347,12 +1025,27 @@ fn autostart_passthrough_args(matches: &ArgMatches, args: &Args) -> Vec<String>
                 exec_args.push("-c".to_string());
                 exec_args.push(config.to_string_lossy().to_string());
             }
+            "kanata_config" => {
+                let kanata_config = args
+                    .kanata_config
+                    .as_ref()
+                    .expect("kanata_config missing after command-line input");
+                exec_args.push("--kanata-config".to_string());
+                exec_args.push(kanata_config.to_string_lossy().to_string());
+            }
+            "unknown_layer" => {
+                exec_args.push("--unknown-layer".to_string());
+                exec_args.push(args.unknown_layer.as_arg().to_string());
+            }
             "quiet" => {
                 exec_args.push("-q".to_string());
             }
             "quiet_focus" => {
                 exec_args.push("--quiet-focus".to_string());
             }
+            "debug" => {
+                exec_args.push("--debug".to_string());
+            }
             "install_gnome_extension" => {
                 exec_args.push("--install-gnome-extension".to_string());
             }
@@ -362,6 +1055,47 @@ fn autostart_passthrough_args(matches: &ArgMatches, args: &Args) -> Vec<String>
             "no_indicator" => {
                 exec_args.push("--no-indicator".to_string());
             }
+            "no_vt_monitor" => {
+                exec_args.push("--no-vt-monitor".to_string());
+            }
+            "restore_layer" => {
+                exec_args.push("--restore-layer".to_string());
+            }
+            "pause_while_sharing" => {
+                exec_args.push("--pause-while-sharing".to_string());
+            }
+            "sharing_layer" => {
+                let layer = args
+                    .sharing_layer
+                    .as_ref()
+                    .expect("sharing_layer missing after command-line input");
+                exec_args.push("--sharing-layer".to_string());
+                exec_args.push(layer.clone());
+            }
+            "harden" => {
+                exec_args.push("--harden".to_string());
+            }
+            "session_id" => {
+                let session_id = args
+                    .session_id
+                    .as_ref()
+                    .expect("session_id missing after command-line input");
+                exec_args.push("--session-id".to_string());
+                exec_args.push(session_id.clone());
+            }
+            "seat" => {
+                let seat = args.seat.as_ref().expect("seat missing after command-line input");
+                exec_args.push("--seat".to_string());
+                exec_args.push(seat.clone());
+            }
+            "dbus_suffix" => {
+                let suffix = args
+                    .dbus_suffix
+                    .as_ref()
+                    .expect("dbus_suffix missing after command-line input");
+                exec_args.push("--dbus-suffix".to_string());
+                exec_args.push(suffix.clone());
+            }
             "indicator_focus_only" => {
                 let value = args
                     .indicator_focus_only
@@ -369,6 +1103,25 @@ fn autostart_passthrough_args(matches: &ArgMatches, args: &Args) -> Vec<String>
                 exec_args.push("--indicator-focus-only".to_string());
                 exec_args.push(value.as_arg().to_string());
             }
+            "autostart_delay" => {
+                let delay = args
+                    .autostart_delay
+                    .expect("autostart_delay missing after command-line input");
+                exec_args.push("--autostart-delay".to_string());
+                exec_args.push(delay.to_string());
+            }
+            "wait_for_unit" => {
+                let unit = args
+                    .wait_for_unit
+                    .as_ref()
+                    .expect("wait_for_unit missing after command-line input");
+                exec_args.push("--wait-for-unit".to_string());
+                exec_args.push(unit.clone());
+            }
+            "wait_for_unit_timeout" => {
+                exec_args.push("--wait-for-unit-timeout".to_string());
+                exec_args.push(args.wait_for_unit_timeout.to_string());
+            }
             _ => {
                 panic!("autostart passthrough option missing handler: {}", name);
             }
@@ -378,30 +1131,47 @@ fn autostart_passthrough_args(matches: &ArgMatches, args: &Args) -> Vec<String>
     exec_args
 }
 
-fn install_autostart_desktop(
-    matches: &ArgMatches,
-    args: &Args,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+fn check_autostart_option_lists() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     for option in AUTOSTART_ONESHOT_OPTIONS {
         if AUTOSTART_PASSTHROUGH_OPTIONS.contains(option) {
             return Err(format!("autostart option lists overlap: {}", option).into());
         }
     }
+    Ok(())
+}
+
+fn install_autostart_desktop(
+    matches: &ArgMatches,
+    args: &Args,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    check_autostart_option_lists()?;
     let exec_path = resolve_binary_path()?;
     let exec_args = autostart_passthrough_args(matches, args);
-    let content = build_autostart_desktop_content(&exec_path, &exec_args);
+    let conditions = AutostartConditions::from_args(args);
+    let metadata = InvocationMetadata::from_args(args);
+    let content = format!(
+        "{}{}",
+        metadata.comment_lines(),
+        build_autostart_desktop_content(&exec_path, &exec_args, &conditions)
+    );
 
-    let autostart_dir = autostart_dir()?;
-    std::fs::create_dir_all(&autostart_dir)?;
-    let desktop_path = autostart_dir.join(AUTOSTART_DESKTOP_FILENAME);
+    std::fs::create_dir_all(autostart_dir()?)?;
+    let desktop_path = autostart_desktop_path(&DbusInstance::new(args.dbus_suffix.as_deref()))?;
 
     std::fs::write(&desktop_path, content)?;
     println!("[Autostart] Installed {}", desktop_path.display());
+    println!(
+        "[Autostart] Config: {}, backend: {}",
+        metadata.config_path.display(),
+        metadata.backend.as_str()
+    );
     Ok(())
 }
 
-fn uninstall_autostart_desktop() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let desktop_path = autostart_desktop_path()?;
+fn uninstall_autostart_desktop(
+    instance: &DbusInstance,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let desktop_path = autostart_desktop_path(instance)?;
     if !desktop_path.exists() {
         return Err(format!("autostart entry not found: {}", desktop_path.display()).into());
     }
@@ -410,4559 +1180,16552 @@ fn uninstall_autostart_desktop() -> Result<(), Box<dyn std::error::Error + Send
     Ok(())
 }
 
-async fn send_control_command(
-    command: ControlCommand,
+// === systemd User Service ===
+
+const SYSTEMD_UNIT_STEM: &str = "kanata-switcher";
+/// Restart the daemon when it stops pinging for this long (it pings at half the interval)
+const SYSTEMD_WATCHDOG_SECS: u64 = 30;
+
+/// `kanata-switcher.service`, or `kanata-switcher-SUFFIX.service` for a `--dbus-suffix` instance
+fn systemd_unit_name(instance: &DbusInstance) -> String {
+    format!("{}{}.service", SYSTEMD_UNIT_STEM, instance.file_tag())
+}
+
+fn systemd_user_unit_dir(config_home: &Path) -> PathBuf {
+    config_home.join("systemd").join("user")
+}
+
+/// `--install-systemd-service` unit: started and stopped with the graphical session, ready once
+/// the backend runs (`READY=1`), restarted when it crashes or stops answering the watchdog.
+/// Start-up has no timeout because the daemon waits for kanata before reporting ready.
+fn build_systemd_unit_content(
+    exec_path: &Path,
+    exec_args: &[String],
+    metadata: &InvocationMetadata,
+) -> String {
+    format!(
+        "{}[Unit]\nDescription=Kanata layer switcher daemon\nAfter=graphical-session.target\nPartOf=graphical-session.target\n\n[Service]\nType=notify\nExecStart={}\nRestart=on-failure\nRestartSec=5\nTimeoutStartSec=infinity\nWatchdogSec={}\n\n[Install]\nWantedBy=graphical-session.target\n",
+        metadata.comment_lines(),
+        build_systemd_exec_start(exec_path, exec_args),
+        SYSTEMD_WATCHDOG_SECS
+    )
+}
+
+/// `systemctl --user ARGS`, failing when systemctl is missing too
+fn run_systemctl_user(args: &[&str]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let args: Vec<&str> = std::iter::once("--user").chain(args.iter().copied()).collect();
+    match run_setup_command("systemctl", &args)? {
+        true => Ok(()),
+        false => Err("systemctl not found (is systemd the service manager?)".into()),
+    }
+}
+
+fn install_systemd_service(
+    matches: &ArgMatches,
+    args: &Args,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let connection = Connection::session().await?;
-    send_control_command_with_connection(&connection, command).await?;
+    check_autostart_option_lists()?;
+    let exec_path = resolve_binary_path()?;
+    let exec_args = autostart_passthrough_args(matches, args);
+    let metadata = InvocationMetadata::from_args(args);
+    let instance = DbusInstance::new(args.dbus_suffix.as_deref());
+    let unit = systemd_unit_name(&instance);
+    let unit_dir = systemd_user_unit_dir(&xdg_config_home()?);
+
+    std::fs::create_dir_all(&unit_dir)?;
+    let unit_path = unit_dir.join(&unit);
+    std::fs::write(
+        &unit_path,
+        build_systemd_unit_content(&exec_path, &exec_args, &metadata),
+    )?;
+    println!("[Systemd] Installed {}", unit_path.display());
     println!(
-        "[Control] Sent {} request to running daemon",
-        command.label()
+        "[Systemd] Config: {}, backend: {}",
+        metadata.config_path.display(),
+        metadata.backend.as_str()
+    );
+    run_systemctl_user(&["daemon-reload"])?;
+    run_systemctl_user(&["enable", &unit])?;
+    println!(
+        "[Systemd] Enabled {}; it starts with the next graphical session (now: systemctl --user start {})",
+        unit, unit
     );
+    let autostart_path = autostart_desktop_path(&instance)?;
+    if autostart_path.exists() {
+        eprintln!(
+            "[Systemd] Warning: {} starts the daemon too; remove it with --uninstall-autostart",
+            autostart_path.display()
+        );
+    }
     Ok(())
 }
 
-async fn send_control_command_with_connection(
-    connection: &Connection,
-    command: ControlCommand,
+/// Stop and disable a user unit, then remove its file
+fn remove_systemd_unit(unit_path: &Path, errors: &mut Vec<String>) {
+    let unit = unit_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if let Err(error) = run_setup_command("systemctl", &["--user", "disable", "--now", &unit]) {
+        errors.push(error);
+    }
+    remove_uninstall_path(unit_path, errors);
+}
+
+fn uninstall_systemd_service(
+    instance: &DbusInstance,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    connection
-        .call_method(
-            Some(DBUS_NAME),
-            DBUS_PATH,
-            Some(DBUS_INTERFACE),
-            command.dbus_method(),
-            &(),
-        )
-        .await?;
+    let unit_path = systemd_user_unit_dir(&xdg_config_home()?).join(systemd_unit_name(instance));
+    if !unit_path.exists() {
+        return Err(format!("systemd unit not found: {}", unit_path.display()).into());
+    }
+    let mut errors = Vec::new();
+    remove_systemd_unit(&unit_path, &mut errors);
+    if let Err(error) = run_setup_command("systemctl", &["--user", "daemon-reload"]) {
+        errors.push(error);
+    }
+    if !errors.is_empty() {
+        return Err(errors.join("; ").into());
+    }
     Ok(())
 }
 
-// === Config ===
+// === Application Launcher Entry ===
 
-/// A rule for matching windows and triggering actions.
-/// At least one of `layer`, `virtual_key`, or `raw_vk_action` should be specified.
-#[derive(Debug, Clone, Deserialize)]
-#[serde(deny_unknown_fields)]
-struct Rule {
-    class: Option<String>,
-    title: Option<String>,
-    /// Layer to switch to when switching to a native terminal (VT)
-    on_native_terminal: Option<String>,
-    /// Layer to switch to when rule matches
-    layer: Option<String>,
-    /// Virtual key to press while window is focused (auto-released on unfocus)
-    virtual_key: Option<String>,
-    /// Raw virtual key actions to fire on focus (fire-and-forget)
-    /// Format: [["vk_name", "Press|Release|Tap|Toggle"], ...]
-    raw_vk_action: Option<Vec<(String, String)>>,
-    /// Continue matching subsequent rules after this one
-    #[serde(default)]
-    fallthrough: bool,
+const DESKTOP_APP_FILENAME: &str = "com.github.kanata.Switcher.desktop";
+const DESKTOP_ICON_NAME: &str = "kanata-switcher";
+const DESKTOP_ICON_SVG: &str = include_str!("../icons/kanata-switcher.svg");
+const KSWITCHCTL_BINARY_NAME: &str = "kswitchctl";
+
+/// What the launcher entry opens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DesktopLauncher {
+    /// GTK settings window (`--settings`, `settings-gui` builds)
+    Settings,
+    /// `kswitchctl monitor` in a terminal
+    Monitor(PathBuf),
 }
 
-#[derive(Debug, Clone)]
-struct NativeTerminalRule {
-    layer: String,
-    virtual_key: Option<String>,
-    raw_vk_action: Vec<(String, String)>,
+fn xdg_data_home() -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    if let Ok(xdg_data_home) = env::var("XDG_DATA_HOME") {
+        if xdg_data_home.is_empty() {
+            return Err("XDG_DATA_HOME is empty".into());
+        }
+        return Ok(PathBuf::from(xdg_data_home));
+    }
+    let home = env::var("HOME")?;
+    if home.is_empty() {
+        return Err("HOME is empty".into());
+    }
+    Ok(PathBuf::from(home).join(".local").join("share"))
 }
 
-#[derive(Debug, Clone)]
-enum ConfigEntry {
-    Default { default: String },
-    Rule(Rule),
+fn desktop_app_path(data_home: &Path) -> PathBuf {
+    data_home.join("applications").join(DESKTOP_APP_FILENAME)
 }
 
-impl<'de> serde::Deserialize<'de> for ConfigEntry {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        use serde::de::Error;
+fn desktop_icon_path(data_home: &Path) -> PathBuf {
+    data_home
+        .join("icons")
+        .join("hicolor")
+        .join("scalable")
+        .join("apps")
+        .join(format!("{}.svg", DESKTOP_ICON_NAME))
+}
 
-        let value = serde_json::Value::deserialize(deserializer)?;
+fn resolve_desktop_launcher(
+    daemon_path: &Path,
+) -> Result<DesktopLauncher, Box<dyn std::error::Error + Send + Sync>> {
+    if cfg!(feature = "settings-gui") {
+        return Ok(DesktopLauncher::Settings);
+    }
+    // kswitchctl is built and installed next to the daemon
+    let kswitchctl = daemon_path.with_file_name(KSWITCHCTL_BINARY_NAME);
+    if !kswitchctl.exists() {
+        return Err(format!(
+            "no settings UI available: build with the settings-gui feature or install {} next to {}",
+            KSWITCHCTL_BINARY_NAME,
+            daemon_path.display()
+        )
+        .into());
+    }
+    Ok(DesktopLauncher::Monitor(kswitchctl))
+}
 
-        // Check if it's a "default" entry
-        if let Some(obj) = value.as_object() {
-            if obj.contains_key("default") {
-                if obj.len() == 1 {
-                    if let Some(default) = obj.get("default").and_then(|v| v.as_str()) {
-                        return Ok(ConfigEntry::Default {
-                            default: default.to_string(),
-                        });
-                    }
-                }
-                return Err(D::Error::custom(
-                    "'default' entry should only contain the 'default' field",
+fn build_app_desktop_content(
+    daemon_path: &Path,
+    launcher: &DesktopLauncher,
+    config: Option<&Path>,
+) -> String {
+    let daemon = escape_desktop_exec_arg(
+        daemon_path
+            .to_str()
+            .expect("desktop exec path contains invalid UTF-8"),
+    );
+    let (name, comment, exec, terminal) = match launcher {
+        DesktopLauncher::Settings => {
+            let mut exec = format!("{} {}", daemon, escape_desktop_exec_arg("--settings"));
+            if let Some(config) = config {
+                exec.push_str(&format!(
+                    " {} {}",
+                    escape_desktop_exec_arg("-c"),
+                    escape_desktop_exec_arg(&config.to_string_lossy())
                 ));
             }
+            (
+                "Kanata Switcher Settings",
+                "Configure kanata layer switching",
+                exec,
+                false,
+            )
         }
-
-        // Try to parse as Rule with custom error handling for unknown fields
-        let known_fields = [
-            "class",
-            "title",
-            "on_native_terminal",
-            "layer",
-            "virtual_key",
-            "raw_vk_action",
-            "fallthrough",
-        ];
-
-        if let Some(obj) = value.as_object() {
-            for key in obj.keys() {
-                if !known_fields.contains(&key.as_str()) {
-                    return Err(D::Error::custom(format!(
-                        "unknown field '{}'. Valid fields are: class, title, on_native_terminal, layer, virtual_key, raw_vk_action, fallthrough",
-                        key
-                    )));
-                }
-            }
-        }
-
-        serde_json::from_value(value).map(ConfigEntry::Rule).map_err(D::Error::custom)
+        DesktopLauncher::Monitor(kswitchctl) => (
+            "Kanata Switcher Monitor",
+            "Watch focus events and kanata layers",
+            format!(
+                "{} {}",
+                escape_desktop_exec_arg(
+                    kswitchctl
+                        .to_str()
+                        .expect("desktop exec path contains invalid UTF-8")
+                ),
+                escape_desktop_exec_arg("monitor")
+            ),
+            true,
+        ),
+    };
+    let mut content = format!(
+        "[Desktop Entry]\nType=Application\nName={}\nComment={}\nIcon={}\nExec={}\nTerminal={}\nCategories=Settings;Utility;\nKeywords=kanata;keyboard;layer;\nActions=pause;unpause;restart;\n",
+        name, comment, DESKTOP_ICON_NAME, exec, terminal
+    );
+    if *launcher == DesktopLauncher::Settings {
+        content.push_str(&format!("StartupWMClass={}\n", SETTINGS_APP_ID));
+    }
+    for (action, label, flag) in [
+        ("pause", "Pause Switching", "--pause"),
+        ("unpause", "Resume Switching", "--unpause"),
+        ("restart", "Restart Daemon", "--restart"),
+    ] {
+        content.push_str(&format!(
+            "\n[Desktop Action {}]\nName={}\nExec={} {}\n",
+            action,
+            label,
+            daemon,
+            escape_desktop_exec_arg(flag)
+        ));
     }
+    content
 }
 
-#[derive(Debug, Clone)]
-struct Config {
-    rules: Vec<Rule>,
-    default_layer: Option<String>,
-    native_terminal_rule: Option<NativeTerminalRule>,
-}
+fn install_desktop_entry(args: &Args) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let daemon_path = resolve_binary_path()?;
+    let launcher = resolve_desktop_launcher(&daemon_path)?;
+    let data_home = xdg_data_home()?;
 
-#[derive(Debug, Clone, Default, Deserialize)]
-struct WindowInfo {
-    class: String,
-    title: String,
-    #[serde(default)]
-    is_native_terminal: bool,
-}
+    let icon_path = desktop_icon_path(&data_home);
+    if let Some(parent) = icon_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&icon_path, DESKTOP_ICON_SVG)?;
+    println!("[Desktop] Installed {}", icon_path.display());
 
-fn load_config(config_path: Option<&Path>) -> Config {
-    let path = config_path.map(|p| p.to_path_buf()).unwrap_or_else(|| {
-        let xdg_config = env::var("XDG_CONFIG_HOME")
-            .map(PathBuf::from)
-            .unwrap_or_else(|_| dirs::home_dir().unwrap().join(".config"));
-        xdg_config.join("kanata").join("kanata-switcher.json")
-    });
+    let desktop_path = desktop_app_path(&data_home);
+    if let Some(parent) = desktop_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let content = build_app_desktop_content(&daemon_path, &launcher, args.config.as_deref());
+    std::fs::write(&desktop_path, content)?;
+    println!("[Desktop] Installed {}", desktop_path.display());
+    Ok(())
+}
 
-    if !path.exists() {
-        eprintln!("[Config] Error: Config file not found: {}", path.display());
-        eprintln!();
-        eprintln!("Example config:");
-        eprintln!(
-            r#"[
-  {{"default": "base"}},
-  {{"on_native_terminal": "tty"}},
-  {{"class": "firefox", "layer": "browser"}},
-  {{"class": "alacritty", "title": "vim", "layer": "vim"}}
-]"#
-        );
-        std::process::exit(1);
+fn uninstall_desktop_entry() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let data_home = xdg_data_home()?;
+    let mut removed = false;
+    for path in [desktop_app_path(&data_home), desktop_icon_path(&data_home)] {
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+            println!("[Desktop] Removed {}", path.display());
+            removed = true;
+        }
     }
+    if !removed {
+        return Err(format!(
+            "application entry not found: {}",
+            desktop_app_path(&data_home).display()
+        )
+        .into());
+    }
+    Ok(())
+}
 
-    match fs::read_to_string(&path) {
-        Ok(content) => match serde_json::from_str::<Vec<ConfigEntry>>(&content) {
-            Ok(entries) => {
-                let mut rules = Vec::new();
-                let mut default_layer: Option<String> = None;
-                let mut native_terminal_rule: Option<NativeTerminalRule> = None;
+// === Uninstall ===
 
-                for entry in entries {
-                    match entry {
-                        ConfigEntry::Default { default } => {
-                            if default_layer.is_some() {
-                                eprintln!(
-                                    "[Config] Error: multiple 'default' entries found, only one allowed"
-                                );
-                                std::process::exit(1);
-                            }
-                            default_layer = Some(default);
-                        }
-                        ConfigEntry::Rule(rule) => {
-                            if let Some(layer) = rule.on_native_terminal.clone() {
-                                if rule.class.is_some() || rule.title.is_some() {
-                                    eprintln!(
-                                        "[Config] Error: 'on_native_terminal' cannot be combined with 'class' or 'title'"
-                                    );
-                                    std::process::exit(1);
-                                }
-                                if rule.layer.is_some() {
-                                    eprintln!(
-                                        "[Config] Error: 'on_native_terminal' cannot be combined with 'layer'"
-                                    );
-                                    std::process::exit(1);
-                                }
-                                if native_terminal_rule.is_some() {
-                                    eprintln!(
-                                        "[Config] Error: multiple 'on_native_terminal' rules found, only one allowed"
-                                    );
-                                    std::process::exit(1);
-                                }
-                                native_terminal_rule = Some(NativeTerminalRule {
-                                    layer,
-                                    virtual_key: rule.virtual_key.clone(),
-                                    raw_vk_action: rule.raw_vk_action.clone().unwrap_or_default(),
-                                });
-                            } else {
-                                // Rule with no matchers and no fallthrough would match everything
-                                // and stop further matching, which is almost certainly a bug
-                                if rule.class.is_none()
-                                    && rule.title.is_none()
-                                    && !rule.fallthrough
-                                {
-                                    eprintln!(
-                                        "[Config] Error: Rule with no 'class' or 'title' matcher requires 'fallthrough: true'"
-                                    );
-                                    eprintln!(
-                                        "[Config] Hint: A catch-all rule without fallthrough would match all windows and stop further matching"
-                                    );
-                                    std::process::exit(1);
-                                }
-                                rules.push(rule);
-                            }
-                        }
-                    }
-                }
+const KWIN_SCRIPT_PREFIX: &str = "kanata-switcher-kwin-";
 
-                println!(
-                    "[Config] Loaded {} rules from {}",
-                    rules.len(),
-                    path.display()
-                );
+/// Files and directories `--uninstall` removes (when present).
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct UninstallTargets {
+    systemd_unit: PathBuf,
+    autostart_entry: PathBuf,
+    desktop_entry: PathBuf,
+    desktop_icon: PathBuf,
+    /// Extension directory
+    gnome_extension: PathBuf,
+    /// Runtime state directory (`$XDG_STATE_HOME/kanata-switcher`)
+    state_dir: PathBuf,
+}
 
-                Config {
-                    rules,
-                    default_layer,
-                    native_terminal_rule,
-                }
-            }
-            Err(e) => {
-                eprintln!("[Config] Error: Failed to parse {}: {}", path.display(), e);
-                std::process::exit(1);
-            }
-        },
-        Err(e) => {
-            eprintln!("[Config] Error: Failed to read {}: {}", path.display(), e);
-            std::process::exit(1);
+impl UninstallTargets {
+    fn new(config_home: &Path, data_home: &Path, state_home: &Path) -> Self {
+        Self {
+            systemd_unit: systemd_user_unit_dir(config_home)
+                .join(systemd_unit_name(&DbusInstance::new(None))),
+            autostart_entry: config_home
+                .join("autostart")
+                .join(AUTOSTART_DESKTOP_FILENAME),
+            desktop_entry: desktop_app_path(data_home),
+            desktop_icon: desktop_icon_path(data_home),
+            gnome_extension: data_home
+                .join("gnome-shell")
+                .join("extensions")
+                .join(GNOME_EXTENSION_UUID),
+            state_dir: state_home.join(STATE_DIR_NAME),
         }
     }
 }
 
-fn match_pattern(pattern: Option<&str>, value: &str) -> bool {
-    match pattern {
-        None => true,
-        Some("*") => true,
-        Some(pat) => match Regex::new(pat) {
-            Ok(re) => re.is_match(value),
-            Err(_) => value.contains(pat),
-        },
-    }
+/// Files of `--dbus-suffix` instances in `dir` (`kanata-switcher-SUFFIX.EXTENSION`: autostart
+/// entries, systemd units).
+fn instance_files(dir: &Path, extension: &str) -> Vec<PathBuf> {
+    let prefix = format!("{}-", AUTOSTART_DESKTOP_STEM);
+    let suffix = format!(".{}", extension);
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(&suffix))
+        })
+        .collect();
+    paths.sort();
+    paths
 }
 
-// === Focus Handler ===
-
-/// Individual action to execute on focus change
-#[derive(Debug, Clone, PartialEq, Eq)]
-enum FocusAction {
-    /// Release a virtual key
-    ReleaseVk(String),
-    /// Switch to a layer
-    ChangeLayer(String),
-    /// Press and hold a virtual key (managed - will be released on next focus change)
-    PressVk(String),
-    /// Raw VK action (name, action: Press/Release/Tap/Toggle)
-    RawVkAction(String, String),
+/// KWin scripts this user's daemons wrote to `tmp_dir` (focus scripts of every instance and
+/// per-query scripts).
+fn kwin_script_leftovers(tmp_dir: &Path, uid: u32) -> Vec<PathBuf> {
+    let focus_script = format!("{}{}.js", KWIN_SCRIPT_PREFIX, uid);
+    let instance_prefix = format!("{}{}-", KWIN_SCRIPT_PREFIX, uid);
+    let query_prefix = format!("{}query-{}-", KWIN_SCRIPT_PREFIX, uid);
+    let Ok(entries) = fs::read_dir(tmp_dir) else {
+        return Vec::new();
+    };
+    let mut leftovers: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    name == focus_script
+                        || ((name.starts_with(&query_prefix) || name.starts_with(&instance_prefix))
+                            && name.ends_with(".js"))
+                })
+        })
+        .collect();
+    leftovers.sort();
+    leftovers
 }
 
-/// Actions to execute on focus change, in order.
-/// With fallthrough, all matching actions are collected and executed sequentially.
-#[derive(Debug, Default, Clone, PartialEq, Eq)]
-struct FocusActions {
-    /// Ordered list of actions to execute
-    actions: Vec<FocusAction>,
-    /// The new ordered list of managed VKs after execution (pressed top-to-bottom, released bottom-to-top)
-    new_managed_vks: Vec<String>,
+/// Run an install or cleanup command; a missing tool is not an error (nothing of its kind can be
+/// installed).
+fn run_setup_command(program: &str, args: &[&str]) -> Result<bool, String> {
+    match Command::new(program).args(args).output() {
+        Ok(output) if output.status.success() => Ok(true),
+        Ok(output) => Err(format!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(error) => Err(format!("{} failed: {}", program, error)),
+    }
 }
 
-impl FocusActions {
-    fn is_empty(&self) -> bool {
-        self.actions.is_empty()
+fn remove_uninstall_path(path: &Path, errors: &mut Vec<String>) {
+    let result = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    match result {
+        Ok(()) => println!("[Uninstall] Removed {}", path.display()),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+        Err(error) => errors.push(format!("{}: {}", path.display(), error)),
     }
 }
 
-const NATIVE_TERMINAL_RULE_INDEX: usize = usize::MAX;
+fn uninstall_everything() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let targets = UninstallTargets::new(&xdg_config_home()?, &xdg_data_home()?, &xdg_state_home()?);
+    let mut errors = Vec::new();
 
-#[derive(Debug)]
-struct FocusHandler {
-    rules: Vec<Rule>,
-    native_terminal_rule: Option<NativeTerminalRule>,
-    last_class: String,
-    last_title: String,
-    last_matched_rules: Vec<usize>,
-    last_effective_layer: String,
-    /// Currently held virtual keys, in order they were pressed (top-to-bottom rule order)
-    current_virtual_keys: Vec<String>,
-    quiet_focus: bool,
-}
+    // systemd user units: stop the running daemons before removing the units
+    let mut systemd_units = Vec::new();
+    if targets.systemd_unit.exists() {
+        systemd_units.push(targets.systemd_unit.clone());
+    }
+    if let Some(unit_dir) = targets.systemd_unit.parent() {
+        systemd_units.extend(instance_files(unit_dir, "service"));
+    }
+    for unit_path in &systemd_units {
+        remove_systemd_unit(unit_path, &mut errors);
+    }
+    if !systemd_units.is_empty()
+        && let Err(error) = run_setup_command("systemctl", &["--user", "daemon-reload"])
+    {
+        errors.push(error);
+    }
 
-impl FocusHandler {
-    fn new(
-        rules: Vec<Rule>,
-        native_terminal_rule: Option<NativeTerminalRule>,
-        quiet_focus: bool,
-    ) -> Self {
-        Self {
-            rules,
-            native_terminal_rule,
-            last_class: String::new(),
-            last_title: String::new(),
-            last_matched_rules: Vec::new(),
-            last_effective_layer: String::new(),
-            current_virtual_keys: Vec::new(),
-            quiet_focus,
+    // GNOME extension (gnome-extensions also disables it); remove leftovers directly
+    if targets.gnome_extension.exists() {
+        if let Err(error) =
+            run_setup_command("gnome-extensions", &["uninstall", GNOME_EXTENSION_UUID])
+        {
+            eprintln!("[Uninstall] {}", error);
         }
+        remove_uninstall_path(&targets.gnome_extension, &mut errors);
     }
 
-    /// Handle a focus change event. Returns actions to execute.
-    /// With fallthrough, ALL matching actions are collected and executed in order.
-    /// All matched virtual_keys are pressed and held simultaneously.
-    fn handle(&mut self, win: &WindowInfo, default_layer: &str) -> Option<FocusActions> {
-        let mut result = FocusActions::default();
-
-        if win.is_native_terminal {
-            return self.handle_native_terminal(default_layer);
+    for path in [
+        &targets.autostart_entry,
+        &targets.desktop_entry,
+        &targets.desktop_icon,
+        &targets.state_dir,
+    ] {
+        remove_uninstall_path(path, &mut errors);
+    }
+    if let Some(autostart_dir) = targets.autostart_entry.parent() {
+        for path in instance_files(autostart_dir, "desktop") {
+            remove_uninstall_path(&path, &mut errors);
         }
+    }
 
-        // Handle unfocused state (no window has focus)
-        if win.class.is_empty() && win.title.is_empty() {
-            return self.handle_unfocused(default_layer);
-        }
+    let uid = unsafe { libc::getuid() };
+    for path in kwin_script_leftovers(Path::new("/tmp"), uid) {
+        remove_uninstall_path(&path, &mut errors);
+    }
 
-        if !self.quiet_focus {
-            println!("[Focus] class=\"{}\" title=\"{}\"", win.class, win.title);
+    if !errors.is_empty() {
+        for error in &errors {
+            eprintln!("[Uninstall] {}", error);
         }
+        return Err(format!("uninstall incomplete: {} step(s) failed", errors.len()).into());
+    }
+    println!("[Uninstall] Done. Config files and the kanata-switcher binary were left in place.");
+    Ok(())
+}
 
-        // Match rules with fallthrough support
-        struct MatchedRule {
-            index: usize,
-            layer: Option<String>,
-            virtual_key: Option<String>,
-            raw_vk_actions: Vec<(String, String)>,
-        }
+async fn send_control_command(
+    command: ControlCommand,
+    instance: &DbusInstance,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let connection = Connection::session().await?;
+    send_control_command_with_connection(&connection, command, instance).await?;
+    println!(
+        "[Control] Sent {} request to running daemon",
+        command.label()
+    );
+    Ok(())
+}
 
-        let mut matched_rules: Vec<MatchedRule> = Vec::new();
+async fn send_control_command_with_connection(
+    connection: &Connection,
+    command: ControlCommand,
+    instance: &DbusInstance,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    connection
+        .call_method(
+            Some(instance.name().as_str()),
+            instance.path().as_str(),
+            Some(DBUS_INTERFACE),
+            command.dbus_method(),
+            &(),
+        )
+        .await?;
+    Ok(())
+}
 
-        for (index, rule) in self.rules.iter().enumerate() {
-            if match_pattern(rule.class.as_deref(), &win.class)
-                && match_pattern(rule.title.as_deref(), &win.title)
-            {
-                matched_rules.push(MatchedRule {
-                    index,
-                    layer: rule.layer.clone(),
-                    virtual_key: rule.virtual_key.clone(),
-                    raw_vk_actions: rule.raw_vk_action.clone().unwrap_or_default(),
-                });
+/// The running daemon's state, for `--status`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct DaemonStatus {
+    layer: String,
+    /// Display name from the config's `layer_names`, if the layer has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    layer_display: Option<String>,
+    virtual_keys: Vec<String>,
+    source: String,
+    paused: bool,
+    /// None for daemons without `GetKanataConnected`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kanata_connected: Option<bool>,
+    /// Degraded-mode problems from `GetHealth` ("component: problem")
+    problems: Vec<String>,
+}
 
-                if !rule.fallthrough {
-                    break;
-                }
-            }
+impl DaemonStatus {
+    fn format(&self, json: bool) -> String {
+        if json {
+            return serde_json::to_string(self).expect("status serialization cannot fail");
+        }
+        let layer = match &self.layer_display {
+            Some(display) => format!("{} ({})", display, self.layer),
+            None => self.layer.clone(),
+        };
+        let virtual_keys = if self.virtual_keys.is_empty() {
+            "none".to_string()
+        } else {
+            self.virtual_keys.join(", ")
+        };
+        let kanata = match self.kanata_connected {
+            Some(true) => "connected",
+            Some(false) => "disconnected",
+            None => "unknown",
+        };
+        let mut lines = vec![
+            format!("layer: {}", layer),
+            format!("virtual keys: {}", virtual_keys),
+            format!("source: {}", self.source),
+            format!("paused: {}", if self.paused { "yes" } else { "no" }),
+            format!("kanata: {}", kanata),
+        ];
+        if !self.problems.is_empty() {
+            lines.push(format!("degraded: {}", self.problems.join("; ")));
         }
+        lines.join("\n")
+    }
+}
 
-        let matched_indices: Vec<usize> = matched_rules.iter().map(|rule| rule.index).collect();
+async fn print_daemon_status(
+    instance: &DbusInstance,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let connection = Connection::session().await?;
+    let status = query_daemon_status(&connection, instance)
+        .await
+        .map_err(|error| format!("cannot query the running daemon: {}", error))?;
+    println!("{}", status.format(json));
+    Ok(())
+}
 
-        // Collect all VKs from matched rules in order (for holding)
-        let new_vks: Vec<String> = matched_rules
-            .iter()
-            .filter_map(|r| r.virtual_key.clone())
-            .collect();
+/// `--healthcheck` outcome, most severe first when several apply
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HealthCheck {
+    NotRunning,
+    KanataDisconnected,
+    Degraded(Vec<String>),
+    Healthy,
+}
 
-        // Release VKs that are no longer matched (in reverse order)
-        for vk in self.current_virtual_keys.iter().rev() {
-            if !new_vks.contains(vk) {
-                result.actions.push(FocusAction::ReleaseVk(vk.clone()));
+impl HealthCheck {
+    /// `None` = no daemon answered. Daemons without `GetKanataConnected` count as connected
+    fn from_status(status: Option<&DaemonStatus>) -> Self {
+        match status {
+            None => HealthCheck::NotRunning,
+            Some(status) if status.kanata_connected == Some(false) => {
+                HealthCheck::KanataDisconnected
+            }
+            Some(status) if !status.problems.is_empty() => {
+                HealthCheck::Degraded(status.problems.clone())
             }
+            Some(_) => HealthCheck::Healthy,
         }
+    }
 
-        // If no rules matched, use default layer
-        if matched_rules.is_empty() {
-            if !default_layer.is_empty() && self.last_effective_layer != default_layer {
-                result
-                    .actions
-                    .push(FocusAction::ChangeLayer(default_layer.to_string()));
-            }
-            result.new_managed_vks = Vec::new();
-            self.last_effective_layer = default_layer.to_string();
-        } else {
-            let matched_changed = matched_indices != self.last_matched_rules;
-            let mut matched_layers: Vec<String> = Vec::new();
-            for matched in &matched_rules {
-                if let Some(layer) = matched.layer.clone() {
-                    matched_layers.push(layer);
-                }
-            }
-            let new_rules: Vec<usize> = matched_indices
-                .iter()
-                .cloned()
-                .filter(|idx| !self.last_matched_rules.contains(idx))
-                .collect();
+    fn exit_code(&self) -> i32 {
+        match self {
+            HealthCheck::Healthy => 0,
+            HealthCheck::Degraded(_) => 1,
+            HealthCheck::KanataDisconnected => 2,
+            HealthCheck::NotRunning => 3,
+        }
+    }
 
-            // Process matched rules in order, building action list
-            for matched in matched_rules {
-                let is_new = new_rules.contains(&matched.index);
-                if is_new {
-                    // Layer change
-                    if let Some(layer) = matched.layer {
-                        result.actions.push(FocusAction::ChangeLayer(layer));
-                    }
+    fn message(&self) -> String {
+        match self {
+            HealthCheck::Healthy => "OK: daemon running, kanata connected".to_string(),
+            HealthCheck::Degraded(problems) => format!("DEGRADED: {}", problems.join("; ")),
+            HealthCheck::KanataDisconnected => {
+                "CRITICAL: daemon running, kanata disconnected".to_string()
+            }
+            HealthCheck::NotRunning => "CRITICAL: kanata-switcher is not running".to_string(),
+        }
+    }
+}
 
-                    // Virtual key: press if not already held
-                    if let Some(ref vk) = matched.virtual_key {
-                        if !self.current_virtual_keys.contains(vk) {
-                            result.actions.push(FocusAction::PressVk(vk.clone()));
-                        }
-                    }
+/// `--healthcheck`: prints one line and returns the exit code
+async fn run_healthcheck(instance: &DbusInstance) -> i32 {
+    let status = match Connection::session().await {
+        Ok(connection) => query_daemon_status(&connection, instance).await.ok(),
+        Err(_) => None,
+    };
+    let result = HealthCheck::from_status(status.as_ref());
+    println!("{}", result.message());
+    result.exit_code()
+}
 
-                    // Raw VK actions
-                    for (name, action) in matched.raw_vk_actions {
-                        result.actions.push(FocusAction::RawVkAction(name, action));
-                    }
-                }
-            }
+/// `GetStatus` and `GetPaused`, plus what newer daemons add (display names, kanata connection,
+/// health); those are left empty when the daemon doesn't have them
+async fn query_daemon_status(
+    connection: &Connection,
+    instance: &DbusInstance,
+) -> zbus::Result<DaemonStatus> {
+    let proxy =
+        zbus::Proxy::new(connection, instance.name(), instance.path(), DBUS_INTERFACE).await?;
+    let reply = proxy.call_method("GetStatus", &()).await?;
+    let body = reply.body();
+    let (layer, virtual_keys, source) = body
+        .deserialize::<(String, Vec<String>, String, Vec<i32>)>()
+        .map(|(layer, virtual_keys, source, _)| (layer, virtual_keys, source))
+        .or_else(|_| body.deserialize::<(String, Vec<String>, String)>())?;
+    let paused: bool = proxy.call("GetPaused", &()).await?;
+    let display_names: BTreeMap<String, String> =
+        proxy.call("GetLayerDisplayNames", &()).await.unwrap_or_default();
+    let kanata_connected = proxy.call("GetKanataConnected", &()).await.ok();
+    let problems = proxy
+        .call::<_, _, (bool, Vec<String>)>("GetHealth", &())
+        .await
+        .map(|(_, problems)| problems)
+        .unwrap_or_default();
+    Ok(DaemonStatus {
+        layer_display: display_names.get(&layer).cloned(),
+        layer,
+        virtual_keys,
+        source,
+        paused,
+        kanata_connected,
+        problems,
+    })
+}
 
-            if matched_changed {
-                if let Some(new_layer) = matched_layers.last().cloned() {
-                    if self.last_effective_layer != new_layer {
-                        let has_new_layer = result.actions.iter().rev().find_map(|action| {
-                            if let FocusAction::ChangeLayer(layer) = action {
-                                Some(layer == &new_layer)
-                            } else {
-                                None
-                            }
-                        });
-                        if has_new_layer != Some(true) {
-                            result
-                                .actions
-                                .push(FocusAction::ChangeLayer(new_layer.clone()));
-                        }
-                    }
-                    self.last_effective_layer = new_layer;
-                }
+/// One `--follow-status` line in waybar's custom module format (`"return-type": "json"`);
+/// `None` = no daemon running. `class` is the kanata layer name, or `paused`/`stopped`
+fn waybar_status_line(status: Option<&DaemonStatus>) -> String {
+    let (text, class, tooltip) = match status {
+        None => (
+            String::new(),
+            "stopped".to_string(),
+            "kanata-switcher is not running".to_string(),
+        ),
+        Some(status) => {
+            let name = status.layer_display.as_ref().unwrap_or(&status.layer);
+            if status.paused {
+                (format!("{} (paused)", name), "paused".to_string(), status.format(false))
+            } else {
+                (name.clone(), status.layer.clone(), status.format(false))
             }
+        }
+    };
+    serde_json::json!({ "text": text, "class": class, "tooltip": tooltip }).to_string()
+}
 
-            result.new_managed_vks = new_vks;
+/// `--follow-status`: emits the status line, then re-queries and emits it again whenever the
+/// daemon signals a status or pause change or appears/disappears. Unchanged lines are skipped
+async fn follow_daemon_status(
+    connection: &Connection,
+    instance: &DbusInstance,
+    mut emit: impl FnMut(String),
+) -> zbus::Result<()> {
+    let proxy =
+        zbus::Proxy::new(connection, instance.name(), instance.path(), DBUS_INTERFACE).await?;
+    let mut status_changes = proxy.receive_signal("StatusChanged").await?;
+    let mut pause_changes = proxy.receive_signal("PausedChanged").await?;
+    let mut owner_changes = proxy.receive_owner_changed().await?;
+    let mut last_line = String::new();
+    loop {
+        let status = query_daemon_status(connection, instance).await.ok();
+        let line = waybar_status_line(status.as_ref());
+        if line != last_line {
+            emit(line.clone());
+            last_line = line;
+        }
+        tokio::select! {
+            Some(_) = status_changes.next() => {}
+            Some(_) = pause_changes.next() => {}
+            Some(_) = owner_changes.next() => {}
+            else => return Ok(()),
         }
+    }
+}
 
-        // Update state
-        self.last_class = win.class.clone();
-        self.last_title = win.title.clone();
-        self.last_matched_rules = matched_indices;
-        self.current_virtual_keys = result.new_managed_vks.clone();
+// === Config ===
 
-        if result.is_empty() {
-            None
-        } else {
-            Some(result)
+/// A rule for matching windows and triggering actions.
+/// At least one of `layer`, `virtual_key`, `raw_vk_action`, `reload_num`, or `reload_next` should be specified.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    /// Label shown instead of the rule number in logs, tooltips and `kswitchctl`
+    name: Option<String>,
+    class: Option<String>,
+    title: Option<String>,
+    /// Layer to switch to when switching to a native terminal (VT)
+    on_native_terminal: Option<String>,
+    /// Layer to switch to while the session is locked or another user's session is active
+    on_session_inactive: Option<String>,
+    /// Layer to switch to while the screen is locked (takes precedence over on_session_inactive)
+    on_locked: Option<String>,
+    /// Layer to switch to when rule matches
+    layer: Option<String>,
+    /// Virtual key to press while window is focused (auto-released on unfocus)
+    virtual_key: Option<String>,
+    /// Raw virtual key actions to fire on focus (fire-and-forget), executed in order
+    /// Format: [["vk_name", "Press|Release|Tap|Toggle"], ["delay", ms], ...]
+    raw_vk_action: Option<Vec<RawVkStep>>,
+    /// Reload kanata config file number N (kanata `lrld-num`) when rule matches
+    reload_num: Option<usize>,
+    /// Reload the next kanata config file (kanata `lrnx`) when rule matches
+    #[serde(default)]
+    reload_next: bool,
+    /// Continue matching subsequent rules after this one
+    #[serde(default)]
+    fallthrough: bool,
+    /// Overrides the config's `class_source` for this rule's `class` pattern
+    class_source: Option<ClassSource>,
+    /// Regex against the app's desktop-file ID (GNOME only). Windows without one never match
+    app: Option<String>,
+    /// Regex against the current workspace/virtual desktop name. Never matches on backends
+    /// that don't report one
+    workspace: Option<String>,
+    /// Regex against the WM_CLASS instance. Never matches windows without one
+    instance: Option<String>,
+    /// Regex against WM_WINDOW_ROLE. Never matches windows without one
+    role: Option<String>,
+    /// Rhai matcher script (`scripting` feature), run after the other conditions matched
+    script: Option<String>,
+    /// Program and arguments run after the other conditions matched, with the class and title
+    /// on stdin: exit code 0 matches, a first stdout line replaces `layer`
+    command: Option<Vec<String>>,
+    /// WASI plugin (`wasm-plugins` feature) whose `kswitch_match` decides, after the other
+    /// conditions matched
+    plugin: Option<String>,
+    /// WASI plugin whose `kswitch_action` runs when the rule starts matching
+    plugin_action: Option<String>,
+    /// Shell command spawned (not waited for) when the rule starts matching, with
+    /// KANATA_CLASS/KANATA_TITLE/KANATA_LAYER set
+    on_focus_cmd: Option<String>,
+    /// Shell command spawned when the rule stops matching, with the window it matched
+    on_unfocus_cmd: Option<String>,
+    /// Regexes against the class/title the window had when it appeared. Windows whose
+    /// initial identity is unknown never match them
+    initial_class: Option<String>,
+    initial_title: Option<String>,
+    /// Window size bounds in pixels (inclusive). Windows of unknown size never match them
+    min_width: Option<u32>,
+    max_width: Option<u32>,
+    min_height: Option<u32>,
+    max_height: Option<u32>,
+    /// Built-in group of apps the window's class must belong to (VM viewers, remote desktops)
+    kind: Option<WindowKind>,
+    /// Whether the window must (true) or must not (false) be a running call, as detected by
+    /// the config's `call_detection` entry
+    in_call: Option<bool>,
+    /// Keep this rule's switches out of the log and the spoken announcements (e.g. for rules
+    /// hit on every terminal title change)
+    #[serde(default)]
+    silent: bool,
+    /// `false` keeps this rule's switches out of the desktop notifications
+    notify: Option<bool>,
+    /// Debounce (dwell) time for windows this rule matches, replacing the global `debounce_ms`
+    debounce_ms: Option<u64>,
+    /// Endpoint from the config's `kanata` entry this rule's actions go to, instead of the
+    /// instance the daemon was started with
+    kanata: Option<String>,
+    /// Key remaps (kanata key names) for the windows this rule matches while kanata is
+    /// unreachable, on top of the `uinput_fallback` entry's
+    fallback_remap: Option<BTreeMap<String, String>>,
+}
+
+/// Apps the `kind` rule condition recognizes by class, for a pass-through layer while they have
+/// the keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum WindowKind {
+    /// Virtual machine viewers
+    Vm,
+    /// Remote desktop and VNC clients
+    RemoteDesktop,
+    /// Either of the above
+    VmOrRemote,
+}
+
+/// Classes of VM viewers: virt-manager, virt-viewer/remote-viewer (SPICE), Looking Glass, GNOME
+/// Boxes, QEMU's own window, VirtualBox VMs, VMware
+const VM_VIEWER_CLASSES: &str = r"^(virt-manager|virt-viewer|remote-viewer|looking-glass-client|org\.gnome\.Boxes|gnome-boxes|qemu(-system-.*)?|VirtualBox Machine|vmware|vmplayer)$";
+/// Classes of remote desktop clients: Remmina, FreeRDP (X11, Wayland, SDL), TigerVNC and
+/// other vncviewers, KRDC, GNOME Connections
+const REMOTE_DESKTOP_CLASSES: &str = r"^(org\.remmina\.Remmina|remmina|xfreerdp|wlfreerdp|sdl-freerdp|sdl3-freerdp|vncviewer|tigervnc|org\.kde\.krdc|krdc|org\.gnome\.Connections|gnome-connections)$";
+
+static VM_VIEWER_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new(VM_VIEWER_CLASSES)
+        .case_insensitive(true)
+        .build()
+        .expect("VM viewer classes are a valid regex")
+});
+static REMOTE_DESKTOP_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    RegexBuilder::new(REMOTE_DESKTOP_CLASSES)
+        .case_insensitive(true)
+        .build()
+        .expect("remote desktop classes are a valid regex")
+});
+
+impl WindowKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            WindowKind::Vm => "vm",
+            WindowKind::RemoteDesktop => "remote_desktop",
+            WindowKind::VmOrRemote => "vm_or_remote",
         }
     }
 
-    fn current_virtual_keys(&self) -> Vec<String> {
-        self.current_virtual_keys.clone()
+    /// Whether `class` belongs to this group (case-insensitive, whole class)
+    fn matches(self, class: &str) -> bool {
+        let vm = || VM_VIEWER_PATTERN.is_match(class);
+        let remote = || REMOTE_DESKTOP_PATTERN.is_match(class);
+        match self {
+            WindowKind::Vm => vm(),
+            WindowKind::RemoteDesktop => remote(),
+            WindowKind::VmOrRemote => vm() || remote(),
+        }
     }
+}
 
-    fn reset(&mut self) {
-        self.last_class.clear();
-        self.last_title.clear();
-        self.last_matched_rules.clear();
-        self.last_effective_layer.clear();
-        self.current_virtual_keys.clear();
+/// Windows of a running call, as (class, title) regexes; both must match, an empty one matches
+/// anything. Main and chat windows of the same apps don't match
+const CALL_WINDOW_PATTERNS: &[(&str, &str)] = &[
+    // Zoom's meeting and webinar windows (X11/XWayland class, Flatpak app_id)
+    (r"^(zoom|us\.zoom\.Zoom)$", r"Meeting|Webinar"),
+    // teams-for-linux and Teams in a browser: "Meeting with ... | Microsoft Teams"
+    ("", r"(Meeting|Call)\b.*\| Microsoft Teams"),
+    // Google Meet in a browser: "Meet - abc-defg-hij"
+    ("", r"^Meet - [a-z]{3}-[a-z]{4}-[a-z]{3}\b"),
+    // Jitsi Meet, in a browser or the desktop app
+    ("", r"\| Jitsi Meet"),
+    (r"^Slack$", r"Huddle"),
+    // Webex's meeting client
+    (r"^(CiscoCollabHost|Webex)$", ""),
+];
+
+static CALL_WINDOW_REGEXES: LazyLock<Vec<(Option<Regex>, Option<Regex>)>> = LazyLock::new(|| {
+    let compile = |pattern: &str| {
+        (!pattern.is_empty()).then(|| {
+            RegexBuilder::new(pattern)
+                .case_insensitive(true)
+                .build()
+                .expect("call window patterns are valid regexes")
+        })
+    };
+    CALL_WINDOW_PATTERNS
+        .iter()
+        .map(|(class, title)| (compile(class), compile(title)))
+        .collect()
+});
+
+/// Whether the window looks like a call of one of the apps in `CALL_WINDOW_PATTERNS`
+fn is_call_window(class: &str, title: &str) -> bool {
+    CALL_WINDOW_REGEXES.iter().any(|(class_pattern, title_pattern)| {
+        class_pattern.as_ref().is_none_or(|pattern| pattern.is_match(class))
+            && title_pattern.as_ref().is_none_or(|pattern| pattern.is_match(title))
+    })
+}
+
+/// Which identity `class` patterns match for XWayland windows on Wayland
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ClassSource {
+    /// The compositor's app_id (what wlr-foreign-toplevel reports)
+    #[default]
+    AppId,
+    /// The X11 WM_CLASS class; native Wayland windows fall back to the app_id
+    X11Class,
+}
+
+impl Rule {
+    /// "rule #N" for messages about this rule, with its `name` if it has one
+    fn context(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => format!("rule #{} '{}'", index + 1, name),
+            None => format!("rule #{}", index + 1),
+        }
     }
 
-    fn handle_unfocused(&mut self, default_layer: &str) -> Option<FocusActions> {
-        let mut result = FocusActions::default();
-        if !self.quiet_focus {
-            println!("[Focus] No window focused");
+    /// One-line description for `GetRuleStats`: conditions, then what the rule does
+    fn summary(&self) -> String {
+        let bounds = [
+            ("min_width", self.min_width),
+            ("max_width", self.max_width),
+            ("min_height", self.min_height),
+            ("max_height", self.max_height),
+        ];
+        let mut parts: Vec<String> = self
+            .patterns()
+            .filter_map(|(key, value)| Some(format!("{}=\"{}\"", key, value?)))
+            .chain(
+                bounds
+                    .iter()
+                    .filter_map(|(key, value)| Some(format!("{}={}", key, (*value)?))),
+            )
+            .collect();
+        if let Some(kind) = self.kind {
+            parts.push(format!("kind={}", kind.as_str()));
         }
-        // Release all active virtual keys in reverse order (bottom-to-top)
-        for vk in self.current_virtual_keys.iter().rev() {
-            result.actions.push(FocusAction::ReleaseVk(vk.clone()));
+        if let Some(in_call) = self.in_call {
+            parts.push(format!("in_call={}", in_call));
         }
-        // Switch to default layer
-        if !default_layer.is_empty() && self.last_effective_layer != default_layer {
-            result
-                .actions
-                .push(FocusAction::ChangeLayer(default_layer.to_string()));
+        if let Some(command) = &self.command {
+            parts.push(format!("command=\"{}\"", command.join(" ")));
         }
-        result.new_managed_vks = Vec::new();
-        self.current_virtual_keys = Vec::new();
-        self.last_matched_rules.clear();
-        self.last_effective_layer = default_layer.to_string();
-        self.last_class.clear();
-        self.last_title.clear();
-        if result.is_empty() {
-            None
-        } else {
-            Some(result)
+        if let Some(plugin) = &self.plugin {
+            parts.push(format!("plugin={}", plugin));
+        }
+        if parts.is_empty() {
+            parts.push("(any window)".to_string());
+        }
+        parts.push("->".to_string());
+        let actions_start = parts.len();
+        if let Some(layer) = &self.layer {
+            parts.push(format!("layer={}", layer));
+        }
+        if let Some(vk) = &self.virtual_key {
+            parts.push(format!("virtual_key={}", vk));
+        }
+        if self.raw_vk_action.is_some() {
+            parts.push("raw_vk_action".to_string());
+        }
+        if let Some(plugin) = &self.plugin_action {
+            parts.push(format!("plugin_action={}", plugin));
+        }
+        if self.on_focus_cmd.is_some() {
+            parts.push("on_focus_cmd".to_string());
+        }
+        if self.on_unfocus_cmd.is_some() {
+            parts.push("on_unfocus_cmd".to_string());
         }
+        if let Some(num) = self.reload_num {
+            parts.push(format!("reload_num={}", num));
+        }
+        if self.reload_next {
+            parts.push("reload_next".to_string());
+        }
+        if self.fallthrough {
+            parts.push("fallthrough".to_string());
+        }
+        if self.silent {
+            parts.push("silent".to_string());
+        }
+        if self.notify == Some(false) {
+            parts.push("notify=false".to_string());
+        }
+        if let Some(endpoint) = &self.kanata {
+            parts.push(format!("kanata={}", endpoint));
+        }
+        if self.fallback_remap.is_some() {
+            parts.push("fallback_remap".to_string());
+        }
+        if parts.len() == actions_start {
+            parts.push("(no action)".to_string());
+        }
+        parts.join(" ")
     }
 
-    fn handle_native_terminal(&mut self, default_layer: &str) -> Option<FocusActions> {
-        let Some(rule) = self.native_terminal_rule.clone() else {
-            return self.handle_native_terminal_without_rule(default_layer);
-        };
+    /// The rule's regex fields, by config key
+    fn patterns(&self) -> impl Iterator<Item = (&'static str, Option<&str>)> {
+        [
+            ("class", self.class.as_deref()),
+            ("title", self.title.as_deref()),
+            ("app", self.app.as_deref()),
+            ("workspace", self.workspace.as_deref()),
+            ("instance", self.instance.as_deref()),
+            ("role", self.role.as_deref()),
+            ("initial_class", self.initial_class.as_deref()),
+            ("initial_title", self.initial_title.as_deref()),
+        ]
+        .into_iter()
+    }
 
-        if !self.quiet_focus {
-            println!("[Focus] Native terminal active");
+    /// The config key and layer of an on_native_terminal, on_session_inactive or on_locked entry
+    fn special_rule_layer(&self) -> Option<(&'static str, String)> {
+        if let Some(layer) = &self.on_native_terminal {
+            return Some(("on_native_terminal", layer.clone()));
         }
-
-        let mut result = FocusActions::default();
-        let mut new_vks = Vec::new();
-
-        if let Some(vk) = rule.virtual_key.clone() {
-            new_vks.push(vk);
+        if let Some(layer) = &self.on_locked {
+            return Some(("on_locked", layer.clone()));
         }
+        self.on_session_inactive
+            .as_ref()
+            .map(|layer| ("on_session_inactive", layer.clone()))
+    }
 
-        for vk in self.current_virtual_keys.iter().rev() {
-            if !new_vks.contains(vk) {
-                result.actions.push(FocusAction::ReleaseVk(vk.clone()));
-            }
-        }
+    /// The `plugin` and `plugin_action` modules this rule uses
+    fn plugin_names(&self) -> impl Iterator<Item = &str> {
+        self.plugin.iter().chain(&self.plugin_action).map(String::as_str)
+    }
 
-        let matched_indices = vec![NATIVE_TERMINAL_RULE_INDEX];
-        let is_new = self.last_matched_rules != matched_indices;
+    fn has_initial_conditions(&self) -> bool {
+        self.initial_class.is_some() || self.initial_title.is_some()
+    }
 
-        if is_new {
-            if !rule.layer.is_empty() && self.last_effective_layer != rule.layer {
-                result
-                    .actions
-                    .push(FocusAction::ChangeLayer(rule.layer.clone()));
-            }
-            if let Some(vk) = rule.virtual_key {
-                if !self.current_virtual_keys.contains(&vk) {
-                    result.actions.push(FocusAction::PressVk(vk));
-                }
-            }
-            for (name, action) in rule.raw_vk_action {
-                result.actions.push(FocusAction::RawVkAction(name, action));
-            }
-        }
-
-        result.new_managed_vks = new_vks;
-        self.last_matched_rules = matched_indices;
-        self.last_effective_layer = rule.layer;
-        self.current_virtual_keys = result.new_managed_vks.clone();
-        self.last_class.clear();
-        self.last_title.clear();
+    fn has_size_conditions(&self) -> bool {
+        self.min_width.is_some()
+            || self.max_width.is_some()
+            || self.min_height.is_some()
+            || self.max_height.is_some()
+    }
 
-        if result.is_empty() {
-            None
-        } else {
-            Some(result)
+    fn matches_size(&self, size: Option<WindowSize>) -> bool {
+        if !self.has_size_conditions() {
+            return true;
         }
+        let Some(size) = size else {
+            return false;
+        };
+        self.min_width.is_none_or(|min| size.width >= min)
+            && self.max_width.is_none_or(|max| size.width <= max)
+            && self.min_height.is_none_or(|min| size.height >= min)
+            && self.max_height.is_none_or(|max| size.height <= max)
     }
 
-    fn handle_native_terminal_without_rule(&mut self, default_layer: &str) -> Option<FocusActions> {
-        let mut result = FocusActions::default();
-        if !self.quiet_focus {
-            println!("[Focus] Native terminal active");
-        }
-        for vk in self.current_virtual_keys.iter().rev() {
-            result.actions.push(FocusAction::ReleaseVk(vk.clone()));
-        }
-        if !default_layer.is_empty() && self.last_effective_layer != default_layer {
-            result
-                .actions
-                .push(FocusAction::ChangeLayer(default_layer.to_string()));
-        }
-        result.new_managed_vks = Vec::new();
-        self.current_virtual_keys = Vec::new();
-        self.last_matched_rules.clear();
-        self.last_effective_layer = default_layer.to_string();
-        self.last_class.clear();
-        self.last_title.clear();
+    /// `kind` against the class picked by `class_source`
+    fn matches_kind(&self, class: &str) -> bool {
+        self.kind.is_none_or(|kind| kind.matches(class))
+    }
+}
 
-        if result.is_empty() {
-            None
-        } else {
-            Some(result)
+/// A single `raw_vk_action` element: either a fake key action or a pause between actions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RawVkStep {
+    /// Fake key action (name, action: Press/Release/Tap/Toggle)
+    Action(String, String),
+    /// Wait this many milliseconds before the next action
+    Delay(u64),
+}
+
+impl RawVkStep {
+    fn to_focus_action(&self) -> FocusAction {
+        match self {
+            RawVkStep::Action(name, action) => FocusAction::RawVkAction(name.clone(), action.clone()),
+            RawVkStep::Delay(ms) => FocusAction::Delay(*ms),
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-struct StatusSnapshot {
-    layer: String,
-    virtual_keys: Vec<String>,
-    layer_source: LayerSource,
-}
+impl<'de> serde::Deserialize<'de> for RawVkStep {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-enum LayerSource {
-    Focus,
-    External,
+        let (name, value) = <(String, serde_json::Value)>::deserialize(deserializer)?;
+        match value {
+            serde_json::Value::String(action) => Ok(RawVkStep::Action(name, action)),
+            serde_json::Value::Number(ms) if name == "delay" => ms
+                .as_u64()
+                .map(RawVkStep::Delay)
+                .ok_or_else(|| D::Error::custom("'delay' must be a non-negative integer (milliseconds)")),
+            _ => Err(D::Error::custom(format!(
+                "invalid raw_vk_action entry for '{}': expected [\"vk_name\", \"Press|Release|Tap|Toggle\"] or [\"delay\", ms]",
+                name
+            ))),
+        }
+    }
 }
 
-impl LayerSource {
-    fn as_str(&self) -> &'static str {
+impl Serialize for RawVkStep {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
         match self {
-            LayerSource::Focus => "focus",
-            LayerSource::External => "external",
+            RawVkStep::Action(name, action) => (name, action).serialize(serializer),
+            RawVkStep::Delay(ms) => ("delay", ms).serialize(serializer),
         }
     }
 }
 
-#[derive(Clone, Debug)]
-struct StatusBroadcaster {
-    sender: watch::Sender<StatusSnapshot>,
+#[derive(Debug, Clone)]
+struct NativeTerminalRule {
+    layer: String,
+    virtual_key: Option<String>,
+    raw_vk_action: Vec<RawVkStep>,
+    silent: bool,
+    notify: bool,
 }
 
-#[derive(Clone, Debug)]
-struct RestartHandle {
-    sender: watch::Sender<bool>,
+/// Named kanata endpoint from the config's `{"kanata": {"NAME": {...}}}` entry, selected with `--kanata NAME`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct KanataEndpoint {
+    #[serde(default = "default_kanata_host")]
+    host: String,
+    #[serde(default = "default_kanata_port")]
+    port: u16,
+    /// Replaces the config's `{"default": ...}` layer while this endpoint is used
+    default_layer: Option<String>,
+    /// Milliseconds to wait before each reconnect attempt; the last value repeats
+    reconnect_delays_ms: Option<Vec<u64>>,
+    tls_ca: Option<PathBuf>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_cert_fingerprints",
+        serialize_with = "serialize_cert_fingerprints"
+    )]
+    tls_pin: Vec<CertFingerprint>,
+    tls_server_name: Option<String>,
 }
 
-#[derive(Clone, Debug)]
-struct PauseBroadcaster {
-    sender: watch::Sender<bool>,
+fn default_kanata_host() -> String {
+    "127.0.0.1".to_string()
 }
 
-#[derive(Clone, Debug)]
-struct ShutdownHandle {
-    sender: watch::Sender<bool>,
+fn default_kanata_port() -> u16 {
+    10000
 }
 
-impl RestartHandle {
-    fn new() -> Self {
-        let (sender, _) = watch::channel(false);
-        Self { sender }
-    }
-
-    fn subscribe(&self) -> watch::Receiver<bool> {
-        self.sender.subscribe()
-    }
+fn deserialize_cert_fingerprints<'de, D>(deserializer: D) -> Result<Vec<CertFingerprint>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
 
-    fn request(&self) {
-        self.sender.send_replace(true);
-    }
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|pin| parse_cert_fingerprint(pin).map_err(D::Error::custom))
+        .collect()
 }
 
-impl ShutdownHandle {
-    fn new() -> Self {
-        let (sender, _) = watch::channel(false);
-        Self { sender }
-    }
-
-    fn subscribe(&self) -> watch::Receiver<bool> {
-        self.sender.subscribe()
-    }
-
-    fn request(&self) {
-        self.sender.send_replace(true);
-    }
+fn serialize_cert_fingerprints<S>(pins: &[CertFingerprint], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_seq(pins.iter().map(format_cert_fingerprint))
 }
 
-impl PauseBroadcaster {
-    fn new() -> Self {
-        let (sender, _) = watch::channel(false);
-        Self { sender }
-    }
-
-    fn subscribe(&self) -> watch::Receiver<bool> {
-        self.sender.subscribe()
-    }
-
-    fn is_paused(&self) -> bool {
-        *self.sender.borrow()
-    }
-
-    fn set_paused(&self, paused: bool) -> bool {
-        let current = *self.sender.borrow();
-        if current == paused {
-            return false;
-        }
-        self.sender.send_replace(paused);
-        true
-    }
+/// Spoken layer announcements from the config's `{"announce": {...}}` entry (opt-in)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct AnnounceConfig {
+    /// Kanata layer name -> spoken text; "" keeps that layer silent
+    #[serde(default)]
+    layers: BTreeMap<String, String>,
+    /// Layer changes within this long after an announcement are coalesced into one
+    #[serde(default = "default_announce_min_interval_ms")]
+    min_interval_ms: u64,
+    /// Program and arguments; the text is appended as the last argument
+    #[serde(default = "default_announce_command")]
+    command: Vec<String>,
 }
 
-impl StatusBroadcaster {
-    fn new() -> Self {
-        let initial = StatusSnapshot {
-            layer: String::new(),
-            virtual_keys: Vec::new(),
-            layer_source: LayerSource::External,
-        };
-        let (sender, _) = watch::channel(initial);
-        Self { sender }
-    }
-
-    fn subscribe(&self) -> watch::Receiver<StatusSnapshot> {
-        self.sender.subscribe()
-    }
-
-    fn snapshot(&self) -> StatusSnapshot {
-        self.sender.borrow().clone()
-    }
-
-    fn update_layer(&self, layer: String, source: LayerSource) {
-        self.update(|state| {
-            state.layer = layer;
-            state.layer_source = source;
-        });
-    }
-
-    fn update_virtual_keys(&self, virtual_keys: Vec<String>) {
-        self.update(|state| {
-            state.virtual_keys = virtual_keys;
-        });
-    }
-
-    fn update_focus_layer(&self, layer: String) {
-        let mut next = self.sender.borrow().clone();
-        next.layer = layer;
-        next.layer_source = LayerSource::Focus;
-        self.sender.send_replace(next);
-    }
+fn default_announce_min_interval_ms() -> u64 {
+    1000
+}
 
-    fn set_paused_status(&self, layer: String) {
-        let mut next = self.sender.borrow().clone();
-        next.layer = layer;
-        next.layer_source = LayerSource::External;
-        next.virtual_keys = Vec::new();
-        self.sender.send_replace(next);
-    }
+fn default_announce_command() -> Vec<String> {
+    vec![
+        "spd-say".to_string(),
+        "--application-name".to_string(),
+        "kanata-switcher".to_string(),
+    ]
+}
 
-    fn update<F>(&self, updater: F)
-    where
-        F: FnOnce(&mut StatusSnapshot),
-    {
-        let current = self.sender.borrow().clone();
-        let mut next = current.clone();
-        updater(&mut next);
-        if next != current {
-            self.sender.send_replace(next);
-        }
-    }
+/// Desktop notifications from the config's `{"notifications": {...}}` entry or
+/// `--notify-layer-changes` (opt-in)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct NotificationConfig {
+    /// Layer changes within this long after a notification are coalesced into one
+    #[serde(default = "default_notification_min_interval_ms")]
+    min_interval_ms: u64,
+    /// How long the notification stays up; 0 leaves it to the notification server
+    #[serde(default = "default_notification_timeout_ms")]
+    timeout_ms: u32,
 }
 
-async fn wait_for_restart_or_shutdown(
-    restart_handle: &RestartHandle,
-    shutdown_handle: &ShutdownHandle,
-) -> RunOutcome {
-    let mut restart_receiver = restart_handle.subscribe();
-    let mut shutdown_receiver = shutdown_handle.subscribe();
+fn default_notification_min_interval_ms() -> u64 {
+    1000
+}
 
-    if *shutdown_receiver.borrow() {
-        return RunOutcome::Exit;
-    }
-    if *restart_receiver.borrow() {
-        return RunOutcome::Restart;
-    }
+fn default_notification_timeout_ms() -> u32 {
+    2000
+}
 
-    tokio::select! {
-        _ = shutdown_receiver.changed() => RunOutcome::Exit,
-        _ = restart_receiver.changed() => {
-            if *shutdown_receiver.borrow() {
-                RunOutcome::Exit
-            } else {
-                RunOutcome::Restart
-            }
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self {
+            min_interval_ms: default_notification_min_interval_ms(),
+            timeout_ms: default_notification_timeout_ms(),
         }
     }
 }
 
-// === SNI Indicator ===
+/// The `in_call` rule condition, from the config's `{"call_detection": {...}}` entry (opt-in)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct CallDetectionConfig {
+    /// A call window only counts while a microphone is recording (polled with `pactl`)
+    #[serde(default = "default_call_detection_mic")]
+    mic: bool,
+    /// How often the microphone is checked
+    #[serde(default = "default_call_detection_poll_interval_ms")]
+    poll_interval_ms: u64,
+}
 
-const SNI_DEFAULT_SHOW_FOCUS_ONLY: bool = true;
-const SNI_FONT_WEIGHT: FontWeight = FontWeight::Regular;
-const SNI_RASTER_HEIGHT: RasterHeight = RasterHeight::Size32;
-const SNI_GLYPH_WIDTH: usize = get_raster_width(SNI_FONT_WEIGHT, SNI_RASTER_HEIGHT);
-const SNI_GLYPH_HEIGHT: usize = SNI_RASTER_HEIGHT.val();
-const SNI_GLYPH_GAP: usize = 4;
-const SNI_ICON_HEIGHT: usize = SNI_GLYPH_HEIGHT;
-const SNI_COLOR_LAYER: [u8; 4] = [255, 255, 255, 255];
-const SNI_COLOR_VK: [u8; 4] = [255, 0, 255, 255];
-const SNI_MAX_VK_COUNT_DIGIT: usize = 9;
-const SNI_MIN_MULTI_VK_COUNT: usize = 2;
-const SNI_INDICATOR_ID: &str = "kanata-switcher";
+/// Key remaps through a uinput keyboard while kanata is unreachable, from the config's
+/// `{"uinput_fallback": {...}}` entry (`uinput-fallback` feature)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct UinputFallbackConfig {
+    /// kanata key name -> key name for every window; rules' `fallback_remap` add to it
+    #[serde(default)]
+    remap: BTreeMap<String, String>,
+    /// Keyboards to grab; empty = every input device with letter keys
+    #[serde(default)]
+    devices: Vec<PathBuf>,
+    /// kanata must be unreachable (and not running) this long before the keyboards are grabbed
+    #[serde(default = "default_uinput_fallback_grace_ms")]
+    grace_ms: u64,
+}
 
-trait DconfBackend: Send + Sync {
-    fn get_bool(&self, key: &str) -> Result<bool, String>;
-    fn set_bool(&self, key: &str, value: bool) -> Result<(), String>;
+fn default_uinput_fallback_grace_ms() -> u64 {
+    3000
 }
 
-struct ShellDconfBackend;
+fn default_call_detection_mic() -> bool {
+    true
+}
 
-impl DconfBackend for ShellDconfBackend {
-    fn get_bool(&self, key: &str) -> Result<bool, String> {
-        dconf_get_bool(key)
-    }
+fn default_call_detection_poll_interval_ms() -> u64 {
+    1000
+}
 
-    fn set_bool(&self, key: &str, value: bool) -> Result<(), String> {
-        dconf_set_bool(key, value)
-    }
+/// Idle layer from the config's `{"on_idle": ..., "idle_timeout_s": ...}` entry
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct IdleConfig {
+    /// Layer kanata switches to once there was no input for `idle_timeout_s`
+    on_idle: String,
+    #[serde(default = "default_idle_timeout_s")]
+    idle_timeout_s: u64,
 }
 
-struct SniSettingsStore {
-    available: bool,
-    backend: Box<dyn DconfBackend>,
+fn default_idle_timeout_s() -> u64 {
+    300
 }
 
-impl SniSettingsStore {
-    fn new() -> Self {
-        Self {
-            available: true,
-            backend: Box::new(ShellDconfBackend),
-        }
-    }
+/// ext-idle-notify takes the timeout as u32 milliseconds
+const MAX_IDLE_TIMEOUT_S: u64 = u32::MAX as u64 / 1000;
 
-    #[cfg(test)]
-    fn with_backend(backend: Box<dyn DconfBackend>) -> Self {
-        Self {
-            available: true,
-            backend,
-        }
-    }
+/// One case of the config's `{"tests": [...]}` entry: a window and what the rules should do
+/// with it, checked by `--check-config`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct RuleTest {
+    #[serde(default)]
+    class: String,
+    #[serde(default)]
+    title: String,
+    /// Expected layer after focusing the window (the default layer if no rule sets one)
+    layer: Option<String>,
+    /// Expected held virtual keys, in rule order
+    virtual_keys: Option<Vec<String>>,
+}
 
-    #[cfg(test)]
-    fn disabled() -> Self {
-        Self {
-            available: false,
-            backend: Box::new(ShellDconfBackend),
-        }
-    }
+#[derive(Debug, Clone)]
+enum ConfigEntry {
+    Announce(AnnounceConfig),
+    Notifications(NotificationConfig),
+    CallDetection(CallDetectionConfig),
+    UinputFallback(UinputFallbackConfig),
+    Idle(IdleConfig),
+    Default { default: String },
+    Kanata(BTreeMap<String, KanataEndpoint>),
+    LayerNames(BTreeMap<String, String>),
+    LayerVirtualKeys(BTreeMap<String, Vec<String>>),
+    TrayIcons(BTreeMap<String, TrayIcon>),
+    Indicator(IndicatorTheme),
+    MinFocusMs(u64),
+    UnfocusGraceMs(u64),
+    DedupeFocus(bool),
+    VtMonitor(bool),
+    DeferDuringGrab(bool),
+    PerWindowState(bool),
+    ClassSource(ClassSource),
+    PluginsDir(PathBuf),
+    Tests(Vec<RuleTest>),
+    /// Checked (and older files migrated) by `migrate_config_entries` before entries are parsed
+    Version,
+    Rule(Box<Rule>),
+}
 
-    fn read_focus_only(&mut self) -> Option<bool> {
-        if !self.available {
-            return None;
-        }
-        match self.backend.get_bool(DCONF_FOCUS_ONLY_KEY) {
-            Ok(value) => Some(value),
-            Err(error) => {
-                if is_dconf_unavailable(&error) {
-                    self.available = false;
+impl<'de> serde::Deserialize<'de> for ConfigEntry {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let value = serde_json::Value::deserialize(deserializer)?;
+
+        // Check if it's a "default" entry
+        if let Some(obj) = value.as_object() {
+            if obj.contains_key("default") {
+                if obj.len() == 1 {
+                    if let Some(default) = obj.get("default").and_then(|v| v.as_str()) {
+                        return Ok(ConfigEntry::Default {
+                            default: default.to_string(),
+                        });
+                    }
                 }
-                eprintln!("[SNI] dconf read failed: {}", error);
-                None
+                return Err(D::Error::custom(
+                    "'default' entry should only contain the 'default' field",
+                ));
+            }
+            // A string `kanata` belongs to a rule (its target endpoint)
+            if let Some(endpoints) = obj.get("kanata").filter(|value| value.is_object()) {
+                if obj.len() != 1 {
+                    return Err(D::Error::custom(
+                        "'kanata' entry should only contain the 'kanata' field",
+                    ));
+                }
+                return serde_json::from_value(endpoints.clone())
+                    .map(ConfigEntry::Kanata)
+                    .map_err(|e| D::Error::custom(format!("invalid 'kanata' endpoints: {}", e)));
+            }
+            // A lone debounce_ms (or its older name min_focus_ms) is the global dwell time;
+            // alongside other keys debounce_ms belongs to a rule
+            for key in ["debounce_ms", "min_focus_ms"] {
+                if let Some(ms) = obj.get(key)
+                    && obj.len() == 1
+                {
+                    return ms.as_u64().map(ConfigEntry::MinFocusMs).ok_or_else(|| {
+                        D::Error::custom(format!(
+                            "'{}' must be a non-negative number of milliseconds",
+                            key
+                        ))
+                    });
+                }
+            }
+            // A lone class_source is the global setting; alongside other keys it belongs to a rule
+            if let Some(class_source) = obj.get("class_source")
+                && obj.len() == 1
+            {
+                return serde_json::from_value(class_source.clone())
+                    .map(ConfigEntry::ClassSource)
+                    .map_err(|e| D::Error::custom(format!("invalid 'class_source': {}", e)));
+            }
+            if let Some(unfocus_grace_ms) = obj.get("unfocus_grace_ms") {
+                if obj.len() != 1 {
+                    return Err(D::Error::custom(
+                        "'unfocus_grace_ms' entry should only contain the 'unfocus_grace_ms' field",
+                    ));
+                }
+                return unfocus_grace_ms
+                    .as_u64()
+                    .map(ConfigEntry::UnfocusGraceMs)
+                    .ok_or_else(|| {
+                        D::Error::custom(
+                            "'unfocus_grace_ms' must be a non-negative number of milliseconds",
+                        )
+                    });
+            }
+            if let Some(dedupe_focus) = obj.get("dedupe_focus") {
+                if obj.len() != 1 {
+                    return Err(D::Error::custom(
+                        "'dedupe_focus' entry should only contain the 'dedupe_focus' field",
+                    ));
+                }
+                return dedupe_focus
+                    .as_bool()
+                    .map(ConfigEntry::DedupeFocus)
+                    .ok_or_else(|| D::Error::custom("'dedupe_focus' must be true or false"));
+            }
+            if let Some(vt_monitor) = obj.get("vt_monitor") {
+                if obj.len() != 1 {
+                    return Err(D::Error::custom(
+                        "'vt_monitor' entry should only contain the 'vt_monitor' field",
+                    ));
+                }
+                return vt_monitor
+                    .as_bool()
+                    .map(ConfigEntry::VtMonitor)
+                    .ok_or_else(|| D::Error::custom("'vt_monitor' must be true or false"));
+            }
+            if let Some(defer) = obj.get("defer_during_grab") {
+                if obj.len() != 1 {
+                    return Err(D::Error::custom(
+                        "'defer_during_grab' entry should only contain the 'defer_during_grab' field",
+                    ));
+                }
+                return defer
+                    .as_bool()
+                    .map(ConfigEntry::DeferDuringGrab)
+                    .ok_or_else(|| D::Error::custom("'defer_during_grab' must be true or false"));
+            }
+            if let Some(per_window_state) = obj.get("per_window_state") {
+                if obj.len() != 1 {
+                    return Err(D::Error::custom(
+                        "'per_window_state' entry should only contain the 'per_window_state' field",
+                    ));
+                }
+                return per_window_state
+                    .as_bool()
+                    .map(ConfigEntry::PerWindowState)
+                    .ok_or_else(|| D::Error::custom("'per_window_state' must be true or false"));
+            }
+            if let Some(announce) = obj.get("announce") {
+                if obj.len() != 1 {
+                    return Err(D::Error::custom(
+                        "'announce' entry should only contain the 'announce' field",
+                    ));
+                }
+                return serde_json::from_value(announce.clone())
+                    .map(ConfigEntry::Announce)
+                    .map_err(|e| D::Error::custom(format!("invalid 'announce': {}", e)));
+            }
+            if let Some(notifications) = obj.get("notifications") {
+                if obj.len() != 1 {
+                    return Err(D::Error::custom(
+                        "'notifications' entry should only contain the 'notifications' field",
+                    ));
+                }
+                return serde_json::from_value(notifications.clone())
+                    .map(ConfigEntry::Notifications)
+                    .map_err(|e| D::Error::custom(format!("invalid 'notifications': {}", e)));
+            }
+            if let Some(call_detection) = obj.get("call_detection") {
+                if obj.len() != 1 {
+                    return Err(D::Error::custom(
+                        "'call_detection' entry should only contain the 'call_detection' field",
+                    ));
+                }
+                return serde_json::from_value(call_detection.clone())
+                    .map(ConfigEntry::CallDetection)
+                    .map_err(|e| D::Error::custom(format!("invalid 'call_detection': {}", e)));
+            }
+            if let Some(uinput_fallback) = obj.get("uinput_fallback") {
+                if obj.len() != 1 {
+                    return Err(D::Error::custom(
+                        "'uinput_fallback' entry should only contain the 'uinput_fallback' field",
+                    ));
+                }
+                return serde_json::from_value(uinput_fallback.clone())
+                    .map(ConfigEntry::UinputFallback)
+                    .map_err(|e| D::Error::custom(format!("invalid 'uinput_fallback': {}", e)));
+            }
+            if obj.contains_key("on_idle") || obj.contains_key("idle_timeout_s") {
+                return serde_json::from_value(value.clone())
+                    .map(ConfigEntry::Idle)
+                    .map_err(|e| D::Error::custom(format!("invalid 'on_idle' entry: {}", e)));
+            }
+            if let Some(plugins_dir) = obj.get("plugins_dir") {
+                if obj.len() != 1 {
+                    return Err(D::Error::custom(
+                        "'plugins_dir' entry should only contain the 'plugins_dir' field",
+                    ));
+                }
+                return plugins_dir
+                    .as_str()
+                    .map(|dir| ConfigEntry::PluginsDir(PathBuf::from(dir)))
+                    .ok_or_else(|| D::Error::custom("'plugins_dir' must be a path"));
+            }
+            if let Some(version) = obj.get("version") {
+                if obj.len() != 1 {
+                    return Err(D::Error::custom(
+                        "'version' entry should only contain the 'version' field",
+                    ));
+                }
+                return version
+                    .as_u64()
+                    .map(|_| ConfigEntry::Version)
+                    .ok_or_else(|| D::Error::custom("'version' must be a positive integer"));
+            }
+            if let Some(tests) = obj.get("tests") {
+                if obj.len() != 1 {
+                    return Err(D::Error::custom(
+                        "'tests' entry should only contain the 'tests' field",
+                    ));
+                }
+                return serde_json::from_value(tests.clone())
+                    .map(ConfigEntry::Tests)
+                    .map_err(|e| D::Error::custom(format!("invalid 'tests': {}", e)));
+            }
+            if let Some(names) = obj.get("layer_names") {
+                if obj.len() != 1 {
+                    return Err(D::Error::custom(
+                        "'layer_names' entry should only contain the 'layer_names' field",
+                    ));
+                }
+                return serde_json::from_value(names.clone())
+                    .map(ConfigEntry::LayerNames)
+                    .map_err(|e| D::Error::custom(format!("invalid 'layer_names': {}", e)));
+            }
+            if let Some(keys) = obj.get("layer_virtual_keys") {
+                if obj.len() != 1 {
+                    return Err(D::Error::custom(
+                        "'layer_virtual_keys' entry should only contain the 'layer_virtual_keys' field",
+                    ));
+                }
+                return serde_json::from_value(keys.clone())
+                    .map(ConfigEntry::LayerVirtualKeys)
+                    .map_err(|e| D::Error::custom(format!("invalid 'layer_virtual_keys': {}", e)));
+            }
+            if let Some(icons) = obj.get("tray_icons") {
+                if obj.len() != 1 {
+                    return Err(D::Error::custom(
+                        "'tray_icons' entry should only contain the 'tray_icons' field",
+                    ));
+                }
+                return serde_json::from_value(icons.clone())
+                    .map(ConfigEntry::TrayIcons)
+                    .map_err(|e| D::Error::custom(format!("invalid 'tray_icons': {}", e)));
+            }
+            if let Some(indicator) = obj.get("indicator") {
+                if obj.len() != 1 {
+                    return Err(D::Error::custom(
+                        "'indicator' entry should only contain the 'indicator' field",
+                    ));
+                }
+                return serde_json::from_value(indicator.clone())
+                    .map(ConfigEntry::Indicator)
+                    .map_err(|e| D::Error::custom(format!("invalid 'indicator': {}", e)));
             }
         }
-    }
 
-    fn write_focus_only(&mut self, value: bool) {
-        if !self.available {
-            return;
-        }
-        if let Err(error) = self.backend.set_bool(DCONF_FOCUS_ONLY_KEY, value) {
-            if is_dconf_unavailable(&error) {
-                self.available = false;
+        // Try to parse as Rule with custom error handling for unknown fields
+        let known_fields = [
+            "name",
+            "class",
+            "title",
+            "on_native_terminal",
+            "on_session_inactive",
+            "on_locked",
+            "layer",
+            "virtual_key",
+            "raw_vk_action",
+            "reload_num",
+            "reload_next",
+            "fallthrough",
+            "class_source",
+            "app",
+            "workspace",
+            "instance",
+            "role",
+            "script",
+            "command",
+            "plugin",
+            "plugin_action",
+            "on_focus_cmd",
+            "on_unfocus_cmd",
+            "initial_class",
+            "initial_title",
+            "min_width",
+            "max_width",
+            "min_height",
+            "max_height",
+            "kind",
+            "in_call",
+            "silent",
+            "notify",
+            "debounce_ms",
+            "kanata",
+            "fallback_remap",
+        ];
+
+        if let Some(obj) = value.as_object() {
+            for key in obj.keys() {
+                if !known_fields.contains(&key.as_str()) {
+                    return Err(D::Error::custom(format!(
+                        "unknown field '{}'. Valid fields are: name, class, title, on_native_terminal, on_session_inactive, on_locked, layer, virtual_key, raw_vk_action, reload_num, reload_next, fallthrough, class_source, app, workspace, instance, role, script, command, plugin, plugin_action, on_focus_cmd, on_unfocus_cmd, initial_class, initial_title, min_width, max_width, min_height, max_height, kind, in_call, silent, notify, debounce_ms, kanata, fallback_remap",
+                        key
+                    )));
+                }
             }
-            eprintln!("[SNI] dconf write failed: {}", error);
         }
+
+        serde_json::from_value(value)
+            .map(|rule| ConfigEntry::Rule(Box::new(rule)))
+            .map_err(D::Error::custom)
     }
 }
 
-struct MenuRefresh {
-    sender: watch::Sender<u64>,
-    version: u64,
+#[derive(Debug, Clone)]
+struct Config {
+    rules: Vec<Rule>,
+    default_layer: Option<String>,
+    native_terminal_rule: Option<NativeTerminalRule>,
+    /// Applied while the session is locked or another user's session is active
+    session_inactive_rule: Option<NativeTerminalRule>,
+    /// Applied instead of session_inactive_rule while the screen is locked
+    locked_rule: Option<NativeTerminalRule>,
+    kanata_endpoints: BTreeMap<String, KanataEndpoint>,
+    /// Kanata layer name -> name shown by indicators and status output
+    layer_display_names: BTreeMap<String, String>,
+    /// Kanata layer name -> virtual keys held while that layer is active, whichever rule set it
+    layer_virtual_keys: BTreeMap<String, Vec<String>>,
+    /// Kanata layer name -> how the tray icon shows it (default: its letter)
+    tray_icons: BTreeMap<String, TrayIcon>,
+    /// Tray icon colors, size and letters
+    indicator: IndicatorTheme,
+    announce: Option<AnnounceConfig>,
+    /// Desktop notifications on layer change (`--notify-layer-changes` enables the defaults)
+    notifications: Option<NotificationConfig>,
+    /// Enables the `in_call` rule condition
+    call_detection: Option<CallDetectionConfig>,
+    /// Enables the uinput fallback remaps (`uinput-fallback` feature)
+    uinput_fallback: Option<UinputFallbackConfig>,
+    /// Layer to hold while the user is idle
+    idle: Option<IdleConfig>,
+    /// Focus must stay on a window this long before its rules apply (`debounce_ms`, formerly
+    /// `min_focus_ms`; 0 = immediately)
+    min_focus_ms: u64,
+    /// "No window focused" is ignored if another window gets focus within this long
+    unfocus_grace_ms: u64,
+    /// Drop focus events identical to the previous one before matching (default true)
+    dedupe_focus: bool,
+    /// Watch logind for VT switches and locks (default true)
+    vt_monitor: bool,
+    /// Hold off focus changes while another client grabs the keyboard (X11, default false)
+    defer_during_grab: bool,
+    /// Re-fire matched rules when focus moves to another window they also match (default false)
+    per_window_state: bool,
+    /// Default identity `class` patterns match on Wayland (rules can override it)
+    class_source: ClassSource,
+    /// Where `plugin`/`plugin_action` modules are loaded from (relative to the config file)
+    plugins_dir: Option<PathBuf>,
+    /// Expected results for example windows, checked by `--check-config`
+    rule_tests: Vec<RuleTest>,
 }
 
-impl MenuRefresh {
-    fn new() -> (Self, watch::Receiver<u64>) {
-        let (sender, receiver) = watch::channel(0u64);
-        (
-            Self {
-                sender,
-                version: 0,
-            },
-            receiver,
-        )
-    }
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+struct WindowInfo {
+    class: String,
+    title: String,
+    #[serde(default)]
+    is_native_terminal: bool,
+    /// The session is locked or another user's session is active (on_session_inactive)
+    #[serde(default)]
+    session_inactive: bool,
+    /// The screen is locked (on_locked); always comes with `session_inactive`
+    #[serde(default)]
+    session_locked: bool,
+    /// None when the backend can't tell (GNOME, KDE, generic wlroots)
+    #[serde(default)]
+    size: Option<WindowSize>,
+    /// Class/title when the window appeared; None when the backend can't tell (GNOME, KDE)
+    #[serde(default)]
+    initial: Option<InitialIdentity>,
+    /// WM_CLASS class: set on X11 and for XWayland windows on Hyprland/Sway, None otherwise
+    #[serde(default)]
+    x11_class: Option<String>,
+    /// Desktop-file ID of the window's app (`ShellApp.get_id()`), reported by the GNOME extension
+    #[serde(default)]
+    desktop_id: Option<String>,
+    /// Name of the current workspace/virtual desktop; None when the backend can't tell
+    /// (generic wlroots, COSMIC)
+    #[serde(default)]
+    workspace: Option<String>,
+    /// Instance part of the X11 WM_CLASS (X11, KWin)
+    #[serde(default)]
+    instance: Option<String>,
+    /// X11 WM_WINDOW_ROLE, e.g. `browser` vs `Popup` for Firefox windows (X11, KWin)
+    #[serde(default)]
+    role: Option<String>,
+    /// Opaque per-window ID from the backend (X11 window ID, wlr-foreign-toplevel handle, KWin
+    /// `internalId`, Mutter window ID); None when the backend can't tell (generic DBus pushes)
+    #[serde(default)]
+    window_id: Option<String>,
+}
 
-    fn notify(&mut self) {
-        self.version += 1;
-        self.sender.send_replace(self.version);
+impl WindowInfo {
+    fn class_for(&self, source: ClassSource) -> &str {
+        match (source, self.x11_class.as_deref()) {
+            (ClassSource::X11Class, Some(x11_class)) => x11_class,
+            _ => &self.class,
+        }
     }
 }
 
-#[derive(Clone, Debug)]
-struct SniIndicatorState {
-    last_status: StatusSnapshot,
-    focus_status: StatusSnapshot,
-    paused: bool,
-    show_focus_only: bool,
-    menu_revision: u64,
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+struct InitialIdentity {
+    class: String,
+    title: String,
 }
 
-impl SniIndicatorState {
-    fn new(initial: StatusSnapshot, show_focus_only: bool) -> Self {
-        Self {
-            last_status: initial.clone(),
-            focus_status: initial,
-            paused: false,
-            show_focus_only,
-            menu_revision: 0,
-        }
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+struct WindowSize {
+    width: u32,
+    height: u32,
+}
 
-    fn update_status(&mut self, snapshot: StatusSnapshot) {
-        if snapshot.layer_source == LayerSource::Focus {
-            self.focus_status = snapshot.clone();
+/// Extensions tried, in order, when no `--config` is given; the first existing file wins
+const CONFIG_FILE_EXTENSIONS: [&str; 4] = ["json", "toml", "yaml", "yml"];
+
+fn resolve_config_path(config_path: Option<&Path>) -> PathBuf {
+    config_path.map(|p| p.to_path_buf()).unwrap_or_else(|| {
+        let xdg_config = env::var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| dirs::home_dir().unwrap().join(".config"));
+        let base = xdg_config.join("kanata").join("kanata-switcher");
+        CONFIG_FILE_EXTENSIONS
+            .iter()
+            .map(|extension| base.with_extension(extension))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| base.with_extension("json"))
+    })
+}
+
+/// Config file syntax, picked by file extension. All formats describe the same entry list
+/// and go through the same `ConfigEntry` deserializer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    fn from_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
         }
-        self.last_status = snapshot;
     }
+}
 
-    fn set_paused(&mut self, paused: bool) {
-        self.paused = paused;
+/// TOML has no top-level arrays, so each entry is an `[[entry]]` table
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct TomlConfig<T> {
+    #[serde(default = "Vec::new")]
+    entry: Vec<T>,
+}
+
+fn parse_entries_as<T: serde::de::DeserializeOwned>(
+    text: &str,
+    format: ConfigFormat,
+) -> Result<Vec<T>, String> {
+    match format {
+        ConfigFormat::Json => serde_json::from_str(text).map_err(|e| e.to_string()),
+        ConfigFormat::Toml => toml::from_str::<TomlConfig<T>>(text)
+            .map(|config| config.entry)
+            .map_err(|e| e.to_string().trim_end().to_string()),
+        ConfigFormat::Yaml => serde_yaml_ng::from_str(text).map_err(|e| e.to_string()),
     }
+}
 
-    fn toggle_focus_only(&mut self) {
-        self.show_focus_only = !self.show_focus_only;
+/// Files of the current format are parsed directly, so errors keep their line numbers; older
+/// ones go through the migrations first
+fn parse_config_entries(text: &str, format: ConfigFormat) -> Result<Vec<ConfigEntry>, String> {
+    let raw_entries = parse_entries_as::<serde_json::Value>(text, format)?;
+    let (entries, version) = migrate_config_entries(raw_entries, CONFIG_MIGRATIONS)?;
+    if version == CONFIG_VERSION {
+        return parse_entries_as(text, format);
     }
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            serde_json::from_value(entry).map_err(|e| {
+                format!(
+                    "entry #{} (after migrating from version {}): {}",
+                    index + 1,
+                    version,
+                    e
+                )
+            })
+        })
+        .collect()
+}
 
-    fn focus_only_enabled(&self) -> bool {
-        self.show_focus_only
+// === Config Migrations ===
+
+/// Rewrites config entries from one format version to the next
+type ConfigMigration = fn(Vec<serde_json::Value>) -> Result<Vec<serde_json::Value>, String>;
+
+/// `CONFIG_MIGRATIONS[N - 1]` turns version N entries into version N + 1 entries. Add one here
+/// (and bump `CONFIG_VERSION`) whenever an entry's format changes incompatibly
+const CONFIG_MIGRATIONS: &[ConfigMigration] = &[];
+
+/// Format version of the config entries this build parses; files without a `{"version": N}`
+/// entry are version 1
+const CONFIG_VERSION: u64 = CONFIG_MIGRATIONS.len() as u64 + 1;
+
+/// Takes the `{"version": N}` entry out of `entries` and runs `migrations` from N up to the
+/// newest version. Returns the migrated entries and N
+fn migrate_config_entries(
+    entries: Vec<serde_json::Value>,
+    migrations: &[ConfigMigration],
+) -> Result<(Vec<serde_json::Value>, u64), String> {
+    let latest = migrations.len() as u64 + 1;
+    let mut version = None;
+    let mut kept = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let Some(value) = entry.get("version") else {
+            kept.push(entry);
+            continue;
+        };
+        if entry.as_object().is_some_and(|fields| fields.len() != 1) {
+            return Err("'version' entry should only contain the 'version' field".to_string());
+        }
+        if version.is_some() {
+            return Err("multiple 'version' entries found, only one allowed".to_string());
+        }
+        version = Some(
+            value
+                .as_u64()
+                .filter(|&version| version >= 1)
+                .ok_or("'version' must be a positive integer")?,
+        );
+    }
+    let version = version.unwrap_or(1);
+    if version > latest {
+        return Err(format!(
+            "config version {} is newer than this kanata-switcher supports ({})",
+            version, latest
+        ));
     }
+    let entries = migrations[(version - 1) as usize..]
+        .iter()
+        .try_fold(kept, |entries, migrate| migrate(entries))?;
+    Ok((entries, version))
+}
 
-    fn bump_menu_revision(&mut self) {
-        self.menu_revision = self.menu_revision.wrapping_add(1);
+fn format_config_entries(
+    entries: &[serde_json::Value],
+    format: ConfigFormat,
+) -> Result<String, String> {
+    match format {
+        ConfigFormat::Json => serde_json::to_string_pretty(entries)
+            .map(|text| text + "\n")
+            .map_err(|e| e.to_string()),
+        ConfigFormat::Toml => toml::to_string(&TomlConfig {
+            entry: entries.to_vec(),
+        })
+        .map_err(|e| e.to_string()),
+        ConfigFormat::Yaml => serde_yaml_ng::to_string(entries).map_err(|e| e.to_string()),
     }
+}
 
-    fn display_status(&self) -> StatusSnapshot {
-        if self.paused {
-            return self.last_status.clone();
-        }
-        if self.show_focus_only {
-            return self.focus_status.clone();
+/// Rewrites an older config file in the format of the newest version, keeping the original
+/// as `<file>.v<N>.bak`. Returns the file's old version, or None if it was already current
+fn migrate_config_file(path: &Path, migrations: &[ConfigMigration]) -> Result<Option<u64>, String> {
+    let format = ConfigFormat::from_path(path);
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let raw_entries = parse_entries_as::<serde_json::Value>(&text, format)
+        .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+    let (entries, version) = migrate_config_entries(raw_entries, migrations)?;
+    let latest = migrations.len() as u64 + 1;
+    if version == latest {
+        return Ok(None);
+    }
+    for (index, entry) in entries.iter().enumerate() {
+        if let Err(error) = serde_json::from_value::<ConfigEntry>(entry.clone()) {
+            return Err(format!("entry #{} after migration: {}", index + 1, error));
         }
-        self.last_status.clone()
     }
+    let mut migrated = vec![serde_json::json!({ "version": latest })];
+    migrated.extend(entries);
+    let content = format_config_entries(&migrated, format)?;
+
+    let backup_path = PathBuf::from(format!("{}.v{}.bak", path.display(), version));
+    fs::copy(path, &backup_path)
+        .map_err(|e| format!("Failed to back up {}: {}", path.display(), e))?;
+    let tmp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    fs::write(&tmp_path, content)
+        .and_then(|()| fs::rename(&tmp_path, path))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    Ok(Some(version))
 }
 
-#[derive(Clone)]
-struct SniLocalControl {
-    runtime_handle: tokio::runtime::Handle,
-    kanata: KanataClient,
-    handler: Arc<Mutex<FocusHandler>>,
-    status_broadcaster: StatusBroadcaster,
-    pause_broadcaster: PauseBroadcaster,
-    restart_handle: RestartHandle,
-    env: Environment,
-    connection: Option<Connection>,
-    is_kde6: bool,
+/// `--migrate-config`
+fn migrate_config(config_path: Option<&Path>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let path = resolve_config_path(config_path);
+    match migrate_config_file(&path, CONFIG_MIGRATIONS)? {
+        Some(version) => println!(
+            "[Config] Migrated {} from version {} to {} (original kept as {}.v{}.bak)",
+            path.display(),
+            version,
+            CONFIG_VERSION,
+            path.display(),
+            version
+        ),
+        None => println!(
+            "[Config] {} is already at version {}",
+            path.display(),
+            CONFIG_VERSION
+        ),
+    }
+    Ok(())
 }
 
-#[derive(Clone)]
-struct SniDbusControl {
-    runtime_handle: tokio::runtime::Handle,
-    connection: Connection,
-    restart_handle: RestartHandle,
+/// Load the config, printing an example when the file is missing
+fn load_config(config_path: Option<&Path>) -> Result<Config, String> {
+    let path = resolve_config_path(config_path);
+
+    if !path.exists() {
+        elog_line!("Example config:");
+        elog_line!(
+            r#"[
+  {{"default": "base"}},
+  {{"on_native_terminal": "tty"}},
+  {{"class": "firefox", "layer": "browser"}},
+  {{"class": "alacritty", "title": "vim", "layer": "vim"}}
+]"#
+        );
+        elog_line!("The same entries can be written as TOML (.toml) or YAML (.yaml/.yml).");
+        return Err(format!("Config file not found: {}", path.display()));
+    }
+
+    let config = parse_config(&path)?;
+    print_config_loaded(&config, &path);
+    Ok(config)
 }
 
-#[derive(Clone)]
-enum SniControl {
-    Local(SniLocalControl),
-    Dbus(SniDbusControl),
+fn print_config_loaded(config: &Config, path: &Path) {
+    log_line!(
+        "[Config] Loaded {} rules from {}",
+        config.rules.len(),
+        path.display()
+    );
 }
 
-trait SniControlOps: Send + Sync {
-    fn restart(&self);
-    fn pause(&self);
-    fn unpause(&self);
+/// Reads and validates a config file; the error is what `load_config` exits with
+fn parse_config(path: &Path) -> Result<Config, String> {
+    match fs::read_to_string(path) {
+        Ok(content) => parse_config_text(&content, path),
+        Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+    }
 }
 
-impl SniControlOps for SniControl {
-    fn restart(&self) {
-        println!("[SNI] Restart requested");
-        match self {
-            SniControl::Local(control) => {
-                control.restart_handle.request();
+/// Validates config text as if read from `path`, which picks the format and anchors a relative
+/// `plugins_dir`; the settings window checks unsaved edits with it
+fn parse_config_text(content: &str, path: &Path) -> Result<Config, String> {
+    match parse_config_entries(content, ConfigFormat::from_path(path)) {
+        Ok(entries) => {
+            let mut rules = Vec::new();
+            let mut default_layer: Option<String> = None;
+            let mut native_terminal_rule: Option<NativeTerminalRule> = None;
+            let mut session_inactive_rule: Option<NativeTerminalRule> = None;
+            let mut locked_rule: Option<NativeTerminalRule> = None;
+            let mut kanata_endpoints: Option<BTreeMap<String, KanataEndpoint>> = None;
+            let mut layer_display_names: Option<BTreeMap<String, String>> = None;
+            let mut tray_icons: Option<BTreeMap<String, TrayIcon>> = None;
+            let mut layer_virtual_keys: Option<BTreeMap<String, Vec<String>>> = None;
+            let mut indicator: Option<IndicatorTheme> = None;
+            let mut announce: Option<AnnounceConfig> = None;
+            let mut notifications: Option<NotificationConfig> = None;
+            let mut call_detection: Option<CallDetectionConfig> = None;
+            let mut uinput_fallback: Option<UinputFallbackConfig> = None;
+            let mut idle: Option<IdleConfig> = None;
+            let mut min_focus_ms: Option<u64> = None;
+            let mut unfocus_grace_ms: Option<u64> = None;
+            let mut dedupe_focus: Option<bool> = None;
+            let mut defer_during_grab: Option<bool> = None;
+            let mut vt_monitor: Option<bool> = None;
+            let mut per_window_state: Option<bool> = None;
+            let mut class_source: Option<ClassSource> = None;
+            let mut plugins_dir: Option<PathBuf> = None;
+            let mut rule_tests: Option<Vec<RuleTest>> = None;
+
+            for (position, entry) in entries.into_iter().enumerate() {
+                // Errors name the rule (as counted in logs and `kswitchctl rules`) or the
+                // entry's position in the file
+                let context = match &entry {
+                    ConfigEntry::Rule(rule) if rule.special_rule_layer().is_none() => {
+                        rule.context(rules.len())
+                    }
+                    _ => format!("entry #{}", position + 1),
+                };
+                let apply_entry = || -> Result<(), String> {
+                    match entry {
+                        ConfigEntry::Version => {}
+                        ConfigEntry::Tests(tests) => {
+                            if rule_tests.is_some() {
+                                return Err(
+                                    "multiple 'tests' entries found, only one allowed".to_string(),
+                                );
+                            }
+                            for (index, test) in tests.iter().enumerate() {
+                                if test.class.is_empty() && test.title.is_empty() {
+                                    return Err(format!(
+                                        "'tests' #{}: needs 'class' or 'title'",
+                                        index + 1
+                                    ));
+                                }
+                                if test.layer.is_none() && test.virtual_keys.is_none() {
+                                    return Err(format!(
+                                        "'tests' #{}: needs 'layer' or 'virtual_keys' to check",
+                                        index + 1
+                                    ));
+                                }
+                            }
+                            rule_tests = Some(tests);
+                        }
+                        ConfigEntry::PluginsDir(dir) => {
+                            if plugins_dir.is_some() {
+                                return Err(
+                                    "multiple 'plugins_dir' entries found, only one allowed".to_string(),
+                                );
+                            }
+                            plugins_dir = Some(match path.parent() {
+                                Some(config_dir) => config_dir.join(dir),
+                                None => dir,
+                            });
+                        }
+                        ConfigEntry::ClassSource(source) => {
+                            if class_source.is_some() {
+                                return Err(
+                                    "multiple 'class_source' entries found, only one allowed".to_string(),
+                                );
+                            }
+                            class_source = Some(source);
+                        }
+                        ConfigEntry::UnfocusGraceMs(ms) => {
+                            if unfocus_grace_ms.is_some() {
+                                return Err(
+                                    "multiple 'unfocus_grace_ms' entries found, only one allowed".to_string(),
+                                );
+                            }
+                            unfocus_grace_ms = Some(ms);
+                        }
+                        ConfigEntry::DedupeFocus(enabled) => {
+                            if dedupe_focus.is_some() {
+                                return Err(
+                                    "multiple 'dedupe_focus' entries found, only one allowed".to_string(),
+                                );
+                            }
+                            dedupe_focus = Some(enabled);
+                        }
+                        ConfigEntry::VtMonitor(enabled) => {
+                            if vt_monitor.is_some() {
+                                return Err(
+                                    "multiple 'vt_monitor' entries found, only one allowed".to_string(),
+                                );
+                            }
+                            vt_monitor = Some(enabled);
+                        }
+                        ConfigEntry::DeferDuringGrab(enabled) => {
+                            if defer_during_grab.is_some() {
+                                return Err("multiple 'defer_during_grab' entries found, only one allowed"
+                                    .to_string());
+                            }
+                            defer_during_grab = Some(enabled);
+                        }
+                        ConfigEntry::PerWindowState(enabled) => {
+                            if per_window_state.is_some() {
+                                return Err(
+                                    "multiple 'per_window_state' entries found, only one allowed".to_string(),
+                                );
+                            }
+                            per_window_state = Some(enabled);
+                        }
+                        ConfigEntry::MinFocusMs(ms) => {
+                            if min_focus_ms.is_some() {
+                                return Err(
+                                    "multiple 'debounce_ms' entries found, only one allowed".to_string(),
+                                );
+                            }
+                            min_focus_ms = Some(ms);
+                        }
+                        ConfigEntry::Announce(config) => {
+                            if announce.is_some() {
+                                return Err(
+                                    "multiple 'announce' entries found, only one allowed".to_string(),
+                                );
+                            }
+                            if config.command.is_empty() {
+                                return Err("'announce': 'command' must not be empty".to_string());
+                            }
+                            announce = Some(config);
+                        }
+                        ConfigEntry::Notifications(config) => {
+                            if notifications.is_some() {
+                                return Err("multiple 'notifications' entries found, only one allowed"
+                                    .to_string());
+                            }
+                            notifications = Some(config);
+                        }
+                        ConfigEntry::CallDetection(config) => {
+                            if call_detection.is_some() {
+                                return Err("multiple 'call_detection' entries found, only one allowed"
+                                    .to_string());
+                            }
+                            if config.poll_interval_ms == 0 {
+                                return Err("'call_detection': 'poll_interval_ms' must be positive"
+                                    .to_string());
+                            }
+                            call_detection = Some(config);
+                        }
+                        ConfigEntry::UinputFallback(config) => {
+                            if uinput_fallback.is_some() {
+                                return Err("multiple 'uinput_fallback' entries found, only one allowed"
+                                    .to_string());
+                            }
+                            if !cfg!(feature = "uinput-fallback") {
+                                return Err("'uinput_fallback' needs a build with the `uinput-fallback` feature"
+                                    .to_string());
+                            }
+                            validate_fallback_remap(&config.remap).map_err(|error| {
+                                format!("'uinput_fallback': 'remap': {}", error)
+                            })?;
+                            uinput_fallback = Some(config);
+                        }
+                        ConfigEntry::Idle(config) => {
+                            if idle.is_some() {
+                                return Err(
+                                    "multiple 'on_idle' entries found, only one allowed".to_string(),
+                                );
+                            }
+                            if config.on_idle.is_empty() {
+                                return Err("'on_idle' must name a layer".to_string());
+                            }
+                            if !(1..=MAX_IDLE_TIMEOUT_S).contains(&config.idle_timeout_s) {
+                                return Err(format!(
+                                    "'idle_timeout_s' must be between 1 and {}",
+                                    MAX_IDLE_TIMEOUT_S
+                                ));
+                            }
+                            idle = Some(config);
+                        }
+                        ConfigEntry::LayerNames(names) => {
+                            if layer_display_names.is_some() {
+                                return Err(
+                                    "multiple 'layer_names' entries found, only one allowed".to_string(),
+                                );
+                            }
+                            for (layer, display_name) in &names {
+                                if display_name.trim().is_empty() {
+                                    return Err(format!(
+                                        "'layer_names': display name for layer \"{}\" must not be empty",
+                                        layer
+                                    ));
+                                }
+                            }
+                            layer_display_names = Some(names);
+                        }
+                        ConfigEntry::LayerVirtualKeys(keys) => {
+                            if layer_virtual_keys.is_some() {
+                                return Err("multiple 'layer_virtual_keys' entries found, only one allowed"
+                                    .to_string());
+                            }
+                            for (layer, vks) in &keys {
+                                if vks.iter().any(|vk| vk.trim().is_empty()) {
+                                    return Err(format!(
+                                        "'layer_virtual_keys': virtual key names for layer \"{}\" must not be empty",
+                                        layer
+                                    ));
+                                }
+                            }
+                            layer_virtual_keys = Some(keys);
+                        }
+                        ConfigEntry::TrayIcons(icons) => {
+                            if tray_icons.is_some() {
+                                return Err(
+                                    "multiple 'tray_icons' entries found, only one allowed".to_string(),
+                                );
+                            }
+                            tray_icons = Some(icons);
+                        }
+                        ConfigEntry::Indicator(theme) => {
+                            if indicator.is_some() {
+                                return Err(
+                                    "multiple 'indicator' entries found, only one allowed".to_string(),
+                                );
+                            }
+                            if IndicatorTheme::raster_height_for(theme.size).is_none() {
+                                return Err(format!(
+                                    "'indicator': 'size' must be 16, 20, 24 or 32, got {}",
+                                    theme.size
+                                ));
+                            }
+                            indicator = Some(theme);
+                        }
+                        ConfigEntry::Kanata(endpoints) => {
+                            if kanata_endpoints.is_some() {
+                                return Err(
+                                    "multiple 'kanata' entries found, only one allowed".to_string(),
+                                );
+                            }
+                            for (name, endpoint) in &endpoints {
+                                if endpoint
+                                    .reconnect_delays_ms
+                                    .as_ref()
+                                    .is_some_and(Vec::is_empty)
+                                {
+                                    return Err(format!(
+                                        "kanata endpoint \"{}\": 'reconnect_delays_ms' must not be empty",
+                                        name
+                                    ));
+                                }
+                            }
+                            kanata_endpoints = Some(endpoints);
+                        }
+                        ConfigEntry::Default { default } => {
+                            if default_layer.is_some() {
+                                return Err(
+                                    "multiple 'default' entries found, only one allowed".to_string(),
+                                );
+                            }
+                            default_layer = Some(default);
+                        }
+                        ConfigEntry::Rule(rule) => {
+                            if let Some((key, layer)) = rule.special_rule_layer() {
+                                if rule.on_native_terminal.is_some()
+                                    && rule.on_session_inactive.is_some()
+                                {
+                                    return Err(
+                                        "'on_native_terminal' cannot be combined with 'on_session_inactive'".to_string(),
+                                    );
+                                }
+                                if rule.on_locked.is_some()
+                                    && (rule.on_native_terminal.is_some()
+                                        || rule.on_session_inactive.is_some())
+                                {
+                                    return Err(
+                                        "'on_locked' cannot be combined with 'on_native_terminal' or 'on_session_inactive'".to_string(),
+                                    );
+                                }
+                                if rule.class.is_some()
+                                    || rule.title.is_some()
+                                    || rule.app.is_some()
+                                    || rule.instance.is_some()
+                                    || rule.role.is_some()
+                                    || rule.script.is_some()
+                                    || rule.command.is_some()
+                                    || rule.plugin.is_some()
+                                {
+                                    return Err(format!(
+                                        "'{}' cannot be combined with 'class', 'title', 'app', 'instance', 'role', 'script', 'command' or 'plugin'",
+                                        key
+                                    ));
+                                }
+                                if rule.plugin_action.is_some()
+                                    || rule.on_focus_cmd.is_some()
+                                    || rule.on_unfocus_cmd.is_some()
+                                {
+                                    return Err(format!(
+                                        "'{}' cannot be combined with 'plugin_action', 'on_focus_cmd' or 'on_unfocus_cmd'",
+                                        key
+                                    ));
+                                }
+                                if rule.layer.is_some() {
+                                    return Err(format!(
+                                        "'{}' cannot be combined with 'layer'",
+                                        key
+                                    ));
+                                }
+                                if rule.fallback_remap.is_some() {
+                                    return Err(format!(
+                                        "'{}' cannot be combined with 'fallback_remap'",
+                                        key
+                                    ));
+                                }
+                                if rule.name.is_some() {
+                                    return Err(format!(
+                                        "'{}' cannot be combined with 'name' (it is already labelled '{}')",
+                                        key, key
+                                    ));
+                                }
+                                if rule.reload_num.is_some() || rule.reload_next {
+                                    return Err(format!(
+                                        "'{}' cannot be combined with 'reload_num' or 'reload_next'",
+                                        key
+                                    ));
+                                }
+                                if rule.debounce_ms.is_some() {
+                                    return Err(format!(
+                                        "'{}' cannot be combined with 'debounce_ms' (it is never delayed)",
+                                        key
+                                    ));
+                                }
+                                if rule.kanata.is_some() {
+                                    return Err(format!(
+                                        "'{}' cannot be combined with 'kanata' (it applies to the daemon's own kanata instance)",
+                                        key
+                                    ));
+                                }
+                                if rule.has_size_conditions()
+                                    || rule.has_initial_conditions()
+                                    || rule.workspace.is_some()
+                                {
+                                    return Err(format!(
+                                        "'{}' cannot be combined with window size, workspace or initial class/title conditions",
+                                        key
+                                    ));
+                                }
+                                let slot = if rule.on_native_terminal.is_some() {
+                                    &mut native_terminal_rule
+                                } else if rule.on_locked.is_some() {
+                                    &mut locked_rule
+                                } else {
+                                    &mut session_inactive_rule
+                                };
+                                if slot.is_some() {
+                                    return Err(format!(
+                                        "multiple '{}' rules found, only one allowed",
+                                        key
+                                    ));
+                                }
+                                *slot = Some(NativeTerminalRule {
+                                    layer,
+                                    virtual_key: rule.virtual_key.clone(),
+                                    raw_vk_action: rule
+                                        .raw_vk_action
+                                        .clone()
+                                        .unwrap_or_default(),
+                                    silent: rule.silent,
+                                    notify: rule.notify.unwrap_or(true),
+                                });
+                            } else {
+                                if rule.reload_num.is_some() && rule.reload_next {
+                                    return Err(
+                                        "'reload_num' cannot be combined with 'reload_next'".to_string(),
+                                    );
+                                }
+                                // Rule with no matchers and no fallthrough would match
+                                // everything and stop further matching, which is almost
+                                // certainly a bug
+                                if rule.class.is_none()
+                                    && rule.title.is_none()
+                                    && rule.app.is_none()
+                                    && rule.workspace.is_none()
+                                    && rule.instance.is_none()
+                                    && rule.role.is_none()
+                                    && rule.script.is_none()
+                                    && rule.command.is_none()
+                                    && rule.plugin.is_none()
+                                    && !rule.has_initial_conditions()
+                                    && !rule.has_size_conditions()
+                                    && rule.kind.is_none()
+                                    && rule.in_call.is_none()
+                                    && !rule.fallthrough
+                                {
+                                    return Err(
+                                        "Rule with no 'class' or 'title' matcher requires 'fallthrough: true'\n\
+                                         [Config] Hint: A catch-all rule without fallthrough would match all windows and stop further matching"
+                                            .to_string(),
+                                    );
+                                }
+                                for (key, command) in [
+                                    ("on_focus_cmd", &rule.on_focus_cmd),
+                                    ("on_unfocus_cmd", &rule.on_unfocus_cmd),
+                                ] {
+                                    if command
+                                        .as_ref()
+                                        .is_some_and(|command| command.trim().is_empty())
+                                    {
+                                        return Err(format!("'{}' must not be empty", key));
+                                    }
+                                }
+                                if let Some(remap) = &rule.fallback_remap {
+                                    validate_fallback_remap(remap).map_err(|error| {
+                                        format!("'fallback_remap': {}", error)
+                                    })?;
+                                }
+                                CompiledRule::compile(&rule)?;
+                                rules.push(*rule);
+                            }
+                        }
+                    }
+                    Ok(())
+                };
+                apply_entry().map_err(|error| format!("{}: {}", context, error))?;
             }
-            SniControl::Dbus(control) => {
-                control.runtime_handle.block_on(async {
-                    if let Err(error) = send_control_command_with_connection(
-                        &control.connection,
-                        ControlCommand::Restart,
-                    )
-                    .await
-                    {
-                        eprintln!("[SNI] Failed to send restart: {}", error);
+
+            // Checked once all entries are read: 'plugins_dir' may come after the rules
+            for (index, rule) in rules.iter().enumerate() {
+                for name in rule.plugin_names() {
+                    if let Err(error) = plugin_path(plugins_dir.as_deref(), name) {
+                        return Err(format!("{}: {}", rule.context(index), error));
                     }
-                });
-                control.restart_handle.request();
+                }
+            }
+            // And 'call_detection'
+            if call_detection.is_none()
+                && let Some(index) = rules.iter().position(|rule| rule.in_call.is_some())
+            {
+                return Err(format!(
+                    "{}: 'in_call' needs a \"call_detection\" entry",
+                    rules[index].context(index)
+                ));
+            }
+            // And 'uinput_fallback'
+            if uinput_fallback.is_none()
+                && let Some(index) = rules.iter().position(|rule| rule.fallback_remap.is_some())
+            {
+                return Err(format!(
+                    "{}: 'fallback_remap' needs a \"uinput_fallback\" entry",
+                    rules[index].context(index)
+                ));
+            }
+            // Same for the 'kanata' entry
+            let kanata_endpoints = kanata_endpoints.unwrap_or_default();
+            for (index, rule) in rules.iter().enumerate() {
+                let Some(name) = &rule.kanata else {
+                    continue;
+                };
+                if kanata_endpoints.contains_key(name) {
+                    continue;
+                }
+                if kanata_endpoints.is_empty() {
+                    return Err(format!(
+                        "{}: unknown kanata endpoint \"{}\": the config has no \"kanata\" entry",
+                        rule.context(index),
+                        name
+                    ));
+                }
+                let names: Vec<&str> = kanata_endpoints.keys().map(String::as_str).collect();
+                return Err(format!(
+                    "{}: unknown kanata endpoint \"{}\" (configured: {})",
+                    rule.context(index),
+                    name,
+                    names.join(", ")
+                ));
             }
+
+            Ok(Config {
+                rules,
+                default_layer,
+                native_terminal_rule,
+                session_inactive_rule,
+                locked_rule,
+                kanata_endpoints,
+                layer_display_names: layer_display_names.unwrap_or_default(),
+                layer_virtual_keys: layer_virtual_keys.unwrap_or_default(),
+                tray_icons: tray_icons.unwrap_or_default(),
+                indicator: indicator.unwrap_or_default(),
+                announce,
+                notifications,
+                call_detection,
+                uinput_fallback,
+                idle,
+                min_focus_ms: min_focus_ms.unwrap_or_default(),
+                unfocus_grace_ms: unfocus_grace_ms.unwrap_or_default(),
+                dedupe_focus: dedupe_focus.unwrap_or(true),
+                vt_monitor: vt_monitor.unwrap_or(true),
+                defer_during_grab: defer_during_grab.unwrap_or_default(),
+                per_window_state: per_window_state.unwrap_or_default(),
+                class_source: class_source.unwrap_or_default(),
+                plugins_dir,
+                rule_tests: rule_tests.unwrap_or_default(),
+            })
         }
+        Err(e) => Err(format!("Failed to parse {}: {}", path.display(), e)),
     }
+}
 
-    fn pause(&self) {
-        println!("[SNI] Pause requested");
-        match self {
-            SniControl::Local(control) => {
-                pause_daemon(
-                    &control.pause_broadcaster,
-                    &control.handler,
-                    &control.status_broadcaster,
-                    &control.kanata,
-                    &control.runtime_handle,
-                    "via SNI",
-                );
-            }
-            SniControl::Dbus(control) => {
-                control.runtime_handle.block_on(async {
-                    if let Err(error) = send_control_command_with_connection(
-                        &control.connection,
-                        ControlCommand::Pause,
-                    )
-                    .await
-                    {
-                        eprintln!("[SNI] Failed to send pause: {}", error);
-                    }
-                });
-            }
+/// Compiled size limits for rule patterns. The regex crate matches in linear time, so these
+/// plus `MAX_MATCH_INPUT_BYTES` bound the work per focus event for any title
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+const REGEX_DFA_SIZE_LIMIT: usize = 1 << 20;
+const REGEX_NEST_LIMIT: u32 = 64;
+/// Longer titles (web page titles are attacker-controlled) are matched as their first and
+/// last half of this, so both "vim ..." prefixes and "... - Mozilla Firefox" suffixes still match
+const MAX_MATCH_INPUT_BYTES: usize = 4096;
+
+fn build_pattern_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .dfa_size_limit(REGEX_DFA_SIZE_LIMIT)
+        .nest_limit(REGEX_NEST_LIMIT)
+        .build()
+}
+
+/// `value` cut to its first and last `MAX_MATCH_INPUT_BYTES / 2` bytes (on char boundaries)
+fn bounded_match_input(value: &str) -> Cow<'_, str> {
+    if value.len() <= MAX_MATCH_INPUT_BYTES {
+        return Cow::Borrowed(value);
+    }
+    let half = MAX_MATCH_INPUT_BYTES / 2;
+    let head_end = value.floor_char_boundary(half);
+    let tail_start = value.ceil_char_boundary(value.len() - half);
+    Cow::Owned(format!("{}{}", &value[..head_end], &value[tail_start..]))
+}
+
+/// A compiled rule pattern; `"*"` matches anything without a regex
+#[derive(Debug, Clone)]
+enum Pattern {
+    Any,
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn compile(key: &str, pattern: &str) -> Result<Self, String> {
+        if pattern == "*" {
+            return Ok(Pattern::Any);
         }
+        build_pattern_regex(pattern)
+            .map(Pattern::Regex)
+            .map_err(|error| match error {
+                regex::Error::CompiledTooBig(limit) => format!(
+                    "'{}' pattern \"{}\" is too complex (compiled size exceeds {} bytes)",
+                    key, pattern, limit
+                ),
+                error => format!(
+                    "'{}' pattern \"{}\" is not a valid regex: {}",
+                    key, pattern, error
+                ),
+            })
     }
 
-    fn unpause(&self) {
-        println!("[SNI] Unpause requested");
+    fn is_match(&self, value: &str) -> bool {
         match self {
-            SniControl::Local(control) => {
-                unpause_daemon(
-                    control.env,
-                    control.connection.clone(),
-                    control.is_kde6,
-                    &control.pause_broadcaster,
-                    &control.handler,
-                    &control.status_broadcaster,
-                    &control.kanata,
-                    &control.runtime_handle,
-                    "via SNI",
-                );
-            }
-            SniControl::Dbus(control) => {
-                control.runtime_handle.block_on(async {
-                    if let Err(error) = send_control_command_with_connection(
-                        &control.connection,
-                        ControlCommand::Unpause,
-                    )
-                    .await
-                    {
-                        eprintln!("[SNI] Failed to send unpause: {}", error);
-                    }
-                });
-            }
+            Pattern::Any => true,
+            Pattern::Regex(regex) => regex.is_match(&bounded_match_input(value)),
         }
     }
 }
 
-struct SniIndicator {
-    state: SniIndicatorState,
-    control: Arc<dyn SniControlOps>,
-    settings: SniSettingsStore,
-    menu_refresh: MenuRefresh,
+/// No pattern = no condition
+fn match_pattern(pattern: Option<&Pattern>, value: &str) -> bool {
+    pattern.is_none_or(|pattern| pattern.is_match(value))
 }
 
-impl SniIndicator {
-    fn update_status(&mut self, snapshot: StatusSnapshot) {
-        self.state.update_status(snapshot);
+/// Like `match_pattern` for values some windows don't have: a pattern never matches those
+fn match_known(pattern: Option<&Pattern>, value: Option<&str>) -> bool {
+    match (pattern, value) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(pattern), Some(value)) => pattern.is_match(value),
     }
+}
 
-    fn set_paused(&mut self, paused: bool) {
-        self.state.set_paused(paused);
+/// A rule's regex fields, compiled once when the rules are loaded instead of on every focus event
+#[derive(Debug, Clone, Default)]
+struct CompiledRule {
+    class: Option<Pattern>,
+    title: Option<Pattern>,
+    app: Option<Pattern>,
+    workspace: Option<Pattern>,
+    instance: Option<Pattern>,
+    role: Option<Pattern>,
+    initial_class: Option<Pattern>,
+    initial_title: Option<Pattern>,
+    script: Option<MatchScript>,
+    command: Option<Arc<CommandMatcher>>,
+}
+
+impl CompiledRule {
+    /// Fails on the first pattern that isn't a valid regex or is over the size limits, on a
+    /// script that doesn't compile or on an empty command
+    fn compile(rule: &Rule) -> Result<Self, String> {
+        let compile = |key: &str, pattern: Option<&str>| {
+            pattern.map(|pattern| Pattern::compile(key, pattern)).transpose()
+        };
+        Ok(CompiledRule {
+            class: compile("class", rule.class.as_deref())?,
+            title: compile("title", rule.title.as_deref())?,
+            app: compile("app", rule.app.as_deref())?,
+            workspace: compile("workspace", rule.workspace.as_deref())?,
+            instance: compile("instance", rule.instance.as_deref())?,
+            role: compile("role", rule.role.as_deref())?,
+            initial_class: compile("initial_class", rule.initial_class.as_deref())?,
+            initial_title: compile("initial_title", rule.initial_title.as_deref())?,
+            script: rule.script.as_deref().map(MatchScript::compile).transpose()?,
+            command: rule
+                .command
+                .as_deref()
+                .map(|argv| CommandMatcher::new(argv).map(Arc::new))
+                .transpose()?,
+        })
     }
 
-    fn toggle_focus_only(&mut self) {
-        self.state.toggle_focus_only();
-        let show_focus_only = self.state.focus_only_enabled();
-        self.settings.write_focus_only(show_focus_only);
-        self.menu_refresh.notify();
+    /// Class (already picked by `class_source`), title, desktop-file ID, workspace, X11
+    /// instance/role and initial identity
+    fn matches(&self, class: &str, win: &WindowInfo) -> bool {
+        match_pattern(self.class.as_ref(), class)
+            && match_pattern(self.title.as_ref(), &win.title)
+            && match_known(self.app.as_ref(), win.desktop_id.as_deref())
+            && match_known(self.workspace.as_ref(), win.workspace.as_deref())
+            && match_known(self.instance.as_ref(), win.instance.as_deref())
+            && match_known(self.role.as_ref(), win.role.as_deref())
+            && self.matches_initial(win.initial.as_ref())
     }
 
-    fn request_pause(&self) {
-        if self.state.paused {
-            self.control.unpause();
-        } else {
-            self.control.pause();
+    fn matches_initial(&self, initial: Option<&InitialIdentity>) -> bool {
+        if self.initial_class.is_none() && self.initial_title.is_none() {
+            return true;
         }
+        let Some(initial) = initial else {
+            return false;
+        };
+        match_pattern(self.initial_class.as_ref(), &initial.class)
+            && match_pattern(self.initial_title.as_ref(), &initial.title)
     }
+}
 
-    fn request_restart(&self) {
-        self.control.restart();
+/// Outcome of a rule's matcher script or command
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ScriptMatch {
+    NoMatch,
+    /// Matched; `Some` replaces the rule's `layer`
+    Match(Option<String>),
+}
+
+#[cfg(feature = "scripting")]
+use scripting::MatchScript;
+
+/// Stand-in for builds without the `scripting` feature: rules with a `script` are rejected at load
+#[cfg(not(feature = "scripting"))]
+#[derive(Debug, Clone)]
+struct MatchScript;
+
+#[cfg(not(feature = "scripting"))]
+impl MatchScript {
+    fn compile(_source: &str) -> Result<Self, String> {
+        Err("'script' needs a build with the `scripting` feature".to_string())
     }
 
-    fn format_layer_letter(layer_name: &str) -> String {
-        let trimmed = layer_name.trim();
-        if trimmed.is_empty() {
-            return "?".to_string();
+    fn run(&self, _win: &WindowInfo, _default_layer: &str) -> Result<ScriptMatch, String> {
+        Ok(ScriptMatch::NoMatch)
+    }
+}
+
+// === Command Matcher ===
+
+/// A `command` gets this long to exit before it's killed and the rule doesn't match
+const COMMAND_MATCH_TIMEOUT: Duration = Duration::from_secs(1);
+/// Results are reused for the same class and title for this long
+const COMMAND_MATCH_CACHE_TTL: Duration = Duration::from_secs(10);
+/// The cache starts over when it holds this many windows
+const COMMAND_MATCH_CACHE_MAX: usize = 256;
+
+/// When a matcher command ran, and its result (an error is reported once, see `run`)
+type CommandResult = (Instant, Result<ScriptMatch, String>);
+
+/// A rule's `command` with its results by (class, title)
+#[derive(Debug)]
+struct CommandMatcher {
+    argv: Vec<String>,
+    cache: Mutex<HashMap<(String, String), CommandResult>>,
+}
+
+impl CommandMatcher {
+    fn new(argv: &[String]) -> Result<Self, String> {
+        if argv.first().is_none_or(|program| program.is_empty()) {
+            return Err("'command' must not be empty".to_string());
         }
-        trimmed
-            .chars()
-            .next()
-            .map(|c| c.to_uppercase().to_string())
-            .unwrap_or_else(|| "?".to_string())
+        Ok(CommandMatcher {
+            argv: argv.to_vec(),
+            cache: Mutex::new(HashMap::new()),
+        })
     }
 
-    fn format_virtual_keys(virtual_keys: &[String]) -> String {
-        let count = virtual_keys.len();
-        if count == 0 {
-            return String::new();
+    /// The result `prefetch` stored for `class`/`title`; matching runs under the handler lock,
+    /// so it never runs the command itself. A failure is returned as an error once, then as no
+    /// match (so a broken command isn't re-run and re-reported on every event)
+    fn run(&self, class: &str, title: &str) -> Result<ScriptMatch, String> {
+        let key = (class.to_string(), title.to_string());
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get_mut(&key) {
+            Some((_, Ok(result))) => Ok(result.clone()),
+            Some((_, result)) => std::mem::replace(result, Ok(ScriptMatch::NoMatch)),
+            None => Err("no result, the command wasn't run before matching".to_string()),
         }
-        if count == 1 {
-            let name = virtual_keys[0].trim();
-            if name.is_empty() {
-                return String::new();
-            }
-            return name
-                .chars()
-                .next()
-                .map(|c| c.to_uppercase().to_string())
-                .unwrap_or_default();
-        }
-        if count < SNI_MIN_MULTI_VK_COUNT {
-            return String::new();
-        }
-        if count > SNI_MAX_VK_COUNT_DIGIT {
-            return format!("{}+", SNI_MAX_VK_COUNT_DIGIT);
-        }
-        count.to_string()
     }
 
-    fn glyph_for_char(ch: char) -> RasterizedChar {
-        get_raster(ch, SNI_FONT_WEIGHT, SNI_RASTER_HEIGHT)
-            .or_else(|| get_raster('?', SNI_FONT_WEIGHT, SNI_RASTER_HEIGHT))
-            .expect("SNI glyph lookup failed")
+    fn is_cached(&self, class: &str, title: &str) -> bool {
+        let key = (class.to_string(), title.to_string());
+        self.cache
+            .lock()
+            .unwrap()
+            .get(&key)
+            .is_some_and(|(at, _)| at.elapsed() < COMMAND_MATCH_CACHE_TTL)
     }
 
-    fn draw_glyph(
-        buffer: &mut [u8],
-        width: usize,
-        height: usize,
-        x: usize,
-        y: usize,
-        glyph: &RasterizedChar,
-        color: [u8; 4],
-    ) {
-        for (row_index, row) in glyph.raster().iter().enumerate() {
-            let dest_y = y + row_index;
-            if dest_y >= height {
-                continue;
+    /// Run the command for `class`/`title` unless a recent result is cached
+    async fn prefetch(&self, class: &str, title: &str) {
+        if self.is_cached(class, title) {
+            return;
+        }
+        let result = self.spawn(class, title).await;
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= COMMAND_MATCH_CACHE_MAX {
+            cache.clear();
+        }
+        cache.insert((class.to_string(), title.to_string()), (Instant::now(), result));
+    }
+
+    async fn spawn(&self, class: &str, title: &str) -> Result<ScriptMatch, String> {
+        let mut command = tokio::process::Command::new(&self.argv[0]);
+        command
+            .args(&self.argv[1..])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true);
+        apply_session_env(&mut command);
+        let mut child = command
+            .spawn()
+            .map_err(|error| format!("failed to run '{}': {}", self.argv[0], error))?;
+        // Titles are bounded like for regexes; a command that exits without reading stdin is fine
+        let input = format!("{}\n{}\n", class, bounded_match_input(title).replace('\n', " "));
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take();
+        // stdout is read while the command runs, so output beyond the pipe buffer can't stall it
+        let run = async {
+            if let Some(mut stdin) = stdin {
+                let _ = stdin.write_all(input.as_bytes()).await;
             }
-            for (col_index, intensity) in row.iter().enumerate() {
-                let dest_x = x + col_index;
-                if dest_x >= width {
-                    continue;
-                }
-                if *intensity == 0 {
-                    continue;
-                }
-                let offset = (dest_y * width + dest_x) * 4;
-                let alpha = (u16::from(color[0]) * u16::from(*intensity) / 255) as u8;
-                let red = (u16::from(color[1]) * u16::from(*intensity) / 255) as u8;
-                let green = (u16::from(color[2]) * u16::from(*intensity) / 255) as u8;
-                let blue = (u16::from(color[3]) * u16::from(*intensity) / 255) as u8;
-                buffer[offset] = alpha;
-                buffer[offset + 1] = red;
-                buffer[offset + 2] = green;
-                buffer[offset + 3] = blue;
+            let mut first_line = String::new();
+            if let Some(stdout) = stdout {
+                let mut stdout = TokioBufReader::new(stdout);
+                let _ = stdout.read_line(&mut first_line).await;
+                let _ = tokio::io::copy(&mut stdout, &mut tokio::io::sink()).await;
+            }
+            child.wait().await.map(|status| (status, first_line))
+        };
+        match tokio::time::timeout(COMMAND_MATCH_TIMEOUT, run).await {
+            // Dropping the child kills it
+            Err(_) => Err(format!("timed out after {} ms", COMMAND_MATCH_TIMEOUT.as_millis())),
+            Ok(Err(error)) => Err(error.to_string()),
+            Ok(Ok((status, _))) if !status.success() => Ok(ScriptMatch::NoMatch),
+            Ok(Ok((_, first_line))) => {
+                let layer = first_line.trim();
+                Ok(ScriptMatch::Match((!layer.is_empty()).then(|| layer.to_string())))
             }
         }
     }
+}
 
-    fn text_width(text: &str) -> usize {
-        SNI_GLYPH_WIDTH * text.chars().count()
-    }
+/// A matcher command a window may need, with the class and title it gets
+struct PendingCommand {
+    matcher: Arc<CommandMatcher>,
+    class: String,
+    title: String,
+}
 
-    fn draw_text(
-        buffer: &mut [u8],
-        width: usize,
-        height: usize,
-        x: usize,
-        y: usize,
-        text: &str,
-        color: [u8; 4],
-    ) -> usize {
-        let mut cursor_x = x;
-        for ch in text.chars() {
-            let glyph = Self::glyph_for_char(ch);
-            Self::draw_glyph(buffer, width, height, cursor_x, y, &glyph, color);
-            cursor_x += glyph.width();
-        }
-        cursor_x - x
-    }
+/// Run matcher commands (concurrently, without the handler lock) so matching finds their
+/// results cached
+async fn run_matcher_commands(commands: Vec<PendingCommand>) {
+    futures_util::future::join_all(
+        commands
+            .iter()
+            .map(|command| command.matcher.prefetch(&command.class, &command.title)),
+    )
+    .await;
+}
 
-    fn render_icon(layer_text: &str, vk_text: &str) -> SniIcon {
-        let layer_width = Self::text_width(layer_text);
-        let vk_width = Self::text_width(vk_text);
-        let gap = if vk_text.is_empty() {
-            0
-        } else {
-            SNI_GLYPH_GAP
-        };
-        let icon_width = layer_width + gap + vk_width;
-        let mut buffer = vec![0u8; icon_width * SNI_ICON_HEIGHT * 4];
-        let glyph_y = (SNI_ICON_HEIGHT - SNI_GLYPH_HEIGHT) / 2;
-        let layer_x = 0;
-        let vk_x = layer_x + layer_width + gap;
+// === Plugins ===
 
-        Self::draw_text(
-            &mut buffer,
-            icon_width,
-            SNI_ICON_HEIGHT,
-            layer_x,
-            glyph_y,
-            layer_text,
-            SNI_COLOR_LAYER,
-        );
-        if !vk_text.is_empty() {
-            Self::draw_text(
-                &mut buffer,
-                icon_width,
-                SNI_ICON_HEIGHT,
-                vk_x,
-                glyph_y,
-                vk_text,
-                SNI_COLOR_VK,
-            );
-        }
+#[cfg(feature = "wasm-plugins")]
+use plugins::WasmPlugin;
 
-        SniIcon {
-            width: icon_width as i32,
-            height: SNI_ICON_HEIGHT as i32,
-            data: buffer,
-        }
+/// Stand-in for builds without the `wasm-plugins` feature: rules using plugins are rejected at load
+#[cfg(not(feature = "wasm-plugins"))]
+#[derive(Debug)]
+struct WasmPlugin;
+
+#[cfg(not(feature = "wasm-plugins"))]
+#[allow(dead_code)]
+impl WasmPlugin {
+    fn load(_name: &str, _path: &Path) -> Result<Self, String> {
+        Err("needs a build with the `wasm-plugins` feature".to_string())
     }
 
-    fn display_strings(&self) -> (String, String) {
-        let status = self.state.display_status();
-        let layer_text = Self::format_layer_letter(&status.layer);
-        let vk_text = Self::format_virtual_keys(&status.virtual_keys);
-        (layer_text, vk_text)
+    fn name(&self) -> &str {
+        ""
     }
 
-    fn tooltip_text(&self) -> String {
-        let status = self.state.display_status();
-        if status.virtual_keys.is_empty() {
-            return format!("Layer: {}", status.layer);
-        }
-        format!(
-            "Layer: {}\nVirtual keys: {}",
-            status.layer,
-            status.virtual_keys.join(", ")
-        )
+    fn matches(&self, _window_json: &str) -> Result<bool, String> {
+        Ok(false)
     }
 
-    fn title_text(&self) -> String {
-        "Kanata Switcher".to_string()
+    fn run_action(&self, _payload: &str) -> Result<(), String> {
+        Ok(())
     }
 }
 
-impl Tray for SniIndicator {
-    fn id(&self) -> String {
-        SNI_INDICATOR_ID.to_string()
+/// `<plugins_dir>/<name>.wasm`, if plugins are available and the module exists
+fn plugin_path(plugins_dir: Option<&Path>, name: &str) -> Result<PathBuf, String> {
+    if !cfg!(feature = "wasm-plugins") {
+        return Err(format!(
+            "plugin '{}' needs a build with the `wasm-plugins` feature",
+            name
+        ));
     }
-
-    fn title(&self) -> String {
-        self.title_text()
+    let Some(plugins_dir) = plugins_dir else {
+        return Err(format!(
+            "plugin '{}' needs a 'plugins_dir' entry to load it from",
+            name
+        ));
+    };
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err(format!(
+            "plugin name '{}' must be a file name without the .wasm extension",
+            name
+        ));
     }
+    let path = plugins_dir.join(format!("{}.wasm", name));
+    if !path.is_file() {
+        return Err(format!("plugin '{}' not found at {}", name, path.display()));
+    }
+    Ok(path)
+}
 
-    fn status(&self) -> SniStatus {
-        SniStatus::Active
+/// Loads every plugin the rules use. Failures are logged; rules using such a plugin never
+/// match (`plugin`) or skip it (`plugin_action`)
+fn load_plugins(config: &Config) -> BTreeMap<String, Arc<WasmPlugin>> {
+    let names: BTreeSet<&str> = config.rules.iter().flat_map(Rule::plugin_names).collect();
+    let mut plugins = BTreeMap::new();
+    for name in names {
+        let loaded = plugin_path(config.plugins_dir.as_deref(), name)
+            .and_then(|path| WasmPlugin::load(name, &path).map(|plugin| (path, plugin)));
+        match loaded {
+            Ok((path, plugin)) => {
+                log_line!("[Plugins] Loaded '{}' from {}", name, path.display());
+                plugins.insert(name.to_string(), Arc::new(plugin));
+            }
+            Err(error) => elog_line!("[Plugins] Failed to load '{}': {}", name, error),
+        }
     }
+    plugins
+}
 
-    fn icon_pixmap(&self) -> Vec<SniIcon> {
-        let (layer_text, vk_text) = self.display_strings();
-        vec![Self::render_icon(&layer_text, &vk_text)]
+/// The window as plugins see it. Values the backend doesn't report are `null`
+fn window_json(win: &WindowInfo) -> serde_json::Value {
+    serde_json::json!({
+        "class": win.class,
+        "title": win.title,
+        "x11_class": win.x11_class,
+        "app": win.desktop_id,
+        "workspace": win.workspace,
+        "instance": win.instance,
+        "role": win.role,
+        "initial_class": win.initial.as_ref().map(|initial| &initial.class),
+        "initial_title": win.initial.as_ref().map(|initial| &initial.title),
+        "width": win.size.map(|size| size.width),
+        "height": win.size.map(|size| size.height),
+        "is_native_terminal": win.is_native_terminal,
+    })
+}
+
+/// A `plugin_action` to run: the plugin and its JSON argument
+#[derive(Debug, Clone)]
+struct PluginCall {
+    plugin: Arc<WasmPlugin>,
+    payload: String,
+}
+
+impl PartialEq for PluginCall {
+    fn eq(&self, other: &Self) -> bool {
+        self.plugin.name() == other.plugin.name() && self.payload == other.payload
     }
+}
 
-    fn tool_tip(&self) -> ToolTip {
-        ToolTip {
-            title: self.title_text(),
-            description: self.tooltip_text(),
-            ..ToolTip::default()
+impl Eq for PluginCall {}
+
+impl PluginCall {
+    /// Runs the action on a blocking thread; failures are logged, not returned
+    async fn run(self) {
+        let plugin = self.plugin.clone();
+        let result = tokio::task::spawn_blocking(move || plugin.run_action(&self.payload)).await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => {
+                elog_line!("[Plugins] '{}' action failed: {}", self.plugin.name(), error)
+            }
+            Err(error) => {
+                elog_line!("[Plugins] '{}' action panicked: {}", self.plugin.name(), error)
+            }
         }
     }
+}
 
-    fn menu(&self) -> Vec<MenuItem<Self>> {
-        vec![
-            MenuItem::Checkmark(CheckmarkItem {
-                label: "Pause".to_string(),
-                checked: self.state.paused,
-                activate: Box::new(|this| {
-                    this.request_pause();
-                }),
-                ..CheckmarkItem::default()
-            }),
-            MenuItem::Checkmark(CheckmarkItem {
-                label: "Show app layer only".to_string(),
-                checked: self.state.show_focus_only,
-                activate: Box::new(|this| {
-                    this.toggle_focus_only();
-                }),
-                ..CheckmarkItem::default()
-            }),
-            MenuItem::Separator,
-            MenuItem::Standard(StandardItem {
-                label: "Restart".to_string(),
-                activate: Box::new(|this| {
-                    this.request_restart();
-                }),
-                ..StandardItem::default()
-            }),
-        ]
-    }
+// === Kanata Config Names ===
 
-    fn watcher_online(&self) {
-        println!("[SNI] StatusNotifierWatcher online");
-    }
+/// Layer and virtual key names declared in a kanata .kbd config.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct KanataConfigNames {
+    layers: Vec<String>,
+    virtual_keys: Vec<String>,
+}
 
-    fn watcher_offine(&self) -> bool {
-        eprintln!("[SNI] StatusNotifierWatcher offline");
-        true
-    }
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum KbdExpr {
+    Atom(String),
+    List(Vec<KbdExpr>),
 }
 
-fn resolve_sni_focus_only(
-    override_value: Option<TrayFocusOnly>,
-    settings: &mut SniSettingsStore,
-) -> bool {
-    if let Some(value) = override_value {
-        return value.as_bool();
+impl KbdExpr {
+    fn as_atom(&self) -> Option<&str> {
+        match self {
+            KbdExpr::Atom(atom) => Some(atom),
+            KbdExpr::List(_) => None,
+        }
     }
-    settings
-        .read_focus_only()
-        .unwrap_or(SNI_DEFAULT_SHOW_FOCUS_ONLY)
 }
 
-/// Execute focus actions in order
-async fn execute_focus_actions(kanata: &KanataClient, actions: FocusActions) {
-    for action in actions.actions {
-        match action {
-            FocusAction::ReleaseVk(vk) => {
-                kanata.act_on_fake_key(&vk, "Release").await;
+/// Parse the top-level s-expressions of a .kbd file.
+/// Handles `;;` line comments, `#| |#` block comments and quoted strings; unbalanced input is an error.
+fn parse_kbd_exprs(content: &str) -> Result<Vec<KbdExpr>, String> {
+    let chars: Vec<char> = content.chars().collect();
+    let mut stack: Vec<Vec<KbdExpr>> = vec![Vec::new()];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let ch = chars[i];
+        if ch.is_whitespace() {
+            i += 1;
+        } else if ch == ';' && chars.get(i + 1) == Some(&';') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
             }
-            FocusAction::ChangeLayer(layer) => {
-                kanata.change_layer(&layer).await;
+        } else if ch == '#' && chars.get(i + 1) == Some(&'|') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '|' && chars.get(i + 1) == Some(&'#')) {
+                i += 1;
             }
-            FocusAction::PressVk(vk) => {
-                kanata.act_on_fake_key(&vk, "Press").await;
+            if i >= chars.len() {
+                return Err("unterminated block comment".to_string());
             }
-            FocusAction::RawVkAction(name, action) => {
-                kanata.act_on_fake_key(&name, &action).await;
+            i += 2;
+        } else if ch == '(' {
+            stack.push(Vec::new());
+            i += 1;
+        } else if ch == ')' {
+            if stack.len() == 1 {
+                return Err("unexpected ')'".to_string());
             }
+            let list = stack.pop().unwrap();
+            stack.last_mut().unwrap().push(KbdExpr::List(list));
+            i += 1;
+        } else if ch == '"' {
+            let start = i + 1;
+            i += 1;
+            while i < chars.len() && chars[i] != '"' {
+                i += 1;
+            }
+            if i >= chars.len() {
+                return Err("unterminated string".to_string());
+            }
+            let atom: String = chars[start..i].iter().collect();
+            stack.last_mut().unwrap().push(KbdExpr::Atom(atom));
+            i += 1;
+        } else {
+            let start = i;
+            while i < chars.len() && !chars[i].is_whitespace() && chars[i] != '(' && chars[i] != ')' {
+                i += 1;
+            }
+            let atom: String = chars[start..i].iter().collect();
+            stack.last_mut().unwrap().push(KbdExpr::Atom(atom));
         }
     }
+
+    if stack.len() != 1 {
+        return Err("unbalanced parentheses".to_string());
+    }
+    Ok(stack.pop().unwrap())
 }
 
-fn extract_focus_layer(actions: &FocusActions) -> Option<String> {
-    actions.actions.iter().fold(None, |last, action| {
-        if let FocusAction::ChangeLayer(layer) = action {
-            Some(layer.clone())
-        } else {
-            last
+/// Collect names from top-level forms. Returns `include` paths for the caller to follow.
+fn collect_kbd_names(exprs: &[KbdExpr], names: &mut KanataConfigNames) -> Vec<String> {
+    let mut includes = Vec::new();
+    for expr in exprs {
+        let KbdExpr::List(items) = expr else {
+            continue;
+        };
+        let Some(head) = items.first().and_then(KbdExpr::as_atom) else {
+            continue;
+        };
+        match head {
+            "deflayer" => {
+                if let Some(name) = items.get(1).and_then(KbdExpr::as_atom) {
+                    names.layers.push(name.to_string());
+                }
+            }
+            "deflayermap" => {
+                if let Some(KbdExpr::List(name)) = items.get(1)
+                    && let Some(name) = name.first().and_then(KbdExpr::as_atom)
+                {
+                    names.layers.push(name.to_string());
+                }
+            }
+            "defvirtualkeys" | "deffakekeys" => {
+                for name in items[1..].iter().step_by(2).filter_map(KbdExpr::as_atom) {
+                    names.virtual_keys.push(name.to_string());
+                }
+            }
+            "include" => {
+                if let Some(path) = items.get(1).and_then(KbdExpr::as_atom) {
+                    includes.push(path.to_string());
+                }
+            }
+            _ => {}
         }
-    })
+    }
+    includes
 }
 
-async fn update_status_for_focus(
-    handler: &Arc<Mutex<FocusHandler>>,
-    status_broadcaster: &StatusBroadcaster,
-    win: &WindowInfo,
-    kanata: &KanataClient,
-    default_layer: &str,
-) -> Option<FocusActions> {
-    let (actions, virtual_keys, focus_layer) = {
-        let mut handler = handler.lock().unwrap();
-        let actions = handler.handle(win, default_layer);
-        let virtual_keys = handler.current_virtual_keys();
-        let focus_layer = actions
-            .as_ref()
-            .and_then(|focus_actions| extract_focus_layer(focus_actions));
-        (actions, virtual_keys, focus_layer)
-    };
+/// Read a kanata config, following `(include ...)` relative to the including file.
+fn load_kanata_config_names(path: &Path) -> Result<KanataConfigNames, String> {
+    let mut names = KanataConfigNames::default();
+    let mut pending = vec![path.to_path_buf()];
+    let mut visited: Vec<PathBuf> = Vec::new();
 
-    // Filter out invalid VKs before updating indicator
-    let known_vks = kanata.known_virtual_keys().await;
-    let valid_virtual_keys = KanataClient::filter_valid_virtual_keys(&known_vks, virtual_keys);
-    status_broadcaster.update_virtual_keys(valid_virtual_keys);
-    if let Some(layer) = focus_layer {
-        if let Some(resolved_layer) = kanata.resolve_layer_name(&layer, false).await {
-            status_broadcaster.update_focus_layer(resolved_layer);
+    while let Some(path) = pending.pop() {
+        if visited.contains(&path) {
+            continue;
+        }
+        let content = fs::read_to_string(&path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let exprs =
+            parse_kbd_exprs(&content).map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+        let base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        for include in collect_kbd_names(&exprs, &mut names) {
+            pending.push(base_dir.join(include));
         }
+        visited.push(path);
     }
 
-    actions
+    Ok(names)
 }
 
-async fn handle_focus_event(
-    handler: &Arc<Mutex<FocusHandler>>,
-    status_broadcaster: &StatusBroadcaster,
-    pause_broadcaster: &PauseBroadcaster,
-    win: &WindowInfo,
-    kanata: &KanataClient,
-    default_layer: &str,
-) -> Option<FocusActions> {
-    if pause_broadcaster.is_paused() {
-        return None;
-    }
-    update_status_for_focus(handler, status_broadcaster, win, kanata, default_layer).await
-}
-
-fn native_terminal_window() -> WindowInfo {
-    WindowInfo {
-        class: String::new(),
-        title: String::new(),
-        is_native_terminal: true,
-    }
+/// Check every layer and virtual key referenced by the config against the kanata config.
+/// Returns one human-readable problem per unknown name.
+fn validate_config_names(config: &Config, names: &KanataConfigNames) -> Vec<String> {
+    config_name_problems(config, &names.layers, Some(&names.virtual_keys))
 }
 
-#[derive(Clone, Copy, Debug)]
-struct RawFdWatcher {
-    fd: RawFd,
+/// A layer or virtual key name the config sends to kanata
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfigNameRef<'a> {
+    Layer(&'a str),
+    VirtualKey(&'a str),
 }
 
-impl RawFdWatcher {
-    fn new(fd: RawFd) -> Self {
-        Self { fd }
+/// Names the config sends to one kanata instance, each with the entry naming it: the daemon's
+/// own instance (`None`) or the endpoint rules target with `"kanata": name`
+fn config_name_refs<'a>(
+    config: &'a Config,
+    endpoint: Option<&str>,
+) -> Vec<(String, ConfigNameRef<'a>)> {
+    fn push_raw<'a>(
+        refs: &mut Vec<(String, ConfigNameRef<'a>)>,
+        context: &str,
+        steps: &'a [RawVkStep],
+    ) {
+        for step in steps {
+            if let RawVkStep::Action(name, _) = step {
+                refs.push((context.to_string(), ConfigNameRef::VirtualKey(name)));
+            }
+        }
     }
-}
 
-impl AsRawFd for RawFdWatcher {
-    fn as_raw_fd(&self) -> RawFd {
-        self.fd
-    }
-}
-
-fn query_wayland_active_window() -> Result<WindowInfo, Box<dyn std::error::Error + Send + Sync>> {
-    #[cfg(test)]
-    {
-        WAYLAND_QUERY_COUNTER.fetch_add(1, Ordering::SeqCst);
-    }
-    let connection = WaylandConnection::connect_to_env()?;
-    let (globals, mut queue) = registry_queue_init::<WaylandState>(&connection)?;
-    let mut state = WaylandState::default();
-
-    if globals
-        .bind::<ZwlrForeignToplevelManagerV1, _, _>(&queue.handle(), 1..=3, ())
-        .is_err()
-        && globals
-            .bind::<ZcosmicToplevelInfoV1, _, _>(&queue.handle(), 1..=1, ())
-            .is_err()
-    {
-        return Err(
-            "No supported toplevel protocol (wlr-foreign-toplevel or cosmic-toplevel-info)".into(),
-        );
+    let mut refs = Vec::new();
+    if endpoint.is_none() {
+        if let Some(default) = config.default_layer.as_deref() {
+            refs.push(("default".to_string(), ConfigNameRef::Layer(default)));
+        }
+        for layer in config.layer_display_names.keys() {
+            refs.push(("layer_names".to_string(), ConfigNameRef::Layer(layer)));
+        }
+        for (layer, vks) in &config.layer_virtual_keys {
+            refs.push(("layer_virtual_keys".to_string(), ConfigNameRef::Layer(layer)));
+            for vk in vks {
+                refs.push(("layer_virtual_keys".to_string(), ConfigNameRef::VirtualKey(vk)));
+            }
+        }
+        for layer in config.tray_icons.keys() {
+            refs.push(("tray_icons".to_string(), ConfigNameRef::Layer(layer)));
+        }
+        for layer in config.indicator.layer_colors.keys() {
+            refs.push(("indicator".to_string(), ConfigNameRef::Layer(layer)));
+        }
+        if let Some(announce) = config.announce.as_ref() {
+            for layer in announce.layers.keys() {
+                refs.push(("announce".to_string(), ConfigNameRef::Layer(layer)));
+            }
+        }
+        if let Some(idle) = config.idle.as_ref() {
+            refs.push(("on_idle".to_string(), ConfigNameRef::Layer(&idle.on_idle)));
+        }
+        let special_rules = [
+            ("on_native_terminal", config.native_terminal_rule.as_ref()),
+            ("on_session_inactive", config.session_inactive_rule.as_ref()),
+            ("on_locked", config.locked_rule.as_ref()),
+        ];
+        for (key, rule) in special_rules {
+            let Some(rule) = rule else {
+                continue;
+            };
+            refs.push((key.to_string(), ConfigNameRef::Layer(&rule.layer)));
+            if let Some(vk) = rule.virtual_key.as_deref() {
+                refs.push((key.to_string(), ConfigNameRef::VirtualKey(vk)));
+            }
+            push_raw(&mut refs, key, &rule.raw_vk_action);
+        }
     }
-
-    for _ in 0..5 {
-        queue.roundtrip(&mut state)?;
-        if state.active_window.is_some() {
-            break;
+    // Rules with `kanata` name another instance's layers and keys
+    for (index, rule) in config.rules.iter().enumerate() {
+        if rule.kanata.as_deref() != endpoint {
+            continue;
         }
+        let context = rule.context(index);
+        if let Some(layer) = rule.layer.as_deref() {
+            refs.push((context.clone(), ConfigNameRef::Layer(layer)));
+        }
+        if let Some(vk) = rule.virtual_key.as_deref() {
+            refs.push((context.clone(), ConfigNameRef::VirtualKey(vk)));
+        }
+        push_raw(&mut refs, &context, rule.raw_vk_action.as_deref().unwrap_or_default());
     }
-    Ok(state.get_active_window())
+    refs
 }
 
-#[cfg(test)]
-fn wayland_query_count() -> usize {
-    WAYLAND_QUERY_COUNTER.load(Ordering::SeqCst)
+/// The layers and virtual keys `config_name_refs` finds, sorted, as if a kanata config declared them
+fn config_kanata_names(config: &Config, endpoint: Option<&str>) -> KanataConfigNames {
+    let mut layers = BTreeSet::new();
+    let mut virtual_keys = BTreeSet::new();
+    for (_, name) in config_name_refs(config, endpoint) {
+        match name {
+            ConfigNameRef::Layer(layer) => layers.insert(layer.to_string()),
+            ConfigNameRef::VirtualKey(vk) => virtual_keys.insert(vk.to_string()),
+        };
+    }
+    KanataConfigNames {
+        layers: layers.into_iter().collect(),
+        virtual_keys: virtual_keys.into_iter().collect(),
+    }
 }
 
-fn query_x11_active_window() -> Result<WindowInfo, Box<dyn std::error::Error + Send + Sync>> {
-    let state = X11State::new()?;
-    Ok(state.get_active_window())
+/// Unknown names referenced by the config; virtual keys are only checked when a list is given.
+fn config_name_problems(
+    config: &Config,
+    layers: &[String],
+    virtual_keys: Option<&[String]>,
+) -> Vec<String> {
+    config_name_refs(config, None)
+        .into_iter()
+        .filter_map(|(context, name)| match name {
+            ConfigNameRef::Layer(layer) => (!layers.iter().any(|known| known == layer))
+                .then(|| format!("{}: unknown layer \"{}\"", context, layer)),
+            ConfigNameRef::VirtualKey(vk) => virtual_keys
+                .filter(|virtual_keys| !virtual_keys.iter().any(|known| known == vk))
+                .map(|_| format!("{}: unknown virtual key \"{}\"", context, vk)),
+        })
+        .collect()
 }
 
-static KDE_QUERY_COUNTER: AtomicU64 = AtomicU64::new(0);
-#[cfg(test)]
-static WAYLAND_QUERY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+/// Validate rules against `--kanata-config` at startup. Problems are warnings: kanata
+/// itself remains the source of truth once connected. Under `--unknown-layer error`,
+/// unknown layers fail startup.
+fn warn_unknown_config_names(
+    config: &Config,
+    kanata_config: &Path,
+    policy: UnknownLayerPolicy,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match load_kanata_config_names(kanata_config) {
+        Ok(names) => {
+            if policy == UnknownLayerPolicy::Error {
+                check_config_layers(config, &names.layers)?;
+            }
+            for problem in validate_config_names(config, &names) {
+                elog_line!("[Config] Warning: {}", problem);
+            }
+        }
+        Err(e) => {
+            elog_line!("[Config] Warning: Cannot validate rules against kanata config: {}", e);
+        }
+    }
+    Ok(())
+}
 
-#[derive(Debug)]
-struct KdeFocusQueryService {
-    sender: TokioMutex<Option<oneshot::Sender<WindowInfo>>>,
+/// `--unknown-layer error`: every layer named in the config must exist in `layers`.
+fn check_config_layers(
+    config: &Config,
+    layers: &[String],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let problems = config_name_problems(config, layers, None);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    for problem in &problems {
+        elog_line!("[Config] Error: {}", problem);
+    }
+    Err(format!(
+        "config names {} unknown layer(s) (--unknown-layer error)",
+        problems.len()
+    )
+    .into())
 }
 
-#[zbus::interface(name = "com.github.kanata.Switcher.KdeQuery")]
-impl KdeFocusQueryService {
-    #[allow(non_snake_case)]
-    async fn Focus(&self, window_class: &str, window_title: &str) {
+/// The config's `tests` cases whose window doesn't end on the expected layer and virtual keys
+/// in a freshly started daemon (matcher commands, scripts and plugins run as usual)
+async fn rule_test_failures(config: &Config) -> Vec<String> {
+    let handler = FocusHandler::from_config(config, true, false);
+    let default_layer = config.default_layer.as_deref().unwrap_or("");
+    let mut failures = Vec::new();
+    for (index, test) in config.rule_tests.iter().enumerate() {
         let win = WindowInfo {
-            class: window_class.to_string(),
-            title: window_title.to_string(),
-            is_native_terminal: false,
+            class: test.class.clone(),
+            title: test.title.clone(),
+            ..Default::default()
         };
-        let mut sender = self.sender.lock().await;
-        if let Some(tx) = sender.take() {
-            let _ = tx.send(win);
+        run_matcher_commands(handler.commands_to_run(&win)).await;
+        let matched = handler.match_rules(&win, default_layer);
+        let layer = matched
+            .iter()
+            .rev()
+            .find_map(|(_, layer)| layer.clone())
+            .or_else(|| config.default_layer.clone());
+        let virtual_keys: Vec<String> = matched
+            .iter()
+            .filter_map(|(rule, _)| handler.rules[*rule].virtual_key.clone())
+            .collect();
+
+        let mut mismatches = Vec::new();
+        if let Some(expected) = &test.layer
+            && layer.as_ref() != Some(expected)
+        {
+            let got = layer.map_or("no layer change".to_string(), |layer| format!("\"{}\"", layer));
+            mismatches.push(format!("expected layer \"{}\", got {}", expected, got));
+        }
+        if let Some(expected) = &test.virtual_keys
+            && *expected != virtual_keys
+        {
+            mismatches.push(format!(
+                "expected virtual keys [{}], got [{}]",
+                expected.join(", "),
+                virtual_keys.join(", ")
+            ));
+        }
+        if mismatches.is_empty() {
+            continue;
+        }
+        let rules: Vec<String> = matched
+            .iter()
+            .map(|(rule, _)| handler.rule_ref(*rule).label())
+            .collect();
+        failures.push(format!(
+            "test #{} (class=\"{}\" title=\"{}\"): {} (matched {})",
+            index + 1,
+            test.class,
+            test.title,
+            mismatches.join("; "),
+            if rules.is_empty() { "no rule".to_string() } else { rules.join(", ") }
+        ));
+    }
+    failures
+}
+
+/// One `--match` line per focus action
+fn describe_focus_action(action: &FocusAction) -> String {
+    match action {
+        FocusAction::ReleaseVk(vk) => format!("release virtual key \"{}\"", vk),
+        FocusAction::ChangeLayer(layer) => format!("switch to layer \"{}\"", layer),
+        FocusAction::PressVk(vk) => format!("press virtual key \"{}\"", vk),
+        FocusAction::RawVkAction(vk, action) => format!("{} virtual key \"{}\"", action, vk),
+        FocusAction::Delay(ms) => format!("wait {} ms", ms),
+        FocusAction::ReloadNum(index) => format!("reload kanata config #{}", index),
+        FocusAction::ReloadNext => "reload the next kanata config".to_string(),
+        FocusAction::Plugin(call) => format!("run plugin '{}'", call.plugin.name()),
+        FocusAction::RunCommand(command) => {
+            format!("run {} of {}: {}", command.key, command.rule, command.command)
         }
     }
 }
 
-fn kwin_script_object_path(
-    script_num: i32,
-    is_kde6: bool,
-) -> Result<OwnedObjectPath, Box<dyn std::error::Error + Send + Sync>> {
-    let path = if is_kde6 {
-        format!("/Scripting/Script{}", script_num)
+/// `--match`: what a freshly started daemon does when a window with `class` and `title` gets
+/// focus. Matcher commands, scripts and plugins run; actions are only listed
+async fn match_window_report(
+    config: &Config,
+    class: &str,
+    title: &str,
+    default_layer: &str,
+) -> String {
+    let mut handler = FocusHandler::from_config(config, true, false);
+    let win = WindowInfo {
+        class: class.to_string(),
+        title: title.to_string(),
+        ..Default::default()
+    };
+    run_matcher_commands(handler.commands_to_run(&win)).await;
+    let actions = handler.handle(&win, default_layer);
+    let mut lines = vec![format!("Window: class=\"{}\" title=\"{}\"", class, title)];
+    let matched = handler.last_matched_rule_refs();
+    if matched.is_empty() {
+        lines.push("Matched: no rule".to_string());
     } else {
-        format!("/{}", script_num)
+        lines.push("Matched:".to_string());
+        for rule in &matched {
+            let label = match &rule.name {
+                Some(name) => format!("{} '{}'", rule_label(rule.index), name),
+                None => rule_label(rule.index),
+            };
+            lines.push(format!("  {}: {}", label, handler.rules[rule.index].summary()));
+        }
+    }
+    let layer = actions.as_ref().and_then(extract_focus_layer);
+    lines.push(match layer {
+        Some(layer) if layer == default_layer => format!("Layer: {} (default)", layer),
+        Some(layer) => format!("Layer: {}", layer),
+        None => "Layer: unchanged".to_string(),
+    });
+    let virtual_keys = handler.current_virtual_keys();
+    lines.push(format!(
+        "Virtual keys held: {}",
+        if virtual_keys.is_empty() { "none".to_string() } else { virtual_keys.join(", ") }
+    ));
+    let Some(actions) = actions else {
+        lines.push("Actions: none".to_string());
+        return lines.join("\n");
     };
-    let obj_path: OwnedObjectPath = path.as_str().try_into()?;
-    Ok(obj_path)
+    lines.push("Actions:".to_string());
+    lines.extend(
+        actions
+            .actions
+            .iter()
+            .map(|action| format!("  {}", describe_focus_action(action))),
+    );
+    for (endpoint, endpoint_actions) in &actions.targeted {
+        lines.extend(endpoint_actions.iter().map(|action| {
+            format!("  kanata \"{}\": {}", endpoint, describe_focus_action(action))
+        }));
+    }
+    lines.join("\n")
 }
 
-async fn load_kwin_script(
-    connection: &Connection,
-    script_path: &str,
-    is_kde6: bool,
-    cleanup_existing: bool,
-) -> Result<(OwnedObjectPath, &'static str), Box<dyn std::error::Error + Send + Sync>> {
-    if cleanup_existing {
-        for _ in 0..5 {
-            let result = connection
-                .call_method(
-                    Some("org.kde.KWin"),
-                    "/Scripting",
-                    Some("org.kde.kwin.Scripting"),
-                    "loadScript",
-                    &(&script_path,),
-                )
-                .await;
+async fn match_window(
+    args: &Args,
+    class: &str,
+    title: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let config = load_config(args.config.as_deref())?;
+    let target = resolve_kanata_target(args, &config)?;
+    let default_layer = target.default_layer.unwrap_or_default();
+    println!("{}", match_window_report(&config, class, title, &default_layer).await);
+    Ok(())
+}
 
-            if result.is_ok() {
-                break;
-            }
-            tokio::time::sleep(Duration::from_secs(1)).await;
+/// `--check-kanata`: the layers and virtual keys of the kanata instance the daemon would
+/// switch, and where it runs. Virtual keys are None for kanata without `RequestFakeKeyNames`
+async fn running_kanata_names(
+    args: &Args,
+    config: &Config,
+) -> Result<(String, Vec<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync>>
+{
+    let target = resolve_kanata_target(args, config)?;
+    let kanata = build_kanata_client(args, config, &StatusBroadcaster::new()).await?;
+    // Each failed protocol probe (older kanata) drops the connection once; the retry skips it
+    let mut probed = KanataCapabilities::default();
+    let mut connected = kanata.try_connect().await;
+    while connected.is_err() && kanata.capabilities().await != probed {
+        probed = kanata.capabilities().await;
+        connected = kanata.try_connect().await;
+    }
+    let location = format!("kanata at {}:{}", target.host, target.port);
+    connected.map_err(|error| format!("cannot connect to {}: {}", location, error))?;
+    let layers = kanata.known_layers().await;
+    let virtual_keys = kanata.known_virtual_keys().await;
+    // Also stops reconnecting
+    kanata.pause_disconnect().await;
+    Ok((location, layers, virtual_keys))
+}
+
+/// `--check-config`: load and validate the config, reporting every problem found.
+async fn check_config(args: &Args) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let config = load_config(args.config.as_deref())?;
+    warn_unsupported_conditions(&config, detect_environment());
+    let mut problems = rule_test_failures(&config).await;
+    if !config.rule_tests.is_empty() && problems.is_empty() {
+        println!("[Config] {} rule test(s) passed", config.rule_tests.len());
+    }
+    // Where the names were checked, e.g. "4 layers, 3 virtual keys in kanata.kbd"
+    let mut checked = Vec::new();
+    if let Some(kanata_config) = args.kanata_config.as_deref() {
+        let names = load_kanata_config_names(kanata_config)?;
+        problems.extend(validate_config_names(&config, &names));
+        checked.push(format!(
+            "{} layers, {} virtual keys in {}",
+            names.layers.len(),
+            names.virtual_keys.len(),
+            kanata_config.display()
+        ));
+    }
+    if args.check_kanata {
+        let (location, layers, virtual_keys) = running_kanata_names(args, &config).await?;
+        problems.extend(config_name_problems(&config, &layers, virtual_keys.as_deref()));
+        checked.push(match virtual_keys {
+            Some(virtual_keys) => format!(
+                "{} layers, {} virtual keys in {}",
+                layers.len(),
+                virtual_keys.len(),
+                location
+            ),
+            None => format!(
+                "{} layers in {}, too old to list virtual keys",
+                layers.len(),
+                location
+            ),
+        });
+    }
+    if problems.is_empty() {
+        if checked.is_empty() {
+            println!(
+                "[Config] OK (pass --kanata-config or --check-kanata to also check layer and virtual key names)"
+            );
+        } else {
+            println!("[Config] OK ({})", checked.join("; "));
         }
+        return Ok(());
+    }
+    for problem in &problems {
+        eprintln!("[Config] Error: {}", problem);
+    }
+    Err(format!("config check found {} problem(s)", problems.len()).into())
+}
 
-        let _ = connection
-            .call_method(
-                Some("org.kde.KWin"),
-                "/Scripting",
-                Some("org.kde.kwin.Scripting"),
-                "unloadScript",
-                &(&script_path,),
-            )
-            .await;
+/// Drops `null` object fields (unset options), so the dump only lists what is set
+fn strip_json_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            fields.retain(|_, field| !field.is_null());
+            fields.values_mut().for_each(strip_json_nulls);
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(strip_json_nulls),
+        _ => {}
     }
+}
 
-    let load_result = connection
-        .call_method(
-            Some("org.kde.KWin"),
-            "/Scripting",
-            Some("org.kde.kwin.Scripting"),
-            "loadScript",
-            &(&script_path,),
-        )
-        .await?;
+/// `--dump-config` output: the kanata connection the daemon would use, and the config as an
+/// entry list with every global setting spelled out (`entries` is itself a valid config)
+fn config_dump(
+    path: &Path,
+    config: &Config,
+    endpoint: Option<&str>,
+    target: &KanataTarget,
+) -> serde_json::Value {
+    let mut entries = vec![serde_json::json!({ "version": CONFIG_VERSION })];
+    if let Some(default) = &target.default_layer {
+        entries.push(serde_json::json!({ "default": default }));
+    }
+    entries.push(serde_json::json!({ "debounce_ms": config.min_focus_ms }));
+    entries.push(serde_json::json!({ "unfocus_grace_ms": config.unfocus_grace_ms }));
+    entries.push(serde_json::json!({ "dedupe_focus": config.dedupe_focus }));
+    entries.push(serde_json::json!({ "vt_monitor": config.vt_monitor }));
+    entries.push(serde_json::json!({ "defer_during_grab": config.defer_during_grab }));
+    entries.push(serde_json::json!({ "per_window_state": config.per_window_state }));
+    entries.push(serde_json::json!({ "class_source": config.class_source }));
+    if let Some(plugins_dir) = &config.plugins_dir {
+        entries.push(serde_json::json!({ "plugins_dir": plugins_dir }));
+    }
+    if !config.layer_display_names.is_empty() {
+        entries.push(serde_json::json!({ "layer_names": config.layer_display_names }));
+    }
+    if !config.layer_virtual_keys.is_empty() {
+        entries.push(serde_json::json!({ "layer_virtual_keys": config.layer_virtual_keys }));
+    }
+    if !config.tray_icons.is_empty() {
+        entries.push(serde_json::json!({ "tray_icons": config.tray_icons }));
+    }
+    if config.indicator != IndicatorTheme::default() {
+        entries.push(serde_json::json!({ "indicator": config.indicator }));
+    }
+    if let Some(announce) = &config.announce {
+        entries.push(serde_json::json!({ "announce": announce }));
+    }
+    if let Some(notifications) = &config.notifications {
+        entries.push(serde_json::json!({ "notifications": notifications }));
+    }
+    if let Some(call_detection) = &config.call_detection {
+        entries.push(serde_json::json!({ "call_detection": call_detection }));
+    }
+    if let Some(uinput_fallback) = &config.uinput_fallback {
+        entries.push(serde_json::json!({ "uinput_fallback": uinput_fallback }));
+    }
+    if let Some(idle) = &config.idle {
+        entries.push(serde_json::json!(idle));
+    }
+    if !config.kanata_endpoints.is_empty() {
+        entries.push(serde_json::json!({ "kanata": config.kanata_endpoints }));
+    }
+    if !config.rule_tests.is_empty() {
+        entries.push(serde_json::json!({ "tests": config.rule_tests }));
+    }
+    for (key, rule) in [
+        ("on_native_terminal", &config.native_terminal_rule),
+        ("on_session_inactive", &config.session_inactive_rule),
+        ("on_locked", &config.locked_rule),
+    ] {
+        if let Some(rule) = rule {
+            entries.push(serde_json::json!({
+                key: rule.layer,
+                "virtual_key": rule.virtual_key,
+                "raw_vk_action": rule.raw_vk_action,
+                "silent": rule.silent,
+                "notify": rule.notify,
+            }));
+        }
+    }
+    entries.extend(
+        config
+            .rules
+            .iter()
+            .map(|rule| serde_json::to_value(rule).expect("rules serialize to JSON")),
+    );
+    let mut dump = serde_json::json!({
+        "config_file": path,
+        "kanata": {
+            "endpoint": endpoint,
+            "host": target.host,
+            "port": target.port,
+            "tls": target.tls.is_some(),
+            "reconnect_delays_ms": target.reconnect_delays_ms,
+        },
+        "entries": entries,
+    });
+    strip_json_nulls(&mut dump);
+    dump
+}
 
-    let script_num: i32 = load_result.body().deserialize()?;
-    let obj_path = kwin_script_object_path(script_num, is_kde6)?;
-    Ok((obj_path, "org.kde.kwin.Script"))
+/// `--dump-config`: load the config like the daemon does and print it as `config_dump`
+fn dump_config(args: &Args) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let path = resolve_config_path(args.config.as_deref());
+    let config = parse_config(&path)?;
+    let target = resolve_kanata_target(args, &config)?;
+    let dump = config_dump(&path, &config, args.kanata.as_deref(), &target);
+    println!("{}", serde_json::to_string_pretty(&dump)?);
+    Ok(())
 }
 
-fn build_kde_query_script(is_kde6: bool, bus_name: &str, object_path: &str) -> String {
-    let active_window = if is_kde6 {
-        "activeWindow"
-    } else {
-        "activeClient"
+fn print_kanata_names(
+    kanata_config: &Path,
+    kind: KanataNameKind,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let names = load_kanata_config_names(kanata_config)?;
+    let list = match kind {
+        KanataNameKind::Layers => names.layers,
+        KanataNameKind::VirtualKeys => names.virtual_keys,
     };
-    format!(
-        r#"function reportFocus(client) {{
-  callDBus(
-    "{bus}",
-    "{path}",
-    "{iface}",
-    "{method}",
-    client ? (client.resourceClass || "") : "",
-    client ? (client.caption || "") : ""
-  );
-}}
-reportFocus(workspace.{active});
-"#,
-        bus = bus_name,
-        path = object_path,
-        iface = KDE_QUERY_INTERFACE,
-        method = KDE_QUERY_METHOD,
-        active = active_window
-    )
+    for name in list {
+        println!("{}", name);
+    }
+    Ok(())
 }
 
-async fn query_kde_focus(
-    connection: &Connection,
-    is_kde6: bool,
-) -> Result<WindowInfo, Box<dyn std::error::Error + Send + Sync>> {
-    let unique_name = connection
-        .unique_name()
-        .ok_or("KDE focus query requires a unique DBus name")?;
-    let query_id = KDE_QUERY_COUNTER.fetch_add(1, Ordering::SeqCst);
-    let query_path = format!("/com/github/kanata/Switcher/KdeQuery{}", query_id);
-    let (sender, receiver) = oneshot::channel();
-    let service = KdeFocusQueryService {
-        sender: TokioMutex::new(Some(sender)),
-    };
-    connection
-        .object_server()
-        .at(query_path.as_str(), service)
-        .await?;
+// === Desktop Entries ===
 
-    let uid = unsafe { libc::getuid() };
-    let script_path = format!("/tmp/kanata-switcher-kwin-query-{}-{}.js", uid, query_id);
-    let script = build_kde_query_script(is_kde6, unique_name.as_str(), query_path.as_str());
-    fs::write(&script_path, script)?;
+/// Installed .desktop files, for resolving windows to desktop-file IDs on backends that
+/// don't report them (everything but GNOME). Keys are lowercased
+#[derive(Debug, Default, Clone)]
+struct DesktopEntries {
+    /// Lowercased desktop-file ID -> ID
+    ids: BTreeMap<String, String>,
+    /// Lowercased `StartupWMClass` -> ID
+    wm_classes: BTreeMap<String, String>,
+}
 
-    let (script_obj_path, script_interface) =
-        load_kwin_script(connection, &script_path, is_kde6, false).await?;
+impl DesktopEntries {
+    /// Scans `applications/` under XDG_DATA_HOME and XDG_DATA_DIRS; earlier dirs shadow later ones
+    fn load() -> Self {
+        let mut data_dirs: Vec<PathBuf> = xdg_data_home().into_iter().collect();
+        let system_dirs = env::var("XDG_DATA_DIRS")
+            .ok()
+            .filter(|dirs| !dirs.is_empty())
+            .unwrap_or_else(|| "/usr/local/share:/usr/share".to_string());
+        data_dirs.extend(
+            system_dirs
+                .split(':')
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from),
+        );
 
-    let _kwin_query_guard = KwinScriptGuard::new(
-        connection.clone(),
-        tokio::runtime::Handle::current(),
-        script_path.clone(),
-        script_obj_path.clone(),
-        script_interface,
-    );
-
-    connection
-        .call_method(
-            Some("org.kde.KWin"),
-            script_obj_path,
-            Some(script_interface),
-            "run",
-            &(),
-        )
-        .await?;
-
-    let win = tokio::time::timeout(Duration::from_secs(5), receiver)
-        .await
-        .map_err(|_| "Timed out waiting for KDE focus callback")?
-        .map_err(|_| "KDE focus callback sender dropped")?;
-
-    Ok(win)
-}
+        let mut entries = Self::default();
+        for data_dir in data_dirs {
+            let applications = data_dir.join("applications");
+            entries.scan_dir(&applications, &applications);
+        }
+        entries
+    }
 
-async fn query_gnome_focus(
-    connection: &Connection,
-) -> Result<WindowInfo, Box<dyn std::error::Error + Send + Sync>> {
-    let reply = connection
-        .call_method(
-            Some(GNOME_SHELL_BUS_NAME),
-            GNOME_FOCUS_OBJECT_PATH,
-            Some(GNOME_FOCUS_INTERFACE),
-            GNOME_FOCUS_METHOD,
-            &(),
-        )
-        .await?;
-    let (class, title): (String, String) = reply.body().deserialize()?;
-    Ok(WindowInfo {
-        class,
-        title,
-        is_native_terminal: false,
-    })
-}
+    fn scan_dir(&mut self, root: &Path, dir: &Path) {
+        let Ok(read_dir) = fs::read_dir(dir) else {
+            return;
+        };
+        for entry in read_dir.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                self.scan_dir(root, &path);
+                continue;
+            }
+            if path.extension().and_then(|ext| ext.to_str()) != Some("desktop") {
+                continue;
+            }
+            // Desktop-file IDs join subdirectories with '-' (Desktop Entry spec)
+            let Some(id) = path.strip_prefix(root).ok().and_then(|rel| rel.to_str()) else {
+                continue;
+            };
+            let id = id.replace('/', "-");
+            if let Ok(contents) = fs::read_to_string(&path) {
+                self.add(&id, &contents);
+            }
+        }
+    }
 
-async fn query_focus_for_env(
-    env: Environment,
-    connection: Option<&Connection>,
-    is_kde6: bool,
-) -> Result<WindowInfo, Box<dyn std::error::Error + Send + Sync>> {
-    match env {
-        Environment::Gnome => {
-            let conn = connection.expect("GNOME focus query requires session connection");
-            query_gnome_focus(conn).await
+    fn add(&mut self, id: &str, contents: &str) {
+        let key = id.to_lowercase();
+        if self.ids.contains_key(&key) {
+            return;
         }
-        Environment::Kde => {
-            let conn = connection.expect("KDE focus query requires session connection");
-            query_kde_focus(conn, is_kde6).await
+        // A hidden entry deletes the ID, also for the dirs it shadows
+        if desktop_entry_value(contents, "Hidden") == Some("true") {
+            self.ids.insert(key, String::new());
+            return;
         }
-        Environment::Wayland => tokio::task::block_in_place(query_wayland_active_window),
-        Environment::X11 => tokio::task::block_in_place(query_x11_active_window),
-        Environment::Unknown => Ok(WindowInfo::default()),
+        if let Some(class) = desktop_entry_value(contents, "StartupWMClass").filter(|c| !c.is_empty()) {
+            self.wm_classes
+                .entry(class.to_lowercase())
+                .or_insert_with(|| id.to_string());
+        }
+        self.ids.insert(key, id.to_string());
     }
-}
 
-async fn apply_focus_for_env(
-    env: Environment,
-    connection: Option<&Connection>,
-    is_kde6: bool,
-    handler: &Arc<Mutex<FocusHandler>>,
-    status_broadcaster: &StatusBroadcaster,
-    pause_broadcaster: &PauseBroadcaster,
-    kanata: &KanataClient,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let win = query_focus_for_env(env, connection, is_kde6).await?;
-    let default_layer = kanata.default_layer().await.unwrap_or_default();
-    if let Some(actions) = handle_focus_event(
-        handler,
-        status_broadcaster,
-        pause_broadcaster,
-        &win,
-        kanata,
-        &default_layer,
-    )
-    .await
-    {
-        execute_focus_actions(kanata, actions).await;
+    /// Best-effort desktop-file ID for a window: `<class>.desktop`, then `StartupWMClass`,
+    /// then a reverse-DNS ID ending in `.<class>.desktop`. X11 WM_CLASS is tried before the app_id
+    fn resolve(&self, win: &WindowInfo) -> Option<String> {
+        let classes: Vec<String> = [win.x11_class.as_deref(), Some(win.class.as_str())]
+            .into_iter()
+            .flatten()
+            .filter(|class| !class.is_empty())
+            .map(str::to_lowercase)
+            .collect();
+        let found = |id: Option<&String>| id.filter(|id| !id.is_empty()).cloned();
+
+        classes
+            .iter()
+            .find_map(|class| found(self.ids.get(&format!("{}.desktop", class))))
+            .or_else(|| {
+                classes
+                    .iter()
+                    .find_map(|class| found(self.wm_classes.get(class)))
+            })
+            .or_else(|| {
+                classes.iter().find_map(|class| {
+                    let suffix = format!(".{}.desktop", class);
+                    self.ids
+                        .iter()
+                        .find(|(key, id)| key.ends_with(&suffix) && !id.is_empty())
+                        .map(|(_, id)| id.clone())
+                })
+            })
     }
-    Ok(())
 }
-async fn apply_session_focus(
-    active: bool,
-    env: Environment,
-    connection: Option<&Connection>,
-    is_kde6: bool,
-    handler: &Arc<Mutex<FocusHandler>>,
-    status_broadcaster: &StatusBroadcaster,
-    pause_broadcaster: &PauseBroadcaster,
-    kanata: &KanataClient,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    if active {
-        return apply_focus_for_env(
-            env,
-            connection,
-            is_kde6,
-            handler,
-            status_broadcaster,
-            pause_broadcaster,
-            kanata,
-        )
-        .await;
-    }
 
-    let win = native_terminal_window();
-    let default_layer = kanata.default_layer().await.unwrap_or_default();
-    if let Some(actions) = handle_focus_event(
-        handler,
-        status_broadcaster,
-        pause_broadcaster,
-        &win,
-        kanata,
-        &default_layer,
-    )
-    .await
-    {
-        execute_focus_actions(kanata, actions).await;
-    }
+// === Focus Handler ===
 
-    Ok(())
+/// Individual action to execute on focus change
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FocusAction {
+    /// Release a virtual key
+    ReleaseVk(String),
+    /// Switch to a layer
+    ChangeLayer(String),
+    /// Press and hold a virtual key (managed - will be released on next focus change)
+    PressVk(String),
+    /// Raw VK action (name, action: Press/Release/Tap/Toggle)
+    RawVkAction(String, String),
+    /// Pause between raw VK actions (milliseconds)
+    Delay(u64),
+    /// Reload kanata config file by index (ReloadNum)
+    ReloadNum(usize),
+    /// Reload the next kanata config file (ReloadNext)
+    ReloadNext,
+    /// Run a `plugin_action` (between kanata writes, like a delay)
+    Plugin(PluginCall),
+    /// Spawn an `on_focus_cmd`/`on_unfocus_cmd` once the kanata actions are sent
+    RunCommand(RuleCommand),
 }
 
-async fn resolve_logind_session_path(
-    connection: &Connection,
-) -> Result<OwnedObjectPath, Box<dyn std::error::Error + Send + Sync>> {
-    let manager = zbus::Proxy::new(
-        connection,
-        LOGIND_BUS_NAME,
-        LOGIND_MANAGER_PATH,
-        LOGIND_MANAGER_INTERFACE,
-    )
-    .await?;
-
-    if let Ok(session_id) = env::var("XDG_SESSION_ID") {
-        println!("[Logind] Using XDG_SESSION_ID={}", session_id);
-        let reply = manager.call_method("GetSession", &(session_id)).await?;
-        let path = decode_logind_object_path_reply(&reply, "GetSession")?;
-        println!("[Logind] Using session path: {}", path.as_str());
-        return Ok(path);
-    }
-    println!("[Logind] XDG_SESSION_ID not set; resolving session via logind");
+/// A rule's `on_focus_cmd` or `on_unfocus_cmd`, with the window and layer it runs for
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RuleCommand {
+    /// "on_focus_cmd" or "on_unfocus_cmd", for log lines
+    key: &'static str,
+    /// The rule's label (`RuleRef::label`), for log lines
+    rule: String,
+    command: String,
+    class: String,
+    title: String,
+    layer: String,
+}
 
-    let pid = std::process::id();
-    match manager.call_method("GetSessionByPID", &(pid)).await {
-        Ok(reply) => {
-            let path = decode_logind_object_path_reply(&reply, "GetSessionByPID")?;
-            println!("[Logind] Using session path: {}", path.as_str());
-            Ok(path)
-        }
-        Err(error) => {
-            if is_logind_no_session_error(&error) {
-                return resolve_logind_display_session_path(&manager, connection, pid).await;
+impl RuleCommand {
+    /// Runs the command with `sh -c` in the background; a failure to start or a non-zero exit
+    /// is logged
+    fn spawn(self) {
+        let mut command = tokio::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&self.command)
+            .env("KANATA_CLASS", &self.class)
+            .env("KANATA_TITLE", &self.title)
+            .env("KANATA_LAYER", &self.layer)
+            .stdin(std::process::Stdio::null());
+        apply_session_env(&mut command);
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(error) => {
+                elog_line!("[Focus] Warning: {} {} failed to start: {}", self.rule, self.key, error);
+                return;
             }
-            Err(error.into())
-        }
+        };
+        tokio::spawn(async move {
+            match child.wait().await {
+                Ok(status) if status.success() => {}
+                Ok(status) => {
+                    elog_line!("[Focus] Warning: {} {} exited with {}", self.rule, self.key, status)
+                }
+                Err(error) => {
+                    elog_line!("[Focus] Warning: {} {} failed: {}", self.rule, self.key, error)
+                }
+            }
+        });
     }
 }
 
-fn is_logind_no_session_error(error: &zbus::Error) -> bool {
-    match error {
-        zbus::Error::MethodError(name, _, _) => name.as_ref() == LOGIND_ERROR_NO_SESSION_FOR_PID,
-        _ => false,
-    }
+/// Actions to execute on focus change, in order.
+/// With fallthrough, all matching actions are collected and executed sequentially.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct FocusActions {
+    /// Ordered list of actions to execute
+    actions: Vec<FocusAction>,
+    /// The new ordered list of managed VKs after execution (pressed top-to-bottom, released bottom-to-top)
+    new_managed_vks: Vec<String>,
+    /// Every matched rule is `silent`: execute without per-switch log lines or announcements
+    silent: bool,
+    /// Every matched rule has `"notify": false`: no desktop notification for this switch
+    quiet_notify: bool,
+    /// Actions for other kanata instances, by endpoint name (rules with `kanata`)
+    targeted: Vec<(String, Vec<FocusAction>)>,
 }
 
-fn is_logind_empty_object_path(path: &OwnedObjectPath) -> bool {
-    path.as_str() == LOGIND_EMPTY_OBJECT_PATH
+impl FocusActions {
+    fn is_empty(&self) -> bool {
+        self.actions.is_empty() && self.targeted.is_empty()
+    }
 }
 
-fn parse_logind_object_path(
-    value: OwnedValue,
-    context: &str,
-) -> Result<OwnedObjectPath, Box<dyn std::error::Error + Send + Sync>> {
-    let debug_value = format!("{:?}", value);
-    if let Ok(path) = OwnedObjectPath::try_from(value.try_clone()?) {
-        return Ok(path);
-    }
-    if let Ok(structure) = Structure::try_from(value.try_clone()?) {
-        if let Some(path) = parse_logind_object_path_from_structure(&structure) {
-            return Ok(path);
-        }
-    }
-    if let Ok(text) = String::try_from(value) {
-        return OwnedObjectPath::try_from(text).map_err(|error| {
-            format!(
-                "logind {} returned invalid object path string: {}",
-                context, error
-            )
-            .into()
-        });
-    }
-    Err(format!(
-        "logind {} returned unexpected value: {}",
-        context, debug_value
+const NATIVE_TERMINAL_RULE_INDEX: usize = usize::MAX;
+const SESSION_INACTIVE_RULE_INDEX: usize = usize::MAX - 1;
+const LOCKED_RULE_INDEX: usize = usize::MAX - 2;
+
+/// `--debug` focus line: every identity rules can match, "-" where the backend has none
+fn window_debug_details(win: &WindowInfo) -> String {
+    let initial = win
+        .initial
+        .as_ref()
+        .map(|initial| format!("class=\"{}\" title=\"{}\"", initial.class, initial.title))
+        .unwrap_or_else(|| "-".to_string());
+    let size = win
+        .size
+        .map(|size| format!("{}x{}", size.width, size.height))
+        .unwrap_or_else(|| "-".to_string());
+    let quoted = |value: Option<&str>| {
+        value
+            .map(|value| format!("\"{}\"", value))
+            .unwrap_or_else(|| "-".to_string())
+    };
+    format!(
+        "app_id/class=\"{}\" id={} x11_class={} instance={} role={} app={} workspace={} initial=({}) size={}",
+        win.class,
+        quoted(win.window_id.as_deref()),
+        quoted(win.x11_class.as_deref()),
+        quoted(win.instance.as_deref()),
+        quoted(win.role.as_deref()),
+        quoted(win.desktop_id.as_deref()),
+        quoted(win.workspace.as_deref()),
+        initial,
+        size
     )
-    .into())
 }
 
-fn parse_logind_object_path_from_structure(structure: &Structure<'_>) -> Option<OwnedObjectPath> {
-    let fields = structure.fields();
-    if fields.is_empty() {
-        return None;
-    }
-    fields
+/// Window classes whose focus is ignored: the daemon's own settings window and panels that
+/// take focus while their (tray) menus are open. Compared case-insensitively.
+const IGNORED_FOCUS_CLASSES: &[&str] = &[
+    SETTINGS_APP_ID,
+    "plasmashell",
+    "org.kde.plasmashell",
+    "xfce4-panel",
+    "lxqt-panel",
+    "mate-panel",
+    "waybar",
+];
+
+fn is_ignored_focus_class(class: &str) -> bool {
+    IGNORED_FOCUS_CLASSES
         .iter()
-        .find_map(|field| logind_object_path_from_value(field))
+        .any(|ignored| ignored.eq_ignore_ascii_case(class))
 }
 
-fn decode_logind_object_path_reply(
-    reply: &zbus::Message,
-    context: &str,
-) -> Result<OwnedObjectPath, Box<dyn std::error::Error + Send + Sync>> {
-    let body = reply.body();
-    let signature = body.signature().to_string();
-    match signature.as_str() {
-        "o" => Ok(body.deserialize_unchecked::<OwnedObjectPath>()?),
-        "s" => {
-            let text = body.deserialize_unchecked::<String>()?;
-            OwnedObjectPath::try_from(text).map_err(|error| {
-                format!(
-                    "logind {} returned invalid object path string: {}",
-                    context, error
-                )
-                .into()
-            })
-        }
-        "v" => {
-            let value = body.deserialize::<OwnedValue>()?;
-            parse_logind_object_path(value, context)
-        }
-        _ => {
-            if signature.starts_with('(') {
-                let structure = body.deserialize::<Structure>()?;
-                return parse_logind_object_path_from_structure(&structure).ok_or_else(|| {
-                    format!(
-                        "logind {} returned unexpected structure: {}",
-                        context, signature
-                    )
-                    .into()
-                });
-            }
-            Err(format!(
-                "logind {} returned unexpected signature: {}",
-                context, signature
-            )
-            .into())
-        }
-    }
+/// Indices of matched rules with the layer each switches to (`FocusHandler::match_rules`)
+type RuleMatches = Vec<(usize, Option<String>)>;
+
+#[derive(Debug)]
+struct FocusHandler {
+    rules: Vec<Rule>,
+    /// Patterns of `rules` (parallel); None = a pattern failed to compile, so the rule never
+    /// matches. Config loading rejects those, only hand-built rules get here
+    compiled_rules: Vec<Option<CompiledRule>>,
+    native_terminal_rule: Option<NativeTerminalRule>,
+    last_class: String,
+    last_title: String,
+    last_matched_rules: Vec<usize>,
+    last_effective_layer: String,
+    /// Currently held virtual keys, in order they were pressed (top-to-bottom rule order, then
+    /// the effective layer's `layer_virtual_keys`)
+    current_virtual_keys: Vec<String>,
+    /// Virtual keys held while a layer is the effective one (`layer_virtual_keys`)
+    layer_virtual_keys: BTreeMap<String, Vec<String>>,
+    quiet_focus: bool,
+    /// Window focus must last this long before its rules apply (`debounce_ms`); zero = immediately
+    min_focus: Duration,
+    /// Rules `begin_focus_event` matched for a window to find its debounce time, reused when
+    /// the event is applied so matchers run once per event
+    matched_ahead: Option<(WindowInfo, RuleMatches)>,
+    /// How long "no window focused" must last before it applies (`unfocus_grace_ms`)
+    unfocus_grace: Duration,
+    /// Default identity `class` patterns match (`class_source`)
+    class_source: ClassSource,
+    /// `--debug`: log every identity of the focused window
+    debug: bool,
+    /// Resolves `desktop_id` for windows whose backend doesn't report one; None = don't resolve
+    desktop_entries: Option<DesktopEntries>,
+    /// Bumped on every focus event so a pending dwell can tell it was superseded
+    focus_generation: u64,
+    /// Focus events each rule matched since the handler was created (parallel to `rules`)
+    rule_hits: Vec<u64>,
+    /// Times the on_native_terminal rule was applied
+    native_terminal_hits: u64,
+    /// Applied instead of on_native_terminal while the session is locked or another user's
+    /// session is active
+    session_inactive_rule: Option<NativeTerminalRule>,
+    /// Times the on_session_inactive rule was applied
+    session_inactive_hits: u64,
+    /// Applied instead of session_inactive_rule while the screen is locked
+    locked_rule: Option<NativeTerminalRule>,
+    /// Times the on_locked rule was applied
+    locked_hits: u64,
+    /// The on_session_inactive or on_locked rule is in effect: window focus is ignored until the
+    /// session becomes active again
+    session_inactive: bool,
+    /// Last logind session state; re-applying focus (startup, unpause, reload) uses it instead of
+    /// the graphical focus while the session isn't active
+    session_state: SessionState,
+    /// Config file `Reload` re-reads; None = built without one (tests)
+    config_path: Option<PathBuf>,
+    /// Skip focus events identical to the last one received (`dedupe_focus`)
+    dedupe_focus: bool,
+    /// X11: hold off focus changes while another client grabs the keyboard (`defer_during_grab`)
+    defer_during_grab: bool,
+    /// The last focus event received (not ignored), for `dedupe_focus`
+    last_window: Option<WindowInfo>,
+    /// Focusing another window re-fires the rules it matches (`per_window_state`)
+    per_window_state: bool,
+    /// `window_id` of the window the last matched rules were applied for
+    last_window_id: Option<String>,
+    /// `in_call` also needs a microphone recording (`call_detection.mic`)
+    call_needs_mic: bool,
+    /// A microphone is recording, as last polled by the call monitor
+    mic_in_use: bool,
+    /// Loaded `plugin`/`plugin_action` modules by name
+    plugins: BTreeMap<String, Arc<WasmPlugin>>,
+    /// Config endpoint of the kanata instance this handler's actions go to (`--kanata`)
+    endpoint: Option<String>,
+    /// Only rules whose `kanata` names `endpoint` apply (a handler in `targets`)
+    endpoint_only: bool,
+    /// Other endpoints rules send actions to, each tracking that instance's layer and keys
+    targets: Vec<FocusTarget>,
 }
 
-fn logind_object_path_from_value(value: &Value<'_>) -> Option<OwnedObjectPath> {
-    match value {
-        Value::ObjectPath(path) => Some(OwnedObjectPath::from(path.clone())),
-        Value::Str(text) => OwnedObjectPath::try_from(text.as_str()).ok(),
-        Value::Value(inner) => logind_object_path_from_value(inner),
-        _ => None,
-    }
+/// Rules of one `kanata` endpoint other than the daemon's own instance
+#[derive(Debug)]
+struct FocusTarget {
+    name: String,
+    /// The endpoint's `default_layer`, else the config's; empty = don't switch back
+    default_layer: String,
+    handler: FocusHandler,
 }
 
-async fn resolve_logind_display_session_path(
-    manager: &zbus::Proxy<'_>,
-    connection: &Connection,
-    pid: u32,
-) -> Result<OwnedObjectPath, Box<dyn std::error::Error + Send + Sync>> {
-    let user_reply = manager.call_method("GetUserByPID", &(pid)).await?;
-    let user_path = decode_logind_object_path_reply(&user_reply, "GetUserByPID")?;
-    let user_proxy = zbus::Proxy::new(
-        connection,
-        LOGIND_BUS_NAME,
-        user_path,
-        LOGIND_USER_INTERFACE,
-    )
-    .await?;
-    let display = parse_logind_object_path(
-        user_proxy.get_property::<OwnedValue>("Display").await?,
-        "User.Display",
-    )?;
-    if is_logind_empty_object_path(&display) {
-        return Err("logind user has no display session".into());
-    }
-    println!("[Logind] Using display session path: {}", display.as_str());
-    Ok(display)
+/// Hit counter of one rule, for `GetRuleStats`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RuleStat {
+    /// Index into the config's rules, NATIVE_TERMINAL_RULE_INDEX for on_native_terminal,
+    /// SESSION_INACTIVE_RULE_INDEX for on_session_inactive, LOCKED_RULE_INDEX for on_locked
+    index: usize,
+    summary: String,
+    hits: u64,
 }
 
-async fn start_logind_session_monitor(
-    env: Environment,
-    session_connection: Option<Connection>,
-    is_kde6: bool,
-    handler: Arc<Mutex<FocusHandler>>,
-    status_broadcaster: StatusBroadcaster,
-    pause_broadcaster: PauseBroadcaster,
-    kanata: KanataClient,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let connection = Connection::system().await?;
-    let session_path = resolve_logind_session_path(&connection).await?;
-    let session_proxy = zbus::Proxy::new(
-        &connection,
-        LOGIND_BUS_NAME,
-        session_path.clone(),
-        LOGIND_SESSION_INTERFACE,
-    )
-    .await?;
-    let active: bool = session_proxy.get_property("Active").await?;
+impl FocusHandler {
+    fn new(
+        rules: Vec<Rule>,
+        native_terminal_rule: Option<NativeTerminalRule>,
+        quiet_focus: bool,
+    ) -> Self {
+        Self {
+            rule_hits: vec![0; rules.len()],
+            compiled_rules: rules.iter().map(|rule| CompiledRule::compile(rule).ok()).collect(),
+            native_terminal_hits: 0,
+            rules,
+            native_terminal_rule,
+            session_inactive_rule: None,
+            session_inactive_hits: 0,
+            locked_rule: None,
+            locked_hits: 0,
+            session_inactive: false,
+            session_state: SessionState::Active,
+            config_path: None,
+            dedupe_focus: true,
+            defer_during_grab: false,
+            last_window: None,
+            per_window_state: false,
+            last_window_id: None,
+            call_needs_mic: false,
+            mic_in_use: false,
+            last_class: String::new(),
+            last_title: String::new(),
+            last_matched_rules: Vec::new(),
+            last_effective_layer: String::new(),
+            current_virtual_keys: Vec::new(),
+            layer_virtual_keys: BTreeMap::new(),
+            quiet_focus,
+            min_focus: Duration::ZERO,
+            matched_ahead: None,
+            unfocus_grace: Duration::ZERO,
+            class_source: ClassSource::default(),
+            debug: false,
+            desktop_entries: None,
+            focus_generation: 0,
+            plugins: BTreeMap::new(),
+            endpoint: None,
+            endpoint_only: false,
+            targets: Vec::new(),
+        }
+    }
 
-    if !active {
-        apply_session_focus(
-            false,
-            env,
-            session_connection.as_ref(),
-            is_kde6,
-            &handler,
-            &status_broadcaster,
-            &pause_broadcaster,
-            &kanata,
+    /// Rules and settings from the config file; `quiet_focus` and `debug` come from the CLI
+    fn from_config(config: &Config, quiet_focus: bool, debug: bool) -> Self {
+        FocusHandler::new(
+            config.rules.clone(),
+            config.native_terminal_rule.clone(),
+            quiet_focus,
         )
-        .await?;
+        .with_class_source(config.class_source)
+        .with_debug(debug)
+        .with_desktop_entries(
+            (debug || config.rules.iter().any(|rule| rule.app.is_some()))
+                .then(DesktopEntries::load),
+        )
+        .with_min_focus(Duration::from_millis(config.min_focus_ms))
+        .with_unfocus_grace(Duration::from_millis(config.unfocus_grace_ms))
+        .with_dedupe_focus(config.dedupe_focus)
+        .with_defer_during_grab(config.defer_during_grab)
+        .with_per_window_state(config.per_window_state)
+        .with_call_detection(config.call_detection.as_ref())
+        .with_layer_virtual_keys(config.layer_virtual_keys.clone())
+        .with_session_inactive_rule(config.session_inactive_rule.clone())
+        .with_locked_rule(config.locked_rule.clone())
+        .with_plugins(load_plugins(config))
+        .with_targets(config)
     }
 
-    let properties_proxy = zbus::fdo::PropertiesProxy::builder(&connection)
-        .destination(LOGIND_BUS_NAME)?
-        .path(session_path.clone())?
-        .build()
-        .await?;
-    let mut signals = properties_proxy.receive_properties_changed().await?;
+    /// A handler per endpoint named by a rule's `kanata`, sharing this handler's plugins
+    fn with_targets(self, config: &Config) -> Self {
+        let mut names: Vec<&String> =
+            config.rules.iter().filter_map(|rule| rule.kanata.as_ref()).collect();
+        names.sort();
+        names.dedup();
+        let targets = names
+            .into_iter()
+            .map(|name| FocusTarget {
+                name: name.clone(),
+                default_layer: config
+                    .kanata_endpoints
+                    .get(name)
+                    .and_then(|endpoint| endpoint.default_layer.clone())
+                    .or_else(|| config.default_layer.clone())
+                    .unwrap_or_default(),
+                handler: FocusHandler {
+                    endpoint: Some(name.clone()),
+                    endpoint_only: true,
+                    ..FocusHandler::new(config.rules.clone(), None, true)
+                        .with_class_source(config.class_source)
+                        .with_per_window_state(config.per_window_state)
+                        .with_call_detection(config.call_detection.as_ref())
+                        .with_plugins(self.plugins.clone())
+                },
+            })
+            .collect();
+        Self { targets, ..self }
+    }
 
-    let session_connection = session_connection.clone();
-    tokio::spawn(async move {
-        let mut last_active = active;
-        while let Some(signal) = signals.next().await {
-            let args = match signal.args() {
-                Ok(args) => args,
-                Err(error) => {
-                    eprintln!(
-                        "[Logind] Failed to parse PropertiesChanged signal: {}",
-                        error
-                    );
-                    std::process::exit(1);
-                }
-            };
-            let Some(value) = args.changed_properties.get("Active") else {
-                continue;
-            };
-            let next_active = match value.downcast_ref::<bool>().ok() {
-                Some(active_value) => active_value,
-                None => {
-                    eprintln!("[Logind] Failed to parse Active property");
-                    std::process::exit(1);
-                }
-            };
+    /// The daemon's own instance is the `--kanata` endpoint: rules naming it apply here
+    /// instead of in a target handler
+    fn with_endpoint(mut self, endpoint: Option<String>) -> Self {
+        self.targets.retain(|target| Some(&target.name) != endpoint.as_ref());
+        Self { endpoint, ..self }
+    }
 
-            if next_active == last_active {
-                continue;
-            }
-            last_active = next_active;
+    /// Endpoints with their own target handler, which need a kanata connection
+    fn target_endpoints(&self) -> Vec<String> {
+        self.targets.iter().map(|target| target.name.clone()).collect()
+    }
 
-            if let Err(error) = apply_session_focus(
-                next_active,
-                env,
-                session_connection.as_ref(),
-                is_kde6,
-                &handler,
-                &status_broadcaster,
-                &pause_broadcaster,
-                &kanata,
-            )
-            .await
-            {
-                eprintln!("[Logind] Failed to apply session focus: {}", error);
-                std::process::exit(1);
+    /// A handler for the reloaded `config` that takes over the held virtual keys and layer, so
+    /// the next focus event releases what the new rules don't hold. Matched rules are cleared
+    /// (their indices refer to the old rules) and hit counters start over.
+    fn reloaded(&self, config: &Config) -> Self {
+        let mut handler = Self {
+            current_virtual_keys: self.current_virtual_keys.clone(),
+            last_effective_layer: self.last_effective_layer.clone(),
+            session_inactive: self.session_inactive,
+            session_state: self.session_state,
+            mic_in_use: self.mic_in_use,
+            // Drops dwell/grace events pending against the old rules
+            focus_generation: self.focus_generation + 1,
+            config_path: self.config_path.clone(),
+            ..FocusHandler::from_config(config, self.quiet_focus, self.debug)
+                .with_endpoint(self.endpoint.clone())
+        };
+        for target in &mut handler.targets {
+            target.handler.mic_in_use = self.mic_in_use;
+            if let Some(old) = self.targets.iter().find(|old| old.name == target.name) {
+                target.handler.current_virtual_keys = old.handler.current_virtual_keys.clone();
+                target.handler.last_effective_layer = old.handler.last_effective_layer.clone();
             }
         }
-    });
+        handler
+    }
 
-    Ok(())
-}
+    fn with_config_path(self, config_path: Option<PathBuf>) -> Self {
+        Self {
+            config_path,
+            ..self
+        }
+    }
 
-async fn start_logind_session_monitor_best_effort<F, Fut>(
-    env: Environment,
-    session_connection: Option<Connection>,
-    is_kde6: bool,
-    handler: Arc<Mutex<FocusHandler>>,
-    status_broadcaster: StatusBroadcaster,
-    pause_broadcaster: PauseBroadcaster,
-    kanata: KanataClient,
-    starter: F,
-) -> bool
-where
-    F: FnOnce(
-        Environment,
-        Option<Connection>,
-        bool,
-        Arc<Mutex<FocusHandler>>,
-        StatusBroadcaster,
-        PauseBroadcaster,
-        KanataClient,
-    ) -> Fut,
-    Fut: std::future::Future<Output = Result<(), Box<dyn std::error::Error + Send + Sync>>>,
-{
-    match starter(
-        env,
-        session_connection,
-        is_kde6,
-        handler,
-        status_broadcaster,
-        pause_broadcaster,
-        kanata,
-    )
-    .await
-    {
-        Ok(()) => true,
-        Err(error) => {
-            eprintln!(
-                "[Logind] Disabled native terminal monitoring (startup failed): {}",
-                error
-            );
-            false
+    fn config_path(&self) -> Option<&Path> {
+        self.config_path.as_deref()
+    }
+
+    fn with_min_focus(self, min_focus: Duration) -> Self {
+        Self { min_focus, ..self }
+    }
+
+    fn with_class_source(self, class_source: ClassSource) -> Self {
+        Self {
+            class_source,
+            ..self
         }
     }
-}
 
-fn pause_daemon(
-    pause_broadcaster: &PauseBroadcaster,
-    handler: &Arc<Mutex<FocusHandler>>,
-    status_broadcaster: &StatusBroadcaster,
-    kanata: &KanataClient,
-    runtime_handle: &tokio::runtime::Handle,
-    request_label: &str,
-) {
-    if !pause_broadcaster.set_paused(true) {
-        println!("[Pause] Pause requested {} (already paused)", request_label);
-        return;
+    fn with_debug(self, debug: bool) -> Self {
+        Self { debug, ..self }
     }
-    println!("[Pause] Pausing daemon");
-    let virtual_keys = {
-        let mut handler = handler.lock().unwrap();
-        let keys = handler.current_virtual_keys();
-        handler.reset();
-        keys
-    };
-    let status_broadcaster = status_broadcaster.clone();
-    let kanata = kanata.clone();
-    runtime_handle.block_on(async move {
-        let default_layer = kanata.default_layer().await.unwrap_or_default();
 
-        for vk in virtual_keys.iter().rev() {
-            kanata.act_on_fake_key(vk, "Release").await;
+    fn with_desktop_entries(self, desktop_entries: Option<DesktopEntries>) -> Self {
+        Self {
+            desktop_entries,
+            ..self
         }
+    }
 
-        if !default_layer.is_empty() {
-            let _ = kanata.change_layer(&default_layer).await;
+    fn with_unfocus_grace(self, unfocus_grace: Duration) -> Self {
+        Self {
+            unfocus_grace,
+            ..self
         }
+    }
 
-        status_broadcaster.set_paused_status(default_layer);
-        kanata.pause_disconnect().await;
-    });
-}
+    fn with_defer_during_grab(self, defer_during_grab: bool) -> Self {
+        Self {
+            defer_during_grab,
+            ..self
+        }
+    }
 
-fn unpause_daemon(
-    env: Environment,
-    connection: Option<Connection>,
-    is_kde6: bool,
-    pause_broadcaster: &PauseBroadcaster,
-    handler: &Arc<Mutex<FocusHandler>>,
-    status_broadcaster: &StatusBroadcaster,
-    kanata: &KanataClient,
-    runtime_handle: &tokio::runtime::Handle,
-    request_label: &str,
-) {
-    if !pause_broadcaster.set_paused(false) {
-        println!(
-            "[Pause] Unpause requested {} (already running)",
-            request_label
-        );
-        return;
+    fn defer_during_grab(&self) -> bool {
+        self.defer_during_grab
     }
-    println!("[Pause] Resuming daemon");
-    let pause_broadcaster = pause_broadcaster.clone();
-    let handler = handler.clone();
-    let status_broadcaster = status_broadcaster.clone();
-    let kanata = kanata.clone();
-    runtime_handle.block_on(async move {
-        kanata.unpause_connect().await;
-        if let Err(error) = apply_focus_for_env(
-            env,
-            connection.as_ref(),
-            is_kde6,
-            &handler,
-            &status_broadcaster,
-            &pause_broadcaster,
-            &kanata,
-        )
-        .await
-        {
-            panic!("[Pause] Failed to refresh focus after unpause: {}", error);
+
+    fn with_dedupe_focus(self, dedupe_focus: bool) -> Self {
+        Self {
+            dedupe_focus,
+            ..self
         }
-    });
-}
+    }
 
-// === Kanata Client ===
+    fn with_per_window_state(self, per_window_state: bool) -> Self {
+        Self {
+            per_window_state,
+            ..self
+        }
+    }
 
-#[derive(Serialize)]
-struct ChangeLayerMsg {
-    #[serde(rename = "ChangeLayer")]
-    change_layer: ChangeLayerPayload,
-}
+    fn with_call_detection(self, call_detection: Option<&CallDetectionConfig>) -> Self {
+        Self {
+            call_needs_mic: call_detection.is_some_and(|config| config.mic),
+            ..self
+        }
+    }
 
-#[derive(Serialize)]
-struct ChangeLayerPayload {
-    new: String,
-}
+    /// The call monitor saw a microphone start or stop recording. Forgets the last window so
+    /// re-applying focus on the same window re-evaluates `in_call` rules
+    fn set_mic_in_use(&mut self, in_use: bool) {
+        self.mic_in_use = in_use;
+        self.last_window = None;
+        self.matched_ahead = None;
+        for target in &mut self.targets {
+            target.handler.set_mic_in_use(in_use);
+        }
+    }
 
-#[derive(Deserialize)]
-struct LayerChangeMsg {
-    #[serde(rename = "LayerChange")]
-    layer_change: Option<LayerChangePayload>,
-}
+    /// The window is a call (`in_call`): its class/title look like one and, with
+    /// `call_detection.mic`, a microphone is recording
+    fn is_in_call(&self, class: &str, title: &str) -> bool {
+        (!self.call_needs_mic || self.mic_in_use) && is_call_window(class, title)
+    }
 
-#[derive(Deserialize)]
-struct LayerChangePayload {
-    new: String,
-}
+    fn with_layer_virtual_keys(self, layer_virtual_keys: BTreeMap<String, Vec<String>>) -> Self {
+        Self {
+            layer_virtual_keys,
+            ..self
+        }
+    }
 
-#[derive(Serialize)]
-struct RequestLayerNamesMsg {
-    #[serde(rename = "RequestLayerNames")]
-    request_layer_names: RequestLayerNamesPayload,
-}
+    fn with_session_inactive_rule(self, session_inactive_rule: Option<NativeTerminalRule>) -> Self {
+        Self {
+            session_inactive_rule,
+            ..self
+        }
+    }
 
-#[derive(Serialize)]
-struct RequestLayerNamesPayload {}
+    fn with_locked_rule(self, locked_rule: Option<NativeTerminalRule>) -> Self {
+        Self {
+            locked_rule,
+            ..self
+        }
+    }
 
-#[derive(Serialize)]
-struct ActOnFakeKeyMsg {
-    #[serde(rename = "ActOnFakeKey")]
-    act_on_fake_key: ActOnFakeKeyPayload,
-}
+    fn with_plugins(self, plugins: BTreeMap<String, Arc<WasmPlugin>>) -> Self {
+        Self { plugins, ..self }
+    }
 
-#[derive(Serialize)]
-struct ActOnFakeKeyPayload {
-    name: String,
-    action: String,
-}
+    fn has_session_inactive_rule(&self) -> bool {
+        self.session_inactive_rule.is_some()
+    }
 
-#[derive(Deserialize)]
-struct LayerNamesMsg {
-    #[serde(rename = "LayerNames")]
-    layer_names: Option<LayerNamesPayload>,
-}
+    fn has_locked_rule(&self) -> bool {
+        self.locked_rule.is_some()
+    }
 
-#[derive(Deserialize)]
-struct LayerNamesPayload {
-    names: Vec<String>,
-}
+    /// The session is active again: window focus applies from the next event on
+    fn end_session_inactive(&mut self) {
+        self.session_inactive = false;
+    }
 
-#[derive(Serialize)]
-struct RequestFakeKeyNamesMsg {
-    #[serde(rename = "RequestFakeKeyNames")]
-    request_fake_key_names: RequestFakeKeyNamesPayload,
-}
+    fn session_state(&self) -> SessionState {
+        self.session_state
+    }
 
-#[derive(Serialize)]
-struct RequestFakeKeyNamesPayload {}
+    fn set_session_state(&mut self, state: SessionState) {
+        self.session_state = state;
+    }
 
-#[derive(Deserialize)]
-struct FakeKeyNamesMsg {
-    #[serde(rename = "FakeKeyNames")]
-    fake_key_names: Option<FakeKeyNamesPayload>,
-}
+    /// Register a new focus event; returns how long to wait before applying it and the
+    /// event's generation. Native terminal switches are never delayed; windows matched by rules
+    /// with their own `debounce_ms` wait the longest of those instead of the global dwell.
+    fn begin_focus_event(&mut self, win: &WindowInfo, default_layer: &str) -> (Duration, u64) {
+        let generation = self.next_focus_generation();
+        (self.focus_delay(win, default_layer), generation)
+    }
 
-#[derive(Deserialize)]
-struct FakeKeyNamesPayload {
-    names: Vec<String>,
-}
+    /// Register a new focus event whose delay is looked up later with `focus_delay` (once its
+    /// matcher commands have run); returns the event's generation
+    fn next_focus_generation(&mut self) -> u64 {
+        self.focus_generation += 1;
+        self.matched_ahead = None;
+        for target in &mut self.targets {
+            target.handler.matched_ahead = None;
+        }
+        self.focus_generation
+    }
 
-struct KanataClientInner {
-    host: String,
-    port: u16,
-    writer: Option<OwnedWriteHalf>,
-    reader_handle: Option<tokio::task::JoinHandle<()>>,
-    current_layer: Option<String>,
-    auto_default_layer: Option<String>,
-    config_default_layer: Option<String>,
-    pending_layer: Option<String>,
-    known_layers: Vec<String>,
-    /// Known virtual keys from kanata. None = older kanata (validation disabled),
-    /// Some(vec) = validate against this list (even if empty).
-    known_virtual_keys: Option<Vec<String>>,
-    /// True if kanata doesn't support RequestFakeKeyNames (drops connection on unknown command).
-    legacy_kanata: bool,
-    connected: bool,
-    paused: bool,
-    quiet: bool,
-    status_broadcaster: StatusBroadcaster,
-}
+    /// `begin_focus_event`'s delay for `win`
+    fn focus_delay(&mut self, win: &WindowInfo, default_layer: &str) -> Duration {
+        if win.is_native_terminal || win.session_inactive {
+            Duration::ZERO
+        } else if win.class.is_empty() && win.title.is_empty() {
+            self.min_focus.max(self.unfocus_grace)
+        } else {
+            self.rule_debounce(win, default_layer).unwrap_or(self.min_focus)
+        }
+    }
 
-#[derive(Clone)]
-pub struct KanataClient {
-    inner: Arc<TokioMutex<KanataClientInner>>,
-}
+    /// Longest `debounce_ms` among the rules `win` matches (this instance's and the targets');
+    /// None if none of them sets one. The matches are kept in `matched_ahead` for `handle`
+    fn rule_debounce(&mut self, win: &WindowInfo, default_layer: &str) -> Option<Duration> {
+        // Skip matching entirely when no rule has a debounce time; `handle` matches instead
+        let has_debounce = |handler: &FocusHandler| {
+            handler.rules.iter().any(|rule| rule.debounce_ms.is_some())
+        };
+        if !has_debounce(self) && !self.targets.iter().any(|target| has_debounce(&target.handler)) {
+            return None;
+        }
+        let win = self.resolve_desktop_id(win).into_owned();
+        let mut debounce = self.match_ahead(&win, default_layer);
+        for target in &mut self.targets {
+            let target_debounce = target.handler.match_ahead(&win, &target.default_layer);
+            debounce = debounce.max(target_debounce);
+        }
+        debounce.map(Duration::from_millis)
+    }
 
-impl std::fmt::Debug for KanataClient {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("KanataClient").finish()
+    /// Match `win` (already through `resolve_desktop_id`) for `rule_debounce`
+    fn match_ahead(&mut self, win: &WindowInfo, default_layer: &str) -> Option<u64> {
+        let matched = self.match_rules(win, default_layer);
+        let debounce = matched
+            .iter()
+            .filter_map(|(index, _)| self.rules[*index].debounce_ms)
+            .max();
+        self.matched_ahead = Some((win.clone(), matched));
+        debounce
     }
-}
 
-impl KanataClient {
-    fn new(
-        host: &str,
-        port: u16,
-        config_default_layer: Option<String>,
-        quiet: bool,
-        status_broadcaster: StatusBroadcaster,
-    ) -> Self {
-        if let Some(ref layer) = config_default_layer {
-            println!(
-                "[Kanata] Using config-specified default layer: \"{}\"",
-                layer
-            );
-        }
-        Self {
-            inner: Arc::new(TokioMutex::new(KanataClientInner {
-                host: host.to_string(),
-                port,
-                writer: None,
-                reader_handle: None,
-                current_layer: None,
-                auto_default_layer: None,
-                config_default_layer,
-                pending_layer: None,
-                known_layers: Vec::new(),
-                known_virtual_keys: None,
-                legacy_kanata: false,
-                connected: false,
-                paused: false,
-                quiet,
-                status_broadcaster,
-            })),
+    /// `win` with its desktop-file ID looked up in `desktop_entries` when the backend has none
+    fn resolve_desktop_id<'a>(&self, win: &'a WindowInfo) -> Cow<'a, WindowInfo> {
+        match &self.desktop_entries {
+            Some(entries) if win.desktop_id.is_none() => Cow::Owned(WindowInfo {
+                desktop_id: entries.resolve(win),
+                ..win.clone()
+            }),
+            _ => Cow::Borrowed(win),
         }
     }
 
-    fn resolve_layer_name_from_inner(
-        inner: &KanataClientInner,
-        layer_name: &str,
-        warn_unknown: bool,
-    ) -> Option<String> {
-        if !inner.known_layers.is_empty()
-            && !inner.known_layers.iter().any(|layer| layer == layer_name)
-        {
-            if warn_unknown && !inner.quiet {
-                eprintln!(
-                    "[Kanata] Warning: Unknown layer \"{}\", switching to default instead",
-                    layer_name
-                );
+    /// Indices of the rules `win` matches, in order and stopping at the first without
+    /// `fallthrough`, each with the layer to switch to (a matcher command or script may replace
+    /// the rule's)
+    fn match_rules(&self, win: &WindowInfo, default_layer: &str) -> RuleMatches {
+        let mut matched = Vec::new();
+        let mut plugin_argument: Option<String> = None;
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !self.handles_rule(rule) {
+                continue;
+            }
+            let class = win.class_for(rule.class_source.unwrap_or(self.class_source));
+            let Some(compiled) = self.compiled_rules[index].as_ref() else {
+                continue;
+            };
+            if !compiled.matches(class, win)
+                || !rule.matches_size(win.size)
+                || !rule.matches_kind(class)
+                || rule
+                    .in_call
+                    .is_some_and(|in_call| in_call != self.is_in_call(class, &win.title))
+            {
+                continue;
+            }
+            if let Some(name) = &rule.plugin {
+                // A plugin that failed to load never matches
+                let Some(plugin) = self.plugins.get(name) else {
+                    continue;
+                };
+                let argument =
+                    plugin_argument.get_or_insert_with(|| window_json(win).to_string());
+                match plugin.matches(argument) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(error) => {
+                        elog_line!(
+                            "[Focus] Warning: {} plugin '{}' failed: {}",
+                            self.rule_ref(index).label(),
+                            name,
+                            error
+                        );
+                        continue;
+                    }
+                }
+            }
+            let mut layer = rule.layer.clone();
+            if let Some(command) = &compiled.command {
+                match command.run(class, &win.title) {
+                    Ok(ScriptMatch::NoMatch) => continue,
+                    Ok(ScriptMatch::Match(picked)) => layer = picked.or(layer),
+                    Err(error) => {
+                        elog_line!(
+                            "[Focus] Warning: {} command failed: {}",
+                            self.rule_ref(index).label(),
+                            error
+                        );
+                        continue;
+                    }
+                }
+            }
+            let layer = match &compiled.script {
+                None => layer,
+                Some(script) => match script.run(win, default_layer) {
+                    Ok(ScriptMatch::NoMatch) => continue,
+                    Ok(ScriptMatch::Match(picked)) => picked.or(layer),
+                    Err(error) => {
+                        elog_line!(
+                            "[Focus] Warning: {} script failed: {}",
+                            self.rule_ref(index).label(),
+                            error
+                        );
+                        continue;
+                    }
+                },
+            };
+            matched.push((index, layer));
+            if !rule.fallthrough {
+                break;
             }
-            return inner
-                .config_default_layer
-                .clone()
-                .or_else(|| inner.auto_default_layer.clone());
         }
-        Some(layer_name.to_string())
+        matched
     }
 
-    async fn resolve_layer_name(&self, layer_name: &str, warn_unknown: bool) -> Option<String> {
-        let inner = self.inner.lock().await;
-        Self::resolve_layer_name_from_inner(&inner, layer_name, warn_unknown)
+    /// Matcher commands `match_rules` may need for `win` here and in the targets, without a
+    /// recent cached result. Collected under the handler lock, run by `run_matcher_commands`
+    /// without it
+    fn commands_to_run(&self, win: &WindowInfo) -> Vec<PendingCommand> {
+        if win.is_native_terminal
+            || win.session_inactive
+            || win.session_locked
+            || (win.class.is_empty() && win.title.is_empty())
+        {
+            return Vec::new();
+        }
+        let win = self.resolve_desktop_id(win);
+        std::iter::once(self)
+            .chain(self.targets.iter().map(|target| &target.handler))
+            .flat_map(|handler| handler.own_commands_to_run(&win))
+            .collect()
     }
 
-    pub async fn connect_with_retry(&self) {
-        let delays = [0, 1000, 2000, 5000];
-        let mut attempt = 0;
-
-        loop {
-            let delay = delays[attempt.min(delays.len() - 1)];
-            if delay > 0 {
-                println!("[Kanata] Retrying connection in {}s...", delay / 1000);
-                tokio::time::sleep(Duration::from_millis(delay as u64)).await;
+    /// `commands_to_run` for this handler's rules: those whose patterns match `win`, up to the
+    /// first rule that is sure to match and stops matching (no `fallthrough`, and no command,
+    /// script, plugin or `in_call` that could reject it)
+    fn own_commands_to_run(&self, win: &WindowInfo) -> Vec<PendingCommand> {
+        let mut pending = Vec::new();
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !self.handles_rule(rule) {
+                continue;
             }
-
-            match self.try_connect().await {
-                Ok(_) => return,
-                Err(e) => {
-                    let inner = self.inner.lock().await;
-                    eprintln!(
-                        "[Kanata] Cannot connect to {}:{}: {}",
-                        inner.host, inner.port, e
-                    );
-                    drop(inner);
-                    attempt += 1;
+            let class = win.class_for(rule.class_source.unwrap_or(self.class_source));
+            let Some(compiled) = self.compiled_rules[index].as_ref() else {
+                continue;
+            };
+            if !compiled.matches(class, win) || !rule.matches_size(win.size) || !rule.matches_kind(class)
+            {
+                continue;
+            }
+            match &compiled.command {
+                Some(command) if !command.is_cached(class, &win.title) => {
+                    pending.push(PendingCommand {
+                        matcher: command.clone(),
+                        class: class.to_string(),
+                        title: win.title.clone(),
+                    });
+                }
+                Some(_) => {}
+                None if !rule.fallthrough
+                    && compiled.script.is_none()
+                    && rule.plugin.is_none()
+                    && rule.in_call.is_none() =>
+                {
+                    break;
                 }
+                None => {}
             }
         }
+        pending
     }
 
-    async fn try_connect(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let (host, port) = {
-            let inner = self.inner.lock().await;
-            (inner.host.clone(), inner.port)
-        };
-
-        let addr = format!("{}:{}", host, port);
-        let stream = TokioTcpStream::connect(&addr).await?;
-        println!("[Kanata] Connected to {}", addr);
-
-        let (reader, mut writer) = stream.into_split();
-        let mut reader = TokioBufReader::new(reader);
-
-        // Read initial LayerChange message
-        let mut line = String::new();
-        reader.read_line(&mut line).await?;
+    /// `rule` sends its actions to this handler's kanata instance
+    fn handles_rule(&self, rule: &Rule) -> bool {
+        match &rule.kanata {
+            Some(endpoint) => self.endpoint.as_ref() == Some(endpoint),
+            None => !self.endpoint_only,
+        }
+    }
 
-        let mut current_layer = None;
-        if let Ok(msg) = serde_json::from_str::<LayerChangeMsg>(&line) {
-            if let Some(lc) = msg.layer_change {
-                println!("[Kanata] Current layer: \"{}\"", lc.new);
-                current_layer = Some(lc.new);
+    /// Focus moved to the daemon's own window or a panel menu, or the session is locked: keep
+    /// the current state as if focus never left the previous window
+    fn is_ignored_focus(&self, win: &WindowInfo) -> bool {
+        if win.is_native_terminal || win.session_inactive {
+            return false;
+        }
+        if self.session_inactive {
+            if !self.quiet_focus {
+                log_line!("[Focus] Ignoring class=\"{}\" (session inactive)", win.class);
             }
+            return true;
         }
+        if !is_ignored_focus_class(&win.class) {
+            return false;
+        }
+        if !self.quiet_focus {
+            log_line!("[Focus] Ignoring class=\"{}\" (daemon or panel window)", win.class);
+        }
+        true
+    }
 
-        // Request layer names
-        let request = RequestLayerNamesMsg {
-            request_layer_names: RequestLayerNamesPayload {},
-        };
-        let request_json = serde_json::to_string(&request).unwrap() + "\n";
-        writer.write_all(request_json.as_bytes()).await?;
+    /// Same window, title and identity as the previous event (KWin and GNOME repeat them, e.g.
+    /// on workspace switches): nothing can match differently, so skip it before matching.
+    /// `reset` forgets the window, so the first event after a pause is always applied.
+    fn is_repeated_focus(&mut self, win: &WindowInfo) -> bool {
+        if self.dedupe_focus && self.last_window.as_ref() == Some(win) {
+            return true;
+        }
+        self.last_window = Some(win.clone());
+        false
+    }
 
-        // Read LayerNames response
-        line.clear();
-        reader.read_line(&mut line).await?;
+    /// `win` is another window than the one the last matched rules were applied for, so
+    /// (with `per_window_state`) its rules fire again even if the same rules stay matched
+    fn is_other_window(&self, win: &WindowInfo) -> bool {
+        self.per_window_state && win.window_id.is_some() && win.window_id != self.last_window_id
+    }
 
-        let mut known_layers = Vec::new();
-        // Auto-detect default layer from the first layer in the list (layers are in definition order)
-        let mut auto_default_layer = None;
-        if let Ok(msg) = serde_json::from_str::<LayerNamesMsg>(&line) {
-            if let Some(ln) = msg.layer_names {
-                println!("[Kanata] Available layers: {:?}", ln.names);
-                auto_default_layer = ln.names.first().cloned();
-                known_layers = ln.names;
+    fn is_latest_focus_event(&self, generation: u64) -> bool {
+        self.focus_generation == generation
+    }
+
+    /// Handle a focus change event for this handler's kanata instance, then for each targeted
+    /// endpoint (their actions go in `targeted`). Returns actions to execute.
+    fn handle(&mut self, win: &WindowInfo, default_layer: &str) -> Option<FocusActions> {
+        let result = self.handle_own(win, default_layer);
+        if self.targets.is_empty() {
+            return result;
+        }
+        let win = self.resolve_desktop_id(win).into_owned();
+        let mut silent = result.as_ref().map(|actions| actions.silent);
+        let mut quiet_notify = result.as_ref().map(|actions| actions.quiet_notify);
+        let mut targeted = Vec::new();
+        for target in &mut self.targets {
+            let previous = target.handler.last_matched_rules.clone();
+            let Some(actions) = target.handler.handle_own(&win, &target.default_layer) else {
+                continue;
+            };
+            let matched = &target.handler.last_matched_rules;
+            if !self.quiet_focus && !actions.silent && !matched.is_empty() && *matched != previous {
+                let labels: Vec<String> = matched
+                    .iter()
+                    .map(|&index| target.handler.rule_ref(index).label())
+                    .collect();
+                log_line!("[Focus] kanata \"{}\": matched {}", target.name, labels.join(", "));
             }
+            silent = Some(silent.unwrap_or(true) && actions.silent);
+            quiet_notify = Some(quiet_notify.unwrap_or(true) && actions.quiet_notify);
+            targeted.push((target.name.clone(), actions.actions));
+        }
+        if targeted.is_empty() {
+            return result;
         }
+        Some(FocusActions {
+            silent: silent.unwrap_or_default(),
+            quiet_notify: quiet_notify.unwrap_or_default(),
+            targeted,
+            ..result.unwrap_or_default()
+        })
+    }
 
-        // Request virtual key names (skip if we know this is older kanata)
-        let legacy_kanata = {
-            let inner = self.inner.lock().await;
-            inner.legacy_kanata
-        };
+    /// Handle a focus change event with the rules of this handler's own instance.
+    /// With fallthrough, ALL matching actions are collected and executed in order.
+    /// All matched virtual_keys are pressed and held simultaneously.
+    fn handle_own(&mut self, win: &WindowInfo, default_layer: &str) -> Option<FocusActions> {
+        let held = self.current_virtual_keys.clone();
+        let result = self.handle_rules(win, default_layer);
+        self.hold_layer_virtual_keys(held, result)
+    }
 
-        let known_virtual_keys = if legacy_kanata {
-            None
-        } else {
-            let request = RequestFakeKeyNamesMsg {
-                request_fake_key_names: RequestFakeKeyNamesPayload {},
-            };
-            let request_json = serde_json::to_string(&request).unwrap() + "\n";
-            writer.write_all(request_json.as_bytes()).await?;
+    /// Hold the effective layer's `layer_virtual_keys` next to the rule VKs `result` manages.
+    /// The rule paths treat every held key as a rule VK, so releases of keys the new layer
+    /// still wants and presses of keys already held are dropped, then the leftovers of the
+    /// previous layer are released and the new layer's keys pressed after the layer change.
+    fn hold_layer_virtual_keys(
+        &mut self,
+        held: Vec<String>,
+        result: Option<FocusActions>,
+    ) -> Option<FocusActions> {
+        if self.layer_virtual_keys.is_empty() {
+            return result;
+        }
+        let mut wanted = self.current_virtual_keys.clone();
+        for vk in self
+            .layer_virtual_keys
+            .get(&self.last_effective_layer)
+            .into_iter()
+            .flatten()
+        {
+            if !wanted.contains(vk) {
+                wanted.push(vk.clone());
+            }
+        }
+        let mut result = result.unwrap_or_default();
+        result.actions.retain(|action| match action {
+            FocusAction::ReleaseVk(vk) => !wanted.contains(vk),
+            FocusAction::PressVk(vk) => !held.contains(vk),
+            _ => true,
+        });
+        let released: Vec<FocusAction> = held
+            .iter()
+            .rev()
+            .filter(|vk| !wanted.contains(vk))
+            .map(|vk| FocusAction::ReleaseVk(vk.clone()))
+            .filter(|release| !result.actions.contains(release))
+            .collect();
+        result.actions.splice(0..0, released);
+        for vk in &wanted {
+            let press = FocusAction::PressVk(vk.clone());
+            if !held.contains(vk) && !result.actions.contains(&press) {
+                result.actions.push(press);
+            }
+        }
+        result.new_managed_vks = wanted.clone();
+        self.current_virtual_keys = wanted;
+        if result.is_empty() {
+            None
+        } else {
+            Some(result)
+        }
+    }
 
-            // Read FakeKeyNames response (or error from older kanata)
-            line.clear();
-            let read_result = reader.read_line(&mut line).await;
+    /// Window rules (or the session/unfocused rule standing in for them) for this handler's own
+    /// instance, before `layer_virtual_keys`
+    fn handle_rules(&mut self, win: &WindowInfo, default_layer: &str) -> Option<FocusActions> {
+        let mut result = FocusActions::default();
 
-            // If connection was dropped or reset, this is older kanata
-            match read_result {
-                Err(_) | Ok(0) => {
-                    let mut inner = self.inner.lock().await;
-                    inner.legacy_kanata = true;
-                    return Err("Older kanata detected (no RequestFakeKeyNames support)".into());
-                }
-                Ok(_) => {}
-            }
+        if win.session_locked && self.locked_rule.is_some() {
+            self.session_inactive = true;
+            return self.handle_locked(default_layer);
+        }
+        if win.session_inactive && self.session_inactive_rule.is_some() {
+            self.session_inactive = true;
+            return self.handle_session_inactive(default_layer);
+        }
+        if win.is_native_terminal || win.session_inactive {
+            self.session_inactive = false;
+            return self.handle_native_terminal(default_layer);
+        }
 
-            // Try to parse as FakeKeyNames response
-            if let Ok(msg) = serde_json::from_str::<FakeKeyNamesMsg>(&line) {
-                if let Some(fk) = msg.fake_key_names {
-                    if !fk.names.is_empty() {
-                        println!("[Kanata] Available virtual keys: {:?}", fk.names);
-                    }
-                    Some(fk.names)
-                } else {
-                    // No FakeKeyNames in response - older kanata sent error or unexpected response
-                    let mut inner = self.inner.lock().await;
-                    inner.legacy_kanata = true;
-                    return Err("Older kanata detected (no RequestFakeKeyNames support)".into());
+        // Handle unfocused state (no window has focus)
+        if win.class.is_empty() && win.title.is_empty() {
+            return self.handle_unfocused(default_layer);
+        }
+
+        let resolved = self.resolve_desktop_id(win);
+        let win = resolved.as_ref();
+
+        // Window titles stay out of `LOG_BUFFER`, they can carry private content
+        if self.debug {
+            println!("[Focus] {}", window_debug_details(win));
+        }
+
+        // Match rules with fallthrough support
+        struct MatchedRule {
+            index: usize,
+            layer: Option<String>,
+            virtual_key: Option<String>,
+            raw_vk_actions: Vec<RawVkStep>,
+            reload_num: Option<usize>,
+            reload_next: bool,
+            silent: bool,
+            notify: bool,
+            plugin_action: Option<PluginCall>,
+        }
+
+        let matched = match self.matched_ahead.take() {
+            Some((matched_win, matched)) if matched_win == *win => matched,
+            _ => self.match_rules(win, default_layer),
+        };
+        let matched_rules: Vec<MatchedRule> = matched
+            .into_iter()
+            .map(|(index, layer)| {
+                let rule = &self.rules[index];
+                let plugin_action = rule
+                    .plugin_action
+                    .as_ref()
+                    .and_then(|name| self.plugins.get(name))
+                    .map(|plugin| PluginCall {
+                        plugin: plugin.clone(),
+                        payload: serde_json::json!({
+                            "rule": index + 1,
+                            "name": rule.name,
+                            "layer": layer,
+                            "window": window_json(win),
+                        })
+                        .to_string(),
+                    });
+                MatchedRule {
+                    index,
+                    layer,
+                    virtual_key: rule.virtual_key.clone(),
+                    raw_vk_actions: rule.raw_vk_action.clone().unwrap_or_default(),
+                    reload_num: rule.reload_num,
+                    reload_next: rule.reload_next,
+                    silent: rule.silent,
+                    notify: rule.notify.unwrap_or(true),
+                    plugin_action,
                 }
+            })
+            .collect();
+
+        result.silent =
+            !matched_rules.is_empty() && matched_rules.iter().all(|matched| matched.silent);
+        result.quiet_notify =
+            !matched_rules.is_empty() && matched_rules.iter().all(|matched| !matched.notify);
+        if !self.quiet_focus && !result.silent {
+            let labels: Vec<String> = matched_rules
+                .iter()
+                .map(|matched| self.rule_ref(matched.index).label())
+                .collect();
+            if labels.is_empty() {
+                println!("[Focus] class=\"{}\" title=\"{}\"", win.class, win.title);
             } else {
-                // Parsing failed - older kanata sent error response
-                let mut inner = self.inner.lock().await;
-                inner.legacy_kanata = true;
-                return Err("Older kanata detected (no RequestFakeKeyNames support)".into());
+                println!(
+                    "[Focus] class=\"{}\" title=\"{}\" matched {}",
+                    win.class,
+                    win.title,
+                    labels.join(", ")
+                );
             }
-        };
+        }
 
-        {
-            let mut inner = self.inner.lock().await;
-            inner.connected = true;
-            inner.writer = Some(writer);
-            inner.current_layer = current_layer;
-            inner.known_layers = known_layers;
-            inner.known_virtual_keys = known_virtual_keys;
-            if let Some(ref layer) = auto_default_layer {
-                if inner.config_default_layer.is_none() {
-                    println!("[Kanata] Using auto-detected default layer: \"{}\"", layer);
-                }
-                inner.auto_default_layer = auto_default_layer;
-            }
-            if let Some(ref layer) = inner.current_layer {
-                inner
-                    .status_broadcaster
-                    .update_layer(layer.clone(), LayerSource::External);
+        let matched_indices: Vec<usize> = matched_rules.iter().map(|rule| rule.index).collect();
+        let other_window = self.is_other_window(win);
+        for &index in &matched_indices {
+            self.rule_hits[index] += 1;
+        }
+
+        // Collect all VKs from matched rules in order (for holding)
+        let new_vks: Vec<String> = matched_rules
+            .iter()
+            .filter_map(|r| r.virtual_key.clone())
+            .collect();
+
+        // Release VKs that are no longer matched (in reverse order)
+        for vk in self.current_virtual_keys.iter().rev() {
+            if !new_vks.contains(vk) {
+                result.actions.push(FocusAction::ReleaseVk(vk.clone()));
             }
         }
 
-        let reader_handle = self.clone().spawn_reader(reader);
-        let mut inner = self.inner.lock().await;
-        inner.reader_handle = Some(reader_handle);
-        Ok(())
-    }
+        // If no rules matched, use default layer
+        if matched_rules.is_empty() {
+            if !default_layer.is_empty() && self.last_effective_layer != default_layer {
+                result
+                    .actions
+                    .push(FocusAction::ChangeLayer(default_layer.to_string()));
+            }
+            result.new_managed_vks = Vec::new();
+            self.last_effective_layer = default_layer.to_string();
+        } else {
+            let matched_changed = other_window || matched_indices != self.last_matched_rules;
+            let mut matched_layers: Vec<String> = Vec::new();
+            for matched in &matched_rules {
+                if let Some(layer) = matched.layer.clone() {
+                    matched_layers.push(layer);
+                }
+            }
+            let new_rules: Vec<usize> = matched_indices
+                .iter()
+                .cloned()
+                .filter(|idx| other_window || !self.last_matched_rules.contains(idx))
+                .collect();
 
-    fn spawn_reader(
-        self,
-        mut reader: TokioBufReader<tokio::net::tcp::OwnedReadHalf>,
-    ) -> tokio::task::JoinHandle<()> {
-        tokio::spawn(async move {
-            let mut line = String::new();
-            loop {
-                line.clear();
-                match reader.read_line(&mut line).await {
-                    Ok(0) => {
-                        println!("[Kanata] Disconnected");
-                        {
-                            let mut inner = self.inner.lock().await;
-                            inner.connected = false;
-                            inner.writer = None;
-                            inner.reader_handle = None;
-                            if inner.paused {
-                                return;
-                            }
-                        }
-                        self.reconnect_loop().await;
-                        return;
+            // Process matched rules in order, building action list
+            for matched in matched_rules {
+                let is_new = new_rules.contains(&matched.index);
+                if is_new {
+                    // Config reload goes first so the rest of the rule applies to the new config
+                    if let Some(index) = matched.reload_num {
+                        result.actions.push(FocusAction::ReloadNum(index));
                     }
-                    Ok(_) => {
-                        if let Ok(msg) = serde_json::from_str::<LayerChangeMsg>(&line) {
-                            if let Some(lc) = msg.layer_change {
-                                let mut inner = self.inner.lock().await;
-                                if inner.paused {
-                                    continue;
-                                }
-                                let old_layer = inner.current_layer.clone();
-                                inner.current_layer = Some(lc.new.clone());
-                                let status_broadcaster = inner.status_broadcaster.clone();
-                                let quiet = inner.quiet;
-                                if old_layer.as_ref() != Some(&lc.new) {
-                                    status_broadcaster
-                                        .update_layer(lc.new.clone(), LayerSource::External);
-                                    if !quiet {
-                                        println!(
-                                            "[Kanata] Layer changed (external): {} -> {}",
-                                            old_layer.as_deref().unwrap_or("(none)"),
-                                            lc.new
-                                        );
-                                    }
-                                }
-                            }
-                        }
+                    if matched.reload_next {
+                        result.actions.push(FocusAction::ReloadNext);
                     }
-                    Err(e) => {
-                        eprintln!("[Kanata] Connection error: {}", e);
-                        {
-                            let mut inner = self.inner.lock().await;
-                            inner.connected = false;
-                            inner.writer = None;
-                            inner.reader_handle = None;
-                            if inner.paused {
-                                return;
-                            }
+
+                    // Layer change
+                    if let Some(layer) = matched.layer {
+                        result.actions.push(FocusAction::ChangeLayer(layer));
+                    }
+
+                    // Virtual key: press if not already held
+                    if let Some(ref vk) = matched.virtual_key {
+                        if !self.current_virtual_keys.contains(vk) {
+                            result.actions.push(FocusAction::PressVk(vk.clone()));
                         }
-                        self.reconnect_loop().await;
-                        return;
                     }
-                }
-            }
-        })
-    }
 
-    async fn reconnect_loop(&self) {
-        let delays = [1000, 2000, 5000];
-        let mut attempt = 0;
+                    // Raw VK actions
+                    for step in &matched.raw_vk_actions {
+                        result.actions.push(step.to_focus_action());
+                    }
 
-        loop {
-            {
-                let inner = self.inner.lock().await;
-                if inner.connected || inner.paused {
-                    return;
+                    if let Some(call) = matched.plugin_action {
+                        result.actions.push(FocusAction::Plugin(call));
+                    }
                 }
             }
 
-            let delay = delays[attempt.min(delays.len() - 1)];
-            println!("[Kanata] Reconnecting in {}s...", delay / 1000);
-            tokio::time::sleep(Duration::from_millis(delay as u64)).await;
-
-            match self.try_connect().await {
-                Ok(_) => {
-                    println!("[Kanata] Reconnected");
-
-                    let pending = {
-                        let mut inner = self.inner.lock().await;
-                        inner.pending_layer.take()
-                    };
-
-                    if let Some(pending) = pending {
-                        let current = self.inner.lock().await.current_layer.clone();
-                        if current.as_ref() != Some(&pending) {
-                            let _ = self.change_layer(&pending).await;
+            // A matcher script can pick another layer while the same rules stay matched
+            let layer_changed = matched_layers
+                .last()
+                .is_some_and(|layer| *layer != self.last_effective_layer);
+            if (matched_changed || layer_changed)
+                && let Some(new_layer) = matched_layers.last().cloned()
+            {
+                if self.last_effective_layer != new_layer {
+                    let has_new_layer = result.actions.iter().rev().find_map(|action| {
+                        if let FocusAction::ChangeLayer(layer) = action {
+                            Some(layer == &new_layer)
+                        } else {
+                            None
                         }
+                    });
+                    if has_new_layer != Some(true) {
+                        result
+                            .actions
+                            .push(FocusAction::ChangeLayer(new_layer.clone()));
                     }
-                    return;
-                }
-                Err(_) => {
-                    attempt += 1;
                 }
+                self.last_effective_layer = new_layer;
             }
+
+            result.new_managed_vks = new_vks;
         }
-    }
 
-    pub async fn change_layer(&self, layer_name: &str) -> bool {
-        let mut inner = self.inner.lock().await;
+        self.push_rule_commands(
+            &mut result,
+            &matched_indices,
+            other_window,
+            win,
+            &self.last_effective_layer,
+        );
 
-        let target_layer =
-            match Self::resolve_layer_name_from_inner(&inner, layer_name, true) {
-                Some(layer) => layer,
-                None => return false,
-            };
+        // Update state
+        self.last_class = win.class.clone();
+        self.last_title = win.title.clone();
+        self.last_window_id = win.window_id.clone();
+        self.last_matched_rules = matched_indices;
+        self.current_virtual_keys = result.new_managed_vks.clone();
 
-        let current = inner.current_layer.clone();
-        if current.as_deref() == Some(&target_layer) {
-            return false;
+        if result.is_empty() {
+            None
+        } else {
+            Some(result)
         }
+    }
 
-        if !inner.connected {
-            inner.pending_layer = Some(target_layer.clone());
-            println!(
-                "[Kanata] Not connected, will switch to \"{}\" on reconnect",
-                target_layer
-            );
-            return false;
-        }
+    fn current_virtual_keys(&self) -> Vec<String> {
+        self.current_virtual_keys.clone()
+    }
 
-        if let Some(ref mut writer) = inner.writer {
-            let msg = ChangeLayerMsg {
-                change_layer: ChangeLayerPayload {
-                    new: target_layer.clone(),
-                },
-            };
-            let json = serde_json::to_string(&msg).unwrap() + "\n";
-
-            if writer.write_all(json.as_bytes()).await.is_ok() {
-                if !inner.quiet {
-                    println!(
-                        "[Kanata] Switching layer (daemon): {} -> {}",
-                        current.as_deref().unwrap_or("(none)"),
-                        target_layer
-                    );
-                }
-                inner.current_layer = Some(target_layer);
-                return true;
-            }
-        }
-        false
+    /// `fallback_remap` of the last matched rules; later rules win on the same key
+    #[cfg(feature = "uinput-fallback")]
+    fn fallback_remap(&self) -> BTreeMap<String, String> {
+        self.last_matched_rules
+            .iter()
+            .filter_map(|&index| self.rules.get(index)?.fallback_remap.as_ref())
+            .flatten()
+            .map(|(from, to)| (from.clone(), to.clone()))
+            .collect()
     }
 
-    pub async fn act_on_fake_key(&self, name: &str, action: &str) -> bool {
-        let mut inner = self.inner.lock().await;
+    #[cfg(test)]
+    fn last_matched_rules(&self) -> Vec<usize> {
+        self.last_matched_rules.clone()
+    }
 
-        if !inner.connected {
-            if !inner.quiet {
-                eprintln!("[Kanata] Not connected, cannot send fake key action");
-            }
-            return false;
+    /// `on_unfocus_cmd` of the rules that stop matching (with the window they matched), then
+    /// `on_focus_cmd` of the rules that start matching `win`; both get the layer now in effect.
+    /// With `other_window` every rule counts as stopped and started again.
+    /// Call before `last_matched_rules` and `last_class`/`last_title` are updated
+    fn push_rule_commands(
+        &self,
+        result: &mut FocusActions,
+        matched: &[usize],
+        other_window: bool,
+        win: &WindowInfo,
+        layer: &str,
+    ) {
+        let stopped = self
+            .last_matched_rules
+            .iter()
+            .filter(|index| other_window || !matched.contains(index))
+            .filter_map(|&index| {
+                let command = self.rules.get(index)?.on_unfocus_cmd.clone()?;
+                Some((index, "on_unfocus_cmd", command, &self.last_class, &self.last_title))
+            });
+        let started = matched
+            .iter()
+            .filter(|index| other_window || !self.last_matched_rules.contains(index))
+            .filter_map(|&index| {
+                let command = self.rules.get(index)?.on_focus_cmd.clone()?;
+                Some((index, "on_focus_cmd", command, &win.class, &win.title))
+            });
+        for (index, key, command, class, title) in stopped.chain(started) {
+            result.actions.push(FocusAction::RunCommand(RuleCommand {
+                key,
+                rule: self.rule_ref(index).label(),
+                command,
+                class: class.clone(),
+                title: title.clone(),
+                layer: layer.to_string(),
+            }));
         }
+    }
 
-        // Validate virtual key name if we have the list from kanata
-        if Self::filter_valid_virtual_keys(&inner.known_virtual_keys, vec![name.to_string()])
-            .is_empty()
-        {
-            if !inner.quiet {
-                eprintln!(
-                    "[Kanata] Warning: Unknown virtual key \"{}\", skipping action",
-                    name
-                );
-            }
-            return false;
+    fn rule_ref(&self, index: usize) -> RuleRef {
+        RuleRef {
+            index,
+            name: self.rules.get(index).and_then(|rule| rule.name.clone()),
         }
+    }
 
-        if let Some(ref mut writer) = inner.writer {
-            let msg = ActOnFakeKeyMsg {
-                act_on_fake_key: ActOnFakeKeyPayload {
-                    name: name.to_string(),
-                    action: action.to_string(),
-                },
-            };
-            let json = serde_json::to_string(&msg).unwrap() + "\n";
+    fn last_matched_rule_refs(&self) -> Vec<RuleRef> {
+        self.last_matched_rules
+            .iter()
+            .map(|&index| self.rule_ref(index))
+            .collect()
+    }
 
-            if writer.write_all(json.as_bytes()).await.is_ok() {
-                if !inner.quiet {
-                    println!("[Kanata] Fake key: {} {}", action, name);
-                }
-                return true;
-            }
-        }
-        false
+    /// Config rule index -> `name`, for the rules that have one
+    fn rule_names(&self) -> BTreeMap<usize, String> {
+        self.rules
+            .iter()
+            .enumerate()
+            .filter_map(|(index, rule)| Some((index, rule.name.clone()?)))
+            .collect()
     }
 
-    pub async fn default_layer(&self) -> Option<String> {
-        let inner = self.inner.lock().await;
-        inner
-            .config_default_layer
-            .clone()
-            .or_else(|| inner.auto_default_layer.clone())
-    }
-
-    pub async fn pause_disconnect(&self) {
-        let mut inner = self.inner.lock().await;
-        inner.paused = true;
-        if let Some(handle) = inner.reader_handle.take() {
-            handle.abort();
+    /// Hit counters in config order, then the on_native_terminal, on_session_inactive and
+    /// on_locked rules (if any)
+    fn rule_stats(&self) -> Vec<RuleStat> {
+        let mut stats: Vec<RuleStat> = self
+            .rules
+            .iter()
+            .zip(&self.rule_hits)
+            .enumerate()
+            .map(|(index, (rule, &hits))| RuleStat {
+                index,
+                summary: rule.summary(),
+                hits: hits
+                    + self
+                        .targets
+                        .iter()
+                        .map(|target| target.handler.rule_hits[index])
+                        .sum::<u64>(),
+            })
+            .collect();
+        if let Some(rule) = &self.native_terminal_rule {
+            stats.push(RuleStat {
+                index: NATIVE_TERMINAL_RULE_INDEX,
+                summary: format!("on_native_terminal -> layer={}", rule.layer),
+                hits: self.native_terminal_hits,
+            });
         }
-        if let Some(mut writer) = inner.writer.take() {
-            let _ = writer.shutdown().await;
+        if let Some(rule) = &self.session_inactive_rule {
+            stats.push(RuleStat {
+                index: SESSION_INACTIVE_RULE_INDEX,
+                summary: format!("on_session_inactive -> layer={}", rule.layer),
+                hits: self.session_inactive_hits,
+            });
         }
-        inner.connected = false;
-        inner.current_layer = None;
-        inner.auto_default_layer = None;
-        inner.pending_layer = None;
-        inner.known_layers.clear();
-        inner.known_virtual_keys = None;
+        if let Some(rule) = &self.locked_rule {
+            stats.push(RuleStat {
+                index: LOCKED_RULE_INDEX,
+                summary: format!("on_locked -> layer={}", rule.layer),
+                hits: self.locked_hits,
+            });
+        }
+        stats
     }
 
-    pub async fn unpause_connect(&self) {
-        {
-            let mut inner = self.inner.lock().await;
-            inner.paused = false;
-        }
-        self.connect_with_retry().await;
+    /// Release the virtual keys held on targeted endpoints and switch them back to their default
+    /// layer, forgetting the rest of their state like `reset` (pause, shutdown)
+    fn release_targets(&mut self) -> Vec<(String, Vec<FocusAction>)> {
+        self.targets
+            .iter_mut()
+            .filter_map(|target| {
+                let handler = &mut target.handler;
+                let mut actions: Vec<FocusAction> = handler
+                    .current_virtual_keys
+                    .iter()
+                    .rev()
+                    .map(|vk| FocusAction::ReleaseVk(vk.clone()))
+                    .collect();
+                if !target.default_layer.is_empty()
+                    && handler.last_effective_layer != target.default_layer
+                {
+                    actions.push(FocusAction::ChangeLayer(target.default_layer.clone()));
+                }
+                handler.reset();
+                handler.last_effective_layer = target.default_layer.clone();
+                (!actions.is_empty()).then(|| (target.name.clone(), actions))
+            })
+            .collect()
     }
 
-    /// Filter a list of virtual key names, returning only those that are valid.
-    /// If validation is disabled (None), all VKs pass through.
-    fn filter_valid_virtual_keys(
-        known_virtual_keys: &Option<Vec<String>>,
-        vks: Vec<String>,
-    ) -> Vec<String> {
-        match known_virtual_keys {
-            None => vks,
-            Some(known_vks) => vks.into_iter().filter(|vk| known_vks.contains(vk)).collect(),
+    fn reset(&mut self) {
+        self.last_window = None;
+        self.matched_ahead = None;
+        self.last_class.clear();
+        self.last_title.clear();
+        self.last_matched_rules.clear();
+        self.last_effective_layer.clear();
+        self.current_virtual_keys.clear();
+    }
+
+    fn handle_unfocused(&mut self, default_layer: &str) -> Option<FocusActions> {
+        let mut result = FocusActions::default();
+        if !self.quiet_focus {
+            log_line!("[Focus] No window focused");
+        }
+        // Release all active virtual keys in reverse order (bottom-to-top)
+        for vk in self.current_virtual_keys.iter().rev() {
+            result.actions.push(FocusAction::ReleaseVk(vk.clone()));
+        }
+        // Switch to default layer
+        if !default_layer.is_empty() && self.last_effective_layer != default_layer {
+            result
+                .actions
+                .push(FocusAction::ChangeLayer(default_layer.to_string()));
+        }
+        self.push_rule_commands(&mut result, &[], false, &WindowInfo::default(), default_layer);
+        result.new_managed_vks = Vec::new();
+        self.current_virtual_keys = Vec::new();
+        self.last_matched_rules.clear();
+        self.last_effective_layer = default_layer.to_string();
+        self.last_class.clear();
+        self.last_title.clear();
+        if result.is_empty() {
+            None
+        } else {
+            Some(result)
         }
     }
 
-    pub async fn known_virtual_keys(&self) -> Option<Vec<String>> {
-        let inner = self.inner.lock().await;
-        inner.known_virtual_keys.clone()
+    fn handle_native_terminal(&mut self, default_layer: &str) -> Option<FocusActions> {
+        let Some(rule) = self.native_terminal_rule.clone() else {
+            return self.handle_native_terminal_without_rule(default_layer);
+        };
+        self.native_terminal_hits += 1;
+        self.apply_session_rule(rule, NATIVE_TERMINAL_RULE_INDEX, "Native terminal active")
     }
 
-    pub fn default_layer_sync(&self) -> String {
-        tokio::task::block_in_place(|| {
-            tokio::runtime::Handle::current().block_on(async {
-                let inner = self.inner.lock().await;
-                inner
-                    .config_default_layer
-                    .clone()
-                    .or_else(|| inner.auto_default_layer.clone())
-                    .unwrap_or_default()
-            })
-        })
+    fn handle_session_inactive(&mut self, default_layer: &str) -> Option<FocusActions> {
+        let Some(rule) = self.session_inactive_rule.clone() else {
+            return self.handle_native_terminal(default_layer);
+        };
+        self.session_inactive_hits += 1;
+        self.apply_session_rule(rule, SESSION_INACTIVE_RULE_INDEX, "Session inactive")
     }
 
-    pub fn switch_to_default_if_connected_sync(&self) {
-        tokio::task::block_in_place(|| {
-            tokio::runtime::Handle::current().block_on(async {
-                let default_layer = self.default_layer().await;
-                let Some(default_layer) = default_layer else {
-                    eprintln!("[Shutdown] No default layer known, skipping reset");
-                    return;
-                };
+    fn handle_locked(&mut self, default_layer: &str) -> Option<FocusActions> {
+        let Some(rule) = self.locked_rule.clone() else {
+            return self.handle_session_inactive(default_layer);
+        };
+        self.locked_hits += 1;
+        self.apply_session_rule(rule, LOCKED_RULE_INDEX, "Screen locked")
+    }
 
-                if default_layer.is_empty() {
-                    eprintln!("[Shutdown] Default layer is empty, skipping reset");
-                    return;
-                }
+    /// Applies an on_native_terminal, on_session_inactive or on_locked rule in place of window
+    /// rules
+    fn apply_session_rule(
+        &mut self,
+        rule: NativeTerminalRule,
+        index: usize,
+        message: &str,
+    ) -> Option<FocusActions> {
+        if !self.quiet_focus && !rule.silent {
+            log_line!("[Focus] {}", message);
+        }
 
-                let mut inner = self.inner.lock().await;
-                if !inner.connected {
-                    eprintln!("[Shutdown] Not connected to kanata, skipping reset");
-                    return;
-                }
+        let mut result = FocusActions {
+            silent: rule.silent,
+            quiet_notify: !rule.notify,
+            ..Default::default()
+        };
+        let mut new_vks = Vec::new();
 
-                if inner.current_layer.as_ref() == Some(&default_layer) {
-                    println!("[Shutdown] Already on default layer \"{}\"", default_layer);
-                    return;
-                }
+        if let Some(vk) = rule.virtual_key.clone() {
+            new_vks.push(vk);
+        }
 
-                if let Some(ref mut writer) = inner.writer {
-                    let msg = ChangeLayerMsg {
-                        change_layer: ChangeLayerPayload {
-                            new: default_layer.clone(),
-                        },
-                    };
-                    let json = serde_json::to_string(&msg).unwrap() + "\n";
+        for vk in self.current_virtual_keys.iter().rev() {
+            if !new_vks.contains(vk) {
+                result.actions.push(FocusAction::ReleaseVk(vk.clone()));
+            }
+        }
 
-                    if writer.write_all(json.as_bytes()).await.is_ok() {
-                        println!("[Shutdown] Switched to default layer \"{}\"", default_layer);
-                    } else {
-                        eprintln!("[Shutdown] Failed to send layer change");
-                    }
+        let matched_indices = vec![index];
+        let is_new = self.last_matched_rules != matched_indices;
+
+        if is_new {
+            if !rule.layer.is_empty() && self.last_effective_layer != rule.layer {
+                result
+                    .actions
+                    .push(FocusAction::ChangeLayer(rule.layer.clone()));
+            }
+            if let Some(vk) = rule.virtual_key {
+                if !self.current_virtual_keys.contains(&vk) {
+                    result.actions.push(FocusAction::PressVk(vk));
                 }
-            })
-        })
-    }
-}
+            }
+            for step in &rule.raw_vk_action {
+                result.actions.push(step.to_focus_action());
+            }
+        }
 
-// === Shutdown Guard ===
+        self.push_rule_commands(
+            &mut result,
+            &matched_indices,
+            false,
+            &WindowInfo::default(),
+            &rule.layer,
+        );
+        result.new_managed_vks = new_vks;
+        self.last_matched_rules = matched_indices;
+        self.last_effective_layer = rule.layer;
+        self.current_virtual_keys = result.new_managed_vks.clone();
+        self.last_class.clear();
+        self.last_title.clear();
 
-struct ShutdownGuard {
-    kanata: KanataClient,
-}
+        if result.is_empty() {
+            None
+        } else {
+            Some(result)
+        }
+    }
 
-impl ShutdownGuard {
-    fn new(kanata: KanataClient) -> Self {
-        Self { kanata }
+    fn handle_native_terminal_without_rule(&mut self, default_layer: &str) -> Option<FocusActions> {
+        let mut result = FocusActions::default();
+        if !self.quiet_focus {
+            log_line!("[Focus] Native terminal active");
+        }
+        for vk in self.current_virtual_keys.iter().rev() {
+            result.actions.push(FocusAction::ReleaseVk(vk.clone()));
+        }
+        if !default_layer.is_empty() && self.last_effective_layer != default_layer {
+            result
+                .actions
+                .push(FocusAction::ChangeLayer(default_layer.to_string()));
+        }
+        self.push_rule_commands(&mut result, &[], false, &WindowInfo::default(), default_layer);
+        result.new_managed_vks = Vec::new();
+        self.current_virtual_keys = Vec::new();
+        self.last_matched_rules.clear();
+        self.last_effective_layer = default_layer.to_string();
+        self.last_class.clear();
+        self.last_title.clear();
+
+        if result.is_empty() {
+            None
+        } else {
+            Some(result)
+        }
     }
 }
 
-impl Drop for ShutdownGuard {
-    fn drop(&mut self) {
-        self.kanata.switch_to_default_if_connected_sync();
-    }
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct StatusSnapshot {
+    layer: String,
+    virtual_keys: Vec<String>,
+    layer_source: LayerSource,
+    /// Rules behind the focus layer and virtual keys (same indices as `FocusEvent`); empty
+    /// when the layer came from outside the rule engine or while paused
+    matched_rules: Vec<RuleRef>,
 }
 
-// === Environment Detection ===
+impl StatusSnapshot {
+    fn dbus_matched_rules(&self) -> Vec<i32> {
+        self.matched_rules
+            .iter()
+            .map(|rule| dbus_rule_index(rule.index))
+            .collect()
+    }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Environment {
-    Gnome,
-    Kde,
-    Wayland,
-    X11,
-    Unknown,
+    /// "#2, 'IDE', #5": rule names, or numbers as in validation messages
+    fn matched_rules_text(&self) -> String {
+        self.matched_rules
+            .iter()
+            .map(RuleRef::label)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum RunOutcome {
-    Restart,
-    Exit,
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum LayerSource {
+    Focus,
+    External,
+    /// Held by `PinLayer` until `UnpinLayer`
+    Pinned,
 }
 
-impl Environment {
-    pub fn as_str(&self) -> &'static str {
+impl LayerSource {
+    fn as_str(&self) -> &'static str {
         match self {
-            Environment::Gnome => "gnome",
-            Environment::Kde => "kde",
-            Environment::Wayland => "wayland",
-            Environment::X11 => "x11",
-            Environment::Unknown => "unknown",
+            LayerSource::Focus => "focus",
+            LayerSource::External => "external",
+            LayerSource::Pinned => "pinned",
         }
     }
 }
 
-fn detect_environment() -> Environment {
-    let desktop = env::var("XDG_CURRENT_DESKTOP")
-        .unwrap_or_default()
-        .to_lowercase();
+/// A focus change as seen by the rule engine (for `FocusChanged` / `kswitchctl monitor`).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct FocusEvent {
+    class: String,
+    title: String,
+    is_native_terminal: bool,
+    /// `WindowInfo::window_id`; None for pseudo-windows and backends without IDs
+    window_id: Option<String>,
+    /// Indices of the matched rules, NATIVE_TERMINAL_RULE_INDEX for the on_native_terminal rule,
+    /// SESSION_INACTIVE_RULE_INDEX for on_session_inactive and LOCKED_RULE_INDEX for on_locked
+    matched_rules: Vec<usize>,
+    /// The switch this focus change caused comes from `silent` rules only
+    silent: bool,
+    /// The switch this focus change caused comes from `"notify": false` rules only
+    quiet_notify: bool,
+}
 
-    // GNOME - needs special DBus extension
-    if desktop.contains("gnome") || env::var("GNOME_SETUP_DISPLAY").is_ok() {
-        return Environment::Gnome;
+impl FocusEvent {
+    /// Rule indices as sent over DBus; the on_native_terminal rule is -1, on_session_inactive -2,
+    /// on_locked -3.
+    fn dbus_matched_rules(&self) -> Vec<i32> {
+        self.matched_rules
+            .iter()
+            .map(|&index| dbus_rule_index(index))
+            .collect()
     }
+}
 
-    // KDE - needs KWin script injection
-    if env::var("KDE_SESSION_VERSION").is_ok() {
-        return Environment::Kde;
-    }
+/// The kanata connection dropped, or came back after dropping (`KanataConnectionChanged`).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct KanataConnectionEvent {
+    connected: bool,
+    /// Virtual key and reload actions dropped while disconnected; always 0 when the connection drops
+    dropped_actions: u64,
+}
 
-    // Wayland compositors (wlr-based or COSMIC) - use toplevel protocol
-    if env::var("WAYLAND_DISPLAY").is_ok() {
-        return Environment::Wayland;
+/// Rule index as sent over DBus; the on_native_terminal rule is -1, on_session_inactive -2,
+/// on_locked -3.
+fn dbus_rule_index(index: usize) -> i32 {
+    match index {
+        NATIVE_TERMINAL_RULE_INDEX => -1,
+        SESSION_INACTIVE_RULE_INDEX => -2,
+        LOCKED_RULE_INDEX => -3,
+        _ => i32::try_from(index).unwrap_or(i32::MAX),
     }
+}
 
-    // X11 fallback
-    if env::var("DISPLAY").is_ok() {
-        return Environment::X11;
+/// Rule name for users, matching `kswitchctl`'s: 1-based `#N` or the special rule's key
+fn rule_label(index: usize) -> String {
+    match index {
+        NATIVE_TERMINAL_RULE_INDEX => "on_native_terminal".to_string(),
+        SESSION_INACTIVE_RULE_INDEX => "on_session_inactive".to_string(),
+        LOCKED_RULE_INDEX => "on_locked".to_string(),
+        _ => format!("#{}", index + 1),
     }
-
-    Environment::Unknown
 }
 
-// === Wayland Toplevel State ===
+/// A rule as users see it: its index plus the config's `name`, if it has one
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct RuleRef {
+    index: usize,
+    name: Option<String>,
+}
 
-#[derive(Default)]
-struct ToplevelWindow {
-    app_id: String,
-    title: String,
+impl RuleRef {
+    /// The quoted `name`, else the number from `rule_label`
+    fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("'{}'", name),
+            None => rule_label(self.index),
+        }
+    }
 }
 
-#[derive(Default)]
-struct WaylandState {
-    windows: HashMap<ObjectId, ToplevelWindow>,
-    active_window: Option<ObjectId>,
+/// Human-friendly layer names from the config's `layer_names` entry. Only what users see
+/// changes (indicators, status output); kanata always gets the real layer names.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct LayerDisplayNames(Arc<BTreeMap<String, String>>);
+
+impl LayerDisplayNames {
+    fn new(names: BTreeMap<String, String>) -> Self {
+        Self(Arc::new(names))
+    }
+
+    /// The display name of `layer`, or `layer` itself if it has none
+    fn display<'a>(&'a self, layer: &'a str) -> &'a str {
+        self.0.get(layer).map(String::as_str).unwrap_or(layer)
+    }
+
+    fn as_map(&self) -> &BTreeMap<String, String> {
+        &self.0
+    }
 }
 
-impl WaylandState {
-    fn get_active_window(&self) -> WindowInfo {
-        self.active_window
-            .as_ref()
-            .and_then(|id| self.windows.get(id))
-            .map(|w| WindowInfo {
-                class: w.app_id.clone(),
-                title: w.title.clone(),
-                is_native_terminal: false,
-            })
-            .unwrap_or_default()
+#[derive(Clone, Debug)]
+struct StatusBroadcaster {
+    sender: watch::Sender<StatusSnapshot>,
+    focus_sender: watch::Sender<FocusEvent>,
+    /// Recoverable runtime failures by component; non-empty = degraded mode
+    health_sender: watch::Sender<BTreeMap<String, String>>,
+    kanata_connection_sender: watch::Sender<KanataConnectionEvent>,
+    layer_display_names: LayerDisplayNames,
+}
+
+#[derive(Clone, Debug)]
+struct RestartHandle {
+    sender: watch::Sender<bool>,
+}
+
+#[derive(Clone, Debug)]
+struct PauseBroadcaster {
+    sender: watch::Sender<bool>,
+    /// Set while the pause comes from `--pause-while-sharing` rather than the user; such a pause
+    /// is not persisted and is lifted when sharing stops
+    automatic: Arc<std::sync::atomic::AtomicBool>,
+}
+
+#[derive(Clone, Debug)]
+struct ShutdownHandle {
+    sender: watch::Sender<bool>,
+}
+
+impl RestartHandle {
+    fn new() -> Self {
+        let (sender, _) = watch::channel(false);
+        Self { sender }
+    }
+
+    fn subscribe(&self) -> watch::Receiver<bool> {
+        self.sender.subscribe()
+    }
+
+    fn request(&self) {
+        self.sender.send_replace(true);
     }
 }
 
-// === WLR Protocol Dispatch ===
+impl ShutdownHandle {
+    fn new() -> Self {
+        let (sender, _) = watch::channel(false);
+        Self { sender }
+    }
 
-impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for WaylandState {
-    fn event(
-        _: &mut Self,
-        _: &wl_registry::WlRegistry,
-        _: wl_registry::Event,
-        _: &GlobalListContents,
-        _: &WaylandConnection,
-        _: &QueueHandle<Self>,
-    ) {
+    fn subscribe(&self) -> watch::Receiver<bool> {
+        self.sender.subscribe()
+    }
+
+    fn request(&self) {
+        self.sender.send_replace(true);
     }
 }
 
-impl Dispatch<ZwlrForeignToplevelManagerV1, ()> for WaylandState {
-    fn event(
-        state: &mut Self,
-        _: &ZwlrForeignToplevelManagerV1,
-        event: zwlr_foreign_toplevel_manager_v1::Event,
-        _: &(),
-        _: &WaylandConnection,
-        _: &QueueHandle<Self>,
-    ) {
-        if let zwlr_foreign_toplevel_manager_v1::Event::Toplevel { toplevel } = event {
-            state
-                .windows
-                .insert(toplevel.id(), ToplevelWindow::default());
+impl PauseBroadcaster {
+    fn new() -> Self {
+        let (sender, _) = watch::channel(false);
+        Self {
+            sender,
+            automatic: Arc::new(std::sync::atomic::AtomicBool::new(false)),
         }
     }
 
-    wayland_client::event_created_child!(WaylandState, ZwlrForeignToplevelManagerV1, [
-        zwlr_foreign_toplevel_manager_v1::EVT_TOPLEVEL_OPCODE => (ZwlrForeignToplevelHandleV1, ())
-    ]);
-}
+    fn subscribe(&self) -> watch::Receiver<bool> {
+        self.sender.subscribe()
+    }
 
-impl Dispatch<ZwlrForeignToplevelHandleV1, ()> for WaylandState {
-    fn event(
-        state: &mut Self,
-        handle: &ZwlrForeignToplevelHandleV1,
-        event: zwlr_foreign_toplevel_handle_v1::Event,
-        _: &(),
-        _: &WaylandConnection,
-        _: &QueueHandle<Self>,
-    ) {
-        match event {
-            zwlr_foreign_toplevel_handle_v1::Event::AppId { app_id } => {
-                if let Some(w) = state.windows.get_mut(&handle.id()) {
-                    w.app_id = app_id;
-                }
-            }
-            zwlr_foreign_toplevel_handle_v1::Event::Title { title } => {
-                if let Some(w) = state.windows.get_mut(&handle.id()) {
-                    w.title = title;
-                }
-            }
-            zwlr_foreign_toplevel_handle_v1::Event::State {
-                state: handle_state,
-            } => {
-                let activated = zwlr_foreign_toplevel_handle_v1::State::Activated as u8;
-                if handle_state.contains(&activated) {
-                    state.active_window = Some(handle.id());
-                } else if state.active_window.as_ref() == Some(&handle.id()) {
-                    // Window lost activation - clear active_window
-                    state.active_window = None;
-                }
+    fn is_paused(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    fn is_automatic(&self) -> bool {
+        self.automatic.load(Ordering::SeqCst)
+    }
+
+    /// User pause/unpause. Pausing while paused automatically keeps the pause after sharing
+    /// stops; subscribers are notified again so the pause gets persisted.
+    fn set_paused(&self, paused: bool) -> bool {
+        let was_automatic = self.automatic.swap(false, Ordering::SeqCst);
+        let current = *self.sender.borrow();
+        if current == paused {
+            if was_automatic {
+                self.sender.send_modify(|_| {});
             }
-            zwlr_foreign_toplevel_handle_v1::Event::Closed => {
-                state.windows.remove(&handle.id());
-                if state.active_window.as_ref() == Some(&handle.id()) {
-                    state.active_window = None;
-                }
+            return false;
+        }
+        self.sender.send_replace(paused);
+        true
+    }
+
+    /// Pause for `--pause-while-sharing`, or lift such a pause. Never overrides a user pause:
+    /// pausing while paused and resuming a pause the user made are no-ops.
+    fn set_paused_automatically(&self, paused: bool) -> bool {
+        if paused {
+            if *self.sender.borrow() {
+                return false;
             }
-            _ => {}
+            self.automatic.store(true, Ordering::SeqCst);
+            self.sender.send_replace(true);
+            return true;
         }
+        if !self.automatic.swap(false, Ordering::SeqCst) {
+            return false;
+        }
+        self.sender.send_replace(false);
+        true
     }
 }
 
-// === COSMIC Protocol Dispatch ===
+// === Display Settings ===
 
-impl Dispatch<ZcosmicToplevelInfoV1, ()> for WaylandState {
-    fn event(
-        state: &mut Self,
-        _: &ZcosmicToplevelInfoV1,
-        event: zcosmic_toplevel_info_v1::Event,
-        _: &(),
-        _: &WaylandConnection,
-        _: &QueueHandle<Self>,
-    ) {
-        if let zcosmic_toplevel_info_v1::Event::Toplevel { toplevel } = event {
-            state
-                .windows
-                .insert(toplevel.id(), ToplevelWindow::default());
-        }
-    }
+/// `GetSettings`/`SetSetting` keys (named after the extension's former GSettings keys)
+const SETTING_FOCUS_ONLY: &str = "show-focus-layer-only";
+const SETTING_SHOW_TOP_BAR_ICON: &str = "show-top-bar-icon";
+const DEFAULT_SHOW_TOP_BAR_ICON: bool = true;
 
-    wayland_client::event_created_child!(WaylandState, ZcosmicToplevelInfoV1, [
-        zcosmic_toplevel_info_v1::EVT_TOPLEVEL_OPCODE => (ZcosmicToplevelHandleV1, ())
-    ]);
+/// Indicator settings owned by the daemon and shared by the SNI indicator, the GNOME extension
+/// and its prefs dialog; persisted in the runtime state file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DisplaySettings {
+    focus_only: bool,
+    show_top_bar_icon: bool,
 }
 
-impl Dispatch<ZcosmicToplevelHandleV1, ()> for WaylandState {
-    fn event(
-        state: &mut Self,
-        handle: &ZcosmicToplevelHandleV1,
-        event: zcosmic_toplevel_handle_v1::Event,
-        _: &(),
-        _: &WaylandConnection,
-        _: &QueueHandle<Self>,
-    ) {
-        match event {
-            zcosmic_toplevel_handle_v1::Event::AppId { app_id } => {
-                if let Some(w) = state.windows.get_mut(&handle.id()) {
-                    w.app_id = app_id;
-                }
-            }
-            zcosmic_toplevel_handle_v1::Event::Title { title } => {
-                if let Some(w) = state.windows.get_mut(&handle.id()) {
-                    w.title = title;
-                }
-            }
-            zcosmic_toplevel_handle_v1::Event::State {
-                state: handle_state,
-            } => {
-                // COSMIC: activated = 2
-                let (chunks, _) = handle_state.as_chunks::<4>();
-                let activated = chunks
-                    .iter()
-                    .map(|&chunk| u32::from_ne_bytes(chunk))
-                    .any(|s| s == zcosmic_toplevel_handle_v1::State::Activated as u32);
-                if activated {
-                    state.active_window = Some(handle.id());
-                } else if state.active_window.as_ref() == Some(&handle.id()) {
-                    // Window lost activation - clear active_window
-                    state.active_window = None;
-                }
-            }
-            zcosmic_toplevel_handle_v1::Event::Closed => {
-                state.windows.remove(&handle.id());
-                if state.active_window.as_ref() == Some(&handle.id()) {
-                    state.active_window = None;
-                }
-            }
-            _ => {}
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        Self {
+            focus_only: SNI_DEFAULT_SHOW_FOCUS_ONLY,
+            show_top_bar_icon: DEFAULT_SHOW_TOP_BAR_ICON,
         }
     }
 }
 
-// Dispatch for workspace types (we ignore these events but need to handle them)
-impl Dispatch<ZcosmicWorkspaceManagerV1, ()> for WaylandState {
-    fn event(
-        _: &mut Self,
-        _: &ZcosmicWorkspaceManagerV1,
-        _: cosmic_workspace::zcosmic_workspace_manager_v1::Event,
-        _: &(),
-        _: &WaylandConnection,
-        _: &QueueHandle<Self>,
-    ) {
+impl DisplaySettings {
+    /// Stored values, defaults for settings never changed
+    fn from_state(state: &RuntimeState) -> Self {
+        let defaults = Self::default();
+        Self {
+            focus_only: state.sni_focus_only.unwrap_or(defaults.focus_only),
+            show_top_bar_icon: state
+                .show_top_bar_icon
+                .unwrap_or(defaults.show_top_bar_icon),
+        }
     }
 
-    wayland_client::event_created_child!(WaylandState, ZcosmicWorkspaceManagerV1, [
-        cosmic_workspace::zcosmic_workspace_manager_v1::EVT_WORKSPACE_GROUP_OPCODE => (ZcosmicWorkspaceGroupHandleV1, ())
-    ]);
-}
+    fn as_map(&self) -> BTreeMap<&'static str, bool> {
+        BTreeMap::from([
+            (SETTING_FOCUS_ONLY, self.focus_only),
+            (SETTING_SHOW_TOP_BAR_ICON, self.show_top_bar_icon),
+        ])
+    }
 
-impl Dispatch<ZcosmicWorkspaceGroupHandleV1, ()> for WaylandState {
-    fn event(
-        _: &mut Self,
-        _: &ZcosmicWorkspaceGroupHandleV1,
-        _: cosmic_workspace::zcosmic_workspace_group_handle_v1::Event,
-        _: &(),
-        _: &WaylandConnection,
-        _: &QueueHandle<Self>,
-    ) {
+    fn set(&mut self, key: &str, value: bool) -> Result<(), String> {
+        match key {
+            SETTING_FOCUS_ONLY => self.focus_only = value,
+            SETTING_SHOW_TOP_BAR_ICON => self.show_top_bar_icon = value,
+            _ => return Err(format!("unknown setting '{}'", key)),
+        }
+        Ok(())
     }
 
-    wayland_client::event_created_child!(WaylandState, ZcosmicWorkspaceGroupHandleV1, [
-        cosmic_workspace::zcosmic_workspace_group_handle_v1::EVT_WORKSPACE_OPCODE => (ZcosmicWorkspaceHandleV1, ())
-    ]);
+    /// Writes the settings that differ from `previous`, so an `--indicator-focus-only` override
+    /// is not persisted by an unrelated change
+    fn store_changes(&self, previous: &DisplaySettings, state: &mut RuntimeState) {
+        if self.focus_only != previous.focus_only {
+            state.sni_focus_only = Some(self.focus_only);
+        }
+        if self.show_top_bar_icon != previous.show_top_bar_icon {
+            state.show_top_bar_icon = Some(self.show_top_bar_icon);
+        }
+    }
 }
 
-impl Dispatch<ZcosmicWorkspaceHandleV1, ()> for WaylandState {
-    fn event(
-        _: &mut Self,
-        _: &ZcosmicWorkspaceHandleV1,
-        _: cosmic_workspace::zcosmic_workspace_handle_v1::Event,
-        _: &(),
-        _: &WaylandConnection,
-        _: &QueueHandle<Self>,
-    ) {
+/// Startup settings: the state file's values, with `--indicator-focus-only` on top
+fn initial_display_settings(
+    store: Option<&RuntimeStateStore>,
+    focus_only_override: Option<TrayFocusOnly>,
+) -> DisplaySettings {
+    let mut settings = store
+        .map(|store| DisplaySettings::from_state(&store.load()))
+        .unwrap_or_default();
+    if let Some(value) = focus_only_override {
+        settings.focus_only = value.as_bool();
     }
+    settings
 }
 
-// Dispatch for wl_output (referenced by toplevel protocol)
-impl Dispatch<wayland_client::protocol::wl_output::WlOutput, ()> for WaylandState {
-    fn event(
-        _: &mut Self,
-        _: &wayland_client::protocol::wl_output::WlOutput,
-        _: wayland_client::protocol::wl_output::Event,
-        _: &(),
-        _: &WaylandConnection,
-        _: &QueueHandle<Self>,
-    ) {
+/// `dconf read` output for a boolean key; nothing (never changed) or anything else is None
+fn parse_dconf_bool(output: &str) -> Option<bool> {
+    match output.trim() {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
     }
 }
 
-// === Wayland Backend ===
-
-#[derive(Debug, Clone, Copy)]
-enum WaylandProtocol {
-    Wlr,
-    Cosmic,
+/// Display settings the GNOME extension left in GSettings before the daemon owned them
+fn read_legacy_display_settings() -> Vec<(&'static str, bool)> {
+    [SETTING_SHOW_TOP_BAR_ICON, SETTING_FOCUS_ONLY]
+        .into_iter()
+        .filter_map(|key| {
+            let output = Command::new("dconf")
+                .arg("read")
+                .arg(format!("{}{}", DCONF_SETTINGS_DIR, key))
+                .output()
+                .ok()
+                .filter(|output| output.status.success())?;
+            parse_dconf_bool(&String::from_utf8_lossy(&output.stdout)).map(|value| (key, value))
+        })
+        .collect()
 }
 
-async fn run_wayland(
-    kanata: KanataClient,
-    handler: Arc<Mutex<FocusHandler>>,
-    status_broadcaster: StatusBroadcaster,
-    pause_broadcaster: PauseBroadcaster,
-    shutdown_handle: ShutdownHandle,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let connection = WaylandConnection::connect_to_env()?;
-    let (globals, mut queue) = registry_queue_init::<WaylandState>(&connection)?;
-
-    let mut state = WaylandState::default();
+fn store_legacy_display_settings(state: &mut RuntimeState, values: &[(&str, bool)]) {
+    let previous = DisplaySettings::from_state(state);
+    let mut settings = previous;
+    for (key, value) in values {
+        let _ = settings.set(key, *value);
+    }
+    settings.store_changes(&previous, state);
+}
 
-    // Try wlr protocol first, fall back to cosmic
-    let protocol = if globals
-        .bind::<ZwlrForeignToplevelManagerV1, _, _>(&queue.handle(), 1..=3, ())
-        .is_ok()
-    {
-        WaylandProtocol::Wlr
-    } else if globals
-        .bind::<ZcosmicToplevelInfoV1, _, _>(&queue.handle(), 1..=1, ())
-        .is_ok()
-    {
-        WaylandProtocol::Cosmic
-    } else {
-        return Err(
-            "No supported toplevel protocol (wlr-foreign-toplevel or cosmic-toplevel-info)".into(),
-        );
-    };
+/// Moves the extension's old GSettings values into the state file, then resets them so this
+/// happens once. Runs before the settings are loaded, so `--indicator-focus-only` still wins
+async fn migrate_legacy_display_settings(store: &RuntimeStateStore) {
+    let values = tokio::task::spawn_blocking(read_legacy_display_settings)
+        .await
+        .unwrap_or_default();
+    if values.is_empty() {
+        return;
+    }
+    store.update(|state| store_legacy_display_settings(state, &values));
+    for (key, value) in &values {
+        log_line!("[Settings] Migrated {} = {} from GSettings", key, value);
+    }
+    let reset = tokio::task::spawn_blocking(|| {
+        run_setup_command("dconf", &["reset", "-f", DCONF_SETTINGS_DIR])
+    })
+    .await;
+    if let Ok(Err(error)) = reset {
+        elog_line!("[Settings] Cannot reset the old GSettings keys: {}", error);
+    }
+}
 
-    println!("[Wayland] Using {:?} toplevel protocol", protocol);
+#[derive(Clone, Debug)]
+struct SettingsBroadcaster {
+    sender: watch::Sender<DisplaySettings>,
+}
 
-    // Initial roundtrip to populate state
-    queue.roundtrip(&mut state)?;
+impl SettingsBroadcaster {
+    fn new(initial: DisplaySettings) -> Self {
+        let (sender, _) = watch::channel(initial);
+        Self { sender }
+    }
 
-    println!("[Wayland] Listening for focus events...");
+    fn subscribe(&self) -> watch::Receiver<DisplaySettings> {
+        self.sender.subscribe()
+    }
 
-    let raw_fd = connection.as_fd().as_raw_fd();
-    let async_fd = AsyncFd::new(RawFdWatcher::new(raw_fd))?;
-    let mut shutdown_receiver = shutdown_handle.subscribe();
+    fn current(&self) -> DisplaySettings {
+        *self.sender.borrow()
+    }
 
-    apply_focus_for_env(
-        Environment::Wayland,
-        None,
-        false,
-        &handler,
-        &status_broadcaster,
-        &pause_broadcaster,
-        &kanata,
-    )
-    .await?;
+    /// Ok(true) if the value changed; Err for unknown keys
+    fn set(&self, key: &str, value: bool) -> Result<bool, String> {
+        let mut next = self.current();
+        next.set(key, value)?;
+        Ok(self.sender.send_if_modified(|settings| {
+            let changed = *settings != next;
+            *settings = next;
+            changed
+        }))
+    }
+}
 
-    loop {
-        if *shutdown_receiver.borrow() {
-            return Ok(());
+impl StatusBroadcaster {
+    fn new() -> Self {
+        let initial = StatusSnapshot {
+            layer: String::new(),
+            virtual_keys: Vec::new(),
+            layer_source: LayerSource::External,
+            matched_rules: Vec::new(),
+        };
+        let (sender, _) = watch::channel(initial);
+        let (focus_sender, _) = watch::channel(FocusEvent::default());
+        let (health_sender, _) = watch::channel(BTreeMap::new());
+        let (kanata_connection_sender, _) = watch::channel(KanataConnectionEvent::default());
+        Self {
+            sender,
+            focus_sender,
+            health_sender,
+            kanata_connection_sender,
+            layer_display_names: LayerDisplayNames::default(),
         }
+    }
 
-        let dispatched = queue.dispatch_pending(&mut state)?;
-        if dispatched > 0 {
-            let win = state.get_active_window();
-            let default_layer = kanata.default_layer_sync();
-            if let Some(actions) = handle_focus_event(
-                &handler,
-                &status_broadcaster,
-                &pause_broadcaster,
-                &win,
-                &kanata,
-                &default_layer,
-            )
-            .await
-            {
-                execute_focus_actions(&kanata, actions).await;
-            }
-            continue;
+    fn with_layer_display_names(names: LayerDisplayNames) -> Self {
+        Self {
+            layer_display_names: names,
+            ..Self::new()
         }
+    }
 
-        connection.flush()?;
-        let guard = match queue.prepare_read() {
-            Some(guard) => guard,
-            None => continue,
-        };
-
-        let mut readiness = tokio::select! {
-            _ = shutdown_receiver.changed() => {
-                return Ok(());
-            }
-            readiness = async_fd.readable() => readiness?,
-        };
-
-        let read_result = guard.read();
-        readiness.clear_ready();
-
-        match read_result {
-            Ok(_) => {}
-            Err(WaylandError::Io(error)) if error.kind() == std::io::ErrorKind::WouldBlock => {}
-            Err(error) => {
-                eprintln!("[Wayland] Read error: {}", error);
-                return Err(error.into());
-            }
-        }
+    fn layer_display_names(&self) -> &LayerDisplayNames {
+        &self.layer_display_names
+    }
 
-        let _ = queue.dispatch_pending(&mut state)?;
-        let win = state.get_active_window();
-        let default_layer = kanata.default_layer_sync();
+    /// Record a runtime failure the daemon recovered from; it keeps running in degraded mode
+    /// until the same component reports success again.
+    fn set_degraded(&self, component: &str, problem: String) {
+        self.health_sender.send_if_modified(|problems| {
+            problems.insert(component.to_string(), problem.clone()) != Some(problem)
+        });
+    }
 
-        if let Some(actions) = handle_focus_event(
-            &handler,
-            &status_broadcaster,
-            &pause_broadcaster,
-            &win,
-            &kanata,
-            &default_layer,
-        )
-        .await
-        {
-            execute_focus_actions(&kanata, actions).await;
-        }
+    fn clear_degraded(&self, component: &str) {
+        self.health_sender
+            .send_if_modified(|problems| problems.remove(component).is_some());
     }
-}
 
-// === X11 Backend ===
+    /// "component: problem" lines, empty when healthy
+    fn degraded_problems(&self) -> Vec<String> {
+        self.health_sender
+            .borrow()
+            .iter()
+            .map(|(component, problem)| format!("{}: {}", component, problem))
+            .collect()
+    }
 
-x11rb::atom_manager! {
-    pub X11Atoms: X11AtomsCookie {
-        _NET_WM_NAME,
-        _NET_ACTIVE_WINDOW,
-        UTF8_STRING,
+    fn subscribe(&self) -> watch::Receiver<StatusSnapshot> {
+        self.sender.subscribe()
     }
-}
 
-struct X11State {
-    connection: RustConnection,
-    root: Window,
-    atoms: X11Atoms,
-}
+    fn subscribe_focus(&self) -> watch::Receiver<FocusEvent> {
+        self.focus_sender.subscribe()
+    }
 
-impl X11State {
-    fn new() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        let (connection, screen_num) = x11rb::connect(None)?;
-        let root = connection.setup().roots[screen_num].root;
-        let atoms = X11Atoms::new(&connection)?.reply()?;
+    /// Publish every handled focus change, even if it repeats the previous one.
+    fn publish_focus(&self, event: FocusEvent) {
+        self.focus_sender.send_replace(event);
+    }
 
-        // Subscribe to PropertyNotify events on root window
-        let attrs = ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE);
-        connection.change_window_attributes(root, &attrs)?;
-        connection.flush()?;
+    fn subscribe_kanata_connection(&self) -> watch::Receiver<KanataConnectionEvent> {
+        self.kanata_connection_sender.subscribe()
+    }
 
-        Ok(Self {
-            connection,
-            root,
-            atoms,
-        })
+    fn publish_kanata_connection(&self, event: KanataConnectionEvent) {
+        self.kanata_connection_sender.send_replace(event);
     }
 
-    fn get_active_window_id(&self) -> Option<Window> {
-        let prop_reply = self
-            .connection
-            .get_property(
-                false,
-                self.root,
-                self.atoms._NET_ACTIVE_WINDOW,
-                AtomEnum::WINDOW,
-                0,
-                1,
-            )
-            .ok()?
-            .reply()
-            .ok()?;
+    fn snapshot(&self) -> StatusSnapshot {
+        self.sender.borrow().clone()
+    }
 
-        if prop_reply.type_ == x11rb::NONE || prop_reply.value.len() != 4 {
-            return None;
-        }
+    fn update_layer(&self, layer: String, source: LayerSource) {
+        self.update(|state| {
+            if source == LayerSource::External {
+                state.matched_rules.clear();
+            }
+            state.layer = layer;
+            state.layer_source = source;
+        });
+    }
 
-        let arr: [u8; 4] = prop_reply.value.clone().try_into().ok()?;
-        let winid = u32::from_le_bytes(arr);
+    /// Virtual keys and rules from a handled focus event, published as one change
+    fn update_focus_match(&self, virtual_keys: Vec<String>, matched_rules: Vec<RuleRef>) {
+        self.update(|state| {
+            state.virtual_keys = virtual_keys;
+            state.matched_rules = matched_rules;
+        });
+    }
 
-        if winid == 0 { None } else { Some(winid) }
+    fn update_focus_layer(&self, layer: String) {
+        let mut next = self.sender.borrow().clone();
+        next.layer = layer;
+        next.layer_source = LayerSource::Focus;
+        self.sender.send_replace(next);
     }
 
-    fn get_window_class(&self, window: Window) -> Option<String> {
-        let reply = self
-            .connection
-            .get_property(false, window, AtomEnum::WM_CLASS, AtomEnum::STRING, 0, 1024)
-            .ok()?
-            .reply()
-            .ok()?;
+    fn set_paused_status(&self, layer: String) {
+        let mut next = self.sender.borrow().clone();
+        next.layer = layer;
+        next.layer_source = LayerSource::External;
+        next.virtual_keys = Vec::new();
+        next.matched_rules = Vec::new();
+        self.sender.send_replace(next);
+    }
 
-        if reply.value.is_empty() {
-            return None;
-        }
+    fn set_pinned_status(&self, layer: String) {
+        self.update(|state| {
+            state.layer = layer;
+            state.layer_source = LayerSource::Pinned;
+            state.virtual_keys = Vec::new();
+            state.matched_rules = Vec::new();
+        });
+    }
 
-        // WM_CLASS format: "instance\0class\0"
-        // We want just the class part (second element)
-        let parts: Vec<&[u8]> = reply.value.split(|&b| b == 0).collect();
-        if parts.len() >= 2 {
-            String::from_utf8(parts[1].to_vec()).ok()
-        } else if !parts.is_empty() {
-            String::from_utf8(parts[0].to_vec()).ok()
-        } else {
-            None
+    fn update<F>(&self, updater: F)
+    where
+        F: FnOnce(&mut StatusSnapshot),
+    {
+        let current = self.sender.borrow().clone();
+        let mut next = current.clone();
+        updater(&mut next);
+        if next != current {
+            self.sender.send_replace(next);
         }
     }
+}
 
-    fn get_window_title(&self, window: Window) -> Option<String> {
-        // Try _NET_WM_NAME first (UTF-8)
-        let prop_reply = self
-            .connection
-            .get_property(
-                false,
-                window,
-                self.atoms._NET_WM_NAME,
-                self.atoms.UTF8_STRING,
-                0,
-                u32::MAX,
-            )
-            .ok()?
-            .reply()
-            .ok()?;
+async fn wait_for_restart_or_shutdown(
+    restart_handle: &RestartHandle,
+    shutdown_handle: &ShutdownHandle,
+) -> RunOutcome {
+    let mut restart_receiver = restart_handle.subscribe();
+    let mut shutdown_receiver = shutdown_handle.subscribe();
 
-        if prop_reply.type_ != x11rb::NONE {
-            return String::from_utf8(prop_reply.value).ok();
+    if *shutdown_receiver.borrow() {
+        return RunOutcome::Exit;
+    }
+    if *restart_receiver.borrow() {
+        return RunOutcome::Restart;
+    }
+
+    tokio::select! {
+        _ = shutdown_receiver.changed() => RunOutcome::Exit,
+        _ = restart_receiver.changed() => {
+            if *shutdown_receiver.borrow() {
+                RunOutcome::Exit
+            } else {
+                RunOutcome::Restart
+            }
         }
+    }
+}
 
-        // Fallback to WM_NAME (Latin-1)
-        let prop_reply = self
-            .connection
-            .get_property(
-                false,
-                window,
-                AtomEnum::WM_NAME,
-                AtomEnum::STRING,
-                0,
-                u32::MAX,
-            )
-            .ok()?
-            .reply()
-            .ok()?;
+async fn wait_for_restart(restart_handle: &RestartHandle) {
+    let mut restart_receiver = restart_handle.subscribe();
+    let _ = restart_receiver.wait_for(|requested| *requested).await;
+}
 
-        String::from_utf8(prop_reply.value).ok()
-    }
+// === Runtime State ===
 
-    fn get_active_window(&self) -> WindowInfo {
-        let Some(window_id) = self.get_active_window_id() else {
-            return WindowInfo::default();
-        };
+const STATE_DIR_NAME: &str = "kanata-switcher";
+const STATE_FILENAME_STEM: &str = "state";
 
-        let class = self.get_window_class(window_id).unwrap_or_default();
-        let title = self.get_window_title(window_id).unwrap_or_default();
+/// Serializes read-modify-write cycles on the state file within this process.
+static STATE_FILE_LOCK: Mutex<()> = Mutex::new(());
 
-        WindowInfo {
-            class,
-            title,
-            is_native_terminal: false,
+/// Runtime settings restored on startup. Unknown or missing fields fall back to defaults so the
+/// file survives upgrades in both directions.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+struct RuntimeState {
+    paused: bool,
+    /// "Show app layer only" (`show-focus-layer-only`); None = never changed
+    sni_focus_only: Option<bool>,
+    /// GNOME top bar indicator (`show-top-bar-icon`); None = never changed
+    show_top_bar_icon: Option<bool>,
+    /// Last focus-derived layer (`--restore-layer`)
+    last_layer: Option<String>,
+    /// Layer pinned via `PinLayer` or the tray; None = not pinned
+    pinned_layer: Option<String>,
+}
+
+fn xdg_state_home() -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    if let Ok(xdg_state_home) = env::var("XDG_STATE_HOME") {
+        if xdg_state_home.is_empty() {
+            return Err("XDG_STATE_HOME is empty".into());
         }
+        return Ok(PathBuf::from(xdg_state_home));
+    }
+    let home = env::var("HOME")?;
+    if home.is_empty() {
+        return Err("HOME is empty".into());
     }
+    Ok(PathBuf::from(home).join(".local").join("state"))
 }
 
-async fn run_x11(
-    kanata: KanataClient,
-    handler: Arc<Mutex<FocusHandler>>,
-    status_broadcaster: StatusBroadcaster,
-    pause_broadcaster: PauseBroadcaster,
-    shutdown_handle: ShutdownHandle,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let state = X11State::new()?;
-
-    println!("[X11] Connected to display");
-
-    apply_focus_for_env(
-        Environment::X11,
-        None,
-        false,
-        &handler,
-        &status_broadcaster,
-        &pause_broadcaster,
-        &kanata,
-    )
-    .await?;
+fn state_dir() -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(xdg_state_home()?.join(STATE_DIR_NAME))
+}
 
-    println!("[X11] Listening for focus events...");
+fn state_filename(instance: &DbusInstance) -> String {
+    format!("{}{}.json", STATE_FILENAME_STEM, instance.file_tag())
+}
 
-    let raw_fd = state.connection.stream().as_raw_fd();
-    let async_fd = AsyncFd::new(RawFdWatcher::new(raw_fd))?;
-    let mut shutdown_receiver = shutdown_handle.subscribe();
+#[derive(Debug, Clone)]
+struct RuntimeStateStore {
+    path: PathBuf,
+}
 
-    // Event loop - wait for PropertyNotify events on _NET_ACTIVE_WINDOW
-    loop {
-        if *shutdown_receiver.borrow() {
-            return Ok(());
-        }
+impl RuntimeStateStore {
+    fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
 
-        while let Some(event) = state.connection.poll_for_event()? {
-            match event {
-                X11Event::PropertyNotify(e) if e.atom == state.atoms._NET_ACTIVE_WINDOW => {
-                    let win = state.get_active_window();
-                    let default_layer = kanata.default_layer_sync();
-
-                    if let Some(actions) = handle_focus_event(
-                        &handler,
-                        &status_broadcaster,
-                        &pause_broadcaster,
-                        &win,
-                        &kanata,
-                        &default_layer,
-                    )
-                    .await
-                    {
-                        execute_focus_actions(&kanata, actions).await;
-                    }
-                }
-                _ => {}
+    /// Store at `$XDG_STATE_HOME/kanata-switcher/state.json` (`state-SUFFIX.json` for a
+    /// `--dbus-suffix` instance), None if no state dir can be resolved.
+    fn open_default(instance: &DbusInstance) -> Option<Self> {
+        match state_dir() {
+            Ok(dir) => Some(Self::new(dir.join(state_filename(instance)))),
+            Err(error) => {
+                elog_line!("[State] Runtime state disabled: {}", error);
+                None
             }
         }
+    }
 
-        let mut readiness = tokio::select! {
-            _ = shutdown_receiver.changed() => {
-                return Ok(());
+    /// Missing or unreadable files yield the default state (with a warning for corrupt files).
+    fn load(&self) -> RuntimeState {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return RuntimeState::default();
+            }
+            Err(error) => {
+                elog_line!("[State] Failed to read {}: {}", self.path.display(), error);
+                return RuntimeState::default();
             }
-            readiness = async_fd.readable() => readiness?,
         };
-        readiness.clear_ready();
+        serde_json::from_str(&content).unwrap_or_else(|error| {
+            elog_line!(
+                "[State] Ignoring invalid state file {}: {}",
+                self.path.display(),
+                error
+            );
+            RuntimeState::default()
+        })
     }
-}
 
-fn start_sni_indicator(
-    control: SniControl,
-    status_broadcaster: StatusBroadcaster,
-    pause_broadcaster: PauseBroadcaster,
-    indicator_focus_only: Option<TrayFocusOnly>,
-) -> Option<ksni::Handle<SniIndicator>> {
-    println!("[SNI] Starting StatusNotifier indicator");
-    let initial_status = status_broadcaster.snapshot();
-    let mut settings = SniSettingsStore::new();
-    let show_focus_only = resolve_sni_focus_only(indicator_focus_only, &mut settings);
-    let (menu_refresh, mut menu_refresh_receiver) = MenuRefresh::new();
-    let control_handle: Arc<dyn SniControlOps> = Arc::new(control);
-    let indicator = SniIndicator {
-        state: SniIndicatorState::new(initial_status, show_focus_only),
-        control: control_handle,
-        settings,
-        menu_refresh,
-    };
-    let service = TrayService::new(indicator);
-    let handle = service.handle();
+    /// Read-modify-write; the file is replaced atomically so a crash never leaves it truncated.
+    fn update<F>(&self, updater: F)
+    where
+        F: FnOnce(&mut RuntimeState),
+    {
+        let _lock = STATE_FILE_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let current = self.load();
+        let mut next = current.clone();
+        updater(&mut next);
+        if next == current {
+            return;
+        }
+        if let Err(error) = self.write(&next) {
+            elog_line!("[State] Failed to write {}: {}", self.path.display(), error);
+        }
+    }
 
-    let pause_initial = pause_broadcaster.is_paused();
-    handle.update(|state| state.set_paused(pause_initial));
+    fn write(&self, state: &RuntimeState) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let content = serde_json::to_string_pretty(state).map_err(std::io::Error::other)?;
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, content)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
 
-    let status_handle = handle.clone();
-    let mut status_receiver = status_broadcaster.subscribe();
+/// Persist pause changes as they happen (DBus, SNI, CLI control), except automatic pauses.
+fn spawn_pause_state_writer(store: RuntimeStateStore, pause_broadcaster: &PauseBroadcaster) {
+    let mut receiver = pause_broadcaster.subscribe();
+    let pause_broadcaster = pause_broadcaster.clone();
     tokio::spawn(async move {
-        loop {
-            if status_receiver.changed().await.is_err() {
-                break;
+        while receiver.changed().await.is_ok() {
+            let paused = *receiver.borrow_and_update();
+            if paused && pause_broadcaster.is_automatic() {
+                continue;
             }
-            let snapshot = status_receiver.borrow().clone();
-            status_handle.update(|state| state.update_status(snapshot));
+            let store = store.clone();
+            let _ = tokio::task::spawn_blocking(move || {
+                store.update(|state| state.paused = paused);
+            })
+            .await;
         }
     });
+}
 
-    let pause_handle = handle.clone();
-    let mut pause_receiver = pause_broadcaster.subscribe();
+/// Persist display setting changes (SNI menu, `SetSetting`)
+fn spawn_settings_state_writer(store: RuntimeStateStore, settings: &SettingsBroadcaster) {
+    let mut receiver = settings.subscribe();
     tokio::spawn(async move {
-        loop {
-            if pause_receiver.changed().await.is_err() {
-                break;
-            }
-            let paused = *pause_receiver.borrow();
-            pause_handle.update(|state| state.set_paused(paused));
+        let mut previous = *receiver.borrow_and_update();
+        while receiver.changed().await.is_ok() {
+            let current = *receiver.borrow_and_update();
+            let store = store.clone();
+            let _ = tokio::task::spawn_blocking(move || {
+                store.update(|state| current.store_changes(&previous, state));
+            })
+            .await;
+            previous = current;
         }
     });
+}
 
-    let menu_handle = handle.clone();
+/// Track the last focus-derived layer (`--restore-layer`): persist it and keep it as the layer
+/// the kanata client re-applies after a reconnect.
+fn spawn_layer_state_writer(
+    store: RuntimeStateStore,
+    status_broadcaster: &StatusBroadcaster,
+    kanata: KanataClient,
+) {
+    let mut receiver = status_broadcaster.subscribe();
     tokio::spawn(async move {
-        loop {
-            if menu_refresh_receiver.changed().await.is_err() {
-                break;
+        let mut last_layer: Option<String> = None;
+        while receiver.changed().await.is_ok() {
+            let snapshot = receiver.borrow_and_update().clone();
+            if snapshot.layer_source != LayerSource::Focus
+                || snapshot.layer.is_empty()
+                || last_layer.as_ref() == Some(&snapshot.layer)
+            {
+                continue;
             }
-            menu_handle.update(|state| state.state.bump_menu_revision());
+            last_layer = Some(snapshot.layer.clone());
+            kanata.set_restore_layer(Some(snapshot.layer.clone())).await;
+            let store = store.clone();
+            let _ = tokio::task::spawn_blocking(move || {
+                store.update(|state| state.last_layer = Some(snapshot.layer));
+            })
+            .await;
         }
     });
+}
 
-    thread::spawn(move || match service.run() {
-        Ok(()) => println!("[SNI] Indicator stopped"),
-        Err(error) => eprintln!("[SNI] Failed to run indicator: {}", error),
+/// Persist pin changes (`PinLayer`/`UnpinLayer`, tray "Pin layer")
+fn spawn_pin_state_writer(store: RuntimeStateStore, kanata: &KanataClient) {
+    let mut receiver = kanata.subscribe_pinned();
+    tokio::spawn(async move {
+        while receiver.changed().await.is_ok() {
+            let pinned_layer = receiver.borrow_and_update().clone();
+            let store = store.clone();
+            let _ = tokio::task::spawn_blocking(move || {
+                store.update(|state| state.pinned_layer = pinned_layer);
+            })
+            .await;
+        }
     });
-
-    Some(handle)
 }
 
-struct SniGuard {
-    handle: Option<ksni::Handle<SniIndicator>>,
-}
+// === Spoken Announcements ===
 
-impl SniGuard {
-    fn new(handle: Option<ksni::Handle<SniIndicator>>) -> Self {
-        Self { handle }
+impl AnnounceConfig {
+    /// Text spoken for `layer`: the per-layer override, else its display name. None = silent
+    fn text_for(&self, layer: &str, display_names: &LayerDisplayNames) -> Option<String> {
+        match self.layers.get(layer) {
+            Some(text) if text.trim().is_empty() => None,
+            Some(text) => Some(text.clone()),
+            None => Some(display_names.display(layer).to_string()),
+        }
     }
 }
 
-impl Drop for SniGuard {
-    fn drop(&mut self) {
-        if let Some(handle) = self.handle.take() {
-            println!("[SNI] Shutting down indicator");
-            handle.shutdown();
+/// Speak each new layer, except focus switches caused by `silent` rules only. A change
+/// arriving within `min_interval_ms` of the previous announcement waits out the interval, and
+/// only the layer active then is spoken, so rapid focus changes don't queue up a backlog of
+/// speech.
+async fn run_layer_announcer<F>(
+    config: AnnounceConfig,
+    display_names: LayerDisplayNames,
+    mut receiver: watch::Receiver<StatusSnapshot>,
+    focus: watch::Receiver<FocusEvent>,
+    mut speak: F,
+) where
+    F: FnMut(String),
+{
+    let min_interval = Duration::from_millis(config.min_interval_ms);
+    let mut last_layer = String::new();
+    let mut last_spoken: Option<tokio::time::Instant> = None;
+    while receiver.changed().await.is_ok() {
+        if let Some(spoken_at) = last_spoken {
+            tokio::time::sleep_until(spoken_at + min_interval).await;
+        }
+        let (layer, source) = {
+            let snapshot = receiver.borrow_and_update();
+            (snapshot.layer.clone(), snapshot.layer_source.clone())
+        };
+        if layer.is_empty() || layer == last_layer {
+            continue;
+        }
+        let silent = source == LayerSource::Focus && focus.borrow().silent;
+        if !silent && let Some(text) = config.text_for(&layer, &display_names) {
+            speak(text);
+            last_spoken = Some(tokio::time::Instant::now());
         }
+        last_layer = layer;
     }
 }
 
-struct DbusControlGuard {
-    _connection: Connection,
+fn spawn_layer_announcer(config: AnnounceConfig, status_broadcaster: &StatusBroadcaster) {
+    log_line!(
+        "[Announce] Speaking layer changes via {}",
+        config.command.first().map(String::as_str).unwrap_or_default()
+    );
+    let command = config.command.clone();
+    let broadcaster = status_broadcaster.clone();
+    let speak = move |text: String| {
+        let command = command.clone();
+        let broadcaster = broadcaster.clone();
+        tokio::spawn(async move {
+            let result = tokio::process::Command::new(&command[0])
+                .args(&command[1..])
+                .arg(&text)
+                .stdin(std::process::Stdio::null())
+                .stdout(std::process::Stdio::null())
+                .status()
+                .await;
+            let problem = match result {
+                Ok(status) if status.success() => {
+                    broadcaster.clear_degraded("announce");
+                    return;
+                }
+                Ok(status) => format!("{} exited with {}", command[0], status),
+                Err(error) => format!("failed to run {}: {}", command[0], error),
+            };
+            elog_line!("[Announce] {}", problem);
+            broadcaster.set_degraded("announce", problem);
+        });
+    };
+    tokio::spawn(run_layer_announcer(
+        config,
+        status_broadcaster.layer_display_names().clone(),
+        status_broadcaster.subscribe(),
+        status_broadcaster.subscribe_focus(),
+        speak,
+    ));
 }
 
-impl DbusControlGuard {
-    fn new(connection: Connection) -> Self {
-        Self {
-            _connection: connection,
-        }
-    }
-}
+// === SNI Indicator ===
 
-fn dconf_get_bool(key: &str) -> Result<bool, String> {
-    let output = Command::new("dconf")
-        .args(["read", key])
-        .output()
-        .map_err(|error| format!("dconf read failed: {}", error))?;
+const SNI_DEFAULT_SHOW_FOCUS_ONLY: bool = true;
+const SNI_FONT_WEIGHT: FontWeight = FontWeight::Regular;
+const SNI_RASTER_HEIGHT: RasterHeight = RasterHeight::Size32;
+const SNI_DEFAULT_ICON_SIZE: u16 = 32;
+const SNI_GLYPH_GAP: usize = 4;
+const SNI_COLOR_LAYER: IconColor = IconColor([255, 255, 255, 255]);
+const SNI_COLOR_VK: IconColor = IconColor([255, 0, 255, 255]);
+const SNI_MAX_VK_COUNT_DIGIT: usize = 9;
+const SNI_MIN_MULTI_VK_COUNT: usize = 2;
+const SNI_INDICATOR_ID: &str = "kanata-switcher";
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("dconf read failed: {}", stderr.trim()));
+struct MenuRefresh {
+    sender: watch::Sender<u64>,
+    version: u64,
+}
+
+impl MenuRefresh {
+    fn new() -> (Self, watch::Receiver<u64>) {
+        let (sender, receiver) = watch::channel(0u64);
+        (
+            Self {
+                sender,
+                version: 0,
+            },
+            receiver,
+        )
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    match stdout.trim() {
-        "true" => Ok(true),
-        "false" => Ok(false),
-        "" => Err("key not set".to_string()),
-        value => Err(format!("unexpected dconf output: {}", value)),
+    fn notify(&mut self) {
+        self.version += 1;
+        self.sender.send_replace(self.version);
     }
 }
 
-fn dconf_set_bool(key: &str, value: bool) -> Result<(), String> {
-    let value_str = if value { "true" } else { "false" };
-    let output = Command::new("dconf")
-        .args(["write", key, value_str])
-        .output()
-        .map_err(|error| format!("dconf write failed: {}", error))?;
-
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("dconf write failed: {}", stderr.trim()));
-    }
-
-    Ok(())
+/// How the tray icon shows one layer (config `tray_icons`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum TrayIcon {
+    /// First letter of the layer's display name (the default)
+    Letter,
+    /// A fixed character instead of the letter
+    Glyph(char),
+    /// Passive status, which trays hide
+    Hidden,
+    /// A freedesktop icon from the user's icon theme (`"icon:NAME"`)
+    Named(String),
+    /// A PNG or SVG file, found by the tray through `IconThemePath`
+    File(PathBuf),
 }
 
-fn is_dconf_unavailable(error: &str) -> bool {
-    let lower = error.to_lowercase();
-    lower.contains("no such file or directory")
-        || lower.contains("not found")
-        || lower.contains("failed to execute")
+impl TrayIcon {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "letter" => return Ok(Self::Letter),
+            "hidden" => return Ok(Self::Hidden),
+            _ => {}
+        }
+        if let Some(name) = value.strip_prefix("icon:") {
+            let valid = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+            if !valid {
+                return Err(format!("invalid icon name \"{}\"", name));
+            }
+            return Ok(Self::Named(name.to_string()));
+        }
+        if value.contains('/') {
+            let path = PathBuf::from(value);
+            let extension = path.extension().and_then(|extension| extension.to_str());
+            if !path.is_absolute() || !matches!(extension, Some("png" | "svg")) {
+                return Err(format!(
+                    "icon files must be absolute .png or .svg paths, got \"{}\"",
+                    value
+                ));
+            }
+            return Ok(Self::File(path));
+        }
+        let mut chars = value.chars();
+        let (Some(glyph), None) = (chars.next(), chars.next()) else {
+            return Err(format!(
+                "expected \"letter\", \"hidden\", a single character, \"icon:NAME\" or an icon \
+                 file path, got \"{}\"",
+                value
+            ));
+        };
+        if get_raster(glyph, SNI_FONT_WEIGHT, SNI_RASTER_HEIGHT).is_none() {
+            return Err(format!("the tray font has no glyph for '{}'", glyph));
+        }
+        Ok(Self::Glyph(glyph))
+    }
 }
 
-// === GNOME Extension Management ===
-
-/// Path to GNOME extension source relative to repository root
-const GNOME_EXTENSION_SRC_PATH: &str = "src/gnome-extension";
-const GNOME_EXTENSION_SCHEMA_FILE: &str =
-    "schemas/org.gnome.shell.extensions.kanata-switcher.gschema.xml";
-const GNOME_EXTENSION_SCHEMA_COMPILED: &str = "schemas/gschemas.compiled";
+/// `#rrggbb` or `#rrggbbaa`, kept as the ARGB bytes of SNI pixmaps
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct IconColor([u8; 4]);
 
-#[cfg(feature = "embed-gnome-extension")]
-macro_rules! gnome_ext_file {
-    ($file:literal) => {
-        concat!("../../", "src/gnome-extension", "/", $file)
-    };
+impl IconColor {
+    fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("expected \"#rrggbb\" or \"#rrggbbaa\", got \"{}\"", value);
+        let hex = value.strip_prefix('#').ok_or_else(invalid)?;
+        if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let byte = |index: usize| u8::from_str_radix(&hex[index..index + 2], 16);
+        let (Ok(red), Ok(green), Ok(blue)) = (byte(0), byte(2), byte(4)) else {
+            return Err(invalid());
+        };
+        let alpha = if hex.len() == 8 {
+            byte(6).map_err(|_| invalid())?
+        } else {
+            255
+        };
+        Ok(Self([alpha, red, green, blue]))
+    }
 }
 
-#[cfg(feature = "embed-gnome-extension")]
-const EMBEDDED_EXTENSION_JS: &str = include_str!(gnome_ext_file!("extension.js"));
-#[cfg(feature = "embed-gnome-extension")]
-const EMBEDDED_METADATA_JSON: &str = include_str!(gnome_ext_file!("metadata.json"));
-#[cfg(feature = "embed-gnome-extension")]
-const EMBEDDED_PREFS_JS: &str = include_str!(gnome_ext_file!("prefs.js"));
-#[cfg(feature = "embed-gnome-extension")]
-const EMBEDDED_FORMAT_JS: &str = include_str!(gnome_ext_file!("format.js"));
-#[cfg(feature = "embed-gnome-extension")]
-const EMBEDDED_DBUS_JS: &str = include_str!(gnome_ext_file!("dbus.js"));
-#[cfg(feature = "embed-gnome-extension")]
-const EMBEDDED_FOCUS_JS: &str = include_str!(gnome_ext_file!("focus.js"));
-#[cfg(feature = "embed-gnome-extension")]
-const EMBEDDED_GSETTINGS_SCHEMA: &str = include_str!(gnome_ext_file!(
-    "schemas/org.gnome.shell.extensions.kanata-switcher.gschema.xml"
-));
+impl<'de> Deserialize<'de> for IconColor {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        Self::parse(&value).map_err(serde::de::Error::custom)
+    }
+}
 
-fn get_gnome_extension_fs_path() -> PathBuf {
-    let exe_path = env::current_exe().unwrap();
-    let exe_dir = exe_path.parent().unwrap();
-    exe_dir.join("gnome")
+impl Serialize for IconColor {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let [alpha, red, green, blue] = self.0;
+        let mut text = format!("#{:02x}{:02x}{:02x}", red, green, blue);
+        if alpha != 255 {
+            text.push_str(&format!("{:02x}", alpha));
+        }
+        serializer.serialize_str(&text)
+    }
 }
 
-fn gnome_extension_fs_exists() -> bool {
-    let path = get_gnome_extension_fs_path();
-    path.join("extension.js").exists()
-        && path.join("metadata.json").exists()
-        && path.join("prefs.js").exists()
-        && path.join("format.js").exists()
-        && path.join("dbus.js").exists()
-        && path.join("focus.js").exists()
-        && path.join(GNOME_EXTENSION_SCHEMA_FILE).exists()
-        && path.join(GNOME_EXTENSION_SCHEMA_COMPILED).exists()
+/// Which letters of a layer's display name the tray icon shows
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum LetterStyle {
+    #[default]
+    Uppercase,
+    Lowercase,
+    /// First letter uppercase, second lowercase ("Ga" for gaming)
+    TwoLetters,
 }
 
-#[cfg(feature = "embed-gnome-extension")]
-fn compile_gnome_schemas(dir: &Path) -> std::io::Result<()> {
-    let schema_dir = dir.join("schemas");
-    let output = Command::new("glib-compile-schemas")
-        .arg(&schema_dir)
-        .output()?;
-    if !output.status.success() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!(
-                "glib-compile-schemas failed: {}",
-                String::from_utf8_lossy(&output.stderr)
-            ),
-        ));
-    }
-    Ok(())
+/// Tray icon look from the config's `{"indicator": {...}}` entry
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct IndicatorTheme {
+    /// Layer letter color
+    #[serde(default = "default_indicator_color")]
+    color: IconColor,
+    /// Virtual key letter/count color
+    #[serde(default = "default_indicator_vk_color")]
+    vk_color: IconColor,
+    /// Fills the icon behind the letters (default: transparent)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    background: Option<IconColor>,
+    /// Icon height in pixels: 16, 20, 24 or 32
+    #[serde(default = "default_indicator_size")]
+    size: u16,
+    #[serde(default)]
+    letters: LetterStyle,
+    /// Kanata layer name -> letter color instead of `color`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    layer_colors: BTreeMap<String, IconColor>,
 }
 
-#[cfg(feature = "embed-gnome-extension")]
-fn write_embedded_extension_to_dir(dir: &Path) -> std::io::Result<()> {
-    fs::write(dir.join("extension.js"), EMBEDDED_EXTENSION_JS)?;
-    fs::write(dir.join("metadata.json"), EMBEDDED_METADATA_JSON)?;
-    fs::write(dir.join("prefs.js"), EMBEDDED_PREFS_JS)?;
-    fs::write(dir.join("format.js"), EMBEDDED_FORMAT_JS)?;
-    fs::write(dir.join("dbus.js"), EMBEDDED_DBUS_JS)?;
-    fs::write(dir.join("focus.js"), EMBEDDED_FOCUS_JS)?;
-    let schema_dir = dir.join("schemas");
-    fs::create_dir_all(&schema_dir)?;
-    fs::write(
-        dir.join(GNOME_EXTENSION_SCHEMA_FILE),
-        EMBEDDED_GSETTINGS_SCHEMA,
-    )?;
-    compile_gnome_schemas(dir)?;
-    Ok(())
+fn default_indicator_color() -> IconColor {
+    SNI_COLOR_LAYER
 }
 
-enum GnomeDetectionMethod {
-    /// Detected via D-Bus call to org.gnome.Shell.Extensions
-    Dbus,
-    /// Detected via gnome-extensions CLI and gsettings
-    Cli,
+fn default_indicator_vk_color() -> IconColor {
+    SNI_COLOR_VK
 }
 
-struct GnomeExtensionStatus {
-    installed: bool,
-    enabled: bool,
-    /// Extension is active in GNOME Shell (verified via D-Bus)
-    active: bool,
-    /// Raw state from D-Bus (None for CLI detection)
-    /// 1=ENABLED, 2=DISABLED, 3=ERROR, 4=OUT_OF_DATE, 5=DOWNLOADING, 6=INITIALIZED
-    state: Option<u8>,
-    /// How the status was detected
-    method: GnomeDetectionMethod,
+fn default_indicator_size() -> u16 {
+    SNI_DEFAULT_ICON_SIZE
 }
 
-fn gnome_state_name(state: u8) -> &'static str {
-    match state {
-        1 => "enabled",
-        2 => "disabled",
-        3 => "error",
-        4 => "out_of_date",
-        5 => "downloading",
-        6 => "initialized",
-        _ => "unknown",
+impl Default for IndicatorTheme {
+    fn default() -> Self {
+        Self {
+            color: SNI_COLOR_LAYER,
+            vk_color: SNI_COLOR_VK,
+            background: None,
+            size: SNI_DEFAULT_ICON_SIZE,
+            letters: LetterStyle::default(),
+            layer_colors: BTreeMap::new(),
+        }
     }
 }
 
-/// Parse GNOME Shell extension state from D-Bus response.
-/// State values: 1.0=ENABLED, 2.0=DISABLED, 3.0=ERROR, 4.0=OUT_OF_DATE, 5.0=DOWNLOADING, 6.0=INITIALIZED
-#[cfg_attr(test, allow(dead_code))]
-fn parse_gnome_extension_state(
-    body: &HashMap<String, zbus::zvariant::OwnedValue>,
-) -> GnomeExtensionStatus {
-    // State is returned as f64 by GNOME Shell D-Bus API
-    let state_f64: f64 = body
-        .get("state")
-        .and_then(|v| v.downcast_ref::<f64>().ok())
-        .unwrap_or(0.0);
-    let state = state_f64 as u8;
+impl IndicatorTheme {
+    /// The bitmap font's heights; None for other sizes
+    fn raster_height_for(size: u16) -> Option<RasterHeight> {
+        match size {
+            16 => Some(RasterHeight::Size16),
+            20 => Some(RasterHeight::Size20),
+            24 => Some(RasterHeight::Size24),
+            32 => Some(RasterHeight::Size32),
+            _ => None,
+        }
+    }
 
-    // State 1 = ENABLED (active)
-    let active = state == 1;
+    fn raster_height(&self) -> RasterHeight {
+        Self::raster_height_for(self.size).unwrap_or(SNI_RASTER_HEIGHT)
+    }
 
-    GnomeExtensionStatus {
-        installed: true,
-        enabled: active,
-        active,
-        state: Some(state),
-        method: GnomeDetectionMethod::Dbus,
+    fn layer_color(&self, layer: &str) -> IconColor {
+        self.layer_colors.get(layer).copied().unwrap_or(self.color)
     }
 }
 
-// D-Bus coordinates for GNOME Shell Extensions interface
-const GNOME_SHELL_BUS_NAME: &str = "org.gnome.Shell";
-const GNOME_SHELL_OBJECT_PATH: &str = "/org/gnome/Shell";
-const GNOME_SHELL_EXTENSIONS_INTERFACE: &str = "org.gnome.Shell.Extensions";
+impl<'de> Deserialize<'de> for TrayIcon {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        Self::parse(&value).map_err(serde::de::Error::custom)
+    }
+}
 
-/// Quick probe: check if extension is active via D-Bus call to GNOME Shell.
-/// This bypasses filesystem searches and works reliably from systemd services.
-fn gnome_extension_dbus_probe() -> Option<GnomeExtensionStatus> {
-    let connection = match zbus::blocking::Connection::session() {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!(
-                "[GNOME] D-Bus probe: failed to connect to session bus: {}",
-                e
-            );
-            return None;
+impl Serialize for TrayIcon {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            Self::Letter => serializer.serialize_str("letter"),
+            Self::Glyph(glyph) => serializer.serialize_char(*glyph),
+            Self::Hidden => serializer.serialize_str("hidden"),
+            Self::Named(name) => serializer.serialize_str(&format!("icon:{}", name)),
+            Self::File(path) => serializer.serialize_str(&path.to_string_lossy()),
         }
-    };
-    gnome_extension_dbus_probe_with_connection(&connection)
+    }
 }
 
-/// Probe using a specific D-Bus connection (for testing with mock services)
-fn gnome_extension_dbus_probe_with_connection(
-    connection: &zbus::blocking::Connection,
-) -> Option<GnomeExtensionStatus> {
-    let reply = match connection.call_method(
-        Some(GNOME_SHELL_BUS_NAME),
-        GNOME_SHELL_OBJECT_PATH,
-        Some(GNOME_SHELL_EXTENSIONS_INTERFACE),
-        "GetExtensionInfo",
-        &(GNOME_EXTENSION_UUID,),
-    ) {
-        Ok(r) => r,
-        Err(e) => {
-            eprintln!("[GNOME] D-Bus probe: GetExtensionInfo call failed: {}", e);
-            return None;
+#[derive(Clone, Debug)]
+struct SniIndicatorState {
+    last_status: StatusSnapshot,
+    focus_status: StatusSnapshot,
+    paused: bool,
+    show_focus_only: bool,
+    /// Kanata's layers, for the "Pin layer" submenu
+    layers: Vec<String>,
+    menu_revision: u64,
+}
+
+impl SniIndicatorState {
+    fn new(initial: StatusSnapshot, show_focus_only: bool) -> Self {
+        Self {
+            last_status: initial.clone(),
+            focus_status: initial,
+            paused: false,
+            show_focus_only,
+            layers: Vec::new(),
+            menu_revision: 0,
         }
-    };
+    }
 
-    // Response is a dict (a{sv}) with extension info
-    let body: HashMap<String, zbus::zvariant::OwnedValue> = match reply.body().deserialize() {
-        Ok(b) => b,
-        Err(e) => {
-            eprintln!("[GNOME] D-Bus probe: failed to deserialize response: {}", e);
-            return None;
+    fn update_status(&mut self, snapshot: StatusSnapshot) {
+        // A pinned layer was chosen by the user, so "Show app layer only" shows it too
+        if matches!(snapshot.layer_source, LayerSource::Focus | LayerSource::Pinned) {
+            self.focus_status = snapshot.clone();
         }
-    };
+        self.last_status = snapshot;
+    }
 
-    Some(parse_gnome_extension_state(&body))
-}
+    fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
 
-fn gnome_extension_status() -> GnomeExtensionStatus {
-    // Quick probe: try D-Bus call to GNOME Shell first
-    // This is the most reliable method from systemd services
-    if let Some(status) = gnome_extension_dbus_probe() {
-        return status;
+    fn set_layers(&mut self, layers: Vec<String>) {
+        self.layers = layers;
     }
 
-    // Fallback: CLI tools (may fail from systemd if XDG_DATA_DIRS is incomplete)
+    fn pinned_layer(&self) -> Option<&str> {
+        (self.last_status.layer_source == LayerSource::Pinned)
+            .then_some(self.last_status.layer.as_str())
+    }
 
-    // Check installed via gnome-extensions info (requires XDG_DATA_DIRS)
-    let installed = Command::new("gnome-extensions")
-        .args(["info", GNOME_EXTENSION_UUID])
-        .output()
-        .map(|o| o.status.success())
-        .unwrap_or(false);
+    fn toggle_focus_only(&mut self) {
+        self.show_focus_only = !self.show_focus_only;
+    }
 
-    // Check enabled via gsettings (more reliable from systemd services)
-    let enabled = Command::new("gsettings")
-        .args(["get", "org.gnome.shell", "enabled-extensions"])
-        .output()
-        .map(|o| {
-            let stdout = String::from_utf8_lossy(&o.stdout);
-            stdout.contains(GNOME_EXTENSION_UUID)
-        })
-        .unwrap_or(false);
+    fn set_focus_only(&mut self, show_focus_only: bool) {
+        self.show_focus_only = show_focus_only;
+    }
 
-    GnomeExtensionStatus {
-        installed,
-        enabled,
-        active: false,
-        state: None,
-        method: GnomeDetectionMethod::Cli,
+    fn focus_only_enabled(&self) -> bool {
+        self.show_focus_only
     }
-}
 
-fn print_gnome_extension_install_instructions(reason: &str) {
-    let fs_path = get_gnome_extension_fs_path();
-    let install_steps = if gnome_extension_fs_exists() {
-        format!(
-            r#"Extension files are available at: {}
+    fn bump_menu_revision(&mut self) {
+        self.menu_revision = self.menu_revision.wrapping_add(1);
+    }
 
-  gnome-extensions pack "{}" --force --out-dir=/tmp
-  gnome-extensions install "/tmp/{}.shell-extension.zip" --force
-  gnome-extensions enable {}"#,
-            fs_path.display(),
-            fs_path.display(),
-            GNOME_EXTENSION_UUID,
-            GNOME_EXTENSION_UUID
-        )
-    } else {
-        format!(
-            r#"Clone the repository and install:
-
-  git clone https://github.com/7mind/kanata-switcher.git /tmp/kanata-switcher
-  gnome-extensions pack /tmp/kanata-switcher/{} --force --out-dir=/tmp
-  gnome-extensions install "/tmp/{}.shell-extension.zip" --force
-  gnome-extensions enable {}"#,
-            GNOME_EXTENSION_SRC_PATH, GNOME_EXTENSION_UUID, GNOME_EXTENSION_UUID
-        )
-    };
-
-    eprintln!(
-        r#"
-[GNOME] Extension not installed.
-
-{}
-
-To install manually:
+    fn display_status(&self) -> StatusSnapshot {
+        if self.paused {
+            return self.last_status.clone();
+        }
+        if self.show_focus_only {
+            return self.focus_status.clone();
+        }
+        self.last_status.clone()
+    }
+}
 
-{}
+#[derive(Clone)]
+struct SniLocalControl {
+    runtime_handle: tokio::runtime::Handle,
+    kanata: KanataClient,
+    handler: Arc<Mutex<FocusHandler>>,
+    status_broadcaster: StatusBroadcaster,
+    pause_broadcaster: PauseBroadcaster,
+    restart_handle: RestartHandle,
+    env: Environment,
+    connection: Option<Connection>,
+    is_kde6: bool,
+}
 
-Then restart GNOME Shell:
-  - Press Alt+F2, type "r", press Enter (X11 only)
-  - Or log out and log back in (Wayland)
-"#,
-        reason, install_steps
-    );
+#[derive(Clone)]
+struct SniDbusControl {
+    runtime_handle: tokio::runtime::Handle,
+    connection: Connection,
+    restart_handle: RestartHandle,
+    instance: DbusInstance,
 }
 
-fn pack_and_install_from_dir(src_dir: &Path, tmp_dir: &Path) -> Result<(), String> {
-    let zip_name = format!("{}.shell-extension.zip", GNOME_EXTENSION_UUID);
+#[derive(Clone)]
+enum SniControl {
+    Local(SniLocalControl),
+    Dbus(SniDbusControl),
+}
 
-    let pack_result = Command::new("gnome-extensions")
-        .args([
-            "pack",
-            src_dir.to_str().unwrap(),
-            "--force",
-            &format!("--out-dir={}", tmp_dir.display()),
-        ])
-        .output();
+trait SniControlOps: Send + Sync {
+    fn restart(&self);
+    fn pause(&self);
+    fn unpause(&self);
+    fn set_layer(&self, layer: &str);
+    fn pin_layer(&self, layer: &str);
+    fn unpin_layer(&self);
+}
 
-    if pack_result.is_err() || !pack_result.as_ref().unwrap().status.success() {
-        return Err("gnome-extensions pack failed".to_string());
+impl SniControlOps for SniControl {
+    fn restart(&self) {
+        log_line!("[SNI] Restart requested");
+        match self {
+            SniControl::Local(control) => {
+                control.restart_handle.request();
+            }
+            SniControl::Dbus(control) => {
+                control.runtime_handle.block_on(async {
+                    if let Err(error) = send_control_command_with_connection(
+                        &control.connection,
+                        ControlCommand::Restart,
+                        &control.instance,
+                    )
+                    .await
+                    {
+                        elog_line!("[SNI] Failed to send restart: {}", error);
+                    }
+                });
+                control.restart_handle.request();
+            }
+        }
     }
 
-    let zip_path = tmp_dir.join(&zip_name);
-    let install_result = Command::new("gnome-extensions")
-        .args(["install", zip_path.to_str().unwrap(), "--force"])
-        .output();
-
-    if install_result.is_err() || !install_result.as_ref().unwrap().status.success() {
-        return Err("gnome-extensions install failed".to_string());
+    fn pause(&self) {
+        log_line!("[SNI] Pause requested");
+        match self {
+            SniControl::Local(control) => {
+                pause_daemon(
+                    &control.pause_broadcaster,
+                    &control.handler,
+                    &control.status_broadcaster,
+                    &control.kanata,
+                    &control.runtime_handle,
+                    "via SNI",
+                );
+            }
+            SniControl::Dbus(control) => {
+                control.runtime_handle.block_on(async {
+                    if let Err(error) = send_control_command_with_connection(
+                        &control.connection,
+                        ControlCommand::Pause,
+                        &control.instance,
+                    )
+                    .await
+                    {
+                        elog_line!("[SNI] Failed to send pause: {}", error);
+                    }
+                });
+            }
+        }
     }
 
-    Ok(())
-}
-
-#[allow(unused_variables, unused_assignments)]
-fn install_gnome_extension() -> bool {
-    let tmp_dir = tempfile::tempdir().unwrap();
-    let fs_path = get_gnome_extension_fs_path();
-    let mut fs_error: Option<String> = None;
-
-    // Try filesystem first
-    if gnome_extension_fs_exists() {
-        println!("[GNOME] Installing from filesystem: {}", fs_path.display());
-        match pack_and_install_from_dir(&fs_path, tmp_dir.path()) {
-            Ok(()) => {
-                println!("[GNOME] Extension installed");
-                return true;
+    fn unpause(&self) {
+        log_line!("[SNI] Unpause requested");
+        match self {
+            SniControl::Local(control) => {
+                unpause_daemon(
+                    control.env,
+                    control.connection.clone(),
+                    control.is_kde6,
+                    &control.pause_broadcaster,
+                    &control.handler,
+                    &control.status_broadcaster,
+                    &control.kanata,
+                    &control.runtime_handle,
+                    "via SNI",
+                );
             }
-            Err(e) => {
-                eprintln!("[GNOME] Failed to install from filesystem: {}", e);
-                fs_error = Some(e);
+            SniControl::Dbus(control) => {
+                control.runtime_handle.block_on(async {
+                    if let Err(error) = send_control_command_with_connection(
+                        &control.connection,
+                        ControlCommand::Unpause,
+                        &control.instance,
+                    )
+                    .await
+                    {
+                        elog_line!("[SNI] Failed to send unpause: {}", error);
+                    }
+                });
             }
         }
-    } else {
-        eprintln!(
-            "[GNOME] Extension files not found at filesystem path: {}",
-            fs_path.display()
-        );
     }
 
-    // Fallback to embedded extension
-    #[cfg(feature = "embed-gnome-extension")]
-    {
-        eprintln!("[GNOME] Falling back to embedded extension...");
-        let embedded_dir = tmp_dir.path().join("embedded");
-        fs::create_dir_all(&embedded_dir).unwrap();
-
-        if let Err(e) = write_embedded_extension_to_dir(&embedded_dir) {
-            eprintln!("[GNOME] Failed to write embedded extension: {}", e);
-            print_gnome_extension_install_instructions(
-                "Auto-install failed: could not write embedded extension files.",
-            );
-            return false;
+    fn set_layer(&self, layer: &str) {
+        log_line!("[SNI] Switch to \"{}\" requested", layer);
+        match self {
+            SniControl::Local(control) => {
+                set_layer_daemon(
+                    &control.kanata,
+                    &control.status_broadcaster,
+                    &control.runtime_handle,
+                    layer,
+                    "via SNI",
+                );
+            }
+            SniControl::Dbus(control) => {
+                control.runtime_handle.block_on(async {
+                    let result = control
+                        .connection
+                        .call_method(
+                            Some(control.instance.name().as_str()),
+                            control.instance.path().as_str(),
+                            Some(DBUS_INTERFACE),
+                            "SetLayer",
+                            &(layer,),
+                        )
+                        .await;
+                    if let Err(error) = result {
+                        elog_line!("[SNI] Failed to send layer switch: {}", error);
+                    }
+                });
+            }
         }
+    }
 
-        match pack_and_install_from_dir(&embedded_dir, tmp_dir.path()) {
-            Ok(()) => {
-                println!("[GNOME] Extension installed (from embedded)");
-                return true;
+    fn pin_layer(&self, layer: &str) {
+        log_line!("[SNI] Pin to \"{}\" requested", layer);
+        match self {
+            SniControl::Local(control) => {
+                pin_daemon(
+                    &control.handler,
+                    &control.kanata,
+                    &control.runtime_handle,
+                    layer,
+                    "via SNI",
+                );
             }
-            Err(e) => {
-                eprintln!("[GNOME] Failed to install from embedded: {}", e);
-                print_gnome_extension_install_instructions(&format!("Auto-install failed: {}", e));
-                return false;
+            SniControl::Dbus(control) => {
+                control.runtime_handle.block_on(async {
+                    let result = control
+                        .connection
+                        .call_method(
+                            Some(control.instance.name().as_str()),
+                            control.instance.path().as_str(),
+                            Some(DBUS_INTERFACE),
+                            "PinLayer",
+                            &(layer,),
+                        )
+                        .await;
+                    if let Err(error) = result {
+                        elog_line!("[SNI] Failed to send pin: {}", error);
+                    }
+                });
             }
         }
     }
 
-    #[cfg(not(feature = "embed-gnome-extension"))]
-    {
-        let reason = if let Some(e) = fs_error {
-            format!(
-                "Found extension files at {}, but installation failed: {}. \
-                 Cannot fall back to embedded extension (disabled in this build).",
-                fs_path.display(),
-                e
-            )
-        } else {
-            "Extension files not found and embedded extension is disabled in this build."
-                .to_string()
-        };
-        print_gnome_extension_install_instructions(&reason);
-        return false;
+    fn unpin_layer(&self) {
+        log_line!("[SNI] Unpin requested");
+        match self {
+            SniControl::Local(control) => {
+                unpin_daemon(
+                    control.env,
+                    control.connection.clone(),
+                    control.is_kde6,
+                    &control.pause_broadcaster,
+                    &control.handler,
+                    &control.status_broadcaster,
+                    &control.kanata,
+                    &control.runtime_handle,
+                    "via SNI",
+                );
+            }
+            SniControl::Dbus(control) => {
+                control.runtime_handle.block_on(async {
+                    let result = control
+                        .connection
+                        .call_method(
+                            Some(control.instance.name().as_str()),
+                            control.instance.path().as_str(),
+                            Some(DBUS_INTERFACE),
+                            "UnpinLayer",
+                            &(),
+                        )
+                        .await;
+                    if let Err(error) = result {
+                        elog_line!("[SNI] Failed to send unpin: {}", error);
+                    }
+                });
+            }
+        }
     }
 }
 
-fn enable_gnome_extension() -> bool {
-    let result = Command::new("gnome-extensions")
-        .args(["enable", GNOME_EXTENSION_UUID])
-        .output();
-
-    match result {
-        Ok(output) if output.status.success() => {
-            println!("[GNOME] Extension enabled");
-            true
+struct SniIndicator {
+    state: SniIndicatorState,
+    layer_display_names: LayerDisplayNames,
+    tray_icons: BTreeMap<String, TrayIcon>,
+    theme: IndicatorTheme,
+    control: Arc<dyn SniControlOps>,
+    settings: SettingsBroadcaster,
+    menu_refresh: MenuRefresh,
+}
+
+impl SniIndicator {
+    fn update_status(&mut self, snapshot: StatusSnapshot) {
+        self.state.update_status(snapshot);
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        self.state.set_paused(paused);
+    }
+
+    fn toggle_focus_only(&mut self) {
+        self.state.toggle_focus_only();
+        let show_focus_only = self.state.focus_only_enabled();
+        let _ = self.settings.set(SETTING_FOCUS_ONLY, show_focus_only);
+        self.menu_refresh.notify();
+    }
+
+    fn request_pause(&self) {
+        if self.state.paused {
+            self.control.unpause();
+        } else {
+            self.control.pause();
         }
-        _ => {
-            eprintln!("[GNOME] Failed to enable extension");
-            eprintln!("[GNOME] Try restarting GNOME Shell first:");
-            eprintln!("[GNOME]   - Press Alt+F2, type \"r\", press Enter (X11 only)");
-            eprintln!("[GNOME]   - Or log out and log back in (Wayland)");
-            eprintln!(
-                "[GNOME] Then run: gnome-extensions enable {}",
-                GNOME_EXTENSION_UUID
+    }
+
+    fn request_restart(&self) {
+        self.control.restart();
+    }
+
+    /// "Switch layer" submenu: one radio item per kanata layer, the current one selected.
+    /// Disabled while paused or pinned, when kanata would refuse the switch
+    fn layer_menu(&self) -> MenuItem<Self> {
+        let layers = self.state.layers.clone();
+        let current = &self.state.last_status.layer;
+        let options = layers
+            .iter()
+            .map(|layer| RadioItem {
+                label: self.layer_display_names.display(layer).replace('_', "__"),
+                ..RadioItem::default()
+            })
+            .collect();
+        let group = MenuItem::RadioGroup(RadioGroup {
+            // Out of range (layer unknown) selects nothing
+            selected: layers.iter().position(|layer| layer == current).unwrap_or(usize::MAX),
+            select: Box::new(move |this: &mut Self, index| {
+                if let Some(layer) = layers.get(index) {
+                    this.control.set_layer(layer);
+                }
+            }),
+            options,
+        });
+        MenuItem::SubMenu(SubMenu {
+            label: "Switch layer".to_string(),
+            enabled: !self.state.layers.is_empty()
+                && !self.state.paused
+                && self.state.pinned_layer().is_none(),
+            submenu: vec![group],
+            ..SubMenu::default()
+        })
+    }
+
+    /// Choosing the pinned layer again unpins it
+    fn request_pin(&self, layer: &str) {
+        if self.state.pinned_layer() == Some(layer) {
+            self.control.unpin_layer();
+        } else {
+            self.control.pin_layer(layer);
+        }
+    }
+
+    /// "Pin layer" submenu: one checkmark per kanata layer, the pinned one checked
+    fn pin_menu(&self) -> MenuItem<Self> {
+        let pinned = self.state.pinned_layer();
+        let submenu = self
+            .state
+            .layers
+            .iter()
+            .map(|layer| {
+                let name = layer.clone();
+                MenuItem::Checkmark(CheckmarkItem {
+                    // Underscores would otherwise mark access keys
+                    label: self.layer_display_names.display(layer).replace('_', "__"),
+                    checked: pinned == Some(layer.as_str()),
+                    activate: Box::new(move |this: &mut Self| this.request_pin(&name)),
+                    ..CheckmarkItem::default()
+                })
+            })
+            .collect();
+        MenuItem::SubMenu(SubMenu {
+            label: "Pin layer".to_string(),
+            enabled: !self.state.layers.is_empty() && !self.state.paused,
+            submenu,
+            ..SubMenu::default()
+        })
+    }
+
+    fn format_layer_letter(layer_name: &str, style: LetterStyle) -> String {
+        let mut chars = layer_name.trim().chars();
+        let Some(first) = chars.next() else {
+            return "?".to_string();
+        };
+        match style {
+            LetterStyle::Uppercase => first.to_uppercase().to_string(),
+            LetterStyle::Lowercase => first.to_lowercase().to_string(),
+            LetterStyle::TwoLetters => first
+                .to_uppercase()
+                .chain(chars.next().into_iter().flat_map(char::to_lowercase))
+                .collect(),
+        }
+    }
+
+    fn format_virtual_keys(virtual_keys: &[String]) -> String {
+        let count = virtual_keys.len();
+        if count == 0 {
+            return String::new();
+        }
+        if count == 1 {
+            let name = virtual_keys[0].trim();
+            if name.is_empty() {
+                return String::new();
+            }
+            return name
+                .chars()
+                .next()
+                .map(|c| c.to_uppercase().to_string())
+                .unwrap_or_default();
+        }
+        if count < SNI_MIN_MULTI_VK_COUNT {
+            return String::new();
+        }
+        if count > SNI_MAX_VK_COUNT_DIGIT {
+            return format!("{}+", SNI_MAX_VK_COUNT_DIGIT);
+        }
+        count.to_string()
+    }
+
+    fn glyph_for_char(ch: char, height: RasterHeight) -> RasterizedChar {
+        get_raster(ch, SNI_FONT_WEIGHT, height)
+            .or_else(|| get_raster('?', SNI_FONT_WEIGHT, height))
+            .expect("SNI glyph lookup failed")
+    }
+
+    fn draw_glyph(
+        buffer: &mut [u8],
+        width: usize,
+        height: usize,
+        x: usize,
+        y: usize,
+        glyph: &RasterizedChar,
+        color: IconColor,
+    ) {
+        for (row_index, row) in glyph.raster().iter().enumerate() {
+            let dest_y = y + row_index;
+            if dest_y >= height {
+                continue;
+            }
+            for (col_index, intensity) in row.iter().enumerate() {
+                let dest_x = x + col_index;
+                if dest_x >= width {
+                    continue;
+                }
+                if *intensity == 0 {
+                    continue;
+                }
+                let offset = (dest_y * width + dest_x) * 4;
+                // Blends into the background (transparent by default) at the glyph's coverage
+                let coverage = u16::from(*intensity);
+                for (channel, value) in color.0.iter().enumerate() {
+                    let below = u16::from(buffer[offset + channel]);
+                    buffer[offset + channel] =
+                        ((u16::from(*value) * coverage + below * (255 - coverage)) / 255) as u8;
+                }
+            }
+        }
+    }
+
+    fn text_width(text: &str, height: RasterHeight) -> usize {
+        get_raster_width(SNI_FONT_WEIGHT, height) * text.chars().count()
+    }
+
+    /// Draws `text` into an icon as tall as `raster_height`
+    fn draw_text(
+        buffer: &mut [u8],
+        width: usize,
+        x: usize,
+        y: usize,
+        text: &str,
+        color: IconColor,
+        raster_height: RasterHeight,
+    ) -> usize {
+        let height = raster_height.val();
+        let mut cursor_x = x;
+        for ch in text.chars() {
+            let glyph = Self::glyph_for_char(ch, raster_height);
+            Self::draw_glyph(buffer, width, height, cursor_x, y, &glyph, color);
+            cursor_x += glyph.width();
+        }
+        cursor_x - x
+    }
+
+    fn render_icon(theme: &IndicatorTheme, layer: &str, layer_text: &str, vk_text: &str) -> SniIcon {
+        let raster_height = theme.raster_height();
+        let icon_height = raster_height.val();
+        let layer_width = Self::text_width(layer_text, raster_height);
+        let vk_width = Self::text_width(vk_text, raster_height);
+        let gap = if vk_text.is_empty() || layer_text.is_empty() {
+            0
+        } else {
+            SNI_GLYPH_GAP
+        };
+        let icon_width = layer_width + gap + vk_width;
+        let mut buffer = vec![0u8; icon_width * icon_height * 4];
+        if let Some(background) = theme.background {
+            for pixel in buffer.chunks_exact_mut(4) {
+                pixel.copy_from_slice(&background.0);
+            }
+        }
+        let layer_x = 0;
+        let vk_x = layer_x + layer_width + gap;
+
+        Self::draw_text(
+            &mut buffer,
+            icon_width,
+            layer_x,
+            0,
+            layer_text,
+            theme.layer_color(layer),
+            raster_height,
+        );
+        if !vk_text.is_empty() {
+            Self::draw_text(
+                &mut buffer,
+                icon_width,
+                vk_x,
+                0,
+                vk_text,
+                theme.vk_color,
+                raster_height,
             );
-            false
         }
+
+        SniIcon {
+            width: icon_width as i32,
+            height: icon_height as i32,
+            data: buffer,
+        }
+    }
+
+    fn tray_icon(&self, layer: &str) -> TrayIcon {
+        self.tray_icons.get(layer).cloned().unwrap_or(TrayIcon::Letter)
+    }
+
+    fn display_strings(&self) -> (String, String) {
+        let status = self.state.display_status();
+        let layer_text = match self.tray_icon(&status.layer) {
+            TrayIcon::Glyph(glyph) => glyph.to_string(),
+            TrayIcon::Letter | TrayIcon::Hidden | TrayIcon::Named(_) | TrayIcon::File(_) => {
+                Self::format_layer_letter(
+                    self.layer_display_names.display(&status.layer),
+                    self.theme.letters,
+                )
+            }
+        };
+        let vk_text = Self::format_virtual_keys(&status.virtual_keys);
+        (layer_text, vk_text)
+    }
+
+    fn tooltip_text(&self) -> String {
+        let status = self.state.display_status();
+        let layer = self.layer_display_names.display(&status.layer);
+        let mut text = format!("Layer: {}", layer);
+        if !status.virtual_keys.is_empty() {
+            text.push_str(&format!("\nVirtual keys: {}", status.virtual_keys.join(", ")));
+        }
+        if !status.matched_rules.is_empty() {
+            text.push_str(&format!("\nRules: {}", status.matched_rules_text()));
+        }
+        text
+    }
+
+    fn title_text(&self) -> String {
+        "Kanata Switcher".to_string()
+    }
+}
+
+impl Tray for SniIndicator {
+    fn id(&self) -> String {
+        SNI_INDICATOR_ID.to_string()
+    }
+
+    fn title(&self) -> String {
+        self.title_text()
+    }
+
+    fn status(&self) -> SniStatus {
+        match self.tray_icon(&self.state.display_status().layer) {
+            TrayIcon::Hidden => SniStatus::Passive,
+            TrayIcon::Letter | TrayIcon::Glyph(_) | TrayIcon::Named(_) | TrayIcon::File(_) => {
+                SniStatus::Active
+            }
+        }
+    }
+
+    fn icon_theme_path(&self) -> String {
+        match self.tray_icon(&self.state.display_status().layer) {
+            TrayIcon::File(path) => path
+                .parent()
+                .map(|dir| dir.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            _ => String::new(),
+        }
+    }
+
+    /// Trays prefer the name over the pixmap, which stays as a fallback for ones that can't
+    /// resolve it
+    fn icon_name(&self) -> String {
+        match self.tray_icon(&self.state.display_status().layer) {
+            TrayIcon::Named(name) => name,
+            TrayIcon::File(path) => path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            _ => String::new(),
+        }
+    }
+
+    fn icon_pixmap(&self) -> Vec<SniIcon> {
+        let (layer_text, vk_text) = self.display_strings();
+        let layer = &self.state.display_status().layer;
+        vec![Self::render_icon(&self.theme, layer, &layer_text, &vk_text)]
+    }
+
+    /// With a named icon the held virtual keys move to the overlay (where trays support one)
+    fn overlay_icon_pixmap(&self) -> Vec<SniIcon> {
+        if self.icon_name().is_empty() {
+            return Vec::new();
+        }
+        let (_, vk_text) = self.display_strings();
+        if vk_text.is_empty() {
+            return Vec::new();
+        }
+        vec![Self::render_icon(&self.theme, "", "", &vk_text)]
+    }
+
+    fn tool_tip(&self) -> ToolTip {
+        ToolTip {
+            title: self.title_text(),
+            description: self.tooltip_text(),
+            ..ToolTip::default()
+        }
+    }
+
+    fn menu(&self) -> Vec<MenuItem<Self>> {
+        vec![
+            MenuItem::Checkmark(CheckmarkItem {
+                label: "Pause".to_string(),
+                checked: self.state.paused,
+                activate: Box::new(|this| {
+                    this.request_pause();
+                }),
+                ..CheckmarkItem::default()
+            }),
+            MenuItem::Checkmark(CheckmarkItem {
+                label: "Show app layer only".to_string(),
+                checked: self.state.show_focus_only,
+                activate: Box::new(|this| {
+                    this.toggle_focus_only();
+                }),
+                ..CheckmarkItem::default()
+            }),
+            self.layer_menu(),
+            self.pin_menu(),
+            MenuItem::Separator,
+            MenuItem::Standard(StandardItem {
+                label: "Restart".to_string(),
+                activate: Box::new(|this| {
+                    this.request_restart();
+                }),
+                ..StandardItem::default()
+            }),
+        ]
+    }
+
+    fn watcher_online(&self) {
+        log_line!("[SNI] StatusNotifierWatcher online");
+    }
+
+    fn watcher_offine(&self) -> bool {
+        elog_line!("[SNI] StatusNotifierWatcher offline");
+        true
     }
 }
 
-fn ensure_gnome_extension(status: &GnomeExtensionStatus, auto_install: bool) -> bool {
-    // If D-Bus probe confirmed extension is active, we're done
-    if status.active {
-        return false;
-    }
+/// Execute focus actions in order
+#[cfg(test)]
+async fn execute_focus_actions(kanata: &KanataClient, actions: FocusActions) {
+    execute_focus_actions_notifying(kanata, actions, None).await;
+}
+
+/// `execute_focus_actions`, signalling `written` once the first batch (everything before the
+/// first delay or plugin call) is written to kanata
+async fn execute_focus_actions_notifying(
+    kanata: &KanataClient,
+    actions: FocusActions,
+    written: Option<tokio::sync::oneshot::Sender<()>>,
+) {
+    let _in_flight = kanata.begin_actions();
+    kanata
+        .execute_actions(actions.actions, actions.silent, written)
+        .await;
+    kanata.execute_targeted_actions(actions.targeted).await;
+}
+
+fn extract_focus_layer(actions: &FocusActions) -> Option<String> {
+    actions.actions.iter().fold(None, |last, action| {
+        if let FocusAction::ChangeLayer(layer) = action {
+            Some(layer.clone())
+        } else {
+            last
+        }
+    })
+}
+
+/// Status side of one focus event, taken under the handler lock together with the actions and
+/// published once kanata has them
+struct FocusStatusUpdate {
+    event: FocusEvent,
+    virtual_keys: Vec<String>,
+    focus_layer: Option<String>,
+    matched_rules: Vec<RuleRef>,
+}
+
+fn match_focus(
+    handler: &Arc<Mutex<FocusHandler>>,
+    win: &WindowInfo,
+    default_layer: &str,
+) -> (Option<FocusActions>, FocusStatusUpdate) {
+    let mut handler = handler.lock().unwrap();
+    let actions = handler.handle(win, default_layer);
+    let matched_rules = handler.last_matched_rule_refs();
+    let update = FocusStatusUpdate {
+        event: FocusEvent {
+            class: win.class.clone(),
+            title: win.title.clone(),
+            is_native_terminal: win.is_native_terminal,
+            window_id: win.window_id.clone(),
+            matched_rules: matched_rules.iter().map(|rule| rule.index).collect(),
+            silent: actions.as_ref().is_some_and(|actions| actions.silent),
+            quiet_notify: actions.as_ref().is_some_and(|actions| actions.quiet_notify),
+        },
+        virtual_keys: handler.current_virtual_keys(),
+        focus_layer: actions.as_ref().and_then(extract_focus_layer),
+        matched_rules,
+    };
+    (actions, update)
+}
+
+async fn update_status_for_focus(
+    status_broadcaster: &StatusBroadcaster,
+    kanata: &KanataClient,
+    update: FocusStatusUpdate,
+) {
+    status_broadcaster.publish_focus(update.event);
+
+    // Filter out invalid VKs before updating indicator
+    let known_vks = kanata.known_virtual_keys().await;
+    let valid_virtual_keys =
+        KanataClient::filter_valid_virtual_keys(&known_vks, update.virtual_keys);
+    status_broadcaster.update_focus_match(valid_virtual_keys, update.matched_rules);
+    if let Some(layer) = update.focus_layer
+        && let Some(resolved_layer) = kanata.resolve_layer_name(&layer, false).await
+    {
+        status_broadcaster.update_focus_layer(resolved_layer);
+    }
+}
+
+/// Match `win`, send the actions to kanata, then update the status. The kanata write comes
+/// first so status subscribers and layer name lookups don't delay the switch; the status goes
+/// out once that first batch is written, without waiting for delay steps or plugin calls after
+/// it. With the `generation` of a focus event, nothing is applied if a newer event arrived
+/// while the matcher commands ran. Returns whether the window changed anything
+async fn apply_focus(
+    handler: &Arc<Mutex<FocusHandler>>,
+    status_broadcaster: &StatusBroadcaster,
+    win: &WindowInfo,
+    kanata: &KanataClient,
+    default_layer: &str,
+    generation: Option<u64>,
+) -> bool {
+    let commands = handler.lock().unwrap().commands_to_run(win);
+    run_matcher_commands(commands).await;
+    if generation.is_some_and(|generation| !handler.lock().unwrap().is_latest_focus_event(generation))
+    {
+        return false;
+    }
+    let (actions, update) = match_focus(handler, win, default_layer);
+    let Some(actions) = actions else {
+        update_status_for_focus(status_broadcaster, kanata, update).await;
+        return false;
+    };
+    let (written_tx, written_rx) = tokio::sync::oneshot::channel();
+    let publish_status = async {
+        // Also published if the actions end without signalling
+        let _ = written_rx.await;
+        update_status_for_focus(status_broadcaster, kanata, update).await;
+    };
+    tokio::join!(
+        execute_focus_actions_notifying(kanata, actions, Some(written_tx)),
+        publish_status
+    );
+    true
+}
+
+/// Apply a focus event unless paused, pinned, ignored or repeated. With a dwell/grace delay or
+/// matcher commands to run first it's applied in the background and this returns false
+async fn handle_focus_event(
+    handler: &Arc<Mutex<FocusHandler>>,
+    status_broadcaster: &StatusBroadcaster,
+    pause_broadcaster: &PauseBroadcaster,
+    win: &WindowInfo,
+    kanata: &KanataClient,
+    default_layer: &str,
+) -> bool {
+    if pause_broadcaster.is_paused() || kanata.pinned_layer().await.is_some() {
+        return false;
+    }
+    let (commands, delay, generation) = {
+        let mut handler = handler.lock().unwrap();
+        if handler.is_ignored_focus(win) || handler.is_repeated_focus(win) {
+            return false;
+        }
+        let commands = handler.commands_to_run(win);
+        if commands.is_empty() {
+            let (delay, generation) = handler.begin_focus_event(win, default_layer);
+            (commands, Some(delay), generation)
+        } else {
+            // Matching (also for the debounce time) needs the results, so it waits for them
+            (commands, None, handler.next_focus_generation())
+        }
+    };
+    if delay.is_some_and(|delay| delay.is_zero()) {
+        return apply_focus(handler, status_broadcaster, win, kanata, default_layer, Some(generation))
+            .await;
+    }
+
+    // Dwell/grace and matcher commands: apply in the background once focus has stayed put for
+    // the delay, so the backend keeps reading events (which is what supersedes this one)
+    let started = Instant::now();
+    let handler = handler.clone();
+    let status_broadcaster = status_broadcaster.clone();
+    let pause_broadcaster = pause_broadcaster.clone();
+    let win = win.clone();
+    let kanata = kanata.clone();
+    let default_layer = default_layer.to_string();
+    tokio::spawn(async move {
+        let delay = match delay {
+            Some(delay) => delay,
+            None => {
+                run_matcher_commands(commands).await;
+                let mut handler = handler.lock().unwrap();
+                if !handler.is_latest_focus_event(generation) {
+                    return;
+                }
+                handler.focus_delay(&win, &default_layer)
+            }
+        };
+        tokio::time::sleep(delay.saturating_sub(started.elapsed())).await;
+        if !handler.lock().unwrap().is_latest_focus_event(generation) || pause_broadcaster.is_paused() {
+            return;
+        }
+        if kanata.pinned_layer().await.is_some() {
+            return;
+        }
+        apply_focus(
+            &handler,
+            &status_broadcaster,
+            &win,
+            &kanata,
+            &default_layer,
+            Some(generation),
+        )
+        .await;
+    });
+    false
+}
+
+fn native_terminal_window() -> WindowInfo {
+    WindowInfo {
+        class: String::new(),
+        title: String::new(),
+        is_native_terminal: true,
+        session_inactive: false,
+        session_locked: false,
+        size: None,
+        initial: None,
+        x11_class: None,
+        desktop_id: None,
+        workspace: None,
+        instance: None,
+        role: None,
+        window_id: None,
+    }
+}
+
+fn session_inactive_window() -> WindowInfo {
+    WindowInfo {
+        session_inactive: true,
+        ..WindowInfo::default()
+    }
+}
+
+fn session_locked_window() -> WindowInfo {
+    WindowInfo {
+        session_inactive: true,
+        session_locked: true,
+        ..WindowInfo::default()
+    }
+}
+
+/// The pseudo-window a non-active session state is handled as; None while the session is active
+fn session_state_window(state: SessionState) -> Option<WindowInfo> {
+    match state {
+        SessionState::Active => None,
+        SessionState::NativeTerminal => Some(native_terminal_window()),
+        SessionState::Inactive => Some(session_inactive_window()),
+        SessionState::Locked => Some(session_locked_window()),
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct RawFdWatcher {
+    fd: RawFd,
+}
+
+impl RawFdWatcher {
+    fn new(fd: RawFd) -> Self {
+        Self { fd }
+    }
+}
+
+impl AsRawFd for RawFdWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+fn query_wayland_active_window() -> Result<WindowInfo, Box<dyn std::error::Error + Send + Sync>> {
+    #[cfg(test)]
+    {
+        WAYLAND_QUERY_COUNTER.fetch_add(1, Ordering::SeqCst);
+    }
+    let connection = connect_wayland()?;
+    let (globals, mut queue) = registry_queue_init::<WaylandState>(&connection)?;
+    let mut state = WaylandState::default();
+
+    if globals
+        .bind::<ZwlrForeignToplevelManagerV1, _, _>(&queue.handle(), 1..=3, ())
+        .is_err()
+        && globals
+            .bind::<ZcosmicToplevelInfoV1, _, _>(&queue.handle(), 1..=1, ())
+            .is_err()
+    {
+        return Err(
+            "No supported toplevel protocol (wlr-foreign-toplevel or cosmic-toplevel-info)".into(),
+        );
+    }
+
+    for _ in 0..5 {
+        queue.roundtrip(&mut state)?;
+        if state.active_window.is_some() {
+            break;
+        }
+    }
+    Ok(state.get_active_window())
+}
+
+/// All toplevels the compositor announces, for `--snapshot`
+fn query_wayland_windows() -> Result<Vec<WindowInfo>, Box<dyn std::error::Error + Send + Sync>> {
+    let connection = connect_wayland()?;
+    let (globals, mut queue) = registry_queue_init::<WaylandState>(&connection)?;
+    let mut state = WaylandState::default();
+
+    if globals
+        .bind::<ZwlrForeignToplevelManagerV1, _, _>(&queue.handle(), 1..=3, ())
+        .is_err()
+        && globals
+            .bind::<ZcosmicToplevelInfoV1, _, _>(&queue.handle(), 1..=1, ())
+            .is_err()
+    {
+        return Err(
+            "No supported toplevel protocol (wlr-foreign-toplevel or cosmic-toplevel-info)".into(),
+        );
+    }
+
+    // The first roundtrip announces the toplevels, the second delivers their app_id/title
+    queue.roundtrip(&mut state)?;
+    queue.roundtrip(&mut state)?;
+    Ok(state.list_windows())
+}
+
+#[cfg(test)]
+fn wayland_query_count() -> usize {
+    WAYLAND_QUERY_COUNTER.load(Ordering::SeqCst)
+}
+
+fn query_x11_active_window() -> Result<WindowInfo, Box<dyn std::error::Error + Send + Sync>> {
+    let state = X11State::new()?;
+    Ok(state.get_active_window())
+}
+
+fn query_x11_windows() -> Result<Vec<WindowInfo>, Box<dyn std::error::Error + Send + Sync>> {
+    let state = X11State::new()?;
+    Ok(state.list_windows())
+}
+
+static KDE_QUERY_COUNTER: AtomicU64 = AtomicU64::new(0);
+#[cfg(test)]
+static WAYLAND_QUERY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+struct KdeFocusQueryService {
+    sender: TokioMutex<Option<oneshot::Sender<WindowInfo>>>,
+}
+
+#[zbus::interface(name = "com.github.kanata.Switcher.KdeQuery")]
+impl KdeFocusQueryService {
+    #[allow(non_snake_case)]
+    async fn Focus(
+        &self,
+        window_class: &str,
+        window_title: &str,
+        workspace: &str,
+        instance: &str,
+        role: &str,
+        window_id: &str,
+    ) {
+        let win = WindowInfo {
+            class: window_class.to_string(),
+            title: window_title.to_string(),
+            is_native_terminal: false,
+            session_inactive: false,
+            session_locked: false,
+            size: None,
+            initial: None,
+            x11_class: None,
+            desktop_id: None,
+            workspace: (!workspace.is_empty()).then(|| workspace.to_string()),
+            instance: (!instance.is_empty()).then(|| instance.to_string()),
+            role: (!role.is_empty()).then(|| role.to_string()),
+            window_id: (!window_id.is_empty()).then(|| window_id.to_string()),
+        };
+        let mut sender = self.sender.lock().await;
+        if let Some(tx) = sender.take() {
+            let _ = tx.send(win);
+        }
+    }
+}
+
+/// Collects the windows a `--snapshot` KWin script reports, one `Window` call each, until `Done`
+#[derive(Debug)]
+struct KdeWindowListService {
+    windows: TokioMutex<Vec<WindowInfo>>,
+    sender: TokioMutex<Option<oneshot::Sender<Vec<WindowInfo>>>>,
+}
+
+#[zbus::interface(name = "com.github.kanata.Switcher.KdeQuery")]
+impl KdeWindowListService {
+    #[allow(non_snake_case)]
+    async fn Window(&self, window_class: &str, window_title: &str) {
+        self.windows.lock().await.push(WindowInfo {
+            class: window_class.to_string(),
+            title: window_title.to_string(),
+            ..Default::default()
+        });
+    }
+
+    #[allow(non_snake_case)]
+    async fn Done(&self) {
+        let windows = std::mem::take(&mut *self.windows.lock().await);
+        if let Some(tx) = self.sender.lock().await.take() {
+            let _ = tx.send(windows);
+        }
+    }
+}
+
+fn kwin_script_object_path(
+    script_num: i32,
+    is_kde6: bool,
+) -> Result<OwnedObjectPath, Box<dyn std::error::Error + Send + Sync>> {
+    let path = if is_kde6 {
+        format!("/Scripting/Script{}", script_num)
+    } else {
+        format!("/{}", script_num)
+    };
+    let obj_path: OwnedObjectPath = path.as_str().try_into()?;
+    Ok(obj_path)
+}
+
+async fn load_kwin_script(
+    connection: &Connection,
+    script_path: &str,
+    is_kde6: bool,
+    cleanup_existing: bool,
+) -> Result<(OwnedObjectPath, &'static str), Box<dyn std::error::Error + Send + Sync>> {
+    if cleanup_existing {
+        for _ in 0..5 {
+            let result = connection
+                .call_method(
+                    Some("org.kde.KWin"),
+                    "/Scripting",
+                    Some("org.kde.kwin.Scripting"),
+                    "loadScript",
+                    &(&script_path,),
+                )
+                .await;
+
+            if result.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+
+        let _ = connection
+            .call_method(
+                Some("org.kde.KWin"),
+                "/Scripting",
+                Some("org.kde.kwin.Scripting"),
+                "unloadScript",
+                &(&script_path,),
+            )
+            .await;
+    }
+
+    let load_result = connection
+        .call_method(
+            Some("org.kde.KWin"),
+            "/Scripting",
+            Some("org.kde.kwin.Scripting"),
+            "loadScript",
+            &(&script_path,),
+        )
+        .await?;
+
+    let script_num: i32 = load_result.body().deserialize()?;
+    let obj_path = kwin_script_object_path(script_num, is_kde6)?;
+    Ok((obj_path, "org.kde.kwin.Script"))
+}
+
+/// KWin script expression for the current virtual desktop's name (KDE 6 made
+/// `currentDesktop` an object; KDE 5 has a desktop number)
+fn kde_current_workspace_expr(is_kde6: bool) -> &'static str {
+    if is_kde6 {
+        "(workspace.currentDesktop ? workspace.currentDesktop.name : \"\")"
+    } else {
+        "workspace.desktopName(workspace.currentDesktop)"
+    }
+}
+
+fn build_kde_query_script(is_kde6: bool, bus_name: &str, object_path: &str) -> String {
+    let active_window = if is_kde6 {
+        "activeWindow"
+    } else {
+        "activeClient"
+    };
+    format!(
+        r#"function reportFocus(client) {{
+  callDBus(
+    "{bus}",
+    "{path}",
+    "{iface}",
+    "{method}",
+    client ? (client.resourceClass || "") : "",
+    client ? (client.caption || "") : "",
+    {workspace} || "",
+    client ? (client.resourceName || "") : "",
+    client ? (client.windowRole || "") : "",
+    client ? String(client.internalId || "") : ""
+  );
+}}
+reportFocus(workspace.{active});
+"#,
+        bus = bus_name,
+        path = object_path,
+        iface = KDE_QUERY_INTERFACE,
+        method = KDE_QUERY_METHOD,
+        workspace = kde_current_workspace_expr(is_kde6),
+        active = active_window
+    )
+}
+
+fn build_kde_list_windows_script(is_kde6: bool, bus_name: &str, object_path: &str) -> String {
+    let window_list = if is_kde6 { "windowList" } else { "clientList" };
+    format!(
+        r#"var windows = workspace.{list}();
+for (var i = 0; i < windows.length; i++) {{
+  var client = windows[i];
+  if (!client.normalWindow || client.skipTaskbar) {{
+    continue;
+  }}
+  callDBus(
+    "{bus}",
+    "{path}",
+    "{iface}",
+    "{window}",
+    client.resourceClass || "",
+    client.caption || ""
+  );
+}}
+callDBus("{bus}", "{path}", "{iface}", "{done}");
+"#,
+        list = window_list,
+        bus = bus_name,
+        path = object_path,
+        iface = KDE_QUERY_INTERFACE,
+        window = KDE_QUERY_WINDOW_METHOD,
+        done = KDE_QUERY_DONE_METHOD
+    )
+}
+
+async fn query_kde_focus(
+    connection: &Connection,
+    is_kde6: bool,
+) -> Result<WindowInfo, Box<dyn std::error::Error + Send + Sync>> {
+    let (sender, receiver) = oneshot::channel();
+    let service = KdeFocusQueryService {
+        sender: TokioMutex::new(Some(sender)),
+    };
+    run_kde_query_script(connection, is_kde6, service, receiver, build_kde_query_script).await
+}
+
+/// Normal windows KWin lists, for `--snapshot`
+async fn query_kde_windows(
+    connection: &Connection,
+    is_kde6: bool,
+) -> Result<Vec<WindowInfo>, Box<dyn std::error::Error + Send + Sync>> {
+    let (sender, receiver) = oneshot::channel();
+    let service = KdeWindowListService {
+        windows: TokioMutex::new(Vec::new()),
+        sender: TokioMutex::new(Some(sender)),
+    };
+    run_kde_query_script(
+        connection,
+        is_kde6,
+        service,
+        receiver,
+        build_kde_list_windows_script,
+    )
+    .await
+}
+
+/// Serve `service` on a fresh query path, run a one-off KWin script built by `build_script`
+/// (is_kde6, bus name, object path) that calls back into it, and wait for its answer.
+async fn run_kde_query_script<T>(
+    connection: &Connection,
+    is_kde6: bool,
+    service: impl zbus::object_server::Interface,
+    receiver: oneshot::Receiver<T>,
+    build_script: fn(bool, &str, &str) -> String,
+) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+    let unique_name = connection
+        .unique_name()
+        .ok_or("KDE focus query requires a unique DBus name")?;
+    let query_id = KDE_QUERY_COUNTER.fetch_add(1, Ordering::SeqCst);
+    let query_path = format!("/com/github/kanata/Switcher/KdeQuery{}", query_id);
+    connection
+        .object_server()
+        .at(query_path.as_str(), service)
+        .await?;
+
+    let uid = unsafe { libc::getuid() };
+    // The pid keeps parallel instances (`--dbus-suffix`) from sharing query script files
+    let script_path = format!(
+        "/tmp/{}query-{}-{}-{}.js",
+        KWIN_SCRIPT_PREFIX,
+        uid,
+        std::process::id(),
+        query_id
+    );
+    let script = build_script(is_kde6, unique_name.as_str(), query_path.as_str());
+    fs::write(&script_path, script)?;
+
+    let (script_obj_path, script_interface) =
+        load_kwin_script(connection, &script_path, is_kde6, false).await?;
+
+    let _kwin_query_guard = KwinScriptGuard::new(
+        connection.clone(),
+        tokio::runtime::Handle::current(),
+        script_path.clone(),
+        script_obj_path.clone(),
+        script_interface,
+    );
+
+    connection
+        .call_method(
+            Some("org.kde.KWin"),
+            script_obj_path,
+            Some(script_interface),
+            "run",
+            &(),
+        )
+        .await?;
+
+    let answer = tokio::time::timeout(Duration::from_secs(5), receiver)
+        .await
+        .map_err(|_| "Timed out waiting for KDE focus callback")?
+        .map_err(|_| "KDE focus callback sender dropped")?;
+
+    Ok(answer)
+}
+
+async fn query_gnome_focus(
+    connection: &Connection,
+) -> Result<WindowInfo, Box<dyn std::error::Error + Send + Sync>> {
+    let id_reply = connection
+        .call_method(
+            Some(GNOME_SHELL_BUS_NAME),
+            GNOME_FOCUS_OBJECT_PATH,
+            Some(GNOME_FOCUS_INTERFACE),
+            GNOME_FOCUS_ID_METHOD,
+            &(),
+        )
+        .await;
+    if let Ok(reply) = id_reply {
+        let (class, title, desktop_id, workspace, window_id): (
+            String,
+            String,
+            String,
+            String,
+            String,
+        ) = reply.body().deserialize()?;
+        return Ok(gnome_identified_window_info(
+            class, title, desktop_id, workspace, window_id,
+        ));
+    }
+
+    let workspace_reply = connection
+        .call_method(
+            Some(GNOME_SHELL_BUS_NAME),
+            GNOME_FOCUS_OBJECT_PATH,
+            Some(GNOME_FOCUS_INTERFACE),
+            GNOME_FOCUS_WORKSPACE_METHOD,
+            &(),
+        )
+        .await;
+    if let Ok(reply) = workspace_reply {
+        let (class, title, desktop_id, workspace): (String, String, String, String) =
+            reply.body().deserialize()?;
+        return Ok(gnome_workspace_window_info(class, title, desktop_id, workspace));
+    }
+
+    let app_reply = connection
+        .call_method(
+            Some(GNOME_SHELL_BUS_NAME),
+            GNOME_FOCUS_OBJECT_PATH,
+            Some(GNOME_FOCUS_INTERFACE),
+            GNOME_FOCUS_APP_METHOD,
+            &(),
+        )
+        .await;
+    if let Ok(reply) = app_reply {
+        let (class, title, desktop_id): (String, String, String) = reply.body().deserialize()?;
+        return Ok(gnome_window_info(class, title, desktop_id));
+    }
+
+    // Older extensions only have GetFocus
+    let reply = connection
+        .call_method(
+            Some(GNOME_SHELL_BUS_NAME),
+            GNOME_FOCUS_OBJECT_PATH,
+            Some(GNOME_FOCUS_INTERFACE),
+            GNOME_FOCUS_METHOD,
+            &(),
+        )
+        .await?;
+    let (class, title): (String, String) = reply.body().deserialize()?;
+    Ok(gnome_window_info(class, title, String::new()))
+}
+
+/// Normal windows the GNOME extension lists, for `--snapshot`
+async fn query_gnome_windows(
+    connection: &Connection,
+) -> Result<Vec<WindowInfo>, Box<dyn std::error::Error + Send + Sync>> {
+    let reply = connection
+        .call_method(
+            Some(GNOME_SHELL_BUS_NAME),
+            GNOME_FOCUS_OBJECT_PATH,
+            Some(GNOME_FOCUS_INTERFACE),
+            GNOME_LIST_WINDOWS_METHOD,
+            &(),
+        )
+        .await
+        .map_err(|error| {
+            format!(
+                "cannot list windows via the GNOME extension (update it and log out and back in): {}",
+                error
+            )
+        })?;
+    let windows: Vec<(String, String, String)> = reply.body().deserialize()?;
+    Ok(windows
+        .into_iter()
+        .map(|(class, title, desktop_id)| gnome_window_info(class, title, desktop_id))
+        .collect())
+}
+
+/// Window reported by the GNOME extension; an empty desktop ID means the window has no app
+fn gnome_window_info(class: String, title: String, desktop_id: String) -> WindowInfo {
+    WindowInfo {
+        class,
+        title,
+        desktop_id: (!desktop_id.is_empty()).then_some(desktop_id),
+        ..Default::default()
+    }
+}
+
+/// `gnome_window_info` on the extension's active workspace; empty means unknown
+fn gnome_workspace_window_info(
+    class: String,
+    title: String,
+    desktop_id: String,
+    workspace: String,
+) -> WindowInfo {
+    WindowInfo {
+        workspace: (!workspace.is_empty()).then_some(workspace),
+        ..gnome_window_info(class, title, desktop_id)
+    }
+}
+
+/// `gnome_workspace_window_info` with Mutter's window ID; empty means unknown
+fn gnome_identified_window_info(
+    class: String,
+    title: String,
+    desktop_id: String,
+    workspace: String,
+    window_id: String,
+) -> WindowInfo {
+    WindowInfo {
+        window_id: (!window_id.is_empty()).then_some(window_id),
+        ..gnome_workspace_window_info(class, title, desktop_id, workspace)
+    }
+}
+
+async fn query_focus_for_env(
+    env: Environment,
+    connection: Option<&Connection>,
+    is_kde6: bool,
+) -> Result<WindowInfo, Box<dyn std::error::Error + Send + Sync>> {
+    match env {
+        Environment::Gnome => {
+            let conn = connection.ok_or("GNOME focus query requires session connection")?;
+            query_gnome_focus(conn).await
+        }
+        Environment::Kde => {
+            let conn = connection.ok_or("KDE focus query requires session connection")?;
+            query_kde_focus(conn, is_kde6).await
+        }
+        Environment::Wayland => tokio::task::block_in_place(query_wayland_active_window),
+        Environment::X11 => tokio::task::block_in_place(query_x11_active_window),
+        Environment::Unknown => Ok(WindowInfo::default()),
+    }
+}
+
+/// Open windows of the session, for `--snapshot`
+async fn list_windows_for_env(
+    env: Environment,
+    connection: Option<&Connection>,
+    is_kde6: bool,
+) -> Result<Vec<WindowInfo>, Box<dyn std::error::Error + Send + Sync>> {
+    match env {
+        Environment::Gnome => {
+            let conn = connection.ok_or("GNOME window list requires session connection")?;
+            query_gnome_windows(conn).await
+        }
+        Environment::Kde => {
+            let conn = connection.ok_or("KDE window list requires session connection")?;
+            query_kde_windows(conn, is_kde6).await
+        }
+        Environment::Wayland => tokio::task::block_in_place(query_wayland_windows),
+        Environment::X11 => tokio::task::block_in_place(query_x11_windows),
+        Environment::Unknown => {
+            Err("could not detect display environment (WAYLAND_DISPLAY or DISPLAY)".into())
+        }
+    }
+}
+
+async fn apply_focus_for_env(
+    env: Environment,
+    connection: Option<&Connection>,
+    is_kde6: bool,
+    handler: &Arc<Mutex<FocusHandler>>,
+    status_broadcaster: &StatusBroadcaster,
+    pause_broadcaster: &PauseBroadcaster,
+    kanata: &KanataClient,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // A VT switch or lock outranks whatever the compositor still reports as focused
+    let session_state = handler.lock().unwrap().session_state();
+    let win = match session_state_window(session_state) {
+        Some(win) => win,
+        None => query_focus_for_env(env, connection, is_kde6).await?,
+    };
+    let default_layer = kanata.default_layer().await.unwrap_or_default();
+    handle_focus_event(
+        handler,
+        status_broadcaster,
+        pause_broadcaster,
+        &win,
+        kanata,
+        &default_layer,
+    )
+    .await;
+    Ok(())
+}
+async fn apply_session_focus(
+    state: SessionState,
+    env: Environment,
+    connection: Option<&Connection>,
+    is_kde6: bool,
+    handler: &Arc<Mutex<FocusHandler>>,
+    status_broadcaster: &StatusBroadcaster,
+    pause_broadcaster: &PauseBroadcaster,
+    kanata: &KanataClient,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    {
+        let mut handler = handler.lock().unwrap();
+        if state == SessionState::Active {
+            handler.end_session_inactive();
+        }
+        handler.set_session_state(state);
+    }
+    apply_focus_for_env(
+        env,
+        connection,
+        is_kde6,
+        handler,
+        status_broadcaster,
+        pause_broadcaster,
+        kanata,
+    )
+    .await
+}
+
+/// The session chosen with `--session-id`/`--seat`
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum LogindSessionOverride {
+    SessionId(String),
+    Seat(String),
+}
+
+impl LogindSessionOverride {
+    fn from_args(args: &Args) -> Option<Self> {
+        args.session_id
+            .clone()
+            .map(Self::SessionId)
+            .or_else(|| args.seat.clone().map(Self::Seat))
+    }
+}
+
+/// Set once from the command line; every logind user (session monitor, idle watcher) resolves
+/// the same session, across restarts
+static LOGIND_SESSION_OVERRIDE: OnceLock<LogindSessionOverride> = OnceLock::new();
+
+/// Paths of `uid`'s sessions on `seat`, from logind's `ListSessions` (id, uid, user, seat, path)
+fn seat_sessions(
+    sessions: &[(String, u32, String, String, OwnedObjectPath)],
+    uid: u32,
+    seat: &str,
+) -> Vec<OwnedObjectPath> {
+    sessions
+        .iter()
+        .filter(|(_, session_uid, _, session_seat, _)| *session_uid == uid && session_seat == seat)
+        .map(|(_, _, _, _, path)| path.clone())
+        .collect()
+}
+
+/// The user's session on `seat`; with several (e.g. a graphical and a text login), the first
+/// graphical one
+async fn resolve_logind_seat_session_path(
+    manager: &zbus::Proxy<'_>,
+    connection: &Connection,
+    seat: &str,
+) -> Result<OwnedObjectPath, Box<dyn std::error::Error + Send + Sync>> {
+    let reply = manager.call_method("ListSessions", &()).await?;
+    let sessions: Vec<(String, u32, String, String, OwnedObjectPath)> =
+        reply.body().deserialize()?;
+    let uid = unsafe { libc::getuid() };
+    let candidates = seat_sessions(&sessions, uid, seat);
+    let mut chosen = None;
+    for path in &candidates {
+        let session_type =
+            get_logind_property(connection, path, LOGIND_SESSION_INTERFACE, "Type").await?;
+        if matches!(
+            String::try_from(session_type).as_deref(),
+            Ok("x11" | "wayland" | "mir")
+        ) {
+            chosen = Some(path.clone());
+            break;
+        }
+    }
+    let path = chosen
+        .or_else(|| candidates.into_iter().next())
+        .ok_or_else(|| format!("no session of uid {} on seat {}", uid, seat))?;
+    log_line!("[Logind] Using session path for --seat {}: {}", seat, path.as_str());
+    Ok(path)
+}
+
+async fn resolve_logind_session_path(
+    connection: &Connection,
+) -> Result<OwnedObjectPath, Box<dyn std::error::Error + Send + Sync>> {
+    let manager = zbus::Proxy::new(
+        connection,
+        LOGIND_BUS_NAME,
+        LOGIND_MANAGER_PATH,
+        LOGIND_MANAGER_INTERFACE,
+    )
+    .await?;
+
+    match LOGIND_SESSION_OVERRIDE.get() {
+        Some(LogindSessionOverride::SessionId(session_id)) => {
+            log_line!("[Logind] Using --session-id {}", session_id);
+            let reply = manager.call_method("GetSession", &(session_id)).await?;
+            let path = decode_logind_object_path_reply(&reply, "GetSession")?;
+            log_line!("[Logind] Using session path: {}", path.as_str());
+            return Ok(path);
+        }
+        Some(LogindSessionOverride::Seat(seat)) => {
+            return resolve_logind_seat_session_path(&manager, connection, seat).await;
+        }
+        None => {}
+    }
+
+    if let Ok(session_id) = env::var("XDG_SESSION_ID") {
+        log_line!("[Logind] Using XDG_SESSION_ID={}", session_id);
+        let reply = manager.call_method("GetSession", &(session_id)).await?;
+        let path = decode_logind_object_path_reply(&reply, "GetSession")?;
+        log_line!("[Logind] Using session path: {}", path.as_str());
+        return Ok(path);
+    }
+    log_line!("[Logind] XDG_SESSION_ID not set; resolving session via logind");
+
+    let pid = std::process::id();
+    match manager.call_method("GetSessionByPID", &(pid)).await {
+        Ok(reply) => {
+            let path = decode_logind_object_path_reply(&reply, "GetSessionByPID")?;
+            log_line!("[Logind] Using session path: {}", path.as_str());
+            Ok(path)
+        }
+        Err(error) => {
+            if is_logind_no_session_error(&error) {
+                return resolve_logind_display_session_path(&manager, connection, pid).await;
+            }
+            Err(error.into())
+        }
+    }
+}
+
+fn is_logind_no_session_error(error: &zbus::Error) -> bool {
+    match error {
+        zbus::Error::MethodError(name, _, _) => name.as_ref() == LOGIND_ERROR_NO_SESSION_FOR_PID,
+        _ => false,
+    }
+}
+
+fn is_logind_empty_object_path(path: &OwnedObjectPath) -> bool {
+    path.as_str() == LOGIND_EMPTY_OBJECT_PATH
+}
+
+fn parse_logind_object_path(
+    value: OwnedValue,
+    context: &str,
+) -> Result<OwnedObjectPath, Box<dyn std::error::Error + Send + Sync>> {
+    let debug_value = format!("{:?}", value);
+    if let Ok(path) = OwnedObjectPath::try_from(value.try_clone()?) {
+        return Ok(path);
+    }
+    if let Ok(structure) = Structure::try_from(value.try_clone()?) {
+        if let Some(path) = parse_logind_object_path_from_structure(&structure) {
+            return Ok(path);
+        }
+    }
+    if let Ok(text) = String::try_from(value) {
+        return OwnedObjectPath::try_from(text).map_err(|error| {
+            format!(
+                "logind {} returned invalid object path string: {}",
+                context, error
+            )
+            .into()
+        });
+    }
+    Err(format!(
+        "logind {} returned unexpected value: {}",
+        context, debug_value
+    )
+    .into())
+}
+
+fn parse_logind_object_path_from_structure(structure: &Structure<'_>) -> Option<OwnedObjectPath> {
+    let fields = structure.fields();
+    if fields.is_empty() {
+        return None;
+    }
+    fields
+        .iter()
+        .find_map(|field| logind_object_path_from_value(field))
+}
+
+fn decode_logind_object_path_reply(
+    reply: &zbus::Message,
+    context: &str,
+) -> Result<OwnedObjectPath, Box<dyn std::error::Error + Send + Sync>> {
+    let body = reply.body();
+    let signature = body.signature().to_string();
+    match signature.as_str() {
+        "o" => Ok(body.deserialize_unchecked::<OwnedObjectPath>()?),
+        "s" => {
+            let text = body.deserialize_unchecked::<String>()?;
+            OwnedObjectPath::try_from(text).map_err(|error| {
+                format!(
+                    "logind {} returned invalid object path string: {}",
+                    context, error
+                )
+                .into()
+            })
+        }
+        "v" => {
+            let value = body.deserialize::<OwnedValue>()?;
+            parse_logind_object_path(value, context)
+        }
+        _ => {
+            if signature.starts_with('(') {
+                let structure = body.deserialize::<Structure>()?;
+                return parse_logind_object_path_from_structure(&structure).ok_or_else(|| {
+                    format!(
+                        "logind {} returned unexpected structure: {}",
+                        context, signature
+                    )
+                    .into()
+                });
+            }
+            Err(format!(
+                "logind {} returned unexpected signature: {}",
+                context, signature
+            )
+            .into())
+        }
+    }
+}
+
+fn logind_object_path_from_value(value: &Value<'_>) -> Option<OwnedObjectPath> {
+    match value {
+        Value::ObjectPath(path) => Some(OwnedObjectPath::from(path.clone())),
+        Value::Str(text) => OwnedObjectPath::try_from(text.as_str()).ok(),
+        Value::Value(inner) => logind_object_path_from_value(inner),
+        _ => None,
+    }
+}
+
+async fn resolve_logind_display_session_path(
+    manager: &zbus::Proxy<'_>,
+    connection: &Connection,
+    pid: u32,
+) -> Result<OwnedObjectPath, Box<dyn std::error::Error + Send + Sync>> {
+    let user_reply = manager.call_method("GetUserByPID", &(pid)).await?;
+    let user_path = decode_logind_object_path_reply(&user_reply, "GetUserByPID")?;
+    let user_proxy = zbus::Proxy::new(
+        connection,
+        LOGIND_BUS_NAME,
+        user_path,
+        LOGIND_USER_INTERFACE,
+    )
+    .await?;
+    let display = parse_logind_object_path(
+        user_proxy.get_property::<OwnedValue>("Display").await?,
+        "User.Display",
+    )?;
+    if is_logind_empty_object_path(&display) {
+        return Err("logind user has no display session".into());
+    }
+    log_line!("[Logind] Using display session path: {}", display.as_str());
+    Ok(display)
+}
+
+/// What the display session's seat shows, as far as rules are concerned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SessionState {
+    Active,
+    /// A text console (or nothing) is in the foreground: on_native_terminal
+    NativeTerminal,
+    /// A greeter or another graphical session is in the foreground: on_session_inactive
+    Inactive,
+    /// The session is in the foreground but locked: on_locked, else on_session_inactive
+    Locked,
+}
+
+/// `foreground` is the (Type, Class) of the seat's active session while ours is inactive,
+/// None when no session is in the foreground (a VT without a login)
+fn classify_session_state(
+    active: bool,
+    locked: bool,
+    foreground: Option<(&str, &str)>,
+) -> SessionState {
+    if active {
+        return if locked {
+            SessionState::Locked
+        } else {
+            SessionState::Active
+        };
+    }
+    match foreground {
+        Some((_, "greeter")) | Some(("x11" | "wayland" | "mir", _)) => SessionState::Inactive,
+        _ => SessionState::NativeTerminal,
+    }
+}
+
+async fn get_logind_property(
+    connection: &Connection,
+    path: &OwnedObjectPath,
+    interface: &'static str,
+    name: &str,
+) -> Result<OwnedValue, Box<dyn std::error::Error + Send + Sync>> {
+    let properties = zbus::fdo::PropertiesProxy::builder(connection)
+        .destination(LOGIND_BUS_NAME)?
+        .path(path.clone())?
+        .build()
+        .await?;
+    let interface = zbus::names::InterfaceName::from_static_str_unchecked(interface);
+    Ok(properties.get(interface, name).await?)
+}
+
+/// (Type, Class) of the session in the foreground on our session's seat
+async fn query_foreground_session(
+    connection: &Connection,
+    session_path: &OwnedObjectPath,
+) -> Result<Option<(String, String)>, Box<dyn std::error::Error + Send + Sync>> {
+    let seat = parse_logind_object_path(
+        get_logind_property(connection, session_path, LOGIND_SESSION_INTERFACE, "Seat").await?,
+        "Session.Seat",
+    )?;
+    if is_logind_empty_object_path(&seat) {
+        return Ok(None);
+    }
+    let foreground = parse_logind_object_path(
+        get_logind_property(connection, &seat, LOGIND_SEAT_INTERFACE, "ActiveSession").await?,
+        "Seat.ActiveSession",
+    )?;
+    if is_logind_empty_object_path(&foreground) {
+        return Ok(None);
+    }
+    let session_type = String::try_from(
+        get_logind_property(connection, &foreground, LOGIND_SESSION_INTERFACE, "Type").await?,
+    )?;
+    let class = String::try_from(
+        get_logind_property(connection, &foreground, LOGIND_SESSION_INTERFACE, "Class").await?,
+    )?;
+    Ok(Some((session_type, class)))
+}
+
+/// The display session as seen by logind. Lock state and the foreground session are only
+/// looked at with `track_inactive` (an on_session_inactive or on_locked rule is configured);
+/// otherwise any inactive session counts as a native terminal.
+struct LogindSession {
+    connection: Connection,
+    path: OwnedObjectPath,
+    track_inactive: bool,
+    active: bool,
+    locked: bool,
+    /// The desktop's screen locker is active (SCREEN_LOCKERS), for desktops that don't set
+    /// `LockedHint`
+    screen_locked: bool,
+}
+
+impl LogindSession {
+    async fn state(&self) -> Result<SessionState, Box<dyn std::error::Error + Send + Sync>> {
+        if !self.track_inactive {
+            return Ok(if self.active {
+                SessionState::Active
+            } else {
+                SessionState::NativeTerminal
+            });
+        }
+        let foreground = if self.active {
+            None
+        } else {
+            query_foreground_session(&self.connection, &self.path).await?
+        };
+        Ok(classify_session_state(
+            self.active,
+            self.locked || self.screen_locked,
+            foreground
+                .as_ref()
+                .map(|(session_type, class)| (session_type.as_str(), class.as_str())),
+        ))
+    }
+}
+
+/// Subscribes to the display session's `Active` (and `LockedHint`) properties; returns their
+/// current values
+async fn subscribe_logind_session(
+    track_inactive: bool,
+) -> Result<
+    (LogindSession, zbus::fdo::PropertiesChangedStream),
+    Box<dyn std::error::Error + Send + Sync>,
+> {
+    let connection = Connection::system().await?;
+    let session_path = resolve_logind_session_path(&connection).await?;
+    let session_proxy = zbus::Proxy::new(
+        &connection,
+        LOGIND_BUS_NAME,
+        session_path.clone(),
+        LOGIND_SESSION_INTERFACE,
+    )
+    .await?;
+    let active: bool = session_proxy.get_property("Active").await?;
+    let locked: bool = if track_inactive {
+        session_proxy.get_property("LockedHint").await?
+    } else {
+        false
+    };
+
+    let properties_proxy = zbus::fdo::PropertiesProxy::builder(&connection)
+        .destination(LOGIND_BUS_NAME)?
+        .path(session_path.clone())?
+        .build()
+        .await?;
+    let signals = properties_proxy.receive_properties_changed().await?;
+    let session = LogindSession {
+        connection,
+        path: session_path,
+        track_inactive,
+        active,
+        locked,
+        screen_locked: false,
+    };
+    Ok((session, signals))
+}
+
+/// Whether `message` is a SCREEN_LOCKERS `ActiveChanged` signal, and its value
+fn screen_locker_active(message: &zbus::Message) -> Option<bool> {
+    let header = message.header();
+    let interface = header.interface()?;
+    if header.member()?.as_str() != "ActiveChanged"
+        || !SCREEN_LOCKERS.iter().any(|(name, _)| interface.as_str() == *name)
+    {
+        return None;
+    }
+    message.body().deserialize::<bool>().ok()
+}
+
+/// Subscribes to the screen lockers' `ActiveChanged` signals on the session bus; returns whether
+/// one is active now
+async fn subscribe_screen_locker(
+    connection: &Connection,
+) -> Result<(zbus::MessageStream, bool), Box<dyn std::error::Error + Send + Sync>> {
+    let rule = zbus::MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .member("ActiveChanged")?
+        .build();
+    let messages = zbus::MessageStream::for_match_rule(rule, connection, None).await?;
+    let mut active = false;
+    for (interface, path) in SCREEN_LOCKERS {
+        if let Ok(reply) = connection
+            .call_method(Some(interface), path, Some(interface), "GetActive", &())
+            .await
+            && let Ok(locker_active) = reply.body().deserialize::<bool>()
+        {
+            active |= locker_active;
+        }
+    }
+    Ok((messages, active))
+}
+
+/// The next screen locker change; never resolves without a screen locker subscription
+async fn next_screen_locker_change(screen_locker: &mut Option<zbus::MessageStream>) -> bool {
+    loop {
+        let Some(messages) = screen_locker.as_mut() else {
+            return std::future::pending().await;
+        };
+        match messages.next().await {
+            Some(Ok(message)) => {
+                if let Some(active) = screen_locker_active(&message) {
+                    return active;
+                }
+            }
+            Some(Err(_)) => {}
+            None => {
+                elog_line!("[Logind] Screen locker signal stream ended");
+                *screen_locker = None;
+            }
+        }
+    }
+}
+
+struct LogindSessionMonitor {
+    env: Environment,
+    session_connection: Option<Connection>,
+    is_kde6: bool,
+    handler: Arc<Mutex<FocusHandler>>,
+    status_broadcaster: StatusBroadcaster,
+    pause_broadcaster: PauseBroadcaster,
+    kanata: KanataClient,
+}
+
+impl LogindSessionMonitor {
+    async fn apply(
+        &self,
+        state: SessionState,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        apply_session_focus(
+            state,
+            self.env,
+            self.session_connection.as_ref(),
+            self.is_kde6,
+            &self.handler,
+            &self.status_broadcaster,
+            &self.pause_broadcaster,
+            &self.kanata,
+        )
+        .await
+    }
+
+    /// Reads a boolean session property from a PropertiesChanged signal, if it changed
+    fn changed_bool(
+        &self,
+        args: &zbus::fdo::PropertiesChangedArgs<'_>,
+        name: &str,
+    ) -> Option<bool> {
+        let value = args.changed_properties.get(name)?;
+        match value.downcast_ref::<bool>().ok() {
+            Some(value) => Some(value),
+            None => {
+                elog_line!("[Logind] Failed to parse {} property", name);
+                self.status_broadcaster
+                    .set_degraded("logind", format!("failed to parse {} property", name));
+                None
+            }
+        }
+    }
+
+    /// Handles `Active` (and `LockedHint` and screen locker) changes until the logind signal
+    /// stream ends
+    async fn watch(
+        &self,
+        signals: &mut zbus::fdo::PropertiesChangedStream,
+        screen_locker: &mut Option<zbus::MessageStream>,
+        session: &mut LogindSession,
+        last_state: &mut SessionState,
+    ) {
+        loop {
+            let signal = tokio::select! {
+                signal = signals.next() => signal,
+                screen_locked = next_screen_locker_change(screen_locker) => {
+                    if screen_locked != session.screen_locked {
+                        log_line!(
+                            "[Logind] Screen locker {}",
+                            if screen_locked { "active" } else { "inactive" }
+                        );
+                        session.screen_locked = screen_locked;
+                        self.apply_state_logged(session, last_state).await;
+                    }
+                    continue;
+                }
+            };
+            let Some(signal) = signal else {
+                break;
+            };
+            let args = match signal.args() {
+                Ok(args) => args,
+                Err(error) => {
+                    elog_line!(
+                        "[Logind] Failed to parse PropertiesChanged signal: {}",
+                        error
+                    );
+                    self.status_broadcaster.set_degraded(
+                        "logind",
+                        format!("failed to parse PropertiesChanged signal: {}", error),
+                    );
+                    continue;
+                }
+            };
+            let active = self.changed_bool(&args, "Active");
+            let locked = if session.track_inactive {
+                self.changed_bool(&args, "LockedHint")
+            } else {
+                None
+            };
+            if active.is_none() && locked.is_none() {
+                continue;
+            }
+            session.active = active.unwrap_or(session.active);
+            session.locked = locked.unwrap_or(session.locked);
+            self.apply_state_logged(session, last_state).await;
+        }
+    }
+
+    /// Applies the session's state if it changed since `last_state`
+    async fn apply_state_logged(&self, session: &LogindSession, last_state: &mut SessionState) {
+        let next_state = match session.state().await {
+            Ok(state) => state,
+            Err(error) => {
+                elog_line!("[Logind] Failed to query session state: {}", error);
+                self.status_broadcaster.set_degraded(
+                    "logind",
+                    format!("failed to query session state: {}", error),
+                );
+                return;
+            }
+        };
+        if next_state == *last_state {
+            return;
+        }
+        *last_state = next_state;
+        self.apply_logged(next_state).await;
+    }
+
+    async fn apply_logged(&self, state: SessionState) {
+        if let Err(error) = self.apply(state).await {
+            elog_line!("[Logind] Failed to apply session focus: {}", error);
+            self.status_broadcaster
+                .set_degraded("logind", format!("failed to apply session focus: {}", error));
+        } else {
+            self.status_broadcaster.clear_degraded("logind");
+        }
+    }
+
+    /// Watches the session forever; when the system bus drops the signal stream, resubscribes
+    /// with backoff and applies any change that happened meanwhile
+    async fn run(
+        self,
+        mut session: LogindSession,
+        mut state: SessionState,
+        mut signals: zbus::fdo::PropertiesChangedStream,
+        mut screen_locker: Option<zbus::MessageStream>,
+    ) {
+        let mut backoff = RestartBackoff::new(&SUPERVISOR_RESTART_DELAYS_MS);
+        loop {
+            let started = Instant::now();
+            self.watch(&mut signals, &mut screen_locker, &mut session, &mut state)
+                .await;
+
+            let mut delay = backoff.next_delay(started.elapsed());
+            elog_line!(
+                "[Logind] Session signal stream ended; resubscribing in {:?}",
+                delay
+            );
+            self.status_broadcaster
+                .set_degraded("logind", "session signal stream ended".to_string());
+            loop {
+                tokio::time::sleep(delay).await;
+                match subscribe_logind_session(session.track_inactive).await {
+                    Ok((next_session, next_signals)) => {
+                        session = LogindSession {
+                            screen_locked: session.screen_locked,
+                            ..next_session
+                        };
+                        signals = next_signals;
+                        break;
+                    }
+                    Err(error) => {
+                        delay = backoff.next_delay(Duration::ZERO);
+                        elog_line!(
+                            "[Logind] Failed to resubscribe: {}; retrying in {:?}",
+                            error, delay
+                        );
+                        self.status_broadcaster
+                            .set_degraded("logind", format!("failed to resubscribe: {}", error));
+                    }
+                }
+            }
+            log_line!("[Logind] Session monitor resubscribed");
+            self.status_broadcaster.clear_degraded("logind");
+            self.apply_state_logged(&session, &mut state).await;
+        }
+    }
+}
+
+/// Starts the supervised logind monitor; the returned guard stops it
+async fn start_logind_session_monitor(
+    env: Environment,
+    session_connection: Option<Connection>,
+    is_kde6: bool,
+    handler: Arc<Mutex<FocusHandler>>,
+    status_broadcaster: StatusBroadcaster,
+    pause_broadcaster: PauseBroadcaster,
+    kanata: KanataClient,
+) -> Result<AbortOnDrop, Box<dyn std::error::Error + Send + Sync>> {
+    let track_inactive = {
+        let handler = handler.lock().unwrap();
+        handler.has_session_inactive_rule() || handler.has_locked_rule()
+    };
+    let (mut session, signals) = subscribe_logind_session(track_inactive).await?;
+    let mut screen_locker = None;
+    if track_inactive && let Some(connection) = session_connection.as_ref() {
+        match subscribe_screen_locker(connection).await {
+            Ok((messages, active)) => {
+                screen_locker = Some(messages);
+                session.screen_locked = active;
+            }
+            Err(error) => {
+                elog_line!("[Logind] Failed to watch the screen locker: {}", error);
+            }
+        }
+    }
+    let state = session.state().await?;
+    let monitor = LogindSessionMonitor {
+        env,
+        session_connection,
+        is_kde6,
+        handler,
+        status_broadcaster,
+        pause_broadcaster,
+        kanata,
+    };
+    if state != SessionState::Active {
+        monitor.apply(state).await?;
+    }
+    Ok(AbortOnDrop(tokio::spawn(
+        monitor.run(session, state, signals, screen_locker),
+    )))
+}
+
+async fn start_logind_session_monitor_best_effort<F, Fut>(
+    env: Environment,
+    session_connection: Option<Connection>,
+    is_kde6: bool,
+    handler: Arc<Mutex<FocusHandler>>,
+    status_broadcaster: StatusBroadcaster,
+    pause_broadcaster: PauseBroadcaster,
+    kanata: KanataClient,
+    starter: F,
+) -> Option<AbortOnDrop>
+where
+    F: FnOnce(
+        Environment,
+        Option<Connection>,
+        bool,
+        Arc<Mutex<FocusHandler>>,
+        StatusBroadcaster,
+        PauseBroadcaster,
+        KanataClient,
+    ) -> Fut,
+    Fut: std::future::Future<Output = Result<AbortOnDrop, Box<dyn std::error::Error + Send + Sync>>>,
+{
+    match starter(
+        env,
+        session_connection,
+        is_kde6,
+        handler,
+        status_broadcaster,
+        pause_broadcaster,
+        kanata,
+    )
+    .await
+    {
+        Ok(monitor) => Some(monitor),
+        Err(error) => {
+            elog_line!(
+                "[Logind] Disabled native terminal monitoring (startup failed): {}",
+                error
+            );
+            None
+        }
+    }
+}
+
+// === Idle ===
+
+/// How often the X11 fallback samples the time since the last input
+const X11_IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Idle (true) and resumed (false) notifications from ext-idle-notify-v1, not yet handled
+#[derive(Default)]
+struct WaylandIdleState {
+    changes: VecDeque<bool>,
+}
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for WaylandIdleState {
+    fn event(
+        _: &mut Self,
+        _: &wl_registry::WlRegistry,
+        _: wl_registry::Event,
+        _: &GlobalListContents,
+        _: &WaylandConnection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WlSeat, ()> for WaylandIdleState {
+    fn event(
+        _: &mut Self,
+        _: &WlSeat,
+        _: <WlSeat as Proxy>::Event,
+        _: &(),
+        _: &WaylandConnection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ExtIdleNotifierV1, ()> for WaylandIdleState {
+    fn event(
+        _: &mut Self,
+        _: &ExtIdleNotifierV1,
+        _: <ExtIdleNotifierV1 as Proxy>::Event,
+        _: &(),
+        _: &WaylandConnection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ExtIdleNotificationV1, ()> for WaylandIdleState {
+    fn event(
+        state: &mut Self,
+        _: &ExtIdleNotificationV1,
+        event: ext_idle_notification_v1::Event,
+        _: &(),
+        _: &WaylandConnection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_idle_notification_v1::Event::Idled => state.changes.push_back(true),
+            ext_idle_notification_v1::Event::Resumed => state.changes.push_back(false),
+            _ => {}
+        }
+    }
+}
+
+struct WaylandIdleWatcher {
+    connection: WaylandConnection,
+    queue: wayland_client::EventQueue<WaylandIdleState>,
+    state: WaylandIdleState,
+    async_fd: AsyncFd<RawFdWatcher>,
+    _notification: ExtIdleNotificationV1,
+}
+
+impl WaylandIdleWatcher {
+    fn connect(timeout_ms: u32) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let connection = connect_wayland()?;
+        let (globals, mut queue) = registry_queue_init::<WaylandIdleState>(&connection)?;
+        let queue_handle = queue.handle();
+        let notifier: ExtIdleNotifierV1 = globals
+            .bind(&queue_handle, 1..=1, ())
+            .map_err(|_| "the compositor doesn't support ext-idle-notify-v1")?;
+        let seat: WlSeat = globals
+            .bind(&queue_handle, 1..=1, ())
+            .map_err(|_| "the compositor has no seat")?;
+        let notification = notifier.get_idle_notification(timeout_ms, &seat, &queue_handle, ());
+        let mut state = WaylandIdleState::default();
+        queue.roundtrip(&mut state)?;
+        let async_fd = AsyncFd::new(RawFdWatcher::new(connection.as_fd().as_raw_fd()))?;
+        Ok(Self {
+            connection,
+            queue,
+            state,
+            async_fd,
+            _notification: notification,
+        })
+    }
+
+    async fn next_change(&mut self) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        loop {
+            self.queue.dispatch_pending(&mut self.state)?;
+            if let Some(idle) = self.state.changes.pop_front() {
+                return Ok(idle);
+            }
+
+            self.connection.flush()?;
+            let guard = match self.queue.prepare_read() {
+                Some(guard) => guard,
+                None => continue,
+            };
+            let mut readiness = self.async_fd.readable().await?;
+            let read_result = guard.read();
+            readiness.clear_ready();
+            match read_result {
+                Ok(_) => {}
+                Err(WaylandError::Io(error)) if error.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+    }
+}
+
+/// New idle state once the time since the last input crosses the timeout, None while unchanged
+fn x11_idle_change(idle: bool, since_input_ms: u32, timeout_ms: u32) -> Option<bool> {
+    let now_idle = since_input_ms >= timeout_ms;
+    (now_idle != idle).then_some(now_idle)
+}
+
+/// Polls the MIT-SCREEN-SAVER extension, which reports the time since the last input
+struct X11IdleWatcher {
+    connection: RustConnection,
+    root: Window,
+    timeout_ms: u32,
+    idle: bool,
+}
+
+impl X11IdleWatcher {
+    fn connect(timeout_ms: u32) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let (connection, screen_num) = x11rb::connect(session_var("DISPLAY").as_deref())?;
+        let root = connection.setup().roots[screen_num].root;
+        connection.screensaver_query_info(root)?.reply()?;
+        Ok(Self {
+            connection,
+            root,
+            timeout_ms,
+            idle: false,
+        })
+    }
+
+    async fn next_change(&mut self) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        loop {
+            tokio::time::sleep(X11_IDLE_POLL_INTERVAL).await;
+            let info = self.connection.screensaver_query_info(self.root)?.reply()?;
+            if let Some(idle) = x11_idle_change(self.idle, info.ms_since_user_input, self.timeout_ms)
+            {
+                self.idle = idle;
+                return Ok(idle);
+            }
+        }
+    }
+}
+
+/// Follows the session's `IdleHint`, which the desktop sets after its own idle delay
+struct LogindIdleWatcher {
+    hint: bool,
+    reported: bool,
+    signals: zbus::fdo::PropertiesChangedStream,
+}
+
+impl LogindIdleWatcher {
+    async fn connect() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let connection = Connection::system().await?;
+        let session_path = resolve_logind_session_path(&connection).await?;
+        let session_proxy = zbus::Proxy::new(
+            &connection,
+            LOGIND_BUS_NAME,
+            session_path.clone(),
+            LOGIND_SESSION_INTERFACE,
+        )
+        .await?;
+        let hint: bool = session_proxy.get_property("IdleHint").await?;
+        let properties_proxy = zbus::fdo::PropertiesProxy::builder(&connection)
+            .destination(LOGIND_BUS_NAME)?
+            .path(session_path)?
+            .build()
+            .await?;
+        let signals = properties_proxy.receive_properties_changed().await?;
+        Ok(Self {
+            hint,
+            reported: false,
+            signals,
+        })
+    }
+
+    async fn next_change(&mut self) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        loop {
+            if self.hint != self.reported {
+                self.reported = self.hint;
+                return Ok(self.hint);
+            }
+            let signal = self
+                .signals
+                .next()
+                .await
+                .ok_or("logind session signals ended")?;
+            if let Ok(args) = signal.args()
+                && let Some(value) = args.changed_properties.get("IdleHint")
+                && let Ok(hint) = value.downcast_ref::<bool>()
+            {
+                self.hint = hint;
+            }
+        }
+    }
+}
+
+enum IdleWatcher {
+    Wayland(WaylandIdleWatcher),
+    X11(X11IdleWatcher),
+    Logind(LogindIdleWatcher),
+}
+
+impl IdleWatcher {
+    /// ext-idle-notify-v1 on Wayland, the screen saver extension on X11, logind's `IdleHint`
+    /// when neither is available
+    async fn connect(timeout_s: u64) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let timeout_ms = (timeout_s * 1000) as u32;
+        if session_var("WAYLAND_DISPLAY").is_some() {
+            match WaylandIdleWatcher::connect(timeout_ms) {
+                Ok(watcher) => {
+                    log_line!("[Idle] Using ext-idle-notify-v1");
+                    return Ok(Self::Wayland(watcher));
+                }
+                Err(error) => log_line!("[Idle] Wayland idle notifications unavailable: {}", error),
+            }
+        } else if session_var("DISPLAY").is_some() {
+            match X11IdleWatcher::connect(timeout_ms) {
+                Ok(watcher) => {
+                    log_line!("[Idle] Using the X11 screen saver extension");
+                    return Ok(Self::X11(watcher));
+                }
+                Err(error) => log_line!("[Idle] X11 idle time unavailable: {}", error),
+            }
+        }
+        let watcher = LogindIdleWatcher::connect().await?;
+        log_line!("[Idle] Using logind IdleHint (the desktop's idle delay applies)");
+        Ok(Self::Logind(watcher))
+    }
+
+    /// Waits for the user to go idle (true) or become active again (false)
+    async fn next_change(&mut self) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        match self {
+            Self::Wayland(watcher) => watcher.next_change().await,
+            Self::X11(watcher) => watcher.next_change().await,
+            Self::Logind(watcher) => watcher.next_change().await,
+        }
+    }
+}
+
+/// Switches kanata to the `on_idle` layer while the user is idle and back afterwards
+struct IdleSwitcher {
+    layer: String,
+    kanata: KanataClient,
+    status_broadcaster: StatusBroadcaster,
+    pause_broadcaster: PauseBroadcaster,
+    /// Kanata's layer and the status from before going idle; None while active
+    before_idle: Option<(Option<String>, StatusSnapshot)>,
+}
+
+impl IdleSwitcher {
+    fn new(
+        layer: String,
+        kanata: KanataClient,
+        status_broadcaster: StatusBroadcaster,
+        pause_broadcaster: PauseBroadcaster,
+    ) -> Self {
+        Self {
+            layer,
+            kanata,
+            status_broadcaster,
+            pause_broadcaster,
+            before_idle: None,
+        }
+    }
+
+    /// Going back only happens while kanata is still on the idle layer; a layer switched to in
+    /// between (focus change, pause) is kept
+    async fn apply(&mut self, idle: bool) {
+        if idle {
+            if self.before_idle.is_some() {
+                return;
+            }
+            if self.pause_broadcaster.is_paused() {
+                log_line!("[Idle] Idle (paused, staying on the current layer)");
+                return;
+            }
+            if self.kanata.pinned_layer().await.is_some() {
+                log_line!("[Idle] Idle (layer pinned, staying on it)");
+                return;
+            }
+            log_line!("[Idle] Idle; switching to \"{}\"", self.layer);
+            let previous = self.kanata.current_layer().await;
+            self.before_idle = Some((previous, self.status_broadcaster.snapshot()));
+            self.kanata.change_layer(&self.layer).await;
+            self.status_broadcaster
+                .update_layer(self.layer.clone(), LayerSource::External);
+            return;
+        }
+
+        let Some((previous, snapshot)) = self.before_idle.take() else {
+            return;
+        };
+        if self.pause_broadcaster.is_paused()
+            || self.kanata.current_layer().await.as_deref() != Some(self.layer.as_str())
+        {
+            log_line!("[Idle] Active again (layer changed meanwhile, keeping it)");
+            return;
+        }
+        let Some(previous) = previous else {
+            log_line!("[Idle] Active again");
+            return;
+        };
+        log_line!("[Idle] Active again; switching back to \"{}\"", previous);
+        self.kanata.change_layer(&previous).await;
+        self.status_broadcaster.update(|state| {
+            state.layer = snapshot.layer;
+            state.layer_source = snapshot.layer_source;
+            state.matched_rules = snapshot.matched_rules;
+        });
+    }
+
+    /// Follows the watcher until it fails; the user then counts as active
+    async fn run(mut self, mut watcher: IdleWatcher) {
+        let error = loop {
+            match watcher.next_change().await {
+                Ok(idle) => self.apply(idle).await,
+                Err(error) => break error,
+            }
+        };
+        elog_line!("[Idle] Idle monitor stopped: {}", error);
+        self.status_broadcaster
+            .set_degraded("idle", format!("idle monitor stopped: {}", error));
+        self.apply(false).await;
+    }
+}
+
+/// Starts the `on_idle` switcher; None (degraded) when idle time can't be followed
+async fn start_idle_monitor(
+    idle: &IdleConfig,
+    kanata: KanataClient,
+    status_broadcaster: StatusBroadcaster,
+    pause_broadcaster: PauseBroadcaster,
+) -> Option<AbortOnDrop> {
+    match IdleWatcher::connect(idle.idle_timeout_s).await {
+        Ok(watcher) => {
+            let switcher = IdleSwitcher::new(
+                idle.on_idle.clone(),
+                kanata,
+                status_broadcaster,
+                pause_broadcaster,
+            );
+            Some(AbortOnDrop(tokio::spawn(switcher.run(watcher))))
+        }
+        Err(error) => {
+            elog_line!("[Idle] Cannot detect idle time: {}", error);
+            status_broadcaster.set_degraded("idle", format!("cannot detect idle time: {}", error));
+            None
+        }
+    }
+}
+
+// === Screen sharing ===
+
+const PORTAL_BUS_NAME: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_SCREENCAST_INTERFACE: &str = "org.freedesktop.portal.ScreenCast";
+const PORTAL_REQUEST_INTERFACE: &str = "org.freedesktop.portal.Request";
+const PORTAL_SESSION_INTERFACE: &str = "org.freedesktop.portal.Session";
+
+/// Follows ScreenCast portal sessions from monitored bus traffic. The portal has no API to list
+/// other clients' sessions, so a session counts as sharing from the successful `Response` to its
+/// `Start` request until it is closed or its client leaves the bus.
+#[derive(Debug, Default)]
+struct ScreenShareTracker {
+    /// `Start` calls awaiting the portal's reply: (client, serial) -> session
+    starting: HashMap<(String, u32), String>,
+    /// `Start` requests awaiting the user's choice: request path -> (client, session)
+    requests: HashMap<String, (String, String)>,
+    /// Sessions streaming the screen -> client
+    active: HashMap<String, String>,
+}
+
+impl ScreenShareTracker {
+    fn is_sharing(&self) -> bool {
+        !self.active.is_empty()
+    }
+
+    /// Updates the tracked sessions from one monitored message; true when sharing started or stopped
+    fn handle(&mut self, message: &zbus::Message) -> bool {
+        let was_sharing = self.is_sharing();
+        let header = message.header();
+        let sender = header.sender().map(|name| name.to_string()).unwrap_or_default();
+        let path = header.path().map(|path| path.to_string()).unwrap_or_default();
+        let interface = header.interface().map(|name| name.as_str()).unwrap_or_default();
+        let member = header.member().map(|name| name.as_str()).unwrap_or_default();
+        let reply_to = || {
+            let destination = header.destination()?.to_string();
+            Some((destination, header.reply_serial()?.get()))
+        };
+
+        match (header.message_type(), interface, member) {
+            (zbus::message::Type::MethodCall, PORTAL_SCREENCAST_INTERFACE, "Start") => {
+                if let Ok((session, _, _)) = message
+                    .body()
+                    .deserialize::<(OwnedObjectPath, String, HashMap<String, OwnedValue>)>()
+                {
+                    let serial = header.primary().serial_num().get();
+                    self.starting.insert((sender, serial), session.to_string());
+                }
+            }
+            (zbus::message::Type::MethodReturn, _, _) => {
+                if let Some(key) = reply_to()
+                    && let Some(session) = self.starting.remove(&key)
+                    && let Ok(request) = message.body().deserialize::<OwnedObjectPath>()
+                {
+                    self.requests.insert(request.to_string(), (key.0, session));
+                }
+            }
+            (zbus::message::Type::Error, _, _) => {
+                if let Some(key) = reply_to() {
+                    self.starting.remove(&key);
+                }
+            }
+            (zbus::message::Type::Signal, PORTAL_REQUEST_INTERFACE, "Response") => {
+                if let Some((client, session)) = self.requests.remove(&path)
+                    && let Ok((0, _)) = message
+                        .body()
+                        .deserialize::<(u32, HashMap<String, OwnedValue>)>()
+                {
+                    self.active.insert(session, client);
+                }
+            }
+            (zbus::message::Type::MethodCall, PORTAL_SESSION_INTERFACE, "Close")
+            | (zbus::message::Type::Signal, PORTAL_SESSION_INTERFACE, "Closed") => {
+                self.active.remove(&path);
+            }
+            (zbus::message::Type::Signal, "org.freedesktop.DBus", "NameOwnerChanged") => {
+                if let Ok((name, _, new_owner)) =
+                    message.body().deserialize::<(String, String, String)>()
+                    && new_owner.is_empty()
+                {
+                    if name == PORTAL_BUS_NAME {
+                        *self = Self::default();
+                    } else {
+                        self.starting.retain(|(client, _), _| *client != name);
+                        self.requests.retain(|_, (client, _)| *client != name);
+                        self.active.retain(|_, client| *client != name);
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.is_sharing() != was_sharing
+    }
+}
+
+/// Match rules for the portal traffic `ScreenShareTracker` follows
+fn screen_share_match_rules() -> zbus::Result<Vec<zbus::MatchRule<'static>>> {
+    use zbus::message::Type;
+    Ok(vec![
+        zbus::MatchRule::builder()
+            .msg_type(Type::MethodCall)
+            .interface(PORTAL_SCREENCAST_INTERFACE)?
+            .member("Start")?
+            .build(),
+        zbus::MatchRule::builder()
+            .msg_type(Type::MethodReturn)
+            .sender(PORTAL_BUS_NAME)?
+            .build(),
+        zbus::MatchRule::builder()
+            .msg_type(Type::Error)
+            .sender(PORTAL_BUS_NAME)?
+            .build(),
+        zbus::MatchRule::builder()
+            .msg_type(Type::Signal)
+            .interface(PORTAL_REQUEST_INTERFACE)?
+            .member("Response")?
+            .build(),
+        zbus::MatchRule::builder()
+            .msg_type(Type::MethodCall)
+            .interface(PORTAL_SESSION_INTERFACE)?
+            .member("Close")?
+            .build(),
+        zbus::MatchRule::builder()
+            .msg_type(Type::Signal)
+            .interface(PORTAL_SESSION_INTERFACE)?
+            .member("Closed")?
+            .build(),
+        zbus::MatchRule::builder()
+            .msg_type(Type::Signal)
+            .sender("org.freedesktop.DBus")?
+            .interface("org.freedesktop.DBus")?
+            .member("NameOwnerChanged")?
+            .build(),
+    ])
+}
+
+/// Turns a fresh session bus connection into a monitor of the ScreenCast portal traffic
+async fn subscribe_screen_share(
+    connection: Connection,
+) -> Result<zbus::MessageStream, Box<dyn std::error::Error + Send + Sync>> {
+    let stream = zbus::MessageStream::from(&connection);
+    zbus::fdo::MonitoringProxy::new(&connection)
+        .await?
+        .become_monitor(&screen_share_match_rules()?, 0)
+        .await?;
+    Ok(stream)
+}
+
+struct ScreenShareMonitor {
+    env: Environment,
+    session_connection: Option<Connection>,
+    is_kde6: bool,
+    handler: Arc<Mutex<FocusHandler>>,
+    status_broadcaster: StatusBroadcaster,
+    pause_broadcaster: PauseBroadcaster,
+    kanata: KanataClient,
+    sharing_layer: Option<String>,
+}
+
+impl ScreenShareMonitor {
+    /// Pauses (holding `--sharing-layer`) when sharing starts; resumes and re-applies the focused
+    /// window's rule when it stops, unless the user paused in between
+    async fn apply(&self, sharing: bool) {
+        if sharing {
+            if !self.pause_broadcaster.set_paused_automatically(true) {
+                log_line!("[ScreenShare] Screen sharing started (already paused)");
+                return;
+            }
+            log_line!("[ScreenShare] Screen sharing started; pausing");
+            let (virtual_keys, targeted) = {
+                let mut handler = self.handler.lock().unwrap();
+                let keys = handler.current_virtual_keys();
+                handler.reset();
+                (keys, handler.release_targets())
+            };
+            apply_pause(
+                &self.kanata,
+                &self.status_broadcaster,
+                &virtual_keys,
+                self.sharing_layer.as_deref(),
+            )
+            .await;
+            self.kanata.execute_targeted_actions(targeted).await;
+            return;
+        }
+
+        if !self.pause_broadcaster.set_paused_automatically(false) {
+            log_line!("[ScreenShare] Screen sharing stopped (paused by the user, staying paused)");
+            return;
+        }
+        log_line!("[ScreenShare] Screen sharing stopped; resuming");
+        self.kanata.unpause_connect().await;
+        if let Err(error) = apply_focus_for_env(
+            self.env,
+            self.session_connection.as_ref(),
+            self.is_kde6,
+            &self.handler,
+            &self.status_broadcaster,
+            &self.pause_broadcaster,
+            &self.kanata,
+        )
+        .await
+        {
+            elog_line!("[ScreenShare] Failed to refresh focus after sharing: {}", error);
+            self.status_broadcaster.set_degraded(
+                "screen-share",
+                format!("failed to refresh focus after sharing: {}", error),
+            );
+        } else {
+            self.status_broadcaster.clear_degraded("screen-share");
+        }
+    }
+
+    /// Monitors the portal forever; when the monitor connection drops, sharing is treated as
+    /// stopped (its sessions can no longer be followed) and the monitor is re-established
+    async fn run(self, mut messages: zbus::MessageStream) {
+        let mut backoff = RestartBackoff::new(&SUPERVISOR_RESTART_DELAYS_MS);
+        loop {
+            let started = Instant::now();
+            let mut tracker = ScreenShareTracker::default();
+            while let Some(message) = messages.next().await {
+                if let Ok(message) = message
+                    && tracker.handle(&message)
+                {
+                    self.apply(tracker.is_sharing()).await;
+                }
+            }
+            if tracker.is_sharing() {
+                self.apply(false).await;
+            }
+
+            let mut delay = backoff.next_delay(started.elapsed());
+            elog_line!("[ScreenShare] Portal monitor ended; reconnecting in {:?}", delay);
+            self.status_broadcaster
+                .set_degraded("screen-share", "portal monitor ended".to_string());
+            messages = loop {
+                tokio::time::sleep(delay).await;
+                let subscribed = match Connection::session().await {
+                    Ok(connection) => subscribe_screen_share(connection).await,
+                    Err(error) => Err(error.into()),
+                };
+                match subscribed {
+                    Ok(messages) => break messages,
+                    Err(error) => {
+                        delay = backoff.next_delay(Duration::ZERO);
+                        elog_line!(
+                            "[ScreenShare] Failed to monitor the portal: {}; retrying in {:?}",
+                            error, delay
+                        );
+                        self.status_broadcaster.set_degraded(
+                            "screen-share",
+                            format!("failed to monitor the portal: {}", error),
+                        );
+                    }
+                }
+            };
+            log_line!("[ScreenShare] Portal monitor reconnected");
+            self.status_broadcaster.clear_degraded("screen-share");
+        }
+    }
+}
+
+/// Starts `--pause-while-sharing` on `monitor_connection`, which becomes unusable for anything
+/// else; the returned guard stops it
+async fn start_screen_share_monitor(
+    monitor_connection: Connection,
+    monitor: ScreenShareMonitor,
+) -> Result<AbortOnDrop, Box<dyn std::error::Error + Send + Sync>> {
+    let messages = subscribe_screen_share(monitor_connection).await?;
+    Ok(AbortOnDrop(tokio::spawn(monitor.run(messages))))
+}
+
+fn pause_daemon(
+    pause_broadcaster: &PauseBroadcaster,
+    handler: &Arc<Mutex<FocusHandler>>,
+    status_broadcaster: &StatusBroadcaster,
+    kanata: &KanataClient,
+    runtime_handle: &tokio::runtime::Handle,
+    request_label: &str,
+) {
+    if !pause_broadcaster.set_paused(true) {
+        log_line!("[Pause] Pause requested {} (already paused)", request_label);
+        return;
+    }
+    log_line!("[Pause] Pausing daemon");
+    let (virtual_keys, targeted) = {
+        let mut handler = handler.lock().unwrap();
+        let keys = handler.current_virtual_keys();
+        handler.reset();
+        (keys, handler.release_targets())
+    };
+    let status_broadcaster = status_broadcaster.clone();
+    let kanata = kanata.clone();
+    runtime_handle.block_on(async move {
+        apply_pause(&kanata, &status_broadcaster, &virtual_keys, None).await;
+        kanata.execute_targeted_actions(targeted).await;
+    });
+}
+
+/// Pause side effects: release held virtual keys, switch to `layer` (default: the default layer),
+/// disconnect from kanata.
+async fn apply_pause(
+    kanata: &KanataClient,
+    status_broadcaster: &StatusBroadcaster,
+    virtual_keys: &[String],
+    layer: Option<&str>,
+) {
+    let layer = match layer {
+        Some(layer) => layer.to_string(),
+        None => kanata.default_layer().await.unwrap_or_default(),
+    };
+    // Kanata stays on a pinned layer
+    let layer = kanata.pinned_layer().await.unwrap_or(layer);
+
+    for vk in virtual_keys.iter().rev() {
+        kanata.act_on_fake_key(vk, "Release").await;
+    }
+
+    if !layer.is_empty() {
+        let _ = kanata.change_layer(&layer).await;
+    }
+
+    status_broadcaster.set_paused_status(layer);
+    kanata.pause_disconnect().await;
+}
+
+fn unpause_daemon(
+    env: Environment,
+    connection: Option<Connection>,
+    is_kde6: bool,
+    pause_broadcaster: &PauseBroadcaster,
+    handler: &Arc<Mutex<FocusHandler>>,
+    status_broadcaster: &StatusBroadcaster,
+    kanata: &KanataClient,
+    runtime_handle: &tokio::runtime::Handle,
+    request_label: &str,
+) {
+    if !pause_broadcaster.set_paused(false) {
+        log_line!(
+            "[Pause] Unpause requested {} (already running)",
+            request_label
+        );
+        return;
+    }
+    log_line!("[Pause] Resuming daemon");
+    let pause_broadcaster = pause_broadcaster.clone();
+    let handler = handler.clone();
+    let status_broadcaster = status_broadcaster.clone();
+    let kanata = kanata.clone();
+    runtime_handle.block_on(async move {
+        kanata.unpause_connect().await;
+        if let Err(error) = apply_focus_for_env(
+            env,
+            connection.as_ref(),
+            is_kde6,
+            &handler,
+            &status_broadcaster,
+            &pause_broadcaster,
+            &kanata,
+        )
+        .await
+        {
+            elog_line!("[Pause] Failed to refresh focus after unpause: {}", error);
+            status_broadcaster.set_degraded(
+                "unpause",
+                format!("failed to refresh focus after unpause: {}", error),
+            );
+        } else {
+            status_broadcaster.clear_degraded("unpause");
+        }
+    });
+}
+
+/// Switches kanata to `layer` until the next focus change; false for layers kanata doesn't
+/// know, while another layer is pinned or when kanata is not connected
+fn set_layer_daemon(
+    kanata: &KanataClient,
+    status_broadcaster: &StatusBroadcaster,
+    runtime_handle: &tokio::runtime::Handle,
+    layer: &str,
+    request_label: &str,
+) -> bool {
+    let kanata = kanata.clone();
+    let requested = layer.to_string();
+    let applied = runtime_handle.block_on(async move {
+        if kanata.resolve_layer_name(&requested, false).await.as_deref() != Some(&requested) {
+            return false;
+        }
+        kanata.change_layer(&requested).await;
+        kanata.current_layer().await.as_deref() == Some(&requested)
+    });
+    if applied {
+        log_line!("[Layer] Layer set to \"{}\" {}", layer, request_label);
+        status_broadcaster.update_layer(layer.to_string(), LayerSource::External);
+    } else {
+        log_line!("[Layer] Switch to \"{}\" {} not applied", layer, request_label);
+    }
+    applied
+}
+
+/// Holds kanata on `layer` and stops focus-based switching until `unpin_daemon`. Unlike pause,
+/// the connection stays up. Held virtual keys are released; false for unknown layers
+fn pin_daemon(
+    handler: &Arc<Mutex<FocusHandler>>,
+    kanata: &KanataClient,
+    runtime_handle: &tokio::runtime::Handle,
+    layer: &str,
+    request_label: &str,
+) -> bool {
+    let pinning = kanata.clone();
+    let requested = layer.to_string();
+    if !runtime_handle.block_on(async move { pinning.pin_layer(&requested).await }) {
+        log_line!("[Pin] Pin to unknown layer \"{}\" requested {}", layer, request_label);
+        return false;
+    }
+    log_line!("[Pin] Pinned layer \"{}\" {}", layer, request_label);
+    let (virtual_keys, targeted) = {
+        let mut handler = handler.lock().unwrap();
+        let keys = handler.current_virtual_keys();
+        handler.reset();
+        (keys, handler.release_targets())
+    };
+    let kanata = kanata.clone();
+    runtime_handle.block_on(async move {
+        for vk in virtual_keys.iter().rev() {
+            kanata.act_on_fake_key(vk, "Release").await;
+        }
+        kanata.execute_targeted_actions(targeted).await;
+    });
+    true
+}
+
+/// Ends `pin_daemon` and re-applies the focused window (unless paused); false when no layer
+/// was pinned
+#[allow(clippy::too_many_arguments)]
+fn unpin_daemon(
+    env: Environment,
+    connection: Option<Connection>,
+    is_kde6: bool,
+    pause_broadcaster: &PauseBroadcaster,
+    handler: &Arc<Mutex<FocusHandler>>,
+    status_broadcaster: &StatusBroadcaster,
+    kanata: &KanataClient,
+    runtime_handle: &tokio::runtime::Handle,
+    request_label: &str,
+) -> bool {
+    let pause_broadcaster = pause_broadcaster.clone();
+    let handler = handler.clone();
+    let status_broadcaster = status_broadcaster.clone();
+    let kanata = kanata.clone();
+    runtime_handle.block_on(async move {
+        let Some(layer) = kanata.unpin_layer().await else {
+            log_line!("[Pin] Unpin requested {} (no layer pinned)", request_label);
+            return false;
+        };
+        log_line!("[Pin] Unpinned layer \"{}\" {}", layer, request_label);
+        if pause_broadcaster.is_paused() {
+            return true;
+        }
+        if let Err(error) = apply_focus_for_env(
+            env,
+            connection.as_ref(),
+            is_kde6,
+            &handler,
+            &status_broadcaster,
+            &pause_broadcaster,
+            &kanata,
+        )
+        .await
+        {
+            elog_line!("[Pin] Failed to refresh focus after unpin: {}", error);
+            status_broadcaster.set_degraded(
+                "unpin",
+                format!("failed to refresh focus after unpin: {}", error),
+            );
+        } else {
+            status_broadcaster.clear_degraded("unpin");
+        }
+        true
+    })
+}
+
+// === Call detection ===
+
+/// Whether a capture stream records from a microphone: `pactl list short source-outputs` rows
+/// (index, source index, ...) against `pactl list short sources` rows (index, name, ...).
+/// Streams from monitor sources (recordings of what is played) don't count
+fn mic_in_use(source_outputs: &str, sources: &str) -> bool {
+    let mics: BTreeSet<&str> = sources
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let index = fields.next()?;
+            let name = fields.next()?;
+            (!name.ends_with(".monitor")).then_some(index)
+        })
+        .collect();
+    source_outputs
+        .lines()
+        .any(|line| line.split('\t').nth(1).is_some_and(|source| mics.contains(source)))
+}
+
+async fn run_pactl(args: &[&str]) -> Result<String, String> {
+    let output = tokio::process::Command::new("pactl")
+        .args(args)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|error| format!("cannot run pactl: {}", error))?;
+    if !output.status.success() {
+        return Err(format!(
+            "pactl {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Asks PulseAudio (or pipewire-pulse) whether a microphone is recording
+async fn query_mic_in_use() -> Result<bool, String> {
+    let source_outputs = run_pactl(&["list", "short", "source-outputs"]).await?;
+    if source_outputs.trim().is_empty() {
+        return Ok(false);
+    }
+    let sources = run_pactl(&["list", "short", "sources"]).await?;
+    Ok(mic_in_use(&source_outputs, &sources))
+}
+
+/// Polls the microphone for `call_detection.mic` and re-applies the focused window's rules when
+/// it starts or stops recording, so `in_call` rules follow a call joined or left in place
+struct CallMonitor {
+    env: Environment,
+    session_connection: Option<Connection>,
+    is_kde6: bool,
+    handler: Arc<Mutex<FocusHandler>>,
+    status_broadcaster: StatusBroadcaster,
+    pause_broadcaster: PauseBroadcaster,
+    kanata: KanataClient,
+    poll_interval: Duration,
+}
+
+impl CallMonitor {
+    async fn apply(&self, in_use: bool) {
+        log_line!(
+            "[Call] Microphone {}",
+            if in_use { "in use" } else { "no longer in use" }
+        );
+        self.handler.lock().unwrap().set_mic_in_use(in_use);
+        if self.pause_broadcaster.is_paused() {
+            return;
+        }
+        if let Err(error) = apply_focus_for_env(
+            self.env,
+            self.session_connection.as_ref(),
+            self.is_kde6,
+            &self.handler,
+            &self.status_broadcaster,
+            &self.pause_broadcaster,
+            &self.kanata,
+        )
+        .await
+        {
+            elog_line!("[Call] Failed to refresh focus: {}", error);
+        }
+    }
+
+    /// Polls forever; while pactl fails the microphone counts as not in use
+    async fn run(self) {
+        let mut poll = tokio::time::interval(self.poll_interval);
+        let mut in_use = false;
+        let mut failing = false;
+        loop {
+            poll.tick().await;
+            let now = match query_mic_in_use().await {
+                Ok(now) => {
+                    if failing {
+                        failing = false;
+                        self.status_broadcaster.clear_degraded("call-detection");
+                    }
+                    now
+                }
+                Err(error) => {
+                    if !failing {
+                        failing = true;
+                        elog_line!("[Call] Cannot check the microphone: {}", error);
+                        self.status_broadcaster.set_degraded(
+                            "call-detection",
+                            format!("cannot check the microphone: {}", error),
+                        );
+                    }
+                    false
+                }
+            };
+            if now != in_use {
+                in_use = now;
+                self.apply(in_use).await;
+            }
+        }
+    }
+}
+
+// === uinput fallback ===
+
+/// kanata key names `uinput_fallback.remap` and `fallback_remap` accept, with their evdev codes
+const FALLBACK_KEY_CODES: &[(&str, u16)] = &[
+    ("esc", 1),
+    ("1", 2),
+    ("2", 3),
+    ("3", 4),
+    ("4", 5),
+    ("5", 6),
+    ("6", 7),
+    ("7", 8),
+    ("8", 9),
+    ("9", 10),
+    ("0", 11),
+    ("min", 12),
+    ("eql", 13),
+    ("bspc", 14),
+    ("tab", 15),
+    ("q", 16),
+    ("w", 17),
+    ("e", 18),
+    ("r", 19),
+    ("t", 20),
+    ("y", 21),
+    ("u", 22),
+    ("i", 23),
+    ("o", 24),
+    ("p", 25),
+    ("lbrc", 26),
+    ("rbrc", 27),
+    ("ret", 28),
+    ("lctl", 29),
+    ("a", 30),
+    ("s", 31),
+    ("d", 32),
+    ("f", 33),
+    ("g", 34),
+    ("h", 35),
+    ("j", 36),
+    ("k", 37),
+    ("l", 38),
+    ("scln", 39),
+    ("apos", 40),
+    ("grv", 41),
+    ("lsft", 42),
+    ("bsls", 43),
+    ("z", 44),
+    ("x", 45),
+    ("c", 46),
+    ("v", 47),
+    ("b", 48),
+    ("n", 49),
+    ("m", 50),
+    ("comm", 51),
+    (".", 52),
+    ("/", 53),
+    ("rsft", 54),
+    ("lalt", 56),
+    ("spc", 57),
+    ("caps", 58),
+    ("f1", 59),
+    ("f2", 60),
+    ("f3", 61),
+    ("f4", 62),
+    ("f5", 63),
+    ("f6", 64),
+    ("f7", 65),
+    ("f8", 66),
+    ("f9", 67),
+    ("f10", 68),
+    ("f11", 87),
+    ("f12", 88),
+    ("rctl", 97),
+    ("prnt", 99),
+    ("ralt", 100),
+    ("home", 102),
+    ("up", 103),
+    ("pgup", 104),
+    ("left", 105),
+    ("rght", 106),
+    ("end", 107),
+    ("down", 108),
+    ("pgdn", 109),
+    ("ins", 110),
+    ("del", 111),
+    ("pause", 119),
+    ("lmet", 125),
+    ("rmet", 126),
+    ("menu", 127),
+];
+
+fn fallback_key_code(name: &str) -> Option<u16> {
+    FALLBACK_KEY_CODES
+        .iter()
+        .find(|(key, _)| *key == name)
+        .map(|(_, code)| *code)
+}
+
+fn validate_fallback_remap(remap: &BTreeMap<String, String>) -> Result<(), String> {
+    for name in remap.iter().flat_map(|(from, to)| [from, to]) {
+        if fallback_key_code(name).is_none() {
+            return Err(format!("unknown key \"{}\"", name));
+        }
+    }
+    Ok(())
+}
+
+/// Key codes to swap: the entry's `remap`, then the matched rules' `fallback_remap` on top
+#[cfg(feature = "uinput-fallback")]
+fn fallback_key_map(
+    remap: &BTreeMap<String, String>,
+    rule_remap: &BTreeMap<String, String>,
+) -> HashMap<u16, u16> {
+    remap
+        .iter()
+        .chain(rule_remap)
+        .filter_map(|(from, to)| Some((fallback_key_code(from)?, fallback_key_code(to)?)))
+        .collect()
+}
+
+/// The keyboards are only taken over once kanata has been unreachable for the grace period,
+/// while the daemon isn't paused (which disconnects on purpose) and no kanata process is up:
+/// a starting kanata needs to grab them itself
+#[cfg(feature = "uinput-fallback")]
+fn uinput_fallback_wanted(
+    paused: bool,
+    unreachable_for: Option<Duration>,
+    grace: Duration,
+    kanata_running: impl FnOnce() -> bool,
+) -> bool {
+    !paused && unreachable_for.is_some_and(|elapsed| elapsed >= grace) && !kanata_running()
+}
+
+/// How often the fallback checks the kanata connection and the focused window's remap
+#[cfg(feature = "uinput-fallback")]
+const UINPUT_FALLBACK_POLL: Duration = Duration::from_millis(200);
+
+/// Applies `uinput_fallback` remaps through `uinput_fallback::UinputRemapper` while kanata is
+/// unreachable, following the focused window's rules
+#[cfg(feature = "uinput-fallback")]
+struct UinputFallbackMonitor {
+    config: UinputFallbackConfig,
+    handler: Arc<Mutex<FocusHandler>>,
+    status_broadcaster: StatusBroadcaster,
+    pause_broadcaster: PauseBroadcaster,
+    kanata: KanataClient,
+}
+
+#[cfg(feature = "uinput-fallback")]
+impl UinputFallbackMonitor {
+    fn key_map(&self) -> HashMap<u16, u16> {
+        let rule_remap = self.handler.lock().unwrap().fallback_remap();
+        fallback_key_map(&self.config.remap, &rule_remap)
+    }
+
+    async fn run(self) {
+        let grace = Duration::from_millis(self.config.grace_ms);
+        let mut poll = tokio::time::interval(UINPUT_FALLBACK_POLL);
+        let mut unreachable_since: Option<Instant> = None;
+        let mut remapper: Option<uinput_fallback::UinputRemapper> = None;
+        let mut failing = false;
+        loop {
+            poll.tick().await;
+            if self.kanata.is_connected().await {
+                unreachable_since = None;
+            } else {
+                unreachable_since.get_or_insert_with(Instant::now);
+            }
+            let wanted = uinput_fallback_wanted(
+                self.pause_broadcaster.is_paused(),
+                unreachable_since.map(|since| since.elapsed()),
+                grace,
+                uinput_fallback::kanata_running,
+            );
+            if !wanted {
+                if remapper.take().is_some() {
+                    log_line!("[Fallback] Released the keyboards");
+                }
+                continue;
+            }
+            if let Some(remapper) = &remapper {
+                remapper.set_remap(self.key_map());
+                continue;
+            }
+            match uinput_fallback::UinputRemapper::start(&self.config.devices, self.key_map()) {
+                Ok(Some(started)) => {
+                    let devices: Vec<String> = started
+                        .devices()
+                        .iter()
+                        .map(|path| path.display().to_string())
+                        .collect();
+                    log_line!(
+                        "[Fallback] kanata unreachable: remapping keys on {}",
+                        devices.join(", ")
+                    );
+                    if failing {
+                        failing = false;
+                        self.status_broadcaster.clear_degraded("uinput-fallback");
+                    }
+                    remapper = Some(started);
+                }
+                // A key is held; try again on the next tick
+                Ok(None) => {}
+                Err(error) => {
+                    if !failing {
+                        failing = true;
+                        elog_line!("[Fallback] Cannot remap keys: {}", error);
+                        self.status_broadcaster.set_degraded(
+                            "uinput-fallback",
+                            format!("cannot remap keys: {}", error),
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+// === Kanata TLS ===
+
+/// SHA-256 fingerprint of a DER certificate
+type CertFingerprint = [u8; 32];
+
+type KanataReader = Box<dyn tokio::io::AsyncRead + Send + Unpin>;
+type KanataWriter = Box<dyn tokio::io::AsyncWrite + Send + Unpin>;
+
+/// Parse a SHA-256 fingerprint as printed by `openssl x509 -fingerprint -sha256`
+/// (hex, colons optional, case-insensitive, optional `sha256:` / `SHA256 Fingerprint=` prefix).
+fn parse_cert_fingerprint(value: &str) -> Result<CertFingerprint, String> {
+    let trimmed = value.trim();
+    let hex = trimmed
+        .rsplit_once('=')
+        .map(|(_, hex)| hex)
+        .or_else(|| trimmed.strip_prefix("sha256:"))
+        .unwrap_or(trimmed);
+    let digits: Vec<u8> = hex
+        .chars()
+        .filter(|c| *c != ':')
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<_>>()
+        .ok_or_else(|| format!("invalid certificate fingerprint \"{}\": not hex", value))?;
+    if digits.len() != 64 {
+        return Err(format!(
+            "invalid certificate fingerprint \"{}\": expected 32 bytes of SHA-256",
+            value
+        ));
+    }
+    let mut fingerprint = [0u8; 32];
+    for (byte, pair) in fingerprint.iter_mut().zip(digits.chunks(2)) {
+        *byte = pair[0] << 4 | pair[1];
+    }
+    Ok(fingerprint)
+}
+
+fn format_cert_fingerprint(fingerprint: &CertFingerprint) -> String {
+    fingerprint
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn cert_fingerprint(cert: &[u8]) -> CertFingerprint {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert);
+    let mut fingerprint = [0u8; 32];
+    fingerprint.copy_from_slice(digest.as_ref());
+    fingerprint
+}
+
+/// Verifies the kanata endpoint certificate: against the CA bundle when given,
+/// and/or against pinned fingerprints of the server (leaf) certificate.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    ca_verifier: Option<Arc<tokio_rustls::rustls::client::WebPkiServerVerifier>>,
+    pins: Vec<CertFingerprint>,
+    provider: Arc<tokio_rustls::rustls::crypto::CryptoProvider>,
+}
+
+impl tokio_rustls::rustls::client::danger::ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &tokio_rustls::rustls::pki_types::CertificateDer<'_>,
+        intermediates: &[tokio_rustls::rustls::pki_types::CertificateDer<'_>],
+        server_name: &tokio_rustls::rustls::pki_types::ServerName<'_>,
+        ocsp_response: &[u8],
+        now: tokio_rustls::rustls::pki_types::UnixTime,
+    ) -> Result<tokio_rustls::rustls::client::danger::ServerCertVerified, tokio_rustls::rustls::Error>
+    {
+        if let Some(ca_verifier) = &self.ca_verifier {
+            ca_verifier.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+        }
+        if !self.pins.is_empty() {
+            let fingerprint = cert_fingerprint(end_entity.as_ref());
+            if !self.pins.contains(&fingerprint) {
+                return Err(tokio_rustls::rustls::Error::General(format!(
+                    "certificate fingerprint {} is not pinned",
+                    format_cert_fingerprint(&fingerprint)
+                )));
+            }
+        }
+        Ok(tokio_rustls::rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &tokio_rustls::rustls::pki_types::CertificateDer<'_>,
+        dss: &tokio_rustls::rustls::DigitallySignedStruct,
+    ) -> Result<
+        tokio_rustls::rustls::client::danger::HandshakeSignatureValid,
+        tokio_rustls::rustls::Error,
+    > {
+        tokio_rustls::rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &tokio_rustls::rustls::pki_types::CertificateDer<'_>,
+        dss: &tokio_rustls::rustls::DigitallySignedStruct,
+    ) -> Result<
+        tokio_rustls::rustls::client::danger::HandshakeSignatureValid,
+        tokio_rustls::rustls::Error,
+    > {
+        tokio_rustls::rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<tokio_rustls::rustls::SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// TLS settings for the kanata connection (`--kanata-tls-ca` / `--kanata-tls-pin`).
+/// Kanata itself speaks plain TCP; TLS is terminated by a proxy next to it (e.g. stunnel).
+#[derive(Clone)]
+struct KanataTls {
+    connector: tokio_rustls::TlsConnector,
+    server_name: tokio_rustls::rustls::pki_types::ServerName<'static>,
+}
+
+impl KanataTls {
+    fn from_args(args: &Args) -> Result<Option<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        if args.kanata_tls_ca.is_none() && args.kanata_tls_pin.is_empty() {
+            if args.kanata_tls_server_name.is_some() {
+                return Err(
+                    "--kanata-tls-server-name requires --kanata-tls-ca or --kanata-tls-pin".into(),
+                );
+            }
+            return Ok(None);
+        }
+        let pins = args.kanata_tls_pin.clone();
+        let server_name = args
+            .kanata_tls_server_name
+            .clone()
+            .unwrap_or_else(|| args.host.clone());
+        Self::new(args.kanata_tls_ca.as_deref(), pins, &server_name).map(Some)
+    }
+
+    fn new(
+        ca_path: Option<&Path>,
+        pins: Vec<CertFingerprint>,
+        server_name: &str,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        use tokio_rustls::rustls::pki_types::pem::PemObject;
+
+        let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+        let ca_verifier = match ca_path {
+            Some(path) => {
+                let mut roots = tokio_rustls::rustls::RootCertStore::empty();
+                for cert in tokio_rustls::rustls::pki_types::CertificateDer::pem_file_iter(path)
+                    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+                {
+                    let cert =
+                        cert.map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+                    roots.add(cert)?;
+                }
+                if roots.is_empty() {
+                    return Err(format!("No certificates found in {}", path.display()).into());
+                }
+                Some(
+                    tokio_rustls::rustls::client::WebPkiServerVerifier::builder_with_provider(
+                        Arc::new(roots),
+                        provider.clone(),
+                    )
+                    .build()?,
+                )
+            }
+            None => None,
+        };
+        let verifier = PinnedCertVerifier {
+            ca_verifier,
+            pins,
+            provider: provider.clone(),
+        };
+        let config = tokio_rustls::rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        let server_name = tokio_rustls::rustls::pki_types::ServerName::try_from(
+            server_name.to_string(),
+        )
+        .map_err(|e| format!("Invalid TLS server name \"{}\": {}", server_name, e))?;
+        Ok(Self {
+            connector: tokio_rustls::TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    async fn connect(
+        &self,
+        stream: TokioTcpStream,
+    ) -> std::io::Result<(KanataReader, KanataWriter)> {
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await?;
+        let (reader, writer) = tokio::io::split(stream);
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+}
+
+// === Kanata Endpoint ===
+
+/// Where and how to reach kanata: -H/-p (+ TLS flags), or a named config endpoint (`--kanata`)
+struct KanataTarget {
+    host: String,
+    port: u16,
+    default_layer: Option<String>,
+    reconnect_delays_ms: Option<Vec<u64>>,
+    tls: Option<KanataTls>,
+}
+
+fn resolve_kanata_target(
+    args: &Args,
+    config: &Config,
+) -> Result<KanataTarget, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(name) = args.kanata.as_deref() else {
+        return Ok(KanataTarget {
+            host: args.host.clone(),
+            port: args.port,
+            default_layer: config.default_layer.clone(),
+            reconnect_delays_ms: None,
+            tls: KanataTls::from_args(args)?,
+        });
+    };
+    let Some(endpoint) = config.kanata_endpoints.get(name) else {
+        if config.kanata_endpoints.is_empty() {
+            return Err(format!(
+                "unknown kanata endpoint \"{}\": the config has no \"kanata\" entry",
+                name
+            )
+            .into());
+        }
+        let names: Vec<&str> = config.kanata_endpoints.keys().map(String::as_str).collect();
+        return Err(format!(
+            "unknown kanata endpoint \"{}\" (configured: {})",
+            name,
+            names.join(", ")
+        )
+        .into());
+    };
+    endpoint_target(endpoint, config)
+}
+
+/// Connection settings of a config endpoint; its `default_layer` falls back to the config's
+fn endpoint_target(
+    endpoint: &KanataEndpoint,
+    config: &Config,
+) -> Result<KanataTarget, Box<dyn std::error::Error + Send + Sync>> {
+    let tls = if endpoint.tls_ca.is_some() || !endpoint.tls_pin.is_empty() {
+        let server_name = endpoint
+            .tls_server_name
+            .clone()
+            .unwrap_or_else(|| endpoint.host.clone());
+        Some(KanataTls::new(
+            endpoint.tls_ca.as_deref(),
+            endpoint.tls_pin.clone(),
+            &server_name,
+        )?)
+    } else {
+        None
+    };
+    Ok(KanataTarget {
+        host: endpoint.host.clone(),
+        port: endpoint.port,
+        default_layer: endpoint
+            .default_layer
+            .clone()
+            .or_else(|| config.default_layer.clone()),
+        reconnect_delays_ms: endpoint.reconnect_delays_ms.clone(),
+        tls,
+    })
+}
+
+// === Kanata Sink ===
+
+/// Where kanata messages go (`--kanata-sink`)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum KanataSink {
+    /// The kanata TCP server (-H/-p or --kanata)
+    #[default]
+    Tcp,
+    /// An embedded stand-in for kanata that prints every message it receives
+    Log,
+    /// An embedded stand-in for kanata that drops every message
+    Null,
+}
+
+impl KanataSink {
+    fn as_arg(self) -> &'static str {
+        match self {
+            KanataSink::Tcp => "tcp",
+            KanataSink::Log => "log",
+            KanataSink::Null => "null",
+        }
+    }
+}
+
+/// Instance a `--dry-run` daemon runs as when no `--dbus-suffix` is given
+const DRY_RUN_DBUS_SUFFIX: &str = "dry_run";
+
+/// `--dry-run`: the `log` sink instead of kanata, and an instance of its own so the real
+/// daemon's DBus name, state file and KWin script are left alone
+fn apply_dry_run(args: &mut Args) {
+    if !args.dry_run {
+        return;
+    }
+    args.kanata_sink = KanataSink::Log;
+    if args.dbus_suffix.is_none() {
+        args.dbus_suffix = Some(DRY_RUN_DBUS_SUFFIX.to_string());
+    }
+}
+
+/// In-process stand-in for kanata's TCP server (`--kanata-sink log|null`). It answers the
+/// client's name and current-layer requests, and like kanata tells every connected client
+/// about layer changes, so a `ChangeLayer` sent by another client (e.g. `nc`) reaches the
+/// daemon as a layer change made in kanata
+struct EmbeddedKanata {
+    mode: KanataSink,
+    port: u16,
+    _server: AbortOnDrop,
+}
+
+/// What the embedded server's connections share
+struct EmbeddedKanataState {
+    label: String,
+    log: bool,
+    names: KanataConfigNames,
+    layer: Mutex<String>,
+    changes: broadcast::Sender<String>,
+}
+
+impl EmbeddedKanata {
+    /// Listens on a free localhost port; `names` are reported as kanata's layers and virtual
+    /// keys, `layer` (default: the first layer) is the current layer
+    async fn start(
+        mode: KanataSink,
+        label: &str,
+        names: KanataConfigNames,
+        layer: Option<String>,
+    ) -> std::io::Result<Self> {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let port = listener.local_addr()?.port();
+        let layer = layer
+            .or_else(|| names.layers.first().cloned())
+            .unwrap_or_else(|| "base".to_string());
+        let state = Arc::new(EmbeddedKanataState {
+            label: label.to_string(),
+            log: mode == KanataSink::Log,
+            names,
+            layer: Mutex::new(layer),
+            changes: broadcast::channel(64).0,
+        });
+        log_line!(
+            "[Sink] Embedded kanata for {} on 127.0.0.1:{} ({} sink); send {{\"ChangeLayer\":{{\"new\":\"LAYER\"}}}} there to simulate a layer change in kanata",
+            label,
+            port,
+            mode.as_arg()
+        );
+        let server = tokio::spawn(async move {
+            let mut connections = tokio::task::JoinSet::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                while connections.try_join_next().is_some() {}
+                connections.spawn(Self::serve(stream, state.clone()));
+            }
+        });
+        Ok(Self {
+            mode,
+            port,
+            _server: AbortOnDrop(server),
+        })
+    }
+
+    /// `target` pointed at this server
+    fn redirect(&self, target: &mut KanataTarget) {
+        target.host = "127.0.0.1".to_string();
+        target.port = self.port;
+        target.tls = None;
+    }
+
+    async fn serve(stream: TokioTcpStream, state: Arc<EmbeddedKanataState>) {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = TokioBufReader::new(reader).lines();
+        let mut changes = state.changes.subscribe();
+        let layer = state.layer.lock().unwrap().clone();
+        let mut reply = Some(KanataServerMessage::LayerChange { new: layer });
+        loop {
+            if let Some(message) = reply.take() {
+                let line = serde_json::to_string(&message).unwrap_or_default() + "\n";
+                if writer.write_all(line.as_bytes()).await.is_err() {
+                    return;
+                }
+            }
+            tokio::select! {
+                line = lines.next_line() => match line {
+                    Ok(Some(line)) => reply = state.handle(line.trim()),
+                    _ => return,
+                },
+                change = changes.recv() => match change {
+                    Ok(layer) => reply = Some(KanataServerMessage::LayerChange { new: layer }),
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+            }
+        }
+    }
+}
+
+impl EmbeddedKanataState {
+    /// Applies one client message; returns the reply, if it needs one
+    fn handle(&self, line: &str) -> Option<KanataServerMessage> {
+        if line.is_empty() {
+            return None;
+        }
+        if self.log {
+            log_line!("[Sink] {}: {}", self.label, line);
+        }
+        let message: serde_json::Value = serde_json::from_str(line).ok()?;
+        if message.get("RequestLayerNames").is_some() {
+            return Some(KanataServerMessage::LayerNames {
+                names: self.names.layers.clone(),
+            });
+        }
+        if message.get("RequestFakeKeyNames").is_some() {
+            return Some(KanataServerMessage::FakeKeyNames {
+                names: self.names.virtual_keys.clone(),
+            });
+        }
+        if message.get("RequestCurrentLayerName").is_some() {
+            return Some(KanataServerMessage::CurrentLayerName {
+                name: self.layer.lock().unwrap().clone(),
+            });
+        }
+        // Reloads are accepted (and logged) like any other command
+        if message.get("Hello").is_some() {
+            return Some(KanataServerMessage::HelloOk {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                protocol: 1,
+                capabilities: vec![KANATA_RELOAD_CAPABILITY.to_string()],
+            });
+        }
+        let new = message.get("ChangeLayer")?.get("new")?.as_str()?;
+        if !self.names.layers.is_empty() && !self.names.layers.iter().any(|layer| layer == new) {
+            return None;
+        }
+        *self.layer.lock().unwrap() = new.to_string();
+        let _ = self.changes.send(new.to_string());
+        None
+    }
+}
+
+// === Kanata Client ===
+
+/// Default for `--kanata-write-timeout`
+const DEFAULT_KANATA_WRITE_TIMEOUT_MS: u64 = 2000;
+/// Default for `--kanata-rate-limit`
+const DEFAULT_KANATA_RATE_LIMIT: u32 = 100;
+
+/// Counters of the `--kanata-rate-limit` cap since the daemon (re)started, for `GetRateLimitStats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct KanataRateLimitStats {
+    /// Messages sent within the cap, including deferred ones sent later
+    sent: u64,
+    /// Messages over the cap, deferred instead of sent right away
+    deferred: u64,
+    /// Deferred messages superseded by a later one and never sent
+    coalesced: u64,
+}
+
+/// A deferred message, sent once the rate limit refills
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DeferredKanataMessage {
+    ChangeLayer(String),
+    ActOnFakeKey { name: String, action: String },
+}
+
+/// Token bucket capping ChangeLayer/ActOnFakeKey messages at `per_second`, with bursts of up
+/// to one second's worth. Messages over the cap queue in order; while anything is deferred, new
+/// messages queue behind it. Only what a later message makes pointless is coalesced: an earlier
+/// layer change, and a Press or Release repeating the key's previous queued action. Taps,
+/// toggles and Press-then-Release all reach kanata, with their original spacing.
+#[derive(Debug)]
+struct KanataRateLimit {
+    per_second: u32,
+    tokens: f64,
+    refilled_at: tokio::time::Instant,
+    /// Deferred messages with the time they were sent, oldest first
+    deferred: VecDeque<(tokio::time::Instant, DeferredKanataMessage)>,
+    /// Send time and flush time of the last deferred message flushed, so the next one keeps its
+    /// distance to it (`delay` steps between actions)
+    last_flushed: Option<(tokio::time::Instant, tokio::time::Instant)>,
+    flush_scheduled: bool,
+    stats: KanataRateLimitStats,
+}
+
+impl KanataRateLimit {
+    fn new(per_second: u32, now: tokio::time::Instant) -> Self {
+        Self {
+            per_second,
+            tokens: f64::from(per_second),
+            refilled_at: now,
+            deferred: VecDeque::new(),
+            last_flushed: None,
+            flush_scheduled: false,
+            stats: KanataRateLimitStats::default(),
+        }
+    }
+
+    fn refill(&mut self, now: tokio::time::Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        let capacity = f64::from(self.per_second);
+        self.tokens = (self.tokens + elapsed * capacity).min(capacity);
+        self.refilled_at = now;
+    }
+
+    fn has_deferred(&self) -> bool {
+        !self.deferred.is_empty()
+    }
+
+    /// Take a token for a message sent right away; false means defer it
+    fn try_acquire(&mut self, now: tokio::time::Instant) -> bool {
+        self.refill(now);
+        if self.has_deferred() || self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        self.stats.sent += 1;
+        true
+    }
+
+    fn defer(&mut self, message: DeferredKanataMessage, now: tokio::time::Instant) {
+        self.stats.deferred += 1;
+        match &message {
+            DeferredKanataMessage::ChangeLayer(_) => self.cancel_deferred_layer(),
+            DeferredKanataMessage::ActOnFakeKey { name, action } => {
+                let previous = self.deferred.iter().rev().find_map(|(_, deferred)| match deferred {
+                    DeferredKanataMessage::ActOnFakeKey {
+                        name: key,
+                        action: previous,
+                    } if key == name => Some(previous),
+                    _ => None,
+                });
+                if matches!(action.as_str(), "Press" | "Release") && previous == Some(action) {
+                    self.stats.coalesced += 1;
+                    return;
+                }
+            }
+        }
+        self.deferred.push_back((now, message));
+    }
+
+    /// A later layer change (or a switch back to the current layer) supersedes the deferred one
+    fn cancel_deferred_layer(&mut self) {
+        let before = self.deferred.len();
+        self.deferred
+            .retain(|(_, message)| !matches!(message, DeferredKanataMessage::ChangeLayer(_)));
+        if self.deferred.len() != before {
+            self.stats.coalesced += 1;
+        }
+    }
+
+    fn clear_deferred(&mut self) {
+        self.deferred.clear();
+        self.last_flushed = None;
+    }
+
+    /// Earliest flush time of the next deferred message that keeps its distance to the last one
+    fn next_spacing(&self) -> Option<tokio::time::Instant> {
+        let (sent_at, _) = self.deferred.front()?;
+        let (previous_sent_at, previous_flushed_at) = self.last_flushed?;
+        Some(previous_flushed_at + sent_at.saturating_duration_since(previous_sent_at))
+    }
+
+    /// Time until the next message may be sent
+    fn next_token_in(&self, now: tokio::time::Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        let tokens = self.tokens + elapsed * f64::from(self.per_second);
+        let token_wait = if tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - tokens) / f64::from(self.per_second))
+        };
+        let spacing_wait = self
+            .next_spacing()
+            .map_or(Duration::ZERO, |at| at.saturating_duration_since(now));
+        token_wait.max(spacing_wait)
+    }
+
+    /// Oldest deferred message, if a token is available and it keeps its spacing
+    fn pop_ready(&mut self, now: tokio::time::Instant) -> Option<DeferredKanataMessage> {
+        self.refill(now);
+        if self.tokens < 1.0 || self.next_spacing().is_some_and(|at| at > now) {
+            return None;
+        }
+        let (sent_at, message) = self.deferred.pop_front()?;
+        self.last_flushed = (!self.deferred.is_empty()).then_some((sent_at, now));
+        self.tokens -= 1.0;
+        self.stats.sent += 1;
+        Some(message)
+    }
+}
+
+/// Write one JSON message line. The flush matters for TLS, which buffers records until flushed.
+/// A write that doesn't complete within `timeout` (kanata or the network stalled) fails with
+/// `TimedOut` instead of blocking the caller, who usually holds the client lock.
+async fn write_kanata_line(
+    writer: &mut KanataWriter,
+    line: &str,
+    timeout: Duration,
+) -> std::io::Result<()> {
+    let write = async {
+        writer.write_all(line.as_bytes()).await?;
+        writer.flush().await
+    };
+    tokio::time::timeout(timeout, write).await.map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("write stalled for {:?}", timeout),
+        )
+    })?
+}
+
+#[derive(Serialize)]
+struct ChangeLayerMsg {
+    #[serde(rename = "ChangeLayer")]
+    change_layer: ChangeLayerPayload,
+}
+
+#[derive(Serialize)]
+struct ChangeLayerPayload {
+    new: String,
+}
+
+#[derive(Serialize)]
+struct RequestLayerNamesMsg {
+    #[serde(rename = "RequestLayerNames")]
+    request_layer_names: RequestLayerNamesPayload,
+}
+
+#[derive(Serialize)]
+struct RequestLayerNamesPayload {}
+
+#[derive(Serialize)]
+struct ActOnFakeKeyMsg {
+    #[serde(rename = "ActOnFakeKey")]
+    act_on_fake_key: ActOnFakeKeyPayload,
+}
+
+#[derive(Serialize)]
+struct ActOnFakeKeyPayload {
+    name: String,
+    action: String,
+}
+
+#[derive(Serialize)]
+struct ReloadNumMsg {
+    #[serde(rename = "ReloadNum")]
+    reload_num: ReloadNumPayload,
+}
+
+#[derive(Serialize)]
+struct ReloadNumPayload {
+    index: usize,
+}
+
+#[derive(Serialize)]
+struct ReloadNextMsg {
+    #[serde(rename = "ReloadNext")]
+    reload_next: ReloadNextPayload,
+}
+
+#[derive(Serialize)]
+struct ReloadNextPayload {}
+
+#[derive(Serialize)]
+struct RequestFakeKeyNamesMsg {
+    #[serde(rename = "RequestFakeKeyNames")]
+    request_fake_key_names: RequestFakeKeyNamesPayload,
+}
+
+#[derive(Serialize)]
+struct RequestFakeKeyNamesPayload {}
+
+#[derive(Serialize)]
+struct RequestCurrentLayerNameMsg {
+    #[serde(rename = "RequestCurrentLayerName")]
+    request_current_layer_name: RequestCurrentLayerNamePayload,
+}
+
+#[derive(Serialize)]
+struct RequestCurrentLayerNamePayload {}
+
+#[derive(Serialize)]
+struct HelloMsg {
+    #[serde(rename = "Hello")]
+    hello: HelloPayload,
+}
+
+#[derive(Serialize)]
+struct HelloPayload {}
+
+/// Messages from kanata the client acts on
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+enum KanataServerMessage {
+    LayerChange { new: String },
+    LayerNames { names: Vec<String> },
+    FakeKeyNames { names: Vec<String> },
+    CurrentLayerName { name: String },
+    /// Reply to `Hello`: the kanata version and the optional commands it accepts
+    HelloOk {
+        version: String,
+        #[serde(default)]
+        protocol: u64,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+}
+
+/// Reply to a command, e.g. `{"status":"Error","msg":"..."}` when kanata rejected it
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+struct StatusReplyMsg {
+    status: String,
+    #[serde(default)]
+    msg: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum KanataIncoming {
+    Message(KanataServerMessage),
+    Status(StatusReplyMsg),
+}
+
+/// Lines longer than this are dropped instead of buffered (kanata's largest messages,
+/// layer and virtual key name lists, stay far below it)
+const KANATA_MAX_LINE_BYTES: usize = 1 << 20;
+
+/// Reads kanata's newline-delimited JSON. Tolerates several objects on one line and lines
+/// split over several reads; unknown message types are skipped (logged with `--debug`).
+/// Cancel safe: a partially read line is kept for the next call, so reads can be timed out.
+struct KanataMessageReader {
+    reader: TokioBufReader<KanataReader>,
+    line: Vec<u8>,
+    /// Dropping the rest of an oversized line
+    discarding: bool,
+    pending: VecDeque<KanataIncoming>,
+    debug: bool,
+}
+
+impl KanataMessageReader {
+    fn new(reader: KanataReader, debug: bool) -> Self {
+        Self {
+            reader: TokioBufReader::new(reader),
+            line: Vec::new(),
+            discarding: false,
+            pending: VecDeque::new(),
+            debug,
+        }
+    }
+
+    /// Next known message, None once kanata closed the connection
+    async fn next_message(&mut self) -> std::io::Result<Option<KanataIncoming>> {
+        loop {
+            if let Some(message) = self.pending.pop_front() {
+                return Ok(Some(message));
+            }
+            let available = self.reader.fill_buf().await?;
+            if available.is_empty() {
+                return Ok(None);
+            }
+            let newline = available.iter().position(|&byte| byte == b'\n');
+            let chunk_len = newline.unwrap_or(available.len());
+            if !self.discarding {
+                self.line.extend_from_slice(&available[..chunk_len]);
+                if self.line.len() > KANATA_MAX_LINE_BYTES {
+                    elog_line!(
+                        "[Kanata] Dropping message over {} bytes",
+                        KANATA_MAX_LINE_BYTES
+                    );
+                    self.line = Vec::new();
+                    self.discarding = true;
+                }
+            }
+            match newline {
+                Some(_) => {
+                    self.reader.consume(chunk_len + 1);
+                    if !std::mem::take(&mut self.discarding) {
+                        let line = std::mem::take(&mut self.line);
+                        self.parse_line(&line);
+                    }
+                }
+                None => self.reader.consume(chunk_len),
+            }
+        }
+    }
+
+    fn parse_line(&mut self, line: &[u8]) {
+        let values = serde_json::Deserializer::from_slice(line).into_iter::<serde_json::Value>();
+        for value in values {
+            let value = match value {
+                Ok(value) => value,
+                Err(error) => {
+                    elog_line!("[Kanata] Ignoring malformed message: {}", error);
+                    return;
+                }
+            };
+            if let Ok(message) = KanataServerMessage::deserialize(&value) {
+                self.pending.push_back(KanataIncoming::Message(message));
+            } else if let Ok(reply) = StatusReplyMsg::deserialize(&value) {
+                self.pending.push_back(KanataIncoming::Status(reply));
+            } else if self.debug {
+                log_line!(
+                    "[Kanata] Debug: ignoring unknown message type {}",
+                    kanata_message_type(&value)
+                );
+            }
+        }
+    }
+}
+
+/// Name of a kanata message for logs: the key of `{"Type": {...}}`, or the JSON itself
+fn kanata_message_type(value: &serde_json::Value) -> String {
+    match value.as_object() {
+        Some(object) if object.len() == 1 => object.keys().next().unwrap().clone(),
+        _ => value.to_string(),
+    }
+}
+
+/// Optional parts of the kanata protocol, probed during the handshake.
+/// None = not probed yet. Older kanata drops clients that send unknown messages, so a failed
+/// probe costs one reconnect; the results are kept across reconnects to not probe again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KanataCapabilities {
+    /// `RequestCurrentLayerName`
+    pub current_layer_name: Option<bool>,
+    /// `RequestFakeKeyNames`
+    pub fake_key_names: Option<bool>,
+    /// `ReloadNum`/`ReloadNext`, from the capabilities listed in kanata's `HelloOk`
+    pub reload: Option<bool>,
+}
+
+impl KanataCapabilities {
+    /// Kanata without reload commands would drop the connection on them, so they are only
+    /// sent once `Hello` has confirmed them
+    pub fn reload(&self) -> bool {
+        self.reload == Some(true)
+    }
+}
+
+/// Capability kanata lists in `HelloOk` when it accepts `ReloadNum`/`ReloadNext`
+const KANATA_RELOAD_CAPABILITY: &str = "reload";
+
+/// How long a handshake request waits for kanata's reply before treating it as unsupported
+const KANATA_REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Send a request and read its reply. LayerChange notifications that arrive in between
+/// update `current_layer`.
+/// Returns None if kanata rejected it (older kanata doesn't know every request): an error
+/// reply, a closed connection or no reply in time.
+async fn request_kanata_reply(
+    reader: &mut KanataMessageReader,
+    writer: &mut KanataWriter,
+    request_json: &str,
+    write_timeout: Duration,
+    current_layer: &mut Option<String>,
+) -> std::io::Result<Option<KanataServerMessage>> {
+    write_kanata_line(writer, request_json, write_timeout).await?;
+    let read_reply = async {
+        loop {
+            match reader.next_message().await {
+                Ok(None) | Err(_) => return None,
+                Ok(Some(KanataIncoming::Message(KanataServerMessage::LayerChange { new }))) => {
+                    *current_layer = Some(new);
+                }
+                Ok(Some(KanataIncoming::Message(message))) => return Some(message),
+                Ok(Some(KanataIncoming::Status(reply))) => {
+                    if reply.status == "Error" {
+                        elog_line!("[Kanata] Request rejected: {}", reply.msg);
+                        return None;
+                    }
+                }
+            }
+        }
+    };
+    Ok(tokio::time::timeout(KANATA_REPLY_TIMEOUT, read_reply)
+        .await
+        .unwrap_or(None))
+}
+
+struct KanataClientInner {
+    host: String,
+    port: u16,
+    writer: Option<KanataWriter>,
+    tls: Option<KanataTls>,
+    /// Milliseconds before each reconnect attempt; the last value repeats
+    reconnect_delays_ms: Vec<u64>,
+    write_timeout: Duration,
+    /// `--kanata-rate-limit`; None = unlimited
+    rate_limit: Option<KanataRateLimit>,
+    unknown_layer_policy: UnknownLayerPolicy,
+    reader_handle: Option<tokio::task::JoinHandle<()>>,
+    current_layer: Option<String>,
+    auto_default_layer: Option<String>,
+    config_default_layer: Option<String>,
+    pending_layer: Option<String>,
+    /// `PinLayer`: the only layer switched to, re-applied when kanata leaves it
+    pinned_layer: Option<String>,
+    /// Layer re-applied after (re)connecting when nothing else is pending (`--restore-layer`)
+    restore_layer: Option<String>,
+    known_layers: Vec<String>,
+    /// Known virtual keys from kanata. None = older kanata (validation disabled),
+    /// Some(vec) = validate against this list (even if empty).
+    known_virtual_keys: Option<Vec<String>>,
+    capabilities: KanataCapabilities,
+    connected: bool,
+    /// Virtual key and reload actions dropped since the connection was lost
+    dropped_actions: u64,
+    paused: bool,
+    /// The daemon is exiting (`begin_shutdown`): a lost connection isn't re-established
+    shutting_down: bool,
+    quiet: bool,
+    debug: bool,
+    status_broadcaster: StatusBroadcaster,
+}
+
+/// Messages sent to kanata in one write (`KanataClient::write_batch`)
+#[derive(Default)]
+struct KanataBatch {
+    /// Newline-terminated JSON messages
+    lines: String,
+    messages: Vec<BatchedMessage>,
+    /// What the client knew about the kanata config a queued reload replaces
+    replaced_config: Option<ReplacedKanataConfig>,
+    /// Written without log lines: the actions of `silent` rules
+    silent: bool,
+}
+
+/// Names and layer of the kanata config before a reload. Taken from the client while the
+/// reload is queued, so later actions of the same batch aren't checked against the old
+/// config, and put back when the write fails.
+struct ReplacedKanataConfig {
+    known_layers: Vec<String>,
+    known_virtual_keys: Option<Vec<String>>,
+    current_layer: Option<String>,
+}
+
+impl KanataBatch {
+    fn push<T: Serialize>(&mut self, msg: &T, message: BatchedMessage) {
+        self.lines.push_str(&serde_json::to_string(msg).unwrap());
+        self.lines.push('\n');
+        self.messages.push(message);
+    }
+}
+
+/// A message in a `KanataBatch`, logged once the write succeeds
+enum BatchedMessage {
+    ChangeLayer { from: Option<String>, to: String },
+    FakeKey { name: String, action: String },
+    Reload(String),
+}
+
+impl BatchedMessage {
+    fn log(&self) {
+        match self {
+            BatchedMessage::ChangeLayer { from, to } => log_line!(
+                "[Kanata] Switching layer (daemon): {} -> {}",
+                from.as_deref().unwrap_or("(none)"),
+                to
+            ),
+            BatchedMessage::FakeKey { name, action } => {
+                log_line!("[Kanata] Fake key: {} {}", action, name);
+            }
+            BatchedMessage::Reload(description) => log_line!("[Kanata] Reloading {}", description),
+        }
+    }
+}
+
+/// Reconnect backoff used unless a config endpoint sets `reconnect_delays_ms`
+const DEFAULT_RECONNECT_DELAYS_MS: [u64; 3] = [1000, 2000, 5000];
+
+#[derive(Clone)]
+pub struct KanataClient {
+    inner: Arc<TokioMutex<KanataClientInner>>,
+    /// Number of focus action batches currently being executed
+    in_flight: watch::Sender<usize>,
+    /// Clients of the other config endpoints rules send actions to (`kanata`), by name
+    endpoints: Arc<Mutex<BTreeMap<String, KanataClient>>>,
+    /// The embedded server this client talks to instead of kanata (`--kanata-sink`)
+    sink: Arc<OnceLock<EmbeddedKanata>>,
+    /// Mirrors `KanataClientInner::pinned_layer` for the runtime state writer
+    pinned: watch::Sender<Option<String>>,
+}
+
+/// Marks a focus action batch as in flight until dropped.
+struct InFlightActions {
+    sender: watch::Sender<usize>,
+}
+
+impl Drop for InFlightActions {
+    fn drop(&mut self) {
+        self.sender.send_modify(|count| *count -= 1);
+    }
+}
+
+impl std::fmt::Debug for KanataClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KanataClient").finish()
+    }
+}
+
+impl KanataClient {
+    fn new(
+        host: &str,
+        port: u16,
+        config_default_layer: Option<String>,
+        quiet: bool,
+        status_broadcaster: StatusBroadcaster,
+    ) -> Self {
+        if let Some(ref layer) = config_default_layer {
+            log_line!(
+                "[Kanata] Using config-specified default layer: \"{}\"",
+                layer
+            );
+        }
+        Self {
+            inner: Arc::new(TokioMutex::new(KanataClientInner {
+                host: host.to_string(),
+                port,
+                writer: None,
+                tls: None,
+                reconnect_delays_ms: DEFAULT_RECONNECT_DELAYS_MS.to_vec(),
+                write_timeout: Duration::from_millis(DEFAULT_KANATA_WRITE_TIMEOUT_MS),
+                rate_limit: None,
+                unknown_layer_policy: UnknownLayerPolicy::default(),
+                reader_handle: None,
+                current_layer: None,
+                auto_default_layer: None,
+                config_default_layer,
+                pending_layer: None,
+                pinned_layer: None,
+                restore_layer: None,
+                known_layers: Vec::new(),
+                known_virtual_keys: None,
+                capabilities: KanataCapabilities::default(),
+                connected: false,
+                dropped_actions: 0,
+                paused: false,
+                shutting_down: false,
+                quiet,
+                debug: false,
+                status_broadcaster,
+            })),
+            in_flight: watch::channel(0).0,
+            endpoints: Arc::new(Mutex::new(BTreeMap::new())),
+            sink: Arc::new(OnceLock::new()),
+            pinned: watch::channel(None).0,
+        }
+    }
+
+    /// Keeps `sink` running as long as this client (or a clone) exists
+    fn hold_sink(&self, sink: EmbeddedKanata) {
+        let _ = self.sink.set(sink);
+    }
+
+    fn sink_mode(&self) -> Option<KanataSink> {
+        self.sink.get().map(|sink| sink.mode)
+    }
+
+    /// A client for `target` with this one's write timeout, rate limit and logging, for the
+    /// actions of rules with `"kanata": name`; not connected yet
+    async fn add_endpoint(&self, name: &str, target: KanataTarget) -> KanataClient {
+        let client = {
+            let inner = self.inner.lock().await;
+            let client = KanataClient::new(
+                &target.host,
+                target.port,
+                target.default_layer,
+                inner.quiet,
+                // The indicator shows the daemon's own instance only
+                StatusBroadcaster::new(),
+            );
+            let mut client_inner = client.inner.lock().await;
+            client_inner.tls = target.tls;
+            if let Some(delays) = target.reconnect_delays_ms {
+                client_inner.reconnect_delays_ms = delays;
+            }
+            client_inner.write_timeout = inner.write_timeout;
+            client_inner.rate_limit = inner.rate_limit.as_ref().map(|limit| {
+                KanataRateLimit::new(limit.per_second, tokio::time::Instant::now())
+            });
+            client_inner.unknown_layer_policy = inner.unknown_layer_policy;
+            client_inner.debug = inner.debug;
+            drop(client_inner);
+            client
+        };
+        log_line!(
+            "[Kanata] Rules also target endpoint \"{}\" ({}:{})",
+            name, target.host, target.port
+        );
+        self.endpoints
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), client.clone());
+        client
+    }
+
+    fn has_endpoint(&self, name: &str) -> bool {
+        self.endpoints.lock().unwrap().contains_key(name)
+    }
+
+    /// Run each endpoint's actions on its client, in order
+    async fn execute_targeted_actions(&self, targeted: Vec<(String, Vec<FocusAction>)>) {
+        for (name, actions) in targeted {
+            let client = self.endpoints.lock().unwrap().get(&name).cloned();
+            match client {
+                Some(client) => client.execute_actions(actions, false, None).await,
+                None => elog_line!(
+                    "[Kanata] Warning: no connection to endpoint \"{}\"; dropping its actions",
+                    name
+                ),
+            }
+        }
+    }
+
+    async fn set_tls(&self, tls: Option<KanataTls>) {
+        let mut inner = self.inner.lock().await;
+        inner.tls = tls;
+    }
+
+    async fn set_reconnect_delays(&self, delays_ms: Vec<u64>) {
+        let mut inner = self.inner.lock().await;
+        inner.reconnect_delays_ms = delays_ms;
+    }
+
+    async fn set_write_timeout(&self, timeout: Duration) {
+        let mut inner = self.inner.lock().await;
+        inner.write_timeout = timeout;
+    }
+
+    /// Cap ChangeLayer/ActOnFakeKey messages per second; 0 = unlimited
+    async fn set_rate_limit(&self, per_second: u32) {
+        let mut inner = self.inner.lock().await;
+        inner.rate_limit =
+            (per_second > 0).then(|| KanataRateLimit::new(per_second, tokio::time::Instant::now()));
+    }
+
+    /// (messages per second, counters); None when unlimited
+    async fn rate_limit_stats(&self) -> Option<(u32, KanataRateLimitStats)> {
+        let inner = self.inner.lock().await;
+        inner
+            .rate_limit
+            .as_ref()
+            .map(|limit| (limit.per_second, limit.stats))
+    }
+
+    async fn set_debug(&self, debug: bool) {
+        let mut inner = self.inner.lock().await;
+        inner.debug = debug;
+    }
+
+    async fn set_unknown_layer_policy(&self, policy: UnknownLayerPolicy) {
+        let mut inner = self.inner.lock().await;
+        inner.unknown_layer_policy = policy;
+    }
+
+    async fn reconnect_delays(&self) -> Vec<u64> {
+        let inner = self.inner.lock().await;
+        inner.reconnect_delays_ms.clone()
+    }
+
+    /// Whether to log the switches, fake keys and reloads of `batch` once written
+    fn logs_batch(inner: &KanataClientInner, batch: &KanataBatch) -> bool {
+        !inner.quiet && !batch.silent
+    }
+
+    /// Announce a lost connection (while not paused) or a reconnect, with the actions dropped
+    /// in between
+    fn publish_connection(inner: &mut KanataClientInner) {
+        let dropped_actions = std::mem::take(&mut inner.dropped_actions);
+        let event = KanataConnectionEvent {
+            connected: inner.connected,
+            dropped_actions: if inner.connected { dropped_actions } else { 0 },
+        };
+        inner.status_broadcaster.publish_kanata_connection(event);
+    }
+
+    fn begin_actions(&self) -> InFlightActions {
+        self.in_flight.send_modify(|count| *count += 1);
+        InFlightActions {
+            sender: self.in_flight.clone(),
+        }
+    }
+
+    /// Wait until no focus action batch is being executed. Returns false on timeout.
+    async fn drain_in_flight(&self, timeout: Duration) -> bool {
+        let mut receiver = self.in_flight.subscribe();
+        tokio::time::timeout(timeout, receiver.wait_for(|count| *count == 0))
+            .await
+            .is_ok()
+    }
+
+    fn resolve_layer_name_from_inner(
+        inner: &KanataClientInner,
+        layer_name: &str,
+        warn_unknown: bool,
+    ) -> Option<String> {
+        if !inner.known_layers.is_empty()
+            && !inner.known_layers.iter().any(|layer| layer == layer_name)
+        {
+            match inner.unknown_layer_policy {
+                UnknownLayerPolicy::Fallback => {
+                    if warn_unknown && !inner.quiet {
+                        elog_line!(
+                            "[Kanata] Warning: Unknown layer \"{}\", switching to default instead",
+                            layer_name
+                        );
+                    }
+                    return inner
+                        .config_default_layer
+                        .clone()
+                        .or_else(|| inner.auto_default_layer.clone());
+                }
+                UnknownLayerPolicy::Skip => {
+                    if warn_unknown && !inner.quiet {
+                        elog_line!(
+                            "[Kanata] Warning: Unknown layer \"{}\", keeping current layer",
+                            layer_name
+                        );
+                    }
+                }
+                UnknownLayerPolicy::Error => {
+                    // Not silenced by --quiet
+                    if warn_unknown {
+                        elog_line!(
+                            "[Kanata] Error: Unknown layer \"{}\", keeping current layer",
+                            layer_name
+                        );
+                    }
+                }
+            }
+            return None;
+        }
+        Some(layer_name.to_string())
+    }
+
+    async fn resolve_layer_name(&self, layer_name: &str, warn_unknown: bool) -> Option<String> {
+        let inner = self.inner.lock().await;
+        Self::resolve_layer_name_from_inner(&inner, layer_name, warn_unknown)
+    }
+
+    pub async fn connect_with_retry(&self) {
+        self.connect_with_deadline(None).await;
+    }
+
+    /// Like `connect_with_retry`, but stops retrying at `deadline`: the first attempt always
+    /// runs and the last one is made at the deadline. Returns whether kanata is connected.
+    pub async fn connect_with_deadline(&self, deadline: Option<tokio::time::Instant>) -> bool {
+        // First attempt is immediate
+        let delays: Vec<u64> = std::iter::once(0)
+            .chain(self.reconnect_delays().await)
+            .collect();
+        let mut attempt = 0;
+
+        loop {
+            let mut delay = Duration::from_millis(delays[attempt.min(delays.len() - 1)]);
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+                if attempt > 0 && remaining.is_zero() {
+                    return false;
+                }
+                delay = delay.min(remaining);
+            }
+            if !delay.is_zero() {
+                log_line!("[Kanata] Retrying connection in {:?}...", delay);
+                tokio::time::sleep(delay).await;
+            }
+
+            match self.try_connect().await {
+                Ok(_) => {
+                    self.apply_restore_layer().await;
+                    return true;
+                }
+                Err(e) => {
+                    let inner = self.inner.lock().await;
+                    elog_line!(
+                        "[Kanata] Cannot connect to {}:{}: {}",
+                        inner.host, inner.port, e
+                    );
+                    drop(inner);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    async fn try_connect(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (host, port, tls, write_timeout) = {
+            let inner = self.inner.lock().await;
+            (
+                inner.host.clone(),
+                inner.port,
+                inner.tls.clone(),
+                inner.write_timeout,
+            )
+        };
+
+        let addr = format!("{}:{}", host, port);
+        let stream = TokioTcpStream::connect(&addr).await?;
+        let (reader, mut writer): (KanataReader, KanataWriter) = match tls {
+            Some(tls) => {
+                let halves = tls.connect(stream).await?;
+                log_line!("[Kanata] Connected to {} (TLS)", addr);
+                halves
+            }
+            None => {
+                log_line!("[Kanata] Connected to {}", addr);
+                let (reader, writer) = stream.into_split();
+                (Box::new(reader), Box::new(writer))
+            }
+        };
+        let debug = self.inner.lock().await.debug;
+        let mut reader = KanataMessageReader::new(reader, debug);
+
+        // Read initial LayerChange message
+        let mut current_layer = None;
+        match reader.next_message().await? {
+            Some(KanataIncoming::Message(KanataServerMessage::LayerChange { new })) => {
+                log_line!("[Kanata] Current layer: \"{}\"", new);
+                current_layer = Some(new);
+            }
+            Some(_) => {}
+            None => return Err("Connection closed during handshake".into()),
+        }
+
+        // Request layer names
+        let request = RequestLayerNamesMsg {
+            request_layer_names: RequestLayerNamesPayload {},
+        };
+        let request_json = serde_json::to_string(&request).unwrap() + "\n";
+        let reply = request_kanata_reply(
+            &mut reader,
+            &mut writer,
+            &request_json,
+            write_timeout,
+            &mut current_layer,
+        )
+        .await?;
+
+        let mut known_layers = Vec::new();
+        // Auto-detect default layer from the first layer in the list (layers are in definition order)
+        let mut auto_default_layer = None;
+        if let Some(KanataServerMessage::LayerNames { names }) = reply {
+            log_line!("[Kanata] Available layers: {:?}", names);
+            auto_default_layer = names.first().cloned();
+            known_layers = names;
+        }
+
+        let mut capabilities = self.inner.lock().await.capabilities;
+
+        // Answers with the layer active right now, which may differ from the initial
+        // LayerChange if the layer changed while the handshake was in progress
+        if capabilities.current_layer_name != Some(false) {
+            let request = RequestCurrentLayerNameMsg {
+                request_current_layer_name: RequestCurrentLayerNamePayload {},
+            };
+            let request_json = serde_json::to_string(&request).unwrap() + "\n";
+            let reply = request_kanata_reply(
+                &mut reader,
+                &mut writer,
+                &request_json,
+                write_timeout,
+                &mut current_layer,
+            )
+            .await?;
+            match reply {
+                Some(KanataServerMessage::CurrentLayerName { name }) => {
+                    capabilities.current_layer_name = Some(true);
+                    current_layer = Some(name);
+                }
+                _ => {
+                    let mut inner = self.inner.lock().await;
+                    inner.capabilities.current_layer_name = Some(false);
+                    return Err("Older kanata detected (no RequestCurrentLayerName support)".into());
+                }
+            }
+        }
+
+        // Request virtual key names (skip if we know this is older kanata)
+        let known_virtual_keys = if capabilities.fake_key_names == Some(false) {
+            None
+        } else {
+            let request = RequestFakeKeyNamesMsg {
+                request_fake_key_names: RequestFakeKeyNamesPayload {},
+            };
+            let request_json = serde_json::to_string(&request).unwrap() + "\n";
+            let reply = request_kanata_reply(
+                &mut reader,
+                &mut writer,
+                &request_json,
+                write_timeout,
+                &mut current_layer,
+            )
+            .await?;
+            match reply {
+                Some(KanataServerMessage::FakeKeyNames { names }) => {
+                    if !names.is_empty() {
+                        log_line!("[Kanata] Available virtual keys: {:?}", names);
+                    }
+                    capabilities.fake_key_names = Some(true);
+                    Some(names)
+                }
+                _ => {
+                    let mut inner = self.inner.lock().await;
+                    inner.capabilities.fake_key_names = Some(false);
+                    return Err("Older kanata detected (no RequestFakeKeyNames support)".into());
+                }
+            }
+        };
+
+        // Kanata lists its optional commands in the reply; without `Hello` it has no reloads
+        if capabilities.reload != Some(false) {
+            let request = HelloMsg {
+                hello: HelloPayload {},
+            };
+            let request_json = serde_json::to_string(&request).unwrap() + "\n";
+            let reply = request_kanata_reply(
+                &mut reader,
+                &mut writer,
+                &request_json,
+                write_timeout,
+                &mut current_layer,
+            )
+            .await?;
+            match reply {
+                Some(KanataServerMessage::HelloOk {
+                    version,
+                    capabilities: supported,
+                    ..
+                }) => {
+                    log_line!("[Kanata] Version: {}", version);
+                    capabilities.reload = Some(
+                        supported
+                            .iter()
+                            .any(|capability| capability == KANATA_RELOAD_CAPABILITY),
+                    );
+                }
+                _ => {
+                    let mut inner = self.inner.lock().await;
+                    inner.capabilities.reload = Some(false);
+                    return Err("Older kanata detected (no Hello support)".into());
+                }
+            }
+        }
+
+        {
+            let mut inner = self.inner.lock().await;
+            inner.connected = true;
+            inner.writer = Some(writer);
+            inner.capabilities = capabilities;
+            inner.current_layer = current_layer;
+            inner.known_layers = known_layers;
+            inner.known_virtual_keys = known_virtual_keys;
+            inner.status_broadcaster.clear_degraded("kanata");
+            if let Some(ref layer) = auto_default_layer {
+                if inner.config_default_layer.is_none() {
+                    log_line!("[Kanata] Using auto-detected default layer: \"{}\"", layer);
+                }
+                inner.auto_default_layer = auto_default_layer;
+            }
+            if let Some(ref layer) = inner.current_layer {
+                inner
+                    .status_broadcaster
+                    .update_layer(layer.clone(), LayerSource::External);
+            }
+        }
+
+        let reader_handle = self.clone().spawn_reader(reader);
+        let mut inner = self.inner.lock().await;
+        inner.reader_handle = Some(reader_handle);
+        Ok(())
+    }
+
+    fn spawn_reader(self, mut reader: KanataMessageReader) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                match reader.next_message().await {
+                    Ok(None) => {
+                        log_line!("[Kanata] Disconnected");
+                        {
+                            let mut inner = self.inner.lock().await;
+                            inner.connected = false;
+                            inner.writer = None;
+                            inner.reader_handle = None;
+                            if inner.paused || inner.shutting_down {
+                                return;
+                            }
+                            Self::publish_connection(&mut inner);
+                        }
+                        self.reconnect_loop().await;
+                        return;
+                    }
+                    Ok(Some(KanataIncoming::Message(KanataServerMessage::LayerChange { new }))) => {
+                        let mut inner = self.inner.lock().await;
+                        if inner.paused {
+                            continue;
+                        }
+                        let old_layer = inner.current_layer.clone();
+                        inner.current_layer = Some(new.clone());
+                        let status_broadcaster = inner.status_broadcaster.clone();
+                        let quiet = inner.quiet;
+                        let pinned = inner.pinned_layer.clone().filter(|pinned| *pinned != new);
+                        drop(inner);
+                        if let Some(pinned) = pinned {
+                            log_line!(
+                                "[Kanata] Layer changed to \"{}\" while pinned; switching back to \"{}\"",
+                                new, pinned
+                            );
+                            self.change_layer(&pinned).await;
+                        } else if old_layer.as_ref() != Some(&new) {
+                            status_broadcaster.update_layer(new.clone(), LayerSource::External);
+                            if !quiet {
+                                log_line!(
+                                    "[Kanata] Layer changed (external): {} -> {}",
+                                    old_layer.as_deref().unwrap_or("(none)"),
+                                    new
+                                );
+                            }
+                        }
+                    }
+                    Ok(Some(KanataIncoming::Status(reply))) if reply.status == "Error" => {
+                        elog_line!("[Kanata] Command rejected: {}", reply.msg);
+                    }
+                    // Replies to `request_config_names` after a reload
+                    Ok(Some(KanataIncoming::Message(KanataServerMessage::LayerNames { names }))) => {
+                        log_line!("[Kanata] Available layers: {:?}", names);
+                        let mut inner = self.inner.lock().await;
+                        inner.auto_default_layer = names.first().cloned();
+                        inner.known_layers = names;
+                    }
+                    Ok(Some(KanataIncoming::Message(KanataServerMessage::FakeKeyNames { names }))) => {
+                        if !names.is_empty() {
+                            log_line!("[Kanata] Available virtual keys: {:?}", names);
+                        }
+                        self.inner.lock().await.known_virtual_keys = Some(names);
+                    }
+                    Ok(Some(KanataIncoming::Message(KanataServerMessage::CurrentLayerName {
+                        name,
+                    }))) => {
+                        self.inner.lock().await.current_layer = Some(name);
+                    }
+                    Ok(Some(other)) => {
+                        // Status acks
+                        if reader.debug {
+                            log_line!("[Kanata] Debug: ignoring {:?}", other);
+                        }
+                    }
+                    Err(e) => {
+                        elog_line!("[Kanata] Connection error: {}", e);
+                        {
+                            let mut inner = self.inner.lock().await;
+                            inner.connected = false;
+                            inner.writer = None;
+                            inner.reader_handle = None;
+                            if inner.paused || inner.shutting_down {
+                                return;
+                            }
+                            Self::publish_connection(&mut inner);
+                        }
+                        self.reconnect_loop().await;
+                        return;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Boxed because it is recursive: a write that breaks while applying the pending layer
+    /// spawns another reconnect loop
+    fn reconnect_loop(&self) -> futures_util::future::BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let delays = self.reconnect_delays().await;
+            let mut attempt = 0;
+
+            loop {
+                {
+                    let inner = self.inner.lock().await;
+                    if inner.connected || inner.paused {
+                        return;
+                    }
+                }
+
+                let delay = delays[attempt.min(delays.len() - 1)];
+                log_line!("[Kanata] Reconnecting in {:?}...", Duration::from_millis(delay));
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+
+                match self.try_connect().await {
+                    Ok(_) => {
+                        log_line!("[Kanata] Reconnected");
+                        Self::publish_connection(&mut *self.inner.lock().await);
+
+                        // A pinned layer replaces the pending one (see apply_restore_layer)
+                        let pending = {
+                            let mut inner = self.inner.lock().await;
+                            let pending = inner.pending_layer.take();
+                            pending.filter(|_| inner.pinned_layer.is_none())
+                        };
+
+                        if let Some(pending) = pending {
+                            let current = self.inner.lock().await.current_layer.clone();
+                            if current.as_ref() != Some(&pending) {
+                                let _ = self.change_layer(&pending).await;
+                            }
+                        } else {
+                            self.apply_restore_layer().await;
+                        }
+                        return;
+                    }
+                    Err(_) => {
+                        attempt += 1;
+                    }
+                }
+            }
+        })
+    }
+
+    pub async fn set_restore_layer(&self, layer: Option<String>) {
+        let mut inner = self.inner.lock().await;
+        inner.restore_layer = layer;
+    }
+
+    /// Re-apply the pinned layer, else the remembered one, right after connecting, before the
+    /// first focus event.
+    async fn apply_restore_layer(&self) {
+        let (layer, status_broadcaster) = {
+            let inner = self.inner.lock().await;
+            if inner.paused {
+                return;
+            }
+            if let Some(pinned) = inner.pinned_layer.clone() {
+                let status_broadcaster = inner.status_broadcaster.clone();
+                drop(inner);
+                self.change_layer(&pinned).await;
+                status_broadcaster.set_pinned_status(pinned);
+                return;
+            }
+            match inner.restore_layer.clone() {
+                Some(layer) => (layer, inner.status_broadcaster.clone()),
+                None => return,
+            }
+        };
+        if self.change_layer(&layer).await {
+            log_line!("[Kanata] Restored last layer \"{}\"", layer);
+            status_broadcaster.update_layer(layer, LayerSource::Focus);
+        }
+    }
+
+    pub async fn change_layer(&self, layer_name: &str) -> bool {
+        let mut inner = self.inner.lock().await;
+        let mut batch = KanataBatch::default();
+        self.queue_change_layer(&mut inner, &mut batch, layer_name);
+        self.write_batch(&mut inner, batch).await
+    }
+
+    pub async fn pinned_layer(&self) -> Option<String> {
+        let inner = self.inner.lock().await;
+        inner.pinned_layer.clone()
+    }
+
+    /// Switch to `layer` and refuse switches to any other layer until `unpin_layer`.
+    /// Returns false for layers kanata doesn't know
+    pub async fn pin_layer(&self, layer: &str) -> bool {
+        let mut inner = self.inner.lock().await;
+        if Self::resolve_layer_name_from_inner(&inner, layer, false).as_deref() != Some(layer) {
+            return false;
+        }
+        inner.pinned_layer = Some(layer.to_string());
+        let mut batch = KanataBatch::default();
+        self.queue_change_layer(&mut inner, &mut batch, layer);
+        self.write_batch(&mut inner, batch).await;
+        inner.status_broadcaster.set_pinned_status(layer.to_string());
+        self.pinned.send_replace(Some(layer.to_string()));
+        true
+    }
+
+    /// Pin from the runtime state. Connected, it's `pin_layer`; otherwise the layer can't be
+    /// checked yet and is pinned as is, `apply_restore_layer` switches to it on connect
+    pub async fn restore_pinned_layer(&self, layer: &str) -> bool {
+        if self.is_connected().await {
+            return self.pin_layer(layer).await;
+        }
+        let mut inner = self.inner.lock().await;
+        inner.pinned_layer = Some(layer.to_string());
+        inner.status_broadcaster.set_pinned_status(layer.to_string());
+        self.pinned.send_replace(Some(layer.to_string()));
+        true
+    }
+
+    /// Returns the layer that was pinned
+    pub async fn unpin_layer(&self) -> Option<String> {
+        let mut inner = self.inner.lock().await;
+        let layer = inner.pinned_layer.take();
+        if layer.is_some() {
+            self.pinned.send_replace(None);
+        }
+        layer
+    }
+
+    fn subscribe_pinned(&self) -> watch::Receiver<Option<String>> {
+        self.pinned.subscribe()
+    }
+
+    /// Run the actions of one focus event. Everything between delays is sent with one lock and
+    /// one write, so it reaches kanata back to back instead of interleaving with other senders.
+    /// `written` is signalled after the first write. Rule commands are spawned last, once kanata
+    /// has the new layer. `silent` batches aren't logged, without muting other senders
+    async fn execute_actions(
+        &self,
+        actions: Vec<FocusAction>,
+        silent: bool,
+        mut written: Option<tokio::sync::oneshot::Sender<()>>,
+    ) {
+        let signal_written = |written: &mut Option<tokio::sync::oneshot::Sender<()>>| {
+            if let Some(written) = written.take() {
+                let _ = written.send(());
+            }
+        };
+        let new_batch = || KanataBatch {
+            silent,
+            ..Default::default()
+        };
+        let mut inner = self.inner.lock().await;
+        let mut batch = new_batch();
+        let mut commands = Vec::new();
+        for action in actions {
+            match action {
+                FocusAction::ReleaseVk(vk) => {
+                    self.queue_fake_key(&mut inner, &mut batch, &vk, "Release");
+                }
+                FocusAction::ChangeLayer(layer) => {
+                    self.queue_change_layer(&mut inner, &mut batch, &layer);
+                }
+                FocusAction::PressVk(vk) => {
+                    self.queue_fake_key(&mut inner, &mut batch, &vk, "Press");
+                }
+                FocusAction::RawVkAction(name, action) => {
+                    self.queue_fake_key(&mut inner, &mut batch, &name, &action);
+                }
+                FocusAction::Delay(ms) => {
+                    self.write_batch(&mut inner, std::mem::replace(&mut batch, new_batch()))
+                        .await;
+                    signal_written(&mut written);
+                    drop(inner);
+                    tokio::time::sleep(Duration::from_millis(ms)).await;
+                    inner = self.inner.lock().await;
+                }
+                FocusAction::ReloadNum(index) => {
+                    let msg = ReloadNumMsg {
+                        reload_num: ReloadNumPayload { index },
+                    };
+                    Self::queue_reload(&mut inner, &mut batch, &msg, format!("config #{}", index));
+                }
+                FocusAction::ReloadNext => {
+                    let msg = ReloadNextMsg {
+                        reload_next: ReloadNextPayload {},
+                    };
+                    Self::queue_reload(&mut inner, &mut batch, &msg, "next config".to_string());
+                }
+                FocusAction::Plugin(call) => {
+                    self.write_batch(&mut inner, std::mem::replace(&mut batch, new_batch()))
+                        .await;
+                    signal_written(&mut written);
+                    drop(inner);
+                    call.run().await;
+                    inner = self.inner.lock().await;
+                }
+                FocusAction::RunCommand(command) => commands.push(command),
+            }
+        }
+        self.write_batch(&mut inner, batch).await;
+        drop(inner);
+        signal_written(&mut written);
+        for command in commands {
+            command.spawn();
+        }
+    }
+
+    /// Add a switch to `layer_name` to `batch` unless it's unknown, already active, kept off
+    /// by a pinned layer, waiting for a reconnect or deferred by the rate limit
+    fn queue_change_layer(
+        &self,
+        inner: &mut KanataClientInner,
+        batch: &mut KanataBatch,
+        layer_name: &str,
+    ) {
+        let target_layer =
+            match Self::resolve_layer_name_from_inner(inner, layer_name, true) {
+                Some(layer) => layer,
+                None => return,
+            };
+
+        if inner
+            .pinned_layer
+            .as_ref()
+            .is_some_and(|pinned| *pinned != target_layer)
+        {
+            return;
+        }
+
+        if inner.current_layer.as_deref() == Some(&target_layer) {
+            if let Some(limit) = inner.rate_limit.as_mut() {
+                limit.cancel_deferred_layer();
+            }
+            return;
+        }
+
+        if !inner.connected {
+            inner.pending_layer = Some(target_layer.clone());
+            log_line!(
+                "[Kanata] Not connected, will switch to \"{}\" on reconnect",
+                target_layer
+            );
+            return;
+        }
+
+        if self.defer_over_rate_limit(
+            inner,
+            DeferredKanataMessage::ChangeLayer(target_layer.clone()),
+        ) {
+            return;
+        }
+        Self::push_change_layer(inner, batch, target_layer);
+    }
+
+    /// Later messages in the batch already see `target_layer` as the current layer
+    fn push_change_layer(
+        inner: &mut KanataClientInner,
+        batch: &mut KanataBatch,
+        target_layer: String,
+    ) {
+        let msg = ChangeLayerMsg {
+            change_layer: ChangeLayerPayload {
+                new: target_layer.clone(),
+            },
+        };
+        let from = inner.current_layer.replace(target_layer.clone());
+        batch.push(
+            &msg,
+            BatchedMessage::ChangeLayer {
+                from,
+                to: target_layer,
+            },
+        );
+    }
+
+    /// Write `batch` in one go and log its messages. When the write fails the layer is put
+    /// back, the last switch is applied once reconnected and the other messages count as dropped
+    async fn write_batch(&self, inner: &mut KanataClientInner, batch: KanataBatch) -> bool {
+        if batch.messages.is_empty() {
+            return false;
+        }
+        if self.send_line(inner, &batch.lines).await {
+            if Self::logs_batch(inner, &batch) {
+                for message in &batch.messages {
+                    message.log();
+                }
+            }
+            if batch.replaced_config.is_some() {
+                self.request_config_names(inner).await;
+            }
+            return true;
+        }
+        let mut layer_before = None;
+        let mut reload_seen = false;
+        for message in batch.messages {
+            match message {
+                BatchedMessage::ChangeLayer { from, to } => {
+                    if !reload_seen {
+                        layer_before.get_or_insert(from);
+                    }
+                    if !inner.connected {
+                        inner.pending_layer = Some(to);
+                    }
+                }
+                BatchedMessage::FakeKey { .. } => {
+                    if !inner.connected {
+                        inner.dropped_actions += 1;
+                    }
+                }
+                BatchedMessage::Reload(_) => {
+                    reload_seen = true;
+                    if !inner.connected {
+                        inner.dropped_actions += 1;
+                    }
+                }
+            }
+        }
+        if let Some(replaced) = batch.replaced_config {
+            inner.known_layers = replaced.known_layers;
+            inner.known_virtual_keys = replaced.known_virtual_keys;
+            layer_before.get_or_insert(replaced.current_layer);
+        }
+        if let Some(layer) = layer_before {
+            inner.current_layer = layer;
+        }
+        false
+    }
+
+    /// Asks kanata for the names (and layer) of the config it reloaded; the reader stores the
+    /// replies. Until then layer and virtual key names aren't validated.
+    async fn request_config_names(&self, inner: &mut KanataClientInner) {
+        let mut lines = serde_json::to_string(&RequestLayerNamesMsg {
+            request_layer_names: RequestLayerNamesPayload {},
+        })
+        .unwrap()
+            + "\n";
+        if inner.capabilities.current_layer_name == Some(true) {
+            let request = RequestCurrentLayerNameMsg {
+                request_current_layer_name: RequestCurrentLayerNamePayload {},
+            };
+            lines.push_str(&(serde_json::to_string(&request).unwrap() + "\n"));
+        }
+        if inner.capabilities.fake_key_names == Some(true) {
+            let request = RequestFakeKeyNamesMsg {
+                request_fake_key_names: RequestFakeKeyNamesPayload {},
+            };
+            lines.push_str(&(serde_json::to_string(&request).unwrap() + "\n"));
+        }
+        self.send_line(inner, &lines).await;
+    }
+
+    /// Defer `message` when the rate limit is exhausted and make sure a flush is scheduled.
+    /// Returns false when the message may be sent right away.
+    fn defer_over_rate_limit(
+        &self,
+        inner: &mut KanataClientInner,
+        message: DeferredKanataMessage,
+    ) -> bool {
+        let Some(limit) = inner.rate_limit.as_mut() else {
+            return false;
+        };
+        if limit.try_acquire(tokio::time::Instant::now()) {
+            return false;
+        }
+        limit.defer(message, tokio::time::Instant::now());
+        if !limit.flush_scheduled {
+            limit.flush_scheduled = true;
+            if !inner.quiet {
+                log_line!(
+                    "[Kanata] Rate limit of {}/s reached, deferring messages until it refills",
+                    limit.per_second
+                );
+            }
+            let client = self.clone();
+            tokio::spawn(async move { client.flush_rate_limited().await });
+        }
+        true
+    }
+
+    /// Send deferred messages as the rate limit refills, until none are left
+    async fn flush_rate_limited(&self) {
+        loop {
+            let wait = {
+                let mut inner = self.inner.lock().await;
+                match inner.rate_limit.as_mut() {
+                    Some(limit) if limit.has_deferred() => {
+                        limit.next_token_in(tokio::time::Instant::now())
+                    }
+                    Some(limit) => {
+                        limit.flush_scheduled = false;
+                        return;
+                    }
+                    None => return,
+                }
+            };
+            tokio::time::sleep(wait).await;
+
+            let mut inner = self.inner.lock().await;
+            let Some(message) = inner
+                .rate_limit
+                .as_mut()
+                .and_then(|limit| limit.pop_ready(tokio::time::Instant::now()))
+            else {
+                continue;
+            };
+            match message {
+                DeferredKanataMessage::ChangeLayer(layer) => {
+                    if !inner.connected {
+                        inner.pending_layer = Some(layer);
+                    } else if inner.current_layer.as_ref() != Some(&layer) {
+                        let mut batch = KanataBatch::default();
+                        Self::push_change_layer(&mut inner, &mut batch, layer);
+                        self.write_batch(&mut inner, batch).await;
+                    }
+                }
+                DeferredKanataMessage::ActOnFakeKey { name, action } => {
+                    if inner.connected {
+                        let mut batch = KanataBatch::default();
+                        Self::push_fake_key(&mut batch, name, action);
+                        self.write_batch(&mut inner, batch).await;
+                    } else {
+                        inner.dropped_actions += 1;
+                        if !inner.quiet {
+                            elog_line!("[Kanata] Not connected, dropping deferred fake key action");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Drop the rate limit so shutdown releases and resets are sent right away
+    /// The daemon is exiting: the last messages (of this client and its endpoints) go out
+    /// without the rate limit, and a failed write doesn't start a reconnect
+    async fn begin_shutdown(&self) {
+        let endpoints: Vec<KanataClient> =
+            self.endpoints.lock().unwrap().values().cloned().collect();
+        for client in std::iter::once(self).chain(&endpoints) {
+            let mut inner = client.inner.lock().await;
+            inner.rate_limit = None;
+            inner.shutting_down = true;
+        }
+    }
+
+    /// Write a line on the current connection. A failed or stalled write drops the connection
+    /// and starts the reconnect loop (unless paused or shutting down), so later sends fail fast
+    /// instead of waiting on it again.
+    async fn send_line(&self, inner: &mut KanataClientInner, line: &str) -> bool {
+        let write_timeout = inner.write_timeout;
+        let Some(writer) = inner.writer.as_mut() else {
+            return false;
+        };
+        let Err(error) = write_kanata_line(writer, line, write_timeout).await else {
+            return true;
+        };
+        inner.connected = false;
+        inner.writer = None;
+        if let Some(handle) = inner.reader_handle.take() {
+            handle.abort();
+        }
+        if inner.shutting_down {
+            elog_line!("[Kanata] Write failed: {}", error);
+        } else {
+            elog_line!("[Kanata] Write failed: {}; reconnecting", error);
+        }
+        if !inner.paused && !inner.shutting_down {
+            Self::publish_connection(inner);
+            let client = self.clone();
+            tokio::spawn(async move { client.reconnect_loop().await });
+        }
+        false
+    }
+
+    pub async fn current_layer(&self) -> Option<String> {
+        let inner = self.inner.lock().await;
+        inner.current_layer.clone()
+    }
+
+    pub async fn is_connected(&self) -> bool {
+        let inner = self.inner.lock().await;
+        inner.connected
+    }
+
+    pub async fn known_layers(&self) -> Vec<String> {
+        let inner = self.inner.lock().await;
+        inner.known_layers.clone()
+    }
+
+    pub async fn act_on_fake_key(&self, name: &str, action: &str) -> bool {
+        let mut inner = self.inner.lock().await;
+        let mut batch = KanataBatch::default();
+        self.queue_fake_key(&mut inner, &mut batch, name, action);
+        self.write_batch(&mut inner, batch).await
+    }
+
+    /// Add a fake key action to `batch` unless kanata is disconnected, doesn't know the key
+    /// or the rate limit defers it
+    fn queue_fake_key(
+        &self,
+        inner: &mut KanataClientInner,
+        batch: &mut KanataBatch,
+        name: &str,
+        action: &str,
+    ) {
+        if !inner.connected {
+            inner.dropped_actions += 1;
+            if !inner.quiet {
+                elog_line!("[Kanata] Not connected, cannot send fake key action");
+            }
+            return;
+        }
+
+        // Validate virtual key name if we have the list from kanata
+        if Self::filter_valid_virtual_keys(&inner.known_virtual_keys, vec![name.to_string()])
+            .is_empty()
+        {
+            if !inner.quiet {
+                elog_line!(
+                    "[Kanata] Warning: Unknown virtual key \"{}\", skipping action",
+                    name
+                );
+            }
+            return;
+        }
+
+        if self.defer_over_rate_limit(
+            inner,
+            DeferredKanataMessage::ActOnFakeKey {
+                name: name.to_string(),
+                action: action.to_string(),
+            },
+        ) {
+            return;
+        }
+        Self::push_fake_key(batch, name.to_string(), action.to_string());
+    }
+
+    fn push_fake_key(batch: &mut KanataBatch, name: String, action: String) {
+        let msg = ActOnFakeKeyMsg {
+            act_on_fake_key: ActOnFakeKeyPayload {
+                name: name.clone(),
+                action: action.clone(),
+            },
+        };
+        batch.push(&msg, BatchedMessage::FakeKey { name, action });
+    }
+
+    pub async fn reload_num(&self, index: usize) -> bool {
+        let msg = ReloadNumMsg {
+            reload_num: ReloadNumPayload { index },
+        };
+        self.send_reload(&msg, format!("config #{}", index)).await
+    }
+
+    pub async fn reload_next(&self) -> bool {
+        let msg = ReloadNextMsg {
+            reload_next: ReloadNextPayload {},
+        };
+        self.send_reload(&msg, "next config".to_string()).await
+    }
+
+    async fn send_reload<T: Serialize>(&self, msg: &T, description: String) -> bool {
+        let mut inner = self.inner.lock().await;
+        let mut batch = KanataBatch::default();
+        Self::queue_reload(&mut inner, &mut batch, msg, description);
+        self.write_batch(&mut inner, batch).await
+    }
+
+    /// Add a reload request to `batch`. Layer and virtual key lists belong to the previous
+    /// config, so they are dropped until the next handshake instead of filtering against stale
+    /// names (later messages in the batch included).
+    fn queue_reload<T: Serialize>(
+        inner: &mut KanataClientInner,
+        batch: &mut KanataBatch,
+        msg: &T,
+        description: String,
+    ) {
+        if !inner.connected {
+            inner.dropped_actions += 1;
+            if !inner.quiet {
+                elog_line!("[Kanata] Not connected, cannot reload {}", description);
+            }
+            return;
+        }
+
+        if !inner.capabilities.reload() {
+            elog_line!(
+                "[Kanata] Connected kanata doesn't support reload commands, not reloading {}",
+                description
+            );
+            return;
+        }
+
+        batch.push(msg, BatchedMessage::Reload(description));
+        if batch.replaced_config.is_none() {
+            batch.replaced_config = Some(ReplacedKanataConfig {
+                known_layers: std::mem::take(&mut inner.known_layers),
+                known_virtual_keys: inner.known_virtual_keys.take(),
+                current_layer: inner.current_layer.take(),
+            });
+        }
+    }
+
+    pub async fn default_layer(&self) -> Option<String> {
+        let inner = self.inner.lock().await;
+        inner
+            .config_default_layer
+            .clone()
+            .or_else(|| inner.auto_default_layer.clone())
+    }
+
+    pub async fn pause_disconnect(&self) {
+        let mut inner = self.inner.lock().await;
+        inner.paused = true;
+        if let Some(handle) = inner.reader_handle.take() {
+            handle.abort();
+        }
+        if let Some(mut writer) = inner.writer.take() {
+            let _ = tokio::time::timeout(inner.write_timeout, writer.shutdown()).await;
+        }
+        inner.connected = false;
+        inner.dropped_actions = 0;
+        inner.current_layer = None;
+        inner.auto_default_layer = None;
+        inner.pending_layer = None;
+        if let Some(limit) = inner.rate_limit.as_mut() {
+            limit.clear_deferred();
+        }
+        inner.known_layers.clear();
+        inner.known_virtual_keys = None;
+    }
+
+    pub async fn unpause_connect(&self) {
+        {
+            let mut inner = self.inner.lock().await;
+            inner.paused = false;
+        }
+        self.connect_with_retry().await;
+    }
+
+    /// Filter a list of virtual key names, returning only those that are valid.
+    /// If validation is disabled (None), all VKs pass through.
+    fn filter_valid_virtual_keys(
+        known_virtual_keys: &Option<Vec<String>>,
+        vks: Vec<String>,
+    ) -> Vec<String> {
+        match known_virtual_keys {
+            None => vks,
+            Some(known_vks) => vks.into_iter().filter(|vk| known_vks.contains(vk)).collect(),
+        }
+    }
+
+    pub async fn capabilities(&self) -> KanataCapabilities {
+        let inner = self.inner.lock().await;
+        inner.capabilities
+    }
+
+    pub async fn known_virtual_keys(&self) -> Option<Vec<String>> {
+        let inner = self.inner.lock().await;
+        inner.known_virtual_keys.clone()
+    }
+
+    pub fn default_layer_sync(&self) -> String {
+        tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(async {
+                let inner = self.inner.lock().await;
+                inner
+                    .config_default_layer
+                    .clone()
+                    .or_else(|| inner.auto_default_layer.clone())
+                    .unwrap_or_default()
+            })
+        })
+    }
+
+    /// Stop the reader and flush + close the write half so everything written reaches kanata.
+    async fn close(&self) {
+        let mut inner = self.inner.lock().await;
+        // Paused keeps the reader and reconnect loop from reconnecting
+        inner.paused = true;
+        if let Some(handle) = inner.reader_handle.take() {
+            handle.abort();
+        }
+        if let Some(mut writer) = inner.writer.take() {
+            let close = async {
+                let _ = writer.flush().await;
+                let _ = writer.shutdown().await;
+            };
+            if tokio::time::timeout(inner.write_timeout, close).await.is_err() {
+                elog_line!("[Kanata] Timed out flushing the connection on close");
+            }
+        }
+        inner.connected = false;
+    }
+
+    async fn release_virtual_keys_if_connected(&self, virtual_keys: &[String]) {
+        if virtual_keys.is_empty() {
+            return;
+        }
+        if !self.is_connected().await {
+            elog_line!("[Shutdown] Not connected to kanata, skipping virtual key release");
+            return;
+        }
+        for vk in virtual_keys.iter().rev() {
+            if self.act_on_fake_key(vk, "Release").await {
+                log_line!("[Shutdown] Released virtual key \"{}\"", vk);
+            } else {
+                elog_line!("[Shutdown] Failed to release virtual key \"{}\"", vk);
+            }
+        }
+    }
+
+    async fn switch_to_default_if_connected(&self) {
+        let default_layer = self.default_layer().await;
+        let Some(default_layer) = default_layer else {
+            elog_line!("[Shutdown] No default layer known, skipping reset");
+            return;
+        };
+
+        if default_layer.is_empty() {
+            elog_line!("[Shutdown] Default layer is empty, skipping reset");
+            return;
+        }
+
+        let mut inner = self.inner.lock().await;
+        if !inner.connected {
+            elog_line!("[Shutdown] Not connected to kanata, skipping reset");
+            return;
+        }
+
+        if inner.current_layer.as_ref() == Some(&default_layer) {
+            log_line!("[Shutdown] Already on default layer \"{}\"", default_layer);
+            return;
+        }
+
+        let msg = ChangeLayerMsg {
+            change_layer: ChangeLayerPayload {
+                new: default_layer.clone(),
+            },
+        };
+        let json = serde_json::to_string(&msg).unwrap() + "\n";
+
+        if self.send_line(&mut inner, &json).await {
+            log_line!("[Shutdown] Switched to default layer \"{}\"", default_layer);
+        } else {
+            elog_line!("[Shutdown] Failed to send layer change");
+        }
+    }
+}
+
+// === Shutdown Guard ===
+
+/// Upper bound for waiting on in-flight focus actions (including their delays) at shutdown
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+struct ShutdownGuard {
+    kanata: KanataClient,
+    handler: Option<Arc<Mutex<FocusHandler>>>,
+}
+
+impl ShutdownGuard {
+    fn new(kanata: KanataClient, handler: Option<Arc<Mutex<FocusHandler>>>) -> Self {
+        Self { kanata, handler }
+    }
+
+    /// Held virtual keys, and the actions releasing those of rule-targeted endpoints
+    fn take_held_virtual_keys(&self) -> (Vec<String>, Vec<(String, Vec<FocusAction>)>) {
+        let Some(handler) = &self.handler else {
+            return (Vec::new(), Vec::new());
+        };
+        // A poisoned lock still holds valid VK state; keys must be released regardless
+        let mut handler = handler.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let keys = handler.current_virtual_keys();
+        handler.reset();
+        (keys, handler.release_targets())
+    }
+}
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(async {
+                // Let in-flight focus actions finish first so the handler's VK state is final
+                if !self.kanata.drain_in_flight(SHUTDOWN_DRAIN_TIMEOUT).await {
+                    elog_line!("[Shutdown] Timed out waiting for in-flight kanata actions");
+                }
+                let (held_virtual_keys, targeted) = self.take_held_virtual_keys();
+                self.kanata.begin_shutdown().await;
+                self.kanata
+                    .release_virtual_keys_if_connected(&held_virtual_keys)
+                    .await;
+                self.kanata.execute_targeted_actions(targeted).await;
+                self.kanata.switch_to_default_if_connected().await;
+                self.kanata.close().await;
+            })
+        })
+    }
+}
+
+// === Environment Detection ===
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Environment {
+    Gnome,
+    Kde,
+    Wayland,
+    X11,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RunOutcome {
+    Restart,
+    Exit,
+}
+
+/// Why a run ended with an error. `run_once` returns it instead of exiting where the error
+/// happens, so the run's guards still release virtual keys, reset the layer and unload the KWin
+/// script before `run_daemon` exits.
+#[derive(Debug)]
+enum DaemonError {
+    /// Config file missing, invalid or without rules
+    Config(String),
+    /// The GNOME extension is needed but not installed or enabled
+    GnomeExtension(String),
+    /// No supported display environment (WAYLAND_DISPLAY/DISPLAY unset or unknown)
+    NoDisplay,
+    /// Everything propagated with `?`
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl std::fmt::Display for DaemonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DaemonError::Config(error) => write!(f, "Config error: {}", error),
+            DaemonError::GnomeExtension(error) => write!(f, "GNOME extension {}", error),
+            DaemonError::NoDisplay => write!(
+                f,
+                "Could not detect display environment; ensure WAYLAND_DISPLAY or DISPLAY is set"
+            ),
+            DaemonError::Other(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+// Not `std::error::Error` itself, which keeps this blanket conversion coherent (as in anyhow)
+impl<E: Into<Box<dyn std::error::Error + Send + Sync>>> From<E> for DaemonError {
+    fn from(error: E) -> Self {
+        DaemonError::Other(error.into())
+    }
+}
+
+impl Environment {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Environment::Gnome => "gnome",
+            Environment::Kde => "kde",
+            Environment::Wayland => "wayland",
+            Environment::X11 => "x11",
+            Environment::Unknown => "unknown",
+        }
+    }
+}
+
+fn detect_environment() -> Environment {
+    detect_environment_with(session_var)
+}
+
+/// Detection over any variable source: the process environment or a session's
+fn detect_environment_with(var: impl Fn(&str) -> Option<String>) -> Environment {
+    let desktop = var("XDG_CURRENT_DESKTOP").unwrap_or_default().to_lowercase();
+
+    // GNOME - needs special DBus extension
+    if desktop.contains("gnome") || var("GNOME_SETUP_DISPLAY").is_some() {
+        return Environment::Gnome;
+    }
+
+    // KDE - needs KWin script injection
+    if var("KDE_SESSION_VERSION").is_some() {
+        return Environment::Kde;
+    }
+
+    // Wayland compositors (wlr-based or COSMIC) - use toplevel protocol
+    if var("WAYLAND_DISPLAY").is_some() {
+        return Environment::Wayland;
+    }
+
+    // X11 fallback
+    if var("DISPLAY").is_some() {
+        return Environment::X11;
+    }
+
+    Environment::Unknown
+}
+
+// === Backend Capabilities ===
+
+/// Optional window data a focus backend reports (`GetBackendInfo`, `--check-config`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct BackendCapabilities {
+    /// Title changes of the focused window re-run the rules (not just focus changes)
+    title_changes: bool,
+    /// Desktop-file ID for `app` rules (reported by GNOME, looked up elsewhere)
+    app: bool,
+    /// Initial class/title for `initial_class`/`initial_title` rules
+    initial: bool,
+    /// Window size for `min_width`/`max_width`/`min_height`/`max_height` rules
+    size: bool,
+    /// WM_CLASS of XWayland windows for `class_source: x11_class`
+    x11_class: bool,
+    /// Current workspace/virtual desktop name for `workspace` rules
+    workspace: bool,
+    /// WM_CLASS instance and WM_WINDOW_ROLE for `instance`/`role` rules
+    instance_role: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BackendInfo {
+    /// `gnome`, `kde`, `hyprland`, `sway`, `wayland` (other wlroots and COSMIC), `x11` or `unknown`
+    name: &'static str,
+    capabilities: BackendCapabilities,
+}
+
+impl BackendInfo {
+    /// Capability name -> supported, as returned by `GetBackendInfo`. No backend reports
+    /// pid, output or fullscreen state yet; they are listed so clients can rely on the keys
+    fn capability_map(&self) -> BTreeMap<String, bool> {
+        let caps = self.capabilities;
+        [
+            ("title_changes", caps.title_changes),
+            ("app", caps.app),
+            ("initial", caps.initial),
+            ("size", caps.size),
+            ("x11_class", caps.x11_class),
+            ("pid", false),
+            ("workspace", caps.workspace),
+            ("instance", caps.instance_role),
+            ("role", caps.instance_role),
+            ("output", false),
+            ("fullscreen", false),
+        ]
+        .into_iter()
+        .map(|(name, supported)| (name.to_string(), supported))
+        .collect()
+    }
+}
+
+fn backend_info(env: Environment) -> BackendInfo {
+    backend_info_with(env, session_var)
+}
+
+/// Same compositor detection as `query_compositor_window`: Hyprland and Sway have IPC for the rest
+fn backend_info_with(env: Environment, var: impl Fn(&str) -> Option<String>) -> BackendInfo {
+    let (name, capabilities) = match env {
+        Environment::Gnome => (
+            "gnome",
+            BackendCapabilities {
+                app: true,
+                workspace: true,
+                ..Default::default()
+            },
+        ),
+        Environment::Kde => (
+            "kde",
+            BackendCapabilities {
+                app: true,
+                workspace: true,
+                instance_role: true,
+                ..Default::default()
+            },
+        ),
+        Environment::Wayland => {
+            let ipc = var("HYPRLAND_INSTANCE_SIGNATURE")
+                .map(|_| "hyprland")
+                .or_else(|| var("SWAYSOCK").map(|_| "sway"));
+            (
+                ipc.unwrap_or("wayland"),
+                BackendCapabilities {
+                    title_changes: true,
+                    app: true,
+                    initial: true,
+                    size: ipc.is_some(),
+                    x11_class: ipc.is_some(),
+                    workspace: ipc.is_some(),
+                    instance_role: false,
+                },
+            )
+        }
+        Environment::X11 => (
+            "x11",
+            BackendCapabilities {
+                title_changes: false,
+                app: true,
+                initial: true,
+                size: true,
+                x11_class: true,
+                workspace: true,
+                instance_role: true,
+            },
+        ),
+        Environment::Unknown => ("unknown", BackendCapabilities::default()),
+    };
+    BackendInfo { name, capabilities }
+}
+
+/// Rule conditions the backend can't satisfy, for `--check-config`
+fn backend_rule_warnings(config: &Config, backend: &BackendInfo) -> Vec<String> {
+    let caps = backend.capabilities;
+    let mut warnings = Vec::new();
+    for (index, rule) in config.rules.iter().enumerate() {
+        let mut unsupported = Vec::new();
+        if rule.app.is_some() && !caps.app {
+            unsupported.push("app");
+        }
+        if rule.workspace.is_some() && !caps.workspace {
+            unsupported.push("workspace");
+        }
+        if (rule.instance.is_some() || rule.role.is_some()) && !caps.instance_role {
+            unsupported.push("instance/role");
+        }
+        if rule.has_initial_conditions() && !caps.initial {
+            unsupported.push("initial_class/initial_title");
+        }
+        if rule.has_size_conditions() && !caps.size {
+            unsupported.push("size conditions");
+        }
+        if !unsupported.is_empty() {
+            warnings.push(format!(
+                "{}: {} can't match on the {} backend",
+                rule.context(index),
+                unsupported.join(", "),
+                backend.name
+            ));
+        }
+        let class_source = rule.class_source.unwrap_or(config.class_source);
+        if rule.class.is_some() && class_source == ClassSource::X11Class && !caps.x11_class {
+            warnings.push(format!(
+                "{}: class_source x11_class falls back to the app_id on the {} backend",
+                rule.context(index),
+                backend.name
+            ));
+        }
+    }
+    warnings
+}
+
+/// Logs `backend_rule_warnings` at startup and for `--check-config`, so rules that can never
+/// match in this session don't fail silently
+fn warn_unsupported_conditions(config: &Config, env: Environment) {
+    // Outside a desktop session (e.g. in CI) the backend is unknown, so there is nothing to compare
+    if env == Environment::Unknown {
+        return;
+    }
+    for warning in backend_rule_warnings(config, &backend_info(env)) {
+        elog_line!("[Config] Warning: {}", warning);
+    }
+}
+
+// === Task Supervision ===
+
+/// Delays between restarts of a failed backend or monitor task; the last one repeats
+const SUPERVISOR_RESTART_DELAYS_MS: [u64; 5] = [1000, 2000, 5000, 10000, 30000];
+/// A task that has run this long counts as recovered: its degraded state is cleared
+/// and the next failure restarts with the shortest delay again
+const SUPERVISOR_STABLE_RUN: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+struct RestartBackoff {
+    delays: Vec<Duration>,
+    attempt: usize,
+}
+
+impl RestartBackoff {
+    fn new(delays_ms: &[u64]) -> Self {
+        Self {
+            delays: delays_ms.iter().copied().map(Duration::from_millis).collect(),
+            attempt: 0,
+        }
+    }
+
+    /// Delay before restarting a task that failed after running for `ran_for`
+    fn next_delay(&mut self, ran_for: Duration) -> Duration {
+        if ran_for >= SUPERVISOR_STABLE_RUN {
+            self.attempt = 0;
+        }
+        let delay = self.delays[self.attempt.min(self.delays.len() - 1)];
+        self.attempt += 1;
+        delay
+    }
+}
+
+/// Aborts a spawned task when the run that started it ends (restart or exit)
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Runs `task` and clears `component`'s degraded state once it has been up for
+/// `SUPERVISOR_STABLE_RUN`
+async fn run_marking_recovery<T, Fut>(
+    component: &str,
+    status_broadcaster: &StatusBroadcaster,
+    task: Fut,
+) -> T
+where
+    Fut: std::future::Future<Output = T>,
+{
+    tokio::pin!(task);
+    tokio::select! {
+        result = &mut task => result,
+        _ = tokio::time::sleep(SUPERVISOR_STABLE_RUN) => {
+            status_broadcaster.clear_degraded(component);
+            task.await
+        }
+    }
+}
+
+/// Runs the focus backend for `env`, restarting it with backoff when it fails.
+///
+/// Failures are logged and reported as degraded. Before each restart the environment is
+/// detected again; if it changed (e.g. a different session on the same seat), the whole
+/// daemon restarts so the matching backend is set up. Restart and shutdown requests end
+/// the backoff early.
+async fn supervise_backend<F, Fut>(
+    env: Environment,
+    mut backoff: RestartBackoff,
+    detect: fn() -> Environment,
+    status_broadcaster: &StatusBroadcaster,
+    restart_handle: &RestartHandle,
+    shutdown_handle: &ShutdownHandle,
+    mut run: F,
+) -> RunOutcome
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<RunOutcome, Box<dyn std::error::Error + Send + Sync>>>,
+{
+    let component = format!("{} backend", env.as_str());
+    loop {
+        let started = Instant::now();
+        let error = match run_marking_recovery(&component, status_broadcaster, run()).await {
+            Ok(outcome) => {
+                status_broadcaster.clear_degraded(&component);
+                return outcome;
+            }
+            Err(error) => error,
+        };
+        let delay = backoff.next_delay(started.elapsed());
+        elog_line!(
+            "[Supervisor] {} failed: {}; restarting in {:?}",
+            component, error, delay
+        );
+        status_broadcaster.set_degraded(&component, error.to_string());
+
+        tokio::select! {
+            outcome = wait_for_restart_or_shutdown(restart_handle, shutdown_handle) => {
+                return outcome;
+            }
+            _ = tokio::time::sleep(delay) => {}
+        }
+
+        // Unknown means the display variables are gone; keep retrying the current backend
+        let detected = detect();
+        if detected != env && detected != Environment::Unknown {
+            log_line!(
+                "[Supervisor] Environment changed from {} to {}, restarting daemon",
+                env.as_str(),
+                detected.as_str()
+            );
+            return RunOutcome::Restart;
+        }
+        log_line!("[Supervisor] Restarting {}", component);
+    }
+}
+
+// === Session Re-detection ===
+
+/// How often the systemd user manager's environment is compared with the one seen at startup
+const SESSION_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Variables `detect_environment` reads; sessions import them into the systemd user manager
+/// (`systemctl --user import-environment`, `dbus-update-activation-environment --systemd`)
+const SESSION_ENV_VARS: [&str; 5] = [
+    "XDG_CURRENT_DESKTOP",
+    "GNOME_SETUP_DISPLAY",
+    "KDE_SESSION_VERSION",
+    "WAYLAND_DISPLAY",
+    "DISPLAY",
+];
+const SYSTEMD_BUS_NAME: &str = "org.freedesktop.systemd1";
+const SYSTEMD_OBJECT_PATH: &str = "/org/freedesktop/systemd1";
+const SYSTEMD_MANAGER_INTERFACE: &str = "org.freedesktop.systemd1.Manager";
+const KWIN_BUS_NAME: &str = "org.kde.KWin";
+
+/// Session variables recorded by the session watcher, applied before the next run starts
+static PENDING_SESSION_ENV: Mutex<Vec<(String, Option<String>)>> = Mutex::new(Vec::new());
+/// Session variables the watcher moved the daemon to (`None`: unset in the session). They
+/// override the process environment, which is never changed: other threads (tokio workers, the
+/// watchdog) may read it at any time
+static SESSION_ENV_OVERRIDES: RwLock<BTreeMap<String, Option<String>>> =
+    RwLock::new(BTreeMap::new());
+
+/// A `SESSION_ENV_VARS` variable as the daemon should see it: the session watcher's value if it
+/// moved the daemon to a new session, the process environment otherwise
+fn session_var(name: &str) -> Option<String> {
+    let overrides = SESSION_ENV_OVERRIDES.read().unwrap_or_else(|e| e.into_inner());
+    session_var_with(&overrides, name, |name| env::var(name).ok())
+}
+
+fn session_var_with(
+    overrides: &BTreeMap<String, Option<String>>,
+    name: &str,
+    process: impl Fn(&str) -> Option<String>,
+) -> Option<String> {
+    match overrides.get(name) {
+        Some(value) => value.clone(),
+        None => process(name),
+    }
+}
+
+/// Passes the session watcher's variables on to a command the daemon starts
+fn apply_session_env(command: &mut tokio::process::Command) {
+    let overrides = SESSION_ENV_OVERRIDES.read().unwrap_or_else(|e| e.into_inner());
+    for (name, value) in overrides.iter() {
+        match value {
+            Some(value) => command.env(name, value),
+            None => command.env_remove(name),
+        };
+    }
+}
+
+/// The `SESSION_ENV_VARS` entries of a systemd manager `Environment` list (`NAME=value`)
+fn session_env_from_manager(entries: &[String]) -> BTreeMap<String, String> {
+    entries
+        .iter()
+        .filter_map(|entry| entry.split_once('='))
+        .filter(|(name, _)| SESSION_ENV_VARS.contains(name))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+fn wayland_socket_exists(display: &str, runtime_dir: Option<&Path>) -> bool {
+    let display = Path::new(display);
+    if display.is_absolute() {
+        display.exists()
+    } else {
+        runtime_dir.is_some_and(|dir| dir.join(display).exists())
+    }
+}
+
+/// Variable updates that move the daemon to the session in `session`, if it changed since
+/// `startup` and differs from what the process uses.
+///
+/// Only changes after startup count, so a daemon started with deliberately different
+/// variables (e.g. `env -u WAYLAND_DISPLAY kanata-switcher` to force X11) is left alone. A
+/// Wayland session is only followed once its socket exists.
+fn session_env_updates(
+    startup: &BTreeMap<String, String>,
+    session: &BTreeMap<String, String>,
+    process: impl Fn(&str) -> Option<String>,
+    runtime_dir: Option<&Path>,
+) -> Option<Vec<(String, Option<String>)>> {
+    if session == startup {
+        return None;
+    }
+    match detect_environment_with(|name| session.get(name).cloned()) {
+        Environment::Unknown => return None,
+        Environment::Wayland
+            if !session
+                .get("WAYLAND_DISPLAY")
+                .is_some_and(|display| wayland_socket_exists(display, runtime_dir)) =>
+        {
+            return None;
+        }
+        _ => {}
+    }
+    let updates: Vec<(String, Option<String>)> = SESSION_ENV_VARS
+        .iter()
+        .filter(|name| session.get(**name).cloned() != process(name))
+        .map(|name| (name.to_string(), session.get(*name).cloned()))
+        .collect();
+    (!updates.is_empty()).then_some(updates)
+}
+
+/// Compositor whose restart drops what the backend set up in it (extension state, KWin script)
+fn session_compositor_bus_name(env: Environment) -> Option<&'static str> {
+    match env {
+        Environment::Gnome => Some(GNOME_SHELL_BUS_NAME),
+        Environment::Kde => Some(KWIN_BUS_NAME),
+        _ => None,
+    }
+}
+
+async fn read_manager_session_env(
+    manager: &zbus::fdo::PropertiesProxy<'_>,
+) -> Option<BTreeMap<String, String>> {
+    let interface = zbus::names::InterfaceName::from_static_str_unchecked(SYSTEMD_MANAGER_INTERFACE);
+    let value = manager.get(interface, "Environment").await.ok()?;
+    let entries = Vec::<String>::try_from(value).ok()?;
+    Some(session_env_from_manager(&entries))
+}
+
+/// Requests a daemon restart when the session changes under it, so the matching backend is
+/// set up without a manual `--restart`:
+/// - the systemd user manager's display variables change (e.g. a Wayland session starts after
+///   the daemon came up on X11); the new values are applied before the next run
+/// - GNOME Shell or KWin gets a new bus owner (restart, relogin with a lingering user manager)
+///
+/// Without a systemd user manager only the compositor is watched.
+async fn spawn_session_watcher(env: Environment, restart_handle: RestartHandle) -> Option<AbortOnDrop> {
+    match Connection::session().await {
+        Ok(connection) => {
+            watch_session(&connection, env, restart_handle, SESSION_POLL_INTERVAL).await
+        }
+        Err(error) => {
+            elog_line!("[Session] Not watching for session changes: {}", error);
+            None
+        }
+    }
+}
+
+async fn watch_session(
+    connection: &Connection,
+    env: Environment,
+    restart_handle: RestartHandle,
+    poll_interval: Duration,
+) -> Option<AbortOnDrop> {
+    let manager = zbus::fdo::PropertiesProxy::builder(connection)
+        .destination(SYSTEMD_BUS_NAME)
+        .ok()?
+        .path(SYSTEMD_OBJECT_PATH)
+        .ok()?
+        .build()
+        .await
+        .ok()?;
+    let startup = read_manager_session_env(&manager).await;
+    let compositor = session_compositor_bus_name(env);
+    let mut owner_changes = match compositor {
+        Some(name) => match zbus::fdo::DBusProxy::new(connection).await {
+            Ok(proxy) => proxy.receive_name_owner_changed_with_args(&[(0, name)]).await.ok(),
+            Err(_) => None,
+        },
+        None => None,
+    };
+    let runtime_dir = env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from);
+
+    Some(AbortOnDrop(tokio::spawn(async move {
+        let mut poll = tokio::time::interval(poll_interval);
+        poll.tick().await;
+        loop {
+            let owner_change = async {
+                match owner_changes.as_mut() {
+                    Some(changes) => changes.next().await,
+                    None => None,
+                }
+            };
+            tokio::select! {
+                Some(change) = owner_change => {
+                    let Ok(args) = change.args() else { continue };
+                    if args.new_owner().is_none() {
+                        continue;
+                    }
+                    log_line!(
+                        "[Session] {} restarted, restarting daemon",
+                        compositor.unwrap_or_default()
+                    );
+                    restart_handle.request();
+                    return;
+                }
+                _ = poll.tick(), if startup.is_some() => {
+                    let (Some(startup), Some(session)) =
+                        (startup.as_ref(), read_manager_session_env(&manager).await)
+                    else {
+                        continue;
+                    };
+                    let Some(updates) = session_env_updates(
+                        startup,
+                        &session,
+                        session_var,
+                        runtime_dir.as_deref(),
+                    ) else {
+                        continue;
+                    };
+                    log_line!(
+                        "[Session] Session changed ({} -> {}), restarting daemon",
+                        env.as_str(),
+                        detect_environment_with(|name| session.get(name).cloned()).as_str()
+                    );
+                    *PENDING_SESSION_ENV.lock().unwrap_or_else(|e| e.into_inner()) = updates;
+                    restart_handle.request();
+                    return;
+                }
+                else => return,
+            }
+        }
+    })))
+}
+
+/// Moves the variables recorded by the session watcher into `SESSION_ENV_OVERRIDES`, between
+/// runs so a run sees one session throughout
+fn apply_pending_session_env() {
+    let updates = std::mem::take(&mut *PENDING_SESSION_ENV.lock().unwrap_or_else(|e| e.into_inner()));
+    let mut overrides = SESSION_ENV_OVERRIDES.write().unwrap_or_else(|e| e.into_inner());
+    for (name, value) in updates {
+        log_line!("[Session] {}={}", name, value.as_deref().unwrap_or("(unset)"));
+        overrides.insert(name, value);
+    }
+}
+
+/// `WaylandConnection::connect_to_env`, except that a `WAYLAND_DISPLAY` the session watcher
+/// picked up wins over the process environment's
+fn connect_wayland() -> Result<WaylandConnection, Box<dyn std::error::Error + Send + Sync>> {
+    let overridden = SESSION_ENV_OVERRIDES
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get("WAYLAND_DISPLAY")
+        .cloned();
+    let Some(display) = overridden else {
+        return Ok(WaylandConnection::connect_to_env()?);
+    };
+    let display = display.ok_or("WAYLAND_DISPLAY is unset in the session")?;
+    let path = Path::new(&display);
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .ok_or("XDG_RUNTIME_DIR is not set")?
+            .join(path)
+    };
+    let stream = std::os::unix::net::UnixStream::connect(&path)?;
+    Ok(WaylandConnection::from_socket(stream)?)
+}
+
+// === Wayland Toplevel State ===
+
+#[derive(Default)]
+struct ToplevelWindow {
+    app_id: String,
+    title: String,
+    /// First app_id/title announced for the toplevel, i.e. at map time
+    initial_app_id: Option<String>,
+    initial_title: Option<String>,
+}
+
+impl ToplevelWindow {
+    fn set_app_id(&mut self, app_id: String) {
+        self.initial_app_id.get_or_insert_with(|| app_id.clone());
+        self.app_id = app_id;
+    }
+
+    fn set_title(&mut self, title: String) {
+        self.initial_title.get_or_insert_with(|| title.clone());
+        self.title = title;
+    }
+
+    fn initial_identity(&self) -> InitialIdentity {
+        InitialIdentity {
+            class: self.initial_app_id.clone().unwrap_or_default(),
+            title: self.initial_title.clone().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct WaylandState {
+    windows: HashMap<ObjectId, ToplevelWindow>,
+    active_window: Option<ObjectId>,
+}
+
+impl WaylandState {
+    fn get_active_window(&self) -> WindowInfo {
+        self.active_window
+            .as_ref()
+            .and_then(|id| Some((id, self.windows.get(id)?)))
+            .map(|(id, w)| {
+                let compositor = query_compositor_window().unwrap_or_default();
+                WindowInfo {
+                    class: w.app_id.clone(),
+                    title: w.title.clone(),
+                    is_native_terminal: false,
+                    session_inactive: false,
+                    session_locked: false,
+                    size: compositor.size,
+                    initial: compositor.initial.or_else(|| Some(w.initial_identity())),
+                    x11_class: compositor.x11_class,
+                    desktop_id: None,
+                    workspace: compositor.workspace,
+                    instance: None,
+                    role: None,
+                    window_id: Some(id.protocol_id().to_string()),
+                }
+            })
+            .unwrap_or_default()
+    }
+
+    fn list_windows(&self) -> Vec<WindowInfo> {
+        self.windows
+            .values()
+            .map(|w| WindowInfo {
+                class: w.app_id.clone(),
+                title: w.title.clone(),
+                ..Default::default()
+            })
+            .collect()
+    }
+}
+
+// === Compositor IPC (window size, initial identity) ===
+
+const COMPOSITOR_IPC_TIMEOUT: Duration = Duration::from_millis(200);
+const SWAY_IPC_MAGIC: &[u8] = b"i3-ipc";
+const SWAY_IPC_GET_TREE: u32 = 4;
+
+/// Focused window details that wlr-foreign-toplevel doesn't carry
+#[derive(Debug, Default, PartialEq, Eq)]
+struct CompositorWindow {
+    size: Option<WindowSize>,
+    initial: Option<InitialIdentity>,
+    x11_class: Option<String>,
+    workspace: Option<String>,
+}
+
+/// Focused window details from Hyprland or Sway IPC; None on other compositors
+fn query_compositor_window() -> Option<CompositorWindow> {
+    if let Some(signature) = env::var_os("HYPRLAND_INSTANCE_SIGNATURE") {
+        return query_hyprland_window(&signature);
+    }
+    if let Some(socket) = env::var_os("SWAYSOCK") {
+        return query_sway_window(Path::new(&socket));
+    }
+    None
+}
+
+fn connect_compositor_ipc(path: &Path) -> Option<std::os::unix::net::UnixStream> {
+    let stream = std::os::unix::net::UnixStream::connect(path).ok()?;
+    stream.set_read_timeout(Some(COMPOSITOR_IPC_TIMEOUT)).ok()?;
+    stream.set_write_timeout(Some(COMPOSITOR_IPC_TIMEOUT)).ok()?;
+    Some(stream)
+}
+
+fn query_hyprland_window(signature: &std::ffi::OsStr) -> Option<CompositorWindow> {
+    use std::io::{Read, Write};
+
+    // Hyprland >= 0.40 keeps its sockets under XDG_RUNTIME_DIR, older versions under /tmp
+    let runtime_dir = env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from);
+    let mut stream = [runtime_dir, Some(PathBuf::from("/tmp"))]
+        .into_iter()
+        .flatten()
+        .map(|dir| dir.join("hypr").join(signature).join(".socket.sock"))
+        .find_map(|path| connect_compositor_ipc(&path))?;
+    stream.write_all(b"j/activewindow").ok()?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).ok()?;
+    parse_hyprland_window(&reply)
+}
+
+/// `hyprctl -j activewindow` reply: `{"size": [w, h], "initialClass": ..., "initialTitle": ...,
+/// "workspace": {"name": ...}}` (`{}` when nothing is focused)
+fn parse_hyprland_window(reply: &str) -> Option<CompositorWindow> {
+    let value: serde_json::Value = serde_json::from_str(reply).ok()?;
+    let size = match value.get("size")?.as_array()?.as_slice() {
+        [width, height] => Some(WindowSize {
+            width: u32::try_from(width.as_u64()?).ok()?,
+            height: u32::try_from(height.as_u64()?).ok()?,
+        }),
+        _ => None,
+    };
+    let initial = match (value.get("initialClass"), value.get("initialTitle")) {
+        (Some(class), Some(title)) => Some(InitialIdentity {
+            class: class.as_str()?.to_string(),
+            title: title.as_str()?.to_string(),
+        }),
+        _ => None,
+    };
+    // Hyprland takes an XWayland window's class from WM_CLASS
+    let x11_class = match value.get("xwayland").and_then(serde_json::Value::as_bool) {
+        Some(true) => value.get("class").and_then(serde_json::Value::as_str).map(String::from),
+        _ => None,
+    };
+    let workspace = value
+        .pointer("/workspace/name")
+        .and_then(serde_json::Value::as_str)
+        .map(String::from);
+    Some(CompositorWindow {
+        size,
+        initial,
+        x11_class,
+        workspace,
+    })
+}
+
+fn query_sway_window(socket: &Path) -> Option<CompositorWindow> {
+    use std::io::{Read, Write};
+
+    let mut stream = connect_compositor_ipc(socket)?;
+    let mut request = SWAY_IPC_MAGIC.to_vec();
+    request.extend_from_slice(&0u32.to_ne_bytes());
+    request.extend_from_slice(&SWAY_IPC_GET_TREE.to_ne_bytes());
+    stream.write_all(&request).ok()?;
+
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header).ok()?;
+    if &header[..6] != SWAY_IPC_MAGIC {
+        return None;
+    }
+    let length = u32::from_ne_bytes(header[6..10].try_into().ok()?) as usize;
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload).ok()?;
+    let tree: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+    find_sway_focused_window(&tree)
+}
+
+/// Rect, XWayland WM_CLASS (`window_properties.class`) and enclosing workspace name of the
+/// focused node in a sway GET_TREE reply
+fn find_sway_focused_window(node: &serde_json::Value) -> Option<CompositorWindow> {
+    find_sway_focused_node(node, None)
+}
+
+fn find_sway_focused_node(
+    node: &serde_json::Value,
+    workspace: Option<&str>,
+) -> Option<CompositorWindow> {
+    let workspace = match node.get("type").and_then(serde_json::Value::as_str) {
+        Some("workspace") => node.get("name").and_then(serde_json::Value::as_str),
+        _ => workspace,
+    };
+    if node.get("focused").and_then(serde_json::Value::as_bool) == Some(true) {
+        let rect = node.get("rect")?;
+        return Some(CompositorWindow {
+            size: Some(WindowSize {
+                width: u32::try_from(rect.get("width")?.as_u64()?).ok()?,
+                height: u32::try_from(rect.get("height")?.as_u64()?).ok()?,
+            }),
+            initial: None,
+            x11_class: node
+                .pointer("/window_properties/class")
+                .and_then(serde_json::Value::as_str)
+                .map(String::from),
+            workspace: workspace.map(String::from),
+        });
+    }
+    ["nodes", "floating_nodes"]
+        .iter()
+        .filter_map(|key| node.get(*key)?.as_array())
+        .flatten()
+        .find_map(|child| find_sway_focused_node(child, workspace))
+}
+
+// === WLR Protocol Dispatch ===
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for WaylandState {
+    fn event(
+        _: &mut Self,
+        _: &wl_registry::WlRegistry,
+        _: wl_registry::Event,
+        _: &GlobalListContents,
+        _: &WaylandConnection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwlrForeignToplevelManagerV1, ()> for WaylandState {
+    fn event(
+        state: &mut Self,
+        _: &ZwlrForeignToplevelManagerV1,
+        event: zwlr_foreign_toplevel_manager_v1::Event,
+        _: &(),
+        _: &WaylandConnection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let zwlr_foreign_toplevel_manager_v1::Event::Toplevel { toplevel } = event {
+            state
+                .windows
+                .insert(toplevel.id(), ToplevelWindow::default());
+        }
+    }
+
+    wayland_client::event_created_child!(WaylandState, ZwlrForeignToplevelManagerV1, [
+        zwlr_foreign_toplevel_manager_v1::EVT_TOPLEVEL_OPCODE => (ZwlrForeignToplevelHandleV1, ())
+    ]);
+}
+
+impl Dispatch<ZwlrForeignToplevelHandleV1, ()> for WaylandState {
+    fn event(
+        state: &mut Self,
+        handle: &ZwlrForeignToplevelHandleV1,
+        event: zwlr_foreign_toplevel_handle_v1::Event,
+        _: &(),
+        _: &WaylandConnection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_foreign_toplevel_handle_v1::Event::AppId { app_id } => {
+                if let Some(w) = state.windows.get_mut(&handle.id()) {
+                    w.set_app_id(app_id);
+                }
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::Title { title } => {
+                if let Some(w) = state.windows.get_mut(&handle.id()) {
+                    w.set_title(title);
+                }
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::State {
+                state: handle_state,
+            } => {
+                let activated = zwlr_foreign_toplevel_handle_v1::State::Activated as u8;
+                if handle_state.contains(&activated) {
+                    state.active_window = Some(handle.id());
+                } else if state.active_window.as_ref() == Some(&handle.id()) {
+                    // Window lost activation - clear active_window
+                    state.active_window = None;
+                }
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::Closed => {
+                state.windows.remove(&handle.id());
+                if state.active_window.as_ref() == Some(&handle.id()) {
+                    state.active_window = None;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// === COSMIC Protocol Dispatch ===
+
+impl Dispatch<ZcosmicToplevelInfoV1, ()> for WaylandState {
+    fn event(
+        state: &mut Self,
+        _: &ZcosmicToplevelInfoV1,
+        event: zcosmic_toplevel_info_v1::Event,
+        _: &(),
+        _: &WaylandConnection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let zcosmic_toplevel_info_v1::Event::Toplevel { toplevel } = event {
+            state
+                .windows
+                .insert(toplevel.id(), ToplevelWindow::default());
+        }
+    }
+
+    wayland_client::event_created_child!(WaylandState, ZcosmicToplevelInfoV1, [
+        zcosmic_toplevel_info_v1::EVT_TOPLEVEL_OPCODE => (ZcosmicToplevelHandleV1, ())
+    ]);
+}
+
+impl Dispatch<ZcosmicToplevelHandleV1, ()> for WaylandState {
+    fn event(
+        state: &mut Self,
+        handle: &ZcosmicToplevelHandleV1,
+        event: zcosmic_toplevel_handle_v1::Event,
+        _: &(),
+        _: &WaylandConnection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zcosmic_toplevel_handle_v1::Event::AppId { app_id } => {
+                if let Some(w) = state.windows.get_mut(&handle.id()) {
+                    w.set_app_id(app_id);
+                }
+            }
+            zcosmic_toplevel_handle_v1::Event::Title { title } => {
+                if let Some(w) = state.windows.get_mut(&handle.id()) {
+                    w.set_title(title);
+                }
+            }
+            zcosmic_toplevel_handle_v1::Event::State {
+                state: handle_state,
+            } => {
+                // COSMIC: activated = 2
+                let (chunks, _) = handle_state.as_chunks::<4>();
+                let activated = chunks
+                    .iter()
+                    .map(|&chunk| u32::from_ne_bytes(chunk))
+                    .any(|s| s == zcosmic_toplevel_handle_v1::State::Activated as u32);
+                if activated {
+                    state.active_window = Some(handle.id());
+                } else if state.active_window.as_ref() == Some(&handle.id()) {
+                    // Window lost activation - clear active_window
+                    state.active_window = None;
+                }
+            }
+            zcosmic_toplevel_handle_v1::Event::Closed => {
+                state.windows.remove(&handle.id());
+                if state.active_window.as_ref() == Some(&handle.id()) {
+                    state.active_window = None;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Dispatch for workspace types (we ignore these events but need to handle them)
+impl Dispatch<ZcosmicWorkspaceManagerV1, ()> for WaylandState {
+    fn event(
+        _: &mut Self,
+        _: &ZcosmicWorkspaceManagerV1,
+        _: cosmic_workspace::zcosmic_workspace_manager_v1::Event,
+        _: &(),
+        _: &WaylandConnection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+
+    wayland_client::event_created_child!(WaylandState, ZcosmicWorkspaceManagerV1, [
+        cosmic_workspace::zcosmic_workspace_manager_v1::EVT_WORKSPACE_GROUP_OPCODE => (ZcosmicWorkspaceGroupHandleV1, ())
+    ]);
+}
+
+impl Dispatch<ZcosmicWorkspaceGroupHandleV1, ()> for WaylandState {
+    fn event(
+        _: &mut Self,
+        _: &ZcosmicWorkspaceGroupHandleV1,
+        _: cosmic_workspace::zcosmic_workspace_group_handle_v1::Event,
+        _: &(),
+        _: &WaylandConnection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+
+    wayland_client::event_created_child!(WaylandState, ZcosmicWorkspaceGroupHandleV1, [
+        cosmic_workspace::zcosmic_workspace_group_handle_v1::EVT_WORKSPACE_OPCODE => (ZcosmicWorkspaceHandleV1, ())
+    ]);
+}
+
+impl Dispatch<ZcosmicWorkspaceHandleV1, ()> for WaylandState {
+    fn event(
+        _: &mut Self,
+        _: &ZcosmicWorkspaceHandleV1,
+        _: cosmic_workspace::zcosmic_workspace_handle_v1::Event,
+        _: &(),
+        _: &WaylandConnection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+// Dispatch for wl_output (referenced by toplevel protocol)
+impl Dispatch<wayland_client::protocol::wl_output::WlOutput, ()> for WaylandState {
+    fn event(
+        _: &mut Self,
+        _: &wayland_client::protocol::wl_output::WlOutput,
+        _: wayland_client::protocol::wl_output::Event,
+        _: &(),
+        _: &WaylandConnection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+// === Wayland Backend ===
+
+#[derive(Debug, Clone, Copy)]
+enum WaylandProtocol {
+    Wlr,
+    Cosmic,
+}
+
+async fn run_wayland(
+    kanata: KanataClient,
+    handler: Arc<Mutex<FocusHandler>>,
+    status_broadcaster: StatusBroadcaster,
+    pause_broadcaster: PauseBroadcaster,
+    shutdown_handle: ShutdownHandle,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let connection = connect_wayland()?;
+    let (globals, mut queue) = registry_queue_init::<WaylandState>(&connection)?;
+
+    let mut state = WaylandState::default();
+
+    // Try wlr protocol first, fall back to cosmic
+    let protocol = if globals
+        .bind::<ZwlrForeignToplevelManagerV1, _, _>(&queue.handle(), 1..=3, ())
+        .is_ok()
+    {
+        WaylandProtocol::Wlr
+    } else if globals
+        .bind::<ZcosmicToplevelInfoV1, _, _>(&queue.handle(), 1..=1, ())
+        .is_ok()
+    {
+        WaylandProtocol::Cosmic
+    } else {
+        return Err(
+            "No supported toplevel protocol (wlr-foreign-toplevel or cosmic-toplevel-info)".into(),
+        );
+    };
+
+    log_line!("[Wayland] Using {:?} toplevel protocol", protocol);
+
+    // Initial roundtrip to populate state
+    queue.roundtrip(&mut state)?;
+
+    log_line!("[Wayland] Listening for focus events...");
+
+    let raw_fd = connection.as_fd().as_raw_fd();
+    let async_fd = AsyncFd::new(RawFdWatcher::new(raw_fd))?;
+    let mut shutdown_receiver = shutdown_handle.subscribe();
+
+    apply_focus_for_env(
+        Environment::Wayland,
+        None,
+        false,
+        &handler,
+        &status_broadcaster,
+        &pause_broadcaster,
+        &kanata,
+    )
+    .await?;
+
+    loop {
+        if *shutdown_receiver.borrow() {
+            return Ok(());
+        }
+
+        let dispatched = queue.dispatch_pending(&mut state)?;
+        if dispatched > 0 {
+            let win = state.get_active_window();
+            let default_layer = kanata.default_layer_sync();
+            handle_focus_event(
+                &handler,
+                &status_broadcaster,
+                &pause_broadcaster,
+                &win,
+                &kanata,
+                &default_layer,
+            )
+            .await;
+            continue;
+        }
+
+        connection.flush()?;
+        let guard = match queue.prepare_read() {
+            Some(guard) => guard,
+            None => continue,
+        };
+
+        let mut readiness = tokio::select! {
+            _ = shutdown_receiver.changed() => {
+                return Ok(());
+            }
+            readiness = async_fd.readable() => readiness?,
+        };
+
+        let read_result = guard.read();
+        readiness.clear_ready();
+
+        match read_result {
+            Ok(_) => {}
+            Err(WaylandError::Io(error)) if error.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(error) => {
+                elog_line!("[Wayland] Read error: {}", error);
+                return Err(error.into());
+            }
+        }
+
+        let _ = queue.dispatch_pending(&mut state)?;
+        let win = state.get_active_window();
+        let default_layer = kanata.default_layer_sync();
+
+        handle_focus_event(
+            &handler,
+            &status_broadcaster,
+            &pause_broadcaster,
+            &win,
+            &kanata,
+            &default_layer,
+        )
+        .await;
+    }
+}
+
+// === X11 Backend ===
+
+x11rb::atom_manager! {
+    pub X11Atoms: X11AtomsCookie {
+        _NET_WM_NAME,
+        _NET_ACTIVE_WINDOW,
+        _NET_CLIENT_LIST,
+        _NET_CURRENT_DESKTOP,
+        _NET_DESKTOP_NAMES,
+        UTF8_STRING,
+        WM_WINDOW_ROLE,
+    }
+}
+
+struct X11State {
+    connection: RustConnection,
+    root: Window,
+    atoms: X11Atoms,
+    /// Class/title of each window when the daemon first saw it (X11 keeps no history)
+    initial_identities: Mutex<HashMap<Window, InitialIdentity>>,
+}
+
+/// Forget initial identities beyond this many windows (ids of closed windows are never removed)
+const X11_MAX_INITIAL_IDENTITIES: usize = 1024;
+
+/// How often a focus change held off by `defer_during_grab` checks whether the grab ended
+const X11_GRAB_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl X11State {
+    fn new() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let (connection, screen_num) = x11rb::connect(session_var("DISPLAY").as_deref())?;
+        let root = connection.setup().roots[screen_num].root;
+        let atoms = X11Atoms::new(&connection)?.reply()?;
+
+        // Subscribe to PropertyNotify events on root window
+        let attrs = ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE);
+        connection.change_window_attributes(root, &attrs)?;
+        connection.flush()?;
+
+        Ok(Self {
+            connection,
+            root,
+            atoms,
+            initial_identities: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Another client holds an active keyboard grab (open menu, screenshot tool, VM viewer).
+    /// X11 has no query for this, so grab the keyboard ourselves and release it right away;
+    /// passive grabs (WM key bindings) don't count
+    fn keyboard_grabbed(&self) -> bool {
+        let Ok(cookie) = self.connection.grab_keyboard(
+            false,
+            self.root,
+            x11rb::CURRENT_TIME,
+            GrabMode::ASYNC,
+            GrabMode::ASYNC,
+        ) else {
+            return false;
+        };
+        match cookie.reply().map(|reply| reply.status) {
+            Ok(GrabStatus::SUCCESS) => {
+                let _ = self.connection.ungrab_keyboard(x11rb::CURRENT_TIME);
+                let _ = self.connection.flush();
+                false
+            }
+            Ok(status) => status == GrabStatus::ALREADY_GRABBED || status == GrabStatus::FROZEN,
+            Err(_) => false,
+        }
+    }
+
+    fn get_active_window_id(&self) -> Option<Window> {
+        let prop_reply = self
+            .connection
+            .get_property(
+                false,
+                self.root,
+                self.atoms._NET_ACTIVE_WINDOW,
+                AtomEnum::WINDOW,
+                0,
+                1,
+            )
+            .ok()?
+            .reply()
+            .ok()?;
+
+        if prop_reply.type_ == x11rb::NONE || prop_reply.value.len() != 4 {
+            return None;
+        }
+
+        let arr: [u8; 4] = prop_reply.value.clone().try_into().ok()?;
+        let winid = u32::from_le_bytes(arr);
+
+        if winid == 0 { None } else { Some(winid) }
+    }
+
+    fn get_window_size(&self, window: Window) -> Option<WindowSize> {
+        let reply = self.connection.get_geometry(window).ok()?.reply().ok()?;
+        Some(WindowSize {
+            width: reply.width.into(),
+            height: reply.height.into(),
+        })
+    }
+
+    /// WM_CLASS as (instance, class)
+    fn get_window_class(&self, window: Window) -> Option<(String, String)> {
+        let reply = self
+            .connection
+            .get_property(false, window, AtomEnum::WM_CLASS, AtomEnum::STRING, 0, 1024)
+            .ok()?
+            .reply()
+            .ok()?;
+        parse_wm_class(&reply.value)
+    }
+
+    fn get_window_role(&self, window: Window) -> Option<String> {
+        let reply = self
+            .connection
+            .get_property(
+                false,
+                window,
+                self.atoms.WM_WINDOW_ROLE,
+                AtomEnum::STRING,
+                0,
+                1024,
+            )
+            .ok()?
+            .reply()
+            .ok()?;
+        String::from_utf8(reply.value)
+            .ok()
+            .filter(|role| !role.is_empty())
+    }
+
+    fn get_window_title(&self, window: Window) -> Option<String> {
+        // Try _NET_WM_NAME first (UTF-8)
+        let prop_reply = self
+            .connection
+            .get_property(
+                false,
+                window,
+                self.atoms._NET_WM_NAME,
+                self.atoms.UTF8_STRING,
+                0,
+                u32::MAX,
+            )
+            .ok()?
+            .reply()
+            .ok()?;
+
+        if prop_reply.type_ != x11rb::NONE {
+            return String::from_utf8(prop_reply.value).ok();
+        }
+
+        // Fallback to WM_NAME (Latin-1)
+        let prop_reply = self
+            .connection
+            .get_property(
+                false,
+                window,
+                AtomEnum::WM_NAME,
+                AtomEnum::STRING,
+                0,
+                u32::MAX,
+            )
+            .ok()?
+            .reply()
+            .ok()?;
+
+        String::from_utf8(prop_reply.value).ok()
+    }
+
+    /// Name of `_NET_CURRENT_DESKTOP` from `_NET_DESKTOP_NAMES`
+    fn get_current_workspace(&self) -> Option<String> {
+        let index = self
+            .connection
+            .get_property(
+                false,
+                self.root,
+                self.atoms._NET_CURRENT_DESKTOP,
+                AtomEnum::CARDINAL,
+                0,
+                1,
+            )
+            .ok()?
+            .reply()
+            .ok()?
+            .value32()?
+            .next()?;
+        let names = self
+            .connection
+            .get_property(
+                false,
+                self.root,
+                self.atoms._NET_DESKTOP_NAMES,
+                self.atoms.UTF8_STRING,
+                0,
+                u32::MAX,
+            )
+            .ok()
+            .and_then(|cookie| cookie.reply().ok())
+            .map(|reply| reply.value)
+            .unwrap_or_default();
+        Some(x11_desktop_name(&names, index))
+    }
+
+    fn get_active_window(&self) -> WindowInfo {
+        let Some(window_id) = self.get_active_window_id() else {
+            return WindowInfo::default();
+        };
+
+        let (instance, class) = self.get_window_class(window_id).unwrap_or_default();
+        let title = self.get_window_title(window_id).unwrap_or_default();
+        let size = self.get_window_size(window_id);
+        let initial = self.initial_identity(window_id, &class, &title);
+        let x11_class = class.clone();
+
+        WindowInfo {
+            class,
+            title,
+            is_native_terminal: false,
+            session_inactive: false,
+            session_locked: false,
+            size,
+            initial: Some(initial),
+            x11_class: Some(x11_class),
+            desktop_id: None,
+            workspace: self.get_current_workspace(),
+            instance: (!instance.is_empty()).then_some(instance),
+            role: self.get_window_role(window_id),
+            window_id: Some(format!("{:#x}", window_id)),
+        }
+    }
+
+    /// Windows the window manager lists in `_NET_CLIENT_LIST`
+    fn list_windows(&self) -> Vec<WindowInfo> {
+        let windows = self
+            .connection
+            .get_property(
+                false,
+                self.root,
+                self.atoms._NET_CLIENT_LIST,
+                AtomEnum::WINDOW,
+                0,
+                u32::MAX,
+            )
+            .ok()
+            .and_then(|cookie| cookie.reply().ok())
+            .and_then(|reply| reply.value32().map(|ids| ids.collect::<Vec<Window>>()))
+            .unwrap_or_default();
+        windows
+            .into_iter()
+            .map(|window| {
+                let (_, class) = self.get_window_class(window).unwrap_or_default();
+                WindowInfo {
+                    title: self.get_window_title(window).unwrap_or_default(),
+                    x11_class: Some(class.clone()),
+                    class,
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+
+    fn initial_identity(&self, window: Window, class: &str, title: &str) -> InitialIdentity {
+        let mut identities = self.initial_identities.lock().unwrap();
+        if identities.len() >= X11_MAX_INITIAL_IDENTITIES && !identities.contains_key(&window) {
+            identities.clear();
+        }
+        identities
+            .entry(window)
+            .or_insert_with(|| InitialIdentity {
+                class: class.to_string(),
+                title: title.to_string(),
+            })
+            .clone()
+    }
+}
+
+/// (instance, class) from a WM_CLASS value (`"instance\0class\0"`); a lone string is both
+fn parse_wm_class(value: &[u8]) -> Option<(String, String)> {
+    if value.is_empty() {
+        return None;
+    }
+    let mut parts = value.split(|&b| b == 0);
+    let instance = String::from_utf8(parts.next()?.to_vec()).ok()?;
+    match parts.next() {
+        Some(class) => Some((instance, String::from_utf8(class.to_vec()).ok()?)),
+        None => Some((instance.clone(), instance)),
+    }
+}
+
+/// Entry `index` of a NUL-separated `_NET_DESKTOP_NAMES` value; window managers that don't
+/// name their desktops get the 1-based desktop number
+fn x11_desktop_name(names: &[u8], index: u32) -> String {
+    names
+        .split(|&b| b == 0)
+        .nth(index as usize)
+        .and_then(|name| std::str::from_utf8(name).ok())
+        .filter(|name| !name.is_empty())
+        .map_or_else(|| (u64::from(index) + 1).to_string(), String::from)
+}
+
+async fn run_x11(
+    kanata: KanataClient,
+    handler: Arc<Mutex<FocusHandler>>,
+    status_broadcaster: StatusBroadcaster,
+    pause_broadcaster: PauseBroadcaster,
+    shutdown_handle: ShutdownHandle,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let state = X11State::new()?;
+
+    log_line!("[X11] Connected to display");
+
+    apply_focus_for_env(
+        Environment::X11,
+        None,
+        false,
+        &handler,
+        &status_broadcaster,
+        &pause_broadcaster,
+        &kanata,
+    )
+    .await?;
+
+    log_line!("[X11] Listening for focus events...");
+
+    let raw_fd = state.connection.stream().as_raw_fd();
+    let async_fd = AsyncFd::new(RawFdWatcher::new(raw_fd))?;
+    let mut shutdown_receiver = shutdown_handle.subscribe();
+    // A focus change held off until another client's keyboard grab ends (`defer_during_grab`)
+    let mut deferred = false;
+
+    // Event loop - wait for PropertyNotify events on _NET_ACTIVE_WINDOW (and
+    // _NET_CURRENT_DESKTOP, so workspace rules apply when switching desktops)
+    loop {
+        if *shutdown_receiver.borrow() {
+            return Ok(());
+        }
+
+        let mut focus_changed = deferred;
+        while let Some(event) = state.connection.poll_for_event()? {
+            match event {
+                X11Event::PropertyNotify(e)
+                    if e.atom == state.atoms._NET_ACTIVE_WINDOW
+                        || e.atom == state.atoms._NET_CURRENT_DESKTOP =>
+                {
+                    focus_changed = true;
+                }
+                _ => {}
+            }
+        }
+
+        if focus_changed {
+            let defer = handler.lock().unwrap().defer_during_grab();
+            if defer && state.keyboard_grabbed() {
+                if !deferred {
+                    log_line!("[X11] Keyboard grabbed by another client, switching after it ends");
+                }
+                deferred = true;
+            } else {
+                deferred = false;
+                // The window focused now; changes during a grab collapse into this one
+                let win = state.get_active_window();
+                let default_layer = kanata.default_layer_sync();
+
+                handle_focus_event(
+                    &handler,
+                    &status_broadcaster,
+                    &pause_broadcaster,
+                    &win,
+                    &kanata,
+                    &default_layer,
+                )
+                .await;
+            }
+        }
+
+        let mut readiness = tokio::select! {
+            _ = shutdown_receiver.changed() => {
+                return Ok(());
+            }
+            _ = tokio::time::sleep(X11_GRAB_POLL_INTERVAL), if deferred => continue,
+            readiness = async_fd.readable() => readiness?,
+        };
+        readiness.clear_ready();
+    }
+}
+
+fn start_sni_indicator(
+    control: SniControl,
+    kanata: KanataClient,
+    status_broadcaster: StatusBroadcaster,
+    pause_broadcaster: PauseBroadcaster,
+    settings_broadcaster: SettingsBroadcaster,
+    tray_icons: BTreeMap<String, TrayIcon>,
+    theme: IndicatorTheme,
+) -> Option<ksni::Handle<SniIndicator>> {
+    log_line!("[SNI] Starting StatusNotifier indicator");
+    let initial_status = status_broadcaster.snapshot();
+    let show_focus_only = settings_broadcaster.current().focus_only;
+    let (menu_refresh, mut menu_refresh_receiver) = MenuRefresh::new();
+    let control_handle: Arc<dyn SniControlOps> = Arc::new(control);
+    let indicator = SniIndicator {
+        state: SniIndicatorState::new(initial_status, show_focus_only),
+        layer_display_names: status_broadcaster.layer_display_names().clone(),
+        tray_icons,
+        theme,
+        control: control_handle,
+        settings: settings_broadcaster.clone(),
+        menu_refresh,
+    };
+    let service = TrayService::new(indicator);
+    let handle = service.handle();
+
+    let pause_initial = pause_broadcaster.is_paused();
+    handle.update(|state| state.set_paused(pause_initial));
+
+    let status_handle = handle.clone();
+    let mut status_receiver = status_broadcaster.subscribe();
+    tokio::spawn(async move {
+        loop {
+            if status_receiver.changed().await.is_err() {
+                break;
+            }
+            let snapshot = status_receiver.borrow().clone();
+            // Kanata reports its layers on (re)connect, which also publishes the layer
+            let layers = kanata.known_layers().await;
+            status_handle.update(|state| {
+                state.update_status(snapshot);
+                state.state.set_layers(layers);
+            });
+        }
+    });
+
+    let pause_handle = handle.clone();
+    let mut pause_receiver = pause_broadcaster.subscribe();
+    tokio::spawn(async move {
+        loop {
+            if pause_receiver.changed().await.is_err() {
+                break;
+            }
+            let paused = *pause_receiver.borrow();
+            pause_handle.update(|state| state.set_paused(paused));
+        }
+    });
+
+    // Changes from SetSetting (settings window, GNOME prefs); the menu's own toggle is a no-op
+    let settings_handle = handle.clone();
+    let mut settings_receiver = settings_broadcaster.subscribe();
+    tokio::spawn(async move {
+        while settings_receiver.changed().await.is_ok() {
+            let focus_only = settings_receiver.borrow_and_update().focus_only;
+            settings_handle.update(|indicator| {
+                if indicator.state.focus_only_enabled() != focus_only {
+                    indicator.state.set_focus_only(focus_only);
+                    indicator.menu_refresh.notify();
+                }
+            });
+        }
+    });
+
+    let menu_handle = handle.clone();
+    tokio::spawn(async move {
+        loop {
+            if menu_refresh_receiver.changed().await.is_err() {
+                break;
+            }
+            menu_handle.update(|state| state.state.bump_menu_revision());
+        }
+    });
+
+    thread::spawn(move || match service.run() {
+        Ok(()) => log_line!("[SNI] Indicator stopped"),
+        Err(error) => elog_line!("[SNI] Failed to run indicator: {}", error),
+    });
+
+    Some(handle)
+}
+
+struct SniGuard {
+    handle: Option<ksni::Handle<SniIndicator>>,
+}
+
+impl SniGuard {
+    fn new(handle: Option<ksni::Handle<SniIndicator>>) -> Self {
+        Self { handle }
+    }
+}
+
+impl Drop for SniGuard {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            log_line!("[SNI] Shutting down indicator");
+            handle.shutdown();
+        }
+    }
+}
+
+struct DbusControlGuard {
+    _connection: Connection,
+}
+
+impl DbusControlGuard {
+    fn new(connection: Connection) -> Self {
+        Self {
+            _connection: connection,
+        }
+    }
+}
+
+// === GNOME Extension Management ===
+
+/// Path to GNOME extension source relative to repository root
+const GNOME_EXTENSION_SRC_PATH: &str = "src/gnome-extension";
+
+#[cfg(feature = "embed-gnome-extension")]
+macro_rules! gnome_ext_file {
+    ($file:literal) => {
+        concat!("../../", "src/gnome-extension", "/", $file)
+    };
+}
+
+#[cfg(feature = "embed-gnome-extension")]
+const EMBEDDED_EXTENSION_JS: &str = include_str!(gnome_ext_file!("extension.js"));
+#[cfg(feature = "embed-gnome-extension")]
+const EMBEDDED_METADATA_JSON: &str = include_str!(gnome_ext_file!("metadata.json"));
+#[cfg(feature = "embed-gnome-extension")]
+const EMBEDDED_PREFS_JS: &str = include_str!(gnome_ext_file!("prefs.js"));
+#[cfg(feature = "embed-gnome-extension")]
+const EMBEDDED_FORMAT_JS: &str = include_str!(gnome_ext_file!("format.js"));
+#[cfg(feature = "embed-gnome-extension")]
+const EMBEDDED_DBUS_JS: &str = include_str!(gnome_ext_file!("dbus.js"));
+#[cfg(feature = "embed-gnome-extension")]
+const EMBEDDED_FOCUS_JS: &str = include_str!(gnome_ext_file!("focus.js"));
+
+fn get_gnome_extension_fs_path() -> PathBuf {
+    let exe_path = env::current_exe().unwrap();
+    let exe_dir = exe_path.parent().unwrap();
+    exe_dir.join("gnome")
+}
+
+fn gnome_extension_fs_exists() -> bool {
+    let path = get_gnome_extension_fs_path();
+    path.join("extension.js").exists()
+        && path.join("metadata.json").exists()
+        && path.join("prefs.js").exists()
+        && path.join("format.js").exists()
+        && path.join("dbus.js").exists()
+        && path.join("focus.js").exists()
+}
+
+#[cfg(feature = "embed-gnome-extension")]
+fn write_embedded_extension_to_dir(dir: &Path) -> std::io::Result<()> {
+    fs::write(dir.join("extension.js"), EMBEDDED_EXTENSION_JS)?;
+    fs::write(dir.join("metadata.json"), EMBEDDED_METADATA_JSON)?;
+    fs::write(dir.join("prefs.js"), EMBEDDED_PREFS_JS)?;
+    fs::write(dir.join("format.js"), EMBEDDED_FORMAT_JS)?;
+    fs::write(dir.join("dbus.js"), EMBEDDED_DBUS_JS)?;
+    fs::write(dir.join("focus.js"), EMBEDDED_FOCUS_JS)?;
+    Ok(())
+}
+
+enum GnomeDetectionMethod {
+    /// Detected via D-Bus call to org.gnome.Shell.Extensions
+    Dbus,
+    /// Detected via gnome-extensions CLI and gsettings
+    Cli,
+}
+
+struct GnomeExtensionStatus {
+    installed: bool,
+    enabled: bool,
+    /// Extension is active in GNOME Shell (verified via D-Bus)
+    active: bool,
+    /// Raw state from D-Bus (None for CLI detection)
+    /// 1=ENABLED, 2=DISABLED, 3=ERROR, 4=OUT_OF_DATE, 5=DOWNLOADING, 6=INITIALIZED
+    state: Option<u8>,
+    /// How the status was detected
+    method: GnomeDetectionMethod,
+}
+
+fn gnome_state_name(state: u8) -> &'static str {
+    match state {
+        1 => "enabled",
+        2 => "disabled",
+        3 => "error",
+        4 => "out_of_date",
+        5 => "downloading",
+        6 => "initialized",
+        _ => "unknown",
+    }
+}
+
+/// Parse GNOME Shell extension state from D-Bus response.
+/// State values: 1.0=ENABLED, 2.0=DISABLED, 3.0=ERROR, 4.0=OUT_OF_DATE, 5.0=DOWNLOADING, 6.0=INITIALIZED
+#[cfg_attr(test, allow(dead_code))]
+fn parse_gnome_extension_state(
+    body: &HashMap<String, zbus::zvariant::OwnedValue>,
+) -> GnomeExtensionStatus {
+    // State is returned as f64 by GNOME Shell D-Bus API
+    let state_f64: f64 = body
+        .get("state")
+        .and_then(|v| v.downcast_ref::<f64>().ok())
+        .unwrap_or(0.0);
+    let state = state_f64 as u8;
+
+    // State 1 = ENABLED (active)
+    let active = state == 1;
+
+    GnomeExtensionStatus {
+        installed: true,
+        enabled: active,
+        active,
+        state: Some(state),
+        method: GnomeDetectionMethod::Dbus,
+    }
+}
+
+// D-Bus coordinates for GNOME Shell Extensions interface
+const GNOME_SHELL_BUS_NAME: &str = "org.gnome.Shell";
+const GNOME_SHELL_OBJECT_PATH: &str = "/org/gnome/Shell";
+const GNOME_SHELL_EXTENSIONS_INTERFACE: &str = "org.gnome.Shell.Extensions";
+
+/// Quick probe: check if extension is active via D-Bus call to GNOME Shell.
+/// This bypasses filesystem searches and works reliably from systemd services.
+fn gnome_extension_dbus_probe() -> Option<GnomeExtensionStatus> {
+    let connection = match zbus::blocking::Connection::session() {
+        Ok(c) => c,
+        Err(e) => {
+            elog_line!(
+                "[GNOME] D-Bus probe: failed to connect to session bus: {}",
+                e
+            );
+            return None;
+        }
+    };
+    gnome_extension_dbus_probe_with_connection(&connection)
+}
+
+/// Probe using a specific D-Bus connection (for testing with mock services)
+fn gnome_extension_dbus_probe_with_connection(
+    connection: &zbus::blocking::Connection,
+) -> Option<GnomeExtensionStatus> {
+    let reply = match connection.call_method(
+        Some(GNOME_SHELL_BUS_NAME),
+        GNOME_SHELL_OBJECT_PATH,
+        Some(GNOME_SHELL_EXTENSIONS_INTERFACE),
+        "GetExtensionInfo",
+        &(GNOME_EXTENSION_UUID,),
+    ) {
+        Ok(r) => r,
+        Err(e) => {
+            elog_line!("[GNOME] D-Bus probe: GetExtensionInfo call failed: {}", e);
+            return None;
+        }
+    };
+
+    // Response is a dict (a{sv}) with extension info
+    let body: HashMap<String, zbus::zvariant::OwnedValue> = match reply.body().deserialize() {
+        Ok(b) => b,
+        Err(e) => {
+            elog_line!("[GNOME] D-Bus probe: failed to deserialize response: {}", e);
+            return None;
+        }
+    };
+
+    Some(parse_gnome_extension_state(&body))
+}
+
+fn gnome_extension_status() -> GnomeExtensionStatus {
+    // Quick probe: try D-Bus call to GNOME Shell first
+    // This is the most reliable method from systemd services
+    if let Some(status) = gnome_extension_dbus_probe() {
+        return status;
+    }
+
+    // Fallback: CLI tools (may fail from systemd if XDG_DATA_DIRS is incomplete)
+
+    // Check installed via gnome-extensions info (requires XDG_DATA_DIRS)
+    let installed = Command::new("gnome-extensions")
+        .args(["info", GNOME_EXTENSION_UUID])
+        .output()
+        .map(|o| o.status.success())
+        .unwrap_or(false);
+
+    // Check enabled via gsettings (more reliable from systemd services)
+    let enabled = Command::new("gsettings")
+        .args(["get", "org.gnome.shell", "enabled-extensions"])
+        .output()
+        .map(|o| {
+            let stdout = String::from_utf8_lossy(&o.stdout);
+            stdout.contains(GNOME_EXTENSION_UUID)
+        })
+        .unwrap_or(false);
+
+    GnomeExtensionStatus {
+        installed,
+        enabled,
+        active: false,
+        state: None,
+        method: GnomeDetectionMethod::Cli,
+    }
+}
+
+fn print_gnome_extension_install_instructions(reason: &str) {
+    let fs_path = get_gnome_extension_fs_path();
+    let install_steps = if gnome_extension_fs_exists() {
+        format!(
+            r#"Extension files are available at: {}
+
+  gnome-extensions pack "{}" --force --out-dir=/tmp
+  gnome-extensions install "/tmp/{}.shell-extension.zip" --force
+  gnome-extensions enable {}"#,
+            fs_path.display(),
+            fs_path.display(),
+            GNOME_EXTENSION_UUID,
+            GNOME_EXTENSION_UUID
+        )
+    } else {
+        format!(
+            r#"Clone the repository and install:
+
+  git clone https://github.com/7mind/kanata-switcher.git /tmp/kanata-switcher
+  gnome-extensions pack /tmp/kanata-switcher/{} --force --out-dir=/tmp
+  gnome-extensions install "/tmp/{}.shell-extension.zip" --force
+  gnome-extensions enable {}"#,
+            GNOME_EXTENSION_SRC_PATH, GNOME_EXTENSION_UUID, GNOME_EXTENSION_UUID
+        )
+    };
+
+    elog_line!(
+        r#"
+[GNOME] Extension not installed.
+
+{}
+
+To install manually:
+
+{}
+
+Then restart GNOME Shell:
+  - Press Alt+F2, type "r", press Enter (X11 only)
+  - Or log out and log back in (Wayland)
+"#,
+        reason, install_steps
+    );
+}
+
+fn pack_and_install_from_dir(src_dir: &Path, tmp_dir: &Path) -> Result<(), String> {
+    let zip_name = format!("{}.shell-extension.zip", GNOME_EXTENSION_UUID);
+
+    let pack_result = Command::new("gnome-extensions")
+        .args([
+            "pack",
+            src_dir.to_str().unwrap(),
+            "--force",
+            &format!("--out-dir={}", tmp_dir.display()),
+        ])
+        .output();
+
+    if pack_result.is_err() || !pack_result.as_ref().unwrap().status.success() {
+        return Err("gnome-extensions pack failed".to_string());
+    }
+
+    let zip_path = tmp_dir.join(&zip_name);
+    let install_result = Command::new("gnome-extensions")
+        .args(["install", zip_path.to_str().unwrap(), "--force"])
+        .output();
+
+    if install_result.is_err() || !install_result.as_ref().unwrap().status.success() {
+        return Err("gnome-extensions install failed".to_string());
+    }
+
+    Ok(())
+}
+
+#[allow(unused_variables, unused_assignments)]
+fn install_gnome_extension() -> bool {
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let fs_path = get_gnome_extension_fs_path();
+    let mut fs_error: Option<String> = None;
+
+    // Try filesystem first
+    if gnome_extension_fs_exists() {
+        log_line!("[GNOME] Installing from filesystem: {}", fs_path.display());
+        match pack_and_install_from_dir(&fs_path, tmp_dir.path()) {
+            Ok(()) => {
+                log_line!("[GNOME] Extension installed");
+                return true;
+            }
+            Err(e) => {
+                elog_line!("[GNOME] Failed to install from filesystem: {}", e);
+                fs_error = Some(e);
+            }
+        }
+    } else {
+        elog_line!(
+            "[GNOME] Extension files not found at filesystem path: {}",
+            fs_path.display()
+        );
+    }
+
+    // Fallback to embedded extension
+    #[cfg(feature = "embed-gnome-extension")]
+    {
+        elog_line!("[GNOME] Falling back to embedded extension...");
+        let embedded_dir = tmp_dir.path().join("embedded");
+        fs::create_dir_all(&embedded_dir).unwrap();
+
+        if let Err(e) = write_embedded_extension_to_dir(&embedded_dir) {
+            elog_line!("[GNOME] Failed to write embedded extension: {}", e);
+            print_gnome_extension_install_instructions(
+                "Auto-install failed: could not write embedded extension files.",
+            );
+            return false;
+        }
+
+        match pack_and_install_from_dir(&embedded_dir, tmp_dir.path()) {
+            Ok(()) => {
+                log_line!("[GNOME] Extension installed (from embedded)");
+                return true;
+            }
+            Err(e) => {
+                elog_line!("[GNOME] Failed to install from embedded: {}", e);
+                print_gnome_extension_install_instructions(&format!("Auto-install failed: {}", e));
+                return false;
+            }
+        }
+    }
+
+    #[cfg(not(feature = "embed-gnome-extension"))]
+    {
+        let reason = if let Some(e) = fs_error {
+            format!(
+                "Found extension files at {}, but installation failed: {}. \
+                 Cannot fall back to embedded extension (disabled in this build).",
+                fs_path.display(),
+                e
+            )
+        } else {
+            "Extension files not found and embedded extension is disabled in this build."
+                .to_string()
+        };
+        print_gnome_extension_install_instructions(&reason);
+        return false;
+    }
+}
+
+fn enable_gnome_extension() -> bool {
+    let result = Command::new("gnome-extensions")
+        .args(["enable", GNOME_EXTENSION_UUID])
+        .output();
+
+    match result {
+        Ok(output) if output.status.success() => {
+            log_line!("[GNOME] Extension enabled");
+            true
+        }
+        _ => {
+            elog_line!("[GNOME] Failed to enable extension");
+            elog_line!("[GNOME] Try restarting GNOME Shell first:");
+            elog_line!("[GNOME]   - Press Alt+F2, type \"r\", press Enter (X11 only)");
+            elog_line!("[GNOME]   - Or log out and log back in (Wayland)");
+            elog_line!(
+                "[GNOME] Then run: gnome-extensions enable {}",
+                GNOME_EXTENSION_UUID
+            );
+            false
+        }
+    }
+}
+
+fn ensure_gnome_extension(
+    status: &GnomeExtensionStatus,
+    auto_install: bool,
+) -> Result<bool, DaemonError> {
+    // If D-Bus probe confirmed extension is active, we're done
+    if status.active {
+        return Ok(false);
+    }
+
+    if !status.installed {
+        if !auto_install {
+            print_gnome_extension_install_instructions(
+                "Auto-install was disabled (--no-install-gnome-extension).",
+            );
+            return Err(DaemonError::GnomeExtension("not installed".to_string()));
+        }
+
+        log_line!("[GNOME] Extension not installed, installing...");
+        if !install_gnome_extension() {
+            return Err(DaemonError::GnomeExtension("installation failed".to_string()));
+        }
+    }
+
+    if !status.enabled {
+        log_line!("[GNOME] Extension not enabled, enabling...");
+        if !enable_gnome_extension() {
+            return Err(DaemonError::GnomeExtension("could not be enabled".to_string()));
+        }
+        return Ok(true);
+    }
+
+    Ok(!status.installed)
+}
+
+fn print_gnome_extension_status(status: &GnomeExtensionStatus) {
+    let method_str = match status.method {
+        GnomeDetectionMethod::Dbus => "via D-Bus",
+        GnomeDetectionMethod::Cli => "via gnome-extensions",
+    };
+
+    if status.active {
+        log_line!("[GNOME] Extension status: active ({})", method_str);
+    } else {
+        let state_info = status
+            .state
+            .map(|s| format!(", state={}", gnome_state_name(s)))
+            .unwrap_or_default();
+        log_line!(
+            "[GNOME] Extension status: {}, {} ({}{}){}",
+            if status.installed {
+                "installed"
+            } else {
+                "not installed"
+            },
+            if status.enabled {
+                "enabled"
+            } else {
+                "not enabled"
+            },
+            method_str,
+            state_info,
+            if !matches!(status.state, Some(2) | Some(4)) {
+                " - waiting for GNOME Shell..."
+            } else {
+                ""
+            }
+        );
+    }
+}
+
+fn setup_gnome_extension(auto_install: bool) -> Result<(), DaemonError> {
+    // Retry settings for when extension is installed but GNOME Shell is still loading
+    const RETRY_INTERVAL_MS: u64 = 50;
+    const MAX_WAIT_MS: u64 = 30_000;
+    const MAX_RETRIES: u64 = MAX_WAIT_MS / RETRY_INTERVAL_MS;
+
+    let mut status = gnome_extension_status();
+    print_gnome_extension_status(&status);
+
+    // Retry on all states except:
+    // - DISABLED (2): user explicitly disabled the extension
+    // - OUT_OF_DATE (4): extension doesn't support current GNOME Shell version
+    let is_transient_state = |s: Option<u8>| !matches!(s, Some(2) | Some(4));
+
+    if status.installed && !status.active && is_transient_state(status.state) {
+        let initial_state = status.state;
+        let mut elapsed_ms: u64 = 0;
+        for attempt in 0..MAX_RETRIES {
+            std::thread::sleep(std::time::Duration::from_millis(RETRY_INTERVAL_MS));
+            elapsed_ms += RETRY_INTERVAL_MS;
+            status = gnome_extension_status();
+
+            if status.active {
+                log_line!("[GNOME] Extension became active after {}ms", elapsed_ms);
+                print_gnome_extension_status(&status);
+                return Ok(());
+            }
+
+            if !is_transient_state(status.state) {
+                log_line!(
+                    "[GNOME] Extension state changed to {} after {}ms",
+                    status.state.map(gnome_state_name).unwrap_or("unknown"),
+                    elapsed_ms
+                );
+                break;
+            }
+
+            // Log progress every second
+            if (attempt + 1) % 20 == 0 {
+                log_line!(
+                    "[GNOME] Still waiting for extension to load (state={})... ({}ms/{}ms)",
+                    initial_state.map(gnome_state_name).unwrap_or("unknown"),
+                    elapsed_ms,
+                    MAX_WAIT_MS
+                );
+            }
+        }
+
+        if !status.active {
+            print_gnome_extension_status(&status);
+        }
+    }
+
+    let needs_restart = ensure_gnome_extension(&status, auto_install)?;
+
+    if needs_restart {
+        log_line!("[GNOME] Extension installed and enabled.");
+        log_line!("[GNOME] Please restart GNOME Shell to activate the extension.");
+        log_line!("[GNOME]   - Press Alt+F2, type \"r\", press Enter (X11 only)");
+        log_line!("[GNOME]   - Or log out and log back in (Wayland)");
+    }    Ok(())
+}
+
+// === DBus Backend (shared by GNOME and KDE) ===
+
+/// Names that are not instance suffixes: the settings window owns `DBUS_NAME.Settings`
+const RESERVED_DBUS_SUFFIXES: &[&str] = &["Settings"];
+/// DBus signal the GNOME extension broadcasts on every focus change; `--dbus-suffix` instances
+/// listen to it because the extension only calls `WindowFocusWorkspace` on the default instance
+const GNOME_FOCUS_SIGNAL: &str = "FocusChanged";
+
+/// Bus name and object path of this daemon. The default instance uses `DBUS_NAME`/`DBUS_PATH`;
+/// `--dbus-suffix work` runs `DBUS_NAME.work` at `DBUS_PATH/work` so several daemons (e.g. one
+/// per keyboard) can share a session.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct DbusInstance {
+    suffix: Option<String>,
+}
+
+impl DbusInstance {
+    fn new(suffix: Option<&str>) -> Self {
+        Self {
+            suffix: suffix.map(str::to_string),
+        }
+    }
+
+    fn is_default(&self) -> bool {
+        self.suffix.is_none()
+    }
+
+    fn name(&self) -> String {
+        match &self.suffix {
+            Some(suffix) => format!("{}.{}", DBUS_NAME, suffix),
+            None => DBUS_NAME.to_string(),
+        }
+    }
+
+    fn path(&self) -> String {
+        match &self.suffix {
+            Some(suffix) => format!("{}/{}", DBUS_PATH, suffix),
+            None => DBUS_PATH.to_string(),
+        }
+    }
+
+    /// Appended to per-instance file names: "" or "-SUFFIX"
+    fn file_tag(&self) -> String {
+        self.suffix
+            .as_ref()
+            .map(|suffix| format!("-{}", suffix))
+            .unwrap_or_default()
+    }
+}
+
+/// `--dbus-suffix` must be valid both as a bus name element and as an object path element
+fn parse_dbus_suffix(value: &str) -> Result<String, String> {
+    let mut chars = value.chars();
+    let Some(first) = chars.next() else {
+        return Err("suffix must not be empty".to_string());
+    };
+    if first.is_ascii_digit() {
+        return Err("suffix must not start with a digit".to_string());
+    }
+    if !value.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '_') {
+        return Err("suffix may only contain ASCII letters, digits and '_'".to_string());
+    }
+    if RESERVED_DBUS_SUFFIXES.contains(&value) {
+        return Err(format!("\"{}\" is reserved", value));
+    }
+    Ok(value.to_string())
+}
+
+#[derive(Debug)]
+struct DbusWindowFocusService {
+    kanata: KanataClient,
+    handler: Arc<Mutex<FocusHandler>>,
+    runtime_handle: tokio::runtime::Handle,
+    status_broadcaster: StatusBroadcaster,
+    restart_handle: RestartHandle,
+    pause_broadcaster: PauseBroadcaster,
+    settings_broadcaster: SettingsBroadcaster,
+    env: Environment,
+    focus_query_connection: Connection,
+    is_kde6: bool,
+}
+
+impl DbusWindowFocusService {
+    fn focus_window(&self, win: WindowInfo) {
+        if self.pause_broadcaster.is_paused() {
+            return;
+        }
+
+        self.runtime_handle.block_on(async {
+            let default_layer = self.kanata.default_layer().await.unwrap_or_default();
+            handle_focus_event(
+                &self.handler,
+                &self.status_broadcaster,
+                &self.pause_broadcaster,
+                &win,
+                &self.kanata,
+                &default_layer,
+            )
+            .await;
+        });
+    }
+
+    /// Swaps the rules of the config file into the shared handler and re-applies the focused
+    /// window; on error the current rules stay
+    fn reload_config(&self) -> Result<(), String> {
+        let path = self
+            .handler
+            .lock()
+            .unwrap()
+            .config_path()
+            .map(Path::to_path_buf)
+            .ok_or("the daemon has no config file to reload")?;
+        let config = parse_config(&path)?;
+        print_config_loaded(&config, &path);
+        warn_unsupported_conditions(&config, self.env);
+        let endpoints = {
+            let mut handler = self.handler.lock().unwrap();
+            *handler = handler.reloaded(&config);
+            handler.target_endpoints()
+        };
+        match self
+            .runtime_handle
+            .block_on(add_rule_endpoints(&self.kanata, endpoints, &config))
+        {
+            Ok(clients) => {
+                for client in clients {
+                    self.runtime_handle
+                        .spawn(async move { client.connect_with_retry().await });
+                }
+            }
+            Err(error) => {
+                elog_line!("[Reload] Warning: cannot set up a kanata endpoint: {}", error)
+            }
+        }
+        if self.pause_broadcaster.is_paused() {
+            log_line!("[Reload] Paused; the new rules apply when unpaused");
+            return Ok(());
+        }
+        let result = self.runtime_handle.block_on(apply_focus_for_env(
+            self.env,
+            Some(&self.focus_query_connection),
+            self.is_kde6,
+            &self.handler,
+            &self.status_broadcaster,
+            &self.pause_broadcaster,
+            &self.kanata,
+        ));
+        if let Err(error) = result {
+            elog_line!("[Reload] Failed to refresh focus after reload: {}", error);
+            self.status_broadcaster.set_degraded(
+                "reload",
+                format!("failed to refresh focus after reload: {}", error),
+            );
+        } else {
+            self.status_broadcaster.clear_degraded("reload");
+        }
+        Ok(())
+    }
+
+    /// Sends one `ActOnFakeKey` for `PressVirtualKey`/`ReleaseVirtualKey`/`TapVirtualKey`
+    fn virtual_key_action(&self, name: &str, action: &str) -> bool {
+        let kanata = self.kanata.clone();
+        let key = name.to_string();
+        let sent_action = action.to_string();
+        let sent = self
+            .runtime_handle
+            .block_on(async move { kanata.act_on_fake_key(&key, &sent_action).await });
+        if sent {
+            log_line!("[DBus] Virtual key \"{}\" {} via DBus", name, action);
+        }
+        sent
+    }
+}
+
+#[zbus::interface(name = "com.github.kanata.Switcher")]
+impl DbusWindowFocusService {
+    async fn window_focus(&self, window_class: &str, window_title: &str) {
+        self.focus_window(WindowInfo {
+            class: window_class.to_string(),
+            title: window_title.to_string(),
+            ..Default::default()
+        });
+    }
+
+    /// `WindowFocus` plus the app's desktop-file ID (GNOME extension)
+    async fn window_focus_app(&self, window_class: &str, window_title: &str, desktop_id: &str) {
+        self.focus_window(gnome_window_info(
+            window_class.to_string(),
+            window_title.to_string(),
+            desktop_id.to_string(),
+        ));
+    }
+
+    /// `WindowFocusApp` plus the current workspace name (GNOME extension); empty app or
+    /// workspace means unknown
+    async fn window_focus_workspace(
+        &self,
+        window_class: &str,
+        window_title: &str,
+        desktop_id: &str,
+        workspace: &str,
+    ) {
+        self.focus_window(gnome_workspace_window_info(
+            window_class.to_string(),
+            window_title.to_string(),
+            desktop_id.to_string(),
+            workspace.to_string(),
+        ));
+    }
+
+    /// `WindowFocusWorkspace` plus the window's ID (GNOME extension); empty means unknown
+    async fn window_focus_id(
+        &self,
+        window_class: &str,
+        window_title: &str,
+        desktop_id: &str,
+        workspace: &str,
+        window_id: &str,
+    ) {
+        self.focus_window(gnome_identified_window_info(
+            window_class.to_string(),
+            window_title.to_string(),
+            desktop_id.to_string(),
+            workspace.to_string(),
+            window_id.to_string(),
+        ));
+    }
+
+    /// Focus pushed by the daemon's KWin script: class, title, virtual desktop name, WM_CLASS
+    /// instance, window role and `internalId` (empty = unknown)
+    async fn window_focus_kwin(
+        &self,
+        window_class: &str,
+        window_title: &str,
+        workspace: &str,
+        instance: &str,
+        role: &str,
+        window_id: &str,
+    ) {
+        let known = |value: &str| (!value.is_empty()).then(|| value.to_string());
+        self.focus_window(WindowInfo {
+            class: window_class.to_string(),
+            title: window_title.to_string(),
+            workspace: known(workspace),
+            instance: known(instance),
+            role: known(role),
+            window_id: known(window_id),
+            ..Default::default()
+        });
+    }
+
+    /// Layer, held virtual keys, layer source and the rules behind them (as in `FocusChanged`)
+    async fn get_status(&self) -> (String, Vec<String>, String, Vec<i32>) {
+        let snapshot = self.status_broadcaster.snapshot();
+        let matched_rules = snapshot.dbus_matched_rules();
+        (
+            snapshot.layer,
+            snapshot.virtual_keys,
+            snapshot.layer_source.as_str().to_string(),
+            matched_rules,
+        )
+    }
+
+    async fn get_paused(&self) -> bool {
+        self.pause_broadcaster.is_paused()
+    }
+
+    /// The `GetStatus` layer as a property; changes are announced with `PropertiesChanged`
+    #[zbus(property)]
+    async fn current_layer(&self) -> String {
+        self.status_broadcaster.snapshot().layer
+    }
+
+    /// The `GetStatus` held virtual keys as a property
+    #[zbus(property)]
+    async fn virtual_keys(&self) -> Vec<String> {
+        self.status_broadcaster.snapshot().virtual_keys
+    }
+
+    #[zbus(property)]
+    async fn paused(&self) -> bool {
+        self.pause_broadcaster.is_paused()
+    }
+
+    /// Kanata layer name -> display name from the config's `layer_names`
+    async fn get_layer_display_names(&self) -> BTreeMap<String, String> {
+        self.status_broadcaster.layer_display_names().as_map().clone()
+    }
+
+    /// (degraded, problems): runtime failures the daemon recovered from, as "component: problem"
+    async fn get_health(&self) -> (bool, Vec<String>) {
+        let problems = self.status_broadcaster.degraded_problems();
+        (!problems.is_empty(), problems)
+    }
+
+    /// (backend, capability -> supported): which optional window data the focus backend reports
+    async fn get_backend_info(&self) -> (String, BTreeMap<String, bool>) {
+        let info = backend_info(self.env);
+        (info.name.to_string(), info.capability_map())
+    }
+
+    /// The last `lines` lines the daemon printed, oldest first (0 = all kept, up to 1000)
+    async fn get_recent_logs(&self, lines: u32) -> Vec<String> {
+        LOG_BUFFER.recent(lines as usize)
+    }
+
+    async fn get_kanata_connected(&self) -> bool {
+        let kanata = self.kanata.clone();
+        self.runtime_handle
+            .block_on(async move { kanata.is_connected().await })
+    }
+
+    /// Config rule index -> the rule's `name`, for rules that have one
+    async fn get_rule_names(&self) -> BTreeMap<i32, String> {
+        let names = self.handler.lock().unwrap().rule_names();
+        names
+            .into_iter()
+            .map(|(index, name)| (dbus_rule_index(index), name))
+            .collect()
+    }
+
+    /// (rule index, summary, hits) for every rule since the daemon (re)started;
+    /// the on_native_terminal rule is index -1, on_session_inactive -2
+    async fn get_rule_stats(&self) -> Vec<(i32, String, u64)> {
+        let stats = self.handler.lock().unwrap().rule_stats();
+        stats
+            .into_iter()
+            .map(|stat| (dbus_rule_index(stat.index), stat.summary, stat.hits))
+            .collect()
+    }
+
+    /// (messages per second, sent, deferred, coalesced) for `--kanata-rate-limit`;
+    /// a limit of 0 means unlimited, with all counters 0
+    async fn get_rate_limit_stats(&self) -> (u32, u64, u64, u64) {
+        let kanata = self.kanata.clone();
+        let stats = self
+            .runtime_handle
+            .block_on(async move { kanata.rate_limit_stats().await });
+        let (per_second, stats) = stats.unwrap_or_default();
+        (per_second, stats.sent, stats.deferred, stats.coalesced)
+    }
+
+    /// Layer names reported by kanata (empty until connected).
+    async fn get_layers(&self) -> Vec<String> {
+        let kanata = self.kanata.clone();
+        self.runtime_handle
+            .block_on(async move { kanata.known_layers().await })
+    }
+
+    /// Switch to a layer until the next focus change.
+    /// Returns false for layers kanata doesn't know, while another layer is pinned or when
+    /// kanata is not connected.
+    async fn set_layer(&self, layer: &str) -> bool {
+        set_layer_daemon(
+            &self.kanata,
+            &self.status_broadcaster,
+            &self.runtime_handle,
+            layer,
+            "via DBus",
+        )
+    }
+
+    /// Hold kanata on a layer and stop focus-based switching until `UnpinLayer`, staying
+    /// connected; kanata leaving the layer is switched back. False for unknown layers.
+    async fn pin_layer(&self, layer: &str) -> bool {
+        pin_daemon(
+            &self.handler,
+            &self.kanata,
+            &self.runtime_handle,
+            layer,
+            "via DBus",
+        )
+    }
+
+    /// Resume focus-based switching; false when no layer was pinned
+    async fn unpin_layer(&self) -> bool {
+        unpin_daemon(
+            self.env,
+            Some(self.focus_query_connection.clone()),
+            self.is_kde6,
+            &self.pause_broadcaster,
+            &self.handler,
+            &self.status_broadcaster,
+            &self.kanata,
+            &self.runtime_handle,
+            "via DBus",
+        )
+    }
+
+    /// Press a kanata virtual key; it stays held until released (the daemon doesn't track it).
+    /// Returns false for keys kanata doesn't know or when kanata is not connected.
+    async fn press_virtual_key(&self, name: &str) -> bool {
+        self.virtual_key_action(name, "Press")
+    }
+
+    /// Release a kanata virtual key; false as for `PressVirtualKey`
+    async fn release_virtual_key(&self, name: &str) -> bool {
+        self.virtual_key_action(name, "Release")
+    }
+
+    /// Press and release a kanata virtual key; false as for `PressVirtualKey`
+    async fn tap_virtual_key(&self, name: &str) -> bool {
+        self.virtual_key_action(name, "Tap")
+    }
+
+    /// Display settings (`show-focus-layer-only`, `show-top-bar-icon`) for the SNI indicator,
+    /// the GNOME extension and its prefs dialog
+    async fn get_settings(&self) -> BTreeMap<String, OwnedValue> {
+        self.settings_broadcaster
+            .current()
+            .as_map()
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), OwnedValue::from(value)))
+            .collect()
+    }
+
+    /// Changes one display setting and persists it; InvalidArgs for unknown keys or values of
+    /// the wrong type
+    async fn set_setting(&self, key: &str, value: Value<'_>) -> zbus::fdo::Result<()> {
+        let Value::Bool(value) = value else {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "setting '{}' expects a boolean, got '{}'",
+                key,
+                value.value_signature()
+            )));
+        };
+        let changed = self
+            .settings_broadcaster
+            .set(key, value)
+            .map_err(zbus::fdo::Error::InvalidArgs)?;
+        if changed {
+            log_line!("[DBus] Setting {} = {} via DBus", key, value);
+        }
+        Ok(())
+    }
+
+    #[zbus(signal)]
+    async fn setting_changed(
+        signal_emitter: &SignalEmitter<'_>,
+        key: &str,
+        value: &Value<'_>,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn status_changed(
+        signal_emitter: &SignalEmitter<'_>,
+        layer: &str,
+        virtual_keys: &[&str],
+        source: &str,
+        matched_rules: &[i32],
+    ) -> zbus::Result<()>;
+
+    /// `PausedChanged`; the Rust name `paused_changed` belongs to the `Paused` property
+    #[zbus(signal, name = "PausedChanged")]
+    async fn paused_signal(signal_emitter: &SignalEmitter<'_>, paused: bool) -> zbus::Result<()>;
+
+    /// The kanata connection was lost (connected = false) or re-established, with the
+    /// virtual key/reload actions dropped while it was down
+    #[zbus(signal)]
+    async fn kanata_connection_changed(
+        signal_emitter: &SignalEmitter<'_>,
+        connected: bool,
+        dropped_actions: u64,
+    ) -> zbus::Result<()>;
+
+    /// Every line the daemon prints (`kswitchctl logs --follow`)
+    #[zbus(signal)]
+    async fn log_line(signal_emitter: &SignalEmitter<'_>, line: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn focus_changed(
+        signal_emitter: &SignalEmitter<'_>,
+        class: &str,
+        title: &str,
+        native_terminal: bool,
+        matched_rules: &[i32],
+        window_id: &str,
+    ) -> zbus::Result<()>;
+
+    async fn restart(&self) {
+        log_line!("[Restart] Restart requested via DBus");
+        self.restart_handle.request();
+    }
+
+    /// Re-reads the config file and swaps in its rules without restarting: the kanata
+    /// connection, pause state and indicators stay. Entries other than rules need a restart.
+    async fn reload(&self) -> zbus::fdo::Result<()> {
+        log_line!("[Reload] Reload requested via DBus");
+        self.reload_config().map_err(|error| {
+            elog_line!("[Reload] Keeping the current rules: {}", error);
+            zbus::fdo::Error::Failed(error)
+        })
+    }
+
+    async fn pause(&self) {
+        pause_daemon(
+            &self.pause_broadcaster,
+            &self.handler,
+            &self.status_broadcaster,
+            &self.kanata,
+            &self.runtime_handle,
+            "via DBus",
+        );
+    }
+
+    async fn unpause(&self) {
+        unpause_daemon(
+            self.env,
+            Some(self.focus_query_connection.clone()),
+            self.is_kde6,
+            &self.pause_broadcaster,
+            &self.handler,
+            &self.status_broadcaster,
+            &self.kanata,
+            &self.runtime_handle,
+            "via DBus",
+        );
+    }
+}
+
+/// Switcher properties that differ between two status snapshots
+fn status_property_changes<'a>(
+    last: &StatusSnapshot,
+    current: &'a StatusSnapshot,
+) -> HashMap<&'static str, Value<'a>> {
+    let mut changed = HashMap::new();
+    if last.layer != current.layer {
+        changed.insert("CurrentLayer", Value::from(current.layer.as_str()));
+    }
+    if last.virtual_keys != current.virtual_keys {
+        changed.insert("VirtualKeys", Value::from(current.virtual_keys.clone()));
+    }
+    changed
+}
+
+/// `PropertiesChanged` for the switcher interface; nothing is sent for an empty change set
+async fn emit_properties_changed(
+    signal_emitter: &SignalEmitter<'_>,
+    changed: HashMap<&str, Value<'_>>,
+) -> zbus::Result<()> {
+    if changed.is_empty() {
+        return Ok(());
+    }
+    zbus::fdo::Properties::properties_changed(
+        signal_emitter,
+        zbus::names::InterfaceName::from_static_str_unchecked(DBUS_INTERFACE),
+        changed,
+        Cow::Borrowed(&[]),
+    )
+    .await
+}
+
+async fn register_dbus_service(
+    connection: &Connection,
+    focus_query_connection: Connection,
+    env: Environment,
+    is_kde6: bool,
+    kanata: KanataClient,
+    handler: Arc<Mutex<FocusHandler>>,
+    status_broadcaster: StatusBroadcaster,
+    restart_handle: RestartHandle,
+    pause_broadcaster: PauseBroadcaster,
+    settings_broadcaster: SettingsBroadcaster,
+    instance: &DbusInstance,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let service = DbusWindowFocusService {
+        kanata,
+        handler,
+        runtime_handle: tokio::runtime::Handle::current(),
+        status_broadcaster: status_broadcaster.clone(),
+        restart_handle,
+        pause_broadcaster: pause_broadcaster.clone(),
+        settings_broadcaster: settings_broadcaster.clone(),
+        env,
+        focus_query_connection,
+        is_kde6,
+    };
+
+    let path = instance.path();
+    connection.object_server().at(path.as_str(), service).await?;
+
+    connection.request_name(instance.name()).await?;
+
+    let mut receiver = status_broadcaster.subscribe();
+    let signal_emitter = SignalEmitter::new(connection, path)?.into_owned();
+    let initial_status = status_broadcaster.snapshot();
+    let initial_virtual_keys: Vec<&str> = initial_status
+        .virtual_keys
+        .iter()
+        .map(|vk| vk.as_str())
+        .collect();
+    DbusWindowFocusService::status_changed(
+        &signal_emitter,
+        &initial_status.layer,
+        &initial_virtual_keys,
+        initial_status.layer_source.as_str(),
+        &initial_status.dbus_matched_rules(),
+    )
+    .await?;
+    let signal_emitter_task = signal_emitter.clone();
+    tokio::spawn(async move {
+        let mut last = receiver.borrow().clone();
+        loop {
+            if receiver.changed().await.is_err() {
+                break;
+            }
+            let current = receiver.borrow().clone();
+            if current != last {
+                let virtual_keys: Vec<&str> =
+                    current.virtual_keys.iter().map(|vk| vk.as_str()).collect();
+                let _ = DbusWindowFocusService::status_changed(
+                    &signal_emitter_task,
+                    &current.layer,
+                    &virtual_keys,
+                    current.layer_source.as_str(),
+                    &current.dbus_matched_rules(),
+                )
+                .await;
+                let _ = emit_properties_changed(
+                    &signal_emitter_task,
+                    status_property_changes(&last, &current),
+                )
+                .await;
+                last = current;
+            }
+        }
+    });
+
+    let mut pause_receiver = pause_broadcaster.subscribe();
+    let pause_emitter = signal_emitter.clone();
+    DbusWindowFocusService::paused_signal(&pause_emitter, pause_broadcaster.is_paused()).await?;
+    tokio::spawn(async move {
+        let mut last = *pause_receiver.borrow();
+        loop {
+            if pause_receiver.changed().await.is_err() {
+                break;
+            }
+            let current = *pause_receiver.borrow();
+            if current != last {
+                let _ = DbusWindowFocusService::paused_signal(&pause_emitter, current).await;
+                let changed = HashMap::from([("Paused", Value::from(current))]);
+                let _ = emit_properties_changed(&pause_emitter, changed).await;
+                last = current;
+            }
+        }
+    });
+
+    let mut settings_receiver = settings_broadcaster.subscribe();
+    let settings_emitter = signal_emitter.clone();
+    tokio::spawn(async move {
+        let mut last = *settings_receiver.borrow_and_update();
+        while settings_receiver.changed().await.is_ok() {
+            let current = *settings_receiver.borrow_and_update();
+            let last_map = last.as_map();
+            for (key, value) in current.as_map() {
+                if last_map.get(key) != Some(&value) {
+                    let _ = DbusWindowFocusService::setting_changed(
+                        &settings_emitter,
+                        key,
+                        &Value::from(value),
+                    )
+                    .await;
+                }
+            }
+            last = current;
+        }
+    });
+
+    let mut focus_receiver = status_broadcaster.subscribe_focus();
+    let focus_emitter = signal_emitter.clone();
+    tokio::spawn(async move {
+        while focus_receiver.changed().await.is_ok() {
+            let event = focus_receiver.borrow_and_update().clone();
+            let _ = DbusWindowFocusService::focus_changed(
+                &focus_emitter,
+                &event.class,
+                &event.title,
+                event.is_native_terminal,
+                &event.dbus_matched_rules(),
+                event.window_id.as_deref().unwrap_or_default(),
+            )
+            .await;
+        }
+    });
+
+    let mut kanata_connection_receiver = status_broadcaster.subscribe_kanata_connection();
+    let kanata_connection_emitter = signal_emitter.clone();
+    tokio::spawn(async move {
+        while kanata_connection_receiver.changed().await.is_ok() {
+            let event = kanata_connection_receiver.borrow_and_update().clone();
+            let _ = DbusWindowFocusService::kanata_connection_changed(
+                &kanata_connection_emitter,
+                event.connected,
+                event.dropped_actions,
+            )
+            .await;
+        }
+    });
+
+    // LOG_BUFFER outlives restarts; the status broadcaster doesn't, so its closing ends this task
+    let mut log_receiver = LOG_BUFFER.subscribe();
+    let mut log_status_receiver = status_broadcaster.subscribe();
+    let log_emitter = signal_emitter.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                changed = log_status_receiver.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                }
+                line = log_receiver.recv() => match line {
+                    Ok(line) => {
+                        let _ = DbusWindowFocusService::log_line(&log_emitter, &line).await;
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// === GNOME Backend ===
+
+async fn run_gnome(
+    kanata: KanataClient,
+    handler: Arc<Mutex<FocusHandler>>,
+    status_broadcaster: StatusBroadcaster,
+    restart_handle: RestartHandle,
+    pause_broadcaster: PauseBroadcaster,
+    settings_broadcaster: SettingsBroadcaster,
+    shutdown_handle: ShutdownHandle,
+    instance: &DbusInstance,
+) -> Result<RunOutcome, Box<dyn std::error::Error + Send + Sync>> {
+    let connection = Connection::session().await?;
+    let focus_query_connection = Connection::session().await?;
+    register_dbus_service(
+        &connection,
+        focus_query_connection.clone(),
+        Environment::Gnome,
+        false,
+        kanata.clone(),
+        handler.clone(),
+        status_broadcaster.clone(),
+        restart_handle.clone(),
+        pause_broadcaster.clone(),
+        settings_broadcaster.clone(),
+        instance,
+    )
+    .await?;
+
+    apply_focus_for_env(
+        Environment::Gnome,
+        Some(&focus_query_connection),
+        false,
+        &handler,
+        &status_broadcaster,
+        &pause_broadcaster,
+        &kanata,
+    )
+    .await?;
+
+    let _focus_signal_task = if instance.is_default() {
+        None
+    } else {
+        Some(
+            watch_gnome_focus_signals(
+                &connection,
+                kanata.clone(),
+                handler.clone(),
+                status_broadcaster.clone(),
+                pause_broadcaster.clone(),
+            )
+            .await?,
+        )
+    };
+
+    log_line!("[GNOME] Listening for focus events from extension...");
+    let outcome = wait_for_restart_or_shutdown(&restart_handle, &shutdown_handle).await;
+    Ok(outcome)
+}
+
+/// Applies the extension's `FocusChanged` broadcasts. Only `--dbus-suffix` instances need this:
+/// the extension calls `WindowFocusWorkspace` on the default instance directly. Extensions
+/// older than `workspace` rules broadcast (class, title, app) without the workspace.
+async fn watch_gnome_focus_signals(
+    connection: &Connection,
+    kanata: KanataClient,
+    handler: Arc<Mutex<FocusHandler>>,
+    status_broadcaster: StatusBroadcaster,
+    pause_broadcaster: PauseBroadcaster,
+) -> Result<AbortOnDrop, Box<dyn std::error::Error + Send + Sync>> {
+    let proxy = zbus::Proxy::new(
+        connection,
+        GNOME_SHELL_BUS_NAME,
+        GNOME_FOCUS_OBJECT_PATH,
+        GNOME_FOCUS_INTERFACE,
+    )
+    .await?;
+    let mut signals = proxy.receive_signal(GNOME_FOCUS_SIGNAL).await?;
+    Ok(AbortOnDrop(tokio::spawn(async move {
+        while let Some(message) = signals.next().await {
+            let body = message.body();
+            let win = if let Ok((class, title, desktop_id, workspace, window_id)) =
+                body.deserialize::<(String, String, String, String, String)>()
+            {
+                gnome_identified_window_info(class, title, desktop_id, workspace, window_id)
+            } else if let Ok((class, title, desktop_id, workspace)) =
+                body.deserialize::<(String, String, String, String)>()
+            {
+                gnome_workspace_window_info(class, title, desktop_id, workspace)
+            } else if let Ok((class, title, desktop_id)) =
+                body.deserialize::<(String, String, String)>()
+            {
+                gnome_window_info(class, title, desktop_id)
+            } else {
+                continue;
+            };
+            if pause_broadcaster.is_paused() {
+                continue;
+            }
+            let default_layer = kanata.default_layer().await.unwrap_or_default();
+            handle_focus_event(
+                &handler,
+                &status_broadcaster,
+                &pause_broadcaster,
+                &win,
+                &kanata,
+                &default_layer,
+            )
+            .await;
+        }
+    })))
+}
+
+// === KDE Backend ===
+
+#[derive(Debug)]
+struct KwinScriptGuard {
+    connection: Connection,
+    runtime_handle: tokio::runtime::Handle,
+    script_path: String,
+    script_obj_path: OwnedObjectPath,
+    script_interface: String,
+}
+
+impl KwinScriptGuard {
+    fn new(
+        connection: Connection,
+        runtime_handle: tokio::runtime::Handle,
+        script_path: String,
+        script_obj_path: OwnedObjectPath,
+        script_interface: &str,
+    ) -> Self {
+        Self {
+            connection,
+            runtime_handle,
+            script_path,
+            script_obj_path,
+            script_interface: script_interface.to_string(),
+        }
+    }
+}
+
+impl Drop for KwinScriptGuard {
+    fn drop(&mut self) {
+        let connection = self.connection.clone();
+        let runtime_handle = self.runtime_handle.clone();
+        let script_path = self.script_path.clone();
+        let script_obj_path = self.script_obj_path.clone();
+        let script_interface = self.script_interface.clone();
+
+        let cleanup = async move {
+            let stop_result = connection
+                .call_method(
+                    Some("org.kde.KWin"),
+                    script_obj_path.clone(),
+                    Some(script_interface.as_str()),
+                    "stop",
+                    &(),
+                )
+                .await;
+            if let Err(error) = stop_result {
+                elog_line!("[KDE] Warning: Failed to stop KWin script: {}", error);
+            }
+
+            let unload_result = connection
+                .call_method(
+                    Some("org.kde.KWin"),
+                    "/Scripting",
+                    Some("org.kde.kwin.Scripting"),
+                    "unloadScript",
+                    &(&script_path,),
+                )
+                .await;
+            if let Err(error) = unload_result {
+                elog_line!("[KDE] Warning: Failed to unload KWin script: {}", error);
+            }
+        };
+
+        if tokio::runtime::Handle::try_current().is_ok() {
+            tokio::task::block_in_place(|| {
+                runtime_handle.block_on(cleanup);
+            });
+        } else {
+            runtime_handle.block_on(cleanup);
+        }
+
+        if let Err(error) = fs::remove_file(&self.script_path)
+            && error.kind() != std::io::ErrorKind::NotFound
+        {
+            elog_line!("[KDE] Warning: Failed to remove KWin script file: {}", error);
+        }
+    }
+}
+
+async fn run_kde(
+    kanata: KanataClient,
+    handler: Arc<Mutex<FocusHandler>>,
+    status_broadcaster: StatusBroadcaster,
+    restart_handle: RestartHandle,
+    pause_broadcaster: PauseBroadcaster,
+    settings_broadcaster: SettingsBroadcaster,
+    shutdown_handle: ShutdownHandle,
+    instance: &DbusInstance,
+) -> Result<RunOutcome, Box<dyn std::error::Error + Send + Sync>> {
+    let connection = Connection::session().await?;
+    let focus_query_connection = Connection::session().await?;
+    let runtime_handle = tokio::runtime::Handle::current();
+    let is_kde6 = session_var("KDE_SESSION_VERSION").is_some_and(|v| v == "6");
+    register_dbus_service(
+        &connection,
+        focus_query_connection.clone(),
+        Environment::Kde,
+        is_kde6,
+        kanata.clone(),
+        handler.clone(),
+        status_broadcaster.clone(),
+        restart_handle.clone(),
+        pause_broadcaster.clone(),
+        settings_broadcaster.clone(),
+        instance,
+    )
+    .await?;
+
+    apply_focus_for_env(
+        Environment::Kde,
+        Some(&focus_query_connection),
+        is_kde6,
+        &handler,
+        &status_broadcaster,
+        &pause_broadcaster,
+        &kanata,
+    )
+    .await?;
+
+    // Inject KWin script (DBus service is ready to receive calls)
+    let api = if is_kde6 {
+        "windowActivated"
+    } else {
+        "clientActivated"
+    };
+    let active_window = if is_kde6 {
+        "activeWindow"
+    } else {
+        "activeClient"
+    };
+    let kwin_script = format!(
+        r#"function notifyFocus(client) {{
+  callDBus(
+    "{name}",
+    "{path}",
+    "{iface}",
+    "WindowFocusKwin",
+    client ? (client.resourceClass || "") : "",
+    client ? (client.caption || "") : "",
+    {workspace} || "",
+    client ? (client.resourceName || "") : "",
+    client ? (client.windowRole || "") : "",
+    client ? String(client.internalId || "") : ""
+  );
+}}
+workspace.{api}.connect(notifyFocus);
+workspace.currentDesktopChanged.connect(function() {{
+  notifyFocus(workspace.{active});
+}});
+notifyFocus(workspace.{active});
+"#,
+        name = instance.name(),
+        path = instance.path(),
+        iface = DBUS_INTERFACE,
+        workspace = kde_current_workspace_expr(is_kde6),
+        api = api,
+        active = active_window
+    );
+
+    // KWin keys loaded scripts by path, so each instance needs its own file
+    let uid = unsafe { libc::getuid() };
+    let script_path = format!("/tmp/{}{}{}.js", KWIN_SCRIPT_PREFIX, uid, instance.file_tag());
+    fs::write(&script_path, &kwin_script)?;
 
-    if !status.installed {
-        if !auto_install {
-            print_gnome_extension_install_instructions(
-                "Auto-install was disabled (--no-install-gnome-extension).",
-            );
-            std::process::exit(1);
-        }
+    for _ in 0..5 {
+        let result = connection
+            .call_method(
+                Some("org.kde.KWin"),
+                "/Scripting",
+                Some("org.kde.kwin.Scripting"),
+                "loadScript",
+                &(&script_path,),
+            )
+            .await;
 
-        println!("[GNOME] Extension not installed, installing...");
-        if !install_gnome_extension() {
-            std::process::exit(1);
+        if result.is_ok() {
+            break;
         }
+        tokio::time::sleep(Duration::from_secs(1)).await;
     }
 
-    if !status.enabled {
-        println!("[GNOME] Extension not enabled, enabling...");
-        if !enable_gnome_extension() {
-            std::process::exit(1);
-        }
-        return true;
-    }
+    let _ = connection
+        .call_method(
+            Some("org.kde.KWin"),
+            "/Scripting",
+            Some("org.kde.kwin.Scripting"),
+            "unloadScript",
+            &(&script_path,),
+        )
+        .await;
 
-    !status.installed
-}
+    let load_result = connection
+        .call_method(
+            Some("org.kde.KWin"),
+            "/Scripting",
+            Some("org.kde.kwin.Scripting"),
+            "loadScript",
+            &(&script_path,),
+        )
+        .await?;
 
-fn print_gnome_extension_status(status: &GnomeExtensionStatus) {
-    let method_str = match status.method {
-        GnomeDetectionMethod::Dbus => "via D-Bus",
-        GnomeDetectionMethod::Cli => "via gnome-extensions",
+    let script_num: i32 = load_result.body().deserialize()?;
+
+    let script_obj_path_str = if is_kde6 {
+        format!("/Scripting/Script{}", script_num)
+    } else {
+        format!("/{}", script_num)
     };
 
-    if status.active {
-        println!("[GNOME] Extension status: active ({})", method_str);
+    let script_interface = if is_kde6 {
+        "org.kde.kwin.Script"
     } else {
-        let state_info = status
-            .state
-            .map(|s| format!(", state={}", gnome_state_name(s)))
-            .unwrap_or_default();
-        println!(
-            "[GNOME] Extension status: {}, {} ({}{}){}",
-            if status.installed {
-                "installed"
-            } else {
-                "not installed"
-            },
-            if status.enabled {
-                "enabled"
-            } else {
-                "not enabled"
-            },
-            method_str,
-            state_info,
-            if !matches!(status.state, Some(2) | Some(4)) {
-                " - waiting for GNOME Shell..."
-            } else {
-                ""
-            }
-        );
-    }
-}
+        "org.kde.kwin.Scripting"
+    };
 
-fn setup_gnome_extension(auto_install: bool) {
-    // Retry settings for when extension is installed but GNOME Shell is still loading
-    const RETRY_INTERVAL_MS: u64 = 50;
-    const MAX_WAIT_MS: u64 = 30_000;
-    const MAX_RETRIES: u64 = MAX_WAIT_MS / RETRY_INTERVAL_MS;
+    let script_obj_path: OwnedObjectPath = script_obj_path_str.as_str().try_into()?;
 
-    let mut status = gnome_extension_status();
-    print_gnome_extension_status(&status);
+    let _kwin_script_guard = KwinScriptGuard::new(
+        connection.clone(),
+        runtime_handle.clone(),
+        script_path.clone(),
+        script_obj_path.clone(),
+        script_interface,
+    );
 
-    // Retry on all states except:
-    // - DISABLED (2): user explicitly disabled the extension
-    // - OUT_OF_DATE (4): extension doesn't support current GNOME Shell version
-    let is_transient_state = |s: Option<u8>| !matches!(s, Some(2) | Some(4));
+    connection
+        .call_method(
+            Some("org.kde.KWin"),
+            script_obj_path,
+            Some(script_interface),
+            "run",
+            &(),
+        )
+        .await?;
 
-    if status.installed && !status.active && is_transient_state(status.state) {
-        let initial_state = status.state;
-        let mut elapsed_ms: u64 = 0;
-        for attempt in 0..MAX_RETRIES {
-            std::thread::sleep(std::time::Duration::from_millis(RETRY_INTERVAL_MS));
-            elapsed_ms += RETRY_INTERVAL_MS;
-            status = gnome_extension_status();
+    log_line!("[KDE] KWin script injected, listening for window focus events...");
 
-            if status.active {
-                println!("[GNOME] Extension became active after {}ms", elapsed_ms);
-                print_gnome_extension_status(&status);
-                return;
-            }
+    let outcome = wait_for_restart_or_shutdown(&restart_handle, &shutdown_handle).await;
+    Ok(outcome)
+}
 
-            if !is_transient_state(status.state) {
-                println!(
-                    "[GNOME] Extension state changed to {} after {}ms",
-                    status.state.map(gnome_state_name).unwrap_or("unknown"),
-                    elapsed_ms
-                );
-                break;
-            }
+// === Backend Dispatch ===
 
-            // Log progress every second
-            if (attempt + 1) % 20 == 0 {
-                println!(
-                    "[GNOME] Still waiting for extension to load (state={})... ({}ms/{}ms)",
-                    initial_state.map(gnome_state_name).unwrap_or("unknown"),
-                    elapsed_ms,
-                    MAX_WAIT_MS
-                );
-            }
+/// One supervised backend run: the backend's `run_*` with clones of the shared daemon state,
+/// so the supervisor can start it again after a failure
+#[allow(clippy::too_many_arguments)]
+async fn run_backend(
+    env: Environment,
+    kanata: KanataClient,
+    handler: Arc<Mutex<FocusHandler>>,
+    status_broadcaster: StatusBroadcaster,
+    restart_handle: RestartHandle,
+    pause_broadcaster: PauseBroadcaster,
+    settings_broadcaster: SettingsBroadcaster,
+    shutdown_handle: ShutdownHandle,
+    instance: &DbusInstance,
+) -> Result<RunOutcome, Box<dyn std::error::Error + Send + Sync>> {
+    match env {
+        Environment::Gnome => {
+            run_gnome(
+                kanata,
+                handler,
+                status_broadcaster,
+                restart_handle,
+                pause_broadcaster,
+                settings_broadcaster,
+                shutdown_handle,
+                instance,
+            )
+            .await
         }
-
-        if !status.active {
-            print_gnome_extension_status(&status);
+        Environment::Kde => {
+            run_kde(
+                kanata,
+                handler,
+                status_broadcaster,
+                restart_handle,
+                pause_broadcaster,
+                settings_broadcaster,
+                shutdown_handle,
+                instance,
+            )
+            .await
         }
+        // The toplevel/X11 loops only watch for shutdown; a restart request ends them here
+        Environment::Wayland => tokio::select! {
+            result = run_wayland(
+                kanata,
+                handler,
+                status_broadcaster,
+                pause_broadcaster,
+                shutdown_handle,
+            ) => result.map(|()| RunOutcome::Exit),
+            _ = wait_for_restart(&restart_handle) => Ok(RunOutcome::Restart),
+        },
+        Environment::X11 => tokio::select! {
+            result = run_x11(
+                kanata,
+                handler,
+                status_broadcaster,
+                pause_broadcaster,
+                shutdown_handle,
+            ) => result.map(|()| RunOutcome::Exit),
+            _ = wait_for_restart(&restart_handle) => Ok(RunOutcome::Restart),
+        },
+        Environment::Unknown => Err("could not detect display environment".into()),
     }
+}
 
-    let needs_restart = ensure_gnome_extension(&status, auto_install);
+// === Hardening (--harden) ===
+
+// Landlock filesystem rights (linux/landlock.h). Only write-type rights are handled, so reads,
+// directory listing and exec stay unrestricted; REFER needs ABI 2 and TRUNCATE ABI 3.
+const LANDLOCK_ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const LANDLOCK_ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const LANDLOCK_ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const LANDLOCK_ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+const LANDLOCK_ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const LANDLOCK_ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const LANDLOCK_ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const LANDLOCK_ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+const LANDLOCK_ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+const LANDLOCK_ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+const LANDLOCK_ACCESS_FS_REFER: u64 = 1 << 13;
+const LANDLOCK_ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+/// Rights that may be granted on a regular file (the rest only apply to directories)
+const LANDLOCK_FILE_ACCESS: u64 = LANDLOCK_ACCESS_FS_WRITE_FILE | LANDLOCK_ACCESS_FS_TRUNCATE;
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_uint = 1;
+
+#[repr(C)]
+struct LandlockRulesetAttr {
+    handled_access_fs: u64,
+}
 
-    if needs_restart {
-        println!("[GNOME] Extension installed and enabled.");
-        println!("[GNOME] Please restart GNOME Shell to activate the extension.");
-        println!("[GNOME]   - Press Alt+F2, type \"r\", press Enter (X11 only)");
-        println!("[GNOME]   - Or log out and log back in (Wayland)");
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// AUDIT_ARCH_* value the seccomp filter expects in `seccomp_data.arch`
+#[cfg(target_arch = "x86_64")]
+const SECCOMP_AUDIT_ARCH: Option<u32> = Some(0xC000_003E);
+#[cfg(target_arch = "aarch64")]
+const SECCOMP_AUDIT_ARCH: Option<u32> = Some(0xC000_00B7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const SECCOMP_AUDIT_ARCH: Option<u32> = None;
+/// x32 syscalls share the x86_64 audit arch and are told apart by this bit in the number
+const SECCOMP_X32_SYSCALL_BIT: u32 = 0x4000_0000;
+/// Offsets into `struct seccomp_data`
+const SECCOMP_DATA_NR_OFFSET: u32 = 0;
+const SECCOMP_DATA_ARCH_OFFSET: u32 = 4;
+
+/// Problems hit while applying `--harden`, reported as degraded mode once the daemon is up
+static HARDEN_PROBLEMS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Syscalls `--harden` refuses with EPERM: debugging other processes, kernel modules and
+/// kexec, mounts and namespaces, keyrings, clocks, BPF and perf. None of them are used by the
+/// daemon, its backends or the helpers it spawns.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn harden_denied_syscalls() -> Vec<libc::c_long> {
+    let mut syscalls = vec![
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_kexec_load,
+        libc::SYS_kexec_file_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_reboot,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_setns,
+        libc::SYS_unshare,
+        libc::SYS_acct,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+        libc::SYS_clock_adjtime,
+        libc::SYS_adjtimex,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_name_to_handle_at,
+        libc::SYS_quotactl,
+        libc::SYS_syslog,
+        libc::SYS_mount_setattr,
+        libc::SYS_fsopen,
+        libc::SYS_fsmount,
+        libc::SYS_fsconfig,
+        libc::SYS_fspick,
+        libc::SYS_move_mount,
+        libc::SYS_open_tree,
+    ];
+    #[cfg(target_arch = "x86_64")]
+    syscalls.extend([libc::SYS_iopl, libc::SYS_ioperm, libc::SYS_modify_ldt]);
+    syscalls
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn harden_denied_syscalls() -> Vec<libc::c_long> {
+    Vec::new()
+}
+
+/// Directories that stay writable under `--harden`: the runtime dir (sockets), the state dir,
+/// the temp dirs (KWin scripts, GNOME extension staging), the GNOME extensions dir (extension
+/// install/update) and, with `--sync-autostart`, the autostart dir. Missing ones are skipped
+/// when the rules are added.
+fn harden_write_dirs(sync_autostart: bool) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        dirs.push(PathBuf::from(runtime_dir));
+    }
+    if let Ok(dir) = state_dir() {
+        dirs.push(dir);
+    }
+    dirs.push(env::temp_dir());
+    dirs.push(PathBuf::from("/tmp"));
+    if let Ok(data_home) = xdg_data_home() {
+        dirs.push(data_home.join("gnome-shell").join("extensions"));
     }
+    if sync_autostart && let Ok(dir) = autostart_dir() {
+        dirs.push(dir);
+    }
+    let mut seen = std::collections::HashSet::new();
+    dirs.retain(|dir| seen.insert(dir.clone()));
+    dirs
 }
 
-// === DBus Backend (shared by GNOME and KDE) ===
+/// Write-type rights handled for a Landlock ABI version
+fn landlock_write_access(abi: i64) -> u64 {
+    let mut access = LANDLOCK_ACCESS_FS_WRITE_FILE
+        | LANDLOCK_ACCESS_FS_REMOVE_DIR
+        | LANDLOCK_ACCESS_FS_REMOVE_FILE
+        | LANDLOCK_ACCESS_FS_MAKE_CHAR
+        | LANDLOCK_ACCESS_FS_MAKE_DIR
+        | LANDLOCK_ACCESS_FS_MAKE_REG
+        | LANDLOCK_ACCESS_FS_MAKE_SOCK
+        | LANDLOCK_ACCESS_FS_MAKE_FIFO
+        | LANDLOCK_ACCESS_FS_MAKE_BLOCK
+        | LANDLOCK_ACCESS_FS_MAKE_SYM;
+    if abi >= 2 {
+        access |= LANDLOCK_ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        access |= LANDLOCK_ACCESS_FS_TRUNCATE;
+    }
+    access
+}
 
-#[derive(Debug)]
-struct DbusWindowFocusService {
-    kanata: KanataClient,
-    handler: Arc<Mutex<FocusHandler>>,
-    runtime_handle: tokio::runtime::Handle,
-    status_broadcaster: StatusBroadcaster,
-    restart_handle: RestartHandle,
-    pause_broadcaster: PauseBroadcaster,
-    env: Environment,
-    focus_query_connection: Connection,
-    is_kde6: bool,
+fn bpf_stmt(code: u32, k: u32) -> libc::sock_filter {
+    bpf_jump(code, k, 0, 0)
 }
 
-#[zbus::interface(name = "com.github.kanata.Switcher")]
-impl DbusWindowFocusService {
-    async fn window_focus(&self, window_class: &str, window_title: &str) {
-        let win = WindowInfo {
-            class: window_class.to_string(),
-            title: window_title.to_string(),
-            is_native_terminal: false,
-        };
+fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
 
-        if self.pause_broadcaster.is_paused() {
-            return;
-        }
+/// Classic BPF program for seccomp: syscalls from other ABIs (i386 or x32 entry points) and the
+/// `denied` ones fail with EPERM, everything else is allowed.
+fn seccomp_deny_filter(arch: u32, denied: &[u32], check_x32: bool) -> Vec<libc::sock_filter> {
+    let deny_jumps = denied.len() + usize::from(check_x32);
+    // arch load, arch check, nr load, deny jumps, allow
+    let deny_index = 3 + deny_jumps + 1;
+    let jump_to_deny = |index: usize| (deny_index - index - 1) as u8;
+
+    let mut program = vec![
+        bpf_stmt(
+            libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+            SECCOMP_DATA_ARCH_OFFSET,
+        ),
+        bpf_jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            arch,
+            0,
+            jump_to_deny(1),
+        ),
+        bpf_stmt(
+            libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+            SECCOMP_DATA_NR_OFFSET,
+        ),
+    ];
+    if check_x32 {
+        let index = program.len();
+        program.push(bpf_jump(
+            libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+            SECCOMP_X32_SYSCALL_BIT,
+            jump_to_deny(index),
+            0,
+        ));
+    }
+    for &nr in denied {
+        let index = program.len();
+        program.push(bpf_jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            nr,
+            jump_to_deny(index),
+            0,
+        ));
+    }
+    program.push(bpf_stmt(
+        libc::BPF_RET | libc::BPF_K,
+        libc::SECCOMP_RET_ALLOW,
+    ));
+    program.push(bpf_stmt(
+        libc::BPF_RET | libc::BPF_K,
+        libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32 & libc::SECCOMP_RET_DATA),
+    ));
+    program
+}
 
-        let default_layer = self
-            .runtime_handle
-            .block_on(async { self.kanata.default_layer().await })
-            .unwrap_or_default();
+fn set_no_new_privs() -> Result<(), String> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(format!(
+            "prctl(PR_SET_NO_NEW_PRIVS) failed: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
 
-        let actions = self.runtime_handle.block_on(async {
-            update_status_for_focus(
-                &self.handler,
-                &self.status_broadcaster,
-                &win,
-                &self.kanata,
-                &default_layer,
-            )
-            .await
-        });
+fn landlock_add_path_rule(ruleset: RawFd, path: &Path, access: u64) -> Result<(), String> {
+    use std::os::unix::fs::OpenOptionsExt;
 
-        if let Some(actions) = actions {
-            let kanata = self.kanata.clone();
-            self.runtime_handle
-                .block_on(async { execute_focus_actions(&kanata, actions).await });
-        }
+    let file = match fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(format!("{}: {}", path.display(), error)),
+    };
+    let rule = LandlockPathBeneathAttr {
+        allowed_access: access,
+        parent_fd: file.as_raw_fd(),
+    };
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset,
+            LANDLOCK_RULE_PATH_BENEATH,
+            &rule as *const LandlockPathBeneathAttr,
+            0 as libc::c_uint,
+        )
+    };
+    if result != 0 {
+        return Err(format!(
+            "landlock_add_rule({}) failed: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        ));
     }
+    Ok(())
+}
 
-    async fn get_status(&self) -> (String, Vec<String>, String) {
-        let snapshot = self.status_broadcaster.snapshot();
-        (
-            snapshot.layer,
-            snapshot.virtual_keys,
-            snapshot.layer_source.as_str().to_string(),
+/// Limits writes of this thread and every thread or child started later to `dirs` (plus
+/// /dev/null). Must run before the tokio runtime spawns its workers.
+fn restrict_writes(dirs: &[PathBuf]) -> Result<(), String> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<LandlockRulesetAttr>(),
+            0usize,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        return Err(format!(
+            "Landlock unavailable: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    let handled = landlock_write_access(abi);
+    let attr = LandlockRulesetAttr {
+        handled_access_fs: handled,
+    };
+    let ruleset_fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const LandlockRulesetAttr,
+            std::mem::size_of::<LandlockRulesetAttr>(),
+            0 as libc::c_uint,
         )
+    };
+    if ruleset_fd < 0 {
+        return Err(format!(
+            "landlock_create_ruleset failed: {}",
+            std::io::Error::last_os_error()
+        ));
     }
+    let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset_fd as RawFd) };
 
-    async fn get_paused(&self) -> bool {
-        self.pause_broadcaster.is_paused()
+    for dir in dirs {
+        landlock_add_path_rule(ruleset.as_raw_fd(), dir, handled)?;
     }
+    landlock_add_path_rule(
+        ruleset.as_raw_fd(),
+        Path::new("/dev/null"),
+        handled & LANDLOCK_FILE_ACCESS,
+    )?;
 
-    #[zbus(signal)]
-    async fn status_changed(
-        signal_emitter: &SignalEmitter<'_>,
-        layer: &str,
-        virtual_keys: &[&str],
-        source: &str,
-    ) -> zbus::Result<()>;
-
-    #[zbus(signal)]
-    async fn paused_changed(signal_emitter: &SignalEmitter<'_>, paused: bool) -> zbus::Result<()>;
+    set_no_new_privs()?;
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_restrict_self,
+            ruleset.as_raw_fd(),
+            0 as libc::c_uint,
+        )
+    };
+    if result != 0 {
+        return Err(format!(
+            "landlock_restrict_self failed: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
 
-    async fn restart(&self) {
-        println!("[Restart] Restart requested via DBus");
-        self.restart_handle.request();
+fn restrict_syscalls() -> Result<(), String> {
+    let arch = SECCOMP_AUDIT_ARCH.ok_or("seccomp filter not available on this architecture")?;
+    let denied: Vec<u32> = harden_denied_syscalls()
+        .into_iter()
+        .map(|nr| nr as u32)
+        .collect();
+    let program = seccomp_deny_filter(arch, &denied, cfg!(target_arch = "x86_64"));
+    let prog = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_ptr() as *mut libc::sock_filter,
+    };
+    set_no_new_privs()?;
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &prog as *const libc::sock_fprog,
+        )
+    };
+    if result != 0 {
+        return Err(format!(
+            "seccomp filter failed: {}",
+            std::io::Error::last_os_error()
+        ));
     }
+    Ok(())
+}
 
-    async fn pause(&self) {
-        pause_daemon(
-            &self.pause_broadcaster,
-            &self.handler,
-            &self.status_broadcaster,
-            &self.kanata,
-            &self.runtime_handle,
-            "via DBus",
-        );
+/// Applies `--harden` to the whole process. Failures don't stop the daemon; they are kept in
+/// `HARDEN_PROBLEMS` and reported as degraded mode.
+fn harden(sync_autostart: bool) {
+    if let Ok(dir) = state_dir()
+        && let Err(error) = fs::create_dir_all(&dir)
+    {
+        elog_line!("[Harden] Failed to create {}: {}", dir.display(), error);
+    }
+    let dirs = harden_write_dirs(sync_autostart);
+    let mut problems = Vec::new();
+    match restrict_writes(&dirs) {
+        Ok(()) => {
+            let dirs: Vec<String> = dirs.iter().map(|dir| dir.display().to_string()).collect();
+            log_line!("[Harden] Filesystem writes limited to {}", dirs.join(", "));
+        }
+        Err(error) => {
+            elog_line!("[Harden] Filesystem restriction not applied: {}", error);
+            problems.push(error);
+        }
+    }
+    match restrict_syscalls() {
+        Ok(()) => log_line!("[Harden] Seccomp filter installed"),
+        Err(error) => {
+            elog_line!("[Harden] Syscall restriction not applied: {}", error);
+            problems.push(error);
+        }
     }
+    let _ = HARDEN_PROBLEMS.set(problems);
+}
 
-    async fn unpause(&self) {
-        unpause_daemon(
-            self.env,
-            Some(self.focus_query_connection.clone()),
-            self.is_kde6,
-            &self.pause_broadcaster,
-            &self.handler,
-            &self.status_broadcaster,
-            &self.kanata,
-            &self.runtime_handle,
-            "via DBus",
-        );
+// === systemd Notifications ===
+
+/// Set by systemd for `Type=notify` services: the socket `sd_notify` messages go to
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+
+/// `sd_notify(3)`: send `state` (newline-separated `KEY=value` lines) to the service manager.
+/// Does nothing outside a `Type=notify` unit; failures only log.
+fn sd_notify(state: &str) {
+    let Some(socket) = env::var_os(NOTIFY_SOCKET_ENV) else {
+        return;
+    };
+    if let Err(error) = send_sd_notify(Path::new(&socket), state) {
+        elog_line!("[Systemd] Failed to notify the service manager: {}", error);
     }
 }
 
-async fn register_dbus_service(
-    connection: &Connection,
-    focus_query_connection: Connection,
-    env: Environment,
-    is_kde6: bool,
-    kanata: KanataClient,
-    handler: Arc<Mutex<FocusHandler>>,
-    status_broadcaster: StatusBroadcaster,
-    restart_handle: RestartHandle,
-    pause_broadcaster: PauseBroadcaster,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let service = DbusWindowFocusService {
-        kanata,
-        handler,
-        runtime_handle: tokio::runtime::Handle::current(),
-        status_broadcaster: status_broadcaster.clone(),
-        restart_handle,
-        pause_broadcaster: pause_broadcaster.clone(),
-        env,
-        focus_query_connection,
-        is_kde6,
+/// `socket` is a path or, with a leading `@`, an abstract socket name
+fn send_sd_notify(socket: &Path, state: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let address = match socket.as_os_str().as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
     };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
 
-    connection
-        .object_server()
-        .at("/com/github/kanata/Switcher", service)
-        .await?;
+/// CLOCK_MONOTONIC in microseconds, for `RELOADING=1`
+fn monotonic_usec() -> u64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000
+}
 
-    connection
-        .request_name("com.github.kanata.Switcher")
-        .await?;
+/// Watchdog ping interval: half of `WATCHDOG_USEC`, when systemd set it for this process
+/// (`WATCHDOG_PID` unset or equal to `own_pid`)
+fn systemd_watchdog_interval(
+    usec: Option<&str>,
+    pid: Option<&str>,
+    own_pid: u32,
+) -> Option<Duration> {
+    if let Some(pid) = pid
+        && pid.trim().parse::<u32>().ok() != Some(own_pid)
+    {
+        return None;
+    }
+    let usec = usec?.trim().parse::<u64>().ok().filter(|usec| *usec > 0)?;
+    Some(Duration::from_micros(usec / 2))
+}
 
-    let mut receiver = status_broadcaster.subscribe();
-    let signal_emitter =
-        SignalEmitter::new(connection, "/com/github/kanata/Switcher")?.into_owned();
-    let initial_status = status_broadcaster.snapshot();
-    let initial_virtual_keys: Vec<&str> = initial_status
-        .virtual_keys
-        .iter()
-        .map(|vk| vk.as_str())
-        .collect();
-    DbusWindowFocusService::status_changed(
-        &signal_emitter,
-        &initial_status.layer,
-        &initial_virtual_keys,
-        initial_status.layer_source.as_str(),
-    )
-    .await?;
-    let signal_emitter_task = signal_emitter.clone();
-    tokio::spawn(async move {
-        let mut last = receiver.borrow().clone();
+/// Pings `WATCHDOG=1` from the runtime; systemd restarts the daemon if every worker is stuck
+fn spawn_systemd_watchdog() -> Option<AbortOnDrop> {
+    let interval = systemd_watchdog_interval(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )?;
+    Some(AbortOnDrop(tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
         loop {
-            if receiver.changed().await.is_err() {
-                break;
-            }
-            let current = receiver.borrow().clone();
-            if current != last {
-                let virtual_keys: Vec<&str> =
-                    current.virtual_keys.iter().map(|vk| vk.as_str()).collect();
-                let _ = DbusWindowFocusService::status_changed(
-                    &signal_emitter_task,
-                    &current.layer,
-                    &virtual_keys,
-                    current.layer_source.as_str(),
-                )
-                .await;
-                last = current;
-            }
+            ticker.tick().await;
+            sd_notify("WATCHDOG=1");
         }
-    });
+    })))
+}
 
-    let mut pause_receiver = pause_broadcaster.subscribe();
-    let pause_emitter = signal_emitter.clone();
-    DbusWindowFocusService::paused_changed(&pause_emitter, pause_broadcaster.is_paused()).await?;
-    tokio::spawn(async move {
-        let mut last = *pause_receiver.borrow();
-        loop {
-            if pause_receiver.changed().await.is_err() {
+// === Main ===
+
+fn main() {
+    // Landlock only restricts the calling thread and threads created after it, so hardening
+    // runs before the tokio runtime starts its workers
+    if let Ok(args) = Args::try_parse()
+        && args.harden
+        && !args.install_autostart
+        && !args.install_systemd_service
+    {
+        harden(args.sync_autostart);
+    }
+    run_daemon();
+}
+
+#[tokio::main]
+async fn run_daemon() {
+    // Spans restarts: a restart waiting for kanata again is not a hang
+    let _watchdog = spawn_systemd_watchdog();
+    let mut first_run = true;
+    loop {
+        match run_once(first_run).await {
+            Ok(RunOutcome::Restart) => {
+                log_line!("[Restart] Restarting daemon");
+                sd_notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", monotonic_usec()));
+                apply_pending_session_env();
+                first_run = false;
+            }
+            Ok(RunOutcome::Exit) => {
+                sd_notify("STOPPING=1");
                 break;
             }
-            let current = *pause_receiver.borrow();
-            if current != last {
-                let _ = DbusWindowFocusService::paused_changed(&pause_emitter, current).await;
-                last = current;
+            Err(e) => {
+                elog_line!("[Fatal] {}", e);
+                std::process::exit(1);
             }
         }
-    });
-
-    Ok(())
+    }
 }
 
-// === GNOME Backend ===
-
-async fn run_gnome(
-    kanata: KanataClient,
-    handler: Arc<Mutex<FocusHandler>>,
-    status_broadcaster: StatusBroadcaster,
-    restart_handle: RestartHandle,
-    pause_broadcaster: PauseBroadcaster,
-    shutdown_handle: ShutdownHandle,
-) -> Result<RunOutcome, Box<dyn std::error::Error + Send + Sync>> {
-    let connection = Connection::session().await?;
-    let focus_query_connection = Connection::session().await?;
-    register_dbus_service(
-        &connection,
-        focus_query_connection.clone(),
-        Environment::Gnome,
-        false,
-        kanata.clone(),
-        handler.clone(),
+async fn build_kanata_client(
+    args: &Args,
+    config: &Config,
+    status_broadcaster: &StatusBroadcaster,
+) -> Result<KanataClient, Box<dyn std::error::Error + Send + Sync>> {
+    let mut kanata_target = resolve_kanata_target(args, config)?;
+    let sink = match args.kanata_sink {
+        KanataSink::Tcp => None,
+        mode => {
+            // Kanata starts on its first layer; without a kanata config, on the config's default
+            let (names, layer) = match args.kanata_config.as_deref() {
+                Some(path) => {
+                    let names = load_kanata_config_names(path)?;
+                    let layer = names.layers.first().cloned();
+                    (names, layer)
+                }
+                None => (
+                    config_kanata_names(config, None),
+                    kanata_target.default_layer.clone(),
+                ),
+            };
+            let sink = EmbeddedKanata::start(mode, "kanata", names, layer).await?;
+            sink.redirect(&mut kanata_target);
+            Some(sink)
+        }
+    };
+    if let Some(name) = &args.kanata {
+        log_line!(
+            "[Kanata] Using endpoint \"{}\" ({}:{})",
+            name, kanata_target.host, kanata_target.port
+        );
+    }
+    let kanata = KanataClient::new(
+        &kanata_target.host,
+        kanata_target.port,
+        kanata_target.default_layer,
+        args.quiet,
         status_broadcaster.clone(),
-        restart_handle.clone(),
-        pause_broadcaster.clone(),
-    )
-    .await?;
+    );
+    kanata.set_tls(kanata_target.tls).await;
+    if let Some(delays) = kanata_target.reconnect_delays_ms {
+        kanata.set_reconnect_delays(delays).await;
+    }
+    kanata.set_unknown_layer_policy(args.unknown_layer).await;
+    kanata
+        .set_write_timeout(Duration::from_millis(args.kanata_write_timeout))
+        .await;
+    kanata.set_rate_limit(args.kanata_rate_limit).await;
+    kanata.set_debug(args.debug).await;
+    if let Some(sink) = sink {
+        kanata.hold_sink(sink);
+    }
+    Ok(kanata)
+}
 
-    apply_focus_for_env(
-        Environment::Gnome,
-        Some(&focus_query_connection),
-        false,
-        &handler,
-        &status_broadcaster,
-        &pause_broadcaster,
-        &kanata,
-    )
-    .await?;
+fn build_focus_handler(args: &Args, config: &Config) -> FocusHandler {
+    FocusHandler::from_config(config, args.quiet || args.quiet_focus, args.debug)
+        .with_config_path(Some(resolve_config_path(args.config.as_deref())))
+        .with_endpoint(args.kanata.clone())
+}
 
-    println!("[GNOME] Listening for focus events from extension...");
-    let outcome = wait_for_restart_or_shutdown(&restart_handle, &shutdown_handle).await;
-    Ok(outcome)
+/// Clients for the endpoints in `names` (rule `kanata` targets) that `kanata` has none for yet;
+/// the caller connects them
+async fn add_rule_endpoints(
+    kanata: &KanataClient,
+    names: Vec<String>,
+    config: &Config,
+) -> Result<Vec<KanataClient>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut clients = Vec::new();
+    for name in names {
+        if kanata.has_endpoint(&name) {
+            continue;
+        }
+        let Some(endpoint) = config.kanata_endpoints.get(&name) else {
+            continue;
+        };
+        let mut target = endpoint_target(endpoint, config)?;
+        let sink = match kanata.sink_mode() {
+            Some(mode) => {
+                let names = config_kanata_names(config, Some(&name));
+                let sink =
+                    EmbeddedKanata::start(mode, &name, names, target.default_layer.clone()).await?;
+                sink.redirect(&mut target);
+                Some(sink)
+            }
+            None => None,
+        };
+        let client = kanata.add_endpoint(&name, target).await;
+        if let Some(sink) = sink {
+            client.hold_sink(sink);
+        }
+        clients.push(client);
+    }
+    Ok(clients)
 }
 
-// === KDE Backend ===
+// === Startup Ordering ===
+
+const SYSTEMD_UNIT_INTERFACE: &str = "org.freedesktop.systemd1.Unit";
+const DEFAULT_WAIT_FOR_UNIT_TIMEOUT_SECS: u64 = 30;
+const UNIT_WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
-#[derive(Debug)]
-struct KwinScriptGuard {
-    connection: Connection,
-    runtime_handle: tokio::runtime::Handle,
-    script_path: String,
-    script_obj_path: OwnedObjectPath,
-    script_interface: String,
+/// What `--wait-for-unit` does about a unit in this state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UnitWait {
+    /// Active (or reloading): connect now
+    Ready,
+    /// Not known to this manager
+    NotFound,
+    /// Starting, or not started yet: keep waiting
+    Pending,
 }
 
-impl KwinScriptGuard {
-    fn new(
-        connection: Connection,
-        runtime_handle: tokio::runtime::Handle,
-        script_path: String,
-        script_obj_path: OwnedObjectPath,
-        script_interface: &str,
-    ) -> Self {
-        Self {
-            connection,
-            runtime_handle,
-            script_path,
-            script_obj_path,
-            script_interface: script_interface.to_string(),
-        }
+fn unit_wait(load_state: &str, active_state: &str) -> UnitWait {
+    match (load_state, active_state) {
+        ("not-found", _) => UnitWait::NotFound,
+        (_, "active" | "reloading") => UnitWait::Ready,
+        _ => UnitWait::Pending,
     }
 }
 
-impl Drop for KwinScriptGuard {
-    fn drop(&mut self) {
-        let connection = self.connection.clone();
-        let runtime_handle = self.runtime_handle.clone();
-        let script_path = self.script_path.clone();
-        let script_obj_path = self.script_obj_path.clone();
-        let script_interface = self.script_interface.clone();
+/// LoadState and ActiveState of `unit` in the systemd manager on `connection`
+async fn read_unit_states(connection: &Connection, unit: &str) -> zbus::Result<(String, String)> {
+    let reply = connection
+        .call_method(
+            Some(SYSTEMD_BUS_NAME),
+            SYSTEMD_OBJECT_PATH,
+            Some(SYSTEMD_MANAGER_INTERFACE),
+            "LoadUnit",
+            &(unit,),
+        )
+        .await?;
+    let path: zbus::zvariant::OwnedObjectPath = reply.body().deserialize()?;
+    let properties = zbus::fdo::PropertiesProxy::builder(connection)
+        .destination(SYSTEMD_BUS_NAME)?
+        .path(path)?
+        .build()
+        .await?;
+    let interface = zbus::names::InterfaceName::from_static_str_unchecked(SYSTEMD_UNIT_INTERFACE);
+    let load_state = String::try_from(properties.get(interface.clone(), "LoadState").await?)?;
+    let active_state = String::try_from(properties.get(interface, "ActiveState").await?)?;
+    Ok((load_state, active_state))
+}
 
-        let cleanup = async move {
-            let stop_result = connection
-                .call_method(
-                    Some("org.kde.KWin"),
-                    script_obj_path.clone(),
-                    Some(script_interface.as_str()),
-                    "stop",
-                    &(),
-                )
-                .await;
-            if let Err(error) = stop_result {
-                panic!("[KDE] Failed to stop KWin script: {}", error);
+/// `--wait-for-unit`: poll the user manager (or the system manager, if the user manager
+/// doesn't know the unit) until `unit` is active or `timeout` passes. Never fails: a unit
+/// that can't be found or read is logged and the daemon connects as usual.
+async fn wait_for_systemd_unit(unit: &str, timeout: Duration) {
+    let mut found = None;
+    for scope in ["user", "system"] {
+        let connection = match scope {
+            "user" => Connection::session().await,
+            _ => Connection::system().await,
+        };
+        let Ok(connection) = connection else {
+            continue;
+        };
+        match read_unit_states(&connection, unit).await {
+            Ok((load_state, active_state)) => {
+                let wait = unit_wait(&load_state, &active_state);
+                if wait != UnitWait::NotFound {
+                    found = Some((scope, connection, wait));
+                    break;
+                }
             }
-
-            let unload_result = connection
-                .call_method(
-                    Some("org.kde.KWin"),
-                    "/Scripting",
-                    Some("org.kde.kwin.Scripting"),
-                    "unloadScript",
-                    &(&script_path,),
-                )
-                .await;
-            if let Err(error) = unload_result {
-                panic!("[KDE] Failed to unload KWin script: {}", error);
+            Err(error) => {
+                elog_line!("[Init] Cannot read {} from the {} manager: {}", unit, scope, error);
             }
-        };
-
-        if tokio::runtime::Handle::try_current().is_ok() {
-            tokio::task::block_in_place(|| {
-                runtime_handle.block_on(cleanup);
-            });
-        } else {
-            runtime_handle.block_on(cleanup);
         }
-
-        if let Err(error) = fs::remove_file(&self.script_path) {
-            if error.kind() != std::io::ErrorKind::NotFound {
-                panic!("[KDE] Failed to remove KWin script file: {}", error);
-            }
+    }
+    let Some((scope, connection, mut wait)) = found else {
+        elog_line!(
+            "[Init] Warning: {} not found in the user or system manager, not waiting for it",
+            unit
+        );
+        return;
+    };
+    if wait == UnitWait::Ready {
+        return;
+    }
+    log_line!("[Init] Waiting for {} ({} unit) to become active (--wait-for-unit)", unit, scope);
+    let deadline = Instant::now() + timeout;
+    while wait != UnitWait::Ready {
+        if Instant::now() >= deadline {
+            elog_line!(
+                "[Init] Warning: {} still not active after {}s, connecting anyway",
+                unit,
+                timeout.as_secs()
+            );
+            return;
         }
+        tokio::time::sleep(UNIT_WAIT_POLL_INTERVAL).await;
+        wait = match read_unit_states(&connection, unit).await {
+            Ok((load_state, active_state)) => unit_wait(&load_state, &active_state),
+            Err(_) => UnitWait::Pending,
+        };
     }
+    log_line!("[Init] {} is active", unit);
 }
 
-async fn run_kde(
-    kanata: KanataClient,
-    handler: Arc<Mutex<FocusHandler>>,
-    status_broadcaster: StatusBroadcaster,
-    restart_handle: RestartHandle,
-    pause_broadcaster: PauseBroadcaster,
-    shutdown_handle: ShutdownHandle,
-) -> Result<RunOutcome, Box<dyn std::error::Error + Send + Sync>> {
-    let connection = Connection::session().await?;
-    let focus_query_connection = Connection::session().await?;
-    let runtime_handle = tokio::runtime::Handle::current();
-    let is_kde6 = env::var("KDE_SESSION_VERSION")
-        .map(|v| v == "6")
-        .unwrap_or(false);
-    register_dbus_service(
-        &connection,
-        focus_query_connection.clone(),
-        Environment::Kde,
-        is_kde6,
-        kanata.clone(),
-        handler.clone(),
-        status_broadcaster.clone(),
-        restart_handle.clone(),
-        pause_broadcaster.clone(),
-    )
-    .await?;
-
-    apply_focus_for_env(
-        Environment::Kde,
-        Some(&focus_query_connection),
-        is_kde6,
-        &handler,
-        &status_broadcaster,
-        &pause_broadcaster,
-        &kanata,
-    )
-    .await?;
-
-    // Inject KWin script (DBus service is ready to receive calls)
-    let api = if is_kde6 {
-        "windowActivated"
-    } else {
-        "clientActivated"
-    };
-    let active_window = if is_kde6 {
-        "activeWindow"
-    } else {
-        "activeClient"
+/// Connect to kanata before the daemon starts. `--connect-timeout` bounds the retries; with
+/// `--allow-offline` a failed connection is not fatal: the daemon starts degraded and the caller
+/// keeps reconnecting in the background. Returns whether kanata is connected.
+async fn connect_kanata_at_startup(
+    kanata: &KanataClient,
+    connect_timeout: Option<Duration>,
+    allow_offline: bool,
+    status_broadcaster: &StatusBroadcaster,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let deadline = match (connect_timeout, allow_offline) {
+        (Some(timeout), _) => Some(tokio::time::Instant::now() + timeout),
+        (None, true) => Some(tokio::time::Instant::now()),
+        (None, false) => None,
     };
-    let kwin_script = format!(
-        r#"function notifyFocus(client) {{
-  callDBus(
-    "com.github.kanata.Switcher",
-    "/com/github/kanata/Switcher",
-    "com.github.kanata.Switcher",
-    "WindowFocus",
-    client ? (client.resourceClass || "") : "",
-    client ? (client.caption || "") : ""
-  );
-}}
-workspace.{api}.connect(notifyFocus);
-notifyFocus(workspace.{active});
-"#,
-        api = api,
-        active = active_window
+    if kanata.connect_with_deadline(deadline).await {
+        return Ok(true);
+    }
+    if !allow_offline {
+        return Err(format!(
+            "cannot connect to kanata within {:?} (--allow-offline starts without it)",
+            connect_timeout.unwrap_or_default()
+        )
+        .into());
+    }
+    elog_line!("[Kanata] Starting without kanata, reconnecting in the background");
+    status_broadcaster.set_degraded(
+        "kanata",
+        "unreachable since startup, reconnecting".to_string(),
     );
+    Ok(false)
+}
 
-    let uid = unsafe { libc::getuid() };
-    let script_path = format!("/tmp/kanata-switcher-kwin-{}.js", uid);
-    fs::write(&script_path, &kwin_script)?;
+// === Kanata Connection Notifications ===
 
-    for _ in 0..5 {
-        let result = connection
-            .call_method(
-                Some("org.kde.KWin"),
-                "/Scripting",
-                Some("org.kde.kwin.Scripting"),
-                "loadScript",
-                &(&script_path,),
-            )
-            .await;
+const NOTIFICATIONS_BUS_NAME: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
 
-        if result.is_ok() {
-            break;
-        }
-        tokio::time::sleep(Duration::from_secs(1)).await;
+/// (summary, body) of the `--notify-kanata` notification for a connection change
+fn kanata_connection_notification(event: &KanataConnectionEvent) -> (String, String) {
+    if !event.connected {
+        return (
+            "Kanata disconnected".to_string(),
+            "Reconnecting; layers switch again once kanata is back".to_string(),
+        );
     }
+    let body = match event.dropped_actions {
+        0 => "No actions were dropped".to_string(),
+        1 => "1 virtual key or reload action was dropped while disconnected".to_string(),
+        count => format!(
+            "{} virtual key or reload actions were dropped while disconnected",
+            count
+        ),
+    };
+    ("Kanata reconnected".to_string(), body)
+}
 
-    let _ = connection
-        .call_method(
-            Some("org.kde.KWin"),
-            "/Scripting",
-            Some("org.kde.kwin.Scripting"),
-            "unloadScript",
-            &(&script_path,),
-        )
-        .await;
+/// `--notify-kanata`: one desktop notification per connection change. The reconnect
+/// notification replaces the disconnect one
+fn spawn_kanata_notifier(
+    connection: Connection,
+    status_broadcaster: &StatusBroadcaster,
+) -> AbortOnDrop {
+    let mut receiver = status_broadcaster.subscribe_kanata_connection();
+    AbortOnDrop(tokio::spawn(async move {
+        let mut replaces_id = 0u32;
+        while receiver.changed().await.is_ok() {
+            let event = receiver.borrow_and_update().clone();
+            let (summary, body) = kanata_connection_notification(&event);
+            match send_notification(&connection, replaces_id, &summary, &body, false, -1).await {
+                Ok(id) => replaces_id = id,
+                Err(error) => elog_line!("[Notify] Cannot show notification: {}", error),
+            }
+        }
+    }))
+}
 
-    let load_result = connection
+/// Show a notification through org.freedesktop.Notifications, replacing `replaces_id`
+/// (0 = none). Returns the new notification's id
+async fn send_notification(
+    connection: &Connection,
+    replaces_id: u32,
+    summary: &str,
+    body: &str,
+    transient: bool,
+    expire_timeout: i32,
+) -> zbus::Result<u32> {
+    let mut hints = HashMap::<&str, Value>::new();
+    if transient {
+        hints.insert("transient", Value::from(true));
+    }
+    connection
         .call_method(
-            Some("org.kde.KWin"),
-            "/Scripting",
-            Some("org.kde.kwin.Scripting"),
-            "loadScript",
-            &(&script_path,),
+            Some(NOTIFICATIONS_BUS_NAME),
+            NOTIFICATIONS_PATH,
+            Some(NOTIFICATIONS_BUS_NAME),
+            "Notify",
+            &(
+                "kanata-switcher",
+                replaces_id,
+                "input-keyboard",
+                summary,
+                body,
+                Vec::<&str>::new(),
+                hints,
+                expire_timeout,
+            ),
         )
-        .await?;
+        .await?
+        .body()
+        .deserialize()
+}
 
-    let script_num: i32 = load_result.body().deserialize()?;
+// === Layer Change Notifications ===
+
+/// Notify about each new layer, coalescing bursts like `run_layer_announcer`. Focus switches
+/// caused by `"notify": false` rules only are skipped
+async fn run_layer_notifier<F>(
+    config: NotificationConfig,
+    display_names: LayerDisplayNames,
+    mut receiver: watch::Receiver<StatusSnapshot>,
+    focus: watch::Receiver<FocusEvent>,
+    mut notify: F,
+) where
+    F: FnMut(String),
+{
+    let min_interval = Duration::from_millis(config.min_interval_ms);
+    let mut last_layer = String::new();
+    let mut last_shown: Option<tokio::time::Instant> = None;
+    while receiver.changed().await.is_ok() {
+        if let Some(shown_at) = last_shown {
+            tokio::time::sleep_until(shown_at + min_interval).await;
+        }
+        let (layer, source) = {
+            let snapshot = receiver.borrow_and_update();
+            (snapshot.layer.clone(), snapshot.layer_source.clone())
+        };
+        if layer.is_empty() || layer == last_layer {
+            continue;
+        }
+        if source != LayerSource::Focus || !focus.borrow().quiet_notify {
+            notify(format!("Layer: {}", display_names.display(&layer)));
+            last_shown = Some(tokio::time::Instant::now());
+        }
+        last_layer = layer;
+    }
+}
 
-    let script_obj_path_str = if is_kde6 {
-        format!("/Scripting/Script{}", script_num)
-    } else {
-        format!("/{}", script_num)
+/// `--notify-layer-changes` / `notifications`: each notification replaces the previous one
+/// instead of stacking up
+fn spawn_layer_notifier(
+    connection: Connection,
+    config: NotificationConfig,
+    status_broadcaster: &StatusBroadcaster,
+) -> AbortOnDrop {
+    let expire_timeout = match config.timeout_ms {
+        0 => -1,
+        ms => i32::try_from(ms).unwrap_or(i32::MAX),
     };
+    let (summaries, mut receiver) = watch::channel(String::new());
+    let broadcaster = status_broadcaster.clone();
+    tokio::spawn(run_layer_notifier(
+        config,
+        status_broadcaster.layer_display_names().clone(),
+        status_broadcaster.subscribe(),
+        status_broadcaster.subscribe_focus(),
+        move |summary| {
+            let _ = summaries.send(summary);
+        },
+    ));
+    AbortOnDrop(tokio::spawn(async move {
+        let mut replaces_id = 0u32;
+        while receiver.changed().await.is_ok() {
+            let summary = receiver.borrow_and_update().clone();
+            match send_notification(&connection, replaces_id, &summary, "", true, expire_timeout)
+                .await
+            {
+                Ok(id) => {
+                    replaces_id = id;
+                    broadcaster.clear_degraded("notifications");
+                }
+                Err(error) => {
+                    let problem = format!("cannot show notification: {}", error);
+                    elog_line!("[Notify] {}", problem);
+                    broadcaster.set_degraded("notifications", problem);
+                }
+            }
+        }
+    }))
+}
 
-    let script_interface = if is_kde6 {
-        "org.kde.kwin.Script"
+/// Longest window title quoted in a `--snapshot` comment
+const SNAPSHOT_TITLE_MAX_CHARS: usize = 60;
+
+/// Placeholder layer for a `--snapshot` rule: the last segment of reverse-DNS app IDs
+/// (`org.gnome.Nautilus` → `nautilus`), lowercased, other characters turned into dashes.
+fn snapshot_layer_name(class: &str) -> String {
+    let name = class.rsplit('.').find(|segment| !segment.is_empty()).unwrap_or("");
+    let slug: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let slug = slug.split('-').filter(|part| !part.is_empty()).collect::<Vec<_>>().join("-");
+    if slug.is_empty() {
+        "app".to_string()
     } else {
-        "org.kde.kwin.Scripting"
-    };
+        slug
+    }
+}
 
-    let script_obj_path: OwnedObjectPath = script_obj_path_str.as_str().try_into()?;
+/// `--snapshot` output: a commented JSON fragment with one rule per window class, sorted by
+/// class ignoring case. Windows without a class and the daemon's own ignored classes (panels, settings) are
+/// left out; the comments carry the window count and a sample title.
+fn format_snapshot(env: Environment, windows: &[WindowInfo]) -> String {
+    let mut apps: BTreeMap<(String, String), (String, usize, String)> = BTreeMap::new();
+    for window in windows {
+        if window.class.is_empty() || is_ignored_focus_class(&window.class) {
+            continue;
+        }
+        let entry = apps
+            .entry((window.class.to_lowercase(), window.class.clone()))
+            .or_insert_with(|| (window.class.clone(), 0, String::new()));
+        entry.1 += 1;
+        if entry.2.is_empty() {
+            entry.2 = window.title.clone();
+        }
+    }
+    let window_count: usize = apps.values().map(|(_, count, _)| count).sum();
 
-    let _kwin_script_guard = KwinScriptGuard::new(
-        connection.clone(),
-        runtime_handle.clone(),
-        script_path.clone(),
-        script_obj_path.clone(),
-        script_interface,
+    let mut out = format!(
+        "// Generated by kanata-switcher --snapshot ({}): {} apps in {} windows\n",
+        env.as_str(),
+        apps.len(),
+        window_count
     );
+    out.push_str("// Replace the placeholder layers, drop unwanted rules and these comment lines,\n");
+    out.push_str("// then paste the rules into the config array before its default rule.\n");
+    let last = apps.len().saturating_sub(1);
+    for (index, (class, count, title)) in apps.into_values().enumerate() {
+        let title: String = title
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+        let mut sample: String = title.trim().chars().take(SNAPSHOT_TITLE_MAX_CHARS).collect();
+        if title.trim().chars().count() > SNAPSHOT_TITLE_MAX_CHARS {
+            sample.push('…');
+        }
+        let windows = if count == 1 { "window" } else { "windows" };
+        if sample.is_empty() {
+            out.push_str(&format!("// {} {}\n", count, windows));
+        } else {
+            out.push_str(&format!("// {} {}, e.g. \"{}\"\n", count, windows, sample));
+        }
+        let pattern = serde_json::to_string(&format!("^{}$", regex::escape(&class)))
+            .expect("string serializes to JSON");
+        let layer = serde_json::to_string(&snapshot_layer_name(&class))
+            .expect("string serializes to JSON");
+        let comma = if index == last { "" } else { "," };
+        out.push_str(&format!("{{\"class\": {}, \"layer\": {}}}{}\n", pattern, layer, comma));
+    }
+    out
+}
 
-    connection
-        .call_method(
-            Some("org.kde.KWin"),
-            script_obj_path,
-            Some(script_interface),
-            "run",
-            &(),
-        )
-        .await?;
-
-    println!("[KDE] KWin script injected, listening for window focus events...");
+/// `--once`: query the focused window, apply the matching actions and return.
+///
+/// Nothing is registered or subscribed (no DBus service, indicator, logind monitor or KWin
+/// focus script) and nothing is reset on exit, so the layer stays and virtual keys pressed by
+/// `virtual_key` rules stay held. Kanata is connected without retries and the GNOME extension
+/// is not installed, so failures surface as an error exit. A daemon paused via the state file
+/// applies nothing.
+async fn apply_once(
+    args: &Args,
+    instance: &DbusInstance,
+    env: Environment,
+    connection: Option<&Connection>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if env == Environment::Unknown {
+        return Err("could not detect display environment (WAYLAND_DISPLAY or DISPLAY)".into());
+    }
+    log_line!("[Once] Detected environment: {}", env.as_str());
 
-    let outcome = wait_for_restart_or_shutdown(&restart_handle, &shutdown_handle).await;
-    Ok(outcome)
-}
+    let config = load_config(args.config.as_deref())?;
+    if config.rules.is_empty() && config.native_terminal_rule.is_none() {
+        return Err("no rules found in config file".into());
+    }
+    if let Some(kanata_config) = args.kanata_config.as_deref() {
+        warn_unknown_config_names(&config, kanata_config, args.unknown_layer)?;
+    }
+    if RuntimeStateStore::open_default(instance).is_some_and(|store| store.load().paused) {
+        log_line!("[Once] Switching is paused (unpause via tray, kswitchctl or --unpause), nothing applied");
+        return Ok(());
+    }
 
-// === Main ===
+    let status_broadcaster = StatusBroadcaster::with_layer_display_names(LayerDisplayNames::new(
+        config.layer_display_names.clone(),
+    ));
+    let kanata = build_kanata_client(args, &config, &status_broadcaster).await?;
+    kanata
+        .try_connect()
+        .await
+        .map_err(|error| format!("cannot connect to kanata: {}", error))?;
+    if args.unknown_layer == UnknownLayerPolicy::Error {
+        let known_layers = kanata.known_layers().await;
+        if !known_layers.is_empty() {
+            check_config_layers(&config, &known_layers)?;
+        }
+    }
 
-#[tokio::main]
-async fn main() {
-    loop {
-        match run_once().await {
-            Ok(RunOutcome::Restart) => {
-                println!("[Restart] Restarting daemon");
-            }
-            Ok(RunOutcome::Exit) => break,
-            Err(e) => {
-                eprintln!("[Fatal] {}", e);
-                std::process::exit(1);
-            }
+    let handler = Arc::new(Mutex::new(build_focus_handler(args, &config)));
+    let endpoints = handler.lock().unwrap().target_endpoints();
+    for client in add_rule_endpoints(&kanata, endpoints, &config).await? {
+        if let Err(error) = client.try_connect().await {
+            elog_line!("[Once] Warning: cannot connect to a rule's kanata endpoint: {}", error);
         }
     }
+    let is_kde6 = session_var("KDE_SESSION_VERSION").is_some_and(|v| v == "6");
+    apply_focus_for_env(
+        env,
+        connection,
+        is_kde6,
+        &handler,
+        &status_broadcaster,
+        &PauseBroadcaster::new(),
+        &kanata,
+    )
+    .await
 }
 
-async fn run_once() -> Result<RunOutcome, Box<dyn std::error::Error + Send + Sync>> {
+async fn run_once(first_run: bool) -> Result<RunOutcome, DaemonError> {
     let matches = Args::command().get_matches();
-    let args = Args::from_arg_matches(&matches)?;
+    let args = {
+        let mut args = Args::from_arg_matches(&matches)?;
+        apply_dry_run(&mut args);
+        args
+    };
+    let instance = DbusInstance::new(args.dbus_suffix.as_deref());
+    if let Some(session) = LogindSessionOverride::from_args(&args) {
+        let _ = LOGIND_SESSION_OVERRIDE.set(session);
+    }
     if args.install_autostart {
         install_autostart_desktop(&matches, &args)?;
         return Ok(RunOutcome::Exit);
     }
     if args.uninstall_autostart {
-        uninstall_autostart_desktop()?;
+        uninstall_autostart_desktop(&instance)?;
+        return Ok(RunOutcome::Exit);
+    }
+    if args.install_systemd_service {
+        install_systemd_service(&matches, &args)?;
+        return Ok(RunOutcome::Exit);
+    }
+    if args.uninstall_systemd_service {
+        uninstall_systemd_service(&instance)?;
+        return Ok(RunOutcome::Exit);
+    }
+    if args.uninstall {
+        uninstall_everything()?;
+        return Ok(RunOutcome::Exit);
+    }
+    if args.install_desktop {
+        install_desktop_entry(&args)?;
+        return Ok(RunOutcome::Exit);
+    }
+    if args.uninstall_desktop {
+        uninstall_desktop_entry()?;
         return Ok(RunOutcome::Exit);
     }
     if let Some(command) = resolve_control_command(&args) {
-        send_control_command(command).await?;
+        send_control_command(command, &instance).await?;
+        return Ok(RunOutcome::Exit);
+    }
+    if args.status {
+        print_daemon_status(&instance, args.json).await?;
+        return Ok(RunOutcome::Exit);
+    }
+    if args.healthcheck {
+        std::process::exit(run_healthcheck(&instance).await);
+    }
+    if args.follow_status {
+        let connection = Connection::session().await?;
+        follow_daemon_status(&connection, &instance, |line| println!("{}", line)).await?;
+        return Ok(RunOutcome::Exit);
+    }
+    if args.check_config {
+        check_config(&args).await?;
+        return Ok(RunOutcome::Exit);
+    }
+    if args.dump_config {
+        dump_config(&args)?;
+        return Ok(RunOutcome::Exit);
+    }
+    if let Some([class, title]) = args.match_window.as_deref() {
+        match_window(&args, class, title).await?;
+        return Ok(RunOutcome::Exit);
+    }
+    if args.migrate_config {
+        migrate_config(args.config.as_deref())?;
+        return Ok(RunOutcome::Exit);
+    }
+    if args.settings {
+        open_settings_window(resolve_config_path(args.config.as_deref()), instance)?;
+        return Ok(RunOutcome::Exit);
+    }
+    if args.print_effective_invocation {
+        print_effective_invocation(&matches, &args)?;
+        return Ok(RunOutcome::Exit);
+    }
+    if let Some(kind) = args.print_kanata_names {
+        let kanata_config = args
+            .kanata_config
+            .as_deref()
+            .expect("kanata_config missing for --print-kanata-names");
+        print_kanata_names(kanata_config, kind)?;
+        return Ok(RunOutcome::Exit);
+    }
+    if args.once {
+        let env = detect_environment();
+        let connection = if matches!(env, Environment::Gnome | Environment::Kde) {
+            Some(Connection::session().await?)
+        } else {
+            None
+        };
+        apply_once(&args, &instance, env, connection.as_ref()).await?;
+        return Ok(RunOutcome::Exit);
+    }
+    if args.snapshot {
+        let env = detect_environment();
+        let connection = if matches!(env, Environment::Gnome | Environment::Kde) {
+            Some(Connection::session().await?)
+        } else {
+            None
+        };
+        let is_kde6 = session_var("KDE_SESSION_VERSION").is_some_and(|v| v == "6");
+        let windows = list_windows_for_env(env, connection.as_ref(), is_kde6).await?;
+        print!("{}", format_snapshot(env, &windows));
         return Ok(RunOutcome::Exit);
     }
 
+    if first_run {
+        check_autostart_sync(&matches, &args);
+    }
+    if first_run
+        && let Some(delay) = startup_delay(
+            args.autostart_delay,
+            env::var_os("DESKTOP_AUTOSTART_ID").is_some(),
+        )
+    {
+        log_line!("[Init] Waiting {}s before starting (--autostart-delay)", delay.as_secs());
+        tokio::time::sleep(delay).await;
+    }
+
     let install_gnome_extension = resolve_install_gnome_extension(&matches);
 
     let env = detect_environment();
-    println!("[Init] Detected environment: {}", env.as_str());
+    log_line!("[Init] Detected environment: {}", env.as_str());
+    if args.dry_run {
+        log_line!(
+            "[DryRun] Nothing is sent to kanata; messages are logged as [Sink] lines (instance {})",
+            instance.name()
+        );
+    }
 
     if env == Environment::Gnome {
-        setup_gnome_extension(install_gnome_extension);
+        setup_gnome_extension(install_gnome_extension)?;
     }
 
-    let config = load_config(args.config.as_deref());
-    if config.rules.is_empty() && config.native_terminal_rule.is_none() {
-        eprintln!("[Config] Error: No rules found in config file");
-        eprintln!();
-        eprintln!("Example config (~/.config/kanata/kanata-switcher.json):");
-        eprintln!(
+    let config = load_config(args.config.as_deref()).map_err(DaemonError::Config)?;
+    if config.rules.is_empty()
+        && config.native_terminal_rule.is_none()
+        && config.session_inactive_rule.is_none()
+        && config.locked_rule.is_none()
+    {
+        elog_line!("Example config (~/.config/kanata/kanata-switcher.json):");
+        elog_line!(
             r#"[
   {{"default": "base"}},
   {{"on_native_terminal": "tty"}},
@@ -4970,43 +17733,167 @@ async fn run_once() -> Result<RunOutcome, Box<dyn std::error::Error + Send + Syn
   {{"class": "alacritty", "title": "vim", "layer": "vim"}}
 ]"#
         );
-        std::process::exit(1);
+        return Err(DaemonError::Config(format!(
+            "No rules found in {}",
+            resolve_config_path(args.config.as_deref()).display()
+        )));
+    }
+    warn_unsupported_conditions(&config, env);
+    if let Some(kanata_config) = args.kanata_config.as_deref() {
+        warn_unknown_config_names(&config, kanata_config, args.unknown_layer)?;
     }
 
-    let quiet_focus = args.quiet || args.quiet_focus;
-    let status_broadcaster = StatusBroadcaster::new();
+    let status_broadcaster = StatusBroadcaster::with_layer_display_names(LayerDisplayNames::new(
+        config.layer_display_names.clone(),
+    ));
+    if let Some(problems) = HARDEN_PROBLEMS.get()
+        && !problems.is_empty()
+    {
+        status_broadcaster.set_degraded("harden", problems.join("; "));
+    }
     let restart_handle = RestartHandle::new();
     let pause_broadcaster = PauseBroadcaster::new();
     let shutdown_handle = ShutdownHandle::new();
     let runtime_handle = tokio::runtime::Handle::current();
-    let kanata = KanataClient::new(
-        &args.host,
-        args.port,
-        config.default_layer,
-        args.quiet,
-        status_broadcaster.clone(),
-    );
-    kanata.connect_with_retry().await;
+    let kanata = build_kanata_client(&args, &config, &status_broadcaster).await?;
+    let state_store = RuntimeStateStore::open_default(&instance);
+    if env == Environment::Gnome
+        && let Some(store) = &state_store
+    {
+        migrate_legacy_display_settings(store).await;
+    }
+    let settings_broadcaster = SettingsBroadcaster::new(initial_display_settings(
+        state_store.as_ref(),
+        args.indicator_focus_only,
+    ));
+    if args.restore_layer
+        && let Some(last_layer) = state_store.as_ref().and_then(|store| store.load().last_layer)
+    {
+        kanata.set_restore_layer(Some(last_layer)).await;
+    }
+    let _kanata_notifier = if args.notify_kanata {
+        match Connection::session().await {
+            Ok(connection) => Some(spawn_kanata_notifier(connection, &status_broadcaster)),
+            Err(error) => {
+                elog_line!(
+                    "[Notify] Cannot connect to the session bus, --notify-kanata disabled: {}",
+                    error
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(unit) = args.wait_for_unit.as_deref() {
+        wait_for_systemd_unit(unit, Duration::from_secs(args.wait_for_unit_timeout)).await;
+    }
+    let kanata_connected = connect_kanata_at_startup(
+        &kanata,
+        args.connect_timeout.map(Duration::from_secs),
+        args.allow_offline,
+        &status_broadcaster,
+    )
+    .await?;
+    if args.unknown_layer == UnknownLayerPolicy::Error {
+        let known_layers = kanata.known_layers().await;
+        if !known_layers.is_empty() {
+            check_config_layers(&config, &known_layers)?;
+        }
+    }
+
+    if let Some(announce) = config.announce.clone() {
+        spawn_layer_announcer(announce, &status_broadcaster);
+    }
+    let _layer_notifier = match config
+        .notifications
+        .clone()
+        .or_else(|| args.notify_layer_changes.then(NotificationConfig::default))
+    {
+        Some(notifications) => match Connection::session().await {
+            Ok(connection) => {
+                log_line!("[Notify] Showing layer changes as desktop notifications");
+                Some(spawn_layer_notifier(connection, notifications, &status_broadcaster))
+            }
+            Err(error) => {
+                elog_line!(
+                    "[Notify] Cannot connect to the session bus, layer notifications disabled: {}",
+                    error
+                );
+                None
+            }
+        },
+        None => None,
+    };
+
+    if let Some(state_store) = state_store {
+        if args.restore_layer {
+            spawn_layer_state_writer(state_store.clone(), &status_broadcaster, kanata.clone());
+        }
+        let state = state_store.load();
+        if let Some(layer) = state.pinned_layer {
+            if kanata.restore_pinned_layer(&layer).await {
+                log_line!("[State] Restoring pinned layer \"{}\" (unpin via tray or DBus UnpinLayer)", layer);
+            } else {
+                log_line!("[State] Pinned layer \"{}\" is unknown to kanata, not restored", layer);
+                state_store.update(|state| state.pinned_layer = None);
+            }
+        }
+        if state.paused && pause_broadcaster.set_paused(true) {
+            log_line!("[State] Restoring paused state (unpause via tray, kswitchctl or --unpause)");
+            apply_pause(&kanata, &status_broadcaster, &[], None).await;
+        }
+        spawn_settings_state_writer(state_store.clone(), &settings_broadcaster);
+        spawn_pin_state_writer(state_store.clone(), &kanata);
+        spawn_pause_state_writer(state_store, &pause_broadcaster);
+    }
+    // Started after restoring the pause state, which stops it; aborted when this run ends
+    let _offline_reconnect = (!kanata_connected).then(|| {
+        let kanata = kanata.clone();
+        AbortOnDrop(tokio::spawn(async move { kanata.reconnect_loop().await }))
+    });
 
     let focus_handler = if matches!(env, Environment::Unknown) {
         None
     } else {
-        Some(Arc::new(Mutex::new(FocusHandler::new(
-            config.rules.clone(),
-            config.native_terminal_rule.clone(),
-            quiet_focus,
-        ))))
+        Some(Arc::new(Mutex::new(build_focus_handler(&args, &config))))
     };
+    // Aborted when this run ends, like the offline reconnect
+    let mut _endpoint_connections = Vec::new();
+    if let Some(handler) = &focus_handler {
+        let endpoints = handler.lock().unwrap().target_endpoints();
+        for client in add_rule_endpoints(&kanata, endpoints, &config).await? {
+            _endpoint_connections.push(AbortOnDrop(tokio::spawn(async move {
+                client.connect_with_retry().await
+            })));
+        }
+    }
 
-    if let Some(handler) = focus_handler.clone() {
+    let vt_monitor = !args.no_vt_monitor && config.vt_monitor;
+    if !vt_monitor {
+        log_line!(
+            "[Logind] Native terminal monitoring disabled via {}",
+            if args.no_vt_monitor { "--no-vt-monitor" } else { "config" }
+        );
+        if config.native_terminal_rule.is_some()
+            || config.session_inactive_rule.is_some()
+            || config.locked_rule.is_some()
+        {
+            elog_line!(
+                "[Logind] Warning: on_native_terminal, on_session_inactive and on_locked rules won't apply"
+            );
+        }
+    }
+    // Aborted when this run ends so a restart doesn't leave the old monitor running
+    let _logind_monitor = if let Some(handler) = focus_handler.clone()
+        && vt_monitor
+    {
         let session_connection = if matches!(env, Environment::Gnome | Environment::Kde) {
             Some(Connection::session().await?)
         } else {
             None
         };
-        let is_kde6 = env::var("KDE_SESSION_VERSION")
-            .map(|v| v == "6")
-            .unwrap_or(false);
+        let is_kde6 = session_var("KDE_SESSION_VERSION").is_some_and(|v| v == "6");
         start_logind_session_monitor_best_effort(
             env,
             session_connection,
@@ -5017,8 +17904,104 @@ async fn run_once() -> Result<RunOutcome, Box<dyn std::error::Error + Send + Syn
             kanata.clone(),
             start_logind_session_monitor,
         )
-        .await;
-    }
+        .await
+    } else {
+        None
+    };
+
+    let _call_monitor = if let Some(call_detection) = config.call_detection.as_ref()
+        && call_detection.mic
+        && let Some(handler) = focus_handler.clone()
+    {
+        let session_connection = if matches!(env, Environment::Gnome | Environment::Kde) {
+            Some(Connection::session().await?)
+        } else {
+            None
+        };
+        let is_kde6 = session_var("KDE_SESSION_VERSION").is_some_and(|v| v == "6");
+        let monitor = CallMonitor {
+            env,
+            session_connection,
+            is_kde6,
+            handler,
+            status_broadcaster: status_broadcaster.clone(),
+            pause_broadcaster: pause_broadcaster.clone(),
+            kanata: kanata.clone(),
+            poll_interval: Duration::from_millis(call_detection.poll_interval_ms),
+        };
+        Some(AbortOnDrop(tokio::spawn(monitor.run())))
+    } else {
+        None
+    };
+
+    #[cfg(feature = "uinput-fallback")]
+    let _uinput_fallback = if let Some(uinput_fallback) = config.uinput_fallback.as_ref()
+        && let Some(handler) = focus_handler.clone()
+    {
+        let monitor = UinputFallbackMonitor {
+            config: uinput_fallback.clone(),
+            handler,
+            status_broadcaster: status_broadcaster.clone(),
+            pause_broadcaster: pause_broadcaster.clone(),
+            kanata: kanata.clone(),
+        };
+        Some(AbortOnDrop(tokio::spawn(monitor.run())))
+    } else {
+        None
+    };
+
+    let _screen_share_monitor = if args.pause_while_sharing
+        && let Some(handler) = focus_handler.clone()
+    {
+        let session_connection = if matches!(env, Environment::Gnome | Environment::Kde) {
+            Some(Connection::session().await?)
+        } else {
+            None
+        };
+        let is_kde6 = session_var("KDE_SESSION_VERSION").is_some_and(|v| v == "6");
+        let started = match Connection::session().await {
+            Ok(monitor_connection) => {
+                let monitor = ScreenShareMonitor {
+                    env,
+                    session_connection,
+                    is_kde6,
+                    handler,
+                    status_broadcaster: status_broadcaster.clone(),
+                    pause_broadcaster: pause_broadcaster.clone(),
+                    kanata: kanata.clone(),
+                    sharing_layer: args.sharing_layer.clone(),
+                };
+                start_screen_share_monitor(monitor_connection, monitor).await
+            }
+            Err(error) => Err(error.into()),
+        };
+        match started {
+            Ok(monitor) => Some(monitor),
+            Err(error) => {
+                elog_line!("[ScreenShare] Cannot monitor screen sharing: {}", error);
+                status_broadcaster.set_degraded(
+                    "screen-share",
+                    format!("cannot monitor screen sharing: {}", error),
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let _idle_monitor = match config.idle.as_ref() {
+        Some(idle) => {
+            start_idle_monitor(
+                idle,
+                kanata.clone(),
+                status_broadcaster.clone(),
+                pause_broadcaster.clone(),
+            )
+            .await
+        }
+        None => None,
+    };
 
     let dbus_control_guard = if matches!(env, Environment::Wayland | Environment::X11) {
         let handler = focus_handler
@@ -5036,6 +18019,8 @@ async fn run_once() -> Result<RunOutcome, Box<dyn std::error::Error + Send + Syn
             status_broadcaster.clone(),
             restart_handle.clone(),
             pause_broadcaster.clone(),
+            settings_broadcaster.clone(),
+            &instance,
         )
         .await?;
         Some(DbusControlGuard::new(connection))
@@ -5044,28 +18029,27 @@ async fn run_once() -> Result<RunOutcome, Box<dyn std::error::Error + Send + Syn
     };
     let _dbus_control_guard = dbus_control_guard;
 
-    // Create shutdown guard - will switch to default layer when dropped
-    let _shutdown_guard = ShutdownGuard::new(kanata.clone());
+    // Create shutdown guard - will release held virtual keys and switch to default layer when dropped
+    let _shutdown_guard = ShutdownGuard::new(kanata.clone(), focus_handler.clone());
 
     // Set up signal handlers
     let shutdown_handle_for_signal = shutdown_handle.clone();
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .map_err(|e| format!("failed to install SIGTERM handler: {}", e))?;
+    let mut sigint = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())
+        .map_err(|e| format!("failed to install SIGINT handler: {}", e))?;
+    let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .map_err(|e| format!("failed to install SIGHUP handler: {}", e))?;
     tokio::spawn(async move {
-        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
-            .expect("failed to install SIGTERM handler");
-        let mut sigint = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())
-            .expect("failed to install SIGINT handler");
-        let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
-            .expect("failed to install SIGHUP handler");
-
         tokio::select! {
             _ = sigterm.recv() => {
-                eprintln!("[Signal] Received SIGTERM");
+                elog_line!("[Signal] Received SIGTERM");
             }
             _ = sigint.recv() => {
-                eprintln!("[Signal] Received SIGINT");
+                elog_line!("[Signal] Received SIGINT");
             }
             _ = sighup.recv() => {
-                eprintln!("[Signal] Received SIGHUP");
+                elog_line!("[Signal] Received SIGHUP");
             }
         }
 
@@ -5074,7 +18058,7 @@ async fn run_once() -> Result<RunOutcome, Box<dyn std::error::Error + Send + Syn
 
     let enable_indicator = !args.no_indicator && env != Environment::Gnome;
     if args.no_indicator && env != Environment::Gnome {
-        println!("[SNI] Indicator disabled via --no-indicator");
+        log_line!("[SNI] Indicator disabled via --no-indicator");
     }
 
     let sni_control = if enable_indicator {
@@ -5084,9 +18068,10 @@ async fn run_once() -> Result<RunOutcome, Box<dyn std::error::Error + Send + Syn
                     runtime_handle: runtime_handle.clone(),
                     connection,
                     restart_handle: restart_handle.clone(),
+                    instance: instance.clone(),
                 })),
                 Err(error) => {
-                    eprintln!("[SNI] Failed to connect to session bus: {}", error);
+                    elog_line!("[SNI] Failed to connect to session bus: {}", error);
                     None
                 }
             },
@@ -5115,74 +18100,86 @@ async fn run_once() -> Result<RunOutcome, Box<dyn std::error::Error + Send + Syn
     let sni_handle = sni_control.and_then(|control| {
         start_sni_indicator(
             control,
+            kanata.clone(),
             status_broadcaster.clone(),
             pause_broadcaster.clone(),
-            args.indicator_focus_only,
+            settings_broadcaster.clone(),
+            config.tray_icons.clone(),
+            config.indicator.clone(),
         )
     });
     let _sni_guard = SniGuard::new(sni_handle);
 
-    match env {
-        Environment::Gnome => {
-            let handler = focus_handler.expect("Focus handler missing for GNOME backend");
-            return run_gnome(
-                kanata,
-                handler,
-                status_broadcaster,
-                restart_handle,
-                pause_broadcaster,
-                shutdown_handle,
-            )
-            .await;
-        }
-        Environment::Kde => {
-            let handler = focus_handler.expect("Focus handler missing for KDE backend");
-            return run_kde(
-                kanata,
-                handler,
-                status_broadcaster,
-                restart_handle,
-                pause_broadcaster,
-                shutdown_handle,
-            )
-            .await;
-        }
-        Environment::Wayland => {
-            let handler = focus_handler.expect("Focus handler missing for Wayland backend");
-            run_wayland(
-                kanata,
-                handler,
-                status_broadcaster,
-                pause_broadcaster,
-                shutdown_handle,
-            )
-            .await?;
-        }
-        Environment::X11 => {
-            let handler = focus_handler.expect("Focus handler missing for X11 backend");
-            run_x11(
-                kanata,
-                handler,
-                status_broadcaster,
-                pause_broadcaster,
-                shutdown_handle,
+    let Some(handler) = focus_handler else {
+        return Err(DaemonError::NoDisplay);
+    };
+    let _session_watcher = spawn_session_watcher(env, restart_handle.clone()).await;
+    sd_notify(&format!(
+        "READY=1\nSTATUS=Switching layers ({} backend, kanata {})",
+        env.as_str(),
+        if kanata_connected { "connected" } else { "offline" }
+    ));
+    let outcome = supervise_backend(
+        env,
+        RestartBackoff::new(&SUPERVISOR_RESTART_DELAYS_MS),
+        detect_environment,
+        &status_broadcaster,
+        &restart_handle,
+        &shutdown_handle,
+        || {
+            run_backend(
+                env,
+                kanata.clone(),
+                handler.clone(),
+                status_broadcaster.clone(),
+                restart_handle.clone(),
+                pause_broadcaster.clone(),
+                settings_broadcaster.clone(),
+                shutdown_handle.clone(),
+                &instance,
             )
-            .await?;
-        }
-        Environment::Unknown => {
-            eprintln!("[Error] Could not detect display environment");
-            eprintln!("[Error] Ensure WAYLAND_DISPLAY or DISPLAY is set");
-            std::process::exit(1);
-        }
-    }
+        },
+    )
+    .await;
 
-    Ok(RunOutcome::Exit)
+    Ok(outcome)
 }
 
 // === Tests ===
 
+#[cfg(feature = "settings-gui")]
+mod settings_gui;
+
+#[cfg(feature = "settings-gui")]
+fn open_settings_window(
+    config_path: PathBuf,
+    instance: DbusInstance,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    settings_gui::run(config_path, instance)
+}
+
+#[cfg(not(feature = "settings-gui"))]
+fn open_settings_window(
+    _config_path: PathBuf,
+    _instance: DbusInstance,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    Err("this build does not include the settings window (enable the `settings-gui` feature)".into())
+}
+
+#[cfg(feature = "scripting")]
+mod scripting;
+
+#[cfg(feature = "wasm-plugins")]
+mod plugins;
+
+#[cfg(feature = "uinput-fallback")]
+mod uinput_fallback;
+
 #[cfg(test)]
 mod tests;
 
 #[cfg(test)]
 mod integration_tests;
+
+#[cfg(test)]
+mod benches;
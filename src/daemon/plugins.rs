@@ -0,0 +1,133 @@
+//! WASI plugins for custom rule matchers and actions (`wasm-plugins` feature).
+//!
+//! A plugin is a core WebAssembly module (e.g. a Rust `cdylib` built for `wasm32-wasip1`) in the
+//! config's `plugins_dir`, named `<plugin>.wasm`. The daemon passes JSON through the module's
+//! memory:
+//!
+//! - `memory` and `kswitch_alloc(len: i32) -> i32` (required): a buffer for the JSON argument
+//! - `kswitch_free(ptr: i32, len: i32)` (optional): called once the daemon is done with it
+//! - `kswitch_match(ptr: i32, len: i32) -> i32`: the window as JSON; 1 = match, 0 = no match,
+//!   negative = error (rules with `"plugin"`)
+//! - `kswitch_action(ptr: i32, len: i32) -> i32`: `{"rule", "name", "layer", "window"}`; 0 = ok,
+//!   anything else = error (rules with `"plugin_action"`)
+//!
+//! Each plugin keeps one instance for the daemon's lifetime (globals persist between calls). WASI
+//! gives it stdout/stderr only (no files, env or network), and every call is fuel-limited.
+
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+use wasmtime::{Engine, Instance, Linker, Module, Store, TypedFunc};
+use wasmtime_wasi::WasiCtxBuilder;
+use wasmtime_wasi::preview1::{self, WasiP1Ctx};
+
+/// Fuel (roughly wasm instructions) per call, so a stuck plugin can't stall focus handling
+const PLUGIN_FUEL_PER_CALL: u64 = 10_000_000;
+
+static ENGINE: LazyLock<Result<Engine, String>> = LazyLock::new(|| {
+    let mut config = wasmtime::Config::new();
+    config.consume_fuel(true);
+    Engine::new(&config).map_err(|error| error.to_string())
+});
+
+struct PluginInstance {
+    store: Store<WasiP1Ctx>,
+    instance: Instance,
+}
+
+pub(crate) struct WasmPlugin {
+    name: String,
+    state: Mutex<PluginInstance>,
+}
+
+impl std::fmt::Debug for WasmPlugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WasmPlugin").field("name", &self.name).finish()
+    }
+}
+
+impl WasmPlugin {
+    /// Compiles and instantiates `path`, running the module's `_initialize` if it has one
+    pub(crate) fn load(name: &str, path: &Path) -> Result<Self, String> {
+        let engine = ENGINE.as_ref().map_err(Clone::clone)?;
+        let module = Module::from_file(engine, path).map_err(|error| format!("{:#}", error))?;
+        let mut linker: Linker<WasiP1Ctx> = Linker::new(engine);
+        preview1::add_to_linker_sync(&mut linker, |ctx| ctx).map_err(|error| error.to_string())?;
+        let wasi = WasiCtxBuilder::new()
+            .inherit_stdout()
+            .inherit_stderr()
+            .build_p1();
+        let mut store = Store::new(engine, wasi);
+        store
+            .set_fuel(PLUGIN_FUEL_PER_CALL)
+            .map_err(|error| error.to_string())?;
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .map_err(|error| format!("{:#}", error))?;
+        if let Ok(initialize) = instance.get_typed_func::<(), ()>(&mut store, "_initialize") {
+            initialize
+                .call(&mut store, ())
+                .map_err(|error| format!("_initialize failed: {:#}", error))?;
+        }
+        instance
+            .get_typed_func::<i32, i32>(&mut store, "kswitch_alloc")
+            .map_err(|_| "missing export 'kswitch_alloc(len: i32) -> i32'".to_string())?;
+        Ok(WasmPlugin {
+            name: name.to_string(),
+            state: Mutex::new(PluginInstance { store, instance }),
+        })
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// `kswitch_match` on the window's JSON
+    pub(crate) fn matches(&self, window_json: &str) -> Result<bool, String> {
+        match self.call("kswitch_match", window_json)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            code => Err(format!("kswitch_match returned {}", code)),
+        }
+    }
+
+    /// `kswitch_action` on the action's JSON payload
+    pub(crate) fn run_action(&self, payload: &str) -> Result<(), String> {
+        match self.call("kswitch_action", payload)? {
+            0 => Ok(()),
+            code => Err(format!("kswitch_action returned {}", code)),
+        }
+    }
+
+    fn call(&self, export: &str, argument: &str) -> Result<i32, String> {
+        let mut state = self.state.lock().unwrap();
+        let PluginInstance { store, instance } = &mut *state;
+        store
+            .set_fuel(PLUGIN_FUEL_PER_CALL)
+            .map_err(|error| error.to_string())?;
+        let function: TypedFunc<(i32, i32), i32> = instance
+            .get_typed_func(&mut *store, export)
+            .map_err(|_| format!("missing export '{}(ptr: i32, len: i32) -> i32'", export))?;
+        let memory = instance
+            .get_memory(&mut *store, "memory")
+            .ok_or("missing export 'memory'")?;
+        let alloc: TypedFunc<i32, i32> = instance
+            .get_typed_func(&mut *store, "kswitch_alloc")
+            .map_err(|error| error.to_string())?;
+
+        let bytes = argument.as_bytes();
+        let len = i32::try_from(bytes.len()).map_err(|_| "argument too large".to_string())?;
+        let ptr = alloc
+            .call(&mut *store, len)
+            .map_err(|error| format!("kswitch_alloc failed: {:#}", error))?;
+        memory
+            .write(&mut *store, ptr as u32 as usize, bytes)
+            .map_err(|_| format!("kswitch_alloc returned an invalid buffer ({})", ptr))?;
+        let result = function
+            .call(&mut *store, (ptr, len))
+            .map_err(|error| format!("{} failed: {:#}", export, error));
+        if let Ok(free) = instance.get_typed_func::<(i32, i32), ()>(&mut *store, "kswitch_free") {
+            let _ = free.call(&mut *store, (ptr, len));
+        }
+        result
+    }
+}
@@ -0,0 +1,86 @@
+//! Rhai matcher scripts for rules with a `script` field (`scripting` feature).
+//!
+//! A script sees the focused window as `window` and the default layer as `default_layer`, and
+//! decides whether the rule matches: `true`/`false`, a layer name (match, switching to that layer
+//! instead of the rule's `layer`) or `()` (no match).
+
+use super::{ScriptMatch, WindowInfo};
+use rhai::{AST, Dynamic, Engine, Map, Scope};
+use std::sync::LazyLock;
+
+/// Operations per run, so a runaway loop can't stall focus handling
+const SCRIPT_MAX_OPERATIONS: u64 = 100_000;
+const SCRIPT_MAX_EXPR_DEPTH: usize = 32;
+const SCRIPT_MAX_STRING_SIZE: usize = 64 * 1024;
+
+static ENGINE: LazyLock<Engine> = LazyLock::new(|| {
+    let mut engine = Engine::new();
+    engine.set_max_operations(SCRIPT_MAX_OPERATIONS);
+    engine.set_max_expr_depths(SCRIPT_MAX_EXPR_DEPTH, SCRIPT_MAX_EXPR_DEPTH);
+    engine.set_max_string_size(SCRIPT_MAX_STRING_SIZE);
+    engine
+});
+
+#[derive(Debug, Clone)]
+pub(crate) struct MatchScript {
+    ast: AST,
+}
+
+impl MatchScript {
+    pub(crate) fn compile(source: &str) -> Result<Self, String> {
+        ENGINE
+            .compile(source)
+            .map(|ast| MatchScript { ast })
+            .map_err(|error| format!("'script' does not compile: {}", error))
+    }
+
+    pub(crate) fn run(&self, win: &WindowInfo, default_layer: &str) -> Result<ScriptMatch, String> {
+        let mut scope = Scope::new();
+        scope.push_constant("window", window_map(win));
+        scope.push_constant("default_layer", default_layer.to_string());
+        let result: Dynamic = ENGINE
+            .eval_ast_with_scope(&mut scope, &self.ast)
+            .map_err(|error| error.to_string())?;
+        if result.is_unit() {
+            return Ok(ScriptMatch::NoMatch);
+        }
+        if let Some(matched) = result.clone().try_cast::<bool>() {
+            return Ok(if matched {
+                ScriptMatch::Match(None)
+            } else {
+                ScriptMatch::NoMatch
+            });
+        }
+        match result.into_string() {
+            Ok(layer) => Ok(ScriptMatch::Match(Some(layer))),
+            Err(type_name) => Err(format!(
+                "returned {}; expected true/false, a layer name or ()",
+                type_name
+            )),
+        }
+    }
+}
+
+/// The window as a Rhai object map. Values the backend doesn't report are `""` (strings) or `0`
+/// (sizes), so string methods work on every field
+fn window_map(win: &WindowInfo) -> Map {
+    let text = |value: Option<&str>| Dynamic::from(value.unwrap_or("").to_string());
+    let (width, height) = win
+        .size
+        .map_or((0, 0), |size| (i64::from(size.width), i64::from(size.height)));
+    let initial = win.initial.as_ref();
+    let mut map = Map::new();
+    map.insert("class".into(), win.class.clone().into());
+    map.insert("title".into(), win.title.clone().into());
+    map.insert("x11_class".into(), text(win.x11_class.as_deref()));
+    map.insert("app".into(), text(win.desktop_id.as_deref()));
+    map.insert("workspace".into(), text(win.workspace.as_deref()));
+    map.insert("instance".into(), text(win.instance.as_deref()));
+    map.insert("role".into(), text(win.role.as_deref()));
+    map.insert("initial_class".into(), text(initial.map(|i| i.class.as_str())));
+    map.insert("initial_title".into(), text(initial.map(|i| i.title.as_str())));
+    map.insert("width".into(), width.into());
+    map.insert("height".into(), height.into());
+    map.insert("is_native_terminal".into(), win.is_native_terminal.into());
+    map
+}
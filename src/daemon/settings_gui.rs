@@ -0,0 +1,412 @@
+//! GTK settings window (`kanata-switcher --settings`, `settings-gui` feature).
+//!
+//! Status, pause, restart and the display settings go through the running daemon's DBus control
+//! interface; without a daemon, display settings are written to its state file.
+//! Rules are edited as the config file (JSON, TOML or YAML); saving validates the file and restarts
+//! the daemon so it picks up the new rules.
+
+use super::{
+    ConfigFormat, DBUS_INTERFACE, DbusInstance, DisplaySettings, Environment, RuntimeStateStore,
+    SETTING_FOCUS_ONLY, SETTING_SHOW_TOP_BAR_ICON, SETTINGS_APP_ID, detect_environment,
+    parse_config_text,
+};
+use gtk4::prelude::*;
+use gtk4::{
+    Application, ApplicationWindow, Box as GtkBox, Button, Label, Orientation, ScrolledWindow,
+    Switch, TextView, glib,
+};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use zbus::zvariant::{OwnedValue, Value};
+
+const STATUS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const WINDOW_MARGIN: i32 = 12;
+
+pub(crate) fn run(
+    config_path: PathBuf,
+    instance: DbusInstance,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let app = Application::builder()
+        .application_id(SETTINGS_APP_ID)
+        .build();
+    app.connect_activate(move |app| build_window(app, &config_path, &instance));
+    // Arguments were already consumed by clap; don't let GTK parse them again
+    let exit_code = app.run_with_args::<&str>(&[]);
+    if exit_code != glib::ExitCode::SUCCESS {
+        return Err(format!("settings window exited with {:?}", exit_code).into());
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+struct DaemonClient {
+    connection: Option<zbus::blocking::Connection>,
+    /// Daemon instance this window controls (`--dbus-suffix`)
+    instance: DbusInstance,
+}
+
+impl DaemonClient {
+    fn connect(instance: DbusInstance) -> Self {
+        let connection = match zbus::blocking::Connection::session() {
+            Ok(connection) => Some(connection),
+            Err(error) => {
+                eprintln!("[Settings] Failed to connect to session bus: {}", error);
+                None
+            }
+        };
+        Self {
+            connection,
+            instance,
+        }
+    }
+
+    fn call(&self, method: &str) -> Result<zbus::Message, String> {
+        let connection = self
+            .connection
+            .as_ref()
+            .ok_or_else(|| "session bus unavailable".to_string())?;
+        connection
+            .call_method(
+                Some(self.instance.name().as_str()),
+                self.instance.path().as_str(),
+                Some(DBUS_INTERFACE),
+                method,
+                &(),
+            )
+            .map_err(|error| error.to_string())
+    }
+
+    /// Status with the layer shown by its display name (`layer_names` in the config)
+    fn status(&self) -> Result<(String, Vec<String>, String), String> {
+        let reply = self.call("GetStatus")?;
+        let (layer, virtual_keys, source, _matched_rules): (String, Vec<String>, String, Vec<i32>) =
+            reply
+                .body()
+                .deserialize()
+                .map_err(|error| error.to_string())?;
+        // Older daemons have no GetLayerDisplayNames
+        let display_names: BTreeMap<String, String> = self
+            .call("GetLayerDisplayNames")
+            .and_then(|reply| {
+                reply
+                    .body()
+                    .deserialize()
+                    .map_err(|error| error.to_string())
+            })
+            .unwrap_or_default();
+        let layer = display_names.get(&layer).cloned().unwrap_or(layer);
+        Ok((layer, virtual_keys, source))
+    }
+
+    fn paused(&self) -> Result<bool, String> {
+        let reply = self.call("GetPaused")?;
+        reply
+            .body()
+            .deserialize()
+            .map_err(|error| error.to_string())
+    }
+
+    fn set_paused(&self, paused: bool) -> Result<(), String> {
+        self.call(if paused { "Pause" } else { "Unpause" })
+            .map(|_| ())
+    }
+
+    fn restart(&self) -> Result<(), String> {
+        self.call("Restart").map(|_| ())
+    }
+
+    /// Boolean display settings by key (`GetSettings`)
+    fn settings(&self) -> Result<BTreeMap<String, bool>, String> {
+        let reply = self.call("GetSettings")?;
+        let settings: BTreeMap<String, OwnedValue> = reply
+            .body()
+            .deserialize()
+            .map_err(|error| error.to_string())?;
+        Ok(settings
+            .into_iter()
+            .filter_map(|(key, value)| match &*value {
+                Value::Bool(value) => Some((key, *value)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn set_setting(&self, key: &str, value: bool) -> Result<(), String> {
+        let connection = self
+            .connection
+            .as_ref()
+            .ok_or_else(|| "session bus unavailable".to_string())?;
+        connection
+            .call_method(
+                Some(self.instance.name().as_str()),
+                self.instance.path().as_str(),
+                Some(DBUS_INTERFACE),
+                "SetSetting",
+                &(key, Value::from(value)),
+            )
+            .map(|_| ())
+            .map_err(|error| error.to_string())
+    }
+}
+
+/// A display setting switch: read from and written to the daemon, or its state file while no
+/// daemon runs
+fn setting_switch(
+    client: &Rc<DaemonClient>,
+    store: &Option<RuntimeStateStore>,
+    key: &'static str,
+) -> Switch {
+    let stored = store
+        .as_ref()
+        .map(|store| DisplaySettings::from_state(&store.load()))
+        .unwrap_or_default();
+    let value = client
+        .settings()
+        .ok()
+        .and_then(|settings| settings.get(key).copied())
+        .unwrap_or_else(|| stored.as_map()[key]);
+    let switch = Switch::new();
+    switch.set_active(value);
+    let client = client.clone();
+    let store = store.clone();
+    switch.connect_state_set(move |_, value| {
+        if let Err(error) = client.set_setting(key, value) {
+            eprintln!("[Settings] Daemon unavailable, storing {}: {}", key, error);
+            if let Some(store) = &store {
+                store.update(|state| {
+                    let previous = DisplaySettings::from_state(state);
+                    let mut settings = previous;
+                    if settings.set(key, value).is_ok() {
+                        settings.store_changes(&previous, state);
+                    }
+                });
+            }
+        }
+        glib::Propagation::Proceed
+    });
+    switch
+}
+
+fn format_status(status: &Result<(String, Vec<String>, String), String>) -> String {
+    match status {
+        Ok((layer, virtual_keys, source)) => {
+            let layer = if layer.is_empty() { "(none)" } else { layer };
+            let virtual_keys = if virtual_keys.is_empty() {
+                "(none)".to_string()
+            } else {
+                virtual_keys.join(", ")
+            };
+            format!(
+                "Layer: {} ({})    Virtual keys: {}",
+                layer, source, virtual_keys
+            )
+        }
+        Err(error) => format!("Daemon not reachable: {}", error),
+    }
+}
+
+/// What the status poll hands to the window
+struct StatusUpdate {
+    status: String,
+    paused: Option<bool>,
+}
+
+/// Polls the daemon on a worker thread, so a slow or hung DBus call doesn't freeze the window
+fn spawn_status_poller(client: DaemonClient) -> mpsc::UnboundedReceiver<StatusUpdate> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        loop {
+            let update = StatusUpdate {
+                status: format_status(&client.status()),
+                paused: client.paused().ok(),
+            };
+            if sender.send(update).is_err() {
+                break;
+            }
+            thread::sleep(STATUS_POLL_INTERVAL);
+        }
+    });
+    receiver
+}
+
+/// Validate config text with the daemon's own parser, as if saved to `config_path`
+fn validate_config_text(text: &str, config_path: &Path) -> Result<usize, String> {
+    parse_config_text(text, config_path).map(|config| config.rules.len())
+}
+
+fn read_config_text(path: &Path) -> String {
+    fs::read_to_string(path).unwrap_or_else(|error| {
+        eprintln!("[Settings] Failed to read {}: {}", path.display(), error);
+        // An empty TOML document is a config with no entries; `[]` is not valid TOML
+        match ConfigFormat::from_path(path) {
+            ConfigFormat::Toml => String::new(),
+            ConfigFormat::Json | ConfigFormat::Yaml => "[]".to_string(),
+        }
+    })
+}
+
+fn text_view_contents(view: &TextView) -> String {
+    let buffer = view.buffer();
+    buffer
+        .text(&buffer.start_iter(), &buffer.end_iter(), false)
+        .to_string()
+}
+
+fn switch_row(title: &str, switch: &Switch) -> GtkBox {
+    let row = GtkBox::new(Orientation::Horizontal, WINDOW_MARGIN);
+    let label = Label::new(Some(title));
+    label.set_xalign(0.0);
+    label.set_hexpand(true);
+    row.append(&label);
+    row.append(switch);
+    row
+}
+
+fn build_window(app: &Application, config_path: &Path, instance: &DbusInstance) {
+    let client = Rc::new(DaemonClient::connect(instance.clone()));
+    let state_store = RuntimeStateStore::open_default(instance);
+
+    let root = GtkBox::new(Orientation::Vertical, WINDOW_MARGIN);
+    root.set_margin_top(WINDOW_MARGIN);
+    root.set_margin_bottom(WINDOW_MARGIN);
+    root.set_margin_start(WINDOW_MARGIN);
+    root.set_margin_end(WINDOW_MARGIN);
+
+    // Status, filled in by the first poll
+    let status_label = Label::new(Some("Connecting to the daemon..."));
+    status_label.set_xalign(0.0);
+    root.append(&status_label);
+
+    // Pause
+    let pause_switch = Switch::new();
+    // Last pause state the daemon reported
+    let daemon_paused = Rc::new(Cell::new(false));
+    {
+        let client = client.clone();
+        let daemon_paused = daemon_paused.clone();
+        pause_switch.connect_state_set(move |_, paused| {
+            // Polling also sets the switch; only send a request on a real change
+            if daemon_paused.get() != paused
+                && let Err(error) = client.set_paused(paused)
+            {
+                eprintln!("[Settings] Failed to change pause state: {}", error);
+            }
+            glib::Propagation::Proceed
+        });
+    }
+    root.append(&switch_row("Paused", &pause_switch));
+
+    // Display settings (shared with the SNI indicator and the GNOME extension)
+    let focus_only_switch = setting_switch(&client, &state_store, SETTING_FOCUS_ONLY);
+    root.append(&switch_row("Show app layer only", &focus_only_switch));
+    if detect_environment() == Environment::Gnome {
+        let top_bar_switch = setting_switch(&client, &state_store, SETTING_SHOW_TOP_BAR_ICON);
+        root.append(&switch_row("Show top bar icon", &top_bar_switch));
+    }
+
+    // Rules
+    let config_label = Label::new(Some(&format!("Rules: {}", config_path.display())));
+    config_label.set_xalign(0.0);
+    root.append(&config_label);
+
+    let rules_view = TextView::builder().monospace(true).build();
+    rules_view.buffer().set_text(&read_config_text(config_path));
+    let scrolled = ScrolledWindow::builder()
+        .vexpand(true)
+        .child(&rules_view)
+        .build();
+    root.append(&scrolled);
+
+    let message_label = Label::new(None);
+    message_label.set_xalign(0.0);
+    message_label.set_wrap(true);
+    root.append(&message_label);
+
+    let buttons = GtkBox::new(Orientation::Horizontal, WINDOW_MARGIN);
+    let revert_button = Button::with_label("Revert");
+    let validate_button = Button::with_label("Validate");
+    let save_button = Button::with_label("Save and Apply");
+    buttons.append(&revert_button);
+    buttons.append(&validate_button);
+    buttons.append(&save_button);
+    root.append(&buttons);
+
+    {
+        let rules_view = rules_view.clone();
+        let message_label = message_label.clone();
+        let config_path = config_path.to_path_buf();
+        revert_button.connect_clicked(move |_| {
+            rules_view
+                .buffer()
+                .set_text(&read_config_text(&config_path));
+            message_label.set_text("Reloaded from disk");
+        });
+    }
+    {
+        let rules_view = rules_view.clone();
+        let message_label = message_label.clone();
+        let config_path = config_path.to_path_buf();
+        validate_button.connect_clicked(move |_| {
+            match validate_config_text(&text_view_contents(&rules_view), &config_path) {
+                Ok(count) => message_label.set_text(&format!("Valid: {} rules", count)),
+                Err(error) => message_label.set_text(&format!("Invalid: {}", error)),
+            }
+        });
+    }
+    {
+        let rules_view = rules_view.clone();
+        let message_label = message_label.clone();
+        let client = client.clone();
+        let config_path = config_path.to_path_buf();
+        save_button.connect_clicked(move |_| {
+            let text = text_view_contents(&rules_view);
+            if let Err(error) = validate_config_text(&text, &config_path) {
+                message_label.set_text(&format!("Not saved, invalid config: {}", error));
+                return;
+            }
+            if let Err(error) = fs::write(&config_path, &text) {
+                message_label.set_text(&format!("Failed to save: {}", error));
+                return;
+            }
+            match client.restart() {
+                Ok(()) => message_label.set_text("Saved; daemon restarted with the new rules"),
+                Err(error) => message_label.set_text(&format!(
+                    "Saved; daemon not restarted ({}). Rules apply on next start.",
+                    error
+                )),
+            }
+        });
+    }
+
+    {
+        let mut updates = spawn_status_poller((*client).clone());
+        let status_label = status_label.clone();
+        let pause_switch = pause_switch.clone();
+        glib::spawn_future_local(async move {
+            while let Some(update) = updates.recv().await {
+                status_label.set_text(&update.status);
+                if let Some(paused) = update.paused {
+                    daemon_paused.set(paused);
+                    if pause_switch.is_active() != paused {
+                        pause_switch.set_active(paused);
+                    }
+                }
+            }
+        });
+    }
+
+    let window = ApplicationWindow::builder()
+        .application(app)
+        .title("Kanata Switcher Settings")
+        .default_width(640)
+        .default_height(560)
+        .child(&root)
+        .build();
+    window.present();
+}
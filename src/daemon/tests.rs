@@ -22,6 +22,16 @@ fn win(class: &str, title: &str) -> WindowInfo {
         class: class.to_string(),
         title: title.to_string(),
         is_native_terminal: false,
+        session_inactive: false,
+        session_locked: false,
+        size: None,
+        initial: None,
+        x11_class: None,
+        desktop_id: None,
+        workspace: None,
+        instance: None,
+        role: None,
+        window_id: None,
     }
 }
 
@@ -34,6 +44,7 @@ fn rule(class: Option<&str>, title: Option<&str>, layer: Option<&str>) -> Rule {
         virtual_key: None,
         raw_vk_action: None,
         fallthrough: false,
+        ..Default::default()
     }
 }
 
@@ -46,6 +57,7 @@ fn rule_vk(class: Option<&str>, virtual_key: &str) -> Rule {
         virtual_key: Some(virtual_key.to_string()),
         raw_vk_action: None,
         fallthrough: false,
+        ..Default::default()
     }
 }
 
@@ -59,10 +71,11 @@ fn rule_raw_vk(class: Option<&str>, raw_vk_action: Vec<(&str, &str)>) -> Rule {
         raw_vk_action: Some(
             raw_vk_action
                 .into_iter()
-                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .map(|(k, v)| RawVkStep::Action(k.to_string(), v.to_string()))
                 .collect(),
         ),
         fallthrough: false,
+        ..Default::default()
     }
 }
 
@@ -164,8 +177,9 @@ fn test_same_rule_different_window_no_action_with_vk_and_raw() {
         on_native_terminal: None,
         layer: Some("global".to_string()),
         virtual_key: Some("vk_global".to_string()),
-        raw_vk_action: Some(vec![("vk_raw".to_string(), "Tap".to_string())]),
+        raw_vk_action: Some(vec![RawVkStep::Action("vk_raw".to_string(), "Tap".to_string())]),
         fallthrough: false,
+        ..Default::default()
     }];
     let mut handler = FocusHandler::new(rules, None, true);
 
@@ -207,6 +221,7 @@ fn test_unfocus_releases_vk_and_switches_to_default() {
         virtual_key: Some("vk_browser".to_string()),
         raw_vk_action: None,
         fallthrough: false,
+        ..Default::default()
     }];
     let mut handler = FocusHandler::new(rules, None, true);
 
@@ -229,7 +244,9 @@ fn test_native_terminal_rule_applies_actions() {
     let native_rule = Some(NativeTerminalRule {
         layer: "tty".to_string(),
         virtual_key: Some("vk_tty".to_string()),
-        raw_vk_action: vec![("vk_notify".to_string(), "Tap".to_string())],
+        raw_vk_action: vec![RawVkStep::Action("vk_notify".to_string(), "Tap".to_string())],
+        silent: false,
+        notify: true,
     });
     let mut handler = FocusHandler::new(rules, native_rule, true);
 
@@ -239,6 +256,16 @@ fn test_native_terminal_rule_applies_actions() {
                 class: String::new(),
                 title: String::new(),
                 is_native_terminal: true,
+                session_inactive: false,
+                session_locked: false,
+                size: None,
+                initial: None,
+                x11_class: None,
+                desktop_id: None,
+                workspace: None,
+                instance: None,
+                role: None,
+                window_id: None,
             },
             "default",
         )
@@ -297,6 +324,50 @@ fn test_autostart_passthrough_args_indicator_focus_only() {
     );
 }
 
+#[test]
+fn test_autostart_passthrough_args_kanata_config() {
+    let matches = Args::command().get_matches_from([
+        "kanata-switcher",
+        "--install-autostart",
+        "--kanata-config",
+        "/tmp/kanata.kbd",
+    ]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    let exec_args = autostart_passthrough_args(&matches, &args);
+    assert_eq!(
+        exec_args,
+        vec!["--kanata-config".to_string(), "/tmp/kanata.kbd".to_string()]
+    );
+}
+
+#[test]
+fn test_check_config_conflicts_with_control_commands() {
+    let result = Args::try_parse_from(["kanata-switcher", "--check-config", "--pause"]);
+    assert!(result.is_err());
+    assert!(Args::try_parse_from(["kanata-switcher", "--check-kanata"]).is_err());
+    assert!(
+        Args::try_parse_from(["kanata-switcher", "--check-config", "--check-kanata", "--dry-run"])
+            .is_err()
+    );
+}
+
+#[cfg(not(feature = "settings-gui"))]
+#[test]
+fn test_settings_window_requires_feature() {
+    let error = open_settings_window(
+        PathBuf::from("/tmp/kanata-switcher.json"),
+        DbusInstance::default(),
+    )
+    .unwrap_err();
+    assert!(error.to_string().contains("settings-gui"));
+}
+
+#[test]
+fn test_print_kanata_names_requires_kanata_config() {
+    let result = Args::try_parse_from(["kanata-switcher", "--print-kanata-names", "layers"]);
+    assert!(result.is_err());
+}
+
 #[test]
 fn test_autostart_desktop_content_escapes_exec() {
     let exec_path = Path::new("/tmp/kanata switcher");
@@ -305,7 +376,8 @@ fn test_autostart_desktop_content_escapes_exec() {
         "-c".to_string(),
         "/tmp/config%file.json".to_string(),
     ];
-    let content = build_autostart_desktop_content(exec_path, &exec_args);
+    let content =
+        build_autostart_desktop_content(exec_path, &exec_args, &AutostartConditions::default());
     assert!(content.contains("Type=Application\n"));
     assert!(content.contains("Name=Kanata Switcher\n"));
     assert!(content.contains("X-GNOME-Autostart-enabled=true\n"));
@@ -313,6 +385,201 @@ fn test_autostart_desktop_content_escapes_exec() {
         "Exec=\"/tmp/kanata switcher\" \"--quiet-focus\" \"-c\" \"/tmp/config%%file.json\"\n"
     ));
     assert!(content.contains("TryExec=\"/tmp/kanata switcher\"\n"));
+    assert!(!content.contains("X-GNOME-Autostart-Delay"));
+    assert!(!content.contains("ShowIn"));
+}
+
+#[test]
+fn test_daemon_status_format() {
+    let status = DaemonStatus {
+        layer: "browser".to_string(),
+        layer_display: Some("Web".to_string()),
+        virtual_keys: vec!["vk_browser".to_string(), "vk_docs".to_string()],
+        source: "focus".to_string(),
+        paused: false,
+        kanata_connected: Some(false),
+        problems: vec!["kanata: connection refused".to_string()],
+    };
+    assert_eq!(
+        status.format(false),
+        "layer: Web (browser)\nvirtual keys: vk_browser, vk_docs\nsource: focus\npaused: no\n\
+         kanata: disconnected\ndegraded: kanata: connection refused"
+    );
+    assert_eq!(
+        status.format(true),
+        r#"{"layer":"browser","layer_display":"Web","virtual_keys":["vk_browser","vk_docs"],"source":"focus","paused":false,"kanata_connected":false,"problems":["kanata: connection refused"]}"#
+    );
+
+    let status = DaemonStatus {
+        layer_display: None,
+        virtual_keys: Vec::new(),
+        kanata_connected: None,
+        problems: Vec::new(),
+        ..status
+    };
+    assert_eq!(
+        status.format(false),
+        "layer: browser\nvirtual keys: none\nsource: focus\npaused: no\nkanata: unknown"
+    );
+    assert!(Args::command().try_get_matches_from(["kanata-switcher", "--json"]).is_err());
+    assert!(
+        Args::command()
+            .try_get_matches_from(["kanata-switcher", "--status", "--pause"])
+            .is_err()
+    );
+}
+
+#[test]
+fn test_healthcheck_from_status() {
+    let status = DaemonStatus {
+        layer: "browser".to_string(),
+        layer_display: None,
+        virtual_keys: Vec::new(),
+        source: "focus".to_string(),
+        paused: false,
+        kanata_connected: Some(true),
+        problems: Vec::new(),
+    };
+    assert_eq!(HealthCheck::from_status(Some(&status)), HealthCheck::Healthy);
+    assert_eq!(HealthCheck::from_status(Some(&status)).exit_code(), 0);
+
+    let older = DaemonStatus {
+        kanata_connected: None,
+        ..status.clone()
+    };
+    assert_eq!(HealthCheck::from_status(Some(&older)), HealthCheck::Healthy);
+
+    let degraded = DaemonStatus {
+        problems: vec!["logind: cannot parse IdleHint".to_string()],
+        ..status.clone()
+    };
+    let result = HealthCheck::from_status(Some(&degraded));
+    assert_eq!(result.exit_code(), 1);
+    assert_eq!(result.message(), "DEGRADED: logind: cannot parse IdleHint");
+
+    // A lost kanata connection outranks other problems
+    let disconnected = DaemonStatus {
+        kanata_connected: Some(false),
+        ..degraded
+    };
+    assert_eq!(
+        HealthCheck::from_status(Some(&disconnected)),
+        HealthCheck::KanataDisconnected
+    );
+    assert_eq!(HealthCheck::from_status(Some(&disconnected)).exit_code(), 2);
+    assert_eq!(HealthCheck::from_status(None).exit_code(), 3);
+    assert!(
+        Args::try_parse_from(["kanata-switcher", "--healthcheck", "--status"]).is_err()
+    );
+}
+
+#[test]
+fn test_waybar_status_line() {
+    let status = DaemonStatus {
+        layer: "browser".to_string(),
+        layer_display: Some("Web".to_string()),
+        virtual_keys: Vec::new(),
+        source: "focus".to_string(),
+        paused: false,
+        kanata_connected: Some(true),
+        problems: Vec::new(),
+    };
+    let tooltip = "layer: Web (browser)\\nvirtual keys: none\\nsource: focus\\npaused: no\\nkanata: connected";
+    assert_eq!(
+        waybar_status_line(Some(&status)),
+        format!(r#"{{"class":"browser","text":"Web","tooltip":"{}"}}"#, tooltip)
+    );
+    let paused = DaemonStatus {
+        layer_display: None,
+        paused: true,
+        ..status
+    };
+    assert!(
+        waybar_status_line(Some(&paused)).starts_with(r#"{"class":"paused","text":"browser (paused)","#)
+    );
+    assert_eq!(
+        waybar_status_line(None),
+        r#"{"class":"stopped","text":"","tooltip":"kanata-switcher is not running"}"#
+    );
+    assert!(
+        Args::try_parse_from(["kanata-switcher", "--follow-status", "--status"]).is_err()
+    );
+}
+
+#[test]
+fn test_wait_for_unit_args_and_states() {
+    let matches = Args::command().get_matches_from([
+        "kanata-switcher",
+        "--install-autostart",
+        "--wait-for-unit",
+        "kanata.service",
+    ]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    assert_eq!(args.wait_for_unit_timeout, DEFAULT_WAIT_FOR_UNIT_TIMEOUT_SECS);
+    assert_eq!(
+        autostart_passthrough_args(&matches, &args),
+        vec!["--wait-for-unit".to_string(), "kanata.service".to_string()]
+    );
+    assert!(
+        Args::command()
+            .try_get_matches_from(["kanata-switcher", "--wait-for-unit-timeout", "5"])
+            .is_err()
+    );
+
+    assert_eq!(unit_wait("loaded", "active"), UnitWait::Ready);
+    assert_eq!(unit_wait("loaded", "reloading"), UnitWait::Ready);
+    assert_eq!(unit_wait("loaded", "activating"), UnitWait::Pending);
+    assert_eq!(unit_wait("loaded", "inactive"), UnitWait::Pending);
+    assert_eq!(unit_wait("loaded", "failed"), UnitWait::Pending);
+    assert_eq!(unit_wait("not-found", "inactive"), UnitWait::NotFound);
+}
+
+#[test]
+fn test_autostart_delay_and_conditions() {
+    let matches = Args::command().get_matches_from([
+        "kanata-switcher",
+        "--install-autostart",
+        "--autostart-delay",
+        "5",
+        "--autostart-only-show-in",
+        "GNOME, KDE;",
+    ]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    let exec_args = autostart_passthrough_args(&matches, &args);
+    assert_eq!(exec_args, vec!["--autostart-delay".to_string(), "5".to_string()]);
+
+    let conditions = AutostartConditions::from_args(&args);
+    assert_eq!(conditions.only_show_in, vec!["GNOME", "KDE"]);
+    let content =
+        build_autostart_desktop_content(Path::new("/usr/bin/kanata-switcher"), &exec_args, &conditions);
+    assert!(content.contains("Exec=\"/usr/bin/kanata-switcher\" \"--autostart-delay\" \"5\"\n"));
+    assert!(content.contains("X-GNOME-Autostart-Delay=5\n"));
+    assert!(content.contains("OnlyShowIn=GNOME;KDE;\n"));
+    assert!(!content.contains("NotShowIn"));
+}
+
+#[test]
+fn test_autostart_show_in_options_conflict_and_require_install() {
+    assert!(
+        Args::try_parse_from([
+            "kanata-switcher",
+            "--install-autostart",
+            "--autostart-only-show-in",
+            "GNOME",
+            "--autostart-not-show-in",
+            "KDE",
+        ])
+        .is_err()
+    );
+    assert!(Args::try_parse_from(["kanata-switcher", "--autostart-not-show-in", "KDE"]).is_err());
+}
+
+#[test]
+fn test_startup_delay_skipped_after_gnome_session_delay() {
+    assert_eq!(startup_delay(Some(3), false), Some(Duration::from_secs(3)));
+    assert_eq!(startup_delay(Some(3), true), None);
+    assert_eq!(startup_delay(Some(0), false), None);
+    assert_eq!(startup_delay(None, false), None);
 }
 
 #[test]
@@ -326,6 +593,16 @@ fn test_native_terminal_without_rule_uses_default() {
                 class: String::new(),
                 title: String::new(),
                 is_native_terminal: true,
+                session_inactive: false,
+                session_locked: false,
+                size: None,
+                initial: None,
+                x11_class: None,
+                desktop_id: None,
+                workspace: None,
+                instance: None,
+                role: None,
+                window_id: None,
             },
             "default",
         )
@@ -338,200 +615,902 @@ fn test_native_terminal_without_rule_uses_default() {
 }
 
 #[test]
-fn test_control_command_restart() {
-    let args = Args::parse_from(["kanata-switcher", "--restart"]);
+fn test_locked_rule() {
+    let rules = vec![Rule {
+        class: Some("kitty".to_string()),
+        layer: Some("terminal".to_string()),
+        virtual_key: Some("vk_term".to_string()),
+        ..Default::default()
+    }];
+    let away_rule = NativeTerminalRule {
+        layer: "away".to_string(),
+        virtual_key: None,
+        raw_vk_action: Vec::new(),
+        silent: false,
+        notify: true,
+    };
+    let locked_rule = NativeTerminalRule {
+        layer: "locked".to_string(),
+        virtual_key: None,
+        raw_vk_action: Vec::new(),
+        silent: false,
+        notify: true,
+    };
+
+    // Without on_locked a locked screen is an inactive session
+    let mut handler = FocusHandler::new(rules.clone(), None, true)
+        .with_session_inactive_rule(Some(away_rule.clone()));
+    assert!(!handler.has_locked_rule());
+    let actions = handler.handle(&session_locked_window(), "base").unwrap();
+    assert_eq!(actions.actions, vec![FocusAction::ChangeLayer("away".to_string())]);
+    assert_eq!(handler.last_matched_rules(), vec![SESSION_INACTIVE_RULE_INDEX]);
+
+    let mut handler = FocusHandler::new(rules, None, true)
+        .with_session_inactive_rule(Some(away_rule))
+        .with_locked_rule(Some(locked_rule));
+    handler.handle(&win("kitty", "zsh"), "base");
+    let actions = handler.handle(&session_locked_window(), "base").unwrap();
     assert_eq!(
-        resolve_control_command(&args),
-        Some(ControlCommand::Restart)
+        actions.actions,
+        vec![
+            FocusAction::ReleaseVk("vk_term".to_string()),
+            FocusAction::ChangeLayer("locked".to_string()),
+        ]
+    );
+    assert_eq!(handler.last_matched_rules(), vec![LOCKED_RULE_INDEX]);
+    assert_eq!(dbus_rule_index(LOCKED_RULE_INDEX), -3);
+    assert_eq!(rule_label(LOCKED_RULE_INDEX), "on_locked");
+
+    // The lock screen taking focus doesn't undo the rule; another user's session does
+    assert!(handler.is_ignored_focus(&win("gnome-shell", "")));
+    let actions = handler.handle(&session_inactive_window(), "base").unwrap();
+    assert_eq!(actions.actions, vec![FocusAction::ChangeLayer("away".to_string())]);
+
+    // Unlocking re-applies the focused window's rule
+    handler.handle(&session_locked_window(), "base");
+    handler.end_session_inactive();
+    let actions = handler.handle(&win("kitty", "zsh"), "base").unwrap();
+    assert_eq!(
+        actions.actions,
+        vec![
+            FocusAction::ChangeLayer("terminal".to_string()),
+            FocusAction::PressVk("vk_term".to_string()),
+        ]
     );
+    let stats = handler.rule_stats();
+    assert_eq!(stats[2].index, LOCKED_RULE_INDEX);
+    assert_eq!(stats[2].summary, "on_locked -> layer=locked");
+    assert_eq!(stats[2].hits, 2);
 }
 
 #[test]
-fn test_control_command_pause() {
-    let args = Args::parse_from(["kanata-switcher", "--pause"]);
-    assert_eq!(resolve_control_command(&args), Some(ControlCommand::Pause));
-}
+fn test_session_inactive_rule() {
+    let rules = vec![rule(Some("kitty"), None, Some("terminal"))];
+    let tty_rule = NativeTerminalRule {
+        layer: "tty".to_string(),
+        virtual_key: None,
+        raw_vk_action: Vec::new(),
+        silent: false,
+        notify: true,
+    };
+    let locked_rule = NativeTerminalRule {
+        layer: "locked".to_string(),
+        virtual_key: Some("vk_locked".to_string()),
+        raw_vk_action: Vec::new(),
+        silent: false,
+        notify: true,
+    };
 
-#[test]
-fn test_control_command_unpause() {
-    let args = Args::parse_from(["kanata-switcher", "--unpause"]);
+    // Without on_session_inactive a lock or user switch counts as a native terminal
+    let mut handler = FocusHandler::new(rules.clone(), Some(tty_rule.clone()), true);
+    assert!(!handler.has_session_inactive_rule());
+    let actions = handler.handle(&session_inactive_window(), "base").unwrap();
+    assert_eq!(actions.actions, vec![FocusAction::ChangeLayer("tty".to_string())]);
+    assert_eq!(handler.last_matched_rules(), vec![NATIVE_TERMINAL_RULE_INDEX]);
+
+    let mut handler = FocusHandler::new(rules, Some(tty_rule), true)
+        .with_session_inactive_rule(Some(locked_rule));
+    handler.handle(&win("kitty", "zsh"), "base");
+    let actions = handler.handle(&session_inactive_window(), "base").unwrap();
     assert_eq!(
-        resolve_control_command(&args),
-        Some(ControlCommand::Unpause)
+        actions.actions,
+        vec![
+            FocusAction::ChangeLayer("locked".to_string()),
+            FocusAction::PressVk("vk_locked".to_string()),
+        ]
     );
-}
+    assert_eq!(handler.last_matched_rules(), vec![SESSION_INACTIVE_RULE_INDEX]);
+    assert_eq!(dbus_rule_index(SESSION_INACTIVE_RULE_INDEX), -2);
 
-#[test]
-fn test_control_command_none() {
-    let args = Args::parse_from(["kanata-switcher"]);
-    assert_eq!(resolve_control_command(&args), None);
+    // The lock screen taking focus doesn't undo the rule, a VT switch does
+    assert!(handler.is_ignored_focus(&win("gnome-shell", "")));
+    assert!(!handler.is_ignored_focus(&native_terminal_window()));
+    let actions = handler.handle(&native_terminal_window(), "base").unwrap();
+    assert_eq!(
+        actions.actions,
+        vec![
+            FocusAction::ReleaseVk("vk_locked".to_string()),
+            FocusAction::ChangeLayer("tty".to_string()),
+        ]
+    );
+    assert!(!handler.is_ignored_focus(&win("kitty", "zsh")));
+
+    handler.handle(&session_inactive_window(), "base");
+    handler.end_session_inactive();
+    assert!(!handler.is_ignored_focus(&win("kitty", "zsh")));
+    let stats = handler.rule_stats();
+    assert_eq!(stats[2].index, SESSION_INACTIVE_RULE_INDEX);
+    assert_eq!(stats[2].summary, "on_session_inactive -> layer=locked");
+    assert_eq!(stats[2].hits, 2);
 }
 
 #[test]
-fn test_sni_format_layer_letter() {
-    assert_eq!(SniIndicator::format_layer_letter("base"), "B");
-    assert_eq!(SniIndicator::format_layer_letter(""), "?");
-    assert_eq!(SniIndicator::format_layer_letter("  "), "?");
+fn test_focus_handler_reloaded_keeps_held_keys() {
+    let config = load_config_json(
+        r#"[{"class": "kitty", "virtual_key": "vk_terminal", "layer": "terminal"}]"#,
+    );
+    let mut handler = FocusHandler::from_config(&config, true, false)
+        .with_config_path(Some(PathBuf::from("/tmp/kanata-switcher.json")));
+    handler.handle(&win("kitty", "zsh"), "base");
+    assert_eq!(handler.current_virtual_keys(), vec!["vk_terminal".to_string()]);
+
+    let config = load_config_json(r#"[{"class": "firefox", "layer": "browser"}]"#);
+    let mut handler = handler.reloaded(&config);
+    assert_eq!(handler.rule_stats().len(), 1);
+    assert!(handler.last_matched_rules().is_empty());
+    assert_eq!(handler.config_path(), Some(Path::new("/tmp/kanata-switcher.json")));
+    // The old rule's key is released once the focused window is re-applied
+    let actions = handler.handle(&win("kitty", "zsh"), "base").unwrap();
+    assert_eq!(
+        actions.actions,
+        vec![
+            FocusAction::ReleaseVk("vk_terminal".to_string()),
+            FocusAction::ChangeLayer("base".to_string()),
+        ]
+    );
 }
 
 #[test]
-fn test_sni_format_virtual_keys() {
-    assert_eq!(SniIndicator::format_virtual_keys(&[]), "");
+fn test_parse_config_reports_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kanata-switcher.json");
+    std::fs::write(&path, r#"[{"default": "base"}, {"default": "other"}]"#).unwrap();
     assert_eq!(
-        SniIndicator::format_virtual_keys(&[String::from("vk_media")]),
-        "V"
+        parse_config(&path).unwrap_err(),
+        "entry #2: multiple 'default' entries found, only one allowed"
     );
+    // Rules are numbered as in logs, not counting the other entries
+    std::fs::write(
+        &path,
+        r#"[{"default": "base"}, {"class": "a"}, {"reload_num": 1, "reload_next": true}]"#,
+    )
+    .unwrap();
     assert_eq!(
-        SniIndicator::format_virtual_keys(&[String::from("a"), String::from("b")]),
-        "2"
+        parse_config(&path).unwrap_err(),
+        "rule #2: 'reload_num' cannot be combined with 'reload_next'"
+    );
+    assert!(
+        parse_config(&dir.path().join("missing.json"))
+            .unwrap_err()
+            .starts_with("Failed to read")
     );
-    let keys = vec!["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"]
-        .into_iter()
-        .map(String::from)
-        .collect::<Vec<_>>();
-    assert_eq!(SniIndicator::format_virtual_keys(&keys), "9+");
-}
-
-fn sni_buffer_has_layer_pixels(buffer: &[u8]) -> bool {
-    buffer.chunks_exact(4).any(|chunk| {
-        let [alpha, red, green, blue] = chunk else {
-            return false;
-        };
-        *alpha > 0 && *red > 0 && *red == *green && *green == *blue
-    })
 }
 
-fn sni_buffer_has_vk_pixels(buffer: &[u8]) -> bool {
-    buffer.chunks_exact(4).any(|chunk| {
-        let [alpha, red, green, blue] = chunk else {
-            return false;
-        };
-        *alpha > 0 && *red == 0 && *green > 0 && *blue > 0
-    })
+/// Stand-in v1 -> v2 migration: `{"base_layer": x}` became `{"default": x}`
+fn rename_base_layer(entries: Vec<serde_json::Value>) -> Result<Vec<serde_json::Value>, String> {
+    Ok(entries
+        .into_iter()
+        .map(|entry| match entry.get("base_layer") {
+            Some(layer) => serde_json::json!({ "default": layer }),
+            None => entry,
+        })
+        .collect())
 }
 
 #[test]
-fn test_sni_icon_color_layers_and_vks() {
-    let icon = SniIndicator::render_icon("A", "B");
-    assert!(sni_buffer_has_layer_pixels(&icon.data));
-    assert!(sni_buffer_has_vk_pixels(&icon.data));
-}
+fn test_config_version_and_migrations() {
+    let migrations: &[ConfigMigration] = &[rename_base_layer];
+    let entries = |json: &str| serde_json::from_str::<Vec<serde_json::Value>>(json).unwrap();
 
-#[test]
-fn test_sni_icon_color_layer_only() {
-    let icon = SniIndicator::render_icon("A", "");
-    assert!(sni_buffer_has_layer_pixels(&icon.data));
-    assert!(!sni_buffer_has_vk_pixels(&icon.data));
-}
+    let (migrated, version) = migrate_config_entries(
+        entries(r#"[{"version": 1}, {"base_layer": "base"}, {"class": "a", "layer": "b"}]"#),
+        migrations,
+    )
+    .unwrap();
+    assert_eq!(version, 1);
+    assert_eq!(
+        migrated,
+        entries(r#"[{"default": "base"}, {"class": "a", "layer": "b"}]"#)
+    );
+    // Unversioned files are version 1; current files are left alone
+    assert_eq!(
+        migrate_config_entries(entries(r#"[{"base_layer": "base"}]"#), migrations)
+            .unwrap()
+            .0,
+        entries(r#"[{"default": "base"}]"#)
+    );
+    let (migrated, version) =
+        migrate_config_entries(entries(r#"[{"base_layer": "x"}, {"version": 2}]"#), migrations)
+            .unwrap();
+    assert_eq!((migrated, version), (entries(r#"[{"base_layer": "x"}]"#), 2));
+
+    for (json, error) in [
+        (
+            r#"[{"version": 3}]"#,
+            "config version 3 is newer than this kanata-switcher supports (2)",
+        ),
+        (r#"[{"version": 0}]"#, "'version' must be a positive integer"),
+        (
+            r#"[{"version": 1}, {"version": 1}]"#,
+            "multiple 'version' entries found, only one allowed",
+        ),
+        (
+            r#"[{"version": 1, "layer": "x"}]"#,
+            "'version' entry should only contain the 'version' field",
+        ),
+    ] {
+        assert_eq!(migrate_config_entries(entries(json), migrations).unwrap_err(), error);
+    }
 
-#[derive(Clone, Default)]
-struct MockSniControlCounts {
-    restart: usize,
-    pause: usize,
-    unpause: usize,
+    // This build's format is version 1
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kanata-switcher.json");
+    std::fs::write(&path, r#"[{"version": 1}, {"default": "base"}]"#).unwrap();
+    assert_eq!(parse_config(&path).unwrap().default_layer.as_deref(), Some("base"));
+    std::fs::write(&path, r#"[{"version": 2}, {"default": "base"}]"#).unwrap();
+    assert!(
+        parse_config(&path)
+            .unwrap_err()
+            .ends_with(": config version 2 is newer than this kanata-switcher supports (1)")
+    );
 }
 
-#[derive(Clone)]
-struct MockSniControl {
-    counts: Arc<Mutex<MockSniControlCounts>>,
-}
+#[test]
+fn test_migrate_config_file() {
+    let migrations: &[ConfigMigration] = &[rename_base_layer];
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kanata-switcher.yaml");
+    let original = "- base_layer: base\n- class: firefox\n  layer: browser\n";
+    std::fs::write(&path, original).unwrap();
+
+    assert_eq!(migrate_config_file(&path, migrations), Ok(Some(1)));
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "- version: 2\n- default: base\n- class: firefox\n  layer: browser\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("kanata-switcher.yaml.v1.bak")).unwrap(),
+        original
+    );
+    assert_eq!(migrate_config_file(&path, migrations), Ok(None));
 
-impl MockSniControl {
-    fn new() -> Self {
-        Self {
-            counts: Arc::new(Mutex::new(MockSniControlCounts::default())),
-        }
-    }
+    let toml_path = dir.path().join("kanata-switcher.toml");
+    std::fs::write(&toml_path, "[[entry]]\nbase_layer = \"base\"\n").unwrap();
+    assert_eq!(migrate_config_file(&toml_path, migrations), Ok(Some(1)));
+    assert_eq!(
+        std::fs::read_to_string(&toml_path).unwrap(),
+        "[[entry]]\nversion = 2\n\n[[entry]]\ndefault = \"base\"\n"
+    );
 
-    fn counts(&self) -> MockSniControlCounts {
-        self.counts.lock().unwrap().clone()
-    }
+    // A migration result the daemon can't parse leaves the file untouched
+    let json_path = dir.path().join("kanata-switcher.json");
+    std::fs::write(&json_path, r#"[{"base_layer": 5}]"#).unwrap();
+    assert!(
+        migrate_config_file(&json_path, migrations)
+            .unwrap_err()
+            .starts_with("entry #1 after migration: ")
+    );
+    assert_eq!(
+        std::fs::read_to_string(&json_path).unwrap(),
+        r#"[{"base_layer": 5}]"#
+    );
 }
 
-impl SniControlOps for MockSniControl {
-    fn restart(&self) {
-        self.counts.lock().unwrap().restart += 1;
-    }
-
-    fn pause(&self) {
-        self.counts.lock().unwrap().pause += 1;
-    }
+#[tokio::test]
+async fn test_rule_tests_in_config() {
+    let config = load_config_json(
+        r#"[
+        {"default": "base"},
+        {"name": "Firefox", "class": "firefox", "layer": "browser", "virtual_key": "vk_browser", "fallthrough": true},
+        {"class": "firefox", "title": "YouTube", "virtual_key": "vk_youtube"},
+        {"class": "kitty", "title": "vim", "layer": "vim"},
+        {"tests": [
+            {"class": "firefox", "title": "YouTube - Mozilla Firefox", "layer": "browser", "virtual_keys": ["vk_browser", "vk_youtube"]},
+            {"class": "kitty", "title": "zsh", "layer": "base"},
+            {"class": "kitty", "title": "nvim", "layer": "terminal"},
+            {"class": "firefox", "title": "Docs", "virtual_keys": ["vk_browser", "vk_docs"]}
+        ]}
+    ]"#,
+    );
+    assert_eq!(config.rule_tests.len(), 4);
+    assert_eq!(
+        rule_test_failures(&config).await,
+        vec![
+            "test #3 (class=\"kitty\" title=\"nvim\"): expected layer \"terminal\", got \"vim\" (matched #3)".to_string(),
+            "test #4 (class=\"firefox\" title=\"Docs\"): expected virtual keys [vk_browser, vk_docs], got [vk_browser] (matched 'Firefox')".to_string(),
+        ]
+    );
 
-    fn unpause(&self) {
-        self.counts.lock().unwrap().unpause += 1;
+    for (json, error) in [
+        (
+            r#"[{"tests": [{"layer": "base"}]}]"#,
+            "entry #1: 'tests' #1: needs 'class' or 'title'",
+        ),
+        (
+            r#"[{"tests": [{"class": "a"}]}]"#,
+            "entry #1: 'tests' #1: needs 'layer' or 'virtual_keys' to check",
+        ),
+        (
+            r#"[{"tests": []}, {"tests": []}]"#,
+            "entry #2: multiple 'tests' entries found, only one allowed",
+        ),
+    ] {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kanata-switcher.json");
+        std::fs::write(&path, json).unwrap();
+        assert_eq!(parse_config(&path).unwrap_err(), error);
     }
 }
 
-#[derive(Default)]
-struct MockDconfState {
-    get_calls: usize,
-    set_calls: Vec<bool>,
-}
-
-struct MockDconfBackend {
-    state: Arc<Mutex<MockDconfState>>,
-    get_results: Arc<Mutex<Vec<Result<bool, String>>>>,
-    set_results: Arc<Mutex<Vec<Result<(), String>>>>,
-}
+#[tokio::test]
+async fn test_match_window_report() {
+    let config = load_config_json(
+        r#"[
+        {"default": "base"},
+        {"name": "Firefox", "class": "^firefox$", "layer": "browser", "virtual_key": "vk_browser", "fallthrough": true},
+        {"title": "YouTube", "raw_vk_action": [["vk_video", "Tap"]]},
+        {"class": "^kitty$", "layer": "term", "on_focus_cmd": "true"}
+    ]"#,
+    );
+    assert_eq!(
+        match_window_report(&config, "firefox", "YouTube - Mozilla Firefox", "base").await,
+        [
+            "Window: class=\"firefox\" title=\"YouTube - Mozilla Firefox\"",
+            "Matched:",
+            "  #1 'Firefox': class=\"^firefox$\" -> layer=browser virtual_key=vk_browser fallthrough",
+            "  #2: title=\"YouTube\" -> raw_vk_action",
+            "Layer: browser",
+            "Virtual keys held: vk_browser",
+            "Actions:",
+            "  switch to layer \"browser\"",
+            "  press virtual key \"vk_browser\"",
+            "  Tap virtual key \"vk_video\"",
+        ]
+        .join("\n")
+    );
+    assert_eq!(
+        match_window_report(&config, "kitty", "zsh", "base").await,
+        [
+            "Window: class=\"kitty\" title=\"zsh\"",
+            "Matched:",
+            "  #3: class=\"^kitty$\" -> layer=term on_focus_cmd",
+            "Layer: term",
+            "Virtual keys held: none",
+            "Actions:",
+            "  switch to layer \"term\"",
+            "  run on_focus_cmd of #3: true",
+        ]
+        .join("\n")
+    );
+    assert_eq!(
+        match_window_report(&config, "gimp", "", "base").await,
+        [
+            "Window: class=\"gimp\" title=\"\"",
+            "Matched: no rule",
+            "Layer: base (default)",
+            "Virtual keys held: none",
+            "Actions:",
+            "  switch to layer \"base\"",
+        ]
+        .join("\n")
+    );
 
-impl MockDconfBackend {
-    fn new(
-        state: Arc<Mutex<MockDconfState>>,
-        get_results: Vec<Result<bool, String>>,
-        set_results: Vec<Result<(), String>>,
-    ) -> Self {
-        Self {
-            state,
-            get_results: Arc::new(Mutex::new(get_results)),
-            set_results: Arc::new(Mutex::new(set_results)),
-        }
+    let args = Args::try_parse_from(["kanata-switcher", "--match", "firefox", ""]).unwrap();
+    assert_eq!(
+        args.match_window,
+        Some(vec!["firefox".to_string(), String::new()])
+    );
+    assert!(Args::try_parse_from(["kanata-switcher", "--match", "firefox"]).is_err());
+    assert!(
+        Args::try_parse_from(["kanata-switcher", "--match", "a", "b", "--check-config"]).is_err()
+    );
+}
+
+#[test]
+fn test_status_property_changes() {
+    let last = StatusSnapshot {
+        layer: "base".to_string(),
+        virtual_keys: vec!["vk_a".to_string()],
+        layer_source: LayerSource::Focus,
+        matched_rules: Vec::new(),
+    };
+    assert!(status_property_changes(&last, &last.clone()).is_empty());
+
+    let layer_only = StatusSnapshot {
+        layer: "browser".to_string(),
+        ..last.clone()
+    };
+    assert_eq!(
+        status_property_changes(&last, &layer_only),
+        HashMap::from([("CurrentLayer", Value::from("browser"))])
+    );
+
+    let source_only = StatusSnapshot {
+        layer_source: LayerSource::External,
+        ..last.clone()
+    };
+    assert!(status_property_changes(&last, &source_only).is_empty());
+
+    let both = StatusSnapshot {
+        layer: "term".to_string(),
+        virtual_keys: Vec::new(),
+        ..last.clone()
+    };
+    assert_eq!(
+        status_property_changes(&last, &both),
+        HashMap::from([
+            ("CurrentLayer", Value::from("term")),
+            ("VirtualKeys", Value::from(Vec::<String>::new())),
+        ])
+    );
+}
+
+#[test]
+fn test_config_format_from_extension() {
+    assert_eq!(ConfigFormat::from_path(Path::new("a.json")), ConfigFormat::Json);
+    assert_eq!(ConfigFormat::from_path(Path::new("a.toml")), ConfigFormat::Toml);
+    assert_eq!(ConfigFormat::from_path(Path::new("a.yaml")), ConfigFormat::Yaml);
+    assert_eq!(ConfigFormat::from_path(Path::new("a.YML")), ConfigFormat::Yaml);
+    assert_eq!(ConfigFormat::from_path(Path::new("config")), ConfigFormat::Json);
+}
+
+#[test]
+fn test_parse_config_toml_and_yaml() {
+    let dir = tempfile::tempdir().unwrap();
+    let toml_path = dir.path().join("kanata-switcher.toml");
+    std::fs::write(
+        &toml_path,
+        r#"
+# Comments are allowed
+[[entry]]
+default = "base"
+
+[[entry]]
+min_focus_ms = 150
+
+[[entry]]
+class = "^(firefox|chromium)$"
+layer = "browser"
+virtual_key = "vk_browser"
+"#,
+    )
+    .unwrap();
+    let yaml_path = dir.path().join("kanata-switcher.yaml");
+    std::fs::write(
+        &yaml_path,
+        r#"
+# Comments are allowed
+- default: base
+- min_focus_ms: 150
+- class: "^(firefox|chromium)$"
+  layer: browser
+  virtual_key: vk_browser
+"#,
+    )
+    .unwrap();
+
+    for path in [&toml_path, &yaml_path] {
+        let config = parse_config(path).unwrap();
+        assert_eq!(config.default_layer.as_deref(), Some("base"));
+        assert_eq!(config.min_focus_ms, 150);
+        assert_eq!(config.rules.len(), 1);
+        assert_eq!(config.rules[0].class.as_deref(), Some("^(firefox|chromium)$"));
+        assert_eq!(config.rules[0].layer.as_deref(), Some("browser"));
+        assert_eq!(config.rules[0].virtual_key.as_deref(), Some("vk_browser"));
     }
+}
 
-    fn next_get_result(&self) -> Result<bool, String> {
-        let mut results = self.get_results.lock().unwrap();
-        if results.is_empty() {
-            return Err("no mock get results left".to_string());
-        }
-        results.remove(0)
+#[test]
+fn test_parse_config_toml_and_yaml_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let toml_path = dir.path().join("kanata-switcher.toml");
+    std::fs::write(&toml_path, "[[rules]]\nlayer = \"base\"\n").unwrap();
+    assert!(
+        parse_config(&toml_path)
+            .unwrap_err()
+            .starts_with("Failed to parse")
+    );
+    let yaml_path = dir.path().join("kanata-switcher.yml");
+    std::fs::write(&yaml_path, "- default: base\n  layer: other\n").unwrap();
+    assert!(
+        parse_config(&yaml_path)
+            .unwrap_err()
+            .contains("'default' entry should only contain the 'default' field")
+    );
+}
+
+#[test]
+fn test_parse_config_text_checks_unsaved_text_as_the_file() {
+    // The path only picks the format and anchors plugins_dir; it doesn't have to exist
+    let path = Path::new("/nonexistent/kanata-switcher.yaml");
+    let config = parse_config_text("- default: base\n- plugins_dir: plugins\n", path).unwrap();
+    assert_eq!(config.default_layer.as_deref(), Some("base"));
+    assert_eq!(config.plugins_dir, Some(PathBuf::from("/nonexistent/plugins")));
+    // Rule-level errors, not just syntax ones
+    assert_eq!(
+        parse_config_text("- default: base\n- default: other\n", path).unwrap_err(),
+        parse_config_text(
+            "[{\"default\": \"base\"}, {\"default\": \"other\"}]",
+            Path::new("kanata-switcher.json")
+        )
+        .unwrap_err()
+    );
+    assert!(
+        parse_config_text("- class: \"(\"\n  layer: a\n", path)
+            .unwrap_err()
+            .contains("rule #1")
+    );
+}
+
+#[test]
+fn test_rule_names() {
+    let config = load_config_json(
+        r#"[
+            {"name": "Browsers", "class": "firefox", "layer": "browser", "fallthrough": true},
+            {"class": "firefox", "title": "YouTube", "virtual_key": "vk_video"}
+        ]"#,
+    );
+    assert_eq!(config.rules[0].name.as_deref(), Some("Browsers"));
+    let mut handler = FocusHandler::new(config.rules, None, true);
+    assert_eq!(handler.rule_names(), BTreeMap::from([(0, "Browsers".to_string())]));
+
+    handler.handle(&win("firefox", "YouTube"), "base");
+    let labels: Vec<String> = handler
+        .last_matched_rule_refs()
+        .iter()
+        .map(RuleRef::label)
+        .collect();
+    assert_eq!(labels, vec!["'Browsers'".to_string(), "#2".to_string()]);
+}
+
+#[test]
+fn test_special_rule_rejects_name() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kanata-switcher.json");
+    std::fs::write(&path, r#"[{"on_native_terminal": "tty", "name": "TTY"}]"#).unwrap();
+    assert_eq!(
+        parse_config(&path).unwrap_err(),
+        "entry #1: 'on_native_terminal' cannot be combined with 'name' (it is already labelled 'on_native_terminal')"
+    );
+}
+
+#[test]
+fn test_config_parses_session_inactive_rule() {
+    let config = load_config_json(
+        r#"[
+            {"on_native_terminal": "tty"},
+            {"on_session_inactive": "locked", "virtual_key": "vk_locked"}
+        ]"#,
+    );
+    assert!(config.rules.is_empty());
+    assert_eq!(config.native_terminal_rule.unwrap().layer, "tty");
+    let rule = config.session_inactive_rule.unwrap();
+    assert_eq!(rule.layer, "locked");
+    assert_eq!(rule.virtual_key.as_deref(), Some("vk_locked"));
+}
+
+#[test]
+fn test_config_parses_locked_rule() {
+    let config = load_config_json(
+        r#"[
+            {"on_session_inactive": "away"},
+            {"on_locked": "locked", "virtual_key": "vk_locked"}
+        ]"#,
+    );
+    assert_eq!(config.session_inactive_rule.unwrap().layer, "away");
+    let rule = config.locked_rule.unwrap();
+    assert_eq!(rule.layer, "locked");
+    assert_eq!(rule.virtual_key.as_deref(), Some("vk_locked"));
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kanata-switcher.json");
+    std::fs::write(&path, r#"[{"on_locked": "a", "on_session_inactive": "b"}]"#).unwrap();
+    assert_eq!(
+        parse_config(&path).unwrap_err(),
+        "entry #1: 'on_locked' cannot be combined with 'on_native_terminal' or 'on_session_inactive'"
+    );
+    std::fs::write(&path, r#"[{"on_locked": "a"}, {"on_locked": "b"}]"#).unwrap();
+    assert_eq!(
+        parse_config(&path).unwrap_err(),
+        "entry #2: multiple 'on_locked' rules found, only one allowed"
+    );
+    std::fs::write(&path, r#"[{"on_locked": "a", "class": "firefox"}]"#).unwrap();
+    assert_eq!(
+        parse_config(&path).unwrap_err(),
+        "entry #1: 'on_locked' cannot be combined with 'class', 'title', 'app', 'instance', 'role', 'script', 'command' or 'plugin'"
+    );
+}
+
+#[test]
+fn test_screen_locker_active() {
+    let signal = |interface: &str, member: &str, active: bool| {
+        Message::signal("/org/gnome/ScreenSaver", interface, member)
+            .unwrap()
+            .build(&(active,))
+            .unwrap()
+    };
+    assert_eq!(
+        screen_locker_active(&signal("org.gnome.ScreenSaver", "ActiveChanged", true)),
+        Some(true)
+    );
+    assert_eq!(
+        screen_locker_active(&signal("org.freedesktop.ScreenSaver", "ActiveChanged", false)),
+        Some(false)
+    );
+    assert_eq!(
+        screen_locker_active(&signal("org.example.Player", "ActiveChanged", true)),
+        None
+    );
+    assert_eq!(
+        screen_locker_active(&signal("org.gnome.ScreenSaver", "WakeUpScreen", true)),
+        None
+    );
+}
+
+#[test]
+fn test_classify_session_state() {
+    assert_eq!(classify_session_state(true, false, None), SessionState::Active);
+    assert_eq!(classify_session_state(true, true, None), SessionState::Locked);
+    assert_eq!(
+        classify_session_state(false, false, Some(("tty", "user"))),
+        SessionState::NativeTerminal
+    );
+    assert_eq!(
+        classify_session_state(false, false, None),
+        SessionState::NativeTerminal
+    );
+    assert_eq!(
+        classify_session_state(false, false, Some(("wayland", "user"))),
+        SessionState::Inactive
+    );
+    assert_eq!(
+        classify_session_state(false, false, Some(("x11", "greeter"))),
+        SessionState::Inactive
+    );
+}
+
+#[test]
+fn test_control_command_restart() {
+    let args = Args::parse_from(["kanata-switcher", "--restart"]);
+    assert_eq!(
+        resolve_control_command(&args),
+        Some(ControlCommand::Restart)
+    );
+}
+
+#[test]
+fn test_control_command_reload() {
+    let args = Args::parse_from(["kanata-switcher", "--reload"]);
+    assert_eq!(resolve_control_command(&args), Some(ControlCommand::Reload));
+    assert!(Args::try_parse_from(["kanata-switcher", "--reload", "--restart"]).is_err());
+}
+
+#[test]
+fn test_control_command_pause() {
+    let args = Args::parse_from(["kanata-switcher", "--pause"]);
+    assert_eq!(resolve_control_command(&args), Some(ControlCommand::Pause));
+}
+
+#[test]
+fn test_control_command_unpause() {
+    let args = Args::parse_from(["kanata-switcher", "--unpause"]);
+    assert_eq!(
+        resolve_control_command(&args),
+        Some(ControlCommand::Unpause)
+    );
+}
+
+#[test]
+fn test_control_command_none() {
+    let args = Args::parse_from(["kanata-switcher"]);
+    assert_eq!(resolve_control_command(&args), None);
+}
+
+#[test]
+fn test_sni_format_layer_letter() {
+    assert_eq!(SniIndicator::format_layer_letter("base", LetterStyle::Uppercase), "B");
+    assert_eq!(SniIndicator::format_layer_letter("", LetterStyle::Uppercase), "?");
+    assert_eq!(SniIndicator::format_layer_letter("  ", LetterStyle::Uppercase), "?");
+    assert_eq!(SniIndicator::format_layer_letter("Base", LetterStyle::Lowercase), "b");
+    assert_eq!(SniIndicator::format_layer_letter(" gaming", LetterStyle::TwoLetters), "Ga");
+    assert_eq!(SniIndicator::format_layer_letter("q", LetterStyle::TwoLetters), "Q");
+    assert_eq!(SniIndicator::format_layer_letter("", LetterStyle::TwoLetters), "?");
+}
+
+#[test]
+fn test_sni_format_virtual_keys() {
+    assert_eq!(SniIndicator::format_virtual_keys(&[]), "");
+    assert_eq!(
+        SniIndicator::format_virtual_keys(&[String::from("vk_media")]),
+        "V"
+    );
+    assert_eq!(
+        SniIndicator::format_virtual_keys(&[String::from("a"), String::from("b")]),
+        "2"
+    );
+    let keys = vec!["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"]
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    assert_eq!(SniIndicator::format_virtual_keys(&keys), "9+");
+}
+
+fn sni_buffer_has_layer_pixels(buffer: &[u8]) -> bool {
+    buffer.chunks_exact(4).any(|chunk| {
+        let [alpha, red, green, blue] = chunk else {
+            return false;
+        };
+        *alpha > 0 && *red > 0 && *red == *green && *green == *blue
+    })
+}
+
+fn sni_buffer_has_vk_pixels(buffer: &[u8]) -> bool {
+    buffer.chunks_exact(4).any(|chunk| {
+        let [alpha, red, green, blue] = chunk else {
+            return false;
+        };
+        *alpha > 0 && *red == 0 && *green > 0 && *blue > 0
+    })
+}
+
+#[test]
+fn test_sni_icon_color_layers_and_vks() {
+    let icon = SniIndicator::render_icon(&IndicatorTheme::default(), "base", "A", "B");
+    assert!(sni_buffer_has_layer_pixels(&icon.data));
+    assert!(sni_buffer_has_vk_pixels(&icon.data));
+}
+
+#[test]
+fn test_sni_icon_color_layer_only() {
+    let icon = SniIndicator::render_icon(&IndicatorTheme::default(), "base", "A", "");
+    assert!(sni_buffer_has_layer_pixels(&icon.data));
+    assert!(!sni_buffer_has_vk_pixels(&icon.data));
+}
+
+#[test]
+fn test_sni_icon_theme() {
+    let theme = IndicatorTheme {
+        background: Some(IconColor::parse("#000000").unwrap()),
+        size: 16,
+        layer_colors: BTreeMap::from([(
+            "gaming".to_string(),
+            IconColor::parse("#ff0000").unwrap(),
+        )]),
+        ..IndicatorTheme::default()
+    };
+    let icon = SniIndicator::render_icon(&theme, "base", "Ba", "");
+    assert_eq!(icon.height, 16);
+    assert_eq!(
+        icon.width as usize,
+        2 * get_raster_width(SNI_FONT_WEIGHT, RasterHeight::Size16)
+    );
+    // Opaque everywhere: background where no glyph is drawn
+    assert!(icon.data.chunks_exact(4).all(|pixel| pixel[0] == 255));
+    assert!(icon.data.chunks_exact(4).any(|pixel| pixel == [255, 0, 0, 0]));
+    assert!(sni_buffer_has_layer_pixels(&icon.data));
+
+    let icon = SniIndicator::render_icon(&theme, "gaming", "G", "");
+    assert!(icon.data.chunks_exact(4).any(|pixel| pixel[1] > 0 && pixel[2] == 0 && pixel[3] == 0));
+    assert!(!sni_buffer_has_layer_pixels(&icon.data));
+}
+
+#[test]
+fn test_config_parses_indicator_theme() {
+    let config = load_config_json(
+        r##"[{"indicator": {"color": "#00ff00", "vk_color": "#ffff0080", "background": "#202020", "size": 24, "letters": "two_letters", "layer_colors": {"gaming": "#FF0000"}}}]"##,
+    );
+    let theme = &config.indicator;
+    assert_eq!(theme.color, IconColor([255, 0, 255, 0]));
+    assert_eq!(theme.vk_color, IconColor([0x80, 255, 255, 0]));
+    assert_eq!(theme.background, Some(IconColor([255, 0x20, 0x20, 0x20])));
+    assert_eq!(theme.raster_height().val(), 24);
+    assert_eq!(theme.letters, LetterStyle::TwoLetters);
+    assert_eq!(theme.layer_color("gaming"), IconColor([255, 255, 0, 0]));
+    assert_eq!(theme.layer_color("base"), theme.color);
+    assert_eq!(
+        serde_json::to_value(theme).unwrap(),
+        serde_json::json!({
+            "color": "#00ff00",
+            "vk_color": "#ffff0080",
+            "background": "#202020",
+            "size": 24,
+            "letters": "two_letters",
+            "layer_colors": {"gaming": "#ff0000"}
+        })
+    );
+    let config = load_config_json(r#"[{"class": "a", "layer": "vim"}]"#);
+    assert_eq!(config.indicator, IndicatorTheme::default());
+
+    for json in [
+        r#"[{"indicator": {"color": "white"}}]"#,
+        r##"[{"indicator": {"color": "#fff"}}]"##,
+        r##"[{"indicator": {"color": "#gggggg"}}]"##,
+        r#"[{"indicator": {"letters": "three"}}]"#,
+        r##"[{"indicator": {"colour": "#ffffff"}}]"##,
+        r#"[{"indicator": {}, "layer": "base"}]"#,
+    ] {
+        let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+        assert!(result.is_err(), "{}", json);
     }
 
-    fn next_set_result(&self) -> Result<(), String> {
-        let mut results = self.set_results.lock().unwrap();
-        if results.is_empty() {
-            return Err("no mock set results left".to_string());
-        }
-        results.remove(0)
+    for (json, error) in [
+        (
+            r#"[{"indicator": {"size": 22}}]"#,
+            "entry #1: 'indicator': 'size' must be 16, 20, 24 or 32, got 22",
+        ),
+        (
+            r#"[{"indicator": {}}, {"indicator": {}}]"#,
+            "entry #2: multiple 'indicator' entries found, only one allowed",
+        ),
+    ] {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kanata-switcher.json");
+        std::fs::write(&path, json).unwrap();
+        assert_eq!(parse_config(&path).unwrap_err(), error);
     }
 }
 
-impl DconfBackend for MockDconfBackend {
-    fn get_bool(&self, _key: &str) -> Result<bool, String> {
-        let mut state = self.state.lock().unwrap();
-        state.get_calls += 1;
-        self.next_get_result()
+#[derive(Clone, Default)]
+struct MockSniControlCounts {
+    restart: usize,
+    pause: usize,
+    unpause: usize,
+    layers: Vec<String>,
+    pinned: Vec<String>,
+    unpin: usize,
+}
+
+#[derive(Clone)]
+struct MockSniControl {
+    counts: Arc<Mutex<MockSniControlCounts>>,
+}
+
+impl MockSniControl {
+    fn new() -> Self {
+        Self {
+            counts: Arc::new(Mutex::new(MockSniControlCounts::default())),
+        }
     }
 
-    fn set_bool(&self, _key: &str, value: bool) -> Result<(), String> {
-        let mut state = self.state.lock().unwrap();
-        state.set_calls.push(value);
-        self.next_set_result()
+    fn counts(&self) -> MockSniControlCounts {
+        self.counts.lock().unwrap().clone()
     }
 }
 
-fn mock_dconf_backend_sequence(
-    get_results: Vec<Result<bool, String>>,
-    set_results: Vec<Result<(), String>>,
-) -> (Box<dyn DconfBackend>, Arc<Mutex<MockDconfState>>) {
-    let state = Arc::new(Mutex::new(MockDconfState::default()));
-    let backend = MockDconfBackend::new(state.clone(), get_results, set_results);
-    (Box::new(backend), state)
-}
+impl SniControlOps for MockSniControl {
+    fn restart(&self) {
+        self.counts.lock().unwrap().restart += 1;
+    }
+
+    fn pause(&self) {
+        self.counts.lock().unwrap().pause += 1;
+    }
+
+    fn unpause(&self) {
+        self.counts.lock().unwrap().unpause += 1;
+    }
+
+    fn set_layer(&self, layer: &str) {
+        self.counts.lock().unwrap().layers.push(layer.to_string());
+    }
+
+    fn pin_layer(&self, layer: &str) {
+        self.counts.lock().unwrap().pinned.push(layer.to_string());
+    }
 
-fn mock_dconf_backend(
-    get_result: Result<bool, String>,
-    set_result: Result<(), String>,
-) -> (Box<dyn DconfBackend>, Arc<Mutex<MockDconfState>>) {
-    mock_dconf_backend_sequence(vec![get_result], vec![set_result])
+    fn unpin_layer(&self) {
+        self.counts.lock().unwrap().unpin += 1;
+    }
 }
 
 #[test]
@@ -540,6 +1519,7 @@ fn test_sni_indicator_state_focus_only() {
         layer: "base".to_string(),
         virtual_keys: Vec::new(),
         layer_source: LayerSource::External,
+        matched_rules: Vec::new(),
     };
     let mut state = SniIndicatorState::new(initial.clone(), SNI_DEFAULT_SHOW_FOCUS_ONLY);
     assert_eq!(state.display_status().layer, "base");
@@ -548,6 +1528,7 @@ fn test_sni_indicator_state_focus_only() {
         layer: "browser".to_string(),
         virtual_keys: vec!["vk_browser".to_string()],
         layer_source: LayerSource::Focus,
+        matched_rules: Vec::new(),
     };
     state.update_status(focus_status.clone());
     assert_eq!(state.display_status().layer, "browser");
@@ -559,6 +1540,7 @@ fn test_sni_indicator_state_focus_only() {
         layer: "external".to_string(),
         virtual_keys: Vec::new(),
         layer_source: LayerSource::External,
+        matched_rules: Vec::new(),
     };
     state.update_status(external_status.clone());
     assert_eq!(state.display_status().layer, "external");
@@ -576,6 +1558,7 @@ fn test_sni_indicator_state_initial_focus_only_false() {
         layer: "base".to_string(),
         virtual_keys: Vec::new(),
         layer_source: LayerSource::External,
+        matched_rules: Vec::new(),
     };
     let mut state = SniIndicatorState::new(initial.clone(), false);
 
@@ -583,6 +1566,7 @@ fn test_sni_indicator_state_initial_focus_only_false() {
         layer: "browser".to_string(),
         virtual_keys: vec!["vk_browser".to_string()],
         layer_source: LayerSource::Focus,
+        matched_rules: Vec::new(),
     };
     state.update_status(focus_status);
 
@@ -590,6 +1574,7 @@ fn test_sni_indicator_state_initial_focus_only_false() {
         layer: "external".to_string(),
         virtual_keys: Vec::new(),
         layer_source: LayerSource::External,
+        matched_rules: Vec::new(),
     };
     state.update_status(external_status);
 
@@ -597,87 +1582,158 @@ fn test_sni_indicator_state_initial_focus_only_false() {
 }
 
 #[test]
-fn test_sni_settings_store_reads_from_dconf() {
-    let (backend, state) = mock_dconf_backend(Ok(false), Ok(()));
-    let mut store = SniSettingsStore::with_backend(backend);
-    let value = store.read_focus_only();
-    assert_eq!(value, Some(false));
-    let state = state.lock().unwrap();
-    assert_eq!(state.get_calls, 1);
-    assert!(state.set_calls.is_empty());
+fn test_display_settings_from_state_and_set() {
+    assert_eq!(
+        DisplaySettings::from_state(&RuntimeState::default()),
+        DisplaySettings::default()
+    );
+    let state = RuntimeState {
+        sni_focus_only: Some(false),
+        ..Default::default()
+    };
+    let mut settings = DisplaySettings::from_state(&state);
+    assert!(!settings.focus_only);
+    assert!(settings.show_top_bar_icon);
+
+    settings.set(SETTING_SHOW_TOP_BAR_ICON, false).unwrap();
+    assert_eq!(
+        settings.as_map(),
+        BTreeMap::from([(SETTING_FOCUS_ONLY, false), (SETTING_SHOW_TOP_BAR_ICON, false)])
+    );
+    assert_eq!(
+        settings.set("nope", true),
+        Err("unknown setting 'nope'".to_string())
+    );
 }
 
 #[test]
-fn test_sni_settings_store_writes_to_dconf() {
-    let (backend, state) = mock_dconf_backend(Ok(true), Ok(()));
-    let mut store = SniSettingsStore::with_backend(backend);
-    store.write_focus_only(false);
-    let state = state.lock().unwrap();
-    assert_eq!(state.set_calls, vec![false]);
+fn test_display_settings_store_only_changes() {
+    let previous = DisplaySettings {
+        focus_only: false,
+        show_top_bar_icon: true,
+    };
+    let current = DisplaySettings {
+        show_top_bar_icon: false,
+        ..previous
+    };
+    let mut state = RuntimeState::default();
+    current.store_changes(&previous, &mut state);
+    assert_eq!(state.sni_focus_only, None);
+    assert_eq!(state.show_top_bar_icon, Some(false));
 }
 
 #[test]
-fn test_sni_settings_store_read_error_disables_write() {
-    let (backend, state) = mock_dconf_backend(
-        Err("No such file or directory".to_string()),
-        Ok(()),
+fn test_legacy_display_settings_migration() {
+    assert_eq!(parse_dconf_bool("false\n"), Some(false));
+    assert_eq!(parse_dconf_bool("true\n"), Some(true));
+    // Never changed: dconf prints nothing
+    assert_eq!(parse_dconf_bool(""), None);
+    assert_eq!(parse_dconf_bool("'yes'"), None);
+
+    let focus_only = !DisplaySettings::default().focus_only;
+    let mut state = RuntimeState::default();
+    store_legacy_display_settings(
+        &mut state,
+        &[(SETTING_SHOW_TOP_BAR_ICON, false), (SETTING_FOCUS_ONLY, focus_only)],
     );
-    let mut store = SniSettingsStore::with_backend(backend);
-    let value = store.read_focus_only();
-    assert_eq!(value, None);
-    store.write_focus_only(true);
-    let state = state.lock().unwrap();
-    assert_eq!(state.get_calls, 1);
-    assert!(state.set_calls.is_empty());
+    assert_eq!(state.show_top_bar_icon, Some(false));
+    assert_eq!(state.sni_focus_only, Some(focus_only));
+
+    // A value equal to the default leaves the state alone
+    let mut state = RuntimeState::default();
+    store_legacy_display_settings(&mut state, &[(SETTING_SHOW_TOP_BAR_ICON, true)]);
+    assert_eq!(state.show_top_bar_icon, None);
+}
+
+#[test]
+fn test_initial_display_settings_override() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = RuntimeStateStore::new(dir.path().join("state.json"));
+    store.update(|state| {
+        state.sni_focus_only = Some(false);
+        state.show_top_bar_icon = Some(false);
+    });
+
+    let settings = initial_display_settings(Some(&store), None);
+    assert!(!settings.focus_only);
+    assert!(!settings.show_top_bar_icon);
+
+    let settings = initial_display_settings(Some(&store), Some(TrayFocusOnly::True));
+    assert!(settings.focus_only);
+    assert!(!settings.show_top_bar_icon);
+    assert_eq!(initial_display_settings(None, None), DisplaySettings::default());
+}
+
+#[test]
+fn test_settings_broadcaster_set() {
+    let broadcaster = SettingsBroadcaster::new(DisplaySettings::default());
+    let mut receiver = broadcaster.subscribe();
+    assert_eq!(broadcaster.set(SETTING_FOCUS_ONLY, true), Ok(false));
+    assert!(!receiver.has_changed().unwrap());
+    assert_eq!(broadcaster.set(SETTING_FOCUS_ONLY, false), Ok(true));
+    assert!(receiver.has_changed().unwrap());
+    assert!(!receiver.borrow_and_update().focus_only);
+    assert!(broadcaster.set("show-everything", true).is_err());
+    assert!(!receiver.has_changed().unwrap());
 }
 
 #[test]
-fn test_sni_settings_store_key_not_set_allows_write() {
-    let (backend, state) = mock_dconf_backend(
-        Err("key not set".to_string()),
-        Ok(()),
+fn test_runtime_state_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = RuntimeStateStore::new(dir.path().join("nested").join("state.json"));
+    assert_eq!(store.load(), RuntimeState::default());
+
+    store.update(|state| state.paused = true);
+    store.update(|state| state.sni_focus_only = Some(false));
+    store.update(|state| state.pinned_layer = Some("nav".to_string()));
+    assert_eq!(
+        store.load(),
+        RuntimeState {
+            paused: true,
+            sni_focus_only: Some(false),
+            pinned_layer: Some("nav".to_string()),
+            ..Default::default()
+        }
     );
-    let mut store = SniSettingsStore::with_backend(backend);
-    let value = store.read_focus_only();
-    assert_eq!(value, None);
-    store.write_focus_only(true);
-    let state = state.lock().unwrap();
-    assert_eq!(state.get_calls, 1);
-    assert_eq!(state.set_calls, vec![true]);
+    assert!(!dir.path().join("nested").join("state.json.tmp").exists());
 }
 
 #[test]
-fn test_resolve_sni_focus_only_override_skips_dconf() {
-    let (backend, state) = mock_dconf_backend(Ok(false), Ok(()));
-    let mut store = SniSettingsStore::with_backend(backend);
-    let value = resolve_sni_focus_only(Some(TrayFocusOnly::True), &mut store);
-    assert!(value);
-    let state = state.lock().unwrap();
-    assert_eq!(state.get_calls, 0);
-    assert!(state.set_calls.is_empty());
+fn test_runtime_state_tolerates_unknown_and_invalid_content() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.json");
+    let store = RuntimeStateStore::new(path.clone());
+
+    std::fs::write(&path, r#"{"paused": true, "from_newer_version": 1}"#).unwrap();
+    assert!(store.load().paused);
+
+    std::fs::write(&path, "not json").unwrap();
+    assert_eq!(store.load(), RuntimeState::default());
 }
 
 #[test]
-fn test_sni_toggle_persists_to_dconf() {
-    let (backend, state) = mock_dconf_backend(Ok(true), Ok(()));
-    let store = SniSettingsStore::with_backend(backend);
+fn test_sni_toggle_updates_settings() {
+    let settings = SettingsBroadcaster::new(DisplaySettings::default());
     let initial = StatusSnapshot {
         layer: "base".to_string(),
         virtual_keys: Vec::new(),
         layer_source: LayerSource::External,
+        matched_rules: Vec::new(),
     };
     let control = MockSniControl::new();
     let (menu_refresh, _menu_receiver) = MenuRefresh::new();
     let mut indicator = SniIndicator {
         state: SniIndicatorState::new(initial, true),
         control: Arc::new(control),
-        settings: store,
+        settings: settings.clone(),
         menu_refresh,
+        layer_display_names: LayerDisplayNames::default(),
+        tray_icons: BTreeMap::new(),
+        theme: IndicatorTheme::default(),
     };
 
     indicator.toggle_focus_only();
-    let state = state.lock().unwrap();
-    assert_eq!(state.set_calls, vec![false]);
+    assert!(!settings.current().focus_only);
 }
 
 #[test]
@@ -688,13 +1744,17 @@ fn test_sni_toggle_sends_menu_refresh() {
         layer: "base".to_string(),
         virtual_keys: Vec::new(),
         layer_source: LayerSource::External,
+        matched_rules: Vec::new(),
     };
     let control = MockSniControl::new();
     let mut indicator = SniIndicator {
         state: SniIndicatorState::new(initial, SNI_DEFAULT_SHOW_FOCUS_ONLY),
         control: Arc::new(control),
-        settings: SniSettingsStore::disabled(),
+        settings: SettingsBroadcaster::new(DisplaySettings::default()),
         menu_refresh,
+        layer_display_names: LayerDisplayNames::default(),
+        tray_icons: BTreeMap::new(),
+        theme: IndicatorTheme::default(),
     };
 
     indicator.toggle_focus_only();
@@ -707,6 +1767,7 @@ fn test_sni_menu_actions_dispatch_control() {
         layer: "base".to_string(),
         virtual_keys: Vec::new(),
         layer_source: LayerSource::External,
+        matched_rules: Vec::new(),
     };
     let control = MockSniControl::new();
     let control_counts = control.clone();
@@ -714,8 +1775,11 @@ fn test_sni_menu_actions_dispatch_control() {
     let mut indicator = SniIndicator {
         state: SniIndicatorState::new(initial, SNI_DEFAULT_SHOW_FOCUS_ONLY),
         control: Arc::new(control),
-        settings: SniSettingsStore::disabled(),
+        settings: SettingsBroadcaster::new(DisplaySettings::default()),
         menu_refresh,
+        layer_display_names: LayerDisplayNames::default(),
+        tray_icons: BTreeMap::new(),
+        theme: IndicatorTheme::default(),
     };
 
     let menu = indicator.menu();
@@ -742,26 +1806,160 @@ fn test_sni_menu_actions_dispatch_control() {
     assert_eq!(counts.restart, 1);
 }
 
+#[test]
+fn test_sni_pin_menu() {
+    let initial = StatusSnapshot {
+        layer: "base".to_string(),
+        virtual_keys: Vec::new(),
+        layer_source: LayerSource::External,
+        matched_rules: Vec::new(),
+    };
+    let control = MockSniControl::new();
+    let control_counts = control.clone();
+    let (menu_refresh, _menu_receiver) = MenuRefresh::new();
+    let mut indicator = SniIndicator {
+        state: SniIndicatorState::new(initial.clone(), SNI_DEFAULT_SHOW_FOCUS_ONLY),
+        control: Arc::new(control),
+        settings: SettingsBroadcaster::new(DisplaySettings::default()),
+        menu_refresh,
+        layer_display_names: LayerDisplayNames::default(),
+        tray_icons: BTreeMap::new(),
+        theme: IndicatorTheme::default(),
+    };
+    let pin_submenu = |indicator: &SniIndicator| {
+        indicator
+            .menu()
+            .into_iter()
+            .find_map(|item| match item {
+                MenuItem::SubMenu(submenu) if submenu.label == "Pin layer" => Some(submenu),
+                _ => None,
+            })
+            .expect("Pin layer submenu missing")
+    };
+    // Disabled until kanata reports its layers
+    assert!(!pin_submenu(&indicator).enabled);
+
+    indicator
+        .state
+        .set_layers(vec!["base".to_string(), "l_nav".to_string()]);
+    let submenu = pin_submenu(&indicator);
+    assert!(submenu.enabled);
+    let MenuItem::Checkmark(nav) = &submenu.submenu[1] else {
+        panic!("expected a checkmark item");
+    };
+    assert_eq!(nav.label, "l__nav");
+    assert!(!nav.checked);
+    (nav.activate)(&mut indicator);
+    assert_eq!(control_counts.counts().pinned, vec!["l_nav".to_string()]);
+
+    // Pinned: the layer is checked and shown with "Show app layer only"; choosing it unpins
+    indicator.update_status(StatusSnapshot {
+        layer: "l_nav".to_string(),
+        layer_source: LayerSource::Pinned,
+        ..initial
+    });
+    assert_eq!(indicator.state.display_status().layer, "l_nav");
+    let submenu = pin_submenu(&indicator);
+    let MenuItem::Checkmark(nav) = &submenu.submenu[1] else {
+        panic!("expected a checkmark item");
+    };
+    assert!(nav.checked);
+    (nav.activate)(&mut indicator);
+    assert_eq!(control_counts.counts().unpin, 1);
+}
+
+#[test]
+fn test_sni_layer_menu() {
+    let initial = StatusSnapshot {
+        layer: "base".to_string(),
+        virtual_keys: Vec::new(),
+        layer_source: LayerSource::External,
+        matched_rules: Vec::new(),
+    };
+    let control = MockSniControl::new();
+    let control_counts = control.clone();
+    let (menu_refresh, _menu_receiver) = MenuRefresh::new();
+    let mut indicator = SniIndicator {
+        state: SniIndicatorState::new(initial.clone(), SNI_DEFAULT_SHOW_FOCUS_ONLY),
+        control: Arc::new(control),
+        settings: SettingsBroadcaster::new(DisplaySettings::default()),
+        menu_refresh,
+        layer_display_names: LayerDisplayNames::default(),
+        tray_icons: BTreeMap::new(),
+        theme: IndicatorTheme::default(),
+    };
+    let layer_submenu = |indicator: &SniIndicator| {
+        indicator
+            .menu()
+            .into_iter()
+            .find_map(|item| match item {
+                MenuItem::SubMenu(submenu) if submenu.label == "Switch layer" => Some(submenu),
+                _ => None,
+            })
+            .expect("Switch layer submenu missing")
+    };
+    assert!(!layer_submenu(&indicator).enabled);
+
+    indicator
+        .state
+        .set_layers(vec!["base".to_string(), "l_nav".to_string()]);
+    let mut submenu = layer_submenu(&indicator);
+    assert!(submenu.enabled);
+    let MenuItem::RadioGroup(group) = submenu.submenu.remove(0) else {
+        panic!("expected a radio group");
+    };
+    assert_eq!(group.selected, 0);
+    assert_eq!(group.options[1].label, "l__nav");
+    (group.select)(&mut indicator, 1);
+    assert_eq!(control_counts.counts().layers, vec!["l_nav".to_string()]);
+
+    // A layer kanata didn't list selects nothing
+    indicator.update_status(StatusSnapshot {
+        layer: "other".to_string(),
+        ..initial.clone()
+    });
+    let MenuItem::RadioGroup(group) = layer_submenu(&indicator).submenu.remove(0) else {
+        panic!("expected a radio group");
+    };
+    assert!(group.selected >= group.options.len());
+
+    // Kanata refuses switches while a layer is pinned or the daemon is paused
+    indicator.update_status(StatusSnapshot {
+        layer: "l_nav".to_string(),
+        layer_source: LayerSource::Pinned,
+        ..initial.clone()
+    });
+    assert!(!layer_submenu(&indicator).enabled);
+    indicator.update_status(initial);
+    indicator.state.set_paused(true);
+    assert!(!layer_submenu(&indicator).enabled);
+}
+
 #[test]
 fn test_sni_menu_toggle_affects_display() {
     let initial = StatusSnapshot {
         layer: "base".to_string(),
         virtual_keys: Vec::new(),
         layer_source: LayerSource::External,
+        matched_rules: Vec::new(),
     };
     let control = MockSniControl::new();
     let (menu_refresh, _menu_receiver) = MenuRefresh::new();
     let mut indicator = SniIndicator {
         state: SniIndicatorState::new(initial, SNI_DEFAULT_SHOW_FOCUS_ONLY),
         control: Arc::new(control),
-        settings: SniSettingsStore::disabled(),
+        settings: SettingsBroadcaster::new(DisplaySettings::default()),
         menu_refresh,
+        layer_display_names: LayerDisplayNames::default(),
+        tray_icons: BTreeMap::new(),
+        theme: IndicatorTheme::default(),
     };
 
     let focus_status = StatusSnapshot {
         layer: "browser".to_string(),
         virtual_keys: vec!["vk_browser".to_string()],
         layer_source: LayerSource::Focus,
+        matched_rules: Vec::new(),
     };
     indicator.update_status(focus_status);
 
@@ -772,6 +1970,7 @@ fn test_sni_menu_toggle_affects_display() {
         layer: "external".to_string(),
         virtual_keys: Vec::new(),
         layer_source: LayerSource::External,
+        matched_rules: Vec::new(),
     };
     indicator.update_status(external_status);
 
@@ -795,26 +1994,186 @@ fn test_sni_tooltip_includes_virtual_keys() {
         layer: "base".to_string(),
         virtual_keys: Vec::new(),
         layer_source: LayerSource::External,
+        matched_rules: Vec::new(),
     };
     let control = MockSniControl::new();
     let (menu_refresh, _menu_receiver) = MenuRefresh::new();
     let mut indicator = SniIndicator {
         state: SniIndicatorState::new(initial, SNI_DEFAULT_SHOW_FOCUS_ONLY),
         control: Arc::new(control),
-        settings: SniSettingsStore::disabled(),
+        settings: SettingsBroadcaster::new(DisplaySettings::default()),
         menu_refresh,
+        layer_display_names: LayerDisplayNames::default(),
+        tray_icons: BTreeMap::new(),
+        theme: IndicatorTheme::default(),
     };
 
     let focus_status = StatusSnapshot {
         layer: "browser".to_string(),
         virtual_keys: vec!["vk_browser".to_string(), "vk_media".to_string()],
         layer_source: LayerSource::Focus,
+        matched_rules: vec![
+            RuleRef {
+                index: 0,
+                name: None,
+            },
+            RuleRef {
+                index: 3,
+                name: Some("Media".to_string()),
+            },
+        ],
     };
     indicator.update_status(focus_status);
     let tooltip = indicator.tooltip_text();
     assert!(tooltip.contains("Layer: browser"));
     assert!(tooltip.contains("vk_browser"));
     assert!(tooltip.contains("vk_media"));
+    assert!(tooltip.contains("Rules: #1, 'Media'"));
+}
+
+#[test]
+fn test_sni_uses_layer_display_names() {
+    let initial = StatusSnapshot {
+        layer: "l_nav_v2".to_string(),
+        virtual_keys: Vec::new(),
+        layer_source: LayerSource::Focus,
+        matched_rules: Vec::new(),
+    };
+    let control = MockSniControl::new();
+    let (menu_refresh, _menu_receiver) = MenuRefresh::new();
+    let indicator = SniIndicator {
+        state: SniIndicatorState::new(initial, SNI_DEFAULT_SHOW_FOCUS_ONLY),
+        control: Arc::new(control),
+        settings: SettingsBroadcaster::new(DisplaySettings::default()),
+        menu_refresh,
+        layer_display_names: LayerDisplayNames::new(BTreeMap::from([(
+            "l_nav_v2".to_string(),
+            "Navigation".to_string(),
+        )])),
+        tray_icons: BTreeMap::new(),
+        theme: IndicatorTheme::default(),
+    };
+
+    let (layer_text, _) = indicator.display_strings();
+    assert_eq!(layer_text, "N");
+    assert!(indicator.tooltip_text().contains("Layer: Navigation"));
+}
+
+#[test]
+fn test_sni_tray_icons_per_layer() {
+    let status = |layer: &str| StatusSnapshot {
+        layer: layer.to_string(),
+        virtual_keys: vec!["vk_media".to_string()],
+        layer_source: LayerSource::Focus,
+        matched_rules: Vec::new(),
+    };
+    let control = MockSniControl::new();
+    let (menu_refresh, _menu_receiver) = MenuRefresh::new();
+    let mut indicator = SniIndicator {
+        state: SniIndicatorState::new(status("base"), SNI_DEFAULT_SHOW_FOCUS_ONLY),
+        control: Arc::new(control),
+        settings: SettingsBroadcaster::new(DisplaySettings::default()),
+        menu_refresh,
+        layer_display_names: LayerDisplayNames::default(),
+        tray_icons: BTreeMap::from([
+            ("base".to_string(), TrayIcon::Hidden),
+            ("nav".to_string(), TrayIcon::Glyph('*')),
+        ]),
+        theme: IndicatorTheme::default(),
+    };
+    assert_eq!(indicator.status(), SniStatus::Passive);
+
+    indicator.update_status(status("nav"));
+    assert_eq!(indicator.status(), SniStatus::Active);
+    assert_eq!(indicator.display_strings(), ("*".to_string(), "V".to_string()));
+
+    indicator.update_status(status("vim"));
+    assert_eq!(indicator.status(), SniStatus::Active);
+    assert_eq!(indicator.display_strings().0, "V");
+    assert_eq!(indicator.icon_name(), "");
+    assert!(indicator.overlay_icon_pixmap().is_empty());
+}
+
+#[test]
+fn test_sni_named_tray_icons() {
+    let status = |layer: &str, virtual_keys: Vec<String>| StatusSnapshot {
+        layer: layer.to_string(),
+        virtual_keys,
+        layer_source: LayerSource::Focus,
+        matched_rules: Vec::new(),
+    };
+    let control = MockSniControl::new();
+    let (menu_refresh, _menu_receiver) = MenuRefresh::new();
+    let mut indicator = SniIndicator {
+        state: SniIndicatorState::new(status("gaming", Vec::new()), SNI_DEFAULT_SHOW_FOCUS_ONLY),
+        control: Arc::new(control),
+        settings: SettingsBroadcaster::new(DisplaySettings::default()),
+        menu_refresh,
+        layer_display_names: LayerDisplayNames::default(),
+        tray_icons: BTreeMap::from([
+            ("gaming".to_string(), TrayIcon::Named("input-gaming".to_string())),
+            ("nav".to_string(), TrayIcon::File(PathBuf::from("/home/me/icons/nav.png"))),
+        ]),
+        theme: IndicatorTheme::default(),
+    };
+    assert_eq!(indicator.icon_name(), "input-gaming");
+    assert_eq!(indicator.icon_theme_path(), "");
+    assert_eq!(indicator.status(), SniStatus::Active);
+    // The letter stays as the pixmap fallback; no VKs, no overlay
+    assert_eq!(indicator.icon_pixmap().len(), 1);
+    assert!(indicator.overlay_icon_pixmap().is_empty());
+
+    indicator.update_status(status("nav", vec!["vk_media".to_string()]));
+    assert_eq!(indicator.icon_name(), "nav");
+    assert_eq!(indicator.icon_theme_path(), "/home/me/icons");
+    let overlay = indicator.overlay_icon_pixmap();
+    assert_eq!(overlay.len(), 1);
+    assert!(sni_buffer_has_vk_pixels(&overlay[0].data));
+    assert!(!sni_buffer_has_layer_pixels(&overlay[0].data));
+}
+
+#[test]
+fn test_config_parses_tray_icons() {
+    let config = load_config_json(
+        r#"[{"tray_icons": {"base": "hidden", "nav": "*", "vim": "letter", "gaming": "icon:input-gaming", "typing": "/usr/share/icons/typing.svg"}}, {"class": "a", "layer": "vim"}]"#,
+    );
+    assert_eq!(
+        config.tray_icons,
+        BTreeMap::from([
+            ("base".to_string(), TrayIcon::Hidden),
+            ("nav".to_string(), TrayIcon::Glyph('*')),
+            ("vim".to_string(), TrayIcon::Letter),
+            ("gaming".to_string(), TrayIcon::Named("input-gaming".to_string())),
+            (
+                "typing".to_string(),
+                TrayIcon::File(PathBuf::from("/usr/share/icons/typing.svg"))
+            ),
+        ])
+    );
+    assert_eq!(
+        serde_json::to_value(&config.tray_icons).unwrap(),
+        serde_json::json!({
+            "base": "hidden",
+            "nav": "*",
+            "vim": "letter",
+            "gaming": "icon:input-gaming",
+            "typing": "/usr/share/icons/typing.svg"
+        })
+    );
+
+    for json in [
+        r#"[{"tray_icons": {"base": "hide"}}]"#,
+        r#"[{"tray_icons": {"base": ""}}]"#,
+        r#"[{"tray_icons": {"base": "→"}}]"#,
+        r#"[{"tray_icons": {"base": "icon:"}}]"#,
+        r#"[{"tray_icons": {"base": "icon:my icon"}}]"#,
+        r#"[{"tray_icons": {"base": "icons/base.png"}}]"#,
+        r#"[{"tray_icons": {"base": "/icons/base.jpg"}}]"#,
+        r#"[{"tray_icons": {"base": "N"}, "layer": "base"}]"#,
+    ] {
+        let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+        assert!(result.is_err(), "{}", json);
+    }
 }
 
 #[test]
@@ -823,20 +2182,25 @@ fn test_sni_title_text_is_single_line() {
         layer: "base".to_string(),
         virtual_keys: Vec::new(),
         layer_source: LayerSource::External,
+        matched_rules: Vec::new(),
     };
     let control = MockSniControl::new();
     let (menu_refresh, _menu_receiver) = MenuRefresh::new();
     let mut indicator = SniIndicator {
         state: SniIndicatorState::new(initial, SNI_DEFAULT_SHOW_FOCUS_ONLY),
         control: Arc::new(control),
-        settings: SniSettingsStore::disabled(),
+        settings: SettingsBroadcaster::new(DisplaySettings::default()),
         menu_refresh,
+        layer_display_names: LayerDisplayNames::default(),
+        tray_icons: BTreeMap::new(),
+        theme: IndicatorTheme::default(),
     };
 
     let focus_status = StatusSnapshot {
         layer: "browser".to_string(),
         virtual_keys: vec!["vk_browser".to_string(), "vk_media".to_string()],
         layer_source: LayerSource::Focus,
+        matched_rules: Vec::new(),
     };
     indicator.update_status(focus_status);
 
@@ -844,6 +2208,33 @@ fn test_sni_title_text_is_single_line() {
     assert!(title == "Kanata Switcher");
 }
 
+#[test]
+fn test_status_broadcaster_degraded_problems() {
+    let status_broadcaster = StatusBroadcaster::new();
+    assert!(status_broadcaster.degraded_problems().is_empty());
+
+    let mut health = status_broadcaster.health_sender.subscribe();
+    status_broadcaster.set_degraded("logind", "bad property".to_string());
+    status_broadcaster.set_degraded("unpause", "switch failed".to_string());
+    assert!(health.has_changed().unwrap());
+    health.mark_unchanged();
+
+    // Re-reporting the same problem is not a change
+    status_broadcaster.set_degraded("logind", "bad property".to_string());
+    assert!(!health.has_changed().unwrap());
+    assert_eq!(
+        status_broadcaster.degraded_problems(),
+        vec!["logind: bad property", "unpause: switch failed"]
+    );
+
+    status_broadcaster.clear_degraded("logind");
+    status_broadcaster.clear_degraded("missing");
+    assert_eq!(
+        status_broadcaster.degraded_problems(),
+        vec!["unpause: switch failed"]
+    );
+}
+
 #[tokio::test]
 async fn test_update_status_for_focus_updates_snapshot() {
     let rules = vec![rule(Some("firefox"), None, Some("browser"))];
@@ -852,21 +2243,220 @@ async fn test_update_status_for_focus_updates_snapshot() {
     let kanata = KanataClient::new("127.0.0.1", 10000, None, true, status_broadcaster.clone());
 
     let win = win("firefox", "");
-    let actions = update_status_for_focus(
+    let applied = apply_focus(
         &handler,
         &status_broadcaster,
         &win,
         &kanata,
         "default",
+        None,
     )
     .await;
-    assert!(actions.is_some());
+    assert!(applied);
 
     let snapshot = status_broadcaster.snapshot();
     assert_eq!(snapshot.layer, "browser");
     assert_eq!(snapshot.layer_source, LayerSource::Focus);
 }
 
+/// Kanata writer that records the indicator layer at the time of each write
+struct StatusRecordingWriter {
+    status_broadcaster: StatusBroadcaster,
+    layers_at_write: Arc<Mutex<Vec<String>>>,
+}
+
+impl tokio::io::AsyncWrite for StatusRecordingWriter {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let layer = self.status_broadcaster.snapshot().layer;
+        self.layers_at_write.lock().unwrap().push(layer);
+        std::task::Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn test_apply_focus_writes_kanata_before_status() {
+    let rules = vec![rule(Some("firefox"), None, Some("browser"))];
+    let handler = Arc::new(Mutex::new(FocusHandler::new(rules, None, true)));
+    let status_broadcaster = StatusBroadcaster::new();
+    let kanata = KanataClient::new("127.0.0.1", 10000, None, true, status_broadcaster.clone());
+    let layers_at_write = Arc::new(Mutex::new(Vec::new()));
+    {
+        let mut inner = kanata
+            .inner
+            .try_lock()
+            .expect("Expected KanataClient lock");
+        inner.known_layers = vec!["default".to_string(), "browser".to_string()];
+        inner.writer = Some(Box::new(StatusRecordingWriter {
+            status_broadcaster: status_broadcaster.clone(),
+            layers_at_write: layers_at_write.clone(),
+        }));
+        inner.connected = true;
+    }
+
+    let win = win("firefox", "");
+    assert!(apply_focus(&handler, &status_broadcaster, &win, &kanata, "default", None).await);
+
+    assert!(!layers_at_write.lock().unwrap().is_empty());
+    assert!(layers_at_write.lock().unwrap().iter().all(|layer| layer.is_empty()));
+    assert_eq!(status_broadcaster.snapshot().layer, "browser");
+    assert_eq!(kanata.inner.lock().await.current_layer.as_deref(), Some("browser"));
+}
+
+#[tokio::test]
+async fn test_apply_focus_publishes_status_before_delay_steps() {
+    let rules = vec![Rule {
+        class: Some("firefox".to_string()),
+        layer: Some("browser".to_string()),
+        raw_vk_action: Some(vec![
+            RawVkStep::Action("vk_a".to_string(), "Tap".to_string()),
+            RawVkStep::Delay(10_000),
+            RawVkStep::Action("vk_b".to_string(), "Tap".to_string()),
+        ]),
+        ..Default::default()
+    }];
+    let handler = Arc::new(Mutex::new(FocusHandler::new(rules, None, true)));
+    let status_broadcaster = StatusBroadcaster::new();
+    let kanata = KanataClient::new("127.0.0.1", 10000, None, true, status_broadcaster.clone());
+    {
+        let mut inner = kanata
+            .inner
+            .try_lock()
+            .expect("Expected KanataClient lock");
+        inner.known_layers = vec!["default".to_string(), "browser".to_string()];
+        inner.writer = Some(Box::new(StatusRecordingWriter {
+            status_broadcaster: status_broadcaster.clone(),
+            layers_at_write: Arc::new(Mutex::new(Vec::new())),
+        }));
+        inner.connected = true;
+    }
+
+    let apply = tokio::spawn({
+        let handler = handler.clone();
+        let status_broadcaster = status_broadcaster.clone();
+        let kanata = kanata.clone();
+        async move {
+            apply_focus(
+                &handler,
+                &status_broadcaster,
+                &win("firefox", ""),
+                &kanata,
+                "default",
+                None,
+            )
+            .await
+        }
+    });
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(2);
+    while status_broadcaster.snapshot().layer != "browser" {
+        assert!(tokio::time::Instant::now() < deadline, "status waited for the delay");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(!apply.is_finished());
+    apply.abort();
+}
+
+#[tokio::test]
+async fn test_handle_focus_event_ignores_daemon_and_panel_windows() {
+    let rules = vec![rule_vk(Some("firefox"), "vk_browser")];
+    let handler = Arc::new(Mutex::new(FocusHandler::new(rules, None, true)));
+    let status_broadcaster = StatusBroadcaster::new();
+    let pause_broadcaster = PauseBroadcaster::new();
+    let kanata = KanataClient::new("127.0.0.1", 10000, None, true, status_broadcaster.clone());
+
+    let focus = |win: WindowInfo| {
+        let handler = handler.clone();
+        let status_broadcaster = status_broadcaster.clone();
+        let pause_broadcaster = pause_broadcaster.clone();
+        let kanata = kanata.clone();
+        async move {
+            handle_focus_event(
+                &handler,
+                &status_broadcaster,
+                &pause_broadcaster,
+                &win,
+                &kanata,
+                "default",
+            )
+            .await
+        }
+    };
+
+    assert!(focus(win("firefox", "")).await);
+    for class in [SETTINGS_APP_ID, "org.kde.plasmashell", "Xfce4-panel"] {
+        assert!(!focus(win(class, "menu")).await, "{}", class);
+    }
+    // The virtual key stays held, as if focus never left firefox
+    let handler = handler.lock().unwrap();
+    assert_eq!(handler.current_virtual_keys(), vec!["vk_browser".to_string()]);
+    assert_eq!(handler.last_matched_rules(), vec![0]);
+}
+
+#[tokio::test]
+async fn test_handle_focus_event_runs_matcher_commands_in_background() {
+    let rules = vec![
+        Rule {
+            class: Some("^slack$".to_string()),
+            command: Some(vec!["sh".to_string(), "-c".to_string(), "sleep 0.3".to_string()]),
+            layer: Some("chat".to_string()),
+            ..Default::default()
+        },
+        rule(Some("firefox"), None, Some("browser")),
+    ];
+    let handler = Arc::new(Mutex::new(FocusHandler::new(rules, None, true)));
+    let status_broadcaster = StatusBroadcaster::new();
+    let pause_broadcaster = PauseBroadcaster::new();
+    let kanata = KanataClient::new("127.0.0.1", 10000, None, true, status_broadcaster.clone());
+
+    let focus = |win: WindowInfo| {
+        let handler = handler.clone();
+        let status_broadcaster = status_broadcaster.clone();
+        let pause_broadcaster = pause_broadcaster.clone();
+        let kanata = kanata.clone();
+        async move {
+            handle_focus_event(
+                &handler,
+                &status_broadcaster,
+                &pause_broadcaster,
+                &win,
+                &kanata,
+                "default",
+            )
+            .await
+        }
+    };
+
+    // The command doesn't hold up the next event, which supersedes this one
+    let started = Instant::now();
+    assert!(!focus(win("slack", "")).await);
+    assert!(started.elapsed() < Duration::from_millis(200));
+    assert!(focus(win("firefox", "")).await);
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert_eq!(status_broadcaster.snapshot().layer, "browser");
+
+    // The result was still cached, so the next slack event applies right away
+    assert!(focus(win("slack", "")).await);
+    assert_eq!(status_broadcaster.snapshot().layer, "chat");
+}
+
 #[tokio::test]
 async fn test_update_status_for_focus_unknown_layer_uses_default() {
     let rules = vec![rule(Some("firefox"), None, Some("browser"))];
@@ -889,15 +2479,16 @@ async fn test_update_status_for_focus_unknown_layer_uses_default() {
     }
 
     let win = win("firefox", "");
-    let actions = update_status_for_focus(
+    let applied = apply_focus(
         &handler,
         &status_broadcaster,
         &win,
         &kanata,
         "default",
+        None,
     )
     .await;
-    assert!(actions.is_some());
+    assert!(applied);
 
     let snapshot = status_broadcaster.snapshot();
     assert_eq!(snapshot.layer, "default");
@@ -905,65 +2496,302 @@ async fn test_update_status_for_focus_unknown_layer_uses_default() {
 }
 
 #[tokio::test]
-async fn test_handle_focus_event_ignored_when_paused_no_status_change() {
-    let rules = vec![rule(Some("firefox"), None, Some("browser"))];
-    let handler = Arc::new(Mutex::new(FocusHandler::new(rules, None, true)));
-    let status_broadcaster = StatusBroadcaster::new();
-    let pause_broadcaster = PauseBroadcaster::new();
-    let kanata = KanataClient::new("127.0.0.1", 10000, None, true, status_broadcaster.clone());
+async fn test_write_kanata_line_times_out_when_stalled() {
+    // Nobody reads the other end, so the write blocks once the 1-byte buffer is full
+    let (client_end, _server_end) = tokio::io::duplex(1);
+    let mut writer: KanataWriter = Box::new(client_end);
+
+    let error = with_test_timeout(write_kanata_line(
+        &mut writer,
+        "{\"ChangeLayer\":{\"new\":\"vim\"}}\n",
+        Duration::from_millis(50),
+    ))
+    .await
+    .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+}
 
-    pause_broadcaster.set_paused(true);
-    let win = win("firefox", "");
-    let actions = handle_focus_event(
-        &handler,
-        &status_broadcaster,
-        &pause_broadcaster,
-        &win,
-        &kanata,
-        "default",
-    )
-    .await;
-    assert!(actions.is_none());
-    let snapshot = status_broadcaster.snapshot();
-    assert!(snapshot.layer.is_empty());
+/// A `KanataMessageReader` over `chunks` written one by one, then EOF
+fn message_reader_over(chunks: Vec<Vec<u8>>) -> KanataMessageReader {
+    let (client_end, mut server_end) = tokio::io::duplex(64);
+    tokio::spawn(async move {
+        for chunk in chunks {
+            if server_end.write_all(&chunk).await.is_err() {
+                return;
+            }
+        }
+    });
+    KanataMessageReader::new(Box::new(client_end), false)
+}
 
-    pause_broadcaster.set_paused(false);
-    let actions = handle_focus_event(
-        &handler,
-        &status_broadcaster,
-        &pause_broadcaster,
-        &win,
-        &kanata,
-        "default",
-    )
-    .await;
-    assert!(actions.is_some());
-    let snapshot = status_broadcaster.snapshot();
-    assert_eq!(snapshot.layer, "browser");
-    assert_eq!(snapshot.layer_source, LayerSource::Focus);
+async fn read_all_messages(mut reader: KanataMessageReader) -> Vec<KanataIncoming> {
+    let mut messages = Vec::new();
+    while let Some(message) = reader.next_message().await.unwrap() {
+        messages.push(message);
+    }
+    messages
+}
+
+fn layer_change(new: &str) -> KanataIncoming {
+    KanataIncoming::Message(KanataServerMessage::LayerChange {
+        new: new.to_string(),
+    })
 }
 
 #[tokio::test]
-async fn test_handle_focus_event_unfocus_paused_does_not_switch_layer() {
-    let rules = vec![rule(Some("firefox"), None, Some("browser"))];
-    let handler = Arc::new(Mutex::new(FocusHandler::new(rules, None, true)));
-    let status_broadcaster = StatusBroadcaster::new();
-    let pause_broadcaster = PauseBroadcaster::new();
-    let kanata = KanataClient::new("127.0.0.1", 10000, None, true, status_broadcaster.clone());
+async fn test_kanata_message_reader_multiple_objects_per_line() {
+    let reader = message_reader_over(vec![
+        b"{\"LayerChange\":{\"new\":\"vim\"}}{\"LayerChange\":{\"new\":\"browser\"}} {\"status\":\"Ok\"}\n"
+            .to_vec(),
+    ]);
+    assert_eq!(
+        with_test_timeout(read_all_messages(reader)).await,
+        vec![
+            layer_change("vim"),
+            layer_change("browser"),
+            KanataIncoming::Status(StatusReplyMsg {
+                status: "Ok".to_string(),
+                msg: String::new(),
+            }),
+        ]
+    );
+}
 
-    status_broadcaster.update_layer("current".to_string(), LayerSource::External);
-    pause_broadcaster.set_paused(true);
+#[tokio::test]
+async fn test_kanata_message_reader_partial_reads_and_large_payload() {
+    let names: Vec<String> = (0..10_000).map(|index| format!("layer_{}", index)).collect();
+    let line = format!(
+        "{{\"LayerNames\":{{\"names\":{}}}}}\n",
+        serde_json::to_string(&names).unwrap()
+    );
+    assert!(line.len() > 64 * 1024);
+    let mut chunks: Vec<Vec<u8>> = line.as_bytes().chunks(1000).map(<[u8]>::to_vec).collect();
+    chunks.push(b"{\"LayerChange\":".to_vec());
+    chunks.push(b"{\"new\":\"vim\"}}\n".to_vec());
 
-    let actions = handle_focus_event(
-        &handler,
-        &status_broadcaster,
-        &pause_broadcaster,
-        &WindowInfo::default(),
-        &kanata,
-        "default",
-    )
-    .await;
-    assert!(actions.is_none());
+    let reader = message_reader_over(chunks);
+    assert_eq!(
+        with_test_timeout(read_all_messages(reader)).await,
+        vec![
+            KanataIncoming::Message(KanataServerMessage::LayerNames { names }),
+            layer_change("vim"),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_kanata_message_reader_skips_unknown_malformed_and_oversized() {
+    let mut oversized = b"{\"LayerNames\":{\"names\":[\"".to_vec();
+    oversized.resize(KANATA_MAX_LINE_BYTES + 10, b'a');
+    oversized.extend_from_slice(b"\"]}}\n");
+    let reader = message_reader_over(vec![
+        b"{\"ConfigFileReload\":{\"new\":\"b.kbd\"}}\n".to_vec(),
+        b"{\"LayerChange\":\n".to_vec(),
+        oversized,
+        b"{\"LayerChange\":{\"new\":\"vim\"}}\n".to_vec(),
+    ]);
+    assert_eq!(
+        with_test_timeout(read_all_messages(reader)).await,
+        vec![layer_change("vim")]
+    );
+}
+
+#[test]
+fn test_kanata_message_type_names_tagged_messages() {
+    let value: serde_json::Value =
+        serde_json::from_str("{\"ConfigFileReload\":{\"new\":\"b.kbd\"}}").unwrap();
+    assert_eq!(kanata_message_type(&value), "ConfigFileReload");
+    let value: serde_json::Value = serde_json::from_str("[1,2]").unwrap();
+    assert_eq!(kanata_message_type(&value), "[1,2]");
+}
+
+/// Run `request_kanata_reply` against a fake kanata that answers with `replies`
+async fn request_with_replies(replies: &str) -> (Option<KanataServerMessage>, Option<String>) {
+    let mut reader = message_reader_over(vec![replies.as_bytes().to_vec()]);
+    let mut writer: KanataWriter = Box::new(tokio::io::sink());
+    let mut current_layer = None;
+    let reply = request_kanata_reply(
+        &mut reader,
+        &mut writer,
+        "{\"RequestCurrentLayerName\":{}}\n",
+        Duration::from_secs(1),
+        &mut current_layer,
+    )
+    .await
+    .unwrap();
+    (reply, current_layer)
+}
+
+#[tokio::test]
+async fn test_request_kanata_reply_tracks_layer_change_notifications() {
+    let (reply, current_layer) = with_test_timeout(request_with_replies(
+        "{\"LayerChange\":{\"new\":\"vim\"}}\n{\"CurrentLayerName\":{\"name\":\"vim\"}}\n",
+    ))
+    .await;
+    assert_eq!(
+        reply,
+        Some(KanataServerMessage::CurrentLayerName {
+            name: "vim".to_string()
+        })
+    );
+    assert_eq!(current_layer.as_deref(), Some("vim"));
+}
+
+#[tokio::test]
+async fn test_request_kanata_reply_error_reply_is_unsupported() {
+    let (reply, _) = with_test_timeout(request_with_replies(
+        "{\"status\":\"Error\",\"msg\":\"unknown variant `RequestCurrentLayerName`\"}\n",
+    ))
+    .await;
+    assert_eq!(reply, None);
+}
+
+#[tokio::test]
+async fn test_request_kanata_reply_closed_connection_is_unsupported() {
+    assert_eq!(with_test_timeout(request_with_replies("")).await.0, None);
+}
+
+#[tokio::test]
+async fn test_request_kanata_reply_reads_hello_capabilities() {
+    let (reply, _) = with_test_timeout(request_with_replies(
+        "{\"HelloOk\":{\"version\":\"1.10.0\",\"protocol\":1,\"capabilities\":[\"reload\"]}}\n",
+    ))
+    .await;
+    assert_eq!(
+        reply,
+        Some(KanataServerMessage::HelloOk {
+            version: "1.10.0".to_string(),
+            protocol: 1,
+            capabilities: vec!["reload".to_string()],
+        })
+    );
+}
+
+#[test]
+fn test_kanata_capabilities_reload_defaults_to_unsupported() {
+    assert!(!KanataCapabilities::default().reload());
+    let capabilities = KanataCapabilities {
+        current_layer_name: Some(true),
+        fake_key_names: Some(true),
+        reload: None,
+    };
+    assert!(!capabilities.reload());
+}
+
+#[tokio::test]
+async fn test_change_layer_stalled_write_drops_connection() {
+    let kanata = KanataClient::new("127.0.0.1", 10000, None, true, StatusBroadcaster::new());
+    let (client_end, _server_end) = tokio::io::duplex(1);
+    {
+        let mut inner = kanata
+            .inner
+            .try_lock()
+            .expect("Expected KanataClient lock");
+        inner.known_layers = vec!["default".to_string(), "vim".to_string()];
+        inner.writer = Some(Box::new(client_end));
+        inner.connected = true;
+        inner.write_timeout = Duration::from_millis(50);
+        // Keep the spawned reconnect loop asleep for the rest of the test
+        inner.reconnect_delays_ms = vec![60_000];
+    }
+
+    assert!(!with_test_timeout(kanata.change_layer("vim")).await);
+    assert!(!kanata.is_connected().await);
+    let inner = kanata.inner.lock().await;
+    assert!(inner.writer.is_none());
+    assert_eq!(inner.current_layer, None);
+    assert_eq!(inner.pending_layer.as_deref(), Some("vim"));
+}
+
+#[tokio::test]
+async fn test_failed_write_during_shutdown_does_not_reconnect() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let kanata = KanataClient::new("127.0.0.1", port, None, true, StatusBroadcaster::new());
+    let (client_end, _server_end) = tokio::io::duplex(1);
+    {
+        let mut inner = kanata
+            .inner
+            .try_lock()
+            .expect("Expected KanataClient lock");
+        inner.known_layers = vec!["default".to_string(), "vim".to_string()];
+        inner.writer = Some(Box::new(client_end));
+        inner.connected = true;
+        inner.write_timeout = Duration::from_millis(50);
+        inner.reconnect_delays_ms = vec![0];
+    }
+
+    kanata.begin_shutdown().await;
+    assert!(!with_test_timeout(kanata.change_layer("vim")).await);
+    assert!(!kanata.is_connected().await);
+    // A reconnect would have reached the listener right away
+    assert!(
+        tokio::time::timeout(Duration::from_millis(300), listener.accept())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_handle_focus_event_ignored_when_paused_no_status_change() {
+    let rules = vec![rule(Some("firefox"), None, Some("browser"))];
+    let handler = Arc::new(Mutex::new(FocusHandler::new(rules, None, true)));
+    let status_broadcaster = StatusBroadcaster::new();
+    let pause_broadcaster = PauseBroadcaster::new();
+    let kanata = KanataClient::new("127.0.0.1", 10000, None, true, status_broadcaster.clone());
+
+    pause_broadcaster.set_paused(true);
+    let win = win("firefox", "");
+    let applied = handle_focus_event(
+        &handler,
+        &status_broadcaster,
+        &pause_broadcaster,
+        &win,
+        &kanata,
+        "default",
+    )
+    .await;
+    assert!(!applied);
+    let snapshot = status_broadcaster.snapshot();
+    assert!(snapshot.layer.is_empty());
+
+    pause_broadcaster.set_paused(false);
+    let applied = handle_focus_event(
+        &handler,
+        &status_broadcaster,
+        &pause_broadcaster,
+        &win,
+        &kanata,
+        "default",
+    )
+    .await;
+    assert!(applied);
+    let snapshot = status_broadcaster.snapshot();
+    assert_eq!(snapshot.layer, "browser");
+    assert_eq!(snapshot.layer_source, LayerSource::Focus);
+}
+
+#[tokio::test]
+async fn test_handle_focus_event_unfocus_paused_does_not_switch_layer() {
+    let rules = vec![rule(Some("firefox"), None, Some("browser"))];
+    let handler = Arc::new(Mutex::new(FocusHandler::new(rules, None, true)));
+    let status_broadcaster = StatusBroadcaster::new();
+    let pause_broadcaster = PauseBroadcaster::new();
+    let kanata = KanataClient::new("127.0.0.1", 10000, None, true, status_broadcaster.clone());
+
+    status_broadcaster.update_layer("current".to_string(), LayerSource::External);
+    pause_broadcaster.set_paused(true);
+
+    let applied = handle_focus_event(
+        &handler,
+        &status_broadcaster,
+        &pause_broadcaster,
+        &WindowInfo::default(),
+        &kanata,
+        "default",
+    )
+    .await;
+    assert!(!applied);
     let snapshot = status_broadcaster.snapshot();
     assert_eq!(snapshot.layer, "current");
     assert_eq!(snapshot.layer_source, LayerSource::External);
@@ -973,14 +2801,53 @@ async fn test_handle_focus_event_unfocus_paused_does_not_switch_layer() {
 fn test_paused_status_resets_virtual_keys_and_source() {
     let status_broadcaster = StatusBroadcaster::new();
     status_broadcaster.update_layer("external".to_string(), LayerSource::External);
-    status_broadcaster.update_virtual_keys(vec!["vk_browser".to_string()]);
+    status_broadcaster.update_focus_match(
+        vec!["vk_browser".to_string()],
+        vec![RuleRef {
+            index: 2,
+            name: None,
+        }],
+    );
     status_broadcaster.set_paused_status("base".to_string());
     let snapshot = status_broadcaster.snapshot();
     assert_eq!(snapshot.layer, "base");
     assert!(snapshot.virtual_keys.is_empty());
+    assert!(snapshot.matched_rules.is_empty());
     assert_eq!(snapshot.layer_source, LayerSource::External);
 }
 
+#[test]
+fn test_external_layer_clears_matched_rules() {
+    let status_broadcaster = StatusBroadcaster::new();
+    let matched_rules = vec![
+        RuleRef {
+            index: 1,
+            name: None,
+        },
+        RuleRef {
+            index: 3,
+            name: Some("IDE: JetBrains".to_string()),
+        },
+        RuleRef {
+            index: NATIVE_TERMINAL_RULE_INDEX,
+            name: None,
+        },
+    ];
+    status_broadcaster.update_focus_match(Vec::new(), matched_rules.clone());
+    status_broadcaster.update_focus_layer("browser".to_string());
+    let snapshot = status_broadcaster.snapshot();
+    assert_eq!(snapshot.dbus_matched_rules(), vec![1, 3, -1]);
+    assert_eq!(
+        snapshot.matched_rules_text(),
+        "#2, 'IDE: JetBrains', on_native_terminal"
+    );
+
+    status_broadcaster.update_layer("restored".to_string(), LayerSource::Focus);
+    assert_eq!(status_broadcaster.snapshot().matched_rules, matched_rules);
+    status_broadcaster.update_layer("other".to_string(), LayerSource::External);
+    assert!(status_broadcaster.snapshot().matched_rules.is_empty());
+}
+
 #[test]
 fn test_virtual_key_press_on_focus() {
     let rules = vec![rule_vk(Some("firefox"), "vk_browser")];
@@ -1052,6 +2919,128 @@ fn test_virtual_key_no_change_no_press() {
     );
 }
 
+#[test]
+fn test_layer_virtual_keys_follow_the_effective_layer() {
+    let rule = |class: &str, layer: &str, vk: Option<&str>| Rule {
+        class: Some(class.to_string()),
+        layer: Some(layer.to_string()),
+        virtual_key: vk.map(str::to_string),
+        ..Default::default()
+    };
+    let rules = vec![
+        rule("kitty", "vim", None),
+        rule("nvim-qt", "vim", Some("vk_gui")),
+        rule("firefox", "browser", Some("vk_mode")),
+    ];
+    let native_terminal_rule = NativeTerminalRule {
+        layer: "tty".to_string(),
+        virtual_key: None,
+        raw_vk_action: Vec::new(),
+        silent: false,
+        notify: true,
+    };
+    let mut handler = FocusHandler::new(rules, Some(native_terminal_rule), true)
+        .with_layer_virtual_keys(BTreeMap::from([
+            ("vim".to_string(), vec!["vk_mode".to_string(), "vk_esc".to_string()]),
+            ("tty".to_string(), vec!["vk_tty".to_string()]),
+        ]));
+
+    let actions = handler.handle(&win("kitty", ""), "base").unwrap();
+    assert_eq!(
+        actions.actions,
+        vec![
+            FocusAction::ChangeLayer("vim".to_string()),
+            FocusAction::PressVk("vk_mode".to_string()),
+            FocusAction::PressVk("vk_esc".to_string()),
+        ]
+    );
+    assert_eq!(actions.new_managed_vks, vec!["vk_mode", "vk_esc"]);
+
+    // Another rule selecting the same layer keeps them held next to its own key
+    let actions = handler.handle(&win("nvim-qt", ""), "base").unwrap();
+    assert_eq!(
+        actions.actions,
+        vec![
+            FocusAction::ChangeLayer("vim".to_string()),
+            FocusAction::PressVk("vk_gui".to_string()),
+        ]
+    );
+    assert_eq!(handler.current_virtual_keys(), vec!["vk_gui", "vk_mode", "vk_esc"]);
+    assert!(handler.handle(&win("nvim-qt", "other file"), "base").is_none());
+
+    // A rule VK that is also a layer VK stays held across the switch
+    let actions = handler.handle(&win("firefox", ""), "base").unwrap();
+    assert_eq!(
+        actions.actions,
+        vec![
+            FocusAction::ReleaseVk("vk_esc".to_string()),
+            FocusAction::ReleaseVk("vk_gui".to_string()),
+            FocusAction::ChangeLayer("browser".to_string()),
+        ]
+    );
+    assert_eq!(handler.current_virtual_keys(), vec!["vk_mode"]);
+
+    // Session rules and the unfocused default layer get them too
+    let actions = handler.handle(&native_terminal_window(), "base").unwrap();
+    assert_eq!(
+        actions.actions,
+        vec![
+            FocusAction::ReleaseVk("vk_mode".to_string()),
+            FocusAction::ChangeLayer("tty".to_string()),
+            FocusAction::PressVk("vk_tty".to_string()),
+        ]
+    );
+    let actions = handler.handle(&win("", ""), "base").unwrap();
+    assert_eq!(
+        actions.actions,
+        vec![
+            FocusAction::ReleaseVk("vk_tty".to_string()),
+            FocusAction::ChangeLayer("base".to_string()),
+        ]
+    );
+    assert!(handler.current_virtual_keys().is_empty());
+}
+
+#[test]
+fn test_config_parses_layer_virtual_keys() {
+    let config = load_config_json(
+        r#"[{"layer_virtual_keys": {"vim": ["vk_mode", "vk_esc"]}}, {"class": "a", "layer": "vim"}]"#,
+    );
+    assert_eq!(
+        config.layer_virtual_keys,
+        BTreeMap::from([(
+            "vim".to_string(),
+            vec!["vk_mode".to_string(), "vk_esc".to_string()]
+        )])
+    );
+    let refs = config_name_refs(&config, None);
+    assert!(refs.contains(&("layer_virtual_keys".to_string(), ConfigNameRef::Layer("vim"))));
+    assert!(refs.contains(&(
+        "layer_virtual_keys".to_string(),
+        ConfigNameRef::VirtualKey("vk_esc")
+    )));
+
+    for json in [
+        r#"[{"layer_virtual_keys": {"vim": "vk_mode"}}]"#,
+        r#"[{"layer_virtual_keys": {}, "class": "firefox"}]"#,
+    ] {
+        let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+        assert!(result.is_err(), "{}", json);
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.json");
+    std::fs::write(&path, r#"[{"layer_virtual_keys": {"vim": [""]}}]"#).unwrap();
+    assert_eq!(
+        parse_config(&path).unwrap_err(),
+        "entry #1: 'layer_virtual_keys': virtual key names for layer \"vim\" must not be empty"
+    );
+    std::fs::write(&path, r#"[{"layer_virtual_keys": {}}, {"layer_virtual_keys": {}}]"#).unwrap();
+    assert_eq!(
+        parse_config(&path).unwrap_err(),
+        "entry #2: multiple 'layer_virtual_keys' entries found, only one allowed"
+    );
+}
+
 #[test]
 fn test_partial_vk_set_change_only_releases_removed() {
     // Two rules with fallthrough: vk1 and vk2 are both held
@@ -1065,6 +3054,7 @@ fn test_partial_vk_set_change_only_releases_removed() {
             virtual_key: Some("vk1".to_string()),
             raw_vk_action: None,
             fallthrough: true,
+            ..Default::default()
         },
         Rule {
             class: Some("app".to_string()),
@@ -1074,6 +3064,7 @@ fn test_partial_vk_set_change_only_releases_removed() {
             virtual_key: Some("vk2".to_string()),
             raw_vk_action: None,
             fallthrough: false,
+            ..Default::default()
         },
     ];
     let mut handler = FocusHandler::new(rules, None, true);
@@ -1122,6 +3113,7 @@ fn test_unfocus_releases_multiple_vks_in_reverse_order() {
             virtual_key: Some("vk1".to_string()),
             raw_vk_action: None,
             fallthrough: true,
+            ..Default::default()
         },
         Rule {
             class: Some("app".to_string()),
@@ -1131,6 +3123,7 @@ fn test_unfocus_releases_multiple_vks_in_reverse_order() {
             virtual_key: Some("vk2".to_string()),
             raw_vk_action: None,
             fallthrough: true,
+            ..Default::default()
         },
         Rule {
             class: Some("app".to_string()),
@@ -1140,6 +3133,7 @@ fn test_unfocus_releases_multiple_vks_in_reverse_order() {
             virtual_key: Some("vk3".to_string()),
             raw_vk_action: None,
             fallthrough: false,
+            ..Default::default()
         },
     ];
     let mut handler = FocusHandler::new(rules, None, true);
@@ -1306,8 +3300,9 @@ fn test_fallthrough_action_order_preserved() {
             on_native_terminal: None,
             layer: Some("layer1".to_string()),
             virtual_key: Some("vk1".to_string()),
-            raw_vk_action: Some(vec![("raw1".to_string(), "Tap".to_string())]),
+            raw_vk_action: Some(vec![RawVkStep::Action("raw1".to_string(), "Tap".to_string())]),
             fallthrough: true,
+            ..Default::default()
         },
         Rule {
             class: Some("kitty".to_string()),
@@ -1315,8 +3310,9 @@ fn test_fallthrough_action_order_preserved() {
             on_native_terminal: None,
             layer: Some("layer2".to_string()),
             virtual_key: Some("vk2".to_string()),
-            raw_vk_action: Some(vec![("raw2".to_string(), "Toggle".to_string())]),
+            raw_vk_action: Some(vec![RawVkStep::Action("raw2".to_string(), "Toggle".to_string())]),
             fallthrough: false,
+            ..Default::default()
         },
     ];
     let mut handler = FocusHandler::new(rules, None, true);
@@ -1346,8 +3342,9 @@ fn test_combined_virtual_key_and_raw_vk_action() {
         on_native_terminal: None,
         layer: Some("browser".to_string()),
         virtual_key: Some("vk_browser".to_string()),
-        raw_vk_action: Some(vec![("vk_notify".to_string(), "Tap".to_string())]),
+        raw_vk_action: Some(vec![RawVkStep::Action("vk_notify".to_string(), "Tap".to_string())]),
         fallthrough: false,
+        ..Default::default()
     }];
     let mut handler = FocusHandler::new(rules, None, true);
 
@@ -1430,6 +3427,68 @@ fn test_multiple_raw_vk_actions_per_rule_all_execute() {
     );
 }
 
+#[test]
+fn test_raw_vk_action_delay_preserves_order() {
+    let rules = vec![Rule {
+        class: Some("app".to_string()),
+        title: None,
+        on_native_terminal: None,
+        layer: None,
+        virtual_key: None,
+        raw_vk_action: Some(vec![
+            RawVkStep::Action("vk_a".to_string(), "Tap".to_string()),
+            RawVkStep::Delay(150),
+            RawVkStep::Action("vk_b".to_string(), "Tap".to_string()),
+        ]),
+        fallthrough: false,
+        ..Default::default()
+    }];
+    let mut handler = FocusHandler::new(rules, None, true);
+
+    let actions = handler.handle(&win("app", ""), "default").unwrap();
+    assert_eq!(
+        actions.actions,
+        vec![
+            FocusAction::RawVkAction("vk_a".to_string(), "Tap".to_string()),
+            FocusAction::Delay(150),
+            FocusAction::RawVkAction("vk_b".to_string(), "Tap".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn test_reload_actions_fire_before_layer_on_new_match() {
+    let rules = vec![
+        Rule {
+            class: Some("game".to_string()),
+            layer: Some("gaming".to_string()),
+            reload_num: Some(1),
+            ..Default::default()
+        },
+        Rule {
+            class: Some("editor".to_string()),
+            reload_next: true,
+            ..Default::default()
+        },
+    ];
+    let mut handler = FocusHandler::new(rules, None, true);
+
+    let actions = handler.handle(&win("game", "a"), "default").unwrap();
+    assert_eq!(
+        actions.actions,
+        vec![
+            FocusAction::ReloadNum(1),
+            FocusAction::ChangeLayer("gaming".to_string()),
+        ]
+    );
+
+    // Same rule still matched: no repeated reload
+    assert!(handler.handle(&win("game", "b"), "default").is_none());
+
+    let actions = handler.handle(&win("editor", ""), "default").unwrap();
+    assert!(has_action(&actions, &FocusAction::ReloadNext));
+}
+
 #[test]
 fn test_non_fallthrough_stops_chain() {
     // First rule matches but has fallthrough=false, should stop chain
@@ -1506,34 +3565,1333 @@ fn test_non_matching_non_fallthrough_rule_does_not_stop_chain() {
     );
 }
 
-// === Property Tests ===
+#[test]
+fn test_size_conditions_match_small_popups() {
+    // Tiny popups keep the current layer instead of switching to the default
+    let rules = vec![
+        Rule {
+            max_width: Some(200),
+            max_height: Some(200),
+            ..Default::default()
+        },
+        rule(Some("firefox"), None, Some("browser")),
+    ];
+    let mut handler = FocusHandler::new(rules, None, true);
 
-fn arb_class() -> impl Strategy<Value = String> {
-    prop_oneof![
-        Just("firefox".to_string()),
-        Just("kitty".to_string()),
-        Just("code".to_string()),
-        Just("alacritty".to_string()),
-        Just("".to_string()),
-        "[a-z]{1,10}".prop_map(String::from),
-    ]
+    let sized = |class: &str, width: u32, height: u32| WindowInfo {
+        size: Some(WindowSize { width, height }),
+        ..win(class, "")
+    };
+    let actions = handler.handle(&sized("firefox", 1200, 800), "default").unwrap();
+    assert_eq!(get_layers(&actions), vec!["browser".to_string()]);
+    assert!(handler.handle(&sized("popup", 150, 40), "default").is_none());
+    assert_eq!(handler.last_matched_rules(), vec![0]);
+    // Unknown size never matches size conditions
+    let actions = handler.handle(&win("popup", ""), "default").unwrap();
+    assert_eq!(get_layers(&actions), vec!["default".to_string()]);
 }
 
-fn arb_nonempty_class() -> impl Strategy<Value = String> {
-    arb_class().prop_filter("non-empty class", |class| !class.is_empty())
+#[test]
+fn test_rule_matches_size_bounds() {
+    let rule = Rule {
+        min_width: Some(100),
+        max_height: Some(300),
+        ..Default::default()
+    };
+    let size = |width, height| Some(WindowSize { width, height });
+    assert!(rule.matches_size(size(100, 300)));
+    assert!(!rule.matches_size(size(99, 300)));
+    assert!(!rule.matches_size(size(100, 301)));
+    assert!(!rule.matches_size(None));
+    assert!(Rule::default().matches_size(None));
 }
 
-fn arb_title() -> impl Strategy<Value = String> {
-    prop_oneof![
-        Just("".to_string()),
-        Just("vim".to_string()),
-        Just("bash".to_string()),
-        "[a-zA-Z0-9 ]{0,20}".prop_map(String::from),
-    ]
+#[test]
+fn test_window_kind_conditions() {
+    let entries: Vec<ConfigEntry> = serde_json::from_str(
+        r#"[{"kind": "vm", "layer": "passthrough"},
+            {"kind": "remote_desktop", "title": "work", "layer": "passthrough"}]"#,
+    )
+    .unwrap();
+    let rules: Vec<Rule> = entries
+        .into_iter()
+        .map(|entry| match entry {
+            ConfigEntry::Rule(rule) => *rule,
+            other => panic!("unexpected entry {:?}", other),
+        })
+        .collect();
+    assert_eq!(rules[0].summary(), "kind=vm -> layer=passthrough");
+    let mut handler = FocusHandler::new(rules, None, true);
+
+    for class in [
+        "virt-manager",
+        "looking-glass-client",
+        "VirtualBox Machine",
+        "qemu-system-x86_64",
+    ] {
+        let actions = handler.handle(&win(class, ""), "base").unwrap();
+        assert_eq!(get_layers(&actions), vec!["passthrough".to_string()], "{}", class);
+        handler.handle(&win("firefox", ""), "base");
+    }
+    // Whole class only: a terminal named after a VM tool isn't one
+    let actions = handler.handle(&win("virt-manager-helper", ""), "base");
+    let passthrough = vec!["passthrough".to_string()];
+    assert!(actions.is_none_or(|actions| get_layers(&actions) != passthrough));
+    let actions = handler.handle(&win("org.remmina.Remmina", "work laptop"), "base").unwrap();
+    assert_eq!(get_layers(&actions), vec!["passthrough".to_string()]);
+    handler.handle(&win("firefox", ""), "base");
+    let actions = handler.handle(&win("xfreerdp", "home"), "base");
+    assert!(actions.is_none_or(|actions| get_layers(&actions) != passthrough));
+
+    assert!(WindowKind::VmOrRemote.matches("wlfreerdp"));
+    assert!(WindowKind::VmOrRemote.matches("remote-viewer"));
+    assert!(!WindowKind::RemoteDesktop.matches("virt-manager"));
+    assert!(serde_json::from_str::<ConfigEntry>(r#"{"kind": "browser", "layer": "x"}"#).is_err());
+
+    // `kind` alone is a matcher, not a catch-all
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.json");
+    std::fs::write(&path, r#"[{"kind": "vm_or_remote", "layer": "passthrough"}]"#).unwrap();
+    assert_eq!(parse_config(&path).unwrap().rules.len(), 1);
 }
 
-fn arb_layer() -> impl Strategy<Value = String> {
-    prop_oneof![
+#[test]
+fn test_call_detection() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.json");
+    std::fs::write(
+        &path,
+        r#"[{"call_detection": {}}, {"in_call": true, "layer": "ptt"}, {"default": "base"}]"#,
+    )
+    .unwrap();
+    let config = parse_config(&path).unwrap();
+    assert_eq!(
+        config.call_detection,
+        Some(CallDetectionConfig {
+            mic: true,
+            poll_interval_ms: 1000
+        })
+    );
+    assert_eq!(config.rules[0].summary(), "in_call=true -> layer=ptt");
+    let mut handler = FocusHandler::from_config(&config, true, false);
+    let meeting = win("zoom", "Zoom Meeting");
+
+    // A call window only counts while the microphone records
+    let actions = handler.handle(&meeting, "base").unwrap();
+    assert_eq!(get_layers(&actions), vec!["base".to_string()]);
+    handler.set_mic_in_use(true);
+    let actions = handler.handle(&meeting, "base").unwrap();
+    assert_eq!(get_layers(&actions), vec!["ptt".to_string()]);
+    let actions = handler.handle(&win("zoom", "Zoom Workplace"), "base").unwrap();
+    assert_eq!(get_layers(&actions), vec!["base".to_string()]);
+    handler.set_mic_in_use(false);
+    let actions = handler.handle(&win("zoom", "Zoom Workplace"), "base");
+    assert!(actions.is_none_or(|actions| get_layers(&actions).is_empty()));
+
+    // Without the mic signal the heuristics decide alone
+    std::fs::write(
+        &path,
+        r#"[{"call_detection": {"mic": false}}, {"in_call": true, "layer": "ptt"}]"#,
+    )
+    .unwrap();
+    let config = parse_config(&path).unwrap();
+    let mut handler = FocusHandler::from_config(&config, true, false);
+    let actions = handler.handle(&meeting, "base").unwrap();
+    assert_eq!(get_layers(&actions), vec!["ptt".to_string()]);
+
+    for (class, title) in [
+        ("us.zoom.Zoom", "Zoom Meeting"),
+        ("teams-for-linux", "Meeting with Alice | Microsoft Teams"),
+        ("firefox", "Call with Bob | Microsoft Teams — Mozilla Firefox"),
+        ("google-chrome", "Meet - abc-defg-hij - Google Chrome"),
+        ("firefox", "standup | Jitsi Meet — Mozilla Firefox"),
+        ("Slack", "Huddle: #team - Acme - Slack"),
+        ("CiscoCollabHost", "Webex"),
+    ] {
+        assert!(is_call_window(class, title), "{} {}", class, title);
+    }
+    for (class, title) in [
+        ("zoom", "Zoom Workplace"),
+        ("teams-for-linux", "Chat | Microsoft Teams"),
+        ("teams-for-linux", "Calls | Microsoft Teams"),
+        ("google-chrome", "Google Meet - Google Chrome"),
+        ("Slack", "general - Acme - Slack"),
+        ("kitty", "Meeting notes"),
+    ] {
+        assert!(!is_call_window(class, title), "{} {}", class, title);
+    }
+
+    for (config, error) in [
+        (
+            r#"[{"in_call": true, "layer": "ptt"}]"#,
+            r#"rule #1: 'in_call' needs a "call_detection" entry"#,
+        ),
+        (
+            r#"[{"call_detection": {"poll_interval_ms": 0}}]"#,
+            "entry #1: 'call_detection': 'poll_interval_ms' must be positive",
+        ),
+    ] {
+        std::fs::write(&path, config).unwrap();
+        assert_eq!(parse_config(&path).unwrap_err(), error);
+    }
+}
+
+#[test]
+fn test_fallback_remap_config_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.json");
+    for (config, error) in [
+        (
+            r#"[{"class": "vim", "fallback_remap": {"caps": "esc"}}]"#,
+            r#"rule #1: 'fallback_remap' needs a "uinput_fallback" entry"#,
+        ),
+        (
+            r#"[{"class": "vim", "fallback_remap": {"capslock": "esc"}}]"#,
+            r#"rule #1: 'fallback_remap': unknown key "capslock""#,
+        ),
+        (
+            r#"[{"on_native_terminal": "tty", "fallback_remap": {"caps": "esc"}}]"#,
+            "entry #1: 'on_native_terminal' cannot be combined with 'fallback_remap'",
+        ),
+    ] {
+        std::fs::write(&path, config).unwrap();
+        assert_eq!(parse_config(&path).unwrap_err(), error);
+    }
+}
+
+#[cfg(not(feature = "uinput-fallback"))]
+#[test]
+fn test_config_rejects_uinput_fallback_without_feature() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.json");
+    std::fs::write(&path, r#"[{"uinput_fallback": {}}]"#).unwrap();
+    assert_eq!(
+        parse_config(&path).unwrap_err(),
+        "entry #1: 'uinput_fallback' needs a build with the `uinput-fallback` feature"
+    );
+}
+
+#[cfg(feature = "uinput-fallback")]
+#[test]
+fn test_uinput_fallback_remaps() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.json");
+    std::fs::write(
+        &path,
+        r#"[
+            {"uinput_fallback": {"remap": {"caps": "esc", "esc": "caps"}}},
+            {"class": "^vim$", "fallback_remap": {"caps": "lctl"}, "layer": "vim"}
+        ]"#,
+    )
+    .unwrap();
+    let config = parse_config(&path).unwrap();
+    let fallback = config.uinput_fallback.clone().unwrap();
+    assert_eq!(fallback.grace_ms, 3000);
+    assert!(fallback.devices.is_empty());
+    assert_eq!(
+        config.rules[0].summary(),
+        "class=\"^vim$\" -> layer=vim fallback_remap"
+    );
+
+    let mut handler = FocusHandler::from_config(&config, true, false);
+    let key_map =
+        |handler: &FocusHandler| fallback_key_map(&fallback.remap, &handler.fallback_remap());
+    assert_eq!(key_map(&handler), HashMap::from([(58, 1), (1, 58)]));
+    handler.handle(&win("vim", ""), "base");
+    assert_eq!(key_map(&handler), HashMap::from([(58, 29), (1, 58)]));
+    handler.handle(&win("firefox", ""), "base");
+    assert_eq!(key_map(&handler), HashMap::from([(58, 1), (1, 58)]));
+
+    std::fs::write(&path, r#"[{"uinput_fallback": {"remap": {"caps": "hyper"}}}]"#).unwrap();
+    assert_eq!(
+        parse_config(&path).unwrap_err(),
+        r#"entry #1: 'uinput_fallback': 'remap': unknown key "hyper""#
+    );
+}
+
+#[cfg(feature = "uinput-fallback")]
+#[test]
+fn test_uinput_fallback_wanted() {
+    let grace = Duration::from_secs(3);
+    let unreachable = Some(Duration::from_secs(5));
+    assert!(uinput_fallback_wanted(false, unreachable, grace, || false));
+    assert!(!uinput_fallback_wanted(false, None, grace, || false));
+    assert!(!uinput_fallback_wanted(false, Some(Duration::from_secs(1)), grace, || false));
+    // Pausing disconnects on purpose; a starting kanata needs the keyboards
+    assert!(!uinput_fallback_wanted(true, unreachable, grace, || false));
+    assert!(!uinput_fallback_wanted(false, unreachable, grace, || true));
+}
+
+#[cfg(feature = "uinput-fallback")]
+#[test]
+fn test_uinput_fallback_releases_keys_as_pressed() {
+    let mut translator = uinput_fallback::KeyTranslator::default();
+    let caps_to_esc = HashMap::from([(58, 1)]);
+    assert_eq!(translator.translate(58, 1, &caps_to_esc), 1);
+    assert_eq!(translator.translate(58, 2, &HashMap::new()), 1);
+    // The remap changed while Caps was held: it's still released as Esc
+    assert_eq!(translator.translate(58, 0, &HashMap::new()), 1);
+    assert_eq!(translator.translate(58, 1, &HashMap::new()), 58);
+    assert_eq!(translator.translate(30, 1, &caps_to_esc), 30);
+}
+
+#[test]
+fn test_mic_in_use_from_pactl() {
+    let sources = "53\talsa_output.pci.analog-stereo.monitor\tPipeWire\ts32le 2ch 48000Hz\tIDLE\n\
+                   54\talsa_input.pci.analog-stereo\tPipeWire\ts32le 2ch 48000Hz\tRUNNING\n";
+    assert!(!mic_in_use("", sources));
+    // A recorder of the speaker monitor (e.g. a screen recorder) is no call
+    assert!(!mic_in_use("120\t53\t96\tprotocol-native.c\tfloat32le 2ch 48000Hz\n", sources));
+    assert!(mic_in_use("121\t54\t97\tprotocol-native.c\tfloat32le 1ch 48000Hz\n", sources));
+}
+
+#[test]
+fn test_initial_class_and_title_conditions() {
+    // Terminals rewrite their title; the initial title stays "kitty"
+    let rules = vec![
+        Rule {
+            initial_title: Some("^kitty$".to_string()),
+            layer: Some("terminal".to_string()),
+            ..Default::default()
+        },
+        rule(Some("firefox"), None, Some("browser")),
+    ];
+    let mut handler = FocusHandler::new(rules, None, true);
+
+    let with_initial = |class: &str, title: &str, initial_title: &str| WindowInfo {
+        initial: Some(InitialIdentity {
+            class: class.to_string(),
+            title: initial_title.to_string(),
+        }),
+        ..win(class, title)
+    };
+    let actions = handler
+        .handle(&with_initial("kitty", "vim notes.md", "kitty"), "default")
+        .unwrap();
+    assert_eq!(get_layers(&actions), vec!["terminal".to_string()]);
+    let actions = handler
+        .handle(&with_initial("firefox", "kitty - Search", "Mozilla Firefox"), "default")
+        .unwrap();
+    assert_eq!(get_layers(&actions), vec!["browser".to_string()]);
+    // Unknown initial identity never matches
+    let actions = handler.handle(&win("kitty", "kitty"), "default").unwrap();
+    assert_eq!(get_layers(&actions), vec!["default".to_string()]);
+}
+
+#[test]
+fn test_toplevel_window_keeps_initial_identity() {
+    let mut window = ToplevelWindow::default();
+    window.set_app_id("kitty".to_string());
+    window.set_title("kitty".to_string());
+    window.set_title("vim notes.md".to_string());
+    assert_eq!(window.title, "vim notes.md");
+    assert_eq!(
+        window.initial_identity(),
+        InitialIdentity {
+            class: "kitty".to_string(),
+            title: "kitty".to_string(),
+        }
+    );
+}
+
+#[test]
+fn test_config_parses_initial_conditions() {
+    let config = load_config_json(r#"[{"initial_class": "^kitty$", "initial_title": "^kitty$", "layer": "terminal"}]"#);
+    assert_eq!(config.rules[0].initial_class.as_deref(), Some("^kitty$"));
+    assert_eq!(config.rules[0].initial_title.as_deref(), Some("^kitty$"));
+}
+
+#[test]
+fn test_parse_hyprland_window() {
+    let reply = r#"{"class": "kitty", "title": "vim notes.md", "initialClass": "kitty", "initialTitle": "kitty", "at": [0, 0], "size": [1280, 720], "xwayland": false, "workspace": {"id": 2, "name": "code"}}"#;
+    assert_eq!(
+        parse_hyprland_window(reply),
+        Some(CompositorWindow {
+            size: Some(WindowSize {
+                width: 1280,
+                height: 720
+            }),
+            initial: Some(InitialIdentity {
+                class: "kitty".to_string(),
+                title: "kitty".to_string(),
+            }),
+            x11_class: None,
+            workspace: Some("code".to_string()),
+        })
+    );
+    let xwayland = r#"{"class": "steam", "title": "Steam", "size": [800, 600], "xwayland": true}"#;
+    assert_eq!(
+        parse_hyprland_window(xwayland).and_then(|window| window.x11_class),
+        Some("steam".to_string())
+    );
+    assert_eq!(parse_hyprland_window("{}"), None);
+    assert_eq!(parse_hyprland_window("Invalid"), None);
+}
+
+#[test]
+fn test_find_sway_focused_window() {
+    let tree = serde_json::json!({
+        "focused": false,
+        "rect": {"width": 1920, "height": 1080},
+        "nodes": [{
+            "focused": false,
+            "type": "workspace",
+            "name": "2: web",
+            "nodes": [{"focused": false, "rect": {"width": 960, "height": 1080}}],
+            "floating_nodes": [{
+                "focused": true,
+                "rect": {"width": 320, "height": 180},
+                "app_id": null,
+                "window_properties": {"class": "Steam", "instance": "steamwebhelper"}
+            }]
+        }]
+    });
+    assert_eq!(
+        find_sway_focused_window(&tree),
+        Some(CompositorWindow {
+            size: Some(WindowSize {
+                width: 320,
+                height: 180
+            }),
+            initial: None,
+            x11_class: Some("Steam".to_string()),
+            workspace: Some("2: web".to_string()),
+        })
+    );
+    assert_eq!(
+        find_sway_focused_window(&serde_json::json!({"nodes": []})),
+        None
+    );
+}
+
+#[test]
+fn test_class_source_picks_app_id_or_x11_class() {
+    let rules = vec![
+        Rule {
+            class: Some("^steam$".to_string()),
+            layer: Some("gaming".to_string()),
+            ..Default::default()
+        },
+        Rule {
+            class: Some("^Steam$".to_string()),
+            class_source: Some(ClassSource::X11Class),
+            layer: Some("launcher".to_string()),
+            ..Default::default()
+        },
+    ];
+    let xwayland = WindowInfo {
+        class: "steam".to_string(),
+        title: "Steam".to_string(),
+        x11_class: Some("Steam".to_string()),
+        ..Default::default()
+    };
+    let native = WindowInfo {
+        class: "steam".to_string(),
+        title: "Steam".to_string(),
+        ..Default::default()
+    };
+
+    let mut handler = FocusHandler::new(rules.clone(), None, true);
+    let result = handler.handle(&xwayland, "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("gaming".to_string())]);
+
+    // Global x11_class: the first rule no longer matches "Steam"; the per-rule override is
+    // redundant but harmless
+    let mut handler = FocusHandler::new(rules.clone(), None, true).with_class_source(ClassSource::X11Class);
+    let result = handler.handle(&xwayland, "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("launcher".to_string())]);
+
+    // Native Wayland windows have no WM_CLASS and fall back to the app_id
+    let mut handler = FocusHandler::new(rules, None, true).with_class_source(ClassSource::X11Class);
+    let result = handler.handle(&native, "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("gaming".to_string())]);
+}
+
+#[test]
+fn test_config_parses_class_source() {
+    let config = load_config_json(
+        r#"[{"class_source": "x11_class"}, {"class": "^Steam$", "class_source": "app_id", "layer": "gaming"}]"#,
+    );
+    assert_eq!(config.class_source, ClassSource::X11Class);
+    assert_eq!(config.rules.len(), 1);
+    assert_eq!(config.rules[0].class_source, Some(ClassSource::AppId));
+
+    let config = load_config_json(r#"[{"class": "^kitty$", "layer": "terminal"}]"#);
+    assert_eq!(config.class_source, ClassSource::AppId);
+    assert_eq!(config.rules[0].class_source, None);
+}
+
+#[test]
+fn test_app_condition_matches_desktop_id() {
+    let rules = vec![
+        Rule {
+            app: Some("^org\\.gnome\\.Nautilus\\.desktop$".to_string()),
+            layer: Some("files".to_string()),
+            ..Default::default()
+        },
+        Rule {
+            class: Some("Nautilus".to_string()),
+            layer: Some("class".to_string()),
+            ..Default::default()
+        },
+    ];
+    let mut handler = FocusHandler::new(rules, None, true);
+
+    let nautilus = WindowInfo {
+        class: "org.gnome.Nautilus".to_string(),
+        title: "Home".to_string(),
+        desktop_id: Some("org.gnome.Nautilus.desktop".to_string()),
+        ..Default::default()
+    };
+    let result = handler.handle(&nautilus, "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("files".to_string())]);
+
+    // Backends without desktop IDs never match `app`
+    let without_app = WindowInfo {
+        desktop_id: None,
+        ..nautilus
+    };
+    let result = handler.handle(&without_app, "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("class".to_string())]);
+}
+
+#[test]
+fn test_workspace_condition_matches_current_workspace() {
+    let rules = vec![
+        Rule {
+            class: Some("firefox".to_string()),
+            workspace: Some("^(2: )?web$".to_string()),
+            layer: Some("browser".to_string()),
+            ..Default::default()
+        },
+        Rule {
+            layer: Some("other".to_string()),
+            ..Default::default()
+        },
+    ];
+    let mut handler = FocusHandler::new(rules, None, true);
+
+    let firefox = |workspace: Option<&str>| WindowInfo {
+        class: "firefox".to_string(),
+        title: "Mozilla Firefox".to_string(),
+        workspace: workspace.map(String::from),
+        ..Default::default()
+    };
+    let result = handler.handle(&firefox(Some("2: web")), "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("browser".to_string())]);
+    let result = handler.handle(&firefox(Some("code")), "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("other".to_string())]);
+    let result = handler.handle(&firefox(Some("web")), "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("browser".to_string())]);
+
+    // Backends that don't report workspaces never match `workspace`
+    let result = handler.handle(&firefox(None), "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("other".to_string())]);
+}
+
+#[test]
+fn test_config_parses_workspace_condition() {
+    let config = load_config_json(r#"[{"workspace": "^web$", "layer": "browser"}]"#);
+    assert_eq!(config.rules[0].workspace.as_deref(), Some("^web$"));
+}
+
+#[test]
+fn test_instance_and_role_conditions() {
+    let rules = vec![
+        Rule {
+            class: Some("^firefox$".to_string()),
+            role: Some("^Popup$".to_string()),
+            layer: Some("popup".to_string()),
+            ..Default::default()
+        },
+        Rule {
+            instance: Some("^Navigator$".to_string()),
+            layer: Some("browser".to_string()),
+            ..Default::default()
+        },
+    ];
+    let mut handler = FocusHandler::new(rules, None, true);
+
+    let firefox = |role: Option<&str>| WindowInfo {
+        class: "firefox".to_string(),
+        title: "Mozilla Firefox".to_string(),
+        instance: Some("Navigator".to_string()),
+        role: role.map(String::from),
+        ..Default::default()
+    };
+    let result = handler.handle(&firefox(Some("browser")), "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("browser".to_string())]);
+    let result = handler.handle(&firefox(Some("Popup")), "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("popup".to_string())]);
+    // Windows without a role never match `role`
+    let result = handler.handle(&firefox(None), "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("browser".to_string())]);
+
+    let config = load_config_json(r#"[{"instance": "^Navigator$", "role": "Popup", "layer": "p"}]"#);
+    assert_eq!(config.rules[0].instance.as_deref(), Some("^Navigator$"));
+    assert_eq!(config.rules[0].role.as_deref(), Some("Popup"));
+}
+
+#[cfg(not(feature = "scripting"))]
+#[test]
+fn test_config_rejects_script_without_scripting_feature() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kanata-switcher.json");
+    std::fs::write(&path, r#"[{"script": "true", "layer": "a"}]"#).unwrap();
+    assert_eq!(
+        parse_config(&path).unwrap_err(),
+        "rule #1: 'script' needs a build with the `scripting` feature"
+    );
+}
+
+#[cfg(feature = "scripting")]
+#[test]
+fn test_script_rules() {
+    let rules = vec![
+        Rule {
+            name: Some("Video".to_string()),
+            class: Some("^firefox$".to_string()),
+            script: Some(
+                r#"if window.title.contains("YouTube") { "media" } else if window.width > 1000 { true }"#
+                    .to_string(),
+            ),
+            layer: Some("browser-wide".to_string()),
+            ..Default::default()
+        },
+        Rule {
+            script: Some(r#"window.workspace == "2" && default_layer == "base""#.to_string()),
+            layer: Some("second".to_string()),
+            ..Default::default()
+        },
+        Rule {
+            script: Some("window.title.len() / 0".to_string()),
+            layer: Some("broken".to_string()),
+            ..Default::default()
+        },
+    ];
+    let mut handler = FocusHandler::new(rules, None, true);
+    let firefox = |title: &str, width: u32| WindowInfo {
+        class: "firefox".to_string(),
+        title: title.to_string(),
+        size: Some(WindowSize { width, height: 600 }),
+        ..Default::default()
+    };
+
+    // A string result is the layer to switch to
+    let result = handler.handle(&firefox("YouTube - Firefox", 800), "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("media".to_string())]);
+    // `true` keeps the rule's layer
+    let result = handler.handle(&firefox("Docs", 1200), "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("browser-wide".to_string())]);
+    // `()` (no branch taken) is no match; a failing script never matches
+    let result = handler.handle(&firefox("Docs", 800), "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("base".to_string())]);
+
+    let second = WindowInfo {
+        workspace: Some("2".to_string()),
+        ..firefox("Docs", 800)
+    };
+    let result = handler.handle(&second, "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("second".to_string())]);
+}
+
+#[cfg(feature = "scripting")]
+#[test]
+fn test_script_limits_and_errors() {
+    let error = CompiledRule::compile(&Rule {
+        script: Some("if (".to_string()),
+        ..Default::default()
+    })
+    .unwrap_err();
+    assert!(error.starts_with("'script' does not compile: "), "{error}");
+
+    let run = |source: &str| MatchScript::compile(source).unwrap().run(&WindowInfo::default(), "");
+    assert_eq!(run("false"), Ok(ScriptMatch::NoMatch));
+    assert_eq!(run("()"), Ok(ScriptMatch::NoMatch));
+    assert_eq!(run(r#""code""#), Ok(ScriptMatch::Match(Some("code".to_string()))));
+    assert!(run("42").unwrap_err().contains("expected true/false, a layer name or ()"));
+    // Runaway loops hit the operation limit instead of stalling focus handling
+    assert!(run("loop {}").is_err());
+}
+
+/// `handle` after running the matcher commands `win` needs, like `apply_focus`
+async fn handle_with_commands(handler: &mut FocusHandler, win: &WindowInfo) -> Option<FocusActions> {
+    run_matcher_commands(handler.commands_to_run(win)).await;
+    handler.handle(win, "")
+}
+
+#[tokio::test]
+async fn test_command_rules() {
+    let dir = tempfile::tempdir().unwrap();
+    let runs = dir.path().join("runs");
+    let shell = |script: String| Some(vec!["sh".to_string(), "-c".to_string(), script]);
+    let rules = vec![
+        Rule {
+            class: Some("^slack$".to_string()),
+            command: shell(format!(
+                r#"echo run >> '{}'; read class; read title
+                case "$title" in *Huddle*) echo meeting ;; *) [ "$class" = slack ] ;; esac"#,
+                runs.display()
+            )),
+            layer: Some("chat".to_string()),
+            ..Default::default()
+        },
+        Rule {
+            class: Some("^stuck$".to_string()),
+            command: shell("sleep 5".to_string()),
+            layer: Some("never".to_string()),
+            ..Default::default()
+        },
+        Rule {
+            class: Some("^chatty$".to_string()),
+            command: shell("echo chatty; head -c 200000 /dev/zero".to_string()),
+            layer: Some("never".to_string()),
+            ..Default::default()
+        },
+        Rule {
+            command: shell("exit 1".to_string()),
+            layer: Some("never".to_string()),
+            ..Default::default()
+        },
+    ];
+    let mut handler = FocusHandler::new(rules, None, true);
+
+    let actions = handle_with_commands(&mut handler, &win("slack", "general")).await.unwrap();
+    assert!(has_action(&actions, &FocusAction::ChangeLayer("chat".to_string())));
+    let actions =
+        handle_with_commands(&mut handler, &win("slack", "Huddle in #general")).await.unwrap();
+    assert!(has_action(&actions, &FocusAction::ChangeLayer("meeting".to_string())));
+    assert_eq!(handler.last_matched_rules(), vec![0]);
+
+    // The same class and title reuse the cached result
+    handle_with_commands(&mut handler, &win("slack", "general")).await.unwrap();
+    assert_eq!(std::fs::read_to_string(&runs).unwrap().lines().count(), 2);
+
+    // A command that doesn't exit in time is killed and doesn't match
+    let started = Instant::now();
+    handle_with_commands(&mut handler, &win("stuck", "")).await;
+    assert!(started.elapsed() < Duration::from_secs(4));
+    assert!(handler.last_matched_rules().is_empty());
+
+    // Output beyond the pipe buffer is read while the command runs
+    let actions = handle_with_commands(&mut handler, &win("chatty", "")).await.unwrap();
+    assert!(has_action(&actions, &FocusAction::ChangeLayer("chatty".to_string())));
+
+    // Matching never runs a command itself
+    assert!(handler.handle(&win("slack", "random"), "").is_none());
+    assert_eq!(std::fs::read_to_string(&runs).unwrap().lines().count(), 2);
+}
+
+#[test]
+fn test_config_rejects_empty_command() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kanata-switcher.json");
+    std::fs::write(&path, r#"[{"class": "slack", "command": [], "layer": "chat"}]"#).unwrap();
+    assert_eq!(parse_config(&path).unwrap_err(), "rule #1: 'command' must not be empty");
+}
+
+fn run_command_actions(actions: &FocusActions) -> Vec<RuleCommand> {
+    actions
+        .actions
+        .iter()
+        .filter_map(|action| match action {
+            FocusAction::RunCommand(command) => Some(command.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_rule_focus_and_unfocus_commands() {
+    let rules = vec![
+        Rule {
+            name: Some("Browser".to_string()),
+            class: Some("^firefox$".to_string()),
+            layer: Some("browser".to_string()),
+            on_focus_cmd: Some("notify-send browser".to_string()),
+            on_unfocus_cmd: Some("notify-send bye".to_string()),
+            ..Default::default()
+        },
+        Rule {
+            class: Some("^kitty$".to_string()),
+            layer: Some("terminal".to_string()),
+            ..Default::default()
+        },
+    ];
+    let mut handler = FocusHandler::new(rules, None, true);
+    let command = |key: &'static str, command: &str, class: &str, title: &str, layer: &str| {
+        RuleCommand {
+            key,
+            rule: "'Browser'".to_string(),
+            command: command.to_string(),
+            class: class.to_string(),
+            title: title.to_string(),
+            layer: layer.to_string(),
+        }
+    };
+
+    let actions = handler.handle(&win("firefox", "Docs"), "base").unwrap();
+    assert_eq!(
+        run_command_actions(&actions),
+        vec![command("on_focus_cmd", "notify-send browser", "firefox", "Docs", "browser")]
+    );
+    // Still matching: nothing runs again
+    assert_eq!(handler.handle(&win("firefox", "News"), "base"), None);
+
+    // The unfocus command gets the window the rule matched and the layer now in effect
+    let actions = handler.handle(&win("kitty", "~"), "base").unwrap();
+    assert_eq!(
+        run_command_actions(&actions),
+        vec![command("on_unfocus_cmd", "notify-send bye", "firefox", "News", "terminal")]
+    );
+
+    handler.handle(&win("firefox", "Docs"), "base").unwrap();
+    let actions = handler.handle(&WindowInfo::default(), "base").unwrap();
+    assert_eq!(
+        run_command_actions(&actions),
+        vec![command("on_unfocus_cmd", "notify-send bye", "firefox", "Docs", "base")]
+    );
+}
+
+#[tokio::test]
+async fn test_rule_command_spawn_sets_environment() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("env");
+    RuleCommand {
+        key: "on_focus_cmd",
+        rule: "#1".to_string(),
+        command: format!(
+            r#"printf '%s|%s|%s' "$KANATA_CLASS" "$KANATA_TITLE" "$KANATA_LAYER" > '{}'"#,
+            output.display()
+        ),
+        class: "firefox".to_string(),
+        title: "Docs \"quoted\"".to_string(),
+        layer: "browser".to_string(),
+    }
+    .spawn();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while std::fs::read_to_string(&output).unwrap_or_default().is_empty() {
+        assert!(Instant::now() < deadline, "command did not run");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(
+        std::fs::read_to_string(&output).unwrap(),
+        "firefox|Docs \"quoted\"|browser"
+    );
+}
+
+#[test]
+fn test_config_validates_rule_commands() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kanata-switcher.json");
+    std::fs::write(&path, r#"[{"class": "firefox", "on_focus_cmd": " "}]"#).unwrap();
+    assert_eq!(parse_config(&path).unwrap_err(), "rule #1: 'on_focus_cmd' must not be empty");
+    std::fs::write(&path, r#"[{"on_session_inactive": "lock", "on_unfocus_cmd": "true"}]"#)
+        .unwrap();
+    assert_eq!(
+        parse_config(&path).unwrap_err(),
+        "entry #1: 'on_session_inactive' cannot be combined with 'plugin_action', 'on_focus_cmd' or 'on_unfocus_cmd'"
+    );
+}
+
+#[cfg(not(feature = "wasm-plugins"))]
+#[test]
+fn test_config_rejects_plugin_without_wasm_plugins_feature() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kanata-switcher.json");
+    std::fs::write(&path, r#"[{"class": "firefox", "plugin_action": "notify"}]"#).unwrap();
+    assert_eq!(
+        parse_config(&path).unwrap_err(),
+        "rule #1: plugin 'notify' needs a build with the `wasm-plugins` feature"
+    );
+}
+
+/// Counts `kswitch_action` calls; `kswitch_match` matches once the action has run
+#[cfg(feature = "wasm-plugins")]
+const COUNTER_PLUGIN_WAT: &str = r#"
+(module
+  (memory (export "memory") 1)
+  (global $count (mut i32) (i32.const 0))
+  (func (export "kswitch_alloc") (param i32) (result i32) (i32.const 1024))
+  (func (export "kswitch_match") (param i32 i32) (result i32)
+    (i32.gt_s (global.get $count) (i32.const 0)))
+  (func (export "kswitch_action") (param i32 i32) (result i32)
+    (global.set $count (i32.add (global.get $count) (i32.const 1)))
+    (i32.const 0)))
+"#;
+
+#[cfg(feature = "wasm-plugins")]
+#[test]
+fn test_plugin_rules() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("plugins")).unwrap();
+    std::fs::write(dir.path().join("plugins/counter.wasm"), COUNTER_PLUGIN_WAT).unwrap();
+    let path = dir.path().join("kanata-switcher.json");
+    std::fs::write(
+        &path,
+        r#"[
+            {"plugin": "counter", "layer": "plugged"},
+            {"class": "firefox", "plugin_action": "counter", "layer": "browser"},
+            {"plugins_dir": "plugins"}
+        ]"#,
+    )
+    .unwrap();
+    let config = parse_config(&path).unwrap();
+    assert_eq!(config.plugins_dir, Some(dir.path().join("plugins")));
+    let mut handler = FocusHandler::from_config(&config, true, false);
+
+    // The matcher declines until the action has run once
+    assert_eq!(handler.handle(&win("kitty", ""), ""), None);
+    let actions = handler.handle(&win("firefox", "Docs"), "").unwrap();
+    let [FocusAction::ChangeLayer(layer), FocusAction::Plugin(call)] = actions.actions.as_slice()
+    else {
+        panic!("unexpected actions: {:?}", actions.actions);
+    };
+    assert_eq!(layer, "browser");
+    let payload: serde_json::Value = serde_json::from_str(&call.payload).unwrap();
+    assert_eq!(payload["rule"], 2);
+    assert_eq!(payload["layer"], "browser");
+    assert_eq!(payload["window"]["title"], "Docs");
+    assert_eq!(payload["window"]["workspace"], serde_json::Value::Null);
+    call.plugin.run_action(&call.payload).unwrap();
+
+    let actions = handler.handle(&win("kitty", ""), "").unwrap();
+    assert!(has_action(&actions, &FocusAction::ChangeLayer("plugged".to_string())));
+}
+
+#[cfg(feature = "wasm-plugins")]
+#[test]
+fn test_plugin_config_errors_and_limits() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kanata-switcher.json");
+    let parse = |text: &str| {
+        std::fs::write(&path, text).unwrap();
+        parse_config(&path).map(|_| ())
+    };
+    assert_eq!(
+        parse(r#"[{"plugin": "spin", "layer": "a"}]"#),
+        Err("rule #1: plugin 'spin' needs a 'plugins_dir' entry to load it from".to_string())
+    );
+    assert_eq!(
+        parse(r#"[{"plugins_dir": "."}, {"plugin": "../spin", "layer": "a"}]"#),
+        Err("rule #1: plugin name '../spin' must be a file name without the .wasm extension".to_string())
+    );
+    assert!(
+        parse(r#"[{"plugins_dir": "."}, {"plugin": "spin", "layer": "a"}]"#)
+            .unwrap_err()
+            .starts_with("rule #1: plugin 'spin' not found at ")
+    );
+    assert_eq!(
+        parse(r#"[{"on_native_terminal": "tty", "plugin_action": "spin"}]"#),
+        Err(
+            "'on_native_terminal' cannot be combined with 'plugin_action', 'on_focus_cmd' or 'on_unfocus_cmd'"
+                .to_string()
+        )
+    );
+
+    // A plugin stuck in a loop runs out of fuel instead of stalling focus handling
+    let spin = dir.path().join("spin.wasm");
+    std::fs::write(
+        &spin,
+        r#"(module
+            (memory (export "memory") 1)
+            (func (export "kswitch_alloc") (param i32) (result i32) (i32.const 0))
+            (func (export "kswitch_match") (param i32 i32) (result i32)
+              (loop $forever (br $forever))
+              (i32.const 1)))"#,
+    )
+    .unwrap();
+    let plugin = WasmPlugin::load("spin", &spin).unwrap();
+    assert!(plugin.matches("{}").is_err());
+    let error = plugin.run_action("{}").unwrap_err();
+    assert!(error.contains("missing export 'kswitch_action"), "{error}");
+}
+
+#[test]
+fn test_parse_wm_class() {
+    assert_eq!(
+        parse_wm_class(b"Navigator\0firefox\0"),
+        Some(("Navigator".to_string(), "firefox".to_string()))
+    );
+    assert_eq!(parse_wm_class(b"xterm"), Some(("xterm".to_string(), "xterm".to_string())));
+    assert_eq!(parse_wm_class(b""), None);
+}
+
+#[test]
+fn test_x11_desktop_name() {
+    let names = b"web\0\0code\0";
+    assert_eq!(x11_desktop_name(names, 0), "web");
+    assert_eq!(x11_desktop_name(names, 2), "code");
+    // Unnamed desktops fall back to their 1-based number
+    assert_eq!(x11_desktop_name(names, 1), "2");
+    assert_eq!(x11_desktop_name(names, 5), "6");
+    assert_eq!(x11_desktop_name(b"", 0), "1");
+}
+
+#[test]
+fn test_config_parses_app_condition() {
+    let config = load_config_json(r#"[{"app": "org.gnome.Nautilus.desktop", "layer": "files"}]"#);
+    assert_eq!(config.rules[0].app.as_deref(), Some("org.gnome.Nautilus.desktop"));
+}
+
+#[test]
+fn test_desktop_entries_resolve() {
+    let mut entries = DesktopEntries::default();
+    entries.add("firefox.desktop", "[Desktop Entry]\nName=Firefox\nExec=firefox %u\n");
+    entries.add(
+        "jetbrains-idea.desktop",
+        "[Desktop Entry]\nName=IDEA\nStartupWMClass=jetbrains-idea\n[Desktop Action new]\nStartupWMClass=ignored\n",
+    );
+    entries.add("org.gnome.Nautilus.desktop", "[Desktop Entry]\nName=Files\n");
+    entries.add("org.wezfurlong.wezterm.desktop", "[Desktop Entry]\nName=WezTerm\n");
+    entries.add("hidden.desktop", "[Desktop Entry]\nHidden=true\n");
+    // Shadowed by the hidden entry above (earlier data dirs win)
+    entries.add("hidden.desktop", "[Desktop Entry]\nName=Hidden\n");
+
+    let resolve = |class: &str, x11_class: Option<&str>| {
+        entries.resolve(&WindowInfo {
+            class: class.to_string(),
+            x11_class: x11_class.map(String::from),
+            ..Default::default()
+        })
+    };
+    // app_id equals the ID (case-insensitive)
+    assert_eq!(resolve("Firefox", None).as_deref(), Some("firefox.desktop"));
+    assert_eq!(
+        resolve("org.gnome.Nautilus", None).as_deref(),
+        Some("org.gnome.Nautilus.desktop")
+    );
+    // StartupWMClass, only from the [Desktop Entry] group
+    assert_eq!(
+        resolve("jetbrains-idea", Some("jetbrains-idea")).as_deref(),
+        Some("jetbrains-idea.desktop")
+    );
+    assert_eq!(resolve("ignored", None), None);
+    // Reverse-DNS ID whose last component is the class
+    assert_eq!(
+        resolve("wezterm", None).as_deref(),
+        Some("org.wezfurlong.wezterm.desktop")
+    );
+    // X11 WM_CLASS is tried before the app_id
+    assert_eq!(
+        resolve("steam_app_1", Some("firefox")).as_deref(),
+        Some("firefox.desktop")
+    );
+    assert_eq!(resolve("hidden", None), None);
+    assert_eq!(resolve("unknown", None), None);
+}
+
+#[test]
+fn test_desktop_entries_scan_dir_uses_subdirectory_ids() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let applications = dir.path().join("applications");
+    std::fs::create_dir_all(applications.join("kde")).unwrap();
+    std::fs::write(
+        applications.join("kde").join("konsole.desktop"),
+        "[Desktop Entry]\nStartupWMClass=konsole\n",
+    )
+    .unwrap();
+    std::fs::write(applications.join("notes.txt"), "not a desktop file").unwrap();
+
+    let mut entries = DesktopEntries::default();
+    entries.scan_dir(&applications, &applications);
+    let win = WindowInfo {
+        class: "konsole".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(entries.resolve(&win).as_deref(), Some("kde-konsole.desktop"));
+    assert_eq!(entries.ids.len(), 1);
+}
+
+#[test]
+fn test_app_condition_uses_resolved_desktop_id() {
+    let mut entries = DesktopEntries::default();
+    entries.add("org.wezfurlong.wezterm.desktop", "[Desktop Entry]\nName=WezTerm\n");
+    let rules = vec![Rule {
+        app: Some("^org\\.wezfurlong\\.wezterm\\.desktop$".to_string()),
+        layer: Some("terminal".to_string()),
+        ..Default::default()
+    }];
+    let win = WindowInfo {
+        class: "org.wezfurlong.wezterm".to_string(),
+        title: "zsh".to_string(),
+        ..Default::default()
+    };
+
+    let mut handler = FocusHandler::new(rules.clone(), None, true);
+    let result = handler.handle(&win, "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("base".to_string())]);
+
+    let mut handler = FocusHandler::new(rules, None, true).with_desktop_entries(Some(entries));
+    let result = handler.handle(&win, "base").unwrap();
+    assert_eq!(result.actions, vec![FocusAction::ChangeLayer("terminal".to_string())]);
+}
+
+#[test]
+fn test_gnome_window_info_desktop_id() {
+    let win = gnome_window_info("kitty".to_string(), "bash".to_string(), "kitty.desktop".to_string());
+    assert_eq!(win.desktop_id.as_deref(), Some("kitty.desktop"));
+    let win = gnome_window_info("popup".to_string(), String::new(), String::new());
+    assert_eq!(win.desktop_id, None);
+}
+
+#[test]
+fn test_rule_stats_count_matches() {
+    let rules = vec![
+        Rule {
+            class: Some("firefox".to_string()),
+            layer: Some("browser".to_string()),
+            fallthrough: true,
+            ..Default::default()
+        },
+        Rule {
+            title: Some("vim".to_string()),
+            virtual_key: Some("vk_vim".to_string()),
+            ..Default::default()
+        },
+        Rule {
+            class: Some("never".to_string()),
+            min_width: Some(100),
+            layer: Some("unused".to_string()),
+            ..Default::default()
+        },
+    ];
+    let native_terminal_rule = NativeTerminalRule {
+        layer: "tty".to_string(),
+        virtual_key: None,
+        raw_vk_action: Vec::new(),
+        silent: false,
+        notify: true,
+    };
+    let mut handler = FocusHandler::new(rules, Some(native_terminal_rule), true);
+    handler.handle(&win("firefox", "vim docs"), "base");
+    handler.handle(&win("firefox", "news"), "base");
+    handler.handle(&win("kitty", "vim"), "base");
+    handler.handle(&win("", ""), "base");
+    handler.handle(
+        &WindowInfo {
+            is_native_terminal: true,
+            ..Default::default()
+        },
+        "base",
+    );
+
+    let stats = handler.rule_stats();
+    let hits: Vec<(usize, u64)> = stats.iter().map(|stat| (stat.index, stat.hits)).collect();
+    assert_eq!(
+        hits,
+        vec![(0, 2), (1, 2), (2, 0), (NATIVE_TERMINAL_RULE_INDEX, 1)]
+    );
+    assert_eq!(
+        stats[0].summary,
+        r#"class="firefox" -> layer=browser fallthrough"#
+    );
+    assert_eq!(stats[1].summary, r#"title="vim" -> virtual_key=vk_vim"#);
+    assert_eq!(
+        stats[2].summary,
+        r#"class="never" min_width=100 -> layer=unused"#
+    );
+    assert_eq!(stats[3].summary, "on_native_terminal -> layer=tty");
+
+    // Counters survive pause (reset) but not a new handler
+    handler.reset();
+    assert_eq!(handler.rule_stats()[0].hits, 2);
+}
+
+#[test]
+fn test_rule_summary_without_conditions_or_actions() {
+    let rule = Rule {
+        fallthrough: true,
+        ..Default::default()
+    };
+    assert_eq!(rule.summary(), "(any window) -> fallthrough");
+    let rule = Rule {
+        app: Some("org.gnome.Nautilus.desktop".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(rule.summary(), r#"app="org.gnome.Nautilus.desktop" -> (no action)"#);
+}
+
+#[test]
+fn test_silent_rules() {
+    let entries: Vec<ConfigEntry> = serde_json::from_str(
+        r#"[{"class": "kitty", "layer": "terminal", "silent": true, "fallthrough": true},
+            {"class": "kitty", "title": "vim", "layer": "vim"},
+            {"on_native_terminal": "tty", "silent": true}]"#,
+    )
+    .unwrap();
+    let mut rules: Vec<Rule> = entries
+        .into_iter()
+        .map(|entry| match entry {
+            ConfigEntry::Rule(rule) => *rule,
+            other => panic!("unexpected entry {:?}", other),
+        })
+        .collect();
+    assert!(rules[0].silent && !rules[1].silent);
+    assert_eq!(rules[0].summary(), r#"class="kitty" -> layer=terminal fallthrough silent"#);
+    let native_terminal_rule = NativeTerminalRule {
+        layer: rules.pop().unwrap().on_native_terminal.unwrap(),
+        virtual_key: None,
+        raw_vk_action: Vec::new(),
+        silent: true,
+        notify: true,
+    };
+
+    let mut handler = FocusHandler::new(rules, Some(native_terminal_rule), true);
+    assert!(handler.handle(&win("kitty", "zsh"), "base").unwrap().silent);
+    // A non-silent rule matching too makes the switch loud
+    assert!(!handler.handle(&win("kitty", "vim"), "base").unwrap().silent);
+    assert!(!handler.handle(&win("firefox", ""), "base").unwrap().silent);
+    assert!(handler.handle(&native_terminal_window(), "base").unwrap().silent);
+
+    // Only the silent batch itself goes unlogged, not other batches written meanwhile
+    let kanata = KanataClient::new("127.0.0.1", 1, None, false, StatusBroadcaster::new());
+    let inner = kanata.inner.try_lock().unwrap();
+    let silent = KanataBatch {
+        silent: true,
+        ..Default::default()
+    };
+    assert!(!KanataClient::logs_batch(&inner, &silent));
+    assert!(KanataClient::logs_batch(&inner, &KanataBatch::default()));
+}
+
+#[test]
+fn test_bounded_match_input_keeps_head_and_tail() {
+    assert_eq!(bounded_match_input("short title"), "short title");
+
+    let long = format!("vim notes.md {} - Mozilla Firefox", "x".repeat(100_000));
+    let bounded = bounded_match_input(&long);
+    assert_eq!(bounded.len(), MAX_MATCH_INPUT_BYTES);
+    assert!(bounded.starts_with("vim notes.md"));
+    assert!(bounded.ends_with("- Mozilla Firefox"));
+    assert!(Pattern::compile("title", "Mozilla Firefox$").unwrap().is_match(&long));
+    assert!(Pattern::compile("title", "^vim ").unwrap().is_match(&long));
+
+    // Cuts land on char boundaries
+    let multibyte = "é".repeat(MAX_MATCH_INPUT_BYTES);
+    let bounded = bounded_match_input(&multibyte);
+    assert!(bounded.len() <= MAX_MATCH_INPUT_BYTES);
+    assert!(bounded.chars().all(|c| c == 'é'));
+}
+
+#[test]
+fn test_build_pattern_regex_limits() {
+    assert!(build_pattern_regex("^firefox$").is_ok());
+    assert!(matches!(
+        build_pattern_regex(r"\w{1000}{1000}"),
+        Err(regex::Error::CompiledTooBig(_))
+    ));
+    let nested = format!("{}a{}", "(".repeat(100), ")".repeat(100));
+    assert!(build_pattern_regex(&nested).is_err());
+}
+
+#[test]
+fn test_config_rejects_invalid_patterns_with_rule_index() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kanata-switcher.json");
+    let cases = [
+        (
+            r#"[{"class": "^kitty$", "layer": "a"}, {"title": "(vim", "layer": "b"}]"#,
+            "rule #2: 'title' pattern \"(vim\" is not a valid regex: ",
+        ),
+        (
+            r#"[{"name": "Docs", "app": "[", "layer": "a"}]"#,
+            "rule #1 'Docs': 'app' pattern \"[\" is not a valid regex: ",
+        ),
+        (
+            r#"[{"workspace": "(", "layer": "a"}]"#,
+            "rule #1: 'workspace' pattern \"(\" is not a valid regex: ",
+        ),
+        (
+            r#"[{"class": "\\w{1000}{1000}", "layer": "a"}]"#,
+            "rule #1: 'class' pattern \"\\w{1000}{1000}\" is too complex (compiled size exceeds ",
+        ),
+    ];
+    for (json, expected) in cases {
+        std::fs::write(&path, json).unwrap();
+        let error = parse_config(&path).unwrap_err();
+        assert!(error.starts_with(expected), "{error}");
+    }
+}
+
+#[test]
+fn test_compiled_rule_matching() {
+    let rule = Rule {
+        class: Some("^kitty$".to_string()),
+        title: Some("*".to_string()),
+        app: Some("*".to_string()),
+        ..Default::default()
+    };
+    let compiled = CompiledRule::compile(&rule).unwrap();
+    let mut window = win("kitty", "vim");
+    // "*" still requires a desktop-file ID for `app`
+    assert!(!compiled.matches("kitty", &window));
+    window.desktop_id = Some("kitty.desktop".to_string());
+    assert!(compiled.matches("kitty", &window));
+    assert!(!compiled.matches("kitty-other", &window));
+
+    // Hand-built rules with broken patterns never match instead of panicking
+    let mut handler = FocusHandler::new(
+        vec![Rule {
+            class: Some("(".to_string()),
+            layer: Some("broken".to_string()),
+            ..Default::default()
+        }],
+        None,
+        true,
+    );
+    let actions = handler.handle(&win("(", ""), "base").unwrap();
+    assert_eq!(actions.actions, vec![FocusAction::ChangeLayer("base".to_string())]);
+}
+
+#[test]
+fn test_window_debug_details() {
+    let win = WindowInfo {
+        class: "steam".to_string(),
+        title: "Steam".to_string(),
+        x11_class: Some("Steam".to_string()),
+        size: Some(WindowSize {
+            width: 800,
+            height: 600,
+        }),
+        workspace: Some("2".to_string()),
+        window_id: Some("0x3a00007".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        window_debug_details(&win),
+        r#"app_id/class="steam" id="0x3a00007" x11_class="Steam" instance=- role=- app=- workspace="2" initial=(-) size=800x600"#
+    );
+}
+
+// === Property Tests ===
+
+fn arb_class() -> impl Strategy<Value = String> {
+    prop_oneof![
+        Just("firefox".to_string()),
+        Just("kitty".to_string()),
+        Just("code".to_string()),
+        Just("alacritty".to_string()),
+        Just("".to_string()),
+        "[a-z]{1,10}".prop_map(String::from),
+    ]
+}
+
+fn arb_nonempty_class() -> impl Strategy<Value = String> {
+    arb_class().prop_filter("non-empty class", |class| !class.is_empty())
+}
+
+fn arb_title() -> impl Strategy<Value = String> {
+    prop_oneof![
+        Just("".to_string()),
+        Just("vim".to_string()),
+        Just("bash".to_string()),
+        "[a-zA-Z0-9 ]{0,20}".prop_map(String::from),
+    ]
+}
+
+fn arb_layer() -> impl Strategy<Value = String> {
+    prop_oneof![
         Just("default".to_string()),
         Just("browser".to_string()),
         Just("terminal".to_string()),
@@ -1542,736 +4900,3565 @@ fn arb_layer() -> impl Strategy<Value = String> {
     ]
 }
 
-fn arb_vk_name() -> impl Strategy<Value = String> {
-    prop_oneof![
-        Just("vk1".to_string()),
-        Just("vk2".to_string()),
-        Just("vk_browser".to_string()),
-        Just("vk_terminal".to_string()),
-    ]
+fn arb_vk_name() -> impl Strategy<Value = String> {
+    prop_oneof![
+        Just("vk1".to_string()),
+        Just("vk2".to_string()),
+        Just("vk_browser".to_string()),
+        Just("vk_terminal".to_string()),
+    ]
+}
+
+fn arb_vk_action() -> impl Strategy<Value = String> {
+    prop_oneof![
+        Just("Press".to_string()),
+        Just("Release".to_string()),
+        Just("Tap".to_string()),
+        Just("Toggle".to_string()),
+    ]
+}
+
+fn arb_rule() -> impl Strategy<Value = Rule> {
+    (
+        prop::option::of(arb_class()),
+        prop::option::of(arb_title()),
+        prop::option::of(arb_layer()),
+        prop::option::of(arb_vk_name()),
+        prop::option::of(prop::collection::vec(
+            (arb_vk_name(), arb_vk_action()),
+            0..3,
+        )),
+        any::<bool>(),
+    )
+        .prop_map(|(class, title, layer, vk, raw_vk, fallthrough)| Rule {
+            class,
+            title,
+            on_native_terminal: None,
+            layer,
+            virtual_key: vk,
+            raw_vk_action: raw_vk.map(|steps| {
+                steps
+                    .into_iter()
+                    .map(|(name, action)| RawVkStep::Action(name, action))
+                    .collect()
+            }),
+            fallthrough,
+            ..Default::default()
+        })
+}
+
+fn arb_window() -> impl Strategy<Value = WindowInfo> {
+    (arb_class(), arb_title()).prop_map(|(class, title)| WindowInfo {
+        class,
+        title,
+        is_native_terminal: false,
+        session_inactive: false,
+        session_locked: false,
+        size: None,
+        initial: None,
+        x11_class: None,
+        desktop_id: None,
+        workspace: None,
+        instance: None,
+        role: None,
+        window_id: None,
+    })
+}
+
+proptest! {
+    #[test]
+    fn prop_managed_vks_consistent(
+        rules in prop::collection::vec(arb_rule(), 1..5),
+        windows in prop::collection::vec(arb_window(), 1..10),
+    ) {
+        let mut handler = FocusHandler::new(rules, None, true);
+
+        for win in &windows {
+            let _ = handler.handle(win, "default");
+            // Just verify the handler state is consistent (Vec is always valid)
+            prop_assert!(handler.current_virtual_keys.len() <= 10); // sanity bound
+        }
+    }
+
+    #[test]
+    fn prop_releases_before_presses(
+        rules in prop::collection::vec(arb_rule(), 1..5),
+        windows in prop::collection::vec(arb_window(), 2..10),
+    ) {
+        let mut handler = FocusHandler::new(rules, None, true);
+
+        for win in &windows {
+            if let Some(actions) = handler.handle(win, "default") {
+                // All ReleaseVk actions should come before any PressVk actions
+                let first_press_idx = actions.actions.iter().position(|a| matches!(a, FocusAction::PressVk(_)));
+                let last_release_idx = actions.actions.iter().rposition(|a| matches!(a, FocusAction::ReleaseVk(_)));
+
+                if let (Some(press_idx), Some(release_idx)) = (first_press_idx, last_release_idx) {
+                    prop_assert!(release_idx < press_idx, "All releases should come before presses");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn prop_unfocus_releases_all_vks(
+        rules in prop::collection::vec(arb_rule(), 1..5),
+        win in arb_window(),
+    ) {
+        let mut handler = FocusHandler::new(rules, None, true);
+
+        // Focus a window first
+        let _ = handler.handle(&win, "default");
+        let vks_before = handler.current_virtual_keys.clone();
+
+        // Unfocus (empty class and title)
+        let actions = handler.handle(
+            &WindowInfo {
+                class: String::new(),
+                title: String::new(),
+                is_native_terminal: false,
+                session_inactive: false,
+                session_locked: false,
+                size: None,
+                initial: None,
+                x11_class: None,
+                desktop_id: None,
+                workspace: None,
+                instance: None,
+                role: None,
+                window_id: None,
+            },
+            "default",
+        );
+
+        // All previously active VKs must be released
+        if !vks_before.is_empty() {
+            prop_assert!(actions.is_some());
+            let actions = actions.unwrap();
+            for old_vk in &vks_before {
+                prop_assert!(has_action(&actions, &FocusAction::ReleaseVk(old_vk.clone())));
+            }
+        }
+        // After unfocus, no VKs should be active
+        prop_assert!(handler.current_virtual_keys.is_empty());
+    }
+
+    #[test]
+    fn prop_fallthrough_collects_all_raw_vk(
+        base_class in arb_nonempty_class(),
+        raw_vk1 in prop::collection::vec((arb_vk_name(), arb_vk_action()), 0..2),
+        raw_vk2 in prop::collection::vec((arb_vk_name(), arb_vk_action()), 0..2),
+    ) {
+        let rules = vec![
+            Rule {
+                class: Some(base_class.clone()),
+                title: None,
+                on_native_terminal: None,
+                layer: None,
+                virtual_key: None,
+                raw_vk_action: if raw_vk1.is_empty() {
+                    None
+                } else {
+                    Some(raw_vk1.iter().map(|(n, a)| RawVkStep::Action(n.clone(), a.clone())).collect())
+                },
+                fallthrough: true,
+                ..Default::default()
+            },
+            Rule {
+                class: Some(base_class.clone()),
+                title: None,
+                on_native_terminal: None,
+                layer: None,
+                virtual_key: None,
+                raw_vk_action: if raw_vk2.is_empty() {
+                    None
+                } else {
+                    Some(raw_vk2.iter().map(|(n, a)| RawVkStep::Action(n.clone(), a.clone())).collect())
+                },
+                fallthrough: false,
+                ..Default::default()
+            },
+        ];
+
+        let mut handler = FocusHandler::new(rules, None, true);
+        let win = WindowInfo {
+            class: base_class,
+            title: String::new(),
+            is_native_terminal: false,
+            session_inactive: false,
+            session_locked: false,
+            size: None,
+            initial: None,
+            x11_class: None,
+            desktop_id: None,
+            workspace: None,
+            instance: None,
+            role: None,
+            window_id: None,
+        };
+
+        if let Some(actions) = handler.handle(&win, "default") {
+            let expected: Vec<_> = raw_vk1.into_iter().chain(raw_vk2).collect();
+            prop_assert_eq!(get_raw_vk_actions(&actions), expected);
+        }
+    }
+
+    #[test]
+    fn prop_fallthrough_collects_all_layers(
+        base_class in arb_nonempty_class(),
+        layer1 in arb_layer(),
+        layer2 in arb_layer(),
+    ) {
+        let rules = vec![
+            Rule {
+                class: Some(base_class.clone()),
+                title: None,
+                on_native_terminal: None,
+                layer: Some(layer1.clone()),
+                virtual_key: None,
+                raw_vk_action: None,
+                fallthrough: true,
+                ..Default::default()
+            },
+            Rule {
+                class: Some(base_class.clone()),
+                title: None,
+                on_native_terminal: None,
+                layer: Some(layer2.clone()),
+                virtual_key: None,
+                raw_vk_action: None,
+                fallthrough: false,
+                ..Default::default()
+            },
+        ];
+
+        let mut handler = FocusHandler::new(rules, None, true);
+        let win = WindowInfo {
+            class: base_class,
+            title: String::new(),
+            is_native_terminal: false,
+            session_inactive: false,
+            session_locked: false,
+            size: None,
+            initial: None,
+            x11_class: None,
+            desktop_id: None,
+            workspace: None,
+            instance: None,
+            role: None,
+            window_id: None,
+        };
+
+        if let Some(actions) = handler.handle(&win, "default") {
+            // Both layers should be collected
+            prop_assert_eq!(get_layers(&actions), vec![layer1, layer2]);
+        }
+    }
+
+    #[test]
+    fn prop_all_matched_vks_pressed_and_held(
+        base_class in arb_nonempty_class(),
+        vk1 in arb_vk_name(),
+        vk2 in arb_vk_name(),
+    ) {
+        let rules = vec![
+            Rule {
+                class: Some(base_class.clone()),
+                title: None,
+                on_native_terminal: None,
+                layer: None,
+                virtual_key: Some(vk1.clone()),
+                raw_vk_action: None,
+                fallthrough: true,
+                ..Default::default()
+            },
+            Rule {
+                class: Some(base_class.clone()),
+                title: None,
+                on_native_terminal: None,
+                layer: None,
+                virtual_key: Some(vk2.clone()),
+                raw_vk_action: None,
+                fallthrough: false,
+                ..Default::default()
+            },
+        ];
+
+        let mut handler = FocusHandler::new(rules, None, true);
+        let win = WindowInfo {
+            class: base_class,
+            title: String::new(),
+            is_native_terminal: false,
+            session_inactive: false,
+            session_locked: false,
+            size: None,
+            initial: None,
+            x11_class: None,
+            desktop_id: None,
+            workspace: None,
+            instance: None,
+            role: None,
+            window_id: None,
+        };
+
+        if let Some(actions) = handler.handle(&win, "default") {
+            // Both vk1 and vk2 should be pressed (all matched VKs are held)
+            prop_assert!(has_action(&actions, &FocusAction::PressVk(vk1.clone())));
+            prop_assert!(has_action(&actions, &FocusAction::PressVk(vk2.clone())));
+            // new_managed_vks should contain both
+            prop_assert_eq!(actions.new_managed_vks, vec![vk1, vk2]);
+        }
+    }
+}
+
+// === GNOME Extension State Parsing Tests ===
+
+#[test]
+fn test_gnome_extension_state_enabled_f64() {
+    // GNOME Shell returns state as f64, 1.0 = ENABLED
+    use zbus::zvariant::{OwnedValue, Value};
+
+    let mut body = HashMap::new();
+    body.insert(
+        "state".to_string(),
+        OwnedValue::try_from(Value::F64(1.0)).unwrap(),
+    );
+
+    let status = parse_gnome_extension_state(&body);
+    assert!(status.active, "state=1.0 should be active");
+    assert!(status.enabled, "state=1.0 should be enabled");
+    assert!(status.installed, "D-Bus response means installed");
+}
+
+#[test]
+fn test_gnome_extension_state_disabled_f64() {
+    // State 2.0 = DISABLED
+    use zbus::zvariant::{OwnedValue, Value};
+
+    let mut body = HashMap::new();
+    body.insert(
+        "state".to_string(),
+        OwnedValue::try_from(Value::F64(2.0)).unwrap(),
+    );
+
+    let status = parse_gnome_extension_state(&body);
+    assert!(!status.active, "state=2.0 should not be active");
+    assert!(!status.enabled, "state=2.0 should not be enabled");
+}
+
+#[test]
+fn test_gnome_extension_state_missing() {
+    // No state field - should default to not enabled
+    let body = HashMap::new();
+
+    let status = parse_gnome_extension_state(&body);
+    assert!(!status.active, "missing state should not be active");
+    assert!(!status.enabled, "missing state should not be enabled");
+}
+
+#[tokio::test]
+async fn test_logind_monitor_startup_failure_is_non_fatal() {
+    with_test_timeout(async {
+        let handler = Arc::new(Mutex::new(FocusHandler::new(Vec::new(), None, true)));
+        let status_broadcaster = StatusBroadcaster::new();
+        let pause_broadcaster = PauseBroadcaster::new();
+        let kanata = KanataClient::new("127.0.0.1", 10000, None, true, status_broadcaster.clone());
+
+        let started = start_logind_session_monitor_best_effort(
+            Environment::Wayland,
+            None,
+            false,
+            handler,
+            status_broadcaster.clone(),
+            pause_broadcaster,
+            kanata,
+            |_env, _session, _is_kde6, _handler, _status, _pause, _kanata| async {
+                Err(std::io::Error::new(std::io::ErrorKind::Other, "logind unavailable").into())
+            },
+        )
+        .await;
+
+        assert!(started.is_none());
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_logind_monitor_startup_success_returns_true() {
+    with_test_timeout(async {
+        let handler = Arc::new(Mutex::new(FocusHandler::new(Vec::new(), None, true)));
+        let status_broadcaster = StatusBroadcaster::new();
+        let pause_broadcaster = PauseBroadcaster::new();
+        let kanata = KanataClient::new("127.0.0.1", 10000, None, true, status_broadcaster.clone());
+
+        let started = start_logind_session_monitor_best_effort(
+            Environment::Wayland,
+            None,
+            false,
+            handler,
+            status_broadcaster.clone(),
+            pause_broadcaster,
+            kanata,
+            |_env, _session, _is_kde6, _handler, _status, _pause, _kanata| async {
+                Ok(AbortOnDrop(tokio::spawn(async {})))
+            },
+        )
+        .await;
+
+        assert!(started.is_some());
+    })
+    .await;
+}
+
+#[test]
+fn test_restart_backoff_grows_and_resets_after_stable_run() {
+    let mut backoff = RestartBackoff::new(&[10, 20, 50]);
+    let quick = Duration::from_millis(1);
+    assert_eq!(backoff.next_delay(quick), Duration::from_millis(10));
+    assert_eq!(backoff.next_delay(quick), Duration::from_millis(20));
+    assert_eq!(backoff.next_delay(quick), Duration::from_millis(50));
+    // The last delay repeats
+    assert_eq!(backoff.next_delay(quick), Duration::from_millis(50));
+    assert_eq!(
+        backoff.next_delay(SUPERVISOR_STABLE_RUN),
+        Duration::from_millis(10)
+    );
+}
+
+fn detect_wayland() -> Environment {
+    Environment::Wayland
+}
+
+fn detect_x11() -> Environment {
+    Environment::X11
+}
+
+fn detect_unknown() -> Environment {
+    Environment::Unknown
+}
+
+#[tokio::test]
+async fn test_supervise_backend_restarts_failed_backend() {
+    with_test_timeout(async {
+        let status_broadcaster = StatusBroadcaster::new();
+        let attempts = AtomicUsize::new(0);
+        let outcome = supervise_backend(
+            Environment::Wayland,
+            RestartBackoff::new(&[1]),
+            detect_unknown,
+            &status_broadcaster,
+            &RestartHandle::new(),
+            &ShutdownHandle::new(),
+            || {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                let degraded = status_broadcaster.degraded_problems();
+                async move {
+                    if attempt < 2 {
+                        return Err("compositor went away".into());
+                    }
+                    // Still degraded from the previous failure while restarting
+                    assert_eq!(
+                        degraded,
+                        vec!["wayland backend: compositor went away"]
+                    );
+                    Ok(RunOutcome::Exit)
+                }
+            },
+        )
+        .await;
+
+        assert_eq!(outcome, RunOutcome::Exit);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert!(status_broadcaster.degraded_problems().is_empty());
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_supervise_backend_restarts_daemon_when_environment_changes() {
+    with_test_timeout(async {
+        let status_broadcaster = StatusBroadcaster::new();
+        let outcome = supervise_backend(
+            Environment::Wayland,
+            RestartBackoff::new(&[1]),
+            detect_x11,
+            &status_broadcaster,
+            &RestartHandle::new(),
+            &ShutdownHandle::new(),
+            || async { Err("no toplevel protocol".into()) },
+        )
+        .await;
+
+        assert_eq!(outcome, RunOutcome::Restart);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_supervise_backend_shutdown_ends_backoff() {
+    with_test_timeout(async {
+        let status_broadcaster = StatusBroadcaster::new();
+        let shutdown_handle = ShutdownHandle::new();
+        shutdown_handle.request();
+        let attempts = AtomicUsize::new(0);
+        let outcome = supervise_backend(
+            Environment::Wayland,
+            RestartBackoff::new(&[60_000]),
+            detect_wayland,
+            &status_broadcaster,
+            &RestartHandle::new(),
+            &shutdown_handle,
+            || {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                async { Err("no toplevel protocol".into()) }
+            },
+        )
+        .await;
+
+        assert_eq!(outcome, RunOutcome::Exit);
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_wait_for_restart_or_shutdown_returns_restart() {
+    with_test_timeout(async {
+        let restart_handle = RestartHandle::new();
+        let shutdown_handle = ShutdownHandle::new();
+
+        let wait_future = wait_for_restart_or_shutdown(&restart_handle, &shutdown_handle);
+        let trigger_future = async {
+            tokio::task::yield_now().await;
+            restart_handle.request();
+        };
+        let (outcome, _) = tokio::join!(wait_future, trigger_future);
+        assert_eq!(outcome, RunOutcome::Restart);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_wait_for_restart_or_shutdown_returns_exit() {
+    with_test_timeout(async {
+        let restart_handle = RestartHandle::new();
+        let shutdown_handle = ShutdownHandle::new();
+
+        shutdown_handle.request();
+
+        let outcome = wait_for_restart_or_shutdown(&restart_handle, &shutdown_handle).await;
+        assert_eq!(outcome, RunOutcome::Exit);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_wait_for_restart_or_shutdown_shutdown_wins() {
+    with_test_timeout(async {
+        let restart_handle = RestartHandle::new();
+        let shutdown_handle = ShutdownHandle::new();
+
+        shutdown_handle.request();
+        restart_handle.request();
+
+        let outcome = wait_for_restart_or_shutdown(&restart_handle, &shutdown_handle).await;
+        assert_eq!(outcome, RunOutcome::Exit);
+    })
+    .await;
+}
+
+#[test]
+fn test_logind_no_session_error_detection() {
+    use zbus::names::OwnedErrorName;
+    use zbus::{Error as ZbusError, Message};
+
+    let name = OwnedErrorName::try_from(LOGIND_ERROR_NO_SESSION_FOR_PID).unwrap();
+    let reply = Message::method_call("/org/freedesktop/login1", "GetSessionByPID")
+        .unwrap()
+        .build(&())
+        .unwrap();
+    let error = ZbusError::MethodError(name, Some("no session".to_string()), reply);
+
+    assert!(is_logind_no_session_error(&error));
+}
+
+#[test]
+fn test_logind_no_session_error_detection_false() {
+    use zbus::names::OwnedErrorName;
+    use zbus::{Error as ZbusError, Message};
+
+    let name = OwnedErrorName::try_from("org.freedesktop.login1.NoSuchSession").unwrap();
+    let reply = Message::method_call("/org/freedesktop/login1", "GetSession")
+        .unwrap()
+        .build(&())
+        .unwrap();
+    let error = ZbusError::MethodError(name, Some("no session".to_string()), reply);
+
+    assert!(!is_logind_no_session_error(&error));
+}
+
+#[test]
+fn test_logind_empty_object_path_detection() {
+    let empty = OwnedObjectPath::try_from(LOGIND_EMPTY_OBJECT_PATH).unwrap();
+    let non_empty = OwnedObjectPath::try_from("/org/freedesktop/login1/session/_1").unwrap();
+
+    assert!(is_logind_empty_object_path(&empty));
+    assert!(!is_logind_empty_object_path(&non_empty));
+}
+
+#[test]
+fn test_parse_logind_object_path_value() {
+    use zbus::zvariant::ObjectPath;
+
+    let path = ObjectPath::try_from("/org/freedesktop/login1/session/_1").unwrap();
+    let value = OwnedValue::from(path);
+    let parsed = parse_logind_object_path(value, "test").unwrap();
+
+    assert_eq!(parsed.as_str(), "/org/freedesktop/login1/session/_1");
+}
+
+#[test]
+fn test_parse_logind_object_path_structure_single_field() {
+    use zbus::zvariant::{ObjectPath, StructureBuilder};
+
+    let path = ObjectPath::try_from("/org/freedesktop/login1/session/_1").unwrap();
+    let structure = StructureBuilder::new().add_field(path).build().unwrap();
+    let value = OwnedValue::try_from(structure).unwrap();
+    let parsed = parse_logind_object_path(value, "test").unwrap();
+
+    assert_eq!(parsed.as_str(), "/org/freedesktop/login1/session/_1");
+}
+
+#[test]
+fn test_parse_logind_object_path_string() {
+    use zbus::zvariant::Str;
+
+    let value = OwnedValue::from(Str::from("/org/freedesktop/login1/session/_1"));
+    let parsed = parse_logind_object_path(value, "test").unwrap();
+
+    assert_eq!(parsed.as_str(), "/org/freedesktop/login1/session/_1");
+}
+
+#[test]
+fn test_decode_logind_object_path_reply_object_path() {
+    use zbus::zvariant::ObjectPath;
+
+    let path = ObjectPath::try_from("/org/freedesktop/login1/session/_1").unwrap();
+    let reply = Message::method_call("/org/freedesktop/login1", "GetSession")
+        .unwrap()
+        .build(&path)
+        .unwrap();
+    let parsed = decode_logind_object_path_reply(&reply, "test").unwrap();
+
+    assert_eq!(parsed.as_str(), "/org/freedesktop/login1/session/_1");
+}
+
+#[test]
+fn test_decode_logind_object_path_reply_structure() {
+    use zbus::zvariant::{ObjectPath, StructureBuilder};
+
+    let path = ObjectPath::try_from("/org/freedesktop/login1/session/_1").unwrap();
+    let structure = StructureBuilder::new().add_field(path).build().unwrap();
+    let reply = Message::method_call("/org/freedesktop/login1", "GetSession")
+        .unwrap()
+        .build(&structure)
+        .unwrap();
+    let parsed = decode_logind_object_path_reply(&reply, "test").unwrap();
+
+    assert_eq!(parsed.as_str(), "/org/freedesktop/login1/session/_1");
+}
+
+#[test]
+fn test_decode_logind_object_path_reply_structure_multi_field() {
+    use zbus::zvariant::{ObjectPath, StructureBuilder};
+
+    let path = ObjectPath::try_from("/org/freedesktop/login1/session/_311").unwrap();
+    let structure = StructureBuilder::new()
+        .add_field("11")
+        .add_field(path)
+        .build()
+        .unwrap();
+    let reply = Message::method_call("/org/freedesktop/login1", "GetSession")
+        .unwrap()
+        .build(&structure)
+        .unwrap();
+    let parsed = decode_logind_object_path_reply(&reply, "test").unwrap();
+
+    assert_eq!(parsed.as_str(), "/org/freedesktop/login1/session/_311");
+}
+
+#[test]
+fn test_decode_logind_object_path_reply_variant() {
+    use zbus::zvariant::{ObjectPath, Value};
+
+    let path = ObjectPath::try_from("/org/freedesktop/login1/session/_1").unwrap();
+    let value = OwnedValue::try_from(Value::from(path)).unwrap();
+    let reply = Message::method_call("/org/freedesktop/login1", "GetSession")
+        .unwrap()
+        .build(&value)
+        .unwrap();
+    let parsed = decode_logind_object_path_reply(&reply, "test").unwrap();
+
+    assert_eq!(parsed.as_str(), "/org/freedesktop/login1/session/_1");
+}
+
+#[tokio::test]
+async fn test_update_status_for_focus_filters_invalid_virtual_keys() {
+    // Rule with a virtual key that's NOT in the known list
+    let rules = vec![Rule {
+        class: Some("firefox".to_string()),
+        title: None,
+        on_native_terminal: None,
+        layer: Some("browser".to_string()),
+        virtual_key: Some("invalid_vk".to_string()),
+        raw_vk_action: None,
+        fallthrough: false,
+        ..Default::default()
+    }];
+    let handler = Arc::new(Mutex::new(FocusHandler::new(rules, None, true)));
+    let status_broadcaster = StatusBroadcaster::new();
+    let kanata = KanataClient::new(
+        "127.0.0.1",
+        10000,
+        Some("default".to_string()),
+        true,
+        status_broadcaster.clone(),
+    );
+
+    {
+        let mut inner = kanata
+            .inner
+            .try_lock()
+            .expect("Expected KanataClient lock");
+        // Set known VKs to a list that does NOT include invalid_vk
+        inner.known_virtual_keys = Some(vec!["valid_vk".to_string()]);
+    }
+
+    let win = win("firefox", "");
+    apply_focus(
+        &handler,
+        &status_broadcaster,
+        &win,
+        &kanata,
+        "default",
+        None,
+    )
+    .await;
+
+    let snapshot = status_broadcaster.snapshot();
+    // The invalid VK should be filtered out - status should show NO virtual keys
+    assert!(
+        snapshot.virtual_keys.is_empty(),
+        "Invalid VK should not appear in status snapshot, got: {:?}",
+        snapshot.virtual_keys
+    );
+}
+
+#[tokio::test]
+async fn test_update_status_for_focus_shows_valid_virtual_keys() {
+    // Rule with a virtual key that IS in the known list
+    let rules = vec![Rule {
+        class: Some("firefox".to_string()),
+        title: None,
+        on_native_terminal: None,
+        layer: Some("browser".to_string()),
+        virtual_key: Some("vk_browser".to_string()),
+        raw_vk_action: None,
+        fallthrough: false,
+        ..Default::default()
+    }];
+    let handler = Arc::new(Mutex::new(FocusHandler::new(rules, None, true)));
+    let status_broadcaster = StatusBroadcaster::new();
+    let kanata = KanataClient::new(
+        "127.0.0.1",
+        10000,
+        Some("default".to_string()),
+        true,
+        status_broadcaster.clone(),
+    );
+
+    {
+        let mut inner = kanata
+            .inner
+            .try_lock()
+            .expect("Expected KanataClient lock");
+        // Set known VKs to include vk_browser
+        inner.known_virtual_keys = Some(vec!["vk_browser".to_string()]);
+    }
+
+    let win = win("firefox", "");
+    apply_focus(
+        &handler,
+        &status_broadcaster,
+        &win,
+        &kanata,
+        "default",
+        None,
+    )
+    .await;
+
+    let snapshot = status_broadcaster.snapshot();
+    // The valid VK should appear in the status
+    assert_eq!(
+        snapshot.virtual_keys,
+        vec!["vk_browser"],
+        "Valid VK should appear in status snapshot"
+    );
+}
+
+// === Config Parsing Tests ===
+
+#[test]
+fn test_config_rejects_unknown_fields() {
+    // "native_terminal" is not a valid field (should be "on_native_terminal")
+    let json = r#"[{"native_terminal": "tty"}]"#;
+    let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+    assert!(
+        result.is_err(),
+        "Config should reject unknown field 'native_terminal'"
+    );
+    let err = result.unwrap_err().to_string();
+    assert!(
+        err.contains("unknown field 'native_terminal'"),
+        "Error should mention the unknown field name, got: {}",
+        err
+    );
+}
+
+#[test]
+fn test_config_parses_size_conditions() {
+    let config = load_config_json(
+        r#"[{"max_width": 200, "max_height": 200}, {"class": "mpv", "min_width": 640, "layer": "media"}]"#,
+    );
+    assert_eq!(config.rules[0].max_width, Some(200));
+    assert_eq!(config.rules[0].max_height, Some(200));
+    assert_eq!(config.rules[1].min_width, Some(640));
+
+    let result: Result<Vec<ConfigEntry>, _> =
+        serde_json::from_str(r#"[{"class": "mpv", "max_width": -1}]"#);
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_config_rejects_typo_in_field_name() {
+    // Common typos should be rejected
+    let json = r#"[{"clas": "firefox", "layer": "browser"}]"#;
+    let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+    assert!(result.is_err(), "Config should reject typo 'clas'");
+    let err = result.unwrap_err().to_string();
+    assert!(
+        err.contains("unknown field 'clas'"),
+        "Error should mention the typo field name, got: {}",
+        err
+    );
+}
+
+#[test]
+fn test_config_accepts_valid_rule() {
+    let json = r#"[{"class": "firefox", "layer": "browser"}]"#;
+    let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+    assert!(result.is_ok(), "Config should accept valid rule");
+}
+
+#[test]
+fn test_config_accepts_on_native_terminal() {
+    let json = r#"[{"on_native_terminal": "tty"}]"#;
+    let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+    assert!(
+        result.is_ok(),
+        "Config should accept 'on_native_terminal' field"
+    );
+}
+
+#[test]
+fn test_config_accepts_default_entry() {
+    let json = r#"[{"default": "base"}]"#;
+    let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+    assert!(result.is_ok(), "Config should accept default entry");
+}
+
+#[test]
+fn test_config_parses_raw_vk_action_delay() {
+    let json = r#"[{"class": "app", "raw_vk_action": [["vk_a", "Tap"], ["delay", 150], ["vk_b", "Tap"]]}]"#;
+    let entries: Vec<ConfigEntry> = serde_json::from_str(json).unwrap();
+    let ConfigEntry::Rule(rule) = &entries[0] else {
+        panic!("Expected Rule entry");
+    };
+    assert_eq!(
+        rule.raw_vk_action,
+        Some(vec![
+            RawVkStep::Action("vk_a".to_string(), "Tap".to_string()),
+            RawVkStep::Delay(150),
+            RawVkStep::Action("vk_b".to_string(), "Tap".to_string()),
+        ])
+    );
+}
+
+#[test]
+fn test_config_rejects_invalid_raw_vk_action_delay() {
+    for json in [
+        r#"[{"class": "app", "raw_vk_action": [["delay", -5]]}]"#,
+        r#"[{"class": "app", "raw_vk_action": [["delay", 1.5]]}]"#,
+        r#"[{"class": "app", "raw_vk_action": [["vk_a", 150]]}]"#,
+    ] {
+        let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+        assert!(result.is_err(), "Config should reject {}", json);
+    }
+}
+
+#[test]
+fn test_config_parses_reload_fields() {
+    let json = r#"[{"class": "game", "reload_num": 2}, {"class": "editor", "reload_next": true}]"#;
+    let entries: Vec<ConfigEntry> = serde_json::from_str(json).unwrap();
+    let (ConfigEntry::Rule(first), ConfigEntry::Rule(second)) = (&entries[0], &entries[1]) else {
+        panic!("Expected Rule entries");
+    };
+    assert_eq!(first.reload_num, Some(2));
+    assert!(!first.reload_next);
+    assert!(second.reload_next);
+}
+
+#[test]
+fn test_config_parses_matcherless_rule_with_fallthrough() {
+    // A rule with no class/title but with fallthrough: true is valid
+    // (applies to all windows and continues matching)
+    let json = r#"[{"layer": "base", "fallthrough": true}]"#;
+    let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+    assert!(
+        result.is_ok(),
+        "Config should parse matcher-less rule with fallthrough"
+    );
+    if let Ok(entries) = result {
+        if let ConfigEntry::Rule(rule) = &entries[0] {
+            assert!(rule.class.is_none());
+            assert!(rule.title.is_none());
+            assert!(rule.fallthrough);
+        } else {
+            panic!("Expected Rule entry");
+        }
+    }
+}
+
+#[test]
+fn test_config_parses_matcherless_rule_without_fallthrough() {
+    // A rule with no class/title and no fallthrough parses but will be rejected
+    // by load_config validation (not tested here since it calls exit)
+    let json = r#"[{"layer": "base"}]"#;
+    let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+    assert!(
+        result.is_ok(),
+        "Config should parse (validation happens in load_config)"
+    );
+    if let Ok(entries) = result {
+        if let ConfigEntry::Rule(rule) = &entries[0] {
+            assert!(rule.class.is_none());
+            assert!(rule.title.is_none());
+            assert!(!rule.fallthrough); // default is false
+        } else {
+            panic!("Expected Rule entry");
+        }
+    }
+}
+
+#[test]
+fn test_config_parses_rule_with_class_no_fallthrough() {
+    // A rule with a class matcher doesn't need fallthrough
+    let json = r#"[{"class": "firefox", "layer": "browser"}]"#;
+    let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+    assert!(result.is_ok(), "Config should parse rule with class matcher");
+    if let Ok(entries) = result {
+        if let ConfigEntry::Rule(rule) = &entries[0] {
+            assert!(rule.class.is_some());
+            assert!(!rule.fallthrough);
+        } else {
+            panic!("Expected Rule entry");
+        }
+    }
+}
+
+// === Kanata Config Names Tests ===
+
+const TEST_KBD: &str = r#"
+;; comment with (deflayer fake)
+#| block comment
+(deflayer hidden _)
+|#
+(defsrc a b)
+(deflayer base a b)
+(deflayer "browser" a b)
+(deflayermap (vim) a b)
+(defvirtualkeys
+  vk_browser (layer-while-held browser)
+  vk_notify (macro a b))
+(deffakekeys vk_legacy a)
+"#;
+
+#[test]
+fn test_parse_kbd_exprs_rejects_unbalanced() {
+    assert!(parse_kbd_exprs("(deflayer base a").is_err());
+    assert!(parse_kbd_exprs("(deflayer base a))").is_err());
+    assert!(parse_kbd_exprs("#| never closed").is_err());
+}
+
+#[test]
+fn test_load_kanata_config_names() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kanata.kbd");
+    std::fs::write(&path, TEST_KBD).unwrap();
+
+    let names = load_kanata_config_names(&path).unwrap();
+    assert_eq!(names.layers, vec!["base", "browser", "vim"]);
+    assert_eq!(names.virtual_keys, vec!["vk_browser", "vk_notify", "vk_legacy"]);
+}
+
+#[test]
+fn test_load_kanata_config_names_follows_includes() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("main.kbd"), "(deflayer base a)\n(include extra.kbd)").unwrap();
+    std::fs::write(dir.path().join("extra.kbd"), "(deflayer extra a)\n(defvirtualkeys vk_x a)").unwrap();
+
+    let names = load_kanata_config_names(&dir.path().join("main.kbd")).unwrap();
+    assert_eq!(names.layers, vec!["base", "extra"]);
+    assert_eq!(names.virtual_keys, vec!["vk_x"]);
+}
+
+#[test]
+fn test_validate_config_names_reports_unknown_names() {
+    let names = KanataConfigNames {
+        layers: vec!["base".to_string(), "browser".to_string()],
+        virtual_keys: vec!["vk_browser".to_string()],
+    };
+    let config = Config {
+        rules: vec![
+            rule(Some("firefox"), None, Some("browser")),
+            rule(Some("kitty"), None, Some("termnial")),
+            rule_vk(Some("firefox"), "vk_brwoser"),
+            rule_raw_vk(Some("app"), vec![("vk_browser", "Tap"), ("vk_missing", "Tap")]),
+        ],
+        default_layer: Some("base".to_string()),
+        native_terminal_rule: Some(NativeTerminalRule {
+            layer: "tty".to_string(),
+            virtual_key: None,
+            raw_vk_action: Vec::new(),
+            silent: false,
+            notify: true,
+        }),
+        session_inactive_rule: Some(NativeTerminalRule {
+            layer: "locked".to_string(),
+            virtual_key: None,
+            raw_vk_action: Vec::new(),
+            silent: false,
+            notify: true,
+        }),
+        locked_rule: Some(NativeTerminalRule {
+            layer: "lockscren".to_string(),
+            virtual_key: None,
+            raw_vk_action: Vec::new(),
+            silent: false,
+            notify: true,
+        }),
+        kanata_endpoints: BTreeMap::new(),
+        layer_display_names: BTreeMap::new(),
+        layer_virtual_keys: BTreeMap::new(),
+        announce: None,
+        notifications: None,
+        call_detection: None,
+        uinput_fallback: None,
+        min_focus_ms: 0,
+        unfocus_grace_ms: 0,
+        dedupe_focus: true,
+        vt_monitor: true,
+        defer_during_grab: false,
+        per_window_state: false,
+        plugins_dir: None,
+        rule_tests: Vec::new(),
+        idle: None,
+        tray_icons: BTreeMap::new(),
+        indicator: IndicatorTheme::default(),
+        class_source: ClassSource::AppId,
+    };
+
+    assert_eq!(
+        validate_config_names(&config, &names),
+        vec![
+            "on_native_terminal: unknown layer \"tty\"".to_string(),
+            "on_session_inactive: unknown layer \"locked\"".to_string(),
+            "on_locked: unknown layer \"lockscren\"".to_string(),
+            "rule #2: unknown layer \"termnial\"".to_string(),
+            "rule #3: unknown virtual key \"vk_brwoser\"".to_string(),
+            "rule #4: unknown virtual key \"vk_missing\"".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn test_install_desktop_conflicts_with_uninstall() {
+    let result = Args::try_parse_from([
+        "kanata-switcher",
+        "--install-desktop",
+        "--uninstall-desktop",
+    ]);
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_app_desktop_content_settings() {
+    let content = build_app_desktop_content(
+        Path::new("/opt/kanata switcher/kanata-switcher"),
+        &DesktopLauncher::Settings,
+        Some(Path::new("/tmp/rules.json")),
+    );
+    assert!(content.starts_with("[Desktop Entry]\nType=Application\n"));
+    assert!(content.contains("Name=Kanata Switcher Settings\n"));
+    assert!(content.contains("Icon=kanata-switcher\n"));
+    assert!(content.contains(
+        "Exec=\"/opt/kanata switcher/kanata-switcher\" \"--settings\" \"-c\" \"/tmp/rules.json\"\n"
+    ));
+    assert!(content.contains("Terminal=false\n"));
+    assert!(content.contains("StartupWMClass=com.github.kanata.Switcher.Settings\n"));
+    assert!(content.contains(
+        "[Desktop Action pause]\nName=Pause Switching\nExec=\"/opt/kanata switcher/kanata-switcher\" \"--pause\"\n"
+    ));
+    // Never autostarts
+    assert!(!content.contains("X-GNOME-Autostart"));
+}
+
+#[test]
+fn test_app_desktop_content_monitor() {
+    let content = build_app_desktop_content(
+        Path::new("/usr/bin/kanata-switcher"),
+        &DesktopLauncher::Monitor(PathBuf::from("/usr/bin/kswitchctl")),
+        None,
+    );
+    assert!(content.contains("Name=Kanata Switcher Monitor\n"));
+    assert!(content.contains("Exec=\"/usr/bin/kswitchctl\" \"monitor\"\n"));
+    assert!(content.contains("Terminal=true\n"));
+    assert!(!content.contains("StartupWMClass"));
+    assert!(content.contains("Exec=\"/usr/bin/kanata-switcher\" \"--restart\"\n"));
+}
+
+#[test]
+fn test_desktop_paths_use_data_home() {
+    let data_home = Path::new("/home/user/.local/share");
+    assert_eq!(
+        desktop_app_path(data_home),
+        PathBuf::from("/home/user/.local/share/applications/com.github.kanata.Switcher.desktop")
+    );
+    assert_eq!(
+        desktop_icon_path(data_home),
+        PathBuf::from("/home/user/.local/share/icons/hicolor/scalable/apps/kanata-switcher.svg")
+    );
+}
+
+#[test]
+fn test_desktop_launcher_requires_kswitchctl_without_gui() {
+    let dir = tempfile::tempdir().unwrap();
+    let daemon = dir.path().join("kanata-switcher");
+    if cfg!(feature = "settings-gui") {
+        assert_eq!(
+            resolve_desktop_launcher(&daemon).unwrap(),
+            DesktopLauncher::Settings
+        );
+        return;
+    }
+    assert!(resolve_desktop_launcher(&daemon).is_err());
+    std::fs::write(dir.path().join("kswitchctl"), "").unwrap();
+    assert_eq!(
+        resolve_desktop_launcher(&daemon).unwrap(),
+        DesktopLauncher::Monitor(dir.path().join("kswitchctl"))
+    );
+}
+
+#[test]
+fn test_autostart_sync_detects_changed_options() {
+    let exec_path = Path::new("/usr/bin/kanata-switcher");
+    let conditions = AutostartConditions {
+        only_show_in: vec!["KDE".to_string()],
+        ..Default::default()
+    };
+    let installed = build_autostart_desktop_content(
+        exec_path,
+        &["-p".to_string(), "10000".to_string()],
+        &conditions,
+    );
+
+    assert_eq!(
+        autostart_sync_content(
+            &installed,
+            exec_path,
+            &["-p".to_string(), "10000".to_string()],
+            None
+        ),
+        None
+    );
+
+    let updated = autostart_sync_content(
+        &installed,
+        exec_path,
+        &["-p".to_string(), "12000".to_string()],
+        None,
+    )
+    .expect("changed port should need a sync");
+    assert!(updated.contains("Exec=\"/usr/bin/kanata-switcher\" \"-p\" \"12000\"\n"));
+    // Install-only conditions survive the rewrite
+    assert!(updated.contains("OnlyShowIn=KDE;\n"));
+}
+
+#[test]
+fn test_effective_invocation_records_config_and_backend() {
+    let dir = tempfile::tempdir().unwrap();
+    let config_path = dir.path().join("kanata-switcher.json");
+    std::fs::write(&config_path, "[]").unwrap();
+    let metadata = InvocationMetadata {
+        config_path: config_path.clone(),
+        backend: Environment::Gnome,
+    };
+    let exec_args = vec![
+        "-p".to_string(),
+        "12000".to_string(),
+        "--kanata".to_string(),
+        "$HOST 100%".to_string(),
+    ];
+    let output =
+        format_effective_invocation(Path::new("/usr/bin/kanata-switcher"), &exec_args, &metadata);
+    assert_eq!(
+        output,
+        format!(
+            "# Config: {}\n# Backend: gnome (detected now; the daemon detects it again at startup)\n\
+             Exec=\"/usr/bin/kanata-switcher\" \"-p\" \"12000\" \"--kanata\" \"$HOST 100%%\"\n\
+             ExecStart=\"/usr/bin/kanata-switcher\" \"-p\" \"12000\" \"--kanata\" \"$$HOST 100%%\"\n",
+            config_path.display()
+        )
+    );
+
+    // The comments go above the group header and leave the entry readable
+    let content = format!(
+        "{}{}",
+        metadata.comment_lines(),
+        build_autostart_desktop_content(
+            Path::new("/usr/bin/kanata-switcher"),
+            &exec_args,
+            &AutostartConditions::default()
+        )
+    );
+    assert!(content.starts_with("# Config: "));
+    assert_eq!(
+        desktop_entry_value(&content, "TryExec"),
+        Some("\"/usr/bin/kanata-switcher\"")
+    );
+
+    let missing = InvocationMetadata {
+        config_path: dir.path().join("missing.json"),
+        backend: Environment::Unknown,
+    };
+    assert!(missing.comment_lines().contains("missing.json (not found)\n# Backend: unknown"));
+
+    let matches = Args::command().get_matches_from([
+        "kanata-switcher",
+        "--print-effective-invocation",
+        "-q",
+    ]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    assert_eq!(autostart_passthrough_args(&matches, &args), vec!["-q".to_string()]);
+    assert!(
+        Args::try_parse_from([
+            "kanata-switcher",
+            "--print-effective-invocation",
+            "--install-autostart"
+        ])
+        .is_err()
+    );
+}
+
+#[test]
+fn test_systemd_unit_content() {
+    let metadata = InvocationMetadata {
+        config_path: PathBuf::from("/home/user/.config/kanata/kanata-switcher.json"),
+        backend: Environment::Kde,
+    };
+    let exec_args = vec!["-p".to_string(), "12000".to_string()];
+    let content = build_systemd_unit_content(
+        Path::new("/home/user/.cargo/bin/kanata-switcher"),
+        &exec_args,
+        &metadata,
+    );
+    assert!(content.starts_with(&metadata.comment_lines()));
+    for line in [
+        "After=graphical-session.target\n",
+        "PartOf=graphical-session.target\n",
+        "Type=notify\n",
+        "ExecStart=\"/home/user/.cargo/bin/kanata-switcher\" \"-p\" \"12000\"\n",
+        "TimeoutStartSec=infinity\n",
+        "WatchdogSec=30\n",
+        "WantedBy=graphical-session.target\n",
+    ] {
+        assert!(content.contains(line), "{} missing from\n{}", line, content);
+    }
+    assert_eq!(
+        systemd_unit_name(&DbusInstance::new(Some("work"))),
+        "kanata-switcher-work.service"
+    );
+
+    let matches = Args::command().get_matches_from([
+        "kanata-switcher",
+        "--install-systemd-service",
+        "--harden",
+        "-q",
+    ]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    assert_eq!(
+        autostart_passthrough_args(&matches, &args),
+        vec!["-q".to_string(), "--harden".to_string()]
+    );
+    for conflicting in [
+        "--uninstall-systemd-service",
+        "--install-autostart",
+        "--uninstall",
+    ] {
+        assert!(
+            Args::try_parse_from(["kanata-switcher", "--install-systemd-service", conflicting])
+                .is_err(),
+            "{}",
+            conflicting
+        );
+    }
+    assert!(
+        Args::try_parse_from(["kanata-switcher", "--uninstall-systemd-service", "--harden"])
+            .is_err()
+    );
+}
+
+#[test]
+fn test_sd_notify_reaches_path_and_abstract_sockets() {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("notify");
+    let listener = UnixDatagram::bind(&path).unwrap();
+    send_sd_notify(&path, "READY=1\nSTATUS=up").unwrap();
+    let mut buffer = [0u8; 64];
+    let len = listener.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"READY=1\nSTATUS=up");
+
+    let name = format!("kanata-switcher-test-{}", std::process::id());
+    let listener =
+        UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(name.as_bytes()).unwrap())
+            .unwrap();
+    send_sd_notify(Path::new(&format!("@{}", name)), "WATCHDOG=1").unwrap();
+    let len = listener.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"WATCHDOG=1");
+
+    assert!(send_sd_notify(&dir.path().join("missing"), "READY=1").is_err());
+}
+
+#[test]
+fn test_systemd_watchdog_interval() {
+    assert_eq!(
+        systemd_watchdog_interval(Some("30000000"), None, 42),
+        Some(Duration::from_secs(15))
+    );
+    assert_eq!(
+        systemd_watchdog_interval(Some("30000000"), Some("42"), 42),
+        Some(Duration::from_secs(15))
+    );
+    assert_eq!(systemd_watchdog_interval(Some("30000000"), Some("7"), 42), None);
+    assert_eq!(systemd_watchdog_interval(None, None, 42), None);
+    assert_eq!(systemd_watchdog_interval(Some("0"), None, 42), None);
+    assert_eq!(systemd_watchdog_interval(Some("soon"), None, 42), None);
+}
+
+#[test]
+fn test_desktop_entry_value_reads_main_group_only() {
+    let content = "[Desktop Entry]\nName=Kanata Switcher\nExec=\"/bin/a\" \"-q\"\n\n[Desktop Action x]\nExec=other\n";
+    assert_eq!(desktop_entry_value(content, "Exec"), Some("\"/bin/a\" \"-q\""));
+    assert_eq!(desktop_entry_value(content, "OnlyShowIn"), None);
+    assert_eq!(desktop_entry_value("Exec=x\n", "Exec"), None);
+}
+
+#[test]
+fn test_dry_run_logs_to_sink_as_own_instance() {
+    let mut args = Args::try_parse_from(["kanata-switcher", "--dry-run", "-c", "new.json"]).unwrap();
+    apply_dry_run(&mut args);
+    assert_eq!(args.kanata_sink, KanataSink::Log);
+    assert_eq!(args.dbus_suffix.as_deref(), Some(DRY_RUN_DBUS_SUFFIX));
+    assert!(parse_dbus_suffix(DRY_RUN_DBUS_SUFFIX).is_ok());
+
+    let mut args =
+        Args::try_parse_from(["kanata-switcher", "--dry-run", "--dbus-suffix", "test"]).unwrap();
+    apply_dry_run(&mut args);
+    assert_eq!(args.dbus_suffix.as_deref(), Some("test"));
+
+    let mut args = Args::try_parse_from(["kanata-switcher"]).unwrap();
+    apply_dry_run(&mut args);
+    assert_eq!(args.kanata_sink, KanataSink::Tcp);
+    assert_eq!(args.dbus_suffix, None);
+
+    for conflicting in [
+        &["-p", "10001"][..],
+        &["--kanata-sink", "null"],
+        &["--install-autostart"],
+    ] {
+        let argv = ["kanata-switcher", "--dry-run"].iter().chain(conflicting);
+        assert!(Args::try_parse_from(argv).is_err(), "{:?}", conflicting);
+    }
+}
+
+#[test]
+fn test_sync_autostart_is_not_passed_through() {
+    let matches =
+        Args::command().get_matches_from(["kanata-switcher", "--sync-autostart", "-q"]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    assert!(args.sync_autostart);
+    assert_eq!(autostart_passthrough_args(&matches, &args), vec!["-q".to_string()]);
+}
+
+#[test]
+fn test_uninstall_targets() {
+    let targets = UninstallTargets::new(
+        Path::new("/home/user/.config"),
+        Path::new("/home/user/.local/share"),
+        Path::new("/home/user/.local/state"),
+    );
+    assert_eq!(
+        targets.systemd_unit,
+        PathBuf::from("/home/user/.config/systemd/user/kanata-switcher.service")
+    );
+    assert_eq!(
+        targets.autostart_entry,
+        PathBuf::from("/home/user/.config/autostart/kanata-switcher.desktop")
+    );
+    assert_eq!(
+        targets.gnome_extension,
+        PathBuf::from("/home/user/.local/share/gnome-shell/extensions/kanata-switcher@7mind.io")
+    );
+    assert_eq!(
+        targets.desktop_entry,
+        desktop_app_path(Path::new("/home/user/.local/share"))
+    );
+    assert_eq!(
+        targets.state_dir,
+        PathBuf::from("/home/user/.local/state/kanata-switcher")
+    );
+}
+
+#[test]
+fn test_kwin_script_leftovers_only_matches_own_uid() {
+    let dir = tempfile::tempdir().unwrap();
+    for name in [
+        "kanata-switcher-kwin-1000.js",
+        "kanata-switcher-kwin-1000-work.js",
+        "kanata-switcher-kwin-query-1000-7.js",
+        "kanata-switcher-kwin-1001.js",
+        "kanata-switcher-kwin-1001-work.js",
+        "kanata-switcher-kwin-query-1001-7.js",
+        "kanata-switcher-kwin-10000.js",
+        "unrelated.js",
+    ] {
+        std::fs::write(dir.path().join(name), "").unwrap();
+    }
+    assert_eq!(
+        kwin_script_leftovers(dir.path(), 1000),
+        vec![
+            dir.path().join("kanata-switcher-kwin-1000-work.js"),
+            dir.path().join("kanata-switcher-kwin-1000.js"),
+            dir.path().join("kanata-switcher-kwin-query-1000-7.js"),
+        ]
+    );
 }
 
-fn arb_vk_action() -> impl Strategy<Value = String> {
-    prop_oneof![
-        Just("Press".to_string()),
-        Just("Release".to_string()),
-        Just("Tap".to_string()),
-        Just("Toggle".to_string()),
-    ]
+#[test]
+fn test_uninstall_conflicts_with_install() {
+    assert!(Args::try_parse_from(["kanata-switcher", "--uninstall", "--install-autostart"]).is_err());
 }
 
-fn arb_rule() -> impl Strategy<Value = Rule> {
-    (
-        prop::option::of(arb_class()),
-        prop::option::of(arb_title()),
-        prop::option::of(arb_layer()),
-        prop::option::of(arb_vk_name()),
-        prop::option::of(prop::collection::vec(
-            (arb_vk_name(), arb_vk_action()),
-            0..3,
-        )),
-        any::<bool>(),
+#[test]
+fn test_parse_cert_fingerprint_formats() {
+    let expected = parse_cert_fingerprint(
+        "c0cc5a7479e51416df8896efd55b7e0bac6940025ff6fa3ed840fd1307a004d3",
     )
-        .prop_map(|(class, title, layer, vk, raw_vk, fallthrough)| Rule {
-            class,
-            title,
-            on_native_terminal: None,
-            layer,
-            virtual_key: vk,
-            raw_vk_action: raw_vk,
-            fallthrough,
-        })
+    .unwrap();
+    for value in [
+        "C0:CC:5A:74:79:E5:14:16:DF:88:96:EF:D5:5B:7E:0B:AC:69:40:02:5F:F6:FA:3E:D8:40:FD:13:07:A0:04:D3",
+        "sha256 Fingerprint=C0:CC:5A:74:79:E5:14:16:DF:88:96:EF:D5:5B:7E:0B:AC:69:40:02:5F:F6:FA:3E:D8:40:FD:13:07:A0:04:D3",
+        "sha256:c0cc5a7479e51416df8896efd55b7e0bac6940025ff6fa3ed840fd1307a004d3",
+    ] {
+        assert_eq!(parse_cert_fingerprint(value).unwrap(), expected, "{}", value);
+    }
+    assert_eq!(expected[0], 0xC0);
+    assert_eq!(
+        format_cert_fingerprint(&expected),
+        "C0:CC:5A:74:79:E5:14:16:DF:88:96:EF:D5:5B:7E:0B:AC:69:40:02:5F:F6:FA:3E:D8:40:FD:13:07:A0:04:D3"
+    );
 }
 
-fn arb_window() -> impl Strategy<Value = WindowInfo> {
-    (arb_class(), arb_title()).prop_map(|(class, title)| WindowInfo {
-        class,
-        title,
-        is_native_terminal: false,
-    })
+#[test]
+fn test_parse_cert_fingerprint_rejects_invalid() {
+    assert!(parse_cert_fingerprint("C0:CC").is_err());
+    assert!(parse_cert_fingerprint(&"zz".repeat(32)).is_err());
+    assert!(Args::try_parse_from(["kanata-switcher", "--kanata-tls-pin", "nope"]).is_err());
 }
 
-proptest! {
-    #[test]
-    fn prop_managed_vks_consistent(
-        rules in prop::collection::vec(arb_rule(), 1..5),
-        windows in prop::collection::vec(arb_window(), 1..10),
-    ) {
-        let mut handler = FocusHandler::new(rules, None, true);
+#[test]
+fn test_autostart_passthrough_args_kanata_tls() {
+    let pin = "sha256:c0cc5a7479e51416df8896efd55b7e0bac6940025ff6fa3ed840fd1307a004d3";
+    let matches = Args::command().get_matches_from([
+        "kanata-switcher",
+        "--install-autostart",
+        "--kanata-tls-pin",
+        pin,
+        "--kanata-tls-server-name",
+        "thin-client",
+    ]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    assert_eq!(
+        autostart_passthrough_args(&matches, &args),
+        vec![
+            "--kanata-tls-pin".to_string(),
+            "C0:CC:5A:74:79:E5:14:16:DF:88:96:EF:D5:5B:7E:0B:AC:69:40:02:5F:F6:FA:3E:D8:40:FD:13:07:A0:04:D3"
+                .to_string(),
+            "--kanata-tls-server-name".to_string(),
+            "thin-client".to_string(),
+        ]
+    );
+}
 
-        for win in &windows {
-            let _ = handler.handle(win, "default");
-            // Just verify the handler state is consistent (Vec is always valid)
-            prop_assert!(handler.current_virtual_keys.len() <= 10); // sanity bound
-        }
-    }
+#[test]
+fn test_kanata_tls_from_args() {
+    let args = Args::try_parse_from(["kanata-switcher"]).unwrap();
+    assert!(KanataTls::from_args(&args).unwrap().is_none());
 
-    #[test]
-    fn prop_releases_before_presses(
-        rules in prop::collection::vec(arb_rule(), 1..5),
-        windows in prop::collection::vec(arb_window(), 2..10),
-    ) {
-        let mut handler = FocusHandler::new(rules, None, true);
+    let args =
+        Args::try_parse_from(["kanata-switcher", "--kanata-tls-server-name", "thin-client"]).unwrap();
+    assert!(KanataTls::from_args(&args).is_err());
 
-        for win in &windows {
-            if let Some(actions) = handler.handle(win, "default") {
-                // All ReleaseVk actions should come before any PressVk actions
-                let first_press_idx = actions.actions.iter().position(|a| matches!(a, FocusAction::PressVk(_)));
-                let last_release_idx = actions.actions.iter().rposition(|a| matches!(a, FocusAction::ReleaseVk(_)));
+    let args = Args::try_parse_from([
+        "kanata-switcher",
+        "--kanata-tls-ca",
+        "/nonexistent/ca.pem",
+    ])
+    .unwrap();
+    assert!(KanataTls::from_args(&args).is_err());
+}
 
-                if let (Some(press_idx), Some(release_idx)) = (first_press_idx, last_release_idx) {
-                    prop_assert!(release_idx < press_idx, "All releases should come before presses");
-                }
-            }
-        }
-    }
+#[test]
+fn test_config_errors_are_returned_not_exited() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing.json");
+    let error = load_config(Some(&missing)).unwrap_err();
+    assert!(error.contains("missing.json"), "{}", error);
 
-    #[test]
-    fn prop_unfocus_releases_all_vks(
-        rules in prop::collection::vec(arb_rule(), 1..5),
-        win in arb_window(),
-    ) {
-        let mut handler = FocusHandler::new(rules, None, true);
+    let invalid = dir.path().join("invalid.json");
+    std::fs::write(&invalid, r#"[{"default": "a"}, {"default": "b"}]"#).unwrap();
+    assert!(load_config(Some(&invalid)).is_err());
 
-        // Focus a window first
-        let _ = handler.handle(&win, "default");
-        let vks_before = handler.current_virtual_keys.clone();
+    assert_eq!(
+        DaemonError::Config(error.clone()).to_string(),
+        format!("Config error: {}", error)
+    );
+    let propagated: DaemonError = std::io::Error::other("bus gone").into();
+    assert!(matches!(propagated, DaemonError::Other(_)));
+    assert_eq!(propagated.to_string(), "bus gone");
+    let status = GnomeExtensionStatus {
+        installed: false,
+        enabled: false,
+        active: false,
+        state: None,
+        method: GnomeDetectionMethod::Cli,
+    };
+    assert!(matches!(
+        ensure_gnome_extension(&status, false),
+        Err(DaemonError::GnomeExtension(_))
+    ));
+}
 
-        // Unfocus (empty class and title)
-        let actions = handler.handle(
-            &WindowInfo {
-                class: String::new(),
-                title: String::new(),
-                is_native_terminal: false,
-            },
-            "default",
-        );
+fn load_config_json(json: &str) -> Config {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kanata-switcher.json");
+    std::fs::write(&path, json).unwrap();
+    load_config(Some(&path)).unwrap()
+}
 
-        // All previously active VKs must be released
-        if !vks_before.is_empty() {
-            prop_assert!(actions.is_some());
-            let actions = actions.unwrap();
-            for old_vk in &vks_before {
-                prop_assert!(has_action(&actions, &FocusAction::ReleaseVk(old_vk.clone())));
-            }
-        }
-        // After unfocus, no VKs should be active
-        prop_assert!(handler.current_virtual_keys.is_empty());
+const KANATA_ENDPOINTS_CONFIG: &str = r#"[
+    {"default": "base"},
+    {"kanata": {
+        "desk": {"host": "desk.lan", "port": 10001, "default_layer": "qwerty", "reconnect_delays_ms": [200, 500]},
+        "local": {}
+    }},
+    {"class": "firefox", "layer": "browser"}
+]"#;
+
+#[test]
+fn test_config_parses_kanata_endpoints() {
+    let config = load_config_json(KANATA_ENDPOINTS_CONFIG);
+    assert_eq!(config.rules.len(), 1);
+    let desk = &config.kanata_endpoints["desk"];
+    assert_eq!(desk.host, "desk.lan");
+    assert_eq!(desk.port, 10001);
+    assert_eq!(desk.reconnect_delays_ms, Some(vec![200, 500]));
+    let local = &config.kanata_endpoints["local"];
+    assert_eq!((local.host.as_str(), local.port), ("127.0.0.1", 10000));
+    assert!(local.tls_pin.is_empty());
+}
+
+#[test]
+fn test_config_rejects_invalid_kanata_entries() {
+    for json in [
+        r#"[{"kanata": {"desk": {"hots": "desk.lan"}}}]"#,
+        r#"[{"kanata": {"desk": {"tls_pin": ["not-hex"]}}}]"#,
+        r#"[{"kanata": {}, "layer": "base"}]"#,
+    ] {
+        let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+        assert!(result.is_err(), "{}", json);
     }
+}
 
-    #[test]
-    fn prop_fallthrough_collects_all_raw_vk(
-        base_class in arb_nonempty_class(),
-        raw_vk1 in prop::collection::vec((arb_vk_name(), arb_vk_action()), 0..2),
-        raw_vk2 in prop::collection::vec((arb_vk_name(), arb_vk_action()), 0..2),
-    ) {
-        let rules = vec![
-            Rule {
-                class: Some(base_class.clone()),
-                title: None,
-                on_native_terminal: None,
-                layer: None,
-                virtual_key: None,
-                raw_vk_action: if raw_vk1.is_empty() { None } else { Some(raw_vk1.clone()) },
-                fallthrough: true,
-            },
-            Rule {
-                class: Some(base_class.clone()),
-                title: None,
-                on_native_terminal: None,
-                layer: None,
-                virtual_key: None,
-                raw_vk_action: if raw_vk2.is_empty() { None } else { Some(raw_vk2.clone()) },
-                fallthrough: false,
-            },
-        ];
+#[test]
+fn test_config_parses_layer_names() {
+    let config = load_config_json(
+        r#"[
+            {"layer_names": {"l_nav_v2": "Navigation", "base": "Base"}},
+            {"class": "firefox", "layer": "l_nav_v2"}
+        ]"#,
+    );
+    assert_eq!(config.rules.len(), 1);
+    let names = LayerDisplayNames::new(config.layer_display_names);
+    assert_eq!(names.display("l_nav_v2"), "Navigation");
+    assert_eq!(names.display("base"), "Base");
+    assert_eq!(names.display("browser"), "browser");
+}
 
-        let mut handler = FocusHandler::new(rules, None, true);
-        let win = WindowInfo {
-            class: base_class,
-            title: String::new(),
-            is_native_terminal: false,
-        };
+#[test]
+fn test_config_rejects_invalid_layer_names_entries() {
+    for json in [
+        r#"[{"layer_names": ["Navigation"]}]"#,
+        r#"[{"layer_names": {"nav": 1}}]"#,
+        r#"[{"layer_names": {}, "layer": "base"}]"#,
+    ] {
+        let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+        assert!(result.is_err(), "{}", json);
+    }
+}
 
-        if let Some(actions) = handler.handle(&win, "default") {
-            let expected: Vec<_> = raw_vk1.into_iter().chain(raw_vk2).collect();
-            prop_assert_eq!(get_raw_vk_actions(&actions), expected);
-        }
+#[test]
+fn test_config_parses_announce() {
+    let config = load_config_json(
+        r#"[
+            {"announce": {"layers": {"l_nav": "Navigation mode", "tty": ""}, "min_interval_ms": 250}},
+            {"class": "firefox", "layer": "l_nav"}
+        ]"#,
+    );
+    let announce = config.announce.unwrap();
+    assert_eq!(announce.min_interval_ms, 250);
+    assert_eq!(announce.command, default_announce_command());
+
+    let names = LayerDisplayNames::new(BTreeMap::from([("base".to_string(), "Base".to_string())]));
+    assert_eq!(announce.text_for("l_nav", &names).as_deref(), Some("Navigation mode"));
+    assert_eq!(announce.text_for("tty", &names), None);
+    assert_eq!(announce.text_for("base", &names).as_deref(), Some("Base"));
+    assert_eq!(announce.text_for("vim", &names).as_deref(), Some("vim"));
+
+    // Announcements are opt-in
+    let config = load_config_json(r#"[{"class": "firefox", "layer": "browser"}]"#);
+    assert!(config.announce.is_none());
+}
+
+#[test]
+fn test_config_rejects_invalid_announce_entries() {
+    for json in [
+        r#"[{"announce": {"layer": {}}}]"#,
+        r#"[{"announce": {"min_interval_ms": -1}}]"#,
+        r#"[{"announce": {}, "class": "firefox"}]"#,
+    ] {
+        let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+        assert!(result.is_err(), "{}", json);
     }
+}
 
-    #[test]
-    fn prop_fallthrough_collects_all_layers(
-        base_class in arb_nonempty_class(),
-        layer1 in arb_layer(),
-        layer2 in arb_layer(),
-    ) {
-        let rules = vec![
-            Rule {
-                class: Some(base_class.clone()),
-                title: None,
-                on_native_terminal: None,
-                layer: Some(layer1.clone()),
-                virtual_key: None,
-                raw_vk_action: None,
-                fallthrough: true,
-            },
-            Rule {
-                class: Some(base_class.clone()),
-                title: None,
-                on_native_terminal: None,
-                layer: Some(layer2.clone()),
-                virtual_key: None,
-                raw_vk_action: None,
-                fallthrough: false,
-            },
-        ];
+#[test]
+fn test_config_parses_notifications() {
+    let config = load_config_json(
+        r#"[
+            {"notifications": {"min_interval_ms": 500}},
+            {"class": "kitty", "layer": "terminal", "notify": false},
+            {"on_native_terminal": "tty", "notify": false}
+        ]"#,
+    );
+    assert_eq!(
+        config.notifications,
+        Some(NotificationConfig {
+            min_interval_ms: 500,
+            timeout_ms: default_notification_timeout_ms(),
+        })
+    );
+    assert_eq!(config.rules[0].notify, Some(false));
+    assert_eq!(config.rules[0].summary(), r#"class="kitty" -> layer=terminal notify=false"#);
+    assert!(!config.native_terminal_rule.unwrap().notify);
 
-        let mut handler = FocusHandler::new(rules, None, true);
-        let win = WindowInfo {
-            class: base_class,
-            title: String::new(),
-            is_native_terminal: false,
-        };
+    let config = load_config_json(
+        r#"[{"notifications": {}}, {"on_native_terminal": "tty"}, {"class": "a", "layer": "b"}]"#,
+    );
+    assert_eq!(config.notifications, Some(NotificationConfig::default()));
+    assert!(config.native_terminal_rule.unwrap().notify);
+    assert_eq!(config.rules[0].notify, None);
+
+    for json in [
+        r#"[{"notifications": {"interval": 5}}]"#,
+        r#"[{"notifications": {"timeout_ms": -1}}]"#,
+        r#"[{"notifications": {}, "class": "firefox"}]"#,
+        r#"[{"class": "firefox", "layer": "browser", "notify": "no"}]"#,
+    ] {
+        let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+        assert!(result.is_err(), "{}", json);
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.json");
+    std::fs::write(&path, r#"[{"notifications": {}}, {"notifications": {}}]"#).unwrap();
+    assert_eq!(
+        parse_config(&path).unwrap_err(),
+        "entry #2: multiple 'notifications' entries found, only one allowed"
+    );
+}
+
+#[test]
+fn test_notify_false_rules_mark_focus_actions() {
+    let rules = vec![
+        Rule {
+            class: Some("kitty".to_string()),
+            layer: Some("terminal".to_string()),
+            notify: Some(false),
+            fallthrough: true,
+            ..Default::default()
+        },
+        Rule {
+            class: Some("kitty".to_string()),
+            title: Some("vim".to_string()),
+            layer: Some("vim".to_string()),
+            ..Default::default()
+        },
+    ];
+    let native_terminal_rule = NativeTerminalRule {
+        layer: "tty".to_string(),
+        virtual_key: None,
+        raw_vk_action: Vec::new(),
+        silent: false,
+        notify: false,
+    };
+    let mut handler = FocusHandler::new(rules, Some(native_terminal_rule), true);
+    assert!(handler.handle(&win("kitty", "zsh"), "base").unwrap().quiet_notify);
+    // Another matching rule without the opt-out brings the notification back
+    assert!(!handler.handle(&win("kitty", "vim"), "base").unwrap().quiet_notify);
+    assert!(!handler.handle(&win("firefox", ""), "base").unwrap().quiet_notify);
+    assert!(handler.handle(&native_terminal_window(), "base").unwrap().quiet_notify);
+}
+
+#[tokio::test]
+async fn test_layer_notifier_coalesces_and_skips_opted_out_rules() {
+    let config = NotificationConfig {
+        min_interval_ms: 200,
+        timeout_ms: 0,
+    };
+    let names = LayerDisplayNames::new(BTreeMap::from([("base".to_string(), "Base".to_string())]));
+    let broadcaster = StatusBroadcaster::new();
+    let shown = Arc::new(Mutex::new(Vec::new()));
+    let shown_for_task = shown.clone();
+    let task = tokio::spawn(run_layer_notifier(
+        config,
+        names,
+        broadcaster.subscribe(),
+        broadcaster.subscribe_focus(),
+        move |summary| shown_for_task.lock().unwrap().push(summary),
+    ));
+    let settle = Duration::from_millis(400);
+
+    broadcaster.update_layer("base".to_string(), LayerSource::Focus);
+    tokio::time::sleep(settle).await;
+    // Fast alt-tab: only the layer active when the interval ends is shown
+    broadcaster.update_layer("vim".to_string(), LayerSource::Focus);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    broadcaster.update_layer("browser".to_string(), LayerSource::Focus);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    broadcaster.update_layer("terminal".to_string(), LayerSource::Focus);
+    tokio::time::sleep(settle).await;
+    broadcaster.publish_focus(FocusEvent {
+        quiet_notify: true,
+        ..Default::default()
+    });
+    broadcaster.update_focus_layer("tty".to_string());
+    tokio::time::sleep(settle).await;
+    broadcaster.update_layer("nav".to_string(), LayerSource::External);
+    tokio::time::sleep(settle).await;
+
+    assert_eq!(
+        *shown.lock().unwrap(),
+        vec![
+            "Layer: Base".to_string(),
+            "Layer: vim".to_string(),
+            "Layer: terminal".to_string(),
+            "Layer: nav".to_string()
+        ]
+    );
+    task.abort();
+}
 
-        if let Some(actions) = handler.handle(&win, "default") {
-            // Both layers should be collected
-            prop_assert_eq!(get_layers(&actions), vec![layer1, layer2]);
-        }
+#[test]
+fn test_config_parses_min_focus_ms() {
+    let config = load_config_json(
+        r#"[{"min_focus_ms": 250}, {"class": "firefox", "layer": "browser"}]"#,
+    );
+    assert_eq!(config.min_focus_ms, 250);
+    let config = load_config_json(r#"[{"class": "firefox", "layer": "browser"}]"#);
+    assert_eq!(config.min_focus_ms, 0);
+
+    for json in [
+        r#"[{"min_focus_ms": -5}]"#,
+        r#"[{"min_focus_ms": "250"}]"#,
+        r#"[{"debounce_ms": -5, "class": "firefox"}]"#,
+    ] {
+        let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+        assert!(result.is_err(), "{}", json);
     }
+}
 
-    #[test]
-    fn prop_all_matched_vks_pressed_and_held(
-        base_class in arb_nonempty_class(),
-        vk1 in arb_vk_name(),
-        vk2 in arb_vk_name(),
-    ) {
-        let rules = vec![
-            Rule {
-                class: Some(base_class.clone()),
-                title: None,
-                on_native_terminal: None,
-                layer: None,
-                virtual_key: Some(vk1.clone()),
-                raw_vk_action: None,
-                fallthrough: true,
-            },
-            Rule {
-                class: Some(base_class.clone()),
-                title: None,
-                on_native_terminal: None,
-                layer: None,
-                virtual_key: Some(vk2.clone()),
-                raw_vk_action: None,
-                fallthrough: false,
-            },
-        ];
+#[test]
+fn test_config_parses_global_debounce_ms() {
+    let config = load_config_json(
+        r#"[{"debounce_ms": 250}, {"class": "firefox", "layer": "browser"}]"#,
+    );
+    assert_eq!(config.min_focus_ms, 250);
 
-        let mut handler = FocusHandler::new(rules, None, true);
-        let win = WindowInfo {
-            class: base_class,
-            title: String::new(),
-            is_native_terminal: false,
-        };
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kanata-switcher.json");
+    std::fs::write(&path, r#"[{"debounce_ms": 250}, {"min_focus_ms": 100}]"#).unwrap();
+    assert_eq!(
+        parse_config(&path).unwrap_err(),
+        "entry #2: multiple 'debounce_ms' entries found, only one allowed"
+    );
+}
 
-        if let Some(actions) = handler.handle(&win, "default") {
-            // Both vk1 and vk2 should be pressed (all matched VKs are held)
-            prop_assert!(has_action(&actions, &FocusAction::PressVk(vk1.clone())));
-            prop_assert!(has_action(&actions, &FocusAction::PressVk(vk2.clone())));
-            // new_managed_vks should contain both
-            prop_assert_eq!(actions.new_managed_vks, vec![vk1, vk2]);
-        }
-    }
+#[test]
+fn test_focus_handler_focus_generation() {
+    let app = win("firefox", "");
+    let mut handler = FocusHandler::new(Vec::new(), None, true);
+    assert_eq!(handler.begin_focus_event(&app, ""), (Duration::ZERO, 1));
+
+    let mut handler = handler.with_min_focus(Duration::from_millis(200));
+    let (min_focus, first) = handler.begin_focus_event(&app, "");
+    assert_eq!(min_focus, Duration::from_millis(200));
+    assert!(handler.is_latest_focus_event(first));
+    let (_, second) = handler.begin_focus_event(&app, "");
+    assert!(!handler.is_latest_focus_event(first));
+    assert!(handler.is_latest_focus_event(second));
 }
 
-// === GNOME Extension State Parsing Tests ===
+#[test]
+fn test_rule_debounce_overrides_global_dwell() {
+    let config = load_config_json(
+        r#"[
+            {"debounce_ms": 300},
+            {"class": "^kitty$", "layer": "terminal", "debounce_ms": 0},
+            {"class": "firefox", "layer": "browser", "debounce_ms": 800, "fallthrough": true},
+            {"class": "firefox", "virtual_key": "vk_web", "debounce_ms": 500}
+        ]"#,
+    );
+    assert_eq!(config.min_focus_ms, 300);
+    assert_eq!(config.rules[0].debounce_ms, Some(0));
+    let mut handler = FocusHandler::new(config.rules, None, true)
+        .with_min_focus(Duration::from_millis(config.min_focus_ms));
+
+    assert_eq!(handler.begin_focus_event(&win("kitty", ""), "").0, Duration::ZERO);
+    // The longest dwell of all matched rules
+    assert_eq!(handler.begin_focus_event(&win("firefox", ""), "").0, Duration::from_millis(800));
+    // Windows no rule with a dwell time matches use the global one
+    assert_eq!(handler.begin_focus_event(&win("code", ""), "").0, Duration::from_millis(300));
+}
 
 #[test]
-fn test_gnome_extension_state_enabled_f64() {
-    // GNOME Shell returns state as f64, 1.0 = ENABLED
-    use zbus::zvariant::{OwnedValue, Value};
+fn test_rule_debounce_matches_once_per_event() {
+    let rules = vec![Rule {
+        class: Some("firefox".to_string()),
+        layer: Some("browser".to_string()),
+        debounce_ms: Some(100),
+        ..Default::default()
+    }];
+    let mut handler = FocusHandler::new(rules, None, true);
+    let firefox = win("firefox", "");
 
-    let mut body = HashMap::new();
-    body.insert(
-        "state".to_string(),
-        OwnedValue::try_from(Value::F64(1.0)).unwrap(),
+    handler.begin_focus_event(&firefox, "");
+    assert_eq!(
+        handler.matched_ahead,
+        Some((firefox.clone(), vec![(0, Some("browser".to_string()))]))
     );
+    // The match from the dwell time is applied instead of matching again
+    let actions = handler.handle(&firefox, "").unwrap();
+    assert!(has_action(&actions, &FocusAction::ChangeLayer("browser".to_string())));
+    assert_eq!(handler.matched_ahead, None);
+
+    // A match for another window isn't reused
+    handler.begin_focus_event(&firefox, "");
+    handler.handle(&win("code", ""), "");
+    assert!(handler.last_matched_rules().is_empty());
+}
 
-    let status = parse_gnome_extension_state(&body);
-    assert!(status.active, "state=1.0 should be active");
-    assert!(status.enabled, "state=1.0 should be enabled");
-    assert!(status.installed, "D-Bus response means installed");
+#[test]
+fn test_config_rejects_debounce_on_native_terminal_rule() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kanata-switcher.json");
+    std::fs::write(&path, r#"[{"on_native_terminal": "tty", "debounce_ms": 100}]"#).unwrap();
+    assert_eq!(
+        parse_config(&path).unwrap_err(),
+        "entry #1: 'on_native_terminal' cannot be combined with 'debounce_ms' (it is never delayed)"
+    );
 }
 
 #[test]
-fn test_gnome_extension_state_disabled_f64() {
-    // State 2.0 = DISABLED
-    use zbus::zvariant::{OwnedValue, Value};
+fn test_focus_handler_unfocus_grace_delay() {
+    let app = win("firefox", "");
+    let unfocused = WindowInfo::default();
+    let mut handler =
+        FocusHandler::new(Vec::new(), None, true).with_unfocus_grace(Duration::from_millis(150));
+    assert_eq!(handler.begin_focus_event(&app, "").0, Duration::ZERO);
+    assert_eq!(handler.begin_focus_event(&unfocused, "").0, Duration::from_millis(150));
+    assert_eq!(handler.begin_focus_event(&native_terminal_window(), "").0, Duration::ZERO);
+
+    // The longer of dwell and grace applies to unfocus
+    let mut handler = handler.with_min_focus(Duration::from_millis(300));
+    assert_eq!(handler.begin_focus_event(&unfocused, "").0, Duration::from_millis(300));
+    assert_eq!(handler.begin_focus_event(&native_terminal_window(), "").0, Duration::ZERO);
+}
 
-    let mut body = HashMap::new();
-    body.insert(
-        "state".to_string(),
-        OwnedValue::try_from(Value::F64(2.0)).unwrap(),
+#[test]
+fn test_config_parses_unfocus_grace_ms() {
+    let config = load_config_json(
+        r#"[{"unfocus_grace_ms": 120}, {"class": "firefox", "layer": "browser"}]"#,
     );
-
-    let status = parse_gnome_extension_state(&body);
-    assert!(!status.active, "state=2.0 should not be active");
-    assert!(!status.enabled, "state=2.0 should not be enabled");
+    assert_eq!(config.unfocus_grace_ms, 120);
+    let config = load_config_json(r#"[{"class": "firefox", "layer": "browser"}]"#);
+    assert_eq!(config.unfocus_grace_ms, 0);
+
+    for json in [
+        r#"[{"unfocus_grace_ms": -1}]"#,
+        r#"[{"unfocus_grace_ms": 100, "layer": "base"}]"#,
+    ] {
+        let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+        assert!(result.is_err(), "{}", json);
+    }
 }
 
 #[test]
-fn test_gnome_extension_state_missing() {
-    // No state field - should default to not enabled
-    let body = HashMap::new();
+fn test_config_parses_idle() {
+    let config = load_config_json(
+        r#"[{"on_idle": "sleep", "idle_timeout_s": 60}, {"class": "firefox", "layer": "browser"}]"#,
+    );
+    let idle = config.idle.unwrap();
+    assert_eq!(idle.on_idle, "sleep");
+    assert_eq!(idle.idle_timeout_s, 60);
+    let config = load_config_json(r#"[{"on_idle": "sleep"}]"#);
+    assert_eq!(config.idle.unwrap().idle_timeout_s, 300);
+    let config = load_config_json(r#"[{"class": "firefox", "layer": "browser"}]"#);
+    assert!(config.idle.is_none());
+
+    for json in [
+        r#"[{"idle_timeout_s": 60}]"#,
+        r#"[{"on_idle": "sleep", "idle_timeout_s": -1}]"#,
+        r#"[{"on_idle": "sleep", "layer": "base"}]"#,
+    ] {
+        let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+        assert!(result.is_err(), "{}", json);
+    }
 
-    let status = parse_gnome_extension_state(&body);
-    assert!(!status.active, "missing state should not be active");
-    assert!(!status.enabled, "missing state should not be enabled");
+    for (json, error) in [
+        (r#"[{"on_idle": ""}]"#, "entry #1: 'on_idle' must name a layer".to_string()),
+        (
+            r#"[{"on_idle": "sleep", "idle_timeout_s": 0}]"#,
+            format!("entry #1: 'idle_timeout_s' must be between 1 and {}", MAX_IDLE_TIMEOUT_S),
+        ),
+        (
+            r#"[{"on_idle": "sleep"}, {"on_idle": "away"}]"#,
+            "entry #2: multiple 'on_idle' entries found, only one allowed".to_string(),
+        ),
+    ] {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kanata-switcher.json");
+        std::fs::write(&path, json).unwrap();
+        assert_eq!(parse_config(&path).unwrap_err(), error);
+    }
+}
+
+#[test]
+fn test_x11_idle_change() {
+    assert_eq!(x11_idle_change(false, 999, 1000), None);
+    assert_eq!(x11_idle_change(false, 1000, 1000), Some(true));
+    assert_eq!(x11_idle_change(true, 5000, 1000), None);
+    assert_eq!(x11_idle_change(true, 20, 1000), Some(false));
 }
 
 #[tokio::test]
-async fn test_logind_monitor_startup_failure_is_non_fatal() {
-    with_test_timeout(async {
-        let handler = Arc::new(Mutex::new(FocusHandler::new(Vec::new(), None, true)));
-        let status_broadcaster = StatusBroadcaster::new();
-        let pause_broadcaster = PauseBroadcaster::new();
-        let kanata = KanataClient::new("127.0.0.1", 10000, None, true, status_broadcaster.clone());
+async fn test_handle_focus_event_skips_repeated_window() {
+    let rules = vec![Rule {
+        class: Some("^firefox$".to_string()),
+        layer: Some("browser".to_string()),
+        ..Default::default()
+    }];
+    let handler = Arc::new(Mutex::new(FocusHandler::new(rules.clone(), None, true)));
+    let status_broadcaster = StatusBroadcaster::new();
+    let pause_broadcaster = PauseBroadcaster::new();
+    let kanata = KanataClient::new("127.0.0.1", 1, None, true, status_broadcaster.clone());
+    let focus = |win: WindowInfo| {
+        let handler = handler.clone();
+        let status_broadcaster = status_broadcaster.clone();
+        let pause_broadcaster = pause_broadcaster.clone();
+        let kanata = kanata.clone();
+        async move {
+            handle_focus_event(
+                &handler,
+                &status_broadcaster,
+                &pause_broadcaster,
+                &win,
+                &kanata,
+                "base",
+            )
+            .await;
+        }
+    };
+    let hits = || handler.lock().unwrap().rule_stats()[0].hits;
+
+    focus(win("firefox", "Docs")).await;
+    focus(win("firefox", "Docs")).await;
+    assert_eq!(hits(), 1);
+    // A title change is a new event; going back to the first title is too
+    focus(win("firefox", "Mail")).await;
+    focus(win("firefox", "Docs")).await;
+    assert_eq!(hits(), 3);
+
+    // After a pause the same window applies again
+    handler.lock().unwrap().reset();
+    focus(win("firefox", "Docs")).await;
+    assert_eq!(hits(), 4);
+
+    *handler.lock().unwrap() = FocusHandler::new(rules, None, true).with_dedupe_focus(false);
+    focus(win("firefox", "Docs")).await;
+    focus(win("firefox", "Docs")).await;
+    assert_eq!(hits(), 2);
+}
 
-        let started = start_logind_session_monitor_best_effort(
-            Environment::Wayland,
-            None,
-            false,
-            handler,
-            status_broadcaster.clone(),
-            pause_broadcaster,
-            kanata,
-            |_env, _session, _is_kde6, _handler, _status, _pause, _kanata| async {
-                Err(std::io::Error::new(std::io::ErrorKind::Other, "logind unavailable").into())
-            },
-        )
-        .await;
+#[test]
+fn test_config_parses_dedupe_focus() {
+    let config = load_config_json(r#"[{"class": "firefox", "layer": "browser"}]"#);
+    assert!(config.dedupe_focus);
+    let config = load_config_json(
+        r#"[{"dedupe_focus": false}, {"class": "firefox", "layer": "browser"}]"#,
+    );
+    assert!(!config.dedupe_focus);
+
+    for json in [
+        r#"[{"dedupe_focus": "no"}]"#,
+        r#"[{"dedupe_focus": false, "layer": "base"}]"#,
+    ] {
+        let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+        assert!(result.is_err(), "{}", json);
+    }
+}
 
-        assert!(!started);
-    })
-    .await;
+#[test]
+fn test_per_window_state_refires_rules_for_another_window() {
+    let rules = vec![Rule {
+        on_focus_cmd: Some("notify".to_string()),
+        ..rule_raw_vk(Some("kitty"), vec![("vk_term", "Tap")])
+    }];
+    let window = |id: &str, title: &str| WindowInfo {
+        window_id: Some(id.to_string()),
+        ..win("kitty", title)
+    };
+    let tap = FocusAction::RawVkAction("vk_term".to_string(), "Tap".to_string());
+    let is_command = |action: &FocusAction| matches!(action, FocusAction::RunCommand(_));
+
+    // By default the rule fires once while it stays matched
+    let mut handler = FocusHandler::new(rules.clone(), None, true);
+    assert_eq!(handler.handle(&window("1", "a"), "base").unwrap().actions[0], tap);
+    assert!(handler.handle(&window("2", "b"), "base").is_none());
+
+    let mut handler = FocusHandler::new(rules, None, true).with_per_window_state(true);
+    handler.handle(&window("1", "a"), "base");
+    let actions = handler.handle(&window("2", "b"), "base").unwrap().actions;
+    assert_eq!(actions[0], tap);
+    assert_eq!(actions.iter().filter(|action| is_command(action)).count(), 1);
+    // A title change of the same window doesn't re-fire
+    assert!(handler.handle(&window("2", "c"), "base").is_none());
+    // Neither does a window the backend can't identify
+    assert!(handler.handle(&win("kitty", "d"), "base").is_none());
+    assert_eq!(handler.handle(&window("1", "a"), "base").unwrap().actions[0], tap);
+}
+
+#[test]
+fn test_config_parses_per_window_state() {
+    let config = load_config_json(r#"[{"class": "firefox", "layer": "browser"}]"#);
+    assert!(!config.per_window_state);
+    let config = load_config_json(
+        r#"[{"per_window_state": true}, {"class": "firefox", "layer": "browser"}]"#,
+    );
+    assert!(config.per_window_state);
+
+    for json in [
+        r#"[{"per_window_state": 1}]"#,
+        r#"[{"per_window_state": true, "layer": "base"}]"#,
+    ] {
+        let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+        assert!(result.is_err(), "{}", json);
+    }
 }
 
 #[tokio::test]
-async fn test_logind_monitor_startup_success_returns_true() {
-    with_test_timeout(async {
-        let handler = Arc::new(Mutex::new(FocusHandler::new(Vec::new(), None, true)));
-        let status_broadcaster = StatusBroadcaster::new();
-        let pause_broadcaster = PauseBroadcaster::new();
-        let kanata = KanataClient::new("127.0.0.1", 10000, None, true, status_broadcaster.clone());
+async fn test_layer_announcer_coalesces_and_honors_overrides() {
+    let config = AnnounceConfig {
+        layers: BTreeMap::from([
+            ("l_nav".to_string(), "Navigation mode".to_string()),
+            ("tty".to_string(), String::new()),
+        ]),
+        min_interval_ms: 200,
+        command: default_announce_command(),
+    };
+    let names = LayerDisplayNames::new(BTreeMap::from([("base".to_string(), "Base".to_string())]));
+    let broadcaster = StatusBroadcaster::new();
+    let spoken = Arc::new(Mutex::new(Vec::new()));
+    let spoken_for_task = spoken.clone();
+    let task = tokio::spawn(run_layer_announcer(
+        config,
+        names,
+        broadcaster.subscribe(),
+        broadcaster.subscribe_focus(),
+        move |text| spoken_for_task.lock().unwrap().push(text),
+    ));
+    let settle = Duration::from_millis(400);
+
+    broadcaster.update_layer("base".to_string(), LayerSource::Focus);
+    tokio::time::sleep(settle).await;
+    // VK changes on the same layer are not announced
+    broadcaster.update_focus_match(
+        vec!["vk_a".to_string()],
+        vec![RuleRef {
+            index: 0,
+            name: None,
+        }],
+    );
+    broadcaster.update_layer("vim".to_string(), LayerSource::Focus);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    // Changes within the interval after "vim" collapse into the last one
+    broadcaster.update_layer("browser".to_string(), LayerSource::Focus);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    broadcaster.update_layer("l_nav".to_string(), LayerSource::Focus);
+    tokio::time::sleep(settle).await;
+    broadcaster.update_layer("tty".to_string(), LayerSource::External);
+    tokio::time::sleep(settle).await;
+    // Switches by silent rules only are not announced
+    broadcaster.publish_focus(FocusEvent {
+        silent: true,
+        ..Default::default()
+    });
+    broadcaster.update_focus_layer("terminal".to_string());
+    tokio::time::sleep(settle).await;
+    broadcaster.update_layer("terminal2".to_string(), LayerSource::External);
+    tokio::time::sleep(settle).await;
 
-        let started = start_logind_session_monitor_best_effort(
-            Environment::Wayland,
-            None,
-            false,
-            handler,
-            status_broadcaster.clone(),
-            pause_broadcaster,
-            kanata,
-            |_env, _session, _is_kde6, _handler, _status, _pause, _kanata| async { Ok(()) },
-        )
-        .await;
+    assert_eq!(
+        *spoken.lock().unwrap(),
+        vec![
+            "Base".to_string(),
+            "vim".to_string(),
+            "Navigation mode".to_string(),
+            "terminal2".to_string()
+        ]
+    );
+    task.abort();
+}
 
-        assert!(started);
-    })
-    .await;
+#[test]
+fn test_resolve_kanata_target_named_endpoint() {
+    let config = load_config_json(KANATA_ENDPOINTS_CONFIG);
+
+    let args = Args::try_parse_from(["kanata-switcher", "--kanata", "desk"]).unwrap();
+    let target = resolve_kanata_target(&args, &config).unwrap();
+    assert_eq!((target.host.as_str(), target.port), ("desk.lan", 10001));
+    assert_eq!(target.default_layer.as_deref(), Some("qwerty"));
+    assert_eq!(target.reconnect_delays_ms, Some(vec![200, 500]));
+    assert!(target.tls.is_none());
+
+    // Endpoints without their own default layer keep the config's default entry
+    let args = Args::try_parse_from(["kanata-switcher", "--kanata", "local"]).unwrap();
+    let target = resolve_kanata_target(&args, &config).unwrap();
+    assert_eq!(target.default_layer.as_deref(), Some("base"));
+
+    let args = Args::try_parse_from(["kanata-switcher", "-H", "10.0.0.2"]).unwrap();
+    let target = resolve_kanata_target(&args, &config).unwrap();
+    assert_eq!((target.host.as_str(), target.port), ("10.0.0.2", 10000));
+    assert_eq!(target.reconnect_delays_ms, None);
 }
 
-#[tokio::test]
-async fn test_wait_for_restart_or_shutdown_returns_restart() {
-    with_test_timeout(async {
-        let restart_handle = RestartHandle::new();
-        let shutdown_handle = ShutdownHandle::new();
+#[test]
+fn test_resolve_kanata_target_unknown_endpoint() {
+    let config = load_config_json(KANATA_ENDPOINTS_CONFIG);
+    let args = Args::try_parse_from(["kanata-switcher", "--kanata", "laptop"]).unwrap();
+    let error = resolve_kanata_target(&args, &config).err().unwrap();
+    assert_eq!(
+        error.to_string(),
+        "unknown kanata endpoint \"laptop\" (configured: desk, local)"
+    );
+}
 
-        let wait_future = wait_for_restart_or_shutdown(&restart_handle, &shutdown_handle);
-        let trigger_future = async {
-            tokio::task::yield_now().await;
-            restart_handle.request();
-        };
-        let (outcome, _) = tokio::join!(wait_future, trigger_future);
-        assert_eq!(outcome, RunOutcome::Restart);
-    })
-    .await;
+const RULE_ENDPOINT_CONFIG: &str = r#"[
+    {"default": "base"},
+    {"kanata": {"desk": {"host": "desk.lan", "default_layer": "qwerty"}}},
+    {"class": "firefox", "layer": "browser"},
+    {"class": "firefox", "layer": "nav", "virtual_key": "vk_ff", "kanata": "desk"}
+]"#;
+
+#[test]
+fn test_config_rule_kanata_endpoint() {
+    let config = load_config_json(RULE_ENDPOINT_CONFIG);
+    assert_eq!(config.rules[1].kanata.as_deref(), Some("desk"));
+    assert_eq!(
+        config.rules[1].summary(),
+        "class=\"firefox\" -> layer=nav virtual_key=vk_ff kanata=desk"
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kanata-switcher.json");
+    for (json, expected) in [
+        (
+            r#"[{"kanata": {"desk": {}}}, {"class": "x", "layer": "a", "kanata": "laptop"}]"#,
+            "rule #1: unknown kanata endpoint \"laptop\" (configured: desk)",
+        ),
+        (
+            r#"[{"class": "x", "layer": "a", "kanata": "desk"}]"#,
+            "rule #1: unknown kanata endpoint \"desk\": the config has no \"kanata\" entry",
+        ),
+        (
+            r#"[{"kanata": {"desk": {}}}, {"on_native_terminal": "tty", "kanata": "desk"}]"#,
+            "entry #2: 'on_native_terminal' cannot be combined with 'kanata' (it applies to the daemon's own kanata instance)",
+        ),
+    ] {
+        std::fs::write(&path, json).unwrap();
+        assert_eq!(parse_config(&path).err().as_deref(), Some(expected), "{}", json);
+    }
 }
 
-#[tokio::test]
-async fn test_wait_for_restart_or_shutdown_returns_exit() {
-    with_test_timeout(async {
-        let restart_handle = RestartHandle::new();
-        let shutdown_handle = ShutdownHandle::new();
+#[test]
+fn test_focus_handler_routes_rules_to_endpoints() {
+    let config = load_config_json(RULE_ENDPOINT_CONFIG);
+    let mut handler = FocusHandler::from_config(&config, true, false);
+    assert_eq!(handler.target_endpoints(), vec!["desk".to_string()]);
+    let firefox = WindowInfo {
+        class: "firefox".to_string(),
+        ..Default::default()
+    };
+    let kitty = WindowInfo {
+        class: "kitty".to_string(),
+        ..Default::default()
+    };
 
-        shutdown_handle.request();
+    let actions = handler.handle(&firefox, "base").unwrap();
+    assert_eq!(actions.actions, vec![FocusAction::ChangeLayer("browser".to_string())]);
+    assert_eq!(
+        actions.targeted,
+        vec![(
+            "desk".to_string(),
+            vec![
+                FocusAction::ChangeLayer("nav".to_string()),
+                FocusAction::PressVk("vk_ff".to_string()),
+            ]
+        )]
+    );
+    // The daemon's own instance holds no virtual key: vk_ff is pressed on desk
+    assert!(handler.current_virtual_keys().is_empty());
+    assert_eq!(handler.last_matched_rules(), vec![0]);
 
-        let outcome = wait_for_restart_or_shutdown(&restart_handle, &shutdown_handle).await;
-        assert_eq!(outcome, RunOutcome::Exit);
-    })
-    .await;
+    let actions = handler.handle(&kitty, "base").unwrap();
+    assert_eq!(actions.actions, vec![FocusAction::ChangeLayer("base".to_string())]);
+    assert_eq!(
+        actions.targeted,
+        vec![(
+            "desk".to_string(),
+            vec![
+                FocusAction::ReleaseVk("vk_ff".to_string()),
+                FocusAction::ChangeLayer("qwerty".to_string()),
+            ]
+        )]
+    );
+
+    handler.handle(&firefox, "base").unwrap();
+    let hits: Vec<u64> = handler.rule_stats().iter().map(|stat| stat.hits).collect();
+    assert_eq!(hits, vec![2, 2]);
+    // Pausing releases the endpoint's keys and switches it back to its default layer
+    assert_eq!(
+        handler.release_targets(),
+        vec![(
+            "desk".to_string(),
+            vec![
+                FocusAction::ReleaseVk("vk_ff".to_string()),
+                FocusAction::ChangeLayer("qwerty".to_string()),
+            ]
+        )]
+    );
+    assert!(handler.release_targets().is_empty());
 }
 
-#[tokio::test]
-async fn test_wait_for_restart_or_shutdown_shutdown_wins() {
-    with_test_timeout(async {
-        let restart_handle = RestartHandle::new();
-        let shutdown_handle = ShutdownHandle::new();
+#[test]
+fn test_focus_handler_own_endpoint_keeps_its_rules() {
+    let config = load_config_json(RULE_ENDPOINT_CONFIG);
+    // Started with `--kanata desk`: the desk rule is an ordinary one, after the first rule
+    let mut handler =
+        FocusHandler::from_config(&config, true, false).with_endpoint(Some("desk".to_string()));
+    assert!(handler.target_endpoints().is_empty());
+    let firefox = WindowInfo {
+        class: "firefox".to_string(),
+        ..Default::default()
+    };
+    let actions = handler.handle(&firefox, "qwerty").unwrap();
+    assert_eq!(actions.actions, vec![FocusAction::ChangeLayer("browser".to_string())]);
+    assert!(actions.targeted.is_empty());
+}
 
-        shutdown_handle.request();
-        restart_handle.request();
+#[test]
+fn test_config_dump() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kanata-switcher.yaml");
+    std::fs::write(
+        &path,
+        r#"
+- default: base
+- kanata:
+    desk: {host: desk.lan, port: 10001, default_layer: qwerty, reconnect_delays_ms: [200, 500]}
+- unfocus_grace_ms: 150
+- on_native_terminal: tty
+  virtual_key: vk_tty
+- class: firefox
+  layer: browser
+  raw_vk_action: [[vk_notify, Tap], [delay, 50]]
+  fallthrough: true
+"#,
+    )
+    .unwrap();
+    let config = parse_config(&path).unwrap();
+    let args = Args::try_parse_from(["kanata-switcher", "--kanata", "desk"]).unwrap();
+    let target = resolve_kanata_target(&args, &config).unwrap();
+    let dump = config_dump(&path, &config, Some("desk"), &target);
 
-        let outcome = wait_for_restart_or_shutdown(&restart_handle, &shutdown_handle).await;
-        assert_eq!(outcome, RunOutcome::Exit);
-    })
-    .await;
+    assert_eq!(dump["config_file"], path.to_str().unwrap());
+    assert_eq!(
+        dump["kanata"],
+        serde_json::json!({
+            "endpoint": "desk",
+            "host": "desk.lan",
+            "port": 10001,
+            "tls": false,
+            "reconnect_delays_ms": [200, 500],
+        })
+    );
+    let entries = dump["entries"].as_array().unwrap();
+    // The endpoint's default layer replaces the config's; unset globals show their defaults
+    assert_eq!(
+        entries[..9],
+        serde_json::json!([
+            {"version": 1},
+            {"default": "qwerty"},
+            {"debounce_ms": 0},
+            {"unfocus_grace_ms": 150},
+            {"dedupe_focus": true},
+            {"vt_monitor": true},
+            {"defer_during_grab": false},
+            {"per_window_state": false},
+            {"class_source": "app_id"},
+        ])
+        .as_array()
+        .unwrap()[..]
+    );
+    assert_eq!(
+        entries[10],
+        serde_json::json!({
+            "on_native_terminal": "tty",
+            "virtual_key": "vk_tty",
+            "raw_vk_action": [],
+            "silent": false,
+            "notify": true,
+        })
+    );
+    assert_eq!(
+        entries[11],
+        serde_json::json!({
+            "class": "firefox",
+            "layer": "browser",
+            "raw_vk_action": [["vk_notify", "Tap"], ["delay", 50]],
+            "reload_next": false,
+            "fallthrough": true,
+            "silent": false,
+        })
+    );
+
+    // The entries load back into the same config
+    let reloaded_path = dir.path().join("dump.json");
+    std::fs::write(&reloaded_path, dump["entries"].to_string()).unwrap();
+    let reloaded = parse_config(&reloaded_path).unwrap();
+    let target = resolve_kanata_target(&args, &reloaded).unwrap();
+    let redump = config_dump(&reloaded_path, &reloaded, Some("desk"), &target);
+    assert_eq!(redump["entries"], dump["entries"]);
+
+    assert!(
+        Args::try_parse_from(["kanata-switcher", "--dump-config", "--check-config"]).is_err()
+    );
+}
+
+#[test]
+fn test_kanata_endpoint_conflicts_with_host_and_passes_through() {
+    assert!(Args::try_parse_from(["kanata-switcher", "--kanata", "desk", "-H", "x"]).is_err());
+
+    let matches =
+        Args::command().get_matches_from(["kanata-switcher", "--install-autostart", "--kanata", "desk"]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    assert_eq!(
+        autostart_passthrough_args(&matches, &args),
+        vec!["--kanata".to_string(), "desk".to_string()]
+    );
 }
 
 #[test]
-fn test_logind_no_session_error_detection() {
-    use zbus::names::OwnedErrorName;
-    use zbus::{Error as ZbusError, Message};
+fn test_check_config_layers_reports_only_layers() {
+    let config = Config {
+        rules: vec![
+            rule(Some("firefox"), None, Some("browser")),
+            rule(Some("kitty"), None, Some("termnial")),
+            rule_vk(Some("firefox"), "vk_missing"),
+            // Another instance's layer
+            Rule {
+                kanata: Some("desk".to_string()),
+                ..rule(Some("blender"), None, Some("numpad"))
+            },
+        ],
+        default_layer: Some("base".to_string()),
+        native_terminal_rule: None,
+        session_inactive_rule: None,
+        locked_rule: None,
+        kanata_endpoints: BTreeMap::new(),
+        layer_display_names: BTreeMap::new(),
+        layer_virtual_keys: BTreeMap::new(),
+        announce: None,
+        notifications: None,
+        call_detection: None,
+        uinput_fallback: None,
+        min_focus_ms: 0,
+        unfocus_grace_ms: 0,
+        dedupe_focus: true,
+        vt_monitor: true,
+        defer_during_grab: false,
+        per_window_state: false,
+        plugins_dir: None,
+        rule_tests: Vec::new(),
+        idle: None,
+        tray_icons: BTreeMap::new(),
+        indicator: IndicatorTheme::default(),
+        class_source: ClassSource::AppId,
+    };
+    let layers = vec!["base".to_string(), "browser".to_string()];
 
-    let name = OwnedErrorName::try_from(LOGIND_ERROR_NO_SESSION_FOR_PID).unwrap();
-    let reply = Message::method_call("/org/freedesktop/login1", "GetSessionByPID")
-        .unwrap()
-        .build(&())
-        .unwrap();
-    let error = ZbusError::MethodError(name, Some("no session".to_string()), reply);
+    assert_eq!(
+        config_name_problems(&config, &layers, None),
+        vec!["rule #2: unknown layer \"termnial\"".to_string()]
+    );
+    let error = check_config_layers(&config, &layers).unwrap_err();
+    assert!(error.to_string().contains("1 unknown layer"), "{}", error);
 
-    assert!(is_logind_no_session_error(&error));
+    let layers = vec!["base".to_string(), "browser".to_string(), "termnial".to_string()];
+    assert!(check_config_layers(&config, &layers).is_ok());
 }
 
 #[test]
-fn test_logind_no_session_error_detection_false() {
-    use zbus::names::OwnedErrorName;
-    use zbus::{Error as ZbusError, Message};
+fn test_config_kanata_names_per_endpoint() {
+    let config = load_config_json(
+        r#"[
+            {"default": "base"},
+            {"on_idle": "sleep"},
+            {"kanata": {"desk": {"port": 10001}}},
+            {"class": "firefox", "layer": "browser", "virtual_key": "vk_ff"},
+            {"class": "kitty", "raw_vk_action": [["vk_term", "Tap"]], "fallthrough": true},
+            {"class": "blender", "layer": "numpad", "virtual_key": "vk_3d", "kanata": "desk"}
+        ]"#,
+    );
+    assert_eq!(
+        config_kanata_names(&config, None),
+        KanataConfigNames {
+            layers: vec!["base".to_string(), "browser".to_string(), "sleep".to_string()],
+            virtual_keys: vec!["vk_ff".to_string(), "vk_term".to_string()],
+        }
+    );
+    assert_eq!(
+        config_kanata_names(&config, Some("desk")),
+        KanataConfigNames {
+            layers: vec!["numpad".to_string()],
+            virtual_keys: vec!["vk_3d".to_string()],
+        }
+    );
+}
 
-    let name = OwnedErrorName::try_from("org.freedesktop.login1.NoSuchSession").unwrap();
-    let reply = Message::method_call("/org/freedesktop/login1", "GetSession")
-        .unwrap()
-        .build(&())
-        .unwrap();
-    let error = ZbusError::MethodError(name, Some("no session".to_string()), reply);
+#[test]
+fn test_kanata_sink_arg() {
+    let args = Args::try_parse_from(["kanata-switcher"]).unwrap();
+    assert_eq!(args.kanata_sink, KanataSink::Tcp);
+    let args = Args::try_parse_from(["kanata-switcher", "--kanata-sink", "log"]).unwrap();
+    assert_eq!(args.kanata_sink, KanataSink::Log);
+    for conflicting in [["-p", "10001"], ["--kanata", "desk"]] {
+        let mut argv = vec!["kanata-switcher", "--kanata-sink", "log"];
+        argv.extend(conflicting);
+        assert!(Args::try_parse_from(argv).is_err());
+    }
+}
 
-    assert!(!is_logind_no_session_error(&error));
+#[test]
+fn test_unknown_layer_policy_arg() {
+    let args = Args::try_parse_from(["kanata-switcher"]).unwrap();
+    assert_eq!(args.unknown_layer, UnknownLayerPolicy::Fallback);
+    assert!(Args::try_parse_from(["kanata-switcher", "--unknown-layer", "ignore"]).is_err());
+
+    let matches = Args::command().get_matches_from([
+        "kanata-switcher",
+        "--install-autostart",
+        "--unknown-layer",
+        "skip",
+    ]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    assert_eq!(
+        autostart_passthrough_args(&matches, &args),
+        vec!["--unknown-layer".to_string(), "skip".to_string()]
+    );
 }
 
 #[test]
-fn test_logind_empty_object_path_detection() {
-    let empty = OwnedObjectPath::try_from(LOGIND_EMPTY_OBJECT_PATH).unwrap();
-    let non_empty = OwnedObjectPath::try_from("/org/freedesktop/login1/session/_1").unwrap();
+fn test_harden_arg() {
+    let matches = Args::command().get_matches_from([
+        "kanata-switcher",
+        "--install-autostart",
+        "--harden",
+    ]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    assert!(args.harden);
+    assert_eq!(
+        autostart_passthrough_args(&matches, &args),
+        vec!["--harden".to_string()]
+    );
+    assert!(Args::try_parse_from(["kanata-switcher", "--harden", "--restart"]).is_err());
+}
 
-    assert!(is_logind_empty_object_path(&empty));
-    assert!(!is_logind_empty_object_path(&non_empty));
+#[test]
+fn test_harden_write_dirs() {
+    let dirs = harden_write_dirs(false);
+    assert!(dirs.contains(&env::temp_dir()));
+    assert!(dirs.contains(&PathBuf::from("/tmp")));
+    let unique: std::collections::HashSet<_> = dirs.iter().collect();
+    assert_eq!(unique.len(), dirs.len());
+    assert!(!dirs.iter().any(|dir| dir.ends_with("autostart")));
 }
 
 #[test]
-fn test_parse_logind_object_path_value() {
-    use zbus::zvariant::ObjectPath;
+fn test_landlock_write_access_by_abi() {
+    let v1 = landlock_write_access(1);
+    assert_eq!(v1 & LANDLOCK_ACCESS_FS_REFER, 0);
+    assert_eq!(v1 & LANDLOCK_ACCESS_FS_TRUNCATE, 0);
+    assert_ne!(v1 & LANDLOCK_ACCESS_FS_WRITE_FILE, 0);
+    assert_ne!(landlock_write_access(2) & LANDLOCK_ACCESS_FS_REFER, 0);
+    assert_eq!(
+        landlock_write_access(3) & LANDLOCK_FILE_ACCESS,
+        LANDLOCK_FILE_ACCESS
+    );
+}
 
-    let path = ObjectPath::try_from("/org/freedesktop/login1/session/_1").unwrap();
-    let value = OwnedValue::from(path);
-    let parsed = parse_logind_object_path(value, "test").unwrap();
+/// Runs a seccomp program the way the kernel would for the instructions it uses
+fn run_seccomp_filter(program: &[libc::sock_filter], arch: u32, nr: u32) -> u32 {
+    let mut accumulator = 0;
+    let mut pc = 0;
+    loop {
+        let insn = program[pc];
+        let code = insn.code as u32;
+        pc += 1;
+        if code == libc::BPF_LD | libc::BPF_W | libc::BPF_ABS {
+            accumulator = match insn.k {
+                SECCOMP_DATA_NR_OFFSET => nr,
+                SECCOMP_DATA_ARCH_OFFSET => arch,
+                offset => panic!("unexpected load offset {}", offset),
+            };
+        } else if code == libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K {
+            pc += if accumulator == insn.k { insn.jt } else { insn.jf } as usize;
+        } else if code == libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K {
+            pc += if accumulator >= insn.k { insn.jt } else { insn.jf } as usize;
+        } else if code == libc::BPF_RET | libc::BPF_K {
+            return insn.k;
+        } else {
+            panic!("unexpected instruction {:#x}", code);
+        }
+    }
+}
 
-    assert_eq!(parsed.as_str(), "/org/freedesktop/login1/session/_1");
+#[test]
+fn test_seccomp_deny_filter() {
+    let arch = 0xC000_003E;
+    let program = seccomp_deny_filter(arch, &[101, 165], true);
+    let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+    assert_eq!(run_seccomp_filter(&program, arch, 0), libc::SECCOMP_RET_ALLOW);
+    assert_eq!(run_seccomp_filter(&program, arch, 102), libc::SECCOMP_RET_ALLOW);
+    assert_eq!(run_seccomp_filter(&program, arch, 101), deny);
+    assert_eq!(run_seccomp_filter(&program, arch, 165), deny);
+    assert_eq!(run_seccomp_filter(&program, arch, SECCOMP_X32_SYSCALL_BIT | 1), deny);
+    assert_eq!(run_seccomp_filter(&program, 0x4000_0003, 0), deny);
+
+    let program = seccomp_deny_filter(arch, &[101], false);
+    assert_eq!(
+        run_seccomp_filter(&program, arch, SECCOMP_X32_SYSCALL_BIT | 1),
+        libc::SECCOMP_RET_ALLOW
+    );
 }
 
 #[test]
-fn test_parse_logind_object_path_structure_single_field() {
-    use zbus::zvariant::{ObjectPath, StructureBuilder};
+fn test_harden_denied_syscalls_keep_daemon_syscalls() {
+    let denied = harden_denied_syscalls();
+    for nr in [
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_openat,
+        libc::SYS_connect,
+        libc::SYS_socket,
+        libc::SYS_clone,
+        libc::SYS_execve,
+        libc::SYS_prctl,
+    ] {
+        assert!(!denied.contains(&nr), "syscall {} is denied", nr);
+    }
+}
 
-    let path = ObjectPath::try_from("/org/freedesktop/login1/session/_1").unwrap();
-    let structure = StructureBuilder::new().add_field(path).build().unwrap();
-    let value = OwnedValue::try_from(structure).unwrap();
-    let parsed = parse_logind_object_path(value, "test").unwrap();
+#[test]
+fn test_restrict_writes_limits_thread() {
+    let allowed = tempfile::tempdir().unwrap();
+    let other = tempfile::tempdir().unwrap();
+    let allowed_dirs = vec![allowed.path().to_path_buf()];
+    let allowed_file = allowed.path().join("ok");
+    let other_file = other.path().join("denied");
+
+    // Landlock applies to the calling thread only, so the rest of the test process is unaffected
+    let result = thread::spawn(move || {
+        restrict_writes(&allowed_dirs)?;
+        fs::write(&allowed_file, "ok").map_err(|error| error.to_string())?;
+        Ok::<_, String>(fs::write(&other_file, "no").map_err(|error| error.kind()))
+    })
+    .join()
+    .unwrap();
+
+    match result {
+        Ok(other_write) => {
+            assert_eq!(other_write, Err(std::io::ErrorKind::PermissionDenied));
+            assert!(allowed.path().join("ok").exists());
+            assert!(!other.path().join("denied").exists());
+        }
+        Err(error) if error.starts_with("Landlock unavailable") => {}
+        Err(error) => panic!("{}", error),
+    }
+}
 
-    assert_eq!(parsed.as_str(), "/org/freedesktop/login1/session/_1");
+#[test]
+fn test_dbus_instance_names() {
+    let default = DbusInstance::default();
+    assert!(default.is_default());
+    assert_eq!(default.name(), DBUS_NAME);
+    assert_eq!(default.path(), DBUS_PATH);
+    assert_eq!(default.file_tag(), "");
+    assert_eq!(state_filename(&default), "state.json");
+
+    let work = DbusInstance::new(Some("work"));
+    assert!(!work.is_default());
+    assert_eq!(work.name(), format!("{}.work", DBUS_NAME));
+    assert_eq!(work.path(), format!("{}/work", DBUS_PATH));
+    assert_eq!(work.file_tag(), "-work");
+    assert_eq!(state_filename(&work), "state-work.json");
+    assert!(zbus::names::WellKnownName::try_from(work.name()).is_ok());
+    assert!(zbus::zvariant::ObjectPath::try_from(work.path()).is_ok());
 }
 
 #[test]
-fn test_parse_logind_object_path_string() {
-    use zbus::zvariant::Str;
+fn test_parse_dbus_suffix() {
+    assert_eq!(parse_dbus_suffix("work"), Ok("work".to_string()));
+    assert_eq!(parse_dbus_suffix("Game_2"), Ok("Game_2".to_string()));
+    for invalid in ["", "1x", "a.b", "a-b", "a/b", "Settings"] {
+        assert!(parse_dbus_suffix(invalid).is_err(), "{invalid:?} accepted");
+    }
+}
 
-    let value = OwnedValue::from(Str::from("/org/freedesktop/login1/session/_1"));
-    let parsed = parse_logind_object_path(value, "test").unwrap();
+#[test]
+fn test_dbus_suffix_arg() {
+    let matches = Args::command().get_matches_from([
+        "kanata-switcher",
+        "--install-autostart",
+        "--dbus-suffix",
+        "work",
+    ]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    assert_eq!(args.dbus_suffix.as_deref(), Some("work"));
+    assert_eq!(
+        autostart_passthrough_args(&matches, &args),
+        vec!["--dbus-suffix".to_string(), "work".to_string()]
+    );
+    assert!(Args::try_parse_from(["kanata-switcher", "--dbus-suffix", "a.b"]).is_err());
+}
 
-    assert_eq!(parsed.as_str(), "/org/freedesktop/login1/session/_1");
+#[test]
+fn test_instance_files() {
+    let dir = tempfile::tempdir().unwrap();
+    for name in [
+        "kanata-switcher.desktop",
+        "kanata-switcher-work.desktop",
+        "kanata-switcher-game.desktop",
+        "kanata-switcher-work.txt",
+        "kanata-switcher-work.service",
+        "other.desktop",
+    ] {
+        std::fs::write(dir.path().join(name), "").unwrap();
+    }
+    assert_eq!(
+        instance_files(dir.path(), "desktop"),
+        vec![
+            dir.path().join("kanata-switcher-game.desktop"),
+            dir.path().join("kanata-switcher-work.desktop"),
+        ]
+    );
+    assert_eq!(
+        instance_files(dir.path(), "service"),
+        vec![dir.path().join("kanata-switcher-work.service")]
+    );
+}
+
+fn session_vars(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+    pairs
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
 }
 
 #[test]
-fn test_decode_logind_object_path_reply_object_path() {
-    use zbus::zvariant::ObjectPath;
+fn test_detect_environment_with() {
+    let detect = |pairs: &[(&str, &str)]| {
+        let vars = session_vars(pairs);
+        detect_environment_with(|name| vars.get(name).cloned())
+    };
+    assert_eq!(detect(&[("XDG_CURRENT_DESKTOP", "ubuntu:GNOME")]), Environment::Gnome);
+    assert_eq!(
+        detect(&[("KDE_SESSION_VERSION", "6"), ("WAYLAND_DISPLAY", "wayland-0")]),
+        Environment::Kde
+    );
+    assert_eq!(
+        detect(&[("WAYLAND_DISPLAY", "wayland-1"), ("DISPLAY", ":0")]),
+        Environment::Wayland
+    );
+    assert_eq!(detect(&[("DISPLAY", ":0")]), Environment::X11);
+    assert_eq!(detect(&[]), Environment::Unknown);
+}
 
-    let path = ObjectPath::try_from("/org/freedesktop/login1/session/_1").unwrap();
-    let reply = Message::method_call("/org/freedesktop/login1", "GetSession")
-        .unwrap()
-        .build(&path)
-        .unwrap();
-    let parsed = decode_logind_object_path_reply(&reply, "test").unwrap();
+#[test]
+fn test_log_buffer_keeps_last_lines() {
+    let buffer = LogBuffer::new(3);
+    let mut receiver = buffer.subscribe();
+    for index in 1..=4 {
+        buffer.push(format!("line {}", index));
+    }
+    assert_eq!(buffer.recent(0), vec!["line 2", "line 3", "line 4"]);
+    assert_eq!(buffer.recent(2), vec!["line 3", "line 4"]);
+    assert_eq!(buffer.recent(10).len(), 3);
+    assert_eq!(receiver.try_recv().unwrap(), "line 1");
+}
 
-    assert_eq!(parsed.as_str(), "/org/freedesktop/login1/session/_1");
+#[test]
+fn test_backend_info() {
+    let info = |env: Environment, pairs: &[(&str, &str)]| {
+        let vars = session_vars(pairs);
+        backend_info_with(env, |name| vars.get(name).cloned())
+    };
+    let gnome = info(Environment::Gnome, &[]);
+    assert_eq!(gnome.name, "gnome");
+    assert!(gnome.capabilities.app && !gnome.capabilities.size && !gnome.capabilities.title_changes);
+    assert!(gnome.capabilities.workspace);
+
+    let hyprland = info(Environment::Wayland, &[("HYPRLAND_INSTANCE_SIGNATURE", "abc")]);
+    assert_eq!(hyprland.name, "hyprland");
+    assert!(hyprland.capabilities.size && hyprland.capabilities.x11_class);
+    assert!(hyprland.capabilities.workspace);
+    assert_eq!(info(Environment::Wayland, &[("SWAYSOCK", "/tmp/sway.sock")]).name, "sway");
+
+    let wlroots = info(Environment::Wayland, &[]);
+    assert_eq!(wlroots.name, "wayland");
+    assert!(wlroots.capabilities.title_changes && wlroots.capabilities.initial);
+    assert!(!wlroots.capabilities.size && !wlroots.capabilities.x11_class);
+    assert!(!wlroots.capabilities.workspace);
+
+    let map = info(Environment::X11, &[]).capability_map();
+    assert_eq!(map.get("size"), Some(&true));
+    assert_eq!(map.get("title_changes"), Some(&false));
+    assert_eq!(map.get("workspace"), Some(&true));
+    assert_eq!(map.get("fullscreen"), Some(&false));
+    assert_eq!(map.get("role"), Some(&true));
+    assert_eq!(map.len(), 11);
 }
 
 #[test]
-fn test_decode_logind_object_path_reply_structure() {
-    use zbus::zvariant::{ObjectPath, StructureBuilder};
+fn test_backend_rule_warnings() {
+    let config = Config {
+        rules: vec![
+            rule(Some("firefox"), None, Some("browser")),
+            Rule {
+                initial_title: Some("^kitty$".to_string()),
+                max_width: Some(200),
+                layer: Some("terminal".to_string()),
+                ..Default::default()
+            },
+            Rule {
+                name: Some("Steam".to_string()),
+                class: Some("^Steam$".to_string()),
+                class_source: Some(ClassSource::X11Class),
+                layer: Some("gaming".to_string()),
+                ..Default::default()
+            },
+        ],
+        default_layer: None,
+        native_terminal_rule: None,
+        session_inactive_rule: None,
+        locked_rule: None,
+        kanata_endpoints: BTreeMap::new(),
+        layer_display_names: BTreeMap::new(),
+        layer_virtual_keys: BTreeMap::new(),
+        announce: None,
+        notifications: None,
+        call_detection: None,
+        uinput_fallback: None,
+        min_focus_ms: 0,
+        unfocus_grace_ms: 0,
+        dedupe_focus: true,
+        vt_monitor: true,
+        defer_during_grab: false,
+        per_window_state: false,
+        plugins_dir: None,
+        rule_tests: Vec::new(),
+        idle: None,
+        tray_icons: BTreeMap::new(),
+        indicator: IndicatorTheme::default(),
+        class_source: ClassSource::AppId,
+    };
 
-    let path = ObjectPath::try_from("/org/freedesktop/login1/session/_1").unwrap();
-    let structure = StructureBuilder::new().add_field(path).build().unwrap();
-    let reply = Message::method_call("/org/freedesktop/login1", "GetSession")
-        .unwrap()
-        .build(&structure)
-        .unwrap();
-    let parsed = decode_logind_object_path_reply(&reply, "test").unwrap();
+    assert_eq!(
+        backend_rule_warnings(&config, &backend_info_with(Environment::Gnome, |_| None)),
+        vec![
+            "rule #2: initial_class/initial_title, size conditions can't match on the gnome backend"
+                .to_string(),
+            "rule #3 'Steam': class_source x11_class falls back to the app_id on the gnome backend"
+                .to_string(),
+        ]
+    );
+    assert_eq!(
+        backend_rule_warnings(&config, &backend_info_with(Environment::Wayland, |_| None)),
+        vec![
+            "rule #2: size conditions can't match on the wayland backend".to_string(),
+            "rule #3 'Steam': class_source x11_class falls back to the app_id on the wayland backend"
+                .to_string(),
+        ]
+    );
+    assert!(backend_rule_warnings(&config, &backend_info_with(Environment::X11, |_| None)).is_empty());
+}
 
-    assert_eq!(parsed.as_str(), "/org/freedesktop/login1/session/_1");
+/// One rule per condition `backend_rule_warnings` checks, named after it
+fn conditions_config() -> Config {
+    parse_config_text(
+        r#"[
+            {"name": "app", "app": "firefox", "layer": "a"},
+            {"name": "workspace", "workspace": "2", "layer": "a"},
+            {"name": "instance", "instance": "^main$", "layer": "a"},
+            {"name": "role", "role": "^browser$", "layer": "a"},
+            {"name": "initial", "initial_class": "^kitty$", "layer": "a"},
+            {"name": "size", "min_width": 800, "layer": "a"},
+            {"name": "x11", "class": "^Steam$", "class_source": "x11_class", "layer": "a"}
+        ]"#,
+        Path::new("kanata-switcher.json"),
+    )
+    .unwrap()
+}
+
+/// Names of the rules with a `backend_rule_warnings` warning on `backend`
+fn warned_rules(backend: &BackendInfo) -> Vec<String> {
+    backend_rule_warnings(&conditions_config(), backend)
+        .iter()
+        .map(|warning| warning.split('\'').nth(1).unwrap().to_string())
+        .collect()
 }
 
 #[test]
-fn test_decode_logind_object_path_reply_structure_multi_field() {
-    use zbus::zvariant::{ObjectPath, StructureBuilder};
+fn test_backend_rule_warnings_per_backend() {
+    let sway = |name: &str| (name == "SWAYSOCK").then(|| "/run/sway.sock".to_string());
+    let hyprland = |name: &str| (name == "HYPRLAND_INSTANCE_SIGNATURE").then(|| "abc".to_string());
+    let cases: [(BackendInfo, &[&str]); 6] = [
+        (
+            backend_info_with(Environment::Gnome, |_| None),
+            &["instance", "role", "initial", "size", "x11"],
+        ),
+        (
+            backend_info_with(Environment::Kde, |_| None),
+            &["initial", "size", "x11"],
+        ),
+        (
+            backend_info_with(Environment::Wayland, |_| None),
+            &["workspace", "instance", "role", "size", "x11"],
+        ),
+        (
+            backend_info_with(Environment::Wayland, sway),
+            &["instance", "role"],
+        ),
+        (
+            backend_info_with(Environment::Wayland, hyprland),
+            &["instance", "role"],
+        ),
+        (backend_info_with(Environment::X11, |_| None), &[]),
+    ];
+    for (backend, warned) in cases {
+        assert_eq!(warned_rules(&backend), warned, "{}", backend.name);
+    }
 
-    let path = ObjectPath::try_from("/org/freedesktop/login1/session/_311").unwrap();
-    let structure = StructureBuilder::new()
-        .add_field("11")
-        .add_field(path)
-        .build()
-        .unwrap();
-    let reply = Message::method_call("/org/freedesktop/login1", "GetSession")
-        .unwrap()
-        .build(&structure)
-        .unwrap();
-    let parsed = decode_logind_object_path_reply(&reply, "test").unwrap();
+    let warnings = backend_rule_warnings(
+        &conditions_config(),
+        &backend_info_with(Environment::Gnome, |_| None),
+    );
+    assert_eq!(
+        warnings[2],
+        "rule #5 'initial': initial_class/initial_title can't match on the gnome backend"
+    );
+    assert_eq!(
+        warnings[3],
+        "rule #6 'size': size conditions can't match on the gnome backend"
+    );
+}
 
-    assert_eq!(parsed.as_str(), "/org/freedesktop/login1/session/_311");
+#[test]
+fn test_warn_unsupported_conditions_logs_each_warning() {
+    let config = conditions_config();
+    let warned = |env: Environment| {
+        let mut logs = LOG_BUFFER.subscribe();
+        warn_unsupported_conditions(&config, env);
+        let expected: Vec<String> = backend_rule_warnings(&config, &backend_info(env))
+            .iter()
+            .map(|warning| format!("[Config] Warning: {}", warning))
+            .collect();
+        // Other tests may log at the same time
+        let mut logged = Vec::new();
+        while let Ok(line) = logs.try_recv() {
+            if expected.contains(&line) {
+                logged.push(line);
+            }
+        }
+        assert_eq!(logged, expected, "{:?}", env);
+        logged.len()
+    };
+    assert_eq!(warned(Environment::Gnome), 5);
+    assert_eq!(warned(Environment::Kde), 3);
+    assert_eq!(warned(Environment::X11), 0);
+    // No backend to compare against
+    let mut logs = LOG_BUFFER.subscribe();
+    warn_unsupported_conditions(&config, Environment::Unknown);
+    while let Ok(line) = logs.try_recv() {
+        assert!(
+            !line.contains("can't match on the unknown backend"),
+            "{}",
+            line
+        );
+    }
 }
 
 #[test]
-fn test_decode_logind_object_path_reply_variant() {
-    use zbus::zvariant::{ObjectPath, Value};
+fn test_session_env_from_manager() {
+    let entries = [
+        "HOME=/home/user",
+        "DISPLAY=:0",
+        "WAYLAND_DISPLAY=wayland-1",
+        "XDG_CURRENT_DESKTOP=sway",
+        "MALFORMED",
+    ]
+    .map(String::from);
+    assert_eq!(
+        session_env_from_manager(&entries),
+        session_vars(&[
+            ("DISPLAY", ":0"),
+            ("WAYLAND_DISPLAY", "wayland-1"),
+            ("XDG_CURRENT_DESKTOP", "sway"),
+        ])
+    );
+}
 
-    let path = ObjectPath::try_from("/org/freedesktop/login1/session/_1").unwrap();
-    let value = OwnedValue::try_from(Value::from(path)).unwrap();
-    let reply = Message::method_call("/org/freedesktop/login1", "GetSession")
-        .unwrap()
-        .build(&value)
-        .unwrap();
-    let parsed = decode_logind_object_path_reply(&reply, "test").unwrap();
+#[test]
+fn test_session_env_updates() {
+    let runtime_dir = tempfile::tempdir().unwrap();
+    let process = |name: &str| (name == "DISPLAY").then(|| ":0".to_string());
+    let x11 = session_vars(&[("DISPLAY", ":0")]);
+    let wayland = session_vars(&[("DISPLAY", ":0"), ("WAYLAND_DISPLAY", "wayland-1")]);
+
+    // Unchanged since startup, even if it differs from the process (forced backend)
+    assert_eq!(session_env_updates(&wayland, &wayland, process, Some(runtime_dir.path())), None);
+    // Wayland session without its socket yet
+    assert_eq!(session_env_updates(&x11, &wayland, process, Some(runtime_dir.path())), None);
+
+    std::fs::write(runtime_dir.path().join("wayland-1"), "").unwrap();
+    assert_eq!(
+        session_env_updates(&x11, &wayland, process, Some(runtime_dir.path())),
+        Some(vec![("WAYLAND_DISPLAY".to_string(), Some("wayland-1".to_string()))])
+    );
+    // Session gone: nothing to switch to
+    assert_eq!(
+        session_env_updates(&x11, &BTreeMap::new(), process, Some(runtime_dir.path())),
+        None
+    );
+    // A new X11 session on another display
+    assert_eq!(
+        session_env_updates(&x11, &session_vars(&[("DISPLAY", ":1")]), process, None),
+        Some(vec![("DISPLAY".to_string(), Some(":1".to_string()))])
+    );
+    // Session changed back to what the process already uses
+    assert_eq!(
+        session_env_updates(&wayland, &x11, process, Some(runtime_dir.path())),
+        None
+    );
+}
 
-    assert_eq!(parsed.as_str(), "/org/freedesktop/login1/session/_1");
+#[test]
+fn test_session_var_prefers_session_overrides() {
+    let process = |name: &str| match name {
+        "DISPLAY" => Some(":0".to_string()),
+        "XDG_CURRENT_DESKTOP" => Some("sway".to_string()),
+        _ => None,
+    };
+    let overrides = BTreeMap::from([
+        ("WAYLAND_DISPLAY".to_string(), Some("wayland-1".to_string())),
+        ("XDG_CURRENT_DESKTOP".to_string(), None),
+    ]);
+    let var = |name: &str| session_var_with(&overrides, name, process);
+    assert_eq!(var("WAYLAND_DISPLAY").as_deref(), Some("wayland-1"));
+    // Unset in the new session, even though the process has it
+    assert_eq!(var("XDG_CURRENT_DESKTOP"), None);
+    assert_eq!(var("DISPLAY").as_deref(), Some(":0"));
+    assert_eq!(detect_environment_with(var), Environment::Wayland);
 }
 
-#[tokio::test]
-async fn test_update_status_for_focus_filters_invalid_virtual_keys() {
-    // Rule with a virtual key that's NOT in the known list
-    let rules = vec![Rule {
-        class: Some("firefox".to_string()),
-        title: None,
-        on_native_terminal: None,
-        layer: Some("browser".to_string()),
-        virtual_key: Some("invalid_vk".to_string()),
-        raw_vk_action: None,
-        fallthrough: false,
-    }];
-    let handler = Arc::new(Mutex::new(FocusHandler::new(rules, None, true)));
-    let status_broadcaster = StatusBroadcaster::new();
-    let kanata = KanataClient::new(
-        "127.0.0.1",
-        10000,
-        Some("default".to_string()),
-        true,
-        status_broadcaster.clone(),
-    );
+#[test]
+fn test_wayland_socket_exists() {
+    let runtime_dir = tempfile::tempdir().unwrap();
+    let socket = runtime_dir.path().join("wayland-0");
+    assert!(!wayland_socket_exists("wayland-0", Some(runtime_dir.path())));
+    std::fs::write(&socket, "").unwrap();
+    assert!(wayland_socket_exists("wayland-0", Some(runtime_dir.path())));
+    assert!(!wayland_socket_exists("wayland-0", None));
+    assert!(wayland_socket_exists(socket.to_str().unwrap(), None));
+}
 
-    {
-        let mut inner = kanata
-            .inner
-            .try_lock()
-            .expect("Expected KanataClient lock");
-        // Set known VKs to a list that does NOT include invalid_vk
-        inner.known_virtual_keys = Some(vec!["valid_vk".to_string()]);
+#[test]
+fn test_once_arg() {
+    let args = Args::try_parse_from(["kanata-switcher", "--once", "-p", "10001"]).unwrap();
+    assert!(args.once);
+    for conflicting in ["--install-autostart", "--restart", "--check-config", "--restore-layer"] {
+        assert!(
+            Args::try_parse_from(["kanata-switcher", "--once", conflicting]).is_err(),
+            "{conflicting} accepted with --once"
+        );
     }
+}
 
-    let win = win("firefox", "");
-    let _actions = update_status_for_focus(
-        &handler,
-        &status_broadcaster,
-        &win,
-        &kanata,
-        "default",
-    )
-    .await;
+#[test]
+fn test_snapshot_arg() {
+    let args = Args::try_parse_from(["kanata-switcher", "--snapshot"]).unwrap();
+    assert!(args.snapshot);
+    for conflicting in ["--once", "--install-autostart", "--check-config"] {
+        assert!(
+            Args::try_parse_from(["kanata-switcher", "--snapshot", conflicting]).is_err(),
+            "{conflicting} accepted with --snapshot"
+        );
+    }
+}
 
-    let snapshot = status_broadcaster.snapshot();
-    // The invalid VK should be filtered out - status should show NO virtual keys
+#[test]
+fn test_snapshot_layer_name() {
+    assert_eq!(snapshot_layer_name("org.gnome.Nautilus"), "nautilus");
+    assert_eq!(snapshot_layer_name("firefox"), "firefox");
+    assert_eq!(snapshot_layer_name("Google-chrome"), "google-chrome");
+    assert_eq!(snapshot_layer_name("jetbrains-idea_ce"), "jetbrains-idea-ce");
+    assert_eq!(snapshot_layer_name("org.kde.dolphin."), "dolphin");
+    assert_eq!(snapshot_layer_name("..."), "app");
+    assert_eq!(snapshot_layer_name("Émacs"), "macs");
+}
+
+#[test]
+fn test_format_snapshot() {
+    let window = |class: &str, title: &str| WindowInfo {
+        class: class.to_string(),
+        title: title.to_string(),
+        ..Default::default()
+    };
+    let windows = vec![
+        window("org.gnome.Nautilus", "Home"),
+        window("firefox", "Mozilla Firefox"),
+        window("firefox", "Docs"),
+        window("", "untitled"),
+        window("waybar", "bar"),
+        window("code.x", &"t".repeat(70)),
+        window("kitty", "vim\nmain.rs"),
+        window("Kitty", ""),
+    ];
+    let output = format_snapshot(Environment::Gnome, &windows);
+    let expected = format!(
+        r#"// Generated by kanata-switcher --snapshot (gnome): 5 apps in 6 windows
+// Replace the placeholder layers, drop unwanted rules and these comment lines,
+// then paste the rules into the config array before its default rule.
+// 1 window, e.g. "{}…"
+{{"class": "^code\\.x$", "layer": "x"}},
+// 2 windows, e.g. "Mozilla Firefox"
+{{"class": "^firefox$", "layer": "firefox"}},
+// 1 window
+{{"class": "^Kitty$", "layer": "kitty"}},
+// 1 window, e.g. "vim main.rs"
+{{"class": "^kitty$", "layer": "kitty"}},
+// 1 window, e.g. "Home"
+{{"class": "^org\\.gnome\\.Nautilus$", "layer": "nautilus"}}
+"#,
+        "t".repeat(60)
+    );
+    assert_eq!(output, expected);
+
+    // Dropping the comment lines leaves rules that parse as a config
+    let rules: String = output.lines().filter(|line| !line.starts_with("//")).collect();
+    let parsed: Vec<serde_json::Value> = serde_json::from_str(&format!("[{}]", rules)).unwrap();
+    assert_eq!(parsed.len(), 5);
+    assert!(Regex::new(parsed[4]["class"].as_str().unwrap()).unwrap().is_match("org.gnome.Nautilus"));
+
+    assert!(format_snapshot(Environment::X11, &[]).contains("0 apps in 0 windows"));
+}
+
+#[test]
+fn test_logind_session_override_args() {
+    let matches = Args::command().get_matches_from([
+        "kanata-switcher",
+        "--install-autostart",
+        "--session-id",
+        "c2",
+    ]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    assert_eq!(
+        LogindSessionOverride::from_args(&args),
+        Some(LogindSessionOverride::SessionId("c2".to_string()))
+    );
+    assert_eq!(autostart_passthrough_args(&matches, &args), vec!["--session-id", "c2"]);
+
+    let args = Args::try_parse_from(["kanata-switcher", "--seat", "seat0"]).unwrap();
+    assert_eq!(
+        LogindSessionOverride::from_args(&args),
+        Some(LogindSessionOverride::Seat("seat0".to_string()))
+    );
+    let args = Args::try_parse_from(["kanata-switcher"]).unwrap();
+    assert_eq!(LogindSessionOverride::from_args(&args), None);
     assert!(
-        snapshot.virtual_keys.is_empty(),
-        "Invalid VK should not appear in status snapshot, got: {:?}",
-        snapshot.virtual_keys
+        Args::try_parse_from(["kanata-switcher", "--session-id", "2", "--seat", "seat0"]).is_err()
     );
 }
 
-#[tokio::test]
-async fn test_update_status_for_focus_shows_valid_virtual_keys() {
-    // Rule with a virtual key that IS in the known list
-    let rules = vec![Rule {
-        class: Some("firefox".to_string()),
-        title: None,
-        on_native_terminal: None,
-        layer: Some("browser".to_string()),
-        virtual_key: Some("vk_browser".to_string()),
-        raw_vk_action: None,
-        fallthrough: false,
-    }];
-    let handler = Arc::new(Mutex::new(FocusHandler::new(rules, None, true)));
-    let status_broadcaster = StatusBroadcaster::new();
-    let kanata = KanataClient::new(
-        "127.0.0.1",
-        10000,
-        Some("default".to_string()),
-        true,
-        status_broadcaster.clone(),
+#[test]
+fn test_seat_sessions_filters_by_user_and_seat() {
+    let session = |id: &str, uid: u32, seat: &str| {
+        (
+            id.to_string(),
+            uid,
+            "user".to_string(),
+            seat.to_string(),
+            OwnedObjectPath::try_from(format!("/org/freedesktop/login1/session/_3{}", id)).unwrap(),
+        )
+    };
+    let sessions = vec![
+        session("1", 1000, "seat0"),
+        session("2", 1001, "seat0"),
+        session("3", 1000, "seat1"),
+        session("4", 1000, ""),
+        session("5", 1000, "seat0"),
+    ];
+    let paths = seat_sessions(&sessions, 1000, "seat0");
+    assert_eq!(
+        paths.iter().map(|path| path.as_str()).collect::<Vec<_>>(),
+        vec!["/org/freedesktop/login1/session/_31", "/org/freedesktop/login1/session/_35"]
     );
+    assert!(seat_sessions(&sessions, 1000, "seat2").is_empty());
+}
 
-    {
-        let mut inner = kanata
-            .inner
-            .try_lock()
-            .expect("Expected KanataClient lock");
-        // Set known VKs to include vk_browser
-        inner.known_virtual_keys = Some(vec!["vk_browser".to_string()]);
+#[test]
+fn test_vt_monitor_switches() {
+    let config = load_config_json(r#"[{"vt_monitor": false}, {"class": "a", "layer": "vim"}]"#);
+    assert!(!config.vt_monitor);
+    let config = load_config_json(r#"[{"class": "a", "layer": "vim"}]"#);
+    assert!(config.vt_monitor);
+    for json in [r#"[{"vt_monitor": "no"}]"#, r#"[{"vt_monitor": false, "class": "a"}]"#] {
+        let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+        assert!(result.is_err(), "{}", json);
     }
 
-    let win = win("firefox", "");
-    let _actions = update_status_for_focus(
-        &handler,
-        &status_broadcaster,
-        &win,
-        &kanata,
-        "default",
-    )
-    .await;
+    let matches =
+        Args::command().get_matches_from(["kanata-switcher", "--install-autostart", "--no-vt-monitor"]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    assert!(args.no_vt_monitor);
+    assert_eq!(autostart_passthrough_args(&matches, &args), vec!["--no-vt-monitor"]);
+}
 
-    let snapshot = status_broadcaster.snapshot();
-    // The valid VK should appear in the status
+#[test]
+fn test_config_parses_defer_during_grab() {
+    let config =
+        load_config_json(r#"[{"defer_during_grab": true}, {"class": "a", "layer": "vim"}]"#);
+    assert!(config.defer_during_grab);
+    assert!(FocusHandler::from_config(&config, true, false).defer_during_grab());
+    let config = load_config_json(r#"[{"class": "a", "layer": "vim"}]"#);
+    assert!(!config.defer_during_grab);
+    for json in [
+        r#"[{"defer_during_grab": 1}]"#,
+        r#"[{"defer_during_grab": true, "class": "a"}]"#,
+    ] {
+        let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+        assert!(result.is_err(), "{}", json);
+    }
+}
+
+#[test]
+fn test_pause_while_sharing_args() {
+    let matches = Args::command().get_matches_from([
+        "kanata-switcher",
+        "--install-autostart",
+        "--pause-while-sharing",
+        "--sharing-layer",
+        "demo",
+    ]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    assert!(args.pause_while_sharing);
+    assert_eq!(args.sharing_layer.as_deref(), Some("demo"));
     assert_eq!(
-        snapshot.virtual_keys,
-        vec!["vk_browser"],
-        "Valid VK should appear in status snapshot"
+        autostart_passthrough_args(&matches, &args),
+        vec!["--pause-while-sharing", "--sharing-layer", "demo"]
     );
+    assert!(Args::try_parse_from(["kanata-switcher", "--sharing-layer", "demo"]).is_err());
 }
 
-// === Config Parsing Tests ===
-
 #[test]
-fn test_config_rejects_unknown_fields() {
-    // "native_terminal" is not a valid field (should be "on_native_terminal")
-    let json = r#"[{"native_terminal": "tty"}]"#;
-    let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
-    assert!(
-        result.is_err(),
-        "Config should reject unknown field 'native_terminal'"
-    );
-    let err = result.unwrap_err().to_string();
-    assert!(
-        err.contains("unknown field 'native_terminal'"),
-        "Error should mention the unknown field name, got: {}",
-        err
-    );
+fn test_pause_broadcaster_automatic_pause() {
+    let pause = PauseBroadcaster::new();
+    let mut receiver = pause.subscribe();
+    assert!(pause.set_paused_automatically(true));
+    assert!(pause.is_paused() && pause.is_automatic());
+    assert!(!pause.set_paused_automatically(true));
+    assert!(pause.set_paused_automatically(false));
+    assert!(!pause.is_paused() && !pause.is_automatic());
+    assert!(!pause.set_paused_automatically(false));
+
+    // A user pause is never lifted automatically
+    assert!(pause.set_paused(true));
+    assert!(!pause.set_paused_automatically(true));
+    assert!(!pause.is_automatic());
+    assert!(!pause.set_paused_automatically(false));
+    assert!(pause.is_paused());
+    assert!(pause.set_paused(false));
+
+    // Pausing during an automatic pause makes it the user's, and notifies so it gets persisted
+    assert!(pause.set_paused_automatically(true));
+    receiver.borrow_and_update();
+    assert!(!pause.set_paused(true));
+    assert!(receiver.has_changed().unwrap());
+    assert!(!pause.is_automatic());
+    assert!(!pause.set_paused_automatically(false));
+    assert!(pause.is_paused());
 }
 
 #[test]
-fn test_config_rejects_typo_in_field_name() {
-    // Common typos should be rejected
-    let json = r#"[{"clas": "firefox", "layer": "browser"}]"#;
-    let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
-    assert!(result.is_err(), "Config should reject typo 'clas'");
-    let err = result.unwrap_err().to_string();
-    assert!(
-        err.contains("unknown field 'clas'"),
-        "Error should mention the typo field name, got: {}",
-        err
-    );
+fn test_screen_share_tracker() {
+    use zbus::message::Message;
+
+    let session = "/org/freedesktop/portal/desktop/session/1_7/s";
+    let request = "/org/freedesktop/portal/desktop/request/1_7/r";
+    let no_options = HashMap::<String, OwnedValue>::new();
+    let start = |client: &str| {
+        Message::method_call("/org/freedesktop/portal/desktop", "Start")
+            .unwrap()
+            .interface(PORTAL_SCREENCAST_INTERFACE)
+            .unwrap()
+            .sender(client)
+            .unwrap()
+            .build(&(OwnedObjectPath::try_from(session).unwrap(), "", &no_options))
+            .unwrap()
+    };
+    let reply = |call: &Message, client: &str| {
+        Message::method_return(&call.header())
+            .unwrap()
+            .destination(client)
+            .unwrap()
+            .build(&OwnedObjectPath::try_from(request).unwrap())
+            .unwrap()
+    };
+    let response = |code: u32| {
+        Message::signal(request, PORTAL_REQUEST_INTERFACE, "Response")
+            .unwrap()
+            .build(&(code, &no_options))
+            .unwrap()
+    };
+    let closed = Message::signal(session, PORTAL_SESSION_INTERFACE, "Closed")
+        .unwrap()
+        .build(&no_options)
+        .unwrap();
+    let name_lost = |name: &str| {
+        Message::signal("/org/freedesktop/DBus", "org.freedesktop.DBus", "NameOwnerChanged")
+            .unwrap()
+            .build(&(name, ":1.99", ""))
+            .unwrap()
+    };
+
+    let mut tracker = ScreenShareTracker::default();
+    let call = start(":1.7");
+    assert!(!tracker.handle(&call));
+    assert!(!tracker.handle(&reply(&call, ":1.7")));
+    assert!(tracker.handle(&response(0)));
+    assert!(tracker.is_sharing());
+    assert!(tracker.handle(&closed));
+    assert!(!tracker.is_sharing());
+
+    // Cancelled in the portal dialog
+    let call = start(":1.7");
+    tracker.handle(&call);
+    tracker.handle(&reply(&call, ":1.7"));
+    assert!(!tracker.handle(&response(1)));
+    assert!(!tracker.is_sharing());
+
+    // Replies to other calls and responses to other requests are ignored
+    let call = start(":1.7");
+    tracker.handle(&call);
+    assert!(!tracker.handle(&reply(&call, ":1.8")));
+    assert!(!tracker.handle(&response(0)));
+
+    // The client leaving the bus, or the portal going away, ends its sessions
+    for gone in [":1.7", PORTAL_BUS_NAME] {
+        let call = start(":1.7");
+        tracker.handle(&call);
+        tracker.handle(&reply(&call, ":1.7"));
+        assert!(tracker.handle(&response(0)));
+        assert!(!tracker.handle(&name_lost(":1.8")));
+        assert!(tracker.handle(&name_lost(gone)));
+        assert!(!tracker.is_sharing());
+    }
 }
 
 #[test]
-fn test_config_accepts_valid_rule() {
-    let json = r#"[{"class": "firefox", "layer": "browser"}]"#;
-    let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
-    assert!(result.is_ok(), "Config should accept valid rule");
+fn test_connect_timeout_and_allow_offline_args() {
+    let matches = Args::command().get_matches_from([
+        "kanata-switcher",
+        "--install-autostart",
+        "--connect-timeout",
+        "10",
+        "--allow-offline",
+    ]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    assert_eq!(args.connect_timeout, Some(10));
+    assert!(args.allow_offline);
+    assert_eq!(
+        autostart_passthrough_args(&matches, &args),
+        vec!["--connect-timeout", "10", "--allow-offline"]
+    );
+    assert!(Args::try_parse_from(["kanata-switcher", "--once", "--allow-offline"]).is_err());
 }
 
 #[test]
-fn test_config_accepts_on_native_terminal() {
-    let json = r#"[{"on_native_terminal": "tty"}]"#;
-    let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
+fn test_notify_kanata_arg() {
+    let matches =
+        Args::command().get_matches_from(["kanata-switcher", "--install-autostart", "--notify-kanata"]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    assert!(args.notify_kanata);
+    assert_eq!(autostart_passthrough_args(&matches, &args), vec!["--notify-kanata"]);
+    assert!(Args::try_parse_from(["kanata-switcher", "--once", "--notify-kanata"]).is_err());
+
+    let matches = Args::command().get_matches_from([
+        "kanata-switcher",
+        "--install-autostart",
+        "--notify-layer-changes",
+    ]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    assert!(args.notify_layer_changes);
+    assert_eq!(autostart_passthrough_args(&matches, &args), vec!["--notify-layer-changes"]);
     assert!(
-        result.is_ok(),
-        "Config should accept 'on_native_terminal' field"
+        Args::try_parse_from(["kanata-switcher", "--once", "--notify-layer-changes"]).is_err()
     );
 }
 
 #[test]
-fn test_config_accepts_default_entry() {
-    let json = r#"[{"default": "base"}]"#;
-    let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
-    assert!(result.is_ok(), "Config should accept default entry");
+fn test_kanata_connection_notification() {
+    let notification = |connected, dropped_actions| {
+        kanata_connection_notification(&KanataConnectionEvent {
+            connected,
+            dropped_actions,
+        })
+    };
+    assert_eq!(notification(false, 0).0, "Kanata disconnected");
+    assert_eq!(
+        notification(true, 0),
+        (
+            "Kanata reconnected".to_string(),
+            "No actions were dropped".to_string()
+        )
+    );
+    assert_eq!(
+        notification(true, 3).1,
+        "3 virtual key or reload actions were dropped while disconnected"
+    );
+}
+
+fn deferred_fake_key(name: &str, action: &str) -> DeferredKanataMessage {
+    DeferredKanataMessage::ActOnFakeKey {
+        name: name.to_string(),
+        action: action.to_string(),
+    }
 }
 
 #[test]
-fn test_config_parses_matcherless_rule_with_fallthrough() {
-    // A rule with no class/title but with fallthrough: true is valid
-    // (applies to all windows and continues matching)
-    let json = r#"[{"layer": "base", "fallthrough": true}]"#;
-    let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
-    assert!(
-        result.is_ok(),
-        "Config should parse matcher-less rule with fallthrough"
+fn test_kanata_rate_limit_bucket() {
+    let start = tokio::time::Instant::now();
+    let mut limit = KanataRateLimit::new(2, start);
+    assert!(limit.try_acquire(start));
+    assert!(limit.try_acquire(start));
+    assert!(!limit.try_acquire(start));
+    assert_eq!(limit.next_token_in(start), Duration::from_millis(500));
+
+    limit.defer(deferred_fake_key("vk_a", "Press"), start);
+    limit.defer(DeferredKanataMessage::ChangeLayer("vim".to_string()), start);
+    limit.defer(deferred_fake_key("vk_b", "Release"), start);
+    assert_eq!(limit.pop_ready(start), None);
+
+    // Refilled, but anything deferred goes first, in order
+    let later = start + Duration::from_secs(1);
+    assert!(!limit.try_acquire(later));
+    assert_eq!(limit.pop_ready(later), Some(deferred_fake_key("vk_a", "Press")));
+    assert_eq!(
+        limit.pop_ready(later),
+        Some(DeferredKanataMessage::ChangeLayer("vim".to_string()))
     );
-    if let Ok(entries) = result {
-        if let ConfigEntry::Rule(rule) = &entries[0] {
-            assert!(rule.class.is_none());
-            assert!(rule.title.is_none());
-            assert!(rule.fallthrough);
-        } else {
-            panic!("Expected Rule entry");
+    assert_eq!(limit.pop_ready(later), None);
+    let last = later + Duration::from_millis(500);
+    assert_eq!(limit.pop_ready(last), Some(deferred_fake_key("vk_b", "Release")));
+    assert!(!limit.has_deferred());
+    assert_eq!(
+        limit.stats,
+        KanataRateLimitStats {
+            sent: 5,
+            deferred: 3,
+            coalesced: 0,
         }
-    }
+    );
 }
 
 #[test]
-fn test_config_parses_matcherless_rule_without_fallthrough() {
-    // A rule with no class/title and no fallthrough parses but will be rejected
-    // by load_config validation (not tested here since it calls exit)
-    let json = r#"[{"layer": "base"}]"#;
-    let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
-    assert!(
-        result.is_ok(),
-        "Config should parse (validation happens in load_config)"
+fn test_kanata_rate_limit_coalescing() {
+    let now = tokio::time::Instant::now();
+    let mut limit = KanataRateLimit::new(1, now);
+    assert!(limit.try_acquire(now));
+    limit.defer(DeferredKanataMessage::ChangeLayer("vim".to_string()), now);
+    limit.defer(DeferredKanataMessage::ChangeLayer("browser".to_string()), now);
+    // Repeating the key's last Press/Release changes nothing; taps are actions of their own
+    for action in ["Press", "Press", "Tap", "Tap", "Release", "Release"] {
+        limit.defer(deferred_fake_key("vk_a", action), now);
+    }
+    let deferred: Vec<DeferredKanataMessage> =
+        limit.deferred.iter().map(|(_, message)| message.clone()).collect();
+    assert_eq!(
+        deferred,
+        vec![
+            DeferredKanataMessage::ChangeLayer("browser".to_string()),
+            deferred_fake_key("vk_a", "Press"),
+            deferred_fake_key("vk_a", "Tap"),
+            deferred_fake_key("vk_a", "Tap"),
+            deferred_fake_key("vk_a", "Release"),
+        ]
     );
-    if let Ok(entries) = result {
-        if let ConfigEntry::Rule(rule) = &entries[0] {
-            assert!(rule.class.is_none());
-            assert!(rule.title.is_none());
-            assert!(!rule.fallthrough); // default is false
-        } else {
-            panic!("Expected Rule entry");
+    limit.cancel_deferred_layer();
+    assert_eq!(limit.deferred.len(), 4);
+    assert_eq!(
+        limit.stats,
+        KanataRateLimitStats {
+            sent: 1,
+            deferred: 8,
+            coalesced: 4,
+        }
+    );
+}
+
+/// Drains the rate limit, returning each message with its flush time relative to `start`
+fn flush_rate_limit(
+    limit: &mut KanataRateLimit,
+    start: tokio::time::Instant,
+) -> Vec<(Duration, DeferredKanataMessage)> {
+    let mut now = start;
+    let mut flushed = Vec::new();
+    while limit.has_deferred() {
+        now += limit.next_token_in(now);
+        if let Some(message) = limit.pop_ready(now) {
+            flushed.push((now - start, message));
         }
     }
+    flushed
 }
 
 #[test]
-fn test_config_parses_rule_with_class_no_fallthrough() {
-    // A rule with a class matcher doesn't need fallthrough
-    let json = r#"[{"class": "firefox", "layer": "browser"}]"#;
-    let result: Result<Vec<ConfigEntry>, _> = serde_json::from_str(json);
-    assert!(result.is_ok(), "Config should parse rule with class matcher");
-    if let Ok(entries) = result {
-        if let ConfigEntry::Rule(rule) = &entries[0] {
-            assert!(rule.class.is_some());
-            assert!(!rule.fallthrough);
-        } else {
-            panic!("Expected Rule entry");
-        }
+fn test_kanata_rate_limit_keeps_toggles() {
+    let start = tokio::time::Instant::now();
+    let mut limit = KanataRateLimit::new(10, start);
+    for _ in 0..10 {
+        assert!(limit.try_acquire(start));
+    }
+    limit.defer(deferred_fake_key("vk_a", "Toggle"), start);
+    limit.defer(deferred_fake_key("vk_a", "Toggle"), start);
+    let flushed = flush_rate_limit(&mut limit, start);
+    assert_eq!(
+        flushed,
+        vec![
+            (Duration::from_millis(100), deferred_fake_key("vk_a", "Toggle")),
+            (Duration::from_millis(200), deferred_fake_key("vk_a", "Toggle")),
+        ]
+    );
+    assert_eq!(limit.stats.coalesced, 0);
+}
+
+#[test]
+fn test_kanata_rate_limit_keeps_press_release_spacing() {
+    let start = tokio::time::Instant::now();
+    let mut limit = KanataRateLimit::new(10, start);
+    for _ in 0..10 {
+        assert!(limit.try_acquire(start));
     }
+    // Press, a 500ms delay step, Release
+    limit.defer(deferred_fake_key("vk_a", "Press"), start);
+    limit.defer(
+        deferred_fake_key("vk_a", "Release"),
+        start + Duration::from_millis(500),
+    );
+    let flushed = flush_rate_limit(&mut limit, start);
+    assert_eq!(
+        flushed,
+        vec![
+            (Duration::from_millis(100), deferred_fake_key("vk_a", "Press")),
+            (Duration::from_millis(600), deferred_fake_key("vk_a", "Release")),
+        ]
+    );
+    assert_eq!(limit.stats.coalesced, 0);
+}
+
+#[test]
+fn test_kanata_rate_limit_arg() {
+    let args = Args::try_parse_from(["kanata-switcher"]).unwrap();
+    assert_eq!(args.kanata_rate_limit, DEFAULT_KANATA_RATE_LIMIT);
+    let matches =
+        Args::command().get_matches_from(["kanata-switcher", "--install-autostart", "--kanata-rate-limit", "0"]);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    assert_eq!(args.kanata_rate_limit, 0);
+    assert_eq!(
+        autostart_passthrough_args(&matches, &args),
+        vec!["--kanata-rate-limit", "0"]
+    );
 }
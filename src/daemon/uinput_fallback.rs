@@ -0,0 +1,340 @@
+//! Plain key remaps while kanata is unreachable (`uinput-fallback` feature).
+//!
+//! The remapper grabs the keyboards (`EVIOCGRAB`), so their events reach this daemon only, and
+//! re-emits their keys through a virtual keyboard with the codes swapped by the current remap.
+//! Dropping it releases the keys it holds, then the grabs, and removes the virtual keyboard.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+const EV_SYN: u16 = 0;
+const EV_KEY: u16 = 1;
+const SYN_REPORT: u16 = 0;
+const KEY_A: u16 = 30;
+const KEY_SPACE: u16 = 57;
+/// Keys the virtual keyboard announces: the keyboard range, without the buttons above it
+const MAX_KEY_CODE: u16 = 255;
+/// `KEY_MAX` + 1 bits
+const KEY_BITS_LEN: usize = 96;
+const BUS_VIRTUAL: u16 = 0x06;
+/// Also keeps the device out of `keyboard_devices` (kanata's own output is "kanata" too)
+const DEVICE_NAME: &str = "kanata-switcher fallback";
+/// How often the reader thread checks whether it should stop
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+const UI_DEV_CREATE: libc::Ioctl = libc::_IO(b'U' as u32, 1);
+const UI_DEV_DESTROY: libc::Ioctl = libc::_IO(b'U' as u32, 2);
+const UI_DEV_SETUP: libc::Ioctl = libc::_IOW::<libc::uinput_setup>(b'U' as u32, 3);
+const UI_SET_EVBIT: libc::Ioctl = libc::_IOW::<libc::c_int>(b'U' as u32, 100);
+const UI_SET_KEYBIT: libc::Ioctl = libc::_IOW::<libc::c_int>(b'U' as u32, 101);
+const EVIOCGKEY: libc::Ioctl = libc::_IOR::<[u8; KEY_BITS_LEN]>(b'E' as u32, 0x18);
+const EVIOCGNAME: libc::Ioctl = libc::_IOR::<[u8; 256]>(b'E' as u32, 0x06);
+const EVIOCGBIT_KEY: libc::Ioctl =
+    libc::_IOR::<[u8; KEY_BITS_LEN]>(b'E' as u32, 0x20 + EV_KEY as u32);
+const EVIOCGRAB: libc::Ioctl = libc::_IOW::<libc::c_int>(b'E' as u32, 0x90);
+
+/// Remapped codes of the keys pressed through the virtual keyboard, so a key is released under
+/// the code it was pressed with even if the remap changed in between
+#[derive(Debug, Default)]
+pub(crate) struct KeyTranslator {
+    held: HashMap<u16, u16>,
+}
+
+impl KeyTranslator {
+    /// Code to emit for `code` going to `value` (0 release, 1 press, 2 repeat)
+    pub(crate) fn translate(&mut self, code: u16, value: i32, remap: &HashMap<u16, u16>) -> u16 {
+        match value {
+            0 => self.held.remove(&code).unwrap_or(code),
+            _ => *self
+                .held
+                .entry(code)
+                .or_insert_with(|| remap.get(&code).copied().unwrap_or(code)),
+        }
+    }
+
+    /// Empties the held keys, returning their emitted codes
+    fn release_all(&mut self) -> Vec<u16> {
+        self.held.drain().map(|(_, emitted)| emitted).collect()
+    }
+}
+
+/// Grabbed keyboards feeding a virtual keyboard, with the remap applied on the way
+pub(crate) struct UinputRemapper {
+    remap: Arc<Mutex<HashMap<u16, u16>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    devices: Vec<PathBuf>,
+}
+
+impl UinputRemapper {
+    /// Creates the virtual keyboard and grabs `devices` (empty = every keyboard). None while a
+    /// key is held on one of them: grabbing then would leave that key stuck for the desktop
+    pub(crate) fn start(
+        devices: &[PathBuf],
+        remap: HashMap<u16, u16>,
+    ) -> Result<Option<Self>, String> {
+        let paths = if devices.is_empty() {
+            keyboard_devices()
+        } else {
+            devices.to_vec()
+        };
+        if paths.is_empty() {
+            return Err("no keyboard found in /dev/input".to_string());
+        }
+        let mut inputs = Vec::new();
+        for path in &paths {
+            let file = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(path)
+                .map_err(|error| format!("cannot open {}: {}", path.display(), error))?;
+            if keys_held(&file) {
+                return Ok(None);
+            }
+            inputs.push(file);
+        }
+        let output = create_virtual_keyboard()?;
+        for (file, path) in inputs.iter().zip(&paths) {
+            // SAFETY: EVIOCGRAB takes an int by value
+            if unsafe { libc::ioctl(file.as_raw_fd(), EVIOCGRAB, 1 as libc::c_int) } < 0 {
+                return Err(format!(
+                    "cannot grab {}: {}",
+                    path.display(),
+                    std::io::Error::last_os_error()
+                ));
+            }
+        }
+
+        let remap = Arc::new(Mutex::new(remap));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let remap = remap.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || forward_keys(inputs, output, &remap, &stop))
+        };
+        Ok(Some(UinputRemapper {
+            remap,
+            stop,
+            thread: Some(thread),
+            devices: paths,
+        }))
+    }
+
+    pub(crate) fn set_remap(&self, remap: HashMap<u16, u16>) {
+        *self.remap.lock().unwrap() = remap;
+    }
+
+    pub(crate) fn devices(&self) -> &[PathBuf] {
+        &self.devices
+    }
+}
+
+impl Drop for UinputRemapper {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Whether a process named `kanata` runs on this machine (e.g. restarting, before its TCP
+/// server is up); it needs the keyboards for itself
+pub(crate) fn kanata_running() -> bool {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        std::fs::read_to_string(entry.path().join("comm"))
+            .is_ok_and(|comm| comm.trim_end() == "kanata")
+    })
+}
+
+/// Input devices with letter keys and a space bar, except virtual keyboards named after
+/// kanata (its output device and ours)
+fn keyboard_devices() -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir("/dev/input") else {
+        return Vec::new();
+    };
+    let mut devices: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("event"))
+        })
+        .filter(|path| is_keyboard(path))
+        .collect();
+    devices.sort();
+    devices
+}
+
+fn is_keyboard(path: &Path) -> bool {
+    let Ok(file) = File::open(path) else {
+        return false;
+    };
+    let mut name = [0u8; 256];
+    // SAFETY: the buffer matches the size encoded in EVIOCGNAME
+    if unsafe { libc::ioctl(file.as_raw_fd(), EVIOCGNAME, name.as_mut_ptr()) } < 0 {
+        return false;
+    }
+    if String::from_utf8_lossy(&name).starts_with("kanata") {
+        return false;
+    }
+    let mut bits = [0u8; KEY_BITS_LEN];
+    // SAFETY: the buffer matches the size encoded in EVIOCGBIT_KEY
+    if unsafe { libc::ioctl(file.as_raw_fd(), EVIOCGBIT_KEY, bits.as_mut_ptr()) } < 0 {
+        return false;
+    }
+    has_bit(&bits, KEY_A) && has_bit(&bits, KEY_SPACE)
+}
+
+fn has_bit(bits: &[u8], code: u16) -> bool {
+    bits.get(code as usize / 8)
+        .is_some_and(|byte| byte & (1 << (code % 8)) != 0)
+}
+
+fn keys_held(file: &File) -> bool {
+    let mut state = [0u8; KEY_BITS_LEN];
+    // SAFETY: the buffer matches the size encoded in EVIOCGKEY
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), EVIOCGKEY, state.as_mut_ptr()) };
+    result >= 0 && state.iter().any(|byte| *byte != 0)
+}
+
+fn create_virtual_keyboard() -> Result<File, String> {
+    let file = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open("/dev/uinput")
+        .map_err(|error| format!("cannot open /dev/uinput: {}", error))?;
+    let fd = file.as_raw_fd();
+    let failed = |what: &str| format!("{}: {}", what, std::io::Error::last_os_error());
+    // SAFETY: UI_SET_EVBIT/UI_SET_KEYBIT take an int by value
+    unsafe {
+        if libc::ioctl(fd, UI_SET_EVBIT, EV_KEY as libc::c_int) < 0 {
+            return Err(failed("cannot set up the virtual keyboard"));
+        }
+        for code in 1..=MAX_KEY_CODE {
+            if libc::ioctl(fd, UI_SET_KEYBIT, code as libc::c_int) < 0 {
+                return Err(failed("cannot set up the virtual keyboard"));
+            }
+        }
+    }
+    // SAFETY: all-zero is a valid uinput_setup
+    let mut setup: libc::uinput_setup = unsafe { std::mem::zeroed() };
+    setup.id.bustype = BUS_VIRTUAL;
+    for (slot, byte) in setup.name.iter_mut().zip(DEVICE_NAME.bytes()) {
+        *slot = byte as libc::c_char;
+    }
+    // SAFETY: UI_DEV_SETUP reads one uinput_setup; UI_DEV_CREATE takes no argument
+    unsafe {
+        if libc::ioctl(fd, UI_DEV_SETUP, &setup) < 0 || libc::ioctl(fd, UI_DEV_CREATE) < 0 {
+            return Err(failed("cannot create the virtual keyboard"));
+        }
+    }
+    Ok(file)
+}
+
+fn write_event(output: &mut File, type_: u16, code: u16, value: i32) {
+    // SAFETY: all-zero is a valid input_event; the kernel fills in the time
+    let mut event: libc::input_event = unsafe { std::mem::zeroed() };
+    event.type_ = type_;
+    event.code = code;
+    event.value = value;
+    // SAFETY: input_event is plain old data
+    let bytes = unsafe {
+        std::slice::from_raw_parts(
+            (&event as *const libc::input_event).cast::<u8>(),
+            std::mem::size_of::<libc::input_event>(),
+        )
+    };
+    let _ = output.write_all(bytes);
+}
+
+/// Reader thread: copies key and sync events from `inputs` to `output` until `stop`, then
+/// releases what it holds. The grabs and the virtual keyboard go with the closed files
+fn forward_keys(
+    mut inputs: Vec<File>,
+    mut output: File,
+    remap: &Mutex<HashMap<u16, u16>>,
+    stop: &AtomicBool,
+) {
+    let mut translator = KeyTranslator::default();
+    let event_size = std::mem::size_of::<libc::input_event>();
+    while !stop.load(Ordering::Relaxed) && !inputs.is_empty() {
+        let mut fds: Vec<libc::pollfd> = inputs
+            .iter()
+            .map(|file| libc::pollfd {
+                fd: file.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        // SAFETY: `fds` is a valid array of `fds.len()` pollfds
+        let ready = unsafe {
+            libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, STOP_POLL.as_millis() as i32)
+        };
+        if ready <= 0 {
+            continue;
+        }
+        let mut gone = Vec::new();
+        for (index, pollfd) in fds.iter().enumerate() {
+            if pollfd.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+                gone.push(index);
+                continue;
+            }
+            if pollfd.revents & libc::POLLIN == 0 {
+                continue;
+            }
+            // SAFETY: all-zero is a valid input_event
+            let mut events: [libc::input_event; 64] = unsafe { std::mem::zeroed() };
+            // SAFETY: reads at most the size of `events` into it
+            let read = unsafe {
+                libc::read(
+                    pollfd.fd,
+                    events.as_mut_ptr().cast(),
+                    std::mem::size_of_val(&events),
+                )
+            };
+            if read < 0 {
+                if std::io::Error::last_os_error().raw_os_error() == Some(libc::ENODEV) {
+                    gone.push(index);
+                }
+                continue;
+            }
+            let remap = remap.lock().unwrap().clone();
+            for event in &events[..read as usize / event_size] {
+                match event.type_ {
+                    EV_KEY if event.code <= MAX_KEY_CODE => {
+                        let code = translator.translate(event.code, event.value, &remap);
+                        write_event(&mut output, EV_KEY, code, event.value);
+                    }
+                    EV_SYN if event.code == SYN_REPORT => {
+                        write_event(&mut output, EV_SYN, SYN_REPORT, 0);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for index in gone.into_iter().rev() {
+            inputs.remove(index);
+        }
+    }
+    for code in translator.release_all() {
+        write_event(&mut output, EV_KEY, code, 0);
+    }
+    write_event(&mut output, EV_SYN, SYN_REPORT, 0);
+    // SAFETY: UI_DEV_DESTROY takes no argument
+    unsafe {
+        libc::ioctl(output.as_raw_fd(), UI_DEV_DESTROY);
+    }
+}
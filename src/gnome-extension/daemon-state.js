@@ -19,12 +19,14 @@ export function disconnectedState() {
   const status = {
     layer: EMPTY_LAYER,
     virtualKeys: [],
-    source: SOURCE_EXTERNAL
+    source: SOURCE_EXTERNAL,
+    matchedRules: []
   };
   const focusStatus = {
     layer: EMPTY_LAYER,
     virtualKeys: [],
-    source: SOURCE_FOCUS
+    source: SOURCE_FOCUS,
+    matchedRules: []
   };
   return {
     status,
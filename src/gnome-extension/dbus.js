@@ -18,3 +18,29 @@ export function unpackSingleBoolean(result) {
 
   return value;
 }
+
+// GetSettings reply (a{sv}) on top of `defaults`; unknown keys and values of another type are
+// ignored so newer or older daemons don't break the extension
+export function unpackSettings(result, defaults) {
+  if (!result) {
+    throw new Error('DBus result is required');
+  }
+
+  const unpacked = result.recursiveUnpack();
+  if (!Array.isArray(unpacked) || unpacked.length !== 1) {
+    throw new Error('DBus result must unpack to a 1-element array');
+  }
+
+  const [values] = unpacked;
+  if (values === null || typeof values !== 'object') {
+    throw new Error(`DBus result must contain a dictionary, got ${typeof values}`);
+  }
+
+  const settings = { ...defaults };
+  for (const [key, value] of Object.entries(values)) {
+    if (Object.hasOwn(defaults, key) && typeof value === typeof defaults[key]) {
+      settings[key] = value;
+    }
+  }
+  return settings;
+}
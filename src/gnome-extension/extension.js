@@ -3,16 +3,24 @@
 
 import Gio from 'gi://Gio';
 import GLib from 'gi://GLib';
+import Meta from 'gi://Meta';
+import Shell from 'gi://Shell';
 import St from 'gi://St';
 import Clutter from 'gi://Clutter';
 import { Extension } from 'resource:///org/gnome/shell/extensions/extension.js';
 import * as Main from 'resource:///org/gnome/shell/ui/main.js';
 import * as PanelMenu from 'resource:///org/gnome/shell/ui/panelMenu.js';
 import * as PopupMenu from 'resource:///org/gnome/shell/ui/popupMenu.js';
-import { formatLayerLetter, formatVirtualKeys, selectStatus } from './format.js';
-import { unpackSingleBoolean } from './dbus.js';
+import {
+  displayLayerName,
+  formatLayerLetter,
+  formatMatchedRules,
+  formatVirtualKeys,
+  selectStatus
+} from './format.js';
+import { unpackSettings, unpackSingleBoolean } from './dbus.js';
 import { disconnectedState, isDaemonOwnerAvailable } from './daemon-state.js';
-import { extractFocus } from './focus.js';
+import { extractFocus, extractWindows } from './focus.js';
 
 const DBUS_NAME = 'com.github.kanata.Switcher';
 const DBUS_PATH = '/com/github/kanata/Switcher';
@@ -26,38 +34,66 @@ const FOCUS_DBUS_XML = `
         <arg type="s" direction="out" name="class"/>
         <arg type="s" direction="out" name="title"/>
       </method>
+      <method name="GetFocusApp">
+        <arg type="s" direction="out" name="class"/>
+        <arg type="s" direction="out" name="title"/>
+        <arg type="s" direction="out" name="app"/>
+      </method>
+      <method name="GetFocusWorkspace">
+        <arg type="s" direction="out" name="class"/>
+        <arg type="s" direction="out" name="title"/>
+        <arg type="s" direction="out" name="app"/>
+        <arg type="s" direction="out" name="workspace"/>
+      </method>
+      <method name="GetFocusId">
+        <arg type="s" direction="out" name="class"/>
+        <arg type="s" direction="out" name="title"/>
+        <arg type="s" direction="out" name="app"/>
+        <arg type="s" direction="out" name="workspace"/>
+        <arg type="s" direction="out" name="id"/>
+      </method>
+      <method name="ListWindows">
+        <arg type="a(sss)" direction="out" name="windows"/>
+      </method>
+      <signal name="FocusChanged">
+        <arg type="s" name="class"/>
+        <arg type="s" name="title"/>
+        <arg type="s" name="app"/>
+        <arg type="s" name="workspace"/>
+        <arg type="s" name="id"/>
+      </signal>
     </interface>
   </node>
 `;
 
+// Display settings are owned by the daemon (GetSettings/SetSetting/SettingChanged)
 const SETTINGS_KEY_SHOW_ICON = 'show-top-bar-icon';
 const SETTINGS_KEY_FOCUS_ONLY = 'show-focus-layer-only';
+const DEFAULT_SETTINGS = {
+  [SETTINGS_KEY_SHOW_ICON]: true,
+  [SETTINGS_KEY_FOCUS_ONLY]: true
+};
 export default class KanataSwitcherExtension extends Extension {
   enable() {
-    this._settings = this.getSettings();
+    this._settings = { ...DEFAULT_SETTINGS };
     this._status = {
       layer: '',
       virtualKeys: [],
-      source: 'external'
+      source: 'external',
+      matchedRules: []
     };
     this._focusStatus = {
       layer: '',
       virtualKeys: [],
-      source: 'focus'
+      source: 'focus',
+      matchedRules: []
     };
     this._lastStatus = this._status;
+    this._layerDisplayNames = {};
+    this._ruleNames = {};
     this._paused = false;
     this._isUpdatingPauseItem = false;
 
-    this._settingsChangedId = this._settings.connect(
-      `changed::${SETTINGS_KEY_SHOW_ICON}`,
-      () => this._syncIndicator()
-    );
-    this._settingsFocusOnlyChangedId = this._settings.connect(
-      `changed::${SETTINGS_KEY_FOCUS_ONLY}`,
-      () => this._applyStatusToIndicator()
-    );
-
     this._daemonProxy = Gio.DBusProxy.new_for_bus_sync(
       Gio.BusType.SESSION,
       Gio.DBusProxyFlags.DO_NOT_AUTO_START,
@@ -78,11 +114,14 @@ export default class KanataSwitcherExtension extends Extension {
       'g-signal',
       (_proxy, _sender, signalName, parameters) => {
         if (signalName === 'StatusChanged') {
-          const [layer, virtualKeys, source] = parameters.deep_unpack();
-          this._setStatus(layer, virtualKeys, source);
+          const [layer, virtualKeys, source, matchedRules] = parameters.deep_unpack();
+          this._setStatus(layer, virtualKeys, source, matchedRules);
         } else if (signalName === 'PausedChanged') {
           const [paused] = parameters.deep_unpack();
           this._setPaused(paused);
+        } else if (signalName === 'SettingChanged') {
+          const [key, value] = parameters.recursiveUnpack();
+          this._setSetting(key, value);
         }
       }
     );
@@ -91,9 +130,16 @@ export default class KanataSwitcherExtension extends Extension {
       'notify::focus-window',
       () => this._notifyFocus()
     );
+    this._workspaceChangedId = global.workspace_manager.connect(
+      'active-workspace-changed',
+      () => this._notifyFocus()
+    );
 
     // Handle initial state at boot
     this._notifyFocus();
+    this._refreshSettingsFromDaemon();
+    this._refreshLayerDisplayNamesFromDaemon();
+    this._refreshRuleNamesFromDaemon();
     this._refreshStatusFromDaemon();
     this._refreshPausedFromDaemon();
     this._onDaemonOwnerChanged();
@@ -107,14 +153,9 @@ export default class KanataSwitcherExtension extends Extension {
       global.display.disconnect(this._signalHandlerId);
       this._signalHandlerId = null;
     }
-
-    if (this._settingsChangedId) {
-      this._settings.disconnect(this._settingsChangedId);
-      this._settingsChangedId = null;
-    }
-    if (this._settingsFocusOnlyChangedId) {
-      this._settings.disconnect(this._settingsFocusOnlyChangedId);
-      this._settingsFocusOnlyChangedId = null;
+    if (this._workspaceChangedId) {
+      global.workspace_manager.disconnect(this._workspaceChangedId);
+      this._workspaceChangedId = null;
     }
 
     if (this._daemonProxySignalId) {
@@ -137,6 +178,7 @@ export default class KanataSwitcherExtension extends Extension {
       this._indicator = null;
       this._layerLabel = null;
       this._vkLabel = null;
+      this._rulesMenuItem = null;
       this._pauseMenuItem = null;
     }
 
@@ -148,8 +190,88 @@ export default class KanataSwitcherExtension extends Extension {
   }
 
   _notifyFocus() {
-    const { windowClass, windowTitle } = this._currentFocus();
+    const { windowClass, windowTitle, desktopId, workspace, windowId } = this._currentFocus();
+
+    // Broadcast for daemons started with --dbus-suffix; the default one is called directly below
+    if (this._focusDbus) {
+      this._focusDbus.emit_signal(
+        'FocusChanged',
+        new GLib.Variant('(sssss)', [windowClass, windowTitle, desktopId, workspace, windowId])
+      );
+    }
 
+    Gio.DBus.session.call(
+      DBUS_NAME,
+      DBUS_PATH,
+      DBUS_INTERFACE,
+      'WindowFocusId',
+      new GLib.Variant('(sssss)', [windowClass, windowTitle, desktopId, workspace, windowId]),
+      null,
+      Gio.DBusCallFlags.NO_AUTO_START,
+      -1,
+      null,
+      (connection, result) => {
+        try {
+          connection.call_finish(result);
+        } catch (error) {
+          if (error.matches(Gio.DBusError, Gio.DBusError.UNKNOWN_METHOD)) {
+            // Older daemons have no WindowFocusId; send the workspace without the window ID
+            this._notifyFocusWithoutId(windowClass, windowTitle, desktopId, workspace);
+          }
+        }
+      }
+    );
+  }
+
+  _notifyFocusWithoutId(windowClass, windowTitle, desktopId, workspace) {
+    Gio.DBus.session.call(
+      DBUS_NAME,
+      DBUS_PATH,
+      DBUS_INTERFACE,
+      'WindowFocusWorkspace',
+      new GLib.Variant('(ssss)', [windowClass, windowTitle, desktopId, workspace]),
+      null,
+      Gio.DBusCallFlags.NO_AUTO_START,
+      -1,
+      null,
+      (connection, result) => {
+        try {
+          connection.call_finish(result);
+        } catch (error) {
+          if (error.matches(Gio.DBusError, Gio.DBusError.UNKNOWN_METHOD)) {
+            // Older daemons have no WindowFocusWorkspace; send the app without the workspace
+            this._notifyFocusWithoutWorkspace(windowClass, windowTitle, desktopId);
+          }
+        }
+      }
+    );
+  }
+
+  _notifyFocusWithoutWorkspace(windowClass, windowTitle, desktopId) {
+    Gio.DBus.session.call(
+      DBUS_NAME,
+      DBUS_PATH,
+      DBUS_INTERFACE,
+      'WindowFocusApp',
+      new GLib.Variant('(sss)', [windowClass, windowTitle, desktopId]),
+      null,
+      Gio.DBusCallFlags.NO_AUTO_START,
+      -1,
+      null,
+      (connection, result) => {
+        try {
+          connection.call_finish(result);
+        } catch (error) {
+          if (error.matches(Gio.DBusError, Gio.DBusError.UNKNOWN_METHOD)) {
+            // Older daemons have no WindowFocusApp; send class and title only
+            this._notifyFocusWithoutApp(windowClass, windowTitle);
+          }
+        }
+      }
+    );
+  }
+
+  _notifyFocusWithoutApp(windowClass, windowTitle) {
     Gio.DBus.session.call(
       DBUS_NAME,
       DBUS_PATH,
@@ -165,7 +287,12 @@ export default class KanataSwitcherExtension extends Extension {
   }
 
   _currentFocus() {
-    return extractFocus(global.display.focus_window);
+    const workspaceIndex = global.workspace_manager.get_active_workspace_index();
+    return extractFocus(
+      global.display.focus_window,
+      Shell.WindowTracker.get_default(),
+      Meta.prefs_get_workspace_name(workspaceIndex)
+    );
   }
 
   GetFocus() {
@@ -173,6 +300,26 @@ export default class KanataSwitcherExtension extends Extension {
     return [windowClass, windowTitle];
   }
 
+  GetFocusApp() {
+    const { windowClass, windowTitle, desktopId } = this._currentFocus();
+    return [windowClass, windowTitle, desktopId];
+  }
+
+  GetFocusWorkspace() {
+    const { windowClass, windowTitle, desktopId, workspace } = this._currentFocus();
+    return [windowClass, windowTitle, desktopId, workspace];
+  }
+
+  GetFocusId() {
+    const { windowClass, windowTitle, desktopId, workspace, windowId } = this._currentFocus();
+    return [windowClass, windowTitle, desktopId, workspace, windowId];
+  }
+
+  ListWindows() {
+    const windows = global.get_window_actors().map(actor => actor.get_meta_window());
+    return [extractWindows(windows, Shell.WindowTracker.get_default())];
+  }
+
   _refreshStatusFromDaemon() {
     if (!this._daemonProxy) {
       return;
@@ -186,15 +333,15 @@ export default class KanataSwitcherExtension extends Extension {
         -1,
         null
       );
-      const [layer, virtualKeys, source] = result.deep_unpack();
-      this._setStatus(layer, virtualKeys, source);
+      const [layer, virtualKeys, source, matchedRules] = result.deep_unpack();
+      this._setStatus(layer, virtualKeys, source, matchedRules);
     } catch (error) {
       console.error(`[KanataSwitcher] Failed to read status: ${error}`);
     }
   }
 
   _syncIndicator() {
-    const shouldShow = this._settings.get_boolean(SETTINGS_KEY_SHOW_ICON);
+    const shouldShow = this._settings[SETTINGS_KEY_SHOW_ICON];
 
     if (shouldShow && !this._indicator) {
       this._createIndicator();
@@ -206,6 +353,7 @@ export default class KanataSwitcherExtension extends Extension {
       this._indicator = null;
       this._layerLabel = null;
       this._vkLabel = null;
+      this._rulesMenuItem = null;
     }
   }
 
@@ -231,6 +379,16 @@ export default class KanataSwitcherExtension extends Extension {
     this._indicator.add_child(box);
 
     Main.panel.addToStatusArea('kanata-switcher', this._indicator, 0, 'right');
+    // Which rules put the shown layer and virtual keys there; hidden when none did
+    this._rulesMenuItem = new PopupMenu.PopupMenuItem('', { reactive: false });
+    this._indicator.menu.addMenuItem(this._rulesMenuItem);
+    // Names change with the daemon's Reload, which sends no signal; re-read them when shown
+    this._indicator.menu.connect('open-state-changed', (_menu, open) => {
+      if (open) {
+        this._refreshRuleNamesFromDaemon();
+        this._applyStatusToIndicator();
+      }
+    });
     this._pauseMenuItem = new PopupMenu.PopupSwitchMenuItem('Pause', false);
     this._pauseMenuItem.connect('toggled', (_item, state) => {
       if (this._isUpdatingPauseItem) {
@@ -250,13 +408,15 @@ export default class KanataSwitcherExtension extends Extension {
     this._applyStatusToIndicator();
   }
 
-  _setStatus(layer, virtualKeys, source) {
+  _setStatus(layer, virtualKeys, source, matchedRules = []) {
     const nextStatus = {
       layer,
       virtualKeys,
-      source
+      source,
+      matchedRules
     };
-    if (source === 'focus') {
+    // A layer pinned via PinLayer counts as the app layer
+    if (source === 'focus' || source === 'pinned') {
       this._focusStatus = nextStatus;
     }
     this._status = nextStatus;
@@ -269,16 +429,20 @@ export default class KanataSwitcherExtension extends Extension {
       return;
     }
 
-    const showFocusOnly = this._settings.get_boolean(SETTINGS_KEY_FOCUS_ONLY);
+    const showFocusOnly = this._settings[SETTINGS_KEY_FOCUS_ONLY];
     const status = this._paused
       ? this._lastStatus
       : selectStatus(showFocusOnly, this._focusStatus, this._lastStatus);
-    const layerText = formatLayerLetter(status.layer);
+    const layerText = formatLayerLetter(displayLayerName(status.layer, this._layerDisplayNames));
     const vkText = formatVirtualKeys(status.virtualKeys);
 
     this._layerLabel.set_text(layerText);
     this._vkLabel.set_text(vkText);
     this._vkLabel.visible = vkText.length > 0;
+
+    const rulesText = formatMatchedRules(status.matchedRules, this._ruleNames);
+    this._rulesMenuItem.label.set_text(`Rules: ${rulesText}`);
+    this._rulesMenuItem.visible = rulesText.length > 0;
   }
 
   _setPaused(paused) {
@@ -313,10 +477,25 @@ export default class KanataSwitcherExtension extends Extension {
       return;
     }
 
+    this._refreshSettingsFromDaemon();
+    this._refreshLayerDisplayNamesFromDaemon();
+    this._refreshRuleNamesFromDaemon();
     this._refreshStatusFromDaemon();
     this._refreshPausedFromDaemon();
   }
 
+  _setSetting(key, value) {
+    if (!Object.hasOwn(DEFAULT_SETTINGS, key) || typeof value !== typeof DEFAULT_SETTINGS[key]) {
+      return;
+    }
+    this._settings = { ...this._settings, [key]: value };
+    if (key === SETTINGS_KEY_SHOW_ICON) {
+      this._syncIndicator();
+    } else {
+      this._applyStatusToIndicator();
+    }
+  }
+
   _setDisconnected() {
     const state = disconnectedState();
     this._status = state.status;
@@ -391,4 +570,68 @@ export default class KanataSwitcherExtension extends Extension {
       console.error(`[KanataSwitcher] Failed to read pause state: ${error}`);
     }
   }
+
+  _refreshSettingsFromDaemon() {
+    if (!this._daemonProxy) {
+      return;
+    }
+
+    try {
+      const result = this._daemonProxy.call_sync(
+        'GetSettings',
+        null,
+        Gio.DBusCallFlags.NO_AUTO_START,
+        -1,
+        null
+      );
+      this._settings = unpackSettings(result, DEFAULT_SETTINGS);
+    } catch (error) {
+      // Daemon not running yet or too old: keep the current settings
+      return;
+    }
+    this._syncIndicator();
+    this._applyStatusToIndicator();
+  }
+
+  _refreshLayerDisplayNamesFromDaemon() {
+    if (!this._daemonProxy) {
+      return;
+    }
+
+    try {
+      const result = this._daemonProxy.call_sync(
+        'GetLayerDisplayNames',
+        null,
+        Gio.DBusCallFlags.NO_AUTO_START,
+        -1,
+        null
+      );
+      const [displayNames] = result.deep_unpack();
+      this._layerDisplayNames = displayNames;
+    } catch (error) {
+      // Older daemons have no GetLayerDisplayNames; show the kanata names
+      this._layerDisplayNames = {};
+    }
+  }
+
+  _refreshRuleNamesFromDaemon() {
+    if (!this._daemonProxy) {
+      return;
+    }
+
+    try {
+      const result = this._daemonProxy.call_sync(
+        'GetRuleNames',
+        null,
+        Gio.DBusCallFlags.NO_AUTO_START,
+        -1,
+        null
+      );
+      const [ruleNames] = result.deep_unpack();
+      this._ruleNames = ruleNames;
+    } catch (error) {
+      // Older daemons have no GetRuleNames; show rule numbers
+      this._ruleNames = {};
+    }
+  }
 }
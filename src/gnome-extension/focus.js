@@ -1,6 +1,8 @@
-export function extractFocus(window) {
+export function extractFocus(window, windowTracker = null, workspace = '') {
   let windowClass = '';
   let windowTitle = '';
+  let desktopId = '';
+  let windowId = '';
 
   if (window) {
     const classValue = window.get_wm_class();
@@ -11,7 +13,25 @@ export function extractFocus(window) {
     if (titleValue) {
       windowTitle = titleValue;
     }
+    const app = windowTracker ? windowTracker.get_window_app(window) : null;
+    const appId = app ? app.get_id() : null;
+    if (appId) {
+      desktopId = appId;
+    }
+    // Mutter's stable per-window ID (a 64-bit number)
+    if (typeof window.get_id === 'function') {
+      windowId = String(window.get_id());
+    }
   }
 
-  return { windowClass, windowTitle };
+  return { windowClass, windowTitle, desktopId, workspace: workspace || '', windowId };
+}
+
+export function extractWindows(windows, windowTracker = null) {
+  return windows
+    .filter(window => window && !window.is_skip_taskbar())
+    .map(window => {
+      const { windowClass, windowTitle, desktopId } = extractFocus(window, windowTracker);
+      return [windowClass, windowTitle, desktopId];
+    });
 }
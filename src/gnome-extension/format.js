@@ -15,6 +15,13 @@ export function formatLayerLetter(layerName) {
   return trimmed[0].toUpperCase();
 }
 
+export function displayLayerName(layerName, displayNames) {
+  if (displayNames && typeof displayNames[layerName] === 'string') {
+    return displayNames[layerName];
+  }
+  return layerName;
+}
+
 export function formatVirtualKeys(virtualKeys) {
   if (!Array.isArray(virtualKeys)) {
     return '';
@@ -51,3 +58,30 @@ export function formatVirtualKeys(virtualKeys) {
 export function selectStatus(showFocusOnly, focusStatus, lastStatus) {
   return showFocusOnly ? focusStatus : lastStatus;
 }
+
+// Rule indices as sent by the daemon: -1 is on_native_terminal, -2 on_session_inactive,
+// -3 on_locked.
+// Rules with a `name` (from GetRuleNames) are shown by it
+export function formatMatchedRules(matchedRules, ruleNames) {
+  if (!Array.isArray(matchedRules)) {
+    return '';
+  }
+
+  return matchedRules
+    .map(index => {
+      if (ruleNames && typeof ruleNames[index] === 'string') {
+        return `'${ruleNames[index]}'`;
+      }
+      if (index === -1) {
+        return 'on_native_terminal';
+      }
+      if (index === -2) {
+        return 'on_session_inactive';
+      }
+      if (index === -3) {
+        return 'on_locked';
+      }
+      return `#${index + 1}`;
+    })
+    .join(', ');
+}
@@ -1,63 +1,123 @@
 import Adw from 'gi://Adw';
 import Gio from 'gi://Gio';
+import GLib from 'gi://GLib';
 import Gtk from 'gi://Gtk';
 import { ExtensionPreferences } from 'resource:///org/gnome/Shell/Extensions/js/extensions/prefs.js';
+import { unpackSettings } from './dbus.js';
 
+const DBUS_NAME = 'com.github.kanata.Switcher';
+const DBUS_PATH = '/com/github/kanata/Switcher';
+const DBUS_INTERFACE = 'com.github.kanata.Switcher';
+
+// Settings live in the daemon; this dialog reads and writes them over DBus
 const SETTINGS_KEY_SHOW_ICON = 'show-top-bar-icon';
 const SETTINGS_KEY_FOCUS_ONLY = 'show-focus-layer-only';
+const DEFAULT_SETTINGS = {
+  [SETTINGS_KEY_SHOW_ICON]: true,
+  [SETTINGS_KEY_FOCUS_ONLY]: true
+};
+
+function readSettings(proxy) {
+  try {
+    const result = proxy.call_sync(
+      'GetSettings',
+      null,
+      Gio.DBusCallFlags.NO_AUTO_START,
+      -1,
+      null
+    );
+    return unpackSettings(result, DEFAULT_SETTINGS);
+  } catch (error) {
+    return null;
+  }
+}
+
+function writeSetting(proxy, key, value) {
+  proxy.call(
+    'SetSetting',
+    new GLib.Variant('(sv)', [key, new GLib.Variant('b', value)]),
+    Gio.DBusCallFlags.NO_AUTO_START,
+    -1,
+    null,
+    (source, result) => {
+      try {
+        source.call_finish(result);
+      } catch (error) {
+        console.error(`[KanataSwitcher] Failed to change ${key}: ${error}`);
+      }
+    }
+  );
+}
 
 export default class KanataSwitcherPreferences extends ExtensionPreferences {
   fillPreferencesWindow(window) {
-    const settings = this.getSettings();
+    const proxy = Gio.DBusProxy.new_for_bus_sync(
+      Gio.BusType.SESSION,
+      Gio.DBusProxyFlags.DO_NOT_AUTO_START,
+      null,
+      DBUS_NAME,
+      DBUS_PATH,
+      DBUS_INTERFACE,
+      null
+    );
+    const settings = readSettings(proxy);
 
     const page = new Adw.PreferencesPage({
       title: 'Kanata Switcher'
     });
     const group = new Adw.PreferencesGroup({
-      title: 'Top Bar'
-    });
-
-    const row = new Adw.ActionRow({
-      title: 'Show top bar icon',
-      subtitle: 'Display the active layer and virtual key status'
+      title: 'Top Bar',
+      description: settings ? '' : 'Start the kanata-switcher daemon to change these settings'
     });
 
-    const toggle = new Gtk.Switch({
-      active: settings.get_boolean(SETTINGS_KEY_SHOW_ICON),
-      valign: Gtk.Align.CENTER
-    });
+    const toggles = {};
+    const addRow = (key, title, subtitle) => {
+      const row = new Adw.ActionRow({ title, subtitle });
+      const toggle = new Gtk.Switch({
+        active: (settings ?? DEFAULT_SETTINGS)[key],
+        sensitive: settings !== null,
+        valign: Gtk.Align.CENTER
+      });
+      toggle.connect('notify::active', () => {
+        if (toggle.active !== toggles[key].daemonValue) {
+          toggles[key].daemonValue = toggle.active;
+          writeSetting(proxy, key, toggle.active);
+        }
+      });
+      toggles[key] = { toggle, daemonValue: toggle.active };
+      row.add_suffix(toggle);
+      row.activatable_widget = toggle;
+      group.add(row);
+    };
 
-    settings.bind(
+    addRow(
       SETTINGS_KEY_SHOW_ICON,
-      toggle,
-      'active',
-      Gio.SettingsBindFlags.DEFAULT
+      'Show top bar icon',
+      'Display the active layer and virtual key status'
+    );
+    addRow(
+      SETTINGS_KEY_FOCUS_ONLY,
+      'Show app layer only',
+      'Show the layer from the current app'
     );
 
-    row.add_suffix(toggle);
-    row.activatable_widget = toggle;
-    group.add(row);
-
-    const focusRow = new Adw.ActionRow({
-      title: 'Show app layer only',
-      subtitle: 'Show the layer from the current app'
+    // Changes made elsewhere (tray menu, settings window) while the dialog is open
+    const signalId = proxy.connect('g-signal', (_proxy, _sender, signalName, parameters) => {
+      if (signalName !== 'SettingChanged') {
+        return;
+      }
+      const [key, value] = parameters.recursiveUnpack();
+      const entry = toggles[key];
+      if (entry && typeof value === 'boolean') {
+        entry.daemonValue = value;
+        entry.toggle.active = value;
+      }
     });
-
-    const focusToggle = new Gtk.Switch({
-      active: settings.get_boolean(SETTINGS_KEY_FOCUS_ONLY),
-      valign: Gtk.Align.CENTER
+    window.connect('close-request', () => {
+      proxy.disconnect(signalId);
+      return false;
     });
 
-    settings.bind(
-      SETTINGS_KEY_FOCUS_ONLY,
-      focusToggle,
-      'active',
-      Gio.SettingsBindFlags.DEFAULT
-    );
-
-    focusRow.add_suffix(focusToggle);
-    focusRow.activatable_widget = focusToggle;
-    group.add(focusRow);
     page.add(group);
     window.add(page);
   }
@@ -0,0 +1,483 @@
+use clap::{Parser, Subcommand};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use zbus::blocking::{Connection, Proxy};
+
+mod monitor;
+
+// Must match the daemon's DBus service (src/daemon/main.rs)
+const DBUS_NAME: &str = "com.github.kanata.Switcher";
+const DBUS_PATH: &str = "/com/github/kanata/Switcher";
+const DBUS_INTERFACE: &str = "com.github.kanata.Switcher";
+
+type CtlResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// === CLI ===
+
+#[derive(Parser)]
+#[command(name = "kswitchctl")]
+#[command(about = "Control a running kanata-switcher daemon over DBus")]
+struct Args {
+    /// Control the daemon started with `kanata-switcher --dbus-suffix SUFFIX`
+    #[arg(long, global = true, value_name = "SUFFIX")]
+    dbus_suffix: Option<String>,
+
+    #[command(subcommand)]
+    command: CtlCommand,
+}
+
+#[derive(Subcommand, Debug, PartialEq, Eq)]
+enum CtlCommand {
+    /// Print the current layer, held virtual keys and pause state
+    Status {
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Pause the daemon (releases managed virtual keys, switches to default layer)
+    Pause,
+    /// Resume focus-based switching
+    Unpause,
+    /// Restart the daemon (reloads config)
+    Restart,
+    /// Re-read the config's rules without restarting (kanata connection and pause state stay)
+    Reload,
+    /// Switch kanata to a layer until the next focus change
+    SetLayer { layer: String },
+    /// Print a line for every status change until interrupted
+    Watch {
+        /// Print as JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print how many focus events each rule has matched since the daemon (re)started
+    Rules {
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print how many messages the kanata rate limit (--kanata-rate-limit) deferred and coalesced
+    Stats {
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the daemon's focus backend and which optional window data it reports
+    Backend {
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the daemon's recent log lines (kept in memory, also without a terminal or journal)
+    Logs {
+        /// Only the last N lines
+        #[arg(short = 'n', long, value_name = "N")]
+        lines: Option<u32>,
+        /// Keep printing new lines until interrupted
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// Interactive view of focus events, matched rules, layer and kanata connection
+    /// (p: pause/unpause, 1-9: set layer, r: restart, q: quit)
+    Monitor,
+}
+
+// === Status ===
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct Status {
+    layer: String,
+    /// Display name from the config's `layer_names`, if the layer has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    layer_display: Option<String>,
+    virtual_keys: Vec<String>,
+    source: String,
+    /// Rule indices from the daemon, labelled in `rules`
+    #[serde(skip)]
+    matched_rules: Vec<i32>,
+    /// Rules behind the focus layer and virtual keys: their `name`, else `#N`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    rules: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    paused: Option<bool>,
+    /// Degraded-mode problems from `GetHealth` ("component: problem")
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    problems: Vec<String>,
+}
+
+impl Status {
+    /// `GetStatus` reply or `StatusChanged` body; daemons before matched rules were added send
+    /// no rule list
+    fn from_dbus(body: &zbus::message::Body) -> CtlResult<Self> {
+        let (layer, virtual_keys, source, matched_rules) = body
+            .deserialize::<(String, Vec<String>, String, Vec<i32>)>()
+            .or_else(|_| {
+                body.deserialize::<(String, Vec<String>, String)>()
+                    .map(|(layer, virtual_keys, source)| (layer, virtual_keys, source, Vec::new()))
+            })?;
+        Ok(Self {
+            layer,
+            layer_display: None,
+            virtual_keys,
+            source,
+            matched_rules,
+            rules: Vec::new(),
+            paused: None,
+            problems: Vec::new(),
+        }
+        .with_rule_names(&RuleNames::new()))
+    }
+
+    fn with_display_names(self, display_names: &BTreeMap<String, String>) -> Self {
+        Self {
+            layer_display: display_names.get(&self.layer).cloned(),
+            ..self
+        }
+    }
+
+    fn with_rule_names(self, rule_names: &RuleNames) -> Self {
+        Self {
+            rules: self
+                .matched_rules
+                .iter()
+                .map(|&index| rule_label(index, rule_names))
+                .collect(),
+            ..self
+        }
+    }
+
+    fn format_line(&self) -> String {
+        let mut line = match &self.layer_display {
+            Some(display) => format!("{} ({}, {})", display, self.layer, self.source),
+            None => format!("{} ({})", self.layer, self.source),
+        };
+        if !self.virtual_keys.is_empty() {
+            line.push_str(&format!(" [{}]", self.virtual_keys.join(", ")));
+        }
+        if !self.rules.is_empty() {
+            line.push_str(&format!(" via {}", self.rules.join(", ")));
+        }
+        if self.paused == Some(true) {
+            line.push_str(" paused");
+        }
+        if !self.problems.is_empty() {
+            line.push_str(&format!(" degraded ({})", self.problems.join("; ")));
+        }
+        line
+    }
+
+    fn format(&self, json: bool) -> String {
+        if json {
+            serde_json::to_string(self).expect("status serialization cannot fail")
+        } else {
+            self.format_line()
+        }
+    }
+}
+
+// === Rule Stats ===
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct RuleStat {
+    /// "#N" (1-based, like config validation messages), "on_native_terminal",
+    /// "on_session_inactive" or "on_locked"
+    rule: String,
+    /// The rule's `name` from the config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    hits: u64,
+    summary: String,
+}
+
+impl RuleStat {
+    fn from_dbus((index, summary, hits): (i32, String, u64), rule_names: &RuleNames) -> Self {
+        Self {
+            rule: rule_number(index),
+            name: rule_names.get(&index).cloned(),
+            hits,
+            summary,
+        }
+    }
+
+    fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} '{}'", self.rule, name),
+            None => self.rule.clone(),
+        }
+    }
+}
+
+/// Rule index as sent by the daemon -> the rule's `name` (`GetRuleNames`)
+pub(crate) type RuleNames = BTreeMap<i32, String>;
+
+/// Number of a rule index sent by the daemon: -1 is the on_native_terminal rule, -2 the
+/// on_session_inactive rule and -3 the on_locked rule
+fn rule_number(index: i32) -> String {
+    match index {
+        -1 => "on_native_terminal".to_string(),
+        -2 => "on_session_inactive".to_string(),
+        -3 => "on_locked".to_string(),
+        // Same 1-based numbering as config validation messages
+        _ => format!("#{}", index + 1),
+    }
+}
+
+/// The rule's quoted `name` if it has one, else its number
+pub(crate) fn rule_label(index: i32, rule_names: &RuleNames) -> String {
+    match rule_names.get(&index) {
+        Some(name) => format!("'{}'", name),
+        None => rule_number(index),
+    }
+}
+
+fn format_rule_stats(stats: &[RuleStat], json: bool) -> String {
+    if json {
+        return serde_json::to_string(stats).expect("rule stats serialization cannot fail");
+    }
+    if stats.is_empty() {
+        return "no rules configured".to_string();
+    }
+    let labels: Vec<String> = stats.iter().map(RuleStat::label).collect();
+    let rule_width = labels.iter().map(|label| label.chars().count()).max().unwrap_or(0);
+    let hits_width = stats
+        .iter()
+        .map(|stat| stat.hits.to_string().len())
+        .max()
+        .unwrap_or(0);
+    stats
+        .iter()
+        .zip(&labels)
+        .map(|(stat, label)| {
+            format!(
+                "{:<rule_width$}  {:>hits_width$}  {}",
+                label, stat.hits, stat.summary
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// === Rate Limit Stats ===
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct RateLimitStats {
+    /// Messages per second, 0 = unlimited
+    limit: u32,
+    sent: u64,
+    deferred: u64,
+    coalesced: u64,
+}
+
+impl RateLimitStats {
+    fn from_dbus((limit, sent, deferred, coalesced): (u32, u64, u64, u64)) -> Self {
+        Self {
+            limit,
+            sent,
+            deferred,
+            coalesced,
+        }
+    }
+
+    fn format(&self, json: bool) -> String {
+        if json {
+            return serde_json::to_string(self).expect("rate limit stats serialization cannot fail");
+        }
+        if self.limit == 0 {
+            return "rate limit: off".to_string();
+        }
+        format!(
+            "rate limit: {}/s\nsent: {}\ndeferred: {}\ncoalesced: {}",
+            self.limit, self.sent, self.deferred, self.coalesced
+        )
+    }
+}
+
+// === Backend Info ===
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct BackendInfo {
+    backend: String,
+    /// Capability name -> whether the backend reports it
+    capabilities: BTreeMap<String, bool>,
+}
+
+impl BackendInfo {
+    fn from_dbus((backend, capabilities): (String, BTreeMap<String, bool>)) -> Self {
+        Self {
+            backend,
+            capabilities,
+        }
+    }
+
+    fn format(&self, json: bool) -> String {
+        if json {
+            return serde_json::to_string(self).expect("backend info serialization cannot fail");
+        }
+        let supported = |value: bool| if value { "yes" } else { "no" };
+        std::iter::once(format!("backend: {}", self.backend))
+            .chain(
+                self.capabilities
+                    .iter()
+                    .map(|(name, value)| format!("{}: {}", name, supported(*value))),
+            )
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+// === DBus ===
+
+/// Session bus connection plus the bus name and object path of one daemon instance
+pub(crate) struct Daemon {
+    connection: Connection,
+    name: String,
+    path: String,
+}
+
+impl Daemon {
+    fn connect(dbus_suffix: Option<&str>) -> CtlResult<Self> {
+        let (name, path) = daemon_address(dbus_suffix);
+        Ok(Self {
+            connection: Connection::session()?,
+            name,
+            path,
+        })
+    }
+}
+
+/// Bus name and object path of the daemon started with `--dbus-suffix` (same scheme as the daemon)
+fn daemon_address(dbus_suffix: Option<&str>) -> (String, String) {
+    match dbus_suffix {
+        Some(suffix) => (
+            format!("{}.{}", DBUS_NAME, suffix),
+            format!("{}/{}", DBUS_PATH, suffix),
+        ),
+        None => (DBUS_NAME.to_string(), DBUS_PATH.to_string()),
+    }
+}
+
+fn daemon_proxy(daemon: &Daemon) -> CtlResult<Proxy<'static>> {
+    Ok(Proxy::new(
+        &daemon.connection,
+        daemon.name.clone(),
+        daemon.path.clone(),
+        DBUS_INTERFACE,
+    )?)
+}
+
+fn fetch_status(proxy: &Proxy<'_>) -> CtlResult<Status> {
+    let status = Status::from_dbus(&proxy.call_method("GetStatus", &())?.body())?;
+    let paused: bool = proxy.call("GetPaused", &())?;
+    // Older daemons have no GetHealth
+    let problems = proxy
+        .call::<_, _, (bool, Vec<String>)>("GetHealth", &())
+        .map(|(_, problems)| problems)
+        .unwrap_or_default();
+    Ok(Status {
+        paused: Some(paused),
+        problems,
+        ..status
+    }
+    .with_display_names(&fetch_layer_display_names(proxy))
+    .with_rule_names(&fetch_rule_names(proxy)))
+}
+
+/// Rule index -> `name`; empty for older daemons without GetRuleNames
+pub(crate) fn fetch_rule_names(proxy: &Proxy<'_>) -> RuleNames {
+    proxy.call("GetRuleNames", &()).unwrap_or_default()
+}
+
+/// Kanata layer name -> display name; empty for older daemons without GetLayerDisplayNames
+fn fetch_layer_display_names(proxy: &Proxy<'_>) -> BTreeMap<String, String> {
+    proxy
+        .call("GetLayerDisplayNames", &())
+        .unwrap_or_default()
+}
+
+fn watch(proxy: &Proxy<'_>, json: bool) -> CtlResult<()> {
+    let signals = proxy.receive_signal("StatusChanged")?;
+    let display_names = fetch_layer_display_names(proxy);
+    let rule_names = fetch_rule_names(proxy);
+    println!("{}", fetch_status(proxy)?.format(json));
+    for message in signals {
+        let status = Status::from_dbus(&message.body())?
+            .with_display_names(&display_names)
+            .with_rule_names(&rule_names);
+        println!("{}", status.format(json));
+    }
+    Ok(())
+}
+
+fn logs(proxy: &Proxy<'_>, lines: Option<u32>, follow: bool) -> CtlResult<()> {
+    // Subscribe first so no line falls between the history and the live feed
+    let signals = if follow {
+        Some(proxy.receive_signal("LogLine")?)
+    } else {
+        None
+    };
+    let recent: Vec<String> = proxy.call("GetRecentLogs", &(lines.unwrap_or(0),))?;
+    for line in recent {
+        println!("{}", line);
+    }
+    for message in signals.into_iter().flatten() {
+        let line: String = message.body().deserialize()?;
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn run(command: CtlCommand, dbus_suffix: Option<&str>) -> CtlResult<()> {
+    let daemon = Daemon::connect(dbus_suffix)?;
+    let proxy = daemon_proxy(&daemon)?;
+    match command {
+        CtlCommand::Status { json } => {
+            println!("{}", fetch_status(&proxy)?.format(json));
+        }
+        CtlCommand::Pause => proxy.call::<_, _, ()>("Pause", &())?,
+        CtlCommand::Unpause => proxy.call::<_, _, ()>("Unpause", &())?,
+        CtlCommand::Restart => proxy.call::<_, _, ()>("Restart", &())?,
+        CtlCommand::Reload => proxy.call::<_, _, ()>("Reload", &())?,
+        CtlCommand::SetLayer { layer } => {
+            let changed: bool = proxy.call("SetLayer", &(layer.as_str(),))?;
+            if !changed {
+                return Err(format!(
+                    "layer \"{}\" was not applied (unknown layer, another layer pinned or kanata not connected)",
+                    layer
+                )
+                .into());
+            }
+        }
+        CtlCommand::Watch { json } => watch(&proxy, json)?,
+        CtlCommand::Rules { json } => {
+            let stats: Vec<(i32, String, u64)> = proxy.call("GetRuleStats", &())?;
+            let rule_names = fetch_rule_names(&proxy);
+            let stats: Vec<RuleStat> = stats
+                .into_iter()
+                .map(|stat| RuleStat::from_dbus(stat, &rule_names))
+                .collect();
+            println!("{}", format_rule_stats(&stats, json));
+        }
+        CtlCommand::Stats { json } => {
+            let stats: (u32, u64, u64, u64) = proxy.call("GetRateLimitStats", &())?;
+            println!("{}", RateLimitStats::from_dbus(stats).format(json));
+        }
+        CtlCommand::Backend { json } => {
+            let info: (String, BTreeMap<String, bool>) = proxy.call("GetBackendInfo", &())?;
+            println!("{}", BackendInfo::from_dbus(info).format(json));
+        }
+        CtlCommand::Logs { lines, follow } => logs(&proxy, lines, follow)?,
+        CtlCommand::Monitor => monitor::run(&daemon)?,
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args.command, args.dbus_suffix.as_deref()) {
+        eprintln!("kswitchctl: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,410 @@
+//! `kswitchctl monitor`: live view of focus events, matched rules, layer and kanata state.
+//!
+//! Signals are read on helper threads (blocking zbus iterators) and forwarded to the
+//! UI loop over a channel; kanata connection state and layer names are polled.
+
+use super::{
+    CtlResult, Daemon, RuleNames, Status, daemon_proxy, fetch_layer_display_names,
+    fetch_rule_names, fetch_status, rule_label,
+};
+use ratatui::DefaultTerminal;
+use ratatui::Frame;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, Paragraph};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const DAEMON_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_FOCUS_EVENTS: usize = 200;
+/// Number keys 1-9 select layers from the listed layer names
+const MAX_LAYER_SHORTCUTS: usize = 9;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FocusEventLine {
+    pub(crate) class: String,
+    pub(crate) title: String,
+    pub(crate) native_terminal: bool,
+    /// Rule indices from the daemon; -1 is the on_native_terminal rule, -2 on_session_inactive,
+    /// -3 on_locked
+    pub(crate) matched_rules: Vec<i32>,
+    /// The backend's window ID; empty when unknown (or from daemons that don't send it)
+    pub(crate) window_id: String,
+}
+
+impl FocusEventLine {
+    pub(crate) fn format_rules(&self, rule_names: &RuleNames) -> String {
+        if self.matched_rules.is_empty() {
+            return "no rule".to_string();
+        }
+        self.matched_rules
+            .iter()
+            .map(|&index| rule_label(index, rule_names))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub(crate) fn format_window(&self) -> String {
+        if self.native_terminal {
+            "(native terminal)".to_string()
+        } else if self.matched_rules == [-2] {
+            "(session inactive)".to_string()
+        } else if self.matched_rules == [-3] {
+            "(screen locked)".to_string()
+        } else if self.class.is_empty() && self.title.is_empty() {
+            "(no focus)".to_string()
+        } else if self.window_id.is_empty() {
+            format!("{} \"{}\"", self.class, self.title)
+        } else {
+            format!("{} \"{}\" [{}]", self.class, self.title, self.window_id)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum MonitorEvent {
+    Status(Status),
+    Paused(bool),
+    Focus(FocusEventLine),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum MonitorAction {
+    TogglePause,
+    SetLayer(String),
+    Restart,
+    Quit,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct MonitorState {
+    pub(crate) status: Option<Status>,
+    pub(crate) kanata_connected: Option<bool>,
+    pub(crate) layers: Vec<String>,
+    /// Kanata layer name -> display name (`layer_names` in the daemon config)
+    pub(crate) layer_display_names: BTreeMap<String, String>,
+    /// Rule index -> `name` (`name` on rules in the daemon config)
+    pub(crate) rule_names: RuleNames,
+    /// Newest first
+    pub(crate) focus_events: VecDeque<FocusEventLine>,
+    pub(crate) message: Option<String>,
+}
+
+impl MonitorState {
+    pub(crate) fn apply(&mut self, event: MonitorEvent) {
+        match event {
+            MonitorEvent::Status(status) => {
+                let status = status
+                    .with_display_names(&self.layer_display_names)
+                    .with_rule_names(&self.rule_names);
+                // StatusChanged carries no pause state or health; keep the last known values
+                let previous = self.status.take();
+                let (paused, problems) = match (status.paused, previous) {
+                    (None, Some(previous)) => (previous.paused, previous.problems),
+                    _ => (status.paused, status.problems),
+                };
+                self.status = Some(Status {
+                    paused,
+                    problems,
+                    ..status
+                });
+            }
+            MonitorEvent::Paused(paused) => {
+                if let Some(status) = self.status.as_mut() {
+                    status.paused = Some(paused);
+                }
+            }
+            MonitorEvent::Focus(line) => {
+                self.focus_events.push_front(line);
+                self.focus_events.truncate(MAX_FOCUS_EVENTS);
+            }
+        }
+    }
+
+    fn is_paused(&self) -> bool {
+        self.status.as_ref().and_then(|s| s.paused) == Some(true)
+    }
+
+    pub(crate) fn key_action(&self, key: KeyCode) -> Option<MonitorAction> {
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => Some(MonitorAction::Quit),
+            KeyCode::Char('p') => Some(MonitorAction::TogglePause),
+            KeyCode::Char('r') => Some(MonitorAction::Restart),
+            KeyCode::Char(c @ '1'..='9') => {
+                let index = c.to_digit(10)? as usize - 1;
+                self.layers
+                    .get(index)
+                    .map(|layer| MonitorAction::SetLayer(layer.clone()))
+            }
+            _ => None,
+        }
+    }
+}
+
+// === Rendering ===
+
+fn yes_no_span(value: Option<bool>, yes: &str, no: &str) -> Span<'static> {
+    match value {
+        Some(true) => Span::styled(yes.to_string(), Style::default().fg(Color::Green)),
+        Some(false) => Span::styled(no.to_string(), Style::default().fg(Color::Red)),
+        None => Span::raw("unknown"),
+    }
+}
+
+pub(crate) fn render(frame: &mut Frame, state: &MonitorState) {
+    let [header_area, layers_area, events_area, help_area] = Layout::vertical([
+        Constraint::Length(5),
+        Constraint::Length(3),
+        Constraint::Min(3),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let bold = Style::default().add_modifier(Modifier::BOLD);
+    let header = match &state.status {
+        Some(status) => {
+            let virtual_keys = if status.virtual_keys.is_empty() {
+                "(none)".to_string()
+            } else {
+                status.virtual_keys.join(", ")
+            };
+            vec![
+                Line::from(vec![
+                    Span::styled("Layer: ", bold),
+                    Span::raw(format!(
+                        "{} ({})",
+                        status.layer_display.as_deref().unwrap_or(&status.layer),
+                        status.source
+                    )),
+                    Span::raw(if status.rules.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" via {}", status.rules.join(", "))
+                    }),
+                ]),
+                Line::from(vec![
+                    Span::styled("Virtual keys: ", bold),
+                    Span::raw(virtual_keys),
+                ]),
+                Line::from(vec![
+                    Span::styled("Kanata: ", bold),
+                    yes_no_span(state.kanata_connected, "connected", "disconnected"),
+                    Span::raw("    "),
+                    Span::styled("Switching: ", bold),
+                    yes_no_span(status.paused.map(|paused| !paused), "active", "paused"),
+                    Span::raw("    "),
+                    Span::styled("Health: ", bold),
+                    yes_no_span(Some(status.problems.is_empty()), "ok", "degraded"),
+                ]),
+            ]
+        }
+        None => vec![Line::from("Daemon not reachable")],
+    };
+    frame.render_widget(
+        Paragraph::new(header).block(Block::bordered().title("kanata-switcher")),
+        header_area,
+    );
+
+    let layers: Vec<Span> = state
+        .layers
+        .iter()
+        .take(MAX_LAYER_SHORTCUTS)
+        .enumerate()
+        .map(|(index, layer)| {
+            let current = state.status.as_ref().is_some_and(|s| &s.layer == layer);
+            let style = if current { bold } else { Style::default() };
+            let name = state.layer_display_names.get(layer).unwrap_or(layer);
+            Span::styled(format!("{}:{}  ", index + 1, name), style)
+        })
+        .collect();
+    frame.render_widget(
+        Paragraph::new(Line::from(layers)).block(Block::bordered().title("Layers")),
+        layers_area,
+    );
+
+    let events: Vec<ListItem> = state
+        .focus_events
+        .iter()
+        .map(|event| {
+            ListItem::new(Line::from(vec![
+                Span::raw(event.format_window()),
+                Span::raw(" -> "),
+                Span::styled(event.format_rules(&state.rule_names), Style::default().fg(Color::Cyan)),
+            ]))
+        })
+        .collect();
+    frame.render_widget(
+        List::new(events).block(Block::bordered().title("Focus events")),
+        events_area,
+    );
+
+    let help = state
+        .message
+        .clone()
+        .unwrap_or_else(|| "p pause/unpause  1-9 set layer  r restart  q quit".to_string());
+    frame.render_widget(Paragraph::new(help), help_area);
+}
+
+// === Daemon I/O ===
+
+fn forward_signal<F>(
+    daemon: &Daemon,
+    name: &'static str,
+    sender: Sender<MonitorEvent>,
+    map: F,
+) -> CtlResult<()>
+where
+    F: Fn(&zbus::Message) -> Option<MonitorEvent> + Send + 'static,
+{
+    let proxy = daemon_proxy(daemon)?;
+    let signals = proxy.receive_signal(name)?;
+    std::thread::spawn(move || {
+        // Keep the proxy alive as long as the signal iterator
+        let _proxy = proxy;
+        for message in signals {
+            if let Some(event) = map(&message)
+                && sender.send(event).is_err()
+            {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+fn subscribe(daemon: &Daemon) -> CtlResult<Receiver<MonitorEvent>> {
+    let (sender, receiver) = mpsc::channel();
+    forward_signal(daemon, "StatusChanged", sender.clone(), |message| {
+        Status::from_dbus(&message.body()).ok().map(MonitorEvent::Status)
+    })?;
+    forward_signal(daemon, "PausedChanged", sender.clone(), |message| {
+        let paused: bool = message.body().deserialize().ok()?;
+        Some(MonitorEvent::Paused(paused))
+    })?;
+    forward_signal(daemon, "FocusChanged", sender, |message| {
+        let body = message.body();
+        // Daemons before window IDs send the first four fields only
+        let (class, title, native_terminal, matched_rules, window_id) = body
+            .deserialize::<(String, String, bool, Vec<i32>, String)>()
+            .or_else(|_| {
+                body.deserialize::<(String, String, bool, Vec<i32>)>()
+                    .map(|(class, title, native, rules)| (class, title, native, rules, String::new()))
+            })
+            .ok()?;
+        Some(MonitorEvent::Focus(FocusEventLine {
+            class,
+            title,
+            native_terminal,
+            matched_rules,
+            window_id,
+        }))
+    })?;
+    Ok(receiver)
+}
+
+fn poll_daemon(daemon: &Daemon, state: &mut MonitorState) {
+    let Ok(proxy) = daemon_proxy(daemon) else {
+        return;
+    };
+    state.layer_display_names = fetch_layer_display_names(&proxy);
+    state.rule_names = fetch_rule_names(&proxy);
+    match fetch_status(&proxy) {
+        Ok(status) => state.apply(MonitorEvent::Status(status)),
+        Err(_) => {
+            state.status = None;
+            state.kanata_connected = None;
+            return;
+        }
+    }
+    state.kanata_connected = proxy.call("GetKanataConnected", &()).ok();
+    if let Ok(layers) = proxy.call::<_, _, Vec<String>>("GetLayers", &()) {
+        state.layers = layers;
+    }
+}
+
+fn perform(
+    daemon: &Daemon,
+    state: &MonitorState,
+    action: &MonitorAction,
+) -> CtlResult<String> {
+    let proxy = daemon_proxy(daemon)?;
+    match action {
+        MonitorAction::TogglePause => {
+            let method = if state.is_paused() {
+                "Unpause"
+            } else {
+                "Pause"
+            };
+            proxy.call::<_, _, ()>(method, &())?;
+            Ok(format!("{} requested", method))
+        }
+        MonitorAction::SetLayer(layer) => {
+            let changed: bool = proxy.call("SetLayer", &(layer.as_str(),))?;
+            if changed {
+                Ok(format!("Layer set to \"{}\"", layer))
+            } else {
+                Ok(format!("Layer \"{}\" was not applied", layer))
+            }
+        }
+        MonitorAction::Restart => {
+            proxy.call::<_, _, ()>("Restart", &())?;
+            Ok("Restart requested".to_string())
+        }
+        MonitorAction::Quit => Ok(String::new()),
+    }
+}
+
+fn event_loop(terminal: &mut DefaultTerminal, daemon: &Daemon) -> CtlResult<()> {
+    let events = subscribe(daemon)?;
+    let mut state = MonitorState::default();
+    poll_daemon(daemon, &mut state);
+    let mut last_poll = Instant::now();
+
+    loop {
+        while let Ok(event) = events.try_recv() {
+            state.apply(event);
+        }
+        if last_poll.elapsed() >= DAEMON_POLL_INTERVAL {
+            poll_daemon(daemon, &mut state);
+            last_poll = Instant::now();
+        }
+
+        terminal.draw(|frame| render(frame, &state))?;
+
+        if !event::poll(INPUT_POLL_INTERVAL)? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let Some(action) = state.key_action(key.code) else {
+            continue;
+        };
+        if action == MonitorAction::Quit {
+            return Ok(());
+        }
+        state.message = Some(match perform(daemon, &state, &action) {
+            Ok(message) => message,
+            Err(error) => format!("Error: {}", error),
+        });
+        // Reflect the action right away instead of waiting for the next poll
+        poll_daemon(daemon, &mut state);
+        last_poll = Instant::now();
+    }
+}
+
+pub(crate) fn run(daemon: &Daemon) -> CtlResult<()> {
+    // Fail before taking over the terminal if the daemon isn't running
+    fetch_status(&daemon_proxy(daemon)?)?;
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, daemon);
+    ratatui::restore();
+    result
+}
@@ -0,0 +1,401 @@
+use super::monitor::{FocusEventLine, MonitorAction, MonitorEvent, MonitorState, render};
+use super::*;
+use ratatui::Terminal;
+use ratatui::backend::TestBackend;
+use ratatui::crossterm::event::KeyCode;
+
+fn status(layer: &str, virtual_keys: &[&str], paused: Option<bool>) -> Status {
+    Status {
+        layer: layer.to_string(),
+        layer_display: None,
+        virtual_keys: virtual_keys.iter().map(|vk| vk.to_string()).collect(),
+        source: "focus".to_string(),
+        matched_rules: Vec::new(),
+        rules: Vec::new(),
+        paused,
+        problems: Vec::new(),
+    }
+}
+
+#[test]
+fn test_parse_set_layer() {
+    let args = Args::try_parse_from(["kswitchctl", "set-layer", "browser"]).unwrap();
+    assert_eq!(
+        args.command,
+        CtlCommand::SetLayer {
+            layer: "browser".to_string()
+        }
+    );
+}
+
+#[test]
+fn test_parse_dbus_suffix_global() {
+    let before = Args::try_parse_from(["kswitchctl", "--dbus-suffix", "work", "status"]).unwrap();
+    assert_eq!(before.dbus_suffix.as_deref(), Some("work"));
+    let after = Args::try_parse_from(["kswitchctl", "status", "--dbus-suffix", "work"]).unwrap();
+    assert_eq!(after.dbus_suffix.as_deref(), Some("work"));
+    assert_eq!(
+        Args::try_parse_from(["kswitchctl", "status"]).unwrap().dbus_suffix,
+        None
+    );
+}
+
+#[test]
+fn test_daemon_address() {
+    assert_eq!(
+        daemon_address(None),
+        (DBUS_NAME.to_string(), DBUS_PATH.to_string())
+    );
+    assert_eq!(
+        daemon_address(Some("work")),
+        (format!("{}.work", DBUS_NAME), format!("{}/work", DBUS_PATH))
+    );
+}
+
+#[test]
+fn test_parse_requires_command() {
+    assert!(Args::try_parse_from(["kswitchctl"]).is_err());
+}
+
+#[test]
+fn test_status_line() {
+    assert_eq!(
+        status("browser", &[], Some(false)).format(false),
+        "browser (focus)"
+    );
+    assert_eq!(
+        status("vim", &["vk_a", "vk_b"], Some(true)).format(false),
+        "vim (focus) [vk_a, vk_b] paused"
+    );
+}
+
+#[test]
+fn test_status_with_rules() {
+    let status = Status {
+        rules: vec!["#2".to_string(), "#5".to_string()],
+        ..status("browser", &["vk_a"], Some(false))
+    };
+    assert_eq!(status.format(false), "browser (focus) [vk_a] via #2, #5");
+    assert_eq!(
+        status.format(true),
+        r##"{"layer":"browser","virtual_keys":["vk_a"],"source":"focus","rules":["#2","#5"],"paused":false}"##
+    );
+}
+
+#[test]
+fn test_status_json() {
+    assert_eq!(
+        status("vim", &["vk_a"], Some(false)).format(true),
+        r#"{"layer":"vim","virtual_keys":["vk_a"],"source":"focus","paused":false}"#
+    );
+    // Watch lines come from StatusChanged, which carries no pause state
+    assert_eq!(
+        status("vim", &[], None).format(true),
+        r#"{"layer":"vim","virtual_keys":[],"source":"focus"}"#
+    );
+}
+
+#[test]
+fn test_parse_rules() {
+    let args = Args::try_parse_from(["kswitchctl", "rules", "--json"]).unwrap();
+    assert_eq!(args.command, CtlCommand::Rules { json: true });
+}
+
+#[test]
+fn test_parse_reload() {
+    let args = Args::try_parse_from(["kswitchctl", "reload"]).unwrap();
+    assert_eq!(args.command, CtlCommand::Reload);
+}
+
+#[test]
+fn test_rule_stats_format() {
+    let stats: Vec<RuleStat> = vec![
+        (0, "class=\"firefox\" -> layer=browser".to_string(), 120),
+        (1, "class=\"kitty\" -> layer=terminal".to_string(), 0),
+        (-1, "on_native_terminal -> layer=tty".to_string(), 3),
+        (-2, "on_session_inactive -> layer=locked".to_string(), 1),
+    ]
+    .into_iter()
+    .map(|stat| RuleStat::from_dbus(stat, &RuleNames::new()))
+    .collect();
+    assert_eq!(
+        format_rule_stats(&stats, false),
+        [
+            "#1                   120  class=\"firefox\" -> layer=browser",
+            "#2                     0  class=\"kitty\" -> layer=terminal",
+            "on_native_terminal     3  on_native_terminal -> layer=tty",
+            "on_session_inactive    1  on_session_inactive -> layer=locked",
+        ]
+        .join("\n")
+    );
+    assert_eq!(
+        format_rule_stats(&stats[1..2], true),
+        r##"[{"rule":"#2","hits":0,"summary":"class=\"kitty\" -> layer=terminal"}]"##
+    );
+    assert_eq!(format_rule_stats(&[], false), "no rules configured");
+}
+
+#[test]
+fn test_rule_stats_with_names() {
+    let names = RuleNames::from([(1, "Terminals".to_string())]);
+    let stats: Vec<RuleStat> = vec![
+        (0, "class=\"firefox\" -> layer=browser".to_string(), 120),
+        (1, "class=\"kitty\" -> layer=terminal".to_string(), 7),
+    ]
+    .into_iter()
+    .map(|stat| RuleStat::from_dbus(stat, &names))
+    .collect();
+    assert_eq!(
+        format_rule_stats(&stats, false),
+        [
+            "#1              120  class=\"firefox\" -> layer=browser",
+            "#2 'Terminals'    7  class=\"kitty\" -> layer=terminal",
+        ]
+        .join("\n")
+    );
+    assert_eq!(
+        format_rule_stats(&stats[1..2], true),
+        r##"[{"rule":"#2","name":"Terminals","hits":7,"summary":"class=\"kitty\" -> layer=terminal"}]"##
+    );
+    assert_eq!(
+        focus_line("kitty", false, &[0, 1]).format_rules(&names),
+        "#1, 'Terminals'"
+    );
+    let status = Status {
+        matched_rules: vec![1],
+        ..status("terminal", &[], Some(false))
+    }
+    .with_rule_names(&names);
+    assert_eq!(status.format(false), "terminal (focus) via 'Terminals'");
+}
+
+#[test]
+fn test_rate_limit_stats_format() {
+    let stats = RateLimitStats::from_dbus((100, 512, 40, 31));
+    assert_eq!(
+        stats.format(false),
+        "rate limit: 100/s\nsent: 512\ndeferred: 40\ncoalesced: 31"
+    );
+    assert_eq!(
+        stats.format(true),
+        r#"{"limit":100,"sent":512,"deferred":40,"coalesced":31}"#
+    );
+    assert_eq!(RateLimitStats::from_dbus((0, 0, 0, 0)).format(false), "rate limit: off");
+    let args = Args::try_parse_from(["kswitchctl", "stats", "--json"]).unwrap();
+    assert_eq!(args.command, CtlCommand::Stats { json: true });
+}
+
+#[test]
+fn test_backend_info_format() {
+    let info = BackendInfo::from_dbus((
+        "sway".to_string(),
+        BTreeMap::from([("size".to_string(), true), ("pid".to_string(), false)]),
+    ));
+    assert_eq!(info.format(false), "backend: sway\npid: no\nsize: yes");
+    assert_eq!(
+        info.format(true),
+        r#"{"backend":"sway","capabilities":{"pid":false,"size":true}}"#
+    );
+    let args = Args::try_parse_from(["kswitchctl", "backend"]).unwrap();
+    assert_eq!(args.command, CtlCommand::Backend { json: false });
+}
+
+#[test]
+fn test_parse_logs() {
+    let args = Args::try_parse_from(["kswitchctl", "logs"]).unwrap();
+    assert_eq!(
+        args.command,
+        CtlCommand::Logs {
+            lines: None,
+            follow: false
+        }
+    );
+    let args = Args::try_parse_from(["kswitchctl", "logs", "-n", "20", "-f"]).unwrap();
+    assert_eq!(
+        args.command,
+        CtlCommand::Logs {
+            lines: Some(20),
+            follow: true
+        }
+    );
+}
+
+fn focus_line(class: &str, native_terminal: bool, matched_rules: &[i32]) -> FocusEventLine {
+    FocusEventLine {
+        class: class.to_string(),
+        title: "title".to_string(),
+        native_terminal,
+        matched_rules: matched_rules.to_vec(),
+        window_id: String::new(),
+    }
+}
+
+#[test]
+fn test_parse_monitor() {
+    let args = Args::try_parse_from(["kswitchctl", "monitor"]).unwrap();
+    assert_eq!(args.command, CtlCommand::Monitor);
+}
+
+#[test]
+fn test_focus_event_formatting() {
+    assert_eq!(
+        focus_line("firefox", false, &[0, 2]).format_rules(&RuleNames::new()),
+        "#1, #3"
+    );
+    assert_eq!(focus_line("firefox", false, &[]).format_rules(&RuleNames::new()), "no rule");
+    assert_eq!(
+        focus_line("", true, &[-1]).format_rules(&RuleNames::new()),
+        "on_native_terminal"
+    );
+    assert_eq!(
+        focus_line("firefox", false, &[]).format_window(),
+        "firefox \"title\""
+    );
+    assert_eq!(
+        focus_line("", true, &[]).format_window(),
+        "(native terminal)"
+    );
+    let identified = FocusEventLine {
+        window_id: "0x3a00007".to_string(),
+        ..focus_line("firefox", false, &[])
+    };
+    assert_eq!(identified.format_window(), "firefox \"title\" [0x3a00007]");
+    assert_eq!(
+        focus_line("", false, &[-2]).format_rules(&RuleNames::new()),
+        "on_session_inactive"
+    );
+    assert_eq!(
+        focus_line("", false, &[-2]).format_window(),
+        "(session inactive)"
+    );
+    assert_eq!(
+        focus_line("", false, &[-3]).format_rules(&RuleNames::new()),
+        "on_locked"
+    );
+    assert_eq!(
+        focus_line("", false, &[-3]).format_window(),
+        "(screen locked)"
+    );
+}
+
+#[test]
+fn test_monitor_keeps_pause_state_across_status_signals() {
+    let mut state = MonitorState::default();
+    state.apply(MonitorEvent::Status(status("base", &[], Some(true))));
+    state.apply(MonitorEvent::Status(status("vim", &[], None)));
+    assert_eq!(state.status, Some(status("vim", &[], Some(true))));
+    state.apply(MonitorEvent::Paused(false));
+    assert_eq!(state.status, Some(status("vim", &[], Some(false))));
+}
+
+#[test]
+fn test_degraded_status_line() {
+    let mut degraded = status("vim", &[], Some(false));
+    degraded.problems = vec![
+        "logind: session vanished".to_string(),
+        "unpause: kanata unreachable".to_string(),
+    ];
+    assert_eq!(
+        degraded.format(false),
+        "vim (focus) degraded (logind: session vanished; unpause: kanata unreachable)"
+    );
+    assert_eq!(
+        degraded.format(true),
+        r#"{"layer":"vim","virtual_keys":[],"source":"focus","paused":false,"problems":["logind: session vanished","unpause: kanata unreachable"]}"#
+    );
+}
+
+#[test]
+fn test_monitor_keeps_health_across_status_signals() {
+    let mut state = MonitorState::default();
+    let mut degraded = status("base", &[], Some(false));
+    degraded.problems = vec!["logind: session vanished".to_string()];
+    state.apply(MonitorEvent::Status(degraded));
+    state.apply(MonitorEvent::Status(status("vim", &[], None)));
+    let current = state.status.expect("status kept");
+    assert_eq!(current.layer, "vim");
+    assert_eq!(current.problems, vec!["logind: session vanished"]);
+}
+
+#[test]
+fn test_monitor_focus_events_newest_first() {
+    let mut state = MonitorState::default();
+    state.apply(MonitorEvent::Focus(focus_line("a", false, &[0])));
+    state.apply(MonitorEvent::Focus(focus_line("b", false, &[1])));
+    let classes: Vec<&str> = state
+        .focus_events
+        .iter()
+        .map(|event| event.class.as_str())
+        .collect();
+    assert_eq!(classes, vec!["b", "a"]);
+}
+
+#[test]
+fn test_status_with_display_name() {
+    let names = BTreeMap::from([("l_nav_v2".to_string(), "Navigation".to_string())]);
+    let nav = status("l_nav_v2", &[], Some(false)).with_display_names(&names);
+    assert_eq!(nav.format_line(), "Navigation (l_nav_v2, focus)");
+    let json: serde_json::Value = serde_json::from_str(&nav.format(true)).unwrap();
+    assert_eq!(json["layer"], "l_nav_v2");
+    assert_eq!(json["layer_display"], "Navigation");
+
+    // Layers without a display name keep the plain format and omit the field
+    let base = status("base", &[], Some(false)).with_display_names(&names);
+    assert_eq!(base.format_line(), "base (focus)");
+    assert!(!base.format(true).contains("layer_display"));
+}
+
+#[test]
+fn test_monitor_key_actions() {
+    let state = MonitorState {
+        layers: vec!["base".to_string(), "vim".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(
+        state.key_action(KeyCode::Char('q')),
+        Some(MonitorAction::Quit)
+    );
+    assert_eq!(state.key_action(KeyCode::Esc), Some(MonitorAction::Quit));
+    assert_eq!(
+        state.key_action(KeyCode::Char('p')),
+        Some(MonitorAction::TogglePause)
+    );
+    assert_eq!(
+        state.key_action(KeyCode::Char('r')),
+        Some(MonitorAction::Restart)
+    );
+    assert_eq!(
+        state.key_action(KeyCode::Char('2')),
+        Some(MonitorAction::SetLayer("vim".to_string()))
+    );
+    // No third layer
+    assert_eq!(state.key_action(KeyCode::Char('3')), None);
+    assert_eq!(state.key_action(KeyCode::Char('x')), None);
+}
+
+#[test]
+fn test_monitor_render() {
+    let mut state = MonitorState {
+        kanata_connected: Some(true),
+        layers: vec!["base".to_string(), "vim".to_string()],
+        ..Default::default()
+    };
+    state.apply(MonitorEvent::Status(status("vim", &["vk_a"], Some(false))));
+    state.apply(MonitorEvent::Focus(focus_line("kitty", false, &[1])));
+
+    let mut terminal = Terminal::new(TestBackend::new(60, 14)).unwrap();
+    terminal.draw(|frame| render(frame, &state)).unwrap();
+    let screen: String = terminal
+        .backend()
+        .buffer()
+        .content()
+        .iter()
+        .map(|cell| cell.symbol())
+        .collect();
+    assert!(screen.contains("Layer: vim (focus)"));
+    assert!(screen.contains("Virtual keys: vk_a"));
+    assert!(screen.contains("Kanata: connected"));
+    assert!(screen.contains("Switching: active"));
+    assert!(screen.contains("1:base  2:vim"));
+    assert!(screen.contains("kitty \"title\" -> #2"));
+}
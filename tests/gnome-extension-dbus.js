@@ -30,7 +30,7 @@ async function main() {
   const modulePath = GLib.build_filenamev([srcRoot, 'src/gnome-extension/dbus.js']);
   const moduleUrl = GLib.filename_to_uri(modulePath, null);
   const module = await import(moduleUrl);
-  const { unpackSingleBoolean } = module;
+  const { unpackSettings, unpackSingleBoolean } = module;
 
   assertEqual(
     unpackSingleBoolean({ deep_unpack() { return [true]; } }),
@@ -50,6 +50,29 @@ async function main() {
     () => unpackSingleBoolean({ deep_unpack() { return [true, false]; } }),
     '1 element'
   );
+
+  const defaults = { 'show-top-bar-icon': true, 'show-focus-layer-only': true };
+  const settings = unpackSettings(
+    new GLib.Variant('(a{sv})', [{
+      'show-focus-layer-only': new GLib.Variant('b', false),
+      'show-top-bar-icon': new GLib.Variant('s', 'no'),
+      'future-setting': new GLib.Variant('b', true)
+    }]),
+    defaults
+  );
+  assertEqual(settings['show-focus-layer-only'], false, 'unpack stored setting');
+  assertEqual(settings['show-top-bar-icon'], true, 'wrong type keeps default');
+  assertEqual(Object.hasOwn(settings, 'future-setting'), false, 'unknown key ignored');
+  assertEqual(defaults['show-focus-layer-only'], true, 'defaults unchanged');
+
+  assertThrows(
+    () => unpackSettings({ recursiveUnpack() { return [true]; } }, defaults),
+    'dictionary'
+  );
+  assertThrows(
+    () => unpackSettings({ recursiveUnpack() { return []; } }, defaults),
+    '1-element'
+  );
 }
 
 main();
@@ -15,7 +15,7 @@ async function main() {
   const modulePath = GLib.build_filenamev([srcRoot, 'src/gnome-extension/focus.js']);
   const moduleUrl = GLib.filename_to_uri(modulePath, null);
   const module = await import(moduleUrl);
-  const { extractFocus } = module;
+  const { extractFocus, extractWindows } = module;
 
   const empty = extractFocus(null);
   assertEqual(empty.windowClass, '', 'null window class');
@@ -29,6 +29,22 @@ async function main() {
   assertEqual(focus.windowClass, 'Terminal', 'window class');
   assertEqual(focus.windowTitle, 'bash', 'window title');
 
+  assertEqual(focus.desktopId, '', 'desktop id without tracker');
+  assertEqual(focus.windowId, '', 'window id without get_id');
+  assertEqual(extractFocus({ ...stubWin, get_id() { return 42; } }).windowId, '42', 'window id');
+  assertEqual(extractFocus(null).windowId, '', 'null window id');
+
+  const tracker = {
+    get_window_app(window) {
+      return window === stubWin ? { get_id() { return 'org.gnome.Terminal.desktop'; } } : null;
+    }
+  };
+  const trackedFocus = extractFocus(stubWin, tracker);
+  assertEqual(trackedFocus.desktopId, 'org.gnome.Terminal.desktop', 'desktop id');
+  assertEqual(trackedFocus.workspace, '', 'workspace defaults to empty');
+  assertEqual(extractFocus(stubWin, tracker, 'Workspace 2').workspace, 'Workspace 2', 'workspace');
+  assertEqual(extractFocus(null, null, 'Workspace 1').workspace, 'Workspace 1', 'workspace without window');
+
   const missing = {
     get_wm_class() { return null; },
     get_title() { return undefined; }
@@ -36,6 +52,13 @@ async function main() {
   const missingFocus = extractFocus(missing);
   assertEqual(missingFocus.windowClass, '', 'missing class');
   assertEqual(missingFocus.windowTitle, '', 'missing title');
+  assertEqual(extractFocus(missing, tracker).desktopId, '', 'missing app');
+
+  const taskbarWin = { ...stubWin, is_skip_taskbar() { return false; } };
+  const popupWin = { ...missing, is_skip_taskbar() { return true; } };
+  const windows = extractWindows([taskbarWin, popupWin, null], tracker);
+  assertEqual(windows.length, 1, 'skip-taskbar windows are not listed');
+  assertEqual(windows[0].join('|'), 'Terminal|bash|', 'listed window');
 }
 
 main();
@@ -15,7 +15,13 @@ async function main() {
   const formatPath = GLib.build_filenamev([srcRoot, 'src/gnome-extension/format.js']);
   const formatUrl = GLib.filename_to_uri(formatPath, null);
   const module = await import(formatUrl);
-  const { formatLayerLetter, formatVirtualKeys, selectStatus } = module;
+  const {
+    displayLayerName,
+    formatLayerLetter,
+    formatMatchedRules,
+    formatVirtualKeys,
+    selectStatus
+  } = module;
 
   assertEqual(formatLayerLetter('base'), 'B', 'layer basic');
   assertEqual(formatLayerLetter('  vim'), 'V', 'layer trim');
@@ -23,6 +29,16 @@ async function main() {
   assertEqual(formatLayerLetter('   '), '?', 'layer whitespace');
   assertEqual(formatLayerLetter(null), '?', 'layer non-string');
 
+  const displayNames = { l_nav_v2: 'Navigation' };
+  assertEqual(displayLayerName('l_nav_v2', displayNames), 'Navigation', 'display name mapped');
+  assertEqual(displayLayerName('base', displayNames), 'base', 'display name fallback');
+  assertEqual(displayLayerName('base', null), 'base', 'display name without map');
+  assertEqual(
+    formatLayerLetter(displayLayerName('l_nav_v2', displayNames)),
+    'N',
+    'layer letter uses display name'
+  );
+
   assertEqual(formatVirtualKeys([]), '', 'vk empty array');
   assertEqual(formatVirtualKeys(['vk_nav']), 'V', 'vk single');
   assertEqual(formatVirtualKeys(['  k']), 'K', 'vk single trim');
@@ -33,6 +49,18 @@ async function main() {
     'vk overflow'
   );
 
+  assertEqual(formatMatchedRules([]), '', 'rules empty');
+  assertEqual(formatMatchedRules([0, 4]), '#1, #5', 'rules numbered from 1');
+  assertEqual(formatMatchedRules([-1]), 'on_native_terminal', 'rules native terminal');
+  assertEqual(formatMatchedRules([-2]), 'on_session_inactive', 'rules session inactive');
+  assertEqual(formatMatchedRules([-3]), 'on_locked', 'rules screen locked');
+  assertEqual(formatMatchedRules(undefined), '', 'rules from older daemon');
+  assertEqual(
+    formatMatchedRules([0, 2], { 2: 'IDE: JetBrains' }),
+    "#1, 'IDE: JetBrains'",
+    'rules with names'
+  );
+
   const focusStatus = { layer: 'vim', virtualKeys: [], source: 'focus' };
   const lastStatus = { layer: 'browser', virtualKeys: [], source: 'external' };
   assertEqual(selectStatus(true, focusStatus, lastStatus).layer, 'vim', 'select focus status');